@@ -33,12 +33,78 @@ fn main() {
                 .long("--version")
                 .help("Display version info and exit."),
         )
+        .flag(Flag::new().long("--all").help(
+            "With --identity, print a single identity file for all compatible keys in connected YubiKeys.",
+        ))
+        .flag(Flag::new().long("--allow-standard-slots").help(
+            "List identities in the standard PIV slots (9a, 9c, 9d and 9e) too, and let --slot name them.",
+        ))
+        .flag(
+            Flag::new()
+                .long("--attest")
+                .help("Print the attestation for the key in a slot, and its issuer, as PEM."),
+        )
+        .flag(Flag::new().long("--attestation-ca").help(
+            "PEM file holding Yubico's PIV root CA, to check attestations against with --verify.",
+        ))
+        .flag(
+            Flag::new()
+                .long("--change-mgmt-key")
+                .help("Change the management key."),
+        )
+        .flag(Flag::new().long("--change-pin").help("Change the PIN."))
+        .flag(Flag::new().long("--change-puk").help("Change the PUK."))
+        .flag(Flag::new().long("--config").help(
+            "Print the settings in the configuration file, or print or set (to a value, or to '' to remove it) the setting given as SECTION.KEY [VALUE].",
+        ))
+        .flag(
+            Flag::new()
+                .long("--delete")
+                .help("Remove the key and certificate in a slot."),
+        )
+        .flag(Flag::new().long("--discover").help(
+            "List the yk-agentd daemons advertised on the local network, and the serials of their YubiKeys.",
+        ))
         .flag(
             Flag::new()
                 .short("-f")
                 .long("--force")
-                .help("Force --generate to overwrite a filled slot."),
+                .help("Force --generate to overwrite a filled slot, --repair to replace a certificate, --export-recipients or --export-stubs to overwrite a file, or --delete to skip confirmation."),
+        )
+        .flag(Flag::new().long("--format").help(
+            "One of [text, pkcs11-uri]. Defaults to 'text'. 'pkcs11-uri' makes --list and --list-all print the PKCS #11 URI of each key instead.",
+        ))
+        .flag(Flag::new().long("--export-recipients").help(
+            "Write the recipients for all compatible keys in a YubiKey to a recipients file.",
+        ))
+        .flag(Flag::new().long("--export-stubs").help(
+            "Back up the identity stubs for all compatible keys in connected YubiKeys to a JSON escrow file.",
+        ))
+        .flag(Flag::new().long("--error-format").help(
+            "One of [text, json]. Defaults to 'text'. 'json' prints errors as a JSON object with a stable code.",
+        ))
+        .flag(Flag::new().long("--log-level").help(
+            "What to log to stderr: a level such as 'debug', or directives such as 'usbip=trace'. Defaults to RUST_LOG, or nothing.",
+        ))
+        .flag(Flag::new().long("--log-format").help(
+            "One of [text, json]. Defaults to 'text'. 'json' logs a JSON object per line.",
+        ))
+        .flag(
+            Flag::new()
+                .long("--forget-pins")
+                .help("Make the PIN agent forget its cached PINs, or only the PIN for --serial."),
         )
+        .flag(
+            Flag::new()
+                .long("--mgmt-key-fd")
+                .help("Read a custom hex-encoded management key from this file descriptor."),
+        )
+        .flag(Flag::new().long("--rotate-mgmt-key").help(
+            "Replace the management key with a new PIN-protected key when generating.",
+        ))
+        .flag(Flag::new().long("--protect-mgmt-key").help(
+            "With --change-mgmt-key, use a random management key stored PIN-protected on the YubiKey.",
+        ))
         .flag(
             Flag::new()
                 .short("-g")
@@ -51,17 +117,69 @@ fn main() {
                 .long("--identity")
                 .help("Print identities stored in connected YubiKeys."),
         )
+        .flag(
+            Flag::new()
+                .long("--info")
+                .help("Print the firmware version and supported features of a YubiKey."),
+        )
+        .flag(Flag::new().long("--import").help(
+            "Import the P-256 or P-384 private key given with --key as a new identity.",
+        ))
+        .flag(
+            Flag::new()
+                .long("--key")
+                .help("PEM file (PKCS #8 or SEC1) holding the private key for --import."),
+        )
+        .flag(
+            Flag::new()
+                .long("--import-stubs")
+                .help("Print the identities backed up in a JSON escrow file by --export-stubs."),
+        )
+        .flag(Flag::new().long("--init-card").help(
+            "Write a random CHUID and CCC to a YubiKey that lacks them, as some smart card middleware needs.",
+        ))
+        .flag(Flag::new().long("--interactive").help(
+            "Manage the identities in connected YubiKeys from interactive menus.",
+        ))
         .flag(
             Flag::new()
                 .short("-l")
                 .long("--list")
                 .help("List recipients for age identities in connected YubiKeys."),
         )
+        .flag(
+            Flag::new()
+                .long("--json")
+                .help("Print --identity, --info, --list and --list-all output as JSON."),
+        )
         .flag(
             Flag::new()
                 .long("--list-all")
                 .help("List recipients for all YubiKey keys that are compatible with age."),
         )
+        .flag(Flag::new().long("--provision").help(
+            "Generate the identities described in a TOML file on every connected YubiKey.",
+        ))
+        .flag(Flag::new().long("--recipient").help(
+            "Only print the identity for this recipient, to find the YubiKey and slot holding it.",
+        ))
+        .flag(
+            Flag::new().long("--recipient-from").help(
+                "Print the recipient for a P-256 public key given as PEM, or as hex-encoded SEC1 or SPKI.",
+            ),
+        )
+        .flag(Flag::new().long("--read-only").help(
+            "Refuse the commands that change a YubiKey, such as --generate, --delete and --change-pin.",
+        ))
+        .flag(Flag::new().long("--remote").help(
+            "Use the YubiKeys shared by the yk-agentd at this address (HOST:PORT, unix:PATH, pipe:NAME or ssh:[USER@]HOST/PATH), for --identity and --list.",
+        ))
+        .flag(Flag::new().long("--rename").help(
+            "Change the name of the identity in a slot to the one given with --name.",
+        ))
+        .flag(Flag::new().long("--repair").help(
+            "Write a new certificate for the key in a slot whose certificate was wiped, keeping its recipient.",
+        ))
         .flag(
             Flag::new()
                 .long("--name")
@@ -72,6 +190,27 @@ fn main() {
                 .long("--pin-policy")
                 .help("One of [always, once, never]. Defaults to 'once'."),
         )
+        .flag(
+            Flag::new()
+                .long("--pin-file")
+                .help("Read the PIN from this file. Requires --unattended-pin."),
+        )
+        .flag(
+            Flag::new()
+                .long("--pin-fd")
+                .help("Read the PIN from this file descriptor. Requires --unattended-pin."),
+        )
+        .flag(
+            Flag::new()
+                .long("--algorithm")
+                .help("One of [p256, p384, rsa2048]. Defaults to 'p256'."),
+        )
+        .flag(Flag::new().long("--require-nondefault-pin").help(
+            "Refuse to list or generate identities on YubiKeys with the default PIN or PUK.",
+        ))
+        .flag(Flag::new().long("--risk-lockout").help(
+            "Try the PIN even if a wrong PIN would block it, because only one try is left.",
+        ))
         .flag(
             Flag::new()
                 .long("--serial")
@@ -80,13 +219,35 @@ fn main() {
         .flag(
             Flag::new()
                 .long("--slot")
-                .help("Specify which slot to use. Defaults to first usable slot."),
+                .help("Specify which slot to use: 1 to 20, or with --allow-standard-slots 9a, 9c, 9d or 9e. Defaults to first usable slot."),
         )
+        .flag(Flag::new().long("--ssh-agent").help(
+            "Run an ssh-agent that offers the P-256 and P-384 keys in connected YubiKeys as ECDSA SSH keys.",
+        ))
         .flag(
             Flag::new()
                 .long("--touch-policy")
                 .help("One of [always, cached, never]. Defaults to 'always'."),
-        );
+        )
+        .flag(
+            Flag::new()
+                .long("--unblock-pin")
+                .help("Set a new PIN after entering the PUK, unblocking the PIN."),
+        )
+        .flag(Flag::new().long("--unattended-pin").help(
+            "Use the PIN from --pin-file, --pin-fd or AGE_YUBIKEY_PIN instead of prompting.",
+        ))
+        .flag(
+            Flag::new()
+                .short("-v")
+                .long("--verbose")
+                .help("Print the underlying causes of errors."),
+        )
+        .flag(Flag::new().long("--verify").help(
+            "Check that the key in a slot matches its certificate, policies and attestation, and any given identities.",
+        ))
+        .arg(Arg::new("[IDENTITY...]"))
+        .arg(Arg::new("[SECTION.KEY [VALUE]]"));
     let page = builder.render();
 
     generate_manpage(page, "age-plugin-yubikey");
@@ -16,14 +16,50 @@
 
 ## CLI commands and flags
 
+-cmd-attest   = --attest
+-cmd-change-mgmt-key = --change-mgmt-key
+-cmd-change-pin = --change-pin
+-cmd-change-puk = --change-puk
+-cmd-config   = --config
+-cmd-delete   = --delete
+-cmd-discover = --discover
+-cmd-export-recipients = --export-recipients
+-cmd-export-stubs = --export-stubs
+-cmd-forget-pins = --forget-pins
 -cmd-generate = --generate
 -cmd-identity = --identity
+-cmd-import   = --import
+-cmd-import-stubs = --import-stubs
+-cmd-info     = --info
+-cmd-init-card = --init-card
+-cmd-interactive = --interactive
 -cmd-list     = --list
 -cmd-list-all = --list-all
-
+-cmd-provision = --provision
+-cmd-recipient-from = --recipient-from
+-cmd-rename   = --rename
+-cmd-repair   = --repair
+-cmd-ssh-agent = --ssh-agent
+-cmd-unblock-pin = --unblock-pin
+-cmd-verify   = --verify
+
+-flag-algorithm = --algorithm
+-flag-allow-standard-slots = --allow-standard-slots
+-flag-attestation-ca = --attestation-ca
+-flag-all    = --all
 -flag-force  = --force
+-flag-format = --format
+-flag-key    = --key
+-flag-name   = --name
+-flag-read-only = --read-only
+-flag-recipient = --recipient
+-flag-remote = --remote
+-flag-require-nondefault-pin = --require-nondefault-pin
+-flag-risk-lockout = --risk-lockout
+-flag-mgmt-key-fd = --mgmt-key-fd
 -flag-serial = --serial
 -flag-slot   = --slot
+-flag-unattended-pin = --unattended-pin
 
 ## YubiKey metadata
 
@@ -115,19 +151,53 @@ cli-setup-finished =
 
     💭 Remember: everything breaks, have a backup plan for when this {-yubikey} does.
 
+## Interactive management
+
+interactive-intro =
+    🔑 Manage the {-age} identities in your {-yubikeys}.
+
+    Use the up/down arrow keys to make a choice and [Enter] to select it, or
+    press [Esc] or [q] to go back.
+interactive-yk = {$yubikey_name} (Serial: {$yubikey_serial}, {$count ->
+    [one] one identity
+   *[other] {$count} identities
+})
+interactive-refresh         = 🔄 Refresh
+interactive-back            = ↩️  Back
+interactive-select-slot     = 🕳️  Select a slot of the {-yubikey} with serial {$yubikey_serial}
+interactive-select-action   = What do you want to do with the identity in slot {$slot}?
+interactive-action-print    = Print the identity
+interactive-action-rename   = Rename the identity
+interactive-action-delete   = Delete the key and certificate
+interactive-failed          = ❌ {$err}
+
 ## Programmatic usage
 
 open-yk-with-serial    = ⏳ Please insert the {-yubikey} with serial {$yubikey_serial}.
 open-yk-without-serial = ⏳ Please insert the {-yubikey}.
+open-yk-reinsert       = ⏳ The {-yubikey} with serial {$yubikey_serial} was removed. Please re-insert it to continue.
 warn-yk-not-connected  = Ignoring {$yubikey_name}: not connected
 warn-yk-missing-applet = Ignoring {$yubikey_name}: Missing {$applet_name} applet
+warn-yk-no-serial      =
+    ⚠️ This {-yubikey} does not expose its serial number. Its identities will find
+    it by the key in their slot instead, so you may be asked to choose between
+    {-yubikeys} that hold the same key.
+warn-invalid-serial-env = Ignoring {$env}, which is not a {-yubikey} serial number.
+warn-unattended-pin-ignored = Ignoring the PIN provided for non-interactive use, because {$env} is not set to 1.
 
 print-recipient = Recipient: {$recipient}
 
+identity-file-header = # {-age} identities for all compatible keys in the connected {-yubikeys}, created by {-age-plugin-yubikey} {$version}.
+
 printed-kind-identities = identities
 printed-kind-recipients = recipients
 printed-multiple = Generated {$kind} for {$count} slots. If you intended to select a slot, use {-flag-slot}.
 
+discover-none = No yk-agentd was found on the local network.
+discover-server = {$name}: {-flag-remote} {$address}
+discover-server-tls = {$name}: {-flag-remote} {$address} (TLS)
+discover-serials = {"    "}{-yubikeys}: {$serials}
+
 ## YubiKey management
 
 mgr-enter-pin = Enter PIN for {-yubikey} with serial {$yubikey_serial} (default is {$default_pin})
@@ -145,21 +215,136 @@ mgr-choose-new-pin    = Choose a new PIN/PUK
 mgr-repeat-new-pin    = Repeat the PIN/PUK
 mgr-pin-mismatch      = PINs don't match
 mgr-nope-default-pin  = You entered the default PIN again. You need to change it.
+mgr-nope-default-puk  = You entered the default PUK. Choose something else.
+
+mgr-pin-tries         = The PIN of the {-yubikey} with serial {$yubikey_serial} {$tries ->
+    [0] is blocked.
+    [one] has {$tries} try remaining.
+   *[other] has {$tries} tries remaining.
+}
+mgr-enter-puk         = Enter the PUK
+mgr-choose-pin        = Choose a new PIN
+mgr-repeat-pin        = Repeat the PIN
+mgr-unblocked-pin     = 🔓 Set the new PIN.
+mgr-choose-puk        = Choose a new PUK
+mgr-repeat-puk        = Repeat the PUK
+mgr-changed-pin       = 🔐 Changed the PIN.
+mgr-changed-puk       = 🔐 Changed the PUK.
+
+mgr-choose-mgmt-key   = Choose a new management key (24 bytes, hex-encoded)
+mgr-repeat-mgmt-key   = Repeat the management key
+mgr-mgmt-key-mismatch = Management keys don't match
+mgr-changed-mgmt-key  = 🔐 Changed the management key.
+
+mgr-wrote-chuid       = 🪪 Wrote a random CHUID.
+mgr-wrote-ccc         = 🪪 Wrote a random CCC.
+mgr-card-initialized  = The {-yubikey} already has a CHUID and CCC.
 
 mgr-changing-mgmt-key =
     ✨ Your {-yubikey} is using the default management key.
     ✨ We'll migrate it to a PIN-protected management key.
+mgr-rotating-mgmt-key =
+    ✨ We'll replace the management key with a new PIN-protected management key.
+mgr-enter-mgmt-key = Enter the management key (hex-encoded)
 mgr-changing-mgmt-key-error =
     An error occurred while setting the new management key.
     ⚠️ SAVE THIS MANAGEMENT KEY - YOU MAY NEED IT TO MANAGE YOUR {-yubikey}! ⚠️
     {"  "}{$management_key}
 mgr-changing-mgmt-key-success = Success!
 
+mgr-default-pin      = ⚠️  {-yubikey} with serial {$yubikey_serial} uses the default PIN. Change it with: {$cmd}
+mgr-default-puk      = ⚠️  {-yubikey} with serial {$yubikey_serial} uses the default PUK. Change it with: {$cmd}
+mgr-default-mgmt-key = ⚠️  {-yubikey} with serial {$yubikey_serial} uses the default management key. Change it with: {$cmd}
+
+## Batch provisioning
+
+provision-yk-start         = ⏳ Provisioning {-yubikey} with serial {$yubikey_serial}...
+provision-yk-pin-replaced  = 🔐 Replaced the default PIN and PUK.
+provision-yk-slot-done     = ✅ Slot {$slot}: {$recipient}
+provision-yk-failed        = ❌ {-yubikey} with serial {$yubikey_serial}: {$err}
+provision-summary          = Provisioned {$succeeded} of {$total} {-yubikeys}.
+provision-err-no-slots     = no [[slot]] tables
+provision-err-duplicate-slot = slot {$slot} is listed more than once
+
+## YubiKey information
+
+info-yubikey = {-yubikey} with serial {$serial}, firmware {$firmware}
+info-algorithms = PIV algorithms: {$algorithms}
+info-aes-mgmt-key = AES management keys: {$supported ->
+    [yes] supported
+   *[no] not supported
+}
+info-applets = Enabled applets: {$applets}
+info-fips = FIPS model: {$fips}
+info-pin-complexity = PIN complexity enforced: {$enforced}
+info-no-device-info = Applets, FIPS status and PIN complexity: unknown (reported by firmware 5.0 and later)
+
+## Slot verification
+
+verify-key-matches-cert = ✅ The key in slot {$slot} matches its certificate ({ $method ->
+    [attestation] checked by attestation
+    [decryption] checked by decrypting a random file key
+   *[ecdh] checked by ECDH
+}).
+verify-stubs-match = ✅ {$count ->
+    [one] The identity matches
+   *[other] All {$count} identities match
+} slot {$slot}.
+verify-policies-match = ✅ The PIN and touch policies in the certificate are those the {-yubikey} enforces.
+verify-policies-mismatch = ❌ The certificate claims PIN policy {$cert_pin_policy} and touch policy {$cert_touch_policy}, but the {-yubikey} enforces PIN policy {$pin_policy} and touch policy {$touch_policy}.
+verify-policies-no-extension = The certificate does not record the PIN and touch policies of the key.
+verify-policies-unknown = The {-yubikey} does not report the PIN and touch policies of the key, so they were not checked.
+verify-no-attestation = The key was not generated on the {-yubikey}, so it has no attestation to check.
+verify-attested-serial-mismatch = ❌ The attestation is from the {-yubikey} with serial {$serial}, not {$yubikey_serial}.
+verify-chain-ok = ✅ The attestation chains to the given root CA.
+verify-chain-broken = ❌ The attestation does not chain to the given root CA.
+verify-chain-no-ca = Pass {-flag-attestation-ca} with Yubico's PIV root CA to check that the attestation chains to it.
+
+## Slot deletion
+
+delete-confirm = Delete the key and certificate in slot {$slot}? Anything encrypted only to it can no longer be decrypted
+delete-done    = 🗑️ Deleted the key and certificate in slot {$slot}. Identities for this slot no longer work.
+
+## Recipient export
+
+export-recipients-header = # {-age} recipients for the {-yubikey} with serial {$serial}, exported by {-age-plugin-yubikey} {$version}.
+export-recipients-done   = {$count ->
+    [one] Wrote one recipient to {$path}.
+   *[other] Wrote {$count} recipients to {$path}.
+}
+
+export-stubs-done = {$count ->
+    [one] Wrote one identity stub to {$path}.
+   *[other] Wrote {$count} identity stubs to {$path}.
+}
+import-stubs-header = # {-age} identities restored from {$path} by {-age-plugin-yubikey} {$version}.
+
+escrow-unsupported-version = unsupported version {$version}
+escrow-mismatch = identity {$identity} does not match its recipient, serial or slot
+
+## Attestation
+
+attest-unknown = unknown
+attest-summary =
+    🔏 Attestation for slot {$slot}
+    {"  "}Serial: {$serial}
+    {"  "}PIN policy: {$pin_policy}
+    {"  "}Touch policy: {$touch_policy}
+    {"  "}Firmware: {$firmware}
+attest-issuer-ok       = ✅ The attestation is signed by the {-yubikey}'s attestation certificate.
+attest-issuer-mismatch = ❌ The attestation is not signed by the {-yubikey}'s attestation certificate.
+attest-key-ok          = ✅ The attested key matches the slot's certificate.
+attest-key-mismatch    = ❌ The attested key does not match the slot's certificate.
+attest-key-no-cert     = The slot has no compatible certificate to compare the attested key with.
+
 ## YubiKey keygen
 
 builder-gen-key  = 🎲 Generating key...
+builder-import-key = 📥 Importing key...
 builder-gen-cert = 🔏 Generating certificate...
+builder-recover-key = 🔍 Recovering the public key from signatures made by the slot...
 builder-touch-yk = 👆 Please touch the {-yubikey}
+import-key-copy  = The private key is still in {$path}, and can decrypt files encrypted to this identity without the {-yubikey}. Keep it as safe as the {-yubikey}, or delete it.
 
 ## Plugin usage
 
@@ -168,30 +353,52 @@ plugin-err-invalid-identity  = Invalid {-yubikey} stub
 plugin-err-invalid-stanza    = Invalid {-yubikey} stanza
 plugin-err-decryption-failed = Failed to decrypt {-yubikey} stanza
 
+unknown-serial              = unknown (key {$tag})
+plugin-use-yk-in-reader     = Several {-yubikeys} hold this key. Use the one in {$reader}?
+plugin-use-this-yk          = Use this {-yubikey}
+plugin-try-next-yk          = Try the next {-yubikey}
 plugin-insert-yk            = Please insert {-yubikey} with serial {$yubikey_serial}
 plugin-yk-is-plugged-in     = {-yubikey} is plugged in
 plugin-skip-this-yk         = Skip this {-yubikey}
 plugin-insert-yk-retry      = Could not open {-yubikey}. Please insert {-yubikey} with serial {$yubikey_serial}
+plugin-reinsert-yk          = {-yubikey} with serial {$yubikey_serial} was removed. Please re-insert it
 plugin-err-yk-not-found     = Could not find {-yubikey} with serial {$yubikey_serial}
 plugin-err-yk-opening       = Could not open {-yubikey} with serial {$yubikey_serial}
 plugin-err-yk-timed-out     = Timed out while waiting for {-yubikey} with serial {$yubikey_serial} to be inserted
 plugin-err-yk-stub-mismatch = A {-yubikey} stub did not match the {-yubikey}
+plugin-err-yk-op-timed-out  = Timed out while waiting for {-yubikey} with serial {$yubikey_serial}. Was it touched?
 
 plugin-err-yk-invalid-pin-policy = Certificate for {-yubikey} identity contains an invalid PIN policy
 
 plugin-enter-pin            = Enter PIN for {-yubikey} with serial {$yubikey_serial}
+plugin-touch-yk             = 👆 Please touch the {-yubikey} with serial {$yubikey_serial}
 plugin-err-accidental-touch = Did you touch the {-yubikey} by accident?
 plugin-err-pin-too-short    = PIN was too short.
 plugin-err-pin-too-long     = PIN was too long.
 plugin-err-pin-required     = A PIN is required for {-yubikey} with serial {$yubikey_serial}
 
+pin-tries-remaining = ({$tries ->
+    [one] {$tries} try remaining
+   *[other] {$tries} tries remaining
+})
+
+## SSH agent
+
+ssh-agent-listening = {$count ->
+    [one] 🔑 Offering one key to SSH clients. Set SSH_AUTH_SOCK as below to use it.
+   *[other] 🔑 Offering {$count} keys to SSH clients. Set SSH_AUTH_SOCK as below to use them.
+}
+ssh-agent-sign-failed = Could not sign with the key in slot {$slot} of {-yubikey} with serial {$yubikey_serial}: {$err}
+
 ## Errors
 
 err-mgmt-key-auth = Failed to authenticate with the PIN-protected management key.
 rec-mgmt-key-auth =
     Check whether your management key is using the TDES algorithm.
     AES is not supported yet: {$aes_url}
-err-custom-mgmt-key = Custom unprotected non-TDES management keys are not supported.
+err-custom-mgmt-key = The {-yubikey} uses a custom management key. Provide it with {-flag-mgmt-key-fd}, or run interactively to enter it.
+err-wrong-mgmt-key  = The {-yubikey} rejected the management key.
+err-aes-mgmt-key    = AES management keys are not supported yet: {$aes_url}
 rec-change-mgmt-key =
     You can use the {-yubikey} Manager CLI to change to a protected management key:
     {"  "}{$cmd}
@@ -199,21 +406,83 @@ rec-change-mgmt-key =
     See here for more information about {-yubikey} Manager:
     {"  "}{$url}
 
+err-all-needs-identity   = {-flag-all} can only be used with {-cmd-identity}.
+err-command-needs-slot   = {$command} requires {-flag-slot}.
+err-default-pin          = {-yubikey} with serial {$serial} uses the default PIN or PUK, which {-flag-require-nondefault-pin} doesn't allow.
+rec-default-pin          =
+    Change them with:
+    {"  "}{$pin_cmd}
+    {"  "}{$puk_cmd}
+err-default-mgmt-key     = The new management key is the default management key. Choose another one.
+err-file-exists          = {$path} already exists. Use {-flag-force} to overwrite it.
+err-final-pin-try        = The {-yubikey} with serial {$serial} has one {$pin_kind} try left, and a wrong {$pin_kind} would block it.
+rec-final-pin-try        =
+    If you are sure of the {$pin_kind}, use {-flag-risk-lockout} (or set {$env}=1 for
+    {-age} clients) to try it anyway.
+err-format-needs-list    = {-flag-format} can only be used with {-cmd-list} or {-cmd-list-all}.
+err-import-needs-key     = {-cmd-import} requires {-flag-key}.
+err-invalid-algorithm    = Invalid algorithm '{$algorithm}' (expected [{$expected}]).
+err-invalid-config       = Invalid configuration file {$path}: {$err}
+err-invalid-config-key   = Invalid setting '{$key}' (expected SECTION.KEY, such as defaults.serial).
+err-invalid-error-format = Invalid error format '{$format}' (expected [{$expected}]).
+err-invalid-attestation-ca = Invalid attestation CA file {$path} (expected a PEM-encoded certificate).
+err-invalid-escrow       = Invalid stub escrow file {$path}: {$err}
 err-invalid-flag-command = Flag '{$flag}' cannot be used with '{$command}'.
 err-invalid-flag-tui     = Flag '{$flag}' cannot be used with the interactive interface.
+err-invalid-identity     = Invalid {-yubikey} identity '{$identity}'.
+err-invalid-language     = Invalid language '{$language}' (expected a language tag such as en-US).
+err-invalid-list-format  = Invalid list format '{$format}' (expected [{$expected}]).
+err-invalid-log-format   = Invalid log format '{$format}' (expected [{$expected}]).
+err-invalid-log-level    = Invalid log level '{$level}' (expected a level such as debug, or directives such as usbip=trace).
+err-invalid-mgmt-key     = Invalid management key (expected 24 bytes, hex-encoded).
+err-invalid-pin-agent-ttl = Invalid PIN agent TTL '{$ttl}' (expected a number of seconds, at most {$max}).
 err-invalid-pin-policy   = Invalid PIN policy '{$policy}' (expected [{$expected}]).
-err-invalid-slot         = Invalid slot '{$slot}' (expected number between 1 and 20).
+err-invalid-private-key  = Invalid private key (expected a P-256 or P-384 key as PKCS #8 or SEC1 PEM).
+err-invalid-provision-spec = Invalid provisioning spec: {$err}
+err-invalid-public-key   = Invalid public key (expected a P-256 key as PEM, or as hex-encoded SEC1 or SPKI).
+err-invalid-recipient    = Invalid {-yubikey} recipient '{$recipient}'.
+err-invalid-slot         = Invalid slot '{$slot}' (expected number between 1 and 20, or 9a, 9c, 9d or 9e with {-flag-allow-standard-slots}).
 err-invalid-touch-policy = Invalid touch policy '{$policy}' (expected [{$expected}]).
+err-invalid-unattended-pin = The PIN provided for non-interactive use must be 6 to 8 characters long.
+err-invalid-wait-setting = Invalid value '{$value}' for {$setting} (expected a number, or 0 or 1 for {$unattended_env}).
 err-io-user              = Failed to get input from user: {$err}
 err-io                   = Failed to set up {-yubikey}: {$err}
-err-multiple-commands    = Only one of {-cmd-generate}, {-cmd-identity}, {-cmd-list}, {-cmd-list-all} can be specified.
+err-multiple-commands    = Only one of {-cmd-attest}, {-cmd-change-mgmt-key}, {-cmd-change-pin}, {-cmd-change-puk}, {-cmd-config}, {-cmd-delete}, {-cmd-discover}, {-cmd-export-recipients}, {-cmd-export-stubs}, {-cmd-forget-pins}, {-cmd-generate}, {-cmd-identity}, {-cmd-import}, {-cmd-import-stubs}, {-cmd-info}, {-cmd-init-card}, {-cmd-interactive}, {-cmd-list}, {-cmd-list-all}, {-cmd-provision}, {-cmd-recipient-from}, {-cmd-rename}, {-cmd-repair}, {-cmd-ssh-agent}, {-cmd-unblock-pin}, {-cmd-verify} can be specified.
 err-multiple-yubikeys    = Multiple {-yubikeys} are plugged in. Use {-flag-serial} to select a single {-yubikey}.
+err-no-attestation       = The key in slot {$slot} can't be attested (only keys generated on the {-yubikey} can).
 err-no-empty-slots       = {-yubikey} with serial {$serial} has no empty slots.
+err-no-identities        = {-yubikey} with serial {$serial} does not contain any {-age} identities or compatible keys.
 err-no-matching-serial   = Could not find {-yubikey} with serial {$serial}.
+err-provision-failed     = {$count ->
+    [one] One {-yubikey} was
+   *[other] {$count} {-yubikeys} were
+} not fully provisioned.
+err-provision-needs-pin  = replace_default_pin requires a PIN provided with {-flag-unattended-pin}.
+err-read-only            = '{$command}' would change the {-yubikey}, which {-flag-read-only} (or read_only in the configuration file) forbids.
+err-read-only-tui        = The interactive interface can change the {-yubikey}, which defaults.read_only in the configuration file forbids.
+rec-read-only-tui        =
+    Use a command such as {-cmd-list} instead, or clear the setting with '{-cmd-config} defaults.read_only false'.
+err-recipient-not-found  = No connected {-yubikey} holds the identity for recipient '{$recipient}'.
+rec-recipient-not-found  =
+    If its key was not generated by {-age-plugin-yubikey}, search with {-cmd-list-all} instead.
+err-remote-not-built     = This build of {-age-plugin-yubikey} can't use remote {-yubikeys} ({-flag-remote}, AGE_YUBIKEY_REMOTE, or the configuration file). Rebuild it with the 'remote' feature.
+err-rename-needs-name    = {-cmd-rename} requires {-flag-name}.
+err-slot-has-certificate = The key in slot {$slot} already has a certificate. Use {-flag-force} to replace it.
 err-slot-has-no-identity = Slot {$slot} does not contain an {-age} identity or compatible key.
 err-slot-is-not-empty    = Slot {$slot} is not empty. Use {-flag-force} to overwrite the slot.
+err-slot-key-mismatch    = The key in slot {$slot} does not match the slot's certificate.
+err-ssh-agent-not-supported = {-cmd-ssh-agent} is not supported on this platform yet.
+err-stub-mismatch        = An identity does not match the key in slot {$slot}.
 err-timed-out            = Timed out while waiting for a {-yubikey} to be inserted.
+err-unattended-default-pin = The {-yubikey} is using the default PIN, which can't be changed non-interactively.
+rec-unattended-default-pin =
+    Change the PIN first, either by running {-age-plugin-yubikey} without a PIN source,
+    or with this command: {$cmd}
+err-unattended-pin-not-allowed = Reading the PIN from a file or file descriptor requires {-flag-unattended-pin}.
+err-unexpected-argument  = Unexpected argument '{$arg}'.
+err-unknown-slot-policies = Could not determine the PIN and touch policies of the key in slot {$slot}.
 err-use-list-for-single  = Use {-cmd-list} to print the recipient for a single slot.
+err-verification-failed  = The identity in slot {$slot} failed verification.
 
 err-yk-no-service-macos = The Crypto Token Kit service is not running.
 rec-yk-no-service-macos =
@@ -236,7 +505,14 @@ rec-yk-no-service-win =
     See this troubleshooting guide for more help:
     {"  "}{$url}
 
+err-yk-sharing-violation = Another program has exclusive access to the {-yubikey}.
+rec-yk-sharing-violation =
+    If GnuPG is using the {-yubikey}, you can release it with:
+    {"  "}{$cmd}
+
 err-yk-not-found         = Please insert the {-yubikey} you want to set up
+err-yk-removed           = The {-yubikey} was removed while it was in use.
+rec-yk-removed           = Keep the {-yubikey} plugged in until the command finishes, and try again.
 err-yk-general           = Error while communicating with {-yubikey}: {$err}
 err-yk-general-cause     = Cause: {$inner_err}
 
@@ -245,6 +521,15 @@ err-yk-wrong-pin = Invalid {$pin_kind} ({$tries ->
    *[other] {$tries} tries remaining
 } before it is blocked)
 err-yk-pin-locked = {$pin_kind} locked
+rec-yk-pin-locked =
+    You can unblock the PIN with the PUK using:
+    {"  "}{$cmd}
+rec-yk-puk-locked =
+    If both the PIN and PUK are locked, the PIV applet can only be reset, which
+    deletes all keys stored in it:
+    {"  "}{$cmd}
+
+err-caused-by = Caused by: {$err}
 
 err-ux-A = Did this not do what you expected? Could an error be more useful?
 err-ux-B = Tell us
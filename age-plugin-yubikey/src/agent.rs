@@ -0,0 +1,251 @@
+//! Per-user agent that caches verified PINs for a limited time.
+//!
+//! Identities with a PIN policy of `always` need the PIN for every decryption, and age
+//! clients start a new plugin instance for every file, so decrypting many files would
+//! otherwise ask for the PIN again and again. When enabled (with a TTL in the
+//! configuration file or `AGE_YUBIKEY_PIN_AGENT_TTL`), plugin instances hand each PIN
+//! that the YubiKey accepted to an agent listening on a socket that only the current
+//! user can access, and ask it for the PIN before prompting. The agent keeps PINs in
+//! locked memory, forgets each one when its TTL expires or when asked to, and exits
+//! once it has no PINs left and has been idle.
+//!
+//! The protocol is line based, like the broker's. A request is one of `get SERIAL`,
+//! `put SERIAL TTL_SECONDS HEX_PIN`, `forget SERIAL` or `forget all`. A response is
+//! either `ok` followed by optional hex-encoded data, or `err` followed by an error kind.
+
+use std::collections::HashMap;
+use std::fs;
+use std::io::{self, BufRead, BufReader, Write};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use age_core::secrecy::{zeroize::Zeroizing, SecretString};
+use tracing::debug;
+use yubikey::Serial;
+
+use crate::{
+    broker::{connect_or_start, socket_path},
+    util::LockedSecret,
+};
+
+const SOCKET: &str = "pin-agent.sock";
+
+/// How long the agent keeps running without any cached PIN.
+const IDLE_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// How long the agent waits for a client to send its request.
+const CLIENT_TIMEOUT: Duration = Duration::from_secs(1);
+
+fn request(stream: UnixStream, line: &str) -> Option<Zeroizing<String>> {
+    let mut writer = stream.try_clone().ok()?;
+    let mut reader = BufReader::new(stream);
+    let mut response = Zeroizing::new(String::new());
+    writer
+        .write_all(line.as_bytes())
+        .and_then(|()| writer.write_all(b"\n"))
+        .and_then(|()| reader.read_line(&mut response))
+        .map_err(|e| debug!("Lost connection to PIN agent: {}", e))
+        .ok()?;
+    let len = response.trim_end().len();
+    response.truncate(len);
+    Some(response)
+}
+
+fn connect() -> Option<UnixStream> {
+    UnixStream::connect(socket_path(SOCKET)?).ok()
+}
+
+/// Returns the PIN that the agent holds for this YubiKey, if it is running.
+pub(crate) fn get(serial: Serial) -> Option<SecretString> {
+    let response = request(connect()?, &format!("get {}", serial.0))?;
+    let pin = Zeroizing::new(hex::decode(response.strip_prefix("ok ")?).ok()?);
+    String::from_utf8(pin.to_vec()).ok().map(SecretString::new)
+}
+
+/// Hands a PIN that this YubiKey accepted to the agent, starting it if necessary.
+pub(crate) fn put(serial: Serial, pin: &str, ttl: Duration) {
+    let stream = match socket_path(SOCKET).and_then(|path| connect_or_start(&path, "--pin-agent")) {
+        Some(stream) => stream,
+        None => return,
+    };
+    let line = Zeroizing::new(format!(
+        "put {} {} {}",
+        serial.0,
+        ttl.as_secs(),
+        hex::encode(pin)
+    ));
+    if request(stream, &line).as_deref().map(String::as_str) != Some("ok") {
+        debug!("PIN agent did not accept the PIN for {}", serial);
+    }
+}
+
+/// Tells the agent to forget the PIN for this YubiKey, or every PIN. Does nothing if
+/// the agent isn't running.
+pub(crate) fn forget(serial: Option<Serial>) {
+    let line = match serial {
+        Some(serial) => format!("forget {}", serial.0),
+        None => "forget all".into(),
+    };
+    if let Some(stream) = connect() {
+        request(stream, &line);
+    }
+}
+
+struct Agent {
+    pins: HashMap<u32, (LockedSecret<String>, Instant)>,
+}
+
+impl Agent {
+    fn expire(&mut self) {
+        let now = Instant::now();
+        self.pins.retain(|_, (_, expiry)| *expiry > now);
+    }
+
+    fn handle(&mut self, line: &str) -> String {
+        self.expire();
+
+        let mut parts = line.split(' ');
+        let command = parts.next().unwrap_or_default();
+        let serial = parts.next();
+        let args: Vec<_> = parts.collect();
+        let serial = match (command, serial) {
+            ("forget", Some("all")) => {
+                self.pins.clear();
+                return "ok".into();
+            }
+            (_, Some(serial)) => match serial.parse::<u32>() {
+                Ok(serial) => serial,
+                Err(_) => return "err invalid".into(),
+            },
+            _ => return "err invalid".into(),
+        };
+
+        match (command, args.as_slice()) {
+            ("get", []) => match self.pins.get(&serial) {
+                Some((pin, _)) => format!("ok {}", hex::encode(pin.as_bytes())),
+                None => "err not-found".into(),
+            },
+            ("put", [ttl, pin]) => {
+                let ttl = ttl.parse().ok().map(Duration::from_secs);
+                let pin = hex::decode(pin)
+                    .ok()
+                    .and_then(|pin| String::from_utf8(pin).ok());
+                // A TTL too long to represent as an Instant is refused rather than
+                // panicking the agent.
+                let expiry = ttl.and_then(|ttl| Instant::now().checked_add(ttl));
+                match (expiry, pin) {
+                    (Some(expiry), Some(pin)) => {
+                        self.pins.insert(serial, (LockedSecret::new(pin), expiry));
+                        "ok".into()
+                    }
+                    _ => "err invalid".into(),
+                }
+            }
+            ("forget", []) => {
+                self.pins.remove(&serial);
+                "ok".into()
+            }
+            _ => "err invalid".into(),
+        }
+    }
+}
+
+fn serve(stream: UnixStream, agent: &mut Agent) -> io::Result<()> {
+    stream.set_read_timeout(Some(CLIENT_TIMEOUT))?;
+    let mut writer = stream.try_clone()?;
+    let mut reader = BufReader::new(stream);
+    loop {
+        let mut line = Zeroizing::new(String::new());
+        if reader.read_line(&mut line)? == 0 {
+            return Ok(());
+        }
+        let response = Zeroizing::new(agent.handle(line.trim_end()));
+        writeln!(writer, "{}", *response)?;
+    }
+}
+
+/// Runs the agent until it has held no PINs for [`IDLE_TIMEOUT`].
+pub(crate) fn run() -> io::Result<()> {
+    let path = socket_path(SOCKET)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::Other, "no usable socket directory"))?;
+
+    // Another plugin instance may have started an agent concurrently.
+    if UnixStream::connect(&path).is_ok() {
+        return Ok(());
+    }
+    let _ = fs::remove_file(&path);
+    let listener = UnixListener::bind(&path)?;
+    listener.set_nonblocking(true)?;
+
+    let mut agent = Agent {
+        pins: HashMap::new(),
+    };
+    let mut last_active = Instant::now();
+
+    loop {
+        match listener.accept() {
+            Ok((stream, _)) => {
+                // Requests are short, so clients are served one at a time.
+                stream.set_nonblocking(false)?;
+                if let Err(e) = serve(stream, &mut agent) {
+                    debug!("PIN agent client failed: {}", e);
+                }
+                last_active = Instant::now();
+            }
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                agent.expire();
+                if agent.pins.is_empty() && last_active.elapsed() >= IDLE_TIMEOUT {
+                    break;
+                }
+                thread::sleep(Duration::from_millis(100));
+            }
+            Err(e) => return Err(e),
+        }
+    }
+
+    let _ = fs::remove_file(&path);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::Agent;
+
+    #[test]
+    fn handle() {
+        let mut agent = Agent {
+            pins: HashMap::new(),
+        };
+        let pin = hex::encode("123456");
+
+        assert_eq!(agent.handle("get 1234"), "err not-found");
+        assert_eq!(agent.handle(&format!("put 1234 300 {pin}")), "ok");
+        assert_eq!(agent.handle("get 1234"), format!("ok {pin}"));
+        assert_eq!(agent.handle("get 5678"), "err not-found");
+
+        assert_eq!(agent.handle("forget 1234"), "ok");
+        assert_eq!(agent.handle("get 1234"), "err not-found");
+
+        assert_eq!(agent.handle(&format!("put 1234 300 {pin}")), "ok");
+        assert_eq!(agent.handle(&format!("put 5678 300 {pin}")), "ok");
+        assert_eq!(agent.handle("forget all"), "ok");
+        assert_eq!(agent.handle("get 5678"), "err not-found");
+
+        // PINs are forgotten once their TTL has passed.
+        assert_eq!(agent.handle(&format!("put 1234 0 {pin}")), "ok");
+        assert_eq!(agent.handle("get 1234"), "err not-found");
+
+        assert_eq!(agent.handle("put 1234 300"), "err invalid");
+        assert_eq!(agent.handle("put 1234 soon 00"), "err invalid");
+        assert_eq!(
+            agent.handle(&format!("put 1234 {} {pin}", u64::MAX)),
+            "err invalid"
+        );
+        assert_eq!(agent.handle("get"), "err invalid");
+        assert_eq!(agent.handle("get all"), "err invalid");
+        assert_eq!(agent.handle("unlock 1234"), "err invalid");
+    }
+}
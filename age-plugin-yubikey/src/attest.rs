@@ -0,0 +1,329 @@
+//! Exporting PIV attestations.
+//!
+//! A YubiKey can attest to a key it generated: the attestation is a certificate for the
+//! key in a slot, signed by the YubiKey's attestation certificate in slot f9, which is
+//! in turn signed by Yubico's PIV root CA. Together they let a third party check that
+//! an age recipient belongs to a key that cannot leave the YubiKey, and which policies
+//! guard it.
+//! https://developers.yubico.com/PIV/Introduction/PIV_attestation.html
+//!
+//! The root CA isn't built in: `--verify --attestation-ca PATH` checks the chain up to
+//! the PEM-encoded root at PATH, which Yubico publishes at
+//! https://developers.yubico.com/PIV/Introduction/piv-attestation-ca.pem
+
+use std::fmt;
+
+use base64::{prelude::BASE64_STANDARD, Engine};
+use p256::pkcs8::DecodePublicKey;
+use rsa::{PublicKey, RsaPublicKey};
+use sha2::{Digest, Sha256, Sha384};
+use x509_parser::{certificate::X509Certificate, der_parser::oid::Oid};
+use yubikey::{
+    certificate::Certificate,
+    piv::{attest, SlotId},
+    PinPolicy, TouchPolicy, YubiKey,
+};
+
+use crate::{error::Error, fl, p256::Recipient, util};
+
+const FIRMWARE_EXTENSION_OID: &[u64] = &[1, 3, 6, 1, 4, 1, 41482, 3, 3];
+const SERIAL_EXTENSION_OID: &[u64] = &[1, 3, 6, 1, 4, 1, 41482, 3, 7];
+
+const SHA256_WITH_RSA_OID: &[u64] = &[1, 2, 840, 113549, 1, 1, 11];
+const SHA384_WITH_RSA_OID: &[u64] = &[1, 2, 840, 113549, 1, 1, 12];
+const ECDSA_WITH_SHA256_OID: &[u64] = &[1, 2, 840, 10045, 4, 3, 2];
+const ECDSA_WITH_SHA384_OID: &[u64] = &[1, 2, 840, 10045, 4, 3, 3];
+
+/// An attestation for a slot, along with the certificate that signed it.
+pub(crate) struct Attestation {
+    slot: SlotId,
+    cert: Vec<u8>,
+    intermediate: Vec<u8>,
+}
+
+impl Attestation {
+    /// Asks the YubiKey to attest to the key in `slot`.
+    pub(crate) fn read(yubikey: &mut YubiKey, slot: SlotId) -> Result<Self, Error> {
+        // Keys that were imported rather than generated on the YubiKey can't be attested.
+        let cert = attest(yubikey, slot)
+            .map_err(|_| Error::NoAttestation(slot))?
+            .to_vec();
+        let intermediate = Certificate::read(yubikey, SlotId::Attestation)?
+            .as_ref()
+            .to_vec();
+        Ok(Attestation {
+            slot,
+            cert,
+            intermediate,
+        })
+    }
+
+    /// Returns the attestation followed by its issuer, as PEM-encoded certificates.
+    pub(crate) fn to_pem(&self) -> String {
+        [&self.cert, &self.intermediate]
+            .iter()
+            .map(|der| {
+                let encoded = BASE64_STANDARD.encode(der);
+                let lines: Vec<_> = encoded
+                    .as_bytes()
+                    .chunks(64)
+                    .map(|line| std::str::from_utf8(line).unwrap())
+                    .collect();
+                format!(
+                    "-----BEGIN CERTIFICATE-----\n{}\n-----END CERTIFICATE-----\n",
+                    lines.join("\n")
+                )
+            })
+            .collect()
+    }
+
+    /// Parses the attestation, and checks it against its issuer and the recipient for
+    /// the slot.
+    pub(crate) fn summarize(&self, recipient: Option<&Recipient>) -> Result<Summary, Error> {
+        let (_, cert) = x509_parser::parse_x509_certificate(&self.cert)
+            .map_err(|_| Error::NoAttestation(self.slot))?;
+        let (_, intermediate) = x509_parser::parse_x509_certificate(&self.intermediate)
+            .map_err(|_| Error::NoAttestation(self.slot))?;
+
+        let (pin_policy, touch_policy) = util::extract_policies(&cert);
+        let attested = Recipient::from_spki_der(cert.public_key().raw);
+
+        Ok(Summary {
+            slot: self.slot,
+            serial: extract_serial(&cert),
+            pin_policy,
+            touch_policy,
+            firmware: extract_firmware(&cert),
+            issued_by_yubikey: signed_by(&cert, &intermediate),
+            matches_recipient: recipient.map(|recipient| {
+                attested.map(|pk| pk.to_encoded()) == Some(recipient.to_encoded())
+            }),
+        })
+    }
+
+    /// Checks that the YubiKey's attestation certificate, which signed the attestation,
+    /// was issued by `root`, a DER-encoded CA certificate.
+    pub(crate) fn chains_to(&self, root: &[u8]) -> bool {
+        match (
+            x509_parser::parse_x509_certificate(&self.intermediate),
+            x509_parser::parse_x509_certificate(root),
+        ) {
+            (Ok((_, intermediate)), Ok((_, root))) => signed_by(&intermediate, &root),
+            _ => false,
+        }
+    }
+}
+
+/// Parses the PEM-encoded CA certificate in `pem`, returning it DER-encoded.
+pub(crate) fn parse_ca(pem: &str) -> Option<Vec<u8>> {
+    let (_, pem) = x509_parser::pem::parse_x509_pem(pem.as_bytes()).ok()?;
+    x509_parser::parse_x509_certificate(&pem.contents).ok()?;
+    Some(pem.contents)
+}
+
+/// Checks that `cert` was issued by `issuer`, and that its signature is valid for the
+/// public key of `issuer`.
+///
+/// Only the algorithms that YubiKeys and Yubico's CAs use are supported: RSA with
+/// PKCS #1 v1.5 padding, and ECDSA with P-256 or P-384, over SHA-256 or SHA-384.
+fn signed_by(cert: &X509Certificate, issuer: &X509Certificate) -> bool {
+    if cert.issuer().as_raw() != issuer.subject().as_raw() {
+        return false;
+    }
+    let tbs = cert.tbs_certificate.as_ref();
+    let signature = cert.signature_value.data.as_ref();
+    let key = issuer.public_key().raw;
+    let algorithm = &cert.signature_algorithm.algorithm;
+
+    let rsa = |hashed: &[u8], padding| {
+        RsaPublicKey::from_public_key_der(key)
+            .map_or(false, |pk| pk.verify(padding, hashed, signature).is_ok())
+    };
+    let ecdsa = |prehash: &[u8]| {
+        use p256::ecdsa::signature::hazmat::PrehashVerifier;
+        if let Ok(vk) = p256::ecdsa::VerifyingKey::from_public_key_der(key) {
+            p256::ecdsa::Signature::from_der(signature)
+                .map_or(false, |sig| vk.verify_prehash(prehash, &sig).is_ok())
+        } else if let Ok(vk) = p384::ecdsa::VerifyingKey::from_public_key_der(key) {
+            p384::ecdsa::Signature::from_der(signature)
+                .map_or(false, |sig| vk.verify_prehash(prehash, &sig).is_ok())
+        } else {
+            false
+        }
+    };
+
+    if *algorithm == Oid::from(SHA256_WITH_RSA_OID).unwrap() {
+        rsa(&Sha256::digest(tbs), rsa::Pkcs1v15Sign::new::<Sha256>())
+    } else if *algorithm == Oid::from(SHA384_WITH_RSA_OID).unwrap() {
+        rsa(&Sha384::digest(tbs), rsa::Pkcs1v15Sign::new::<Sha384>())
+    } else if *algorithm == Oid::from(ECDSA_WITH_SHA256_OID).unwrap() {
+        ecdsa(&Sha256::digest(tbs))
+    } else if *algorithm == Oid::from(ECDSA_WITH_SHA384_OID).unwrap() {
+        ecdsa(&Sha384::digest(tbs))
+    } else {
+        false
+    }
+}
+
+fn extension<'a>(cert: &'a X509Certificate, oid: &[u64]) -> Option<&'a [u8]> {
+    cert.tbs_certificate
+        .get_extension_unique(&Oid::from(oid).unwrap())
+        .ok()
+        .flatten()
+        .map(|ext| ext.value)
+}
+
+/// The serial is stored as a DER-encoded INTEGER.
+fn extract_serial(cert: &X509Certificate) -> Option<u32> {
+    match extension(cert, SERIAL_EXTENSION_OID)? {
+        [0x02, len, bytes @ ..] if *len as usize == bytes.len() && bytes.len() <= 5 => bytes
+            .iter()
+            .fold(0u64, |acc, b| (acc << 8) | u64::from(*b))
+            .try_into()
+            .ok(),
+        _ => None,
+    }
+}
+
+/// The firmware version is stored as three raw bytes.
+fn extract_firmware(cert: &X509Certificate) -> Option<String> {
+    match extension(cert, FIRMWARE_EXTENSION_OID)? {
+        [major, minor, patch] => Some(format!("{major}.{minor}.{patch}")),
+        _ => None,
+    }
+}
+
+/// What an attestation says about the key in a slot.
+pub(crate) struct Summary {
+    slot: SlotId,
+    pub(crate) serial: Option<u32>,
+    pub(crate) pin_policy: Option<PinPolicy>,
+    pub(crate) touch_policy: Option<TouchPolicy>,
+    firmware: Option<String>,
+    pub(crate) issued_by_yubikey: bool,
+    /// `None` if the slot has no certificate to compare with.
+    matches_recipient: Option<bool>,
+}
+
+impl fmt::Display for Summary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let unknown = fl!("attest-unknown");
+        let serial = self
+            .serial
+            .map_or_else(|| unknown.clone(), |s| s.to_string());
+        let firmware = self.firmware.clone().unwrap_or(unknown);
+        writeln!(
+            f,
+            "{}",
+            fl!(
+                "attest-summary",
+                slot = util::slot_name(self.slot),
+                serial = serial,
+                pin_policy = util::pin_policy_to_str(self.pin_policy),
+                touch_policy = util::touch_policy_to_str(self.touch_policy),
+                firmware = firmware,
+            )
+        )?;
+        writeln!(
+            f,
+            "{}",
+            if self.issued_by_yubikey {
+                fl!("attest-issuer-ok")
+            } else {
+                fl!("attest-issuer-mismatch")
+            }
+        )?;
+        match self.matches_recipient {
+            Some(true) => write!(f, "{}", fl!("attest-key-ok")),
+            Some(false) => write!(f, "{}", fl!("attest-key-mismatch")),
+            None => write!(f, "{}", fl!("attest-key-no-cert")),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use yubikey::piv::{RetiredSlotId, SlotId};
+
+    use super::{parse_ca, Attestation};
+
+    // A test chain made with OpenSSL: an RSA root and attestation certificate, as
+    // Yubico's are, and a P-256 attestation for YubiKey 12345678.
+    const ROOT: &str = "-----BEGIN CERTIFICATE-----
+MIICFDCCAX2gAwIBAgIUVVnll7surGNJj4iDgcq6umpogKMwDQYJKoZIhvcNAQEL
+BQAwGzEZMBcGA1UEAwwQVGVzdCBQSVYgUm9vdCBDQTAgFw0yNjEwMTUxNDI5NTBa
+GA8yMTI2MDkyMTE0Mjk1MFowGzEZMBcGA1UEAwwQVGVzdCBQSVYgUm9vdCBDQTCB
+nzANBgkqhkiG9w0BAQEFAAOBjQAwgYkCgYEAu9sENF03t4bD/frZqleu5OF3xxzq
+rFCF0hTaqvjkUtLBAqaKxYPgyoHZhG9tdvNDbvy6BvGGzXTDb4MwoxosrmcFjbtt
+HUNDHCfA7jICXjLU0yX4deLyI9kSzAZQ02XGvkhCtExp3P48eZzqYLNfiu8zoC4f
+wB1rkxqlHvR0PNcCAwEAAaNTMFEwHQYDVR0OBBYEFMK0zqe+wucNnNkJaEf6MRNt
+N4P8MB8GA1UdIwQYMBaAFMK0zqe+wucNnNkJaEf6MRNtN4P8MA8GA1UdEwEB/wQF
+MAMBAf8wDQYJKoZIhvcNAQELBQADgYEAUdOAficq0s89Rr1NKaxRQO6YcFJNED8f
+rur2H2N5FhJ7T0igeMvIG4CEaI7zQuLhhGFLRk7yiIhaAGOkl6jeEVeqgCHhEfis
+y5CWs6R8tcNi4lcLkWSmMXvoDZf1IF7vHKiKJhVQjInUyxIUZ0w+EJDR5sH7xJuH
+h7RqCLo81N0=
+-----END CERTIFICATE-----";
+    const INTERMEDIATE: &str = "-----BEGIN CERTIFICATE-----
+MIICFTCCAX6gAwIBAgIUbrGpdUwZVVUCxy9RqcRmbZNAttUwDQYJKoZIhvcNAQEL
+BQAwGzEZMBcGA1UEAwwQVGVzdCBQSVYgUm9vdCBDQTAgFw0yNjEwMTUxNDI5NTBa
+GA8yMTI2MDkyMTE0Mjk1MFowHzEdMBsGA1UEAwwUVGVzdCBQSVYgQXR0ZXN0YXRp
+b24wgZ8wDQYJKoZIhvcNAQEBBQADgY0AMIGJAoGBAMYLOjoTjuak8aKEfjFpN3GM
+UQIhvAqa3jdHoCyrnAtshpHhXjEJo66yJJl0QFyTT+3vurUsUaFXght3PLI8olwC
+efzJIIkL7oN6fvC7VOu9NOsdzEeUa850m4p/EViDg9NGzhKwesaT0Imz1fUwvovt
+o7bsRd5Wx7pGr8z3XoOxAgMBAAGjUDBOMAwGA1UdEwQFMAMBAf8wHQYDVR0OBBYE
+FHcbKkvg98GjwSqfwT2DoPii5QM/MB8GA1UdIwQYMBaAFMK0zqe+wucNnNkJaEf6
+MRNtN4P8MA0GCSqGSIb3DQEBCwUAA4GBACzLOoPAE78zrLjJdtB+dchxaPNmYWSD
+ak0i3tgGk7xJUiTZEGMWlmzQJDQgQUrnmM5OwnhPvpUd/Uz/RpP+CcIvyTUDw4Yf
+yg/3bMQcuSwdekCI5JWUV5eZlMT2PUIzOsIXslR+MZo0E3mpdmIRj0Wg4ewwvI+Q
+mbzByEth2fBd
+-----END CERTIFICATE-----";
+    const ATTESTATION: &str = "-----BEGIN CERTIFICATE-----
+MIIB7zCCAVigAwIBAgIUX1bx8hQaWq8lCkH+wJsC/JIrX7AwDQYJKoZIhvcNAQEL
+BQAwHzEdMBsGA1UEAwwUVGVzdCBQSVYgQXR0ZXN0YXRpb24wIBcNMjYxMDE1MTQy
+OTUwWhgPMjEyNjA5MjExNDI5NTBaMCIxIDAeBgNVBAMMF1Rlc3QgUElWIEF0dGVz
+dGF0aW9uIDlhMFkwEwYHKoZIzj0CAQYIKoZIzj0DAQcDQgAEU3nd4ARrW5obyMqt
+hvob8mrgsmeU36GOEsEARqWkzF7oorvxQ81RfiEw7AoWsXglXC2xzZnga/IxL2vN
+v0AzOqNqMGgwEAYKKwYBBAGCxAoDCAQCAgMwFAYKKwYBBAGCxAoDBwQGAgQAvGFO
+MB0GA1UdDgQWBBT32ce8wmXw+3th0U/s92dlpu4t+zAfBgNVHSMEGDAWgBR3GypL
+4PfBo8Eqn8E9g6D4ouUDPzANBgkqhkiG9w0BAQsFAAOBgQCWKzn0xtc7ZleVEXTb
+2jxTJ0HIvI/BiMQsiyw64S48nUokhcyZDiSMmjp4LZ2ax3FRoNqD1mIVkjZYkE7s
+mHEN1QO3/Dlt7ToHTbErcy658m7CE4ChfPkXqaG+6mygepmpR2ZoI/UVWnUj71Kf
+itUB9z3rrjcI7hFm0H+teg4x+Q==
+-----END CERTIFICATE-----";
+
+    fn attestation(cert: &str, intermediate: &str) -> Attestation {
+        Attestation {
+            slot: SlotId::Retired(RetiredSlotId::R1),
+            cert: parse_ca(cert).unwrap(),
+            intermediate: parse_ca(intermediate).unwrap(),
+        }
+    }
+
+    #[test]
+    fn chain_signatures() {
+        let root = parse_ca(ROOT).unwrap();
+        let chain = attestation(ATTESTATION, INTERMEDIATE);
+        let summary = chain.summarize(None).unwrap();
+        assert!(summary.issued_by_yubikey);
+        assert_eq!(summary.serial, Some(12345678));
+        assert!(chain.chains_to(&root));
+
+        // The root didn't sign the attestation, and the chain needs the right root.
+        let skipped = attestation(ATTESTATION, ROOT);
+        assert!(!skipped.summarize(None).unwrap().issued_by_yubikey);
+        assert!(!attestation(ATTESTATION, ATTESTATION).chains_to(&root));
+        assert!(!chain.chains_to(&parse_ca(INTERMEDIATE).unwrap()));
+
+        // A tampered signature doesn't verify.
+        let mut tampered = parse_ca(ATTESTATION).unwrap();
+        let last = tampered.len() - 1;
+        tampered[last] ^= 1;
+        let tampered = Attestation {
+            cert: tampered,
+            ..attestation(ATTESTATION, INTERMEDIATE)
+        };
+        assert!(!tampered.summarize(None).unwrap().issued_by_yubikey);
+
+        assert!(parse_ca("not a certificate").is_none());
+    }
+}
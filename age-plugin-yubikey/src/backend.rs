@@ -0,0 +1,366 @@
+//! Backends that hold the private keys for age identities.
+//!
+//! The plugin's decryption path only needs a few operations from whatever holds the key
+//! for a stub: read the public key and policies of a slot, verify the PIN, and perform
+//! ECDH. [`IdentityBackend`] abstracts over these, so that the same path works for
+//! a locally-attached YubiKey, a YubiKey opened on our behalf by the broker, a YubiKey
+//! shared by a remote daemon, and keys held in software.
+
+use std::collections::HashMap;
+use std::thread;
+
+use yubikey::{
+    certificate::Certificate,
+    piv::{decrypt_data, sign_data, AlgorithmId, SlotId},
+    Buffer, Serial, YubiKey,
+};
+
+use crate::{
+    key,
+    p256::{Curve, Recipient},
+    util::Metadata,
+};
+
+#[cfg(unix)]
+use crate::broker::{BrokerClient, BrokeredYubiKey};
+#[cfg(feature = "remote")]
+use crate::remote::RemoteYubiKey;
+
+/// Something that can perform ECDH with the key for a stub.
+///
+/// Backends are `Send` so that several YubiKeys can be opened at once; see [`open_all`].
+pub(crate) trait IdentityBackend: Send {
+    /// Returns the serial of the YubiKey holding the keys.
+    fn serial(&self) -> Serial;
+
+    /// Returns the recipient for the key in `slot`, if it is compatible with this plugin.
+    fn recipient(&mut self, slot: SlotId) -> Option<Recipient>;
+
+    /// Returns the metadata for the key in `slot`.
+    fn metadata(&mut self, slot: SlotId) -> Option<Metadata>;
+
+    /// Verifies the PIN. An empty PIN checks whether the PIN has already been verified.
+    fn verify_pin(&mut self, pin: &[u8]) -> Result<(), yubikey::Error>;
+
+    /// Returns the number of PIN tries left. This also forgets a verified PIN.
+    fn pin_tries(&mut self) -> Result<u8, yubikey::Error>;
+
+    /// Performs ECDH between the key in `slot` and `point`, which is in its uncompressed
+    /// SEC-1 encoding (and thus also identifies the curve), returning the shared secret.
+    ///
+    /// `on_touch` is called if the backend learns that the key is waiting for a touch
+    /// that the user may not otherwise notice, as with remote YubiKeys.
+    fn decrypt(
+        &mut self,
+        slot: SlotId,
+        point: &[u8],
+        on_touch: &mut dyn FnMut(),
+    ) -> Result<Buffer, yubikey::Error>;
+
+    /// Signs `digest` with the key in `slot`, returning the DER-encoded ECDSA
+    /// signature. Only local YubiKeys can sign.
+    fn sign(
+        &mut self,
+        _slot: SlotId,
+        _algorithm: AlgorithmId,
+        _digest: &[u8],
+    ) -> Result<Buffer, yubikey::Error> {
+        Err(yubikey::Error::NotSupported)
+    }
+
+    /// Releases the backend while preserving any PIN and touch caches.
+    fn disconnect_without_reset(self: Box<Self>);
+}
+
+/// Opens the YubiKey with the given serial, from the remote daemon if one is configured,
+/// or else via the broker if it is available.
+pub(crate) fn open(serial: Serial) -> Result<Box<dyn IdentityBackend>, yubikey::Error> {
+    #[cfg(feature = "remote")]
+    if let Some(config) = crate::config::remote() {
+        return RemoteYubiKey::open(&config, serial).map(|yubikey| Box::new(yubikey) as _);
+    }
+    #[cfg(unix)]
+    if let Some(client) = BrokerClient::connect() {
+        return BrokeredYubiKey::open(client, serial).map(|yubikey| Box::new(yubikey) as _);
+    }
+    key::open_by_serial(serial).map(|yubikey| Box::new(yubikey) as _)
+}
+
+/// Opens the YubiKeys with the given serials concurrently, each over its own
+/// connection, returning those that could be opened.
+///
+/// Looking for a YubiKey that isn't plugged in can take a while, particularly through
+/// the broker or a remote daemon; opening them all at once means that the YubiKeys the
+/// user has at hand are found without waiting on the others.
+pub(crate) fn open_all(serials: &[Serial]) -> HashMap<u32, Box<dyn IdentityBackend>> {
+    thread::scope(|scope| {
+        let handles: Vec<_> = serials
+            .iter()
+            .map(|&serial| (serial, scope.spawn(move || open(serial))))
+            .collect();
+        handles
+            .into_iter()
+            .filter_map(|(serial, handle)| match handle.join() {
+                Ok(Ok(backend)) => Some((serial.0, backend)),
+                _ => None,
+            })
+            .collect()
+    })
+}
+
+impl IdentityBackend for YubiKey {
+    fn serial(&self) -> Serial {
+        YubiKey::serial(self)
+    }
+
+    fn recipient(&mut self, slot: SlotId) -> Option<Recipient> {
+        Certificate::read(self, slot)
+            .ok()
+            .and_then(|cert| Recipient::from_certificate(&cert))
+    }
+
+    fn metadata(&mut self, slot: SlotId) -> Option<Metadata> {
+        let cert = Certificate::read(self, slot).ok()?;
+        Metadata::extract(self, slot, &cert, true)
+    }
+
+    fn verify_pin(&mut self, pin: &[u8]) -> Result<(), yubikey::Error> {
+        YubiKey::verify_pin(self, pin)
+    }
+
+    fn pin_tries(&mut self) -> Result<u8, yubikey::Error> {
+        self.get_pin_retries()
+    }
+
+    fn decrypt(
+        &mut self,
+        slot: SlotId,
+        point: &[u8],
+        _: &mut dyn FnMut(),
+    ) -> Result<Buffer, yubikey::Error> {
+        let curve = Curve::from_input_len(point.len()).ok_or(yubikey::Error::SizeError)?;
+        decrypt_data(self, point, curve.algorithm(), slot)
+    }
+
+    fn sign(
+        &mut self,
+        slot: SlotId,
+        algorithm: AlgorithmId,
+        digest: &[u8],
+    ) -> Result<Buffer, yubikey::Error> {
+        sign_data(self, digest, algorithm, slot)
+    }
+
+    fn disconnect_without_reset(self: Box<Self>) {
+        key::disconnect_without_reset(*self);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    use age_core::{
+        format::FileKey,
+        secrecy::{zeroize::Zeroizing, ExposeSecret, SecretString},
+    };
+    use age_plugin::{identity, Callbacks};
+    use p256::{ecdh::diffie_hellman, elliptic_curve::sec1::ToEncodedPoint, SecretKey};
+    use rand::rngs::OsRng;
+    use yubikey::{
+        piv::{RetiredSlotId, SlotId},
+        Buffer, PinPolicy, Serial,
+    };
+
+    use super::IdentityBackend;
+    use crate::{format::RecipientLine, key::Connection, p256::Recipient, util::Metadata};
+
+    const PIN: &str = "654321";
+
+    /// A software key that stands in for a YubiKey slot.
+    struct SoftwareKey {
+        slot: SlotId,
+        secret: SecretKey,
+        /// `None` for a slot without a PIN policy, whose metadata can't be read.
+        pin_policy: Option<PinPolicy>,
+        verified: bool,
+        verifications: Arc<AtomicUsize>,
+    }
+
+    impl SoftwareKey {
+        fn public_recipient(&self) -> Recipient {
+            Recipient::from_sec1(self.secret.public_key().to_encoded_point(true).as_bytes())
+                .unwrap()
+        }
+    }
+
+    impl IdentityBackend for SoftwareKey {
+        fn serial(&self) -> Serial {
+            Serial::from(42)
+        }
+
+        fn recipient(&mut self, slot: SlotId) -> Option<Recipient> {
+            (slot == self.slot).then(|| self.public_recipient())
+        }
+
+        fn metadata(&mut self, slot: SlotId) -> Option<Metadata> {
+            self.pin_policy.map(|pin_policy| Metadata {
+                serial: self.serial(),
+                slot,
+                name: "software key".into(),
+                created: "today".into(),
+                pin_policy: Some(pin_policy),
+                touch_policy: None,
+                firmware: None,
+            })
+        }
+
+        fn verify_pin(&mut self, pin: &[u8]) -> Result<(), yubikey::Error> {
+            if pin.is_empty() {
+                return self
+                    .verified
+                    .then_some(())
+                    .ok_or(yubikey::Error::WrongPin { tries: 3 });
+            }
+            if pin != PIN.as_bytes() {
+                return Err(yubikey::Error::WrongPin { tries: 2 });
+            }
+            self.verifications.fetch_add(1, Ordering::Relaxed);
+            self.verified = true;
+            Ok(())
+        }
+
+        fn pin_tries(&mut self) -> Result<u8, yubikey::Error> {
+            Ok(3)
+        }
+
+        fn decrypt(
+            &mut self,
+            slot: SlotId,
+            point: &[u8],
+            _: &mut dyn FnMut(),
+        ) -> Result<Buffer, yubikey::Error> {
+            if slot != self.slot {
+                return Err(yubikey::Error::NotFound);
+            }
+            match self.pin_policy {
+                Some(PinPolicy::Never) | None => (),
+                _ if !self.verified => return Err(yubikey::Error::AuthenticationError),
+                // The PIN only unlocks one decryption.
+                Some(PinPolicy::Always) => self.verified = false,
+                _ => (),
+            }
+            let point =
+                p256::PublicKey::from_sec1_bytes(point).map_err(|_| yubikey::Error::ParseError)?;
+            let shared = diffie_hellman(self.secret.to_nonzero_scalar(), point.as_affine());
+            Ok(Zeroizing::new(shared.raw_secret_bytes().to_vec()))
+        }
+
+        fn disconnect_without_reset(self: Box<Self>) {}
+    }
+
+    /// Answers PIN requests with [`PIN`], counting them.
+    #[derive(Default)]
+    struct PinCallbacks {
+        prompts: usize,
+    }
+
+    impl Callbacks<identity::Error> for PinCallbacks {
+        fn message(&mut self, _: &str) -> age_core::plugin::Result<()> {
+            Ok(Ok(()))
+        }
+
+        fn confirm(&mut self, _: &str, _: &str, _: Option<&str>) -> age_core::plugin::Result<bool> {
+            Ok(Ok(true))
+        }
+
+        fn request_public(&mut self, _: &str) -> age_core::plugin::Result<String> {
+            Ok(Err(age_core::plugin::Error::Unsupported))
+        }
+
+        fn request_secret(&mut self, _: &str) -> age_core::plugin::Result<SecretString> {
+            self.prompts += 1;
+            Ok(Ok(SecretString::new(PIN.into())))
+        }
+
+        fn error(&mut self, _: identity::Error) -> age_core::plugin::Result<()> {
+            Ok(Ok(()))
+        }
+    }
+
+    fn software_key(slot: RetiredSlotId, pin_policy: Option<PinPolicy>) -> SoftwareKey {
+        SoftwareKey {
+            slot: SlotId::Retired(slot),
+            secret: SecretKey::random(&mut OsRng),
+            pin_policy,
+            verified: false,
+            verifications: Arc::default(),
+        }
+    }
+
+    #[test]
+    fn unwrap_with_software_key() {
+        let backend = software_key(RetiredSlotId::R1, None);
+        let recipient = backend.public_recipient();
+
+        let file_key = FileKey::from([7; 16]);
+        let line = RecipientLine::wrap_file_key(&file_key, &recipient);
+
+        let mut conn = Connection::new(
+            Box::new(backend),
+            recipient,
+            SlotId::Retired(RetiredSlotId::R1),
+            0,
+        );
+        let unwrapped = conn.unwrap_file_key(&line, || ()).unwrap();
+        assert_eq!(unwrapped.expose_secret(), &[7; 16]);
+        conn.disconnect_without_reset();
+    }
+
+    #[test]
+    fn one_pin_for_many_stanzas() {
+        let mut callbacks = PinCallbacks::default();
+        let file_keys = [FileKey::from([7; 16]), FileKey::from([8; 16])];
+
+        // A slot that needs the PIN for every decryption asks for it once.
+        let backend = software_key(RetiredSlotId::R1, Some(PinPolicy::Always));
+        let verifications = backend.verifications.clone();
+        let recipient = backend.public_recipient();
+        let mut conn = Connection::new(
+            Box::new(backend),
+            recipient.clone(),
+            SlotId::Retired(RetiredSlotId::R1),
+            0,
+        );
+        assert!(conn
+            .request_pin_if_necessary(&mut callbacks)
+            .unwrap()
+            .is_ok());
+        for file_key in &file_keys {
+            let line = RecipientLine::wrap_file_key(file_key, &recipient);
+            let unwrapped = conn.unwrap_file_key(&line, || ()).unwrap();
+            assert_eq!(unwrapped.expose_secret(), file_key.expose_secret());
+        }
+        assert_eq!(callbacks.prompts, 1);
+        assert_eq!(verifications.load(Ordering::Relaxed), 2);
+
+        // Another slot of the YubiKey uses the same PIN.
+        let backend = software_key(RetiredSlotId::R2, Some(PinPolicy::Once));
+        let recipient = backend.public_recipient();
+        let mut other = Connection::new(
+            Box::new(backend),
+            recipient.clone(),
+            SlotId::Retired(RetiredSlotId::R2),
+            1,
+        );
+        other.use_pin(conn.pin());
+        assert!(other
+            .request_pin_if_necessary(&mut callbacks)
+            .unwrap()
+            .is_ok());
+        let line = RecipientLine::wrap_file_key(&file_keys[0], &recipient);
+        assert!(other.unwrap_file_key(&line, || ()).is_ok());
+        assert_eq!(callbacks.prompts, 1);
+    }
+}
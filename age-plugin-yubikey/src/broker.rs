@@ -0,0 +1,476 @@
+//! Per-user broker that owns YubiKey connections on behalf of plugin instances.
+//!
+//! age clients may run several plugin processes at once (for example when decrypting
+//! many files in parallel). Each of them would otherwise open the YubiKey with
+//! exclusive access, and all but one would fail with a sharing violation. Instead,
+//! plugin instances send their card operations to a broker listening on a socket that
+//! only the current user can access. The first plugin instance starts the broker, which
+//! performs one operation at a time, keeps each YubiKey open (and thus its PIN cache
+//! warm) while any plugin instance is connected, and exits once it has been idle.
+//!
+//! The protocol is line based. A request is a command followed by the YubiKey serial and
+//! any arguments, separated by spaces. A response is either `ok` followed by optional
+//! hex-encoded data, or `err` followed by an error kind.
+//!
+//! Setting `AGE_PLUGIN_YUBIKEY_BROKER=0` disables the broker.
+
+use std::collections::{hash_map::Entry, HashMap};
+use std::env;
+use std::fs::{self, DirBuilder};
+use std::io::{self, BufRead, BufReader, Write};
+use std::os::unix::fs::{DirBuilderExt, MetadataExt, PermissionsExt};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use age_core::secrecy::zeroize::Zeroizing;
+use tracing::{debug, warn};
+use yubikey::{
+    certificate::Certificate,
+    piv::{decrypt_data, SlotId},
+    Buffer, Serial, YubiKey,
+};
+
+use crate::{
+    backend::IdentityBackend,
+    key::{disconnect_without_reset, is_removed, open_by_serial},
+    p256::{Curve, Recipient},
+    util::{self, Metadata},
+    BINARY_NAME,
+};
+
+/// How long the broker keeps running without any connected plugin instance.
+const IDLE_TIMEOUT: Duration = Duration::from_secs(30);
+
+const SOCKET: &str = "broker.sock";
+
+/// How long a plugin instance waits for a helper process it started to become available.
+const START_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Returns the path of a socket in the per-user runtime directory, which only the current
+/// user can access.
+pub(crate) fn socket_path(name: &str) -> Option<PathBuf> {
+    let uid = rustix::process::getuid().as_raw();
+    let dir = match env::var_os("XDG_RUNTIME_DIR") {
+        Some(dir) => PathBuf::from(dir).join(BINARY_NAME),
+        None => env::temp_dir().join(format!("{}-{}", BINARY_NAME, uid)),
+    };
+    // The directory may already exist, possibly created by someone else to listen in
+    // our place, so check it whether or not we created it.
+    let _ = DirBuilder::new().mode(0o700).create(&dir);
+    if let Err(reason) = check_private_dir(&dir, uid) {
+        warn!("Not using {}: {} {}", name, dir.display(), reason);
+        return None;
+    }
+    Some(dir.join(name))
+}
+
+/// Checks that `dir` is a directory (not a symlink to one) that only `uid` can access.
+fn check_private_dir(dir: &Path, uid: u32) -> Result<(), &'static str> {
+    let metadata = fs::symlink_metadata(dir).map_err(|_| "cannot be read")?;
+    if !metadata.file_type().is_dir() {
+        return Err("is not a directory");
+    }
+    if metadata.uid() != uid {
+        return Err("is owned by another user");
+    }
+    if metadata.permissions().mode() & 0o077 != 0 {
+        return Err("is accessible by others");
+    }
+    Ok(())
+}
+
+/// Connects to the helper process listening at `path`, starting it with `flag` if
+/// necessary.
+pub(crate) fn connect_or_start(path: &Path, flag: &str) -> Option<UnixStream> {
+    if let Ok(stream) = UnixStream::connect(path) {
+        return Some(stream);
+    }
+
+    debug!("Starting {} at {}", flag, path.display());
+    Command::new(env::current_exe().ok()?)
+        .arg(flag)
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn()
+        .map_err(|e| warn!("Could not start {}: {}", flag, e))
+        .ok()?;
+
+    let start = Instant::now();
+    loop {
+        thread::sleep(Duration::from_millis(20));
+        match UnixStream::connect(path) {
+            Ok(stream) => return Some(stream),
+            Err(_) if start.elapsed() < START_TIMEOUT => (),
+            Err(e) => {
+                warn!("Could not connect to {}: {}", path.display(), e);
+                return None;
+            }
+        }
+    }
+}
+
+fn enabled() -> bool {
+    env::var_os("AGE_PLUGIN_YUBIKEY_BROKER").map_or(true, |v| v != "0")
+}
+
+/// A plugin instance's connection to the broker.
+pub(crate) struct BrokerClient {
+    reader: BufReader<UnixStream>,
+    writer: UnixStream,
+}
+
+impl BrokerClient {
+    /// Connects to the broker, starting it if necessary.
+    ///
+    /// Returns `None` if the broker is disabled or cannot be started, in which case the
+    /// caller should access the YubiKey directly.
+    pub(crate) fn connect() -> Option<Self> {
+        if !enabled() {
+            return None;
+        }
+        let stream = connect_or_start(&socket_path(SOCKET)?, "--broker")?;
+
+        Some(BrokerClient {
+            reader: BufReader::new(stream.try_clone().ok()?),
+            writer: stream,
+        })
+    }
+
+    fn request(
+        &mut self,
+        command: &str,
+        serial: Serial,
+        args: &[&str],
+    ) -> Result<Vec<u8>, yubikey::Error> {
+        let mut line = Zeroizing::new(format!("{} {}", command, serial.0));
+        for arg in args {
+            line.push(' ');
+            line.push_str(arg);
+        }
+        line.push('\n');
+
+        let mut response = Zeroizing::new(String::new());
+        self.writer
+            .write_all(line.as_bytes())
+            .and_then(|()| self.reader.read_line(&mut response))
+            .map_err(|e| {
+                warn!("Lost connection to broker: {}", e);
+                yubikey::Error::GenericError
+            })?;
+
+        let mut parts = response.trim_end().split(' ');
+        match (parts.next(), parts.next(), parts.next()) {
+            (Some("ok"), None, _) => Ok(vec![]),
+            (Some("ok"), Some(data), _) => {
+                hex::decode(data).map_err(|_| yubikey::Error::ParseError)
+            }
+            (Some("err"), Some("not-found"), _) => Err(yubikey::Error::NotFound),
+            (Some("err"), Some("pin-locked"), _) => Err(yubikey::Error::PinLocked),
+            (Some("err"), Some("removed"), _) => Err(yubikey::Error::PcscError {
+                inner: Some(pcsc::Error::RemovedCard),
+            }),
+            (Some("err"), Some("wrong-pin"), Some(tries)) => Err(yubikey::Error::WrongPin {
+                tries: tries.parse().unwrap_or(0),
+            }),
+            _ => Err(yubikey::Error::GenericError),
+        }
+    }
+
+    fn open(&mut self, serial: Serial) -> Result<(), yubikey::Error> {
+        self.request("open", serial, &[]).map(|_| ())
+    }
+
+    fn read_certificate(
+        &mut self,
+        serial: Serial,
+        slot: SlotId,
+    ) -> Result<Certificate, yubikey::Error> {
+        let der = self.request("cert", serial, &[&u8::from(slot).to_string()])?;
+        Certificate::from_bytes(der)
+    }
+
+    fn attest(&mut self, serial: Serial, slot: SlotId) -> Result<Vec<u8>, yubikey::Error> {
+        self.request("attest", serial, &[&u8::from(slot).to_string()])
+    }
+
+    fn verify_pin(&mut self, serial: Serial, pin: &[u8]) -> Result<(), yubikey::Error> {
+        let pin = Zeroizing::new(hex::encode(pin));
+        self.request("verify-pin", serial, &[&pin]).map(|_| ())
+    }
+
+    fn pin_tries(&mut self, serial: Serial) -> Result<u8, yubikey::Error> {
+        self.request("pin-tries", serial, &[])?
+            .first()
+            .copied()
+            .ok_or(yubikey::Error::ParseError)
+    }
+
+    fn decrypt(
+        &mut self,
+        serial: Serial,
+        slot: SlotId,
+        point: &[u8],
+    ) -> Result<Buffer, yubikey::Error> {
+        self.request(
+            "decrypt",
+            serial,
+            &[&u8::from(slot).to_string(), &hex::encode(point)],
+        )
+        .map(Zeroizing::new)
+    }
+}
+
+/// A YubiKey that the broker has opened on our behalf.
+pub(crate) struct BrokeredYubiKey {
+    client: BrokerClient,
+    serial: Serial,
+}
+
+impl BrokeredYubiKey {
+    /// Asks the broker to open the YubiKey with the given serial.
+    pub(crate) fn open(mut client: BrokerClient, serial: Serial) -> Result<Self, yubikey::Error> {
+        client.open(serial)?;
+        Ok(BrokeredYubiKey { client, serial })
+    }
+}
+
+impl IdentityBackend for BrokeredYubiKey {
+    fn serial(&self) -> Serial {
+        self.serial
+    }
+
+    fn recipient(&mut self, slot: SlotId) -> Option<Recipient> {
+        self.client
+            .read_certificate(self.serial, slot)
+            .ok()
+            .and_then(|cert| Recipient::from_certificate(&cert))
+    }
+
+    fn metadata(&mut self, slot: SlotId) -> Option<Metadata> {
+        let cert = self.client.read_certificate(self.serial, slot).ok()?;
+        let (client, serial) = (&mut self.client, self.serial);
+        Metadata::extract_with(serial, slot, &cert, true, || {
+            client.attest(serial, slot).ok()
+        })
+    }
+
+    fn verify_pin(&mut self, pin: &[u8]) -> Result<(), yubikey::Error> {
+        self.client.verify_pin(self.serial, pin)
+    }
+
+    fn pin_tries(&mut self) -> Result<u8, yubikey::Error> {
+        self.client.pin_tries(self.serial)
+    }
+
+    fn decrypt(
+        &mut self,
+        slot: SlotId,
+        point: &[u8],
+        _: &mut dyn FnMut(),
+    ) -> Result<Buffer, yubikey::Error> {
+        self.client.decrypt(self.serial, slot, point)
+    }
+
+    fn disconnect_without_reset(self: Box<Self>) {
+        // The broker keeps the YubiKey open while it is in use.
+    }
+}
+
+struct Broker {
+    yubikeys: HashMap<u32, YubiKey>,
+    clients: usize,
+    last_active: Instant,
+}
+
+impl Broker {
+    fn yubikey(&mut self, serial: Serial) -> Result<&mut YubiKey, yubikey::Error> {
+        Ok(match self.yubikeys.entry(serial.0) {
+            Entry::Occupied(entry) => entry.into_mut(),
+            Entry::Vacant(entry) => entry.insert(open_by_serial(serial)?),
+        })
+    }
+
+    fn handle(&mut self, line: &str) -> String {
+        let mut parts = line.split(' ');
+        let command = parts.next().unwrap_or_default();
+        let serial = match parts.next().and_then(|s| s.parse::<u32>().ok()) {
+            Some(serial) => Serial::from(serial),
+            None => return "err invalid".into(),
+        };
+        let args: Vec<_> = parts.collect();
+        let slot = |i: usize| {
+            args.get(i)
+                .and_then(|s| s.parse::<u8>().ok())
+                .and_then(|s| SlotId::try_from(s).ok())
+                .filter(|&slot| util::is_identity_slot(slot))
+        };
+        let data = |i: usize| args.get(i).and_then(|s| hex::decode(s).ok());
+
+        let res = match (command, slot(0)) {
+            ("open", _) => self.yubikey(serial).map(|_| vec![]),
+            ("cert", Some(slot)) => self
+                .yubikey(serial)
+                .and_then(|yubikey| Certificate::read(yubikey, slot).map(|c| c.as_ref().to_vec())),
+            ("attest", Some(slot)) => self
+                .yubikey(serial)
+                .and_then(|yubikey| yubikey::piv::attest(yubikey, slot).map(|b| b.to_vec())),
+            ("verify-pin", _) => match data(0).map(Zeroizing::new) {
+                Some(pin) => self
+                    .yubikey(serial)
+                    .and_then(|yubikey| yubikey.verify_pin(&pin))
+                    .map(|()| vec![]),
+                None => return "err invalid".into(),
+            },
+            ("pin-tries", _) => self
+                .yubikey(serial)
+                .and_then(|yubikey| yubikey.get_pin_retries())
+                .map(|tries| vec![tries]),
+            ("decrypt", Some(slot)) => match data(1) {
+                Some(point) => self.yubikey(serial).and_then(|yubikey| {
+                    let curve =
+                        Curve::from_input_len(point.len()).ok_or(yubikey::Error::SizeError)?;
+                    decrypt_data(yubikey, &point, curve.algorithm(), slot).map(|b| b.to_vec())
+                }),
+                None => return "err invalid".into(),
+            },
+            _ => return "err invalid".into(),
+        };
+
+        match res {
+            Ok(data) if data.is_empty() => "ok".into(),
+            Ok(data) => format!("ok {}", hex::encode(data)),
+            Err(yubikey::Error::NotFound) => "err not-found".into(),
+            Err(yubikey::Error::PinLocked) => "err pin-locked".into(),
+            Err(yubikey::Error::WrongPin { tries }) => format!("err wrong-pin {tries}"),
+            Err(e) => {
+                // The YubiKey may have been removed; reopen it on the next request.
+                debug!("Broker request {} failed: {}", command, e);
+                if let Some(yubikey) = self.yubikeys.remove(&serial.0) {
+                    disconnect_without_reset(yubikey);
+                }
+                if is_removed(&e) {
+                    "err removed".into()
+                } else {
+                    "err other".into()
+                }
+            }
+        }
+    }
+
+    /// Lets other applications use the YubiKeys while no plugin instance needs them.
+    fn release(&mut self) {
+        for (_, yubikey) in self.yubikeys.drain() {
+            disconnect_without_reset(yubikey);
+        }
+    }
+}
+
+fn serve(stream: UnixStream, broker: &Mutex<Broker>) -> io::Result<()> {
+    let mut writer = stream.try_clone()?;
+    let mut reader = BufReader::new(stream);
+    loop {
+        let mut line = Zeroizing::new(String::new());
+        if reader.read_line(&mut line)? == 0 {
+            return Ok(());
+        }
+        // Holding the lock for the whole operation serializes access to the YubiKeys.
+        let response = broker.lock().unwrap().handle(line.trim_end());
+        writeln!(writer, "{response}")?;
+    }
+}
+
+/// Runs the broker until it has been idle for [`IDLE_TIMEOUT`].
+pub(crate) fn run() -> io::Result<()> {
+    let path = socket_path(SOCKET)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::Other, "no usable socket directory"))?;
+
+    // Another plugin instance may have started a broker concurrently.
+    if UnixStream::connect(&path).is_ok() {
+        return Ok(());
+    }
+    let _ = fs::remove_file(&path);
+    let listener = UnixListener::bind(&path)?;
+    listener.set_nonblocking(true)?;
+
+    let broker = Arc::new(Mutex::new(Broker {
+        yubikeys: HashMap::new(),
+        clients: 0,
+        last_active: Instant::now(),
+    }));
+
+    loop {
+        match listener.accept() {
+            Ok((stream, _)) => {
+                stream.set_nonblocking(false)?;
+                broker.lock().unwrap().clients += 1;
+                let broker = broker.clone();
+                thread::spawn(move || {
+                    if let Err(e) = serve(stream, &broker) {
+                        debug!("Broker client failed: {}", e);
+                    }
+                    let mut broker = broker.lock().unwrap();
+                    broker.clients -= 1;
+                    broker.last_active = Instant::now();
+                    if broker.clients == 0 {
+                        broker.release();
+                    }
+                });
+            }
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                let broker = broker.lock().unwrap();
+                if broker.clients == 0 && broker.last_active.elapsed() >= IDLE_TIMEOUT {
+                    break;
+                }
+                drop(broker);
+                thread::sleep(Duration::from_millis(100));
+            }
+            Err(e) => return Err(e),
+        }
+    }
+
+    let _ = fs::remove_file(&path);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::{self, DirBuilder};
+    use std::os::unix::fs::{symlink, DirBuilderExt, PermissionsExt};
+
+    use super::check_private_dir;
+
+    #[test]
+    fn private_dir() {
+        let tmp = tempfile::tempdir().unwrap();
+        let uid = rustix::process::getuid().as_raw();
+
+        let dir = tmp.path().join("private");
+        DirBuilder::new().mode(0o700).create(&dir).unwrap();
+        assert_eq!(check_private_dir(&dir, uid), Ok(()));
+        assert_eq!(
+            check_private_dir(&dir, uid + 1),
+            Err("is owned by another user")
+        );
+
+        let link = tmp.path().join("link");
+        symlink(&dir, &link).unwrap();
+        assert_eq!(check_private_dir(&link, uid), Err("is not a directory"));
+
+        let shared = tmp.path().join("shared");
+        fs::create_dir(&shared).unwrap();
+        fs::set_permissions(&shared, fs::Permissions::from_mode(0o755)).unwrap();
+        assert_eq!(
+            check_private_dir(&shared, uid),
+            Err("is accessible by others")
+        );
+
+        let file = tmp.path().join("file");
+        fs::write(&file, "").unwrap();
+        assert_eq!(check_private_dir(&file, uid), Err("is not a directory"));
+    }
+}
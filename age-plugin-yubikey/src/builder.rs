@@ -2,162 +2,528 @@ use dialoguer::Password;
 use rand::{rngs::OsRng, RngCore};
 use x509::RelativeDistinguishedName;
 use yubikey::{
-    certificate::Certificate,
-    piv::{generate as yubikey_generate, AlgorithmId, RetiredSlotId, SlotId},
-    Key, PinPolicy, TouchPolicy, YubiKey,
+    certificate::{Certificate, PublicKeyInfo},
+    piv::{generate as yubikey_generate, import_ecc_key, sign_data, RetiredSlotId, SlotId},
+    CardId, CccId, ChuId, Key, MgmKey, PinPolicy, TouchPolicy, YubiKey,
 };
 
 use crate::{
     error::Error,
     fl,
     key::{self, Stub},
-    p256::Recipient,
-    util::{Metadata, POLICY_EXTENSION_OID},
+    p256::{Curve, Recipient, SecretKey},
+    pin,
+    util::{self, Metadata, POLICY_EXTENSION_OID},
     BINARY_NAME, USABLE_SLOTS,
 };
 
 pub(crate) const DEFAULT_PIN_POLICY: PinPolicy = PinPolicy::Once;
 pub(crate) const DEFAULT_TOUCH_POLICY: TouchPolicy = TouchPolicy::Always;
+pub(crate) const DEFAULT_CURVE: Curve = Curve::P256;
 
-pub(crate) struct IdentityBuilder {
-    slot: Option<RetiredSlotId>,
+/// The CHUID that `ykman` and `yubico-piv-tool` write: a fixed FASC-N, a card GUID (left
+/// zero here), an expiry of 2030-01-01, and an empty signature.
+const CHUID_TEMPLATE: [u8; ChuId::BYTE_SIZE] = [
+    0x30, 0x19, 0xd4, 0xe7, 0x39, 0xda, 0x73, 0x9c, 0xed, 0x39, 0xce, 0x73, 0x9d, 0x83, 0x68, 0x58,
+    0x21, 0x08, 0x42, 0x10, 0x84, 0x21, 0xc8, 0x42, 0x10, 0xc3, 0xeb, 0x34, 0x10, 0x00, 0x00, 0x00,
+    0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x35, 0x08, 0x32,
+    0x30, 0x33, 0x30, 0x30, 0x31, 0x30, 0x31, 0x3e, 0x00, 0xfe, 0x00,
+];
+const CHUID_GUID_OFFSET: usize = 29;
+
+/// The start of the CCC that those tools write, with a card ID (left zero here) after
+/// the GSC-IS RID, a dummy manufacturer ID and the card type.
+const CCC_TEMPLATE: [u8; CccId::BYTE_SIZE] = [
+    0xf0, 0x15, 0xa0, 0x00, 0x00, 0x01, 0x16, 0xff, 0x02, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+    0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0xf1, 0x01, 0x21, 0xf2, 0x01, 0x21, 0xf3, 0x00, 0xf4,
+    0x01, 0x00, 0xf5, 0x01, 0x10, 0xf6, 0x00, 0xf7, 0x00, 0xfa, 0x00, 0xfb, 0x00, 0xfc, 0x00, 0xfd,
+    0x00, 0xfe, 0x00,
+];
+const CCC_CARD_ID_OFFSET: usize = 9;
+
+/// Generates an age identity in a slot of a YubiKey.
+pub struct IdentityBuilder {
+    slot: Option<SlotId>,
     force: bool,
     name: Option<String>,
     pin_policy: Option<PinPolicy>,
     touch_policy: Option<TouchPolicy>,
+    curve: Option<Curve>,
+    mgmt_key: Option<MgmKey>,
+    rotate_mgmt_key: bool,
+    init_card: bool,
 }
 
 impl IdentityBuilder {
-    pub(crate) fn new(slot: Option<RetiredSlotId>) -> Self {
+    /// Starts building an identity in `slot`, or in the first empty slot if `None`.
+    pub fn new(slot: Option<RetiredSlotId>) -> Self {
         IdentityBuilder {
-            slot,
+            slot: slot.map(SlotId::Retired),
             name: None,
             pin_policy: None,
             touch_policy: None,
+            curve: None,
+            mgmt_key: None,
+            rotate_mgmt_key: false,
+            init_card: true,
             force: false,
         }
     }
 
-    pub(crate) fn with_name(mut self, name: Option<String>) -> Self {
+    /// Sets the slot to put the identity in, which may be one of the standard slots.
+    /// Defaults to the first empty retired slot.
+    pub fn with_slot(mut self, slot: Option<SlotId>) -> Self {
+        self.slot = slot;
+        self
+    }
+
+    /// Sets the name of the identity. Defaults to `age identity HEX_TAG`.
+    pub fn with_name(mut self, name: Option<String>) -> Self {
         self.name = name;
         self
     }
 
-    pub(crate) fn with_pin_policy(mut self, pin_policy: Option<PinPolicy>) -> Self {
+    /// Sets the PIN policy of the key. Defaults to [`PinPolicy::Once`].
+    pub fn with_pin_policy(mut self, pin_policy: Option<PinPolicy>) -> Self {
         self.pin_policy = pin_policy;
         self
     }
 
-    pub(crate) fn with_touch_policy(mut self, touch_policy: Option<TouchPolicy>) -> Self {
+    /// Sets the touch policy of the key. Defaults to [`TouchPolicy::Always`].
+    pub fn with_touch_policy(mut self, touch_policy: Option<TouchPolicy>) -> Self {
         self.touch_policy = touch_policy;
         self
     }
 
-    pub(crate) fn force(mut self, force: bool) -> Self {
+    /// Sets the curve of the key. Defaults to [`Curve::P256`].
+    pub fn with_curve(mut self, curve: Option<Curve>) -> Self {
+        self.curve = curve;
+        self
+    }
+
+    /// Sets a custom management key that is not PIN-protected.
+    pub fn with_mgmt_key(mut self, mgmt_key: Option<MgmKey>) -> Self {
+        self.mgmt_key = mgmt_key;
+        self
+    }
+
+    /// Whether to replace the management key with a new PIN-protected one.
+    pub fn rotate_mgmt_key(mut self, rotate: bool) -> Self {
+        self.rotate_mgmt_key = rotate;
+        self
+    }
+
+    /// Whether to write a random CHUID and CCC to the YubiKey if it lacks them, which
+    /// some smart card middleware (such as the Windows and macOS smart card stacks)
+    /// needs before it will use the card. Defaults to `true`.
+    // The binary always initializes cards; only programs using the library opt out.
+    #[allow(dead_code)]
+    pub fn init_card(mut self, init_card: bool) -> Self {
+        self.init_card = init_card;
+        self
+    }
+
+    /// Whether to overwrite a slot that already holds a key.
+    pub fn force(mut self, force: bool) -> Self {
         self.force = force;
         self
     }
 
-    pub(crate) fn build(self, yubikey: &mut YubiKey) -> Result<(Stub, Recipient, Metadata), Error> {
-        let slot = match self.slot {
+    /// Generates the key and its certificate, returning the new identity.
+    ///
+    /// This needs the YubiKey's PIN, which is prompted for at the terminal unless an
+    /// unattended PIN is configured.
+    pub fn build(self, yubikey: &mut YubiKey) -> Result<(Stub, Recipient, Metadata), Error> {
+        let slot = self.choose_slot(yubikey)?;
+        let pin_policy = self.pin_policy.unwrap_or(DEFAULT_PIN_POLICY);
+        let touch_policy = self.touch_policy.unwrap_or(DEFAULT_TOUCH_POLICY);
+        let curve = self.curve.unwrap_or(DEFAULT_CURVE);
+
+        eprintln!("{}", fl!("builder-gen-key"));
+
+        // No need to ask for users to enter their PIN if the PIN policy requires it,
+        // because here we _always_ require them to enter their PIN in order to access the
+        // protected management key (which is necessary in order to generate identities).
+        //
+        // If the YubiKey is removed before we know the new key, generating it again in
+        // the same slot replaces whatever the interrupted attempt left there.
+        let recipient = key::retry_if_removed(yubikey, |yubikey, _| {
+            key::manage(yubikey, self.mgmt_key.clone(), self.rotate_mgmt_key)?;
+            if self.init_card {
+                init_card(yubikey)?;
+            }
+
+            // Generate a new key in the selected slot.
+            let generated =
+                yubikey_generate(yubikey, slot, curve.algorithm(), pin_policy, touch_policy)?;
+            Ok(Recipient::from_spki(&generated).expect("YubiKey generates a valid pubkey"))
+        })?;
+
+        finish(
+            yubikey,
+            slot,
+            self.name,
+            recipient,
+            pin_policy,
+            touch_policy,
+            self.mgmt_key,
+        )
+    }
+
+    /// Imports a key that was generated elsewhere, and creates its certificate,
+    /// returning the new identity.
+    ///
+    /// As with [`IdentityBuilder::build`], this needs the YubiKey's PIN. The curve is
+    /// that of the key.
+    pub(crate) fn import(
+        self,
+        yubikey: &mut YubiKey,
+        secret: &SecretKey,
+    ) -> Result<(Stub, Recipient, Metadata), Error> {
+        let slot = self.choose_slot(yubikey)?;
+        let pin_policy = self.pin_policy.unwrap_or(DEFAULT_PIN_POLICY);
+        let touch_policy = self.touch_policy.unwrap_or(DEFAULT_TOUCH_POLICY);
+
+        eprintln!("{}", fl!("builder-import-key"));
+
+        key::retry_if_removed(yubikey, |yubikey, _| {
+            key::manage(yubikey, self.mgmt_key.clone(), self.rotate_mgmt_key)?;
+            if self.init_card {
+                init_card(yubikey)?;
+            }
+            import_ecc_key(
+                yubikey,
+                slot,
+                secret.curve().algorithm(),
+                &secret.to_bytes(),
+                touch_policy,
+                pin_policy,
+            )?;
+            Ok(())
+        })?;
+
+        finish(
+            yubikey,
+            slot,
+            self.name,
+            secret.recipient(),
+            pin_policy,
+            touch_policy,
+            self.mgmt_key,
+        )
+    }
+
+    /// Returns the slot to put the new key in.
+    fn choose_slot(&self, yubikey: &mut YubiKey) -> Result<SlotId, Error> {
+        match self.slot {
             Some(slot) => {
                 if !self.force {
                     // Check that the slot is empty.
                     if Key::list(yubikey)?
                         .into_iter()
-                        .any(|key| key.slot() == SlotId::Retired(slot))
+                        .any(|key| key.slot() == slot)
                     {
                         return Err(Error::SlotIsNotEmpty(slot));
                     }
                 }
 
                 // Now either the slot is empty, or --force is specified.
-                slot
+                Ok(slot)
             }
             None => {
                 // Use the first empty slot.
                 let keys = Key::list(yubikey)?;
                 USABLE_SLOTS
                     .iter()
-                    .find(|&&slot| !keys.iter().any(|key| key.slot() == SlotId::Retired(slot)))
-                    .cloned()
-                    .ok_or_else(|| Error::NoEmptySlots(yubikey.serial()))?
+                    .map(|&slot| SlotId::Retired(slot))
+                    .find(|&slot| !keys.iter().any(|key| key.slot() == slot))
+                    .ok_or_else(|| Error::NoEmptySlots(yubikey.serial()))
             }
-        };
+        }
+    }
+}
 
-        let pin_policy = self.pin_policy.unwrap_or(DEFAULT_PIN_POLICY);
-        let touch_policy = self.touch_policy.unwrap_or(DEFAULT_TOUCH_POLICY);
+/// Writes a random CHUID and CCC to `yubikey` where it has none, returning whether each
+/// was written. This needs the management key.
+///
+/// Existing objects are left alone, even if they aren't in the format we would write,
+/// as something else may rely on them.
+pub(crate) fn init_card(yubikey: &mut YubiKey) -> Result<(bool, bool), Error> {
+    let write_chuid = matches!(ChuId::get(yubikey), Err(yubikey::Error::NotFound));
+    if write_chuid {
+        let mut chuid = ChuId(CHUID_TEMPLATE);
+        OsRng.fill_bytes(&mut chuid.0[CHUID_GUID_OFFSET..CHUID_GUID_OFFSET + 16]);
+        chuid.set(yubikey)?;
+    }
 
-        eprintln!("{}", fl!("builder-gen-key"));
+    let write_ccc = matches!(CccId::get(yubikey), Err(yubikey::Error::NotFound));
+    if write_ccc {
+        let mut ccc = CccId(CCC_TEMPLATE);
+        ccc.0[CCC_CARD_ID_OFFSET..CCC_CARD_ID_OFFSET + CardId::BYTE_SIZE]
+            .copy_from_slice(&CardId::generate().0);
+        ccc.set(yubikey)?;
+    }
 
-        // No need to ask for users to enter their PIN if the PIN policy requires it,
-        // because here we _always_ require them to enter their PIN in order to access the
-        // protected management key (which is necessary in order to generate identities).
-        key::manage(yubikey)?;
+    Ok((write_chuid, write_ccc))
+}
+
+/// Creates the certificate for a new key in `slot`, returning the new identity.
+fn finish(
+    yubikey: &mut YubiKey,
+    slot: SlotId,
+    name: Option<String>,
+    recipient: Recipient,
+    pin_policy: PinPolicy,
+    touch_policy: TouchPolicy,
+    mgmt_key: Option<MgmKey>,
+) -> Result<(Stub, Recipient, Metadata), Error> {
+    let stub = Stub::new(yubikey.serial(), slot, &recipient);
+
+    let name = name.unwrap_or(format!("age identity {}", hex::encode(stub.tag)));
 
-        // Generate a new key in the selected slot.
-        let generated = yubikey_generate(
+    // If the YubiKey is removed meanwhile, the new key stays in its slot, so we create
+    // the certificate once the YubiKey is back.
+    let cert = key::retry_if_removed(yubikey, |yubikey, reinserted| {
+        if reinserted {
+            // The management key has already been replaced if it needed to be.
+            key::manage(yubikey, mgmt_key.clone(), false)?;
+        }
+        self_sign(
             yubikey,
-            SlotId::Retired(slot),
-            AlgorithmId::EccP256,
+            slot,
+            &name,
+            recipient.to_public_key_info(),
             pin_policy,
             touch_policy,
-        )?;
+        )
+    })?;
 
-        let recipient = Recipient::from_spki(&generated).expect("YubiKey generates a valid pubkey");
-        let stub = Stub::new(yubikey.serial(), slot, &recipient);
+    let metadata = Metadata::extract(yubikey, slot, &cert, false).unwrap();
 
+    if yubikey.serial().0 == key::NO_SERIAL {
         eprintln!();
-        eprintln!("{}", fl!("builder-gen-cert"));
+        eprintln!("{}", fl!("warn-yk-no-serial"));
+    }
+
+    Ok((stub, recipient, metadata))
+}
 
-        // Pick a random serial for the new self-signed certificate.
-        let mut serial = [0; 20];
-        OsRng.fill_bytes(&mut serial);
+/// Replaces the certificate for the identity in `slot` with one that has a new name,
+/// keeping the key (and thus the recipient).
+pub(crate) fn rename(
+    yubikey: &mut YubiKey,
+    slot: SlotId,
+    name: &str,
+    mgmt_key: Option<MgmKey>,
+) -> Result<(Stub, Recipient, Metadata), Error> {
+    let (key, _, recipient) = key::list_compatible(yubikey)?
+        .find(|(_, s, _)| s == &slot)
+        .ok_or(Error::SlotHasNoIdentity(slot))?;
 
-        let name = self
-            .name
-            .unwrap_or(format!("age identity {}", hex::encode(stub.tag)));
+    // The new certificate records the policies of the key, which we know from the old
+    // certificate for our own identities, or otherwise from an attestation.
+    let metadata = Metadata::extract(yubikey, slot, key.certificate(), true)
+        .ok_or(Error::SlotHasNoIdentity(slot))?;
+    let (pin_policy, touch_policy) = match (metadata.pin_policy, metadata.touch_policy) {
+        (Some(pin_policy), Some(touch_policy)) => (pin_policy, touch_policy),
+        _ => return Err(Error::UnknownSlotPolicies(slot)),
+    };
 
-        if let PinPolicy::Always = pin_policy {
-            // We need to enter the PIN again.
-            let pin = Password::new()
+    // Writing the certificate requires the management key.
+    key::manage(yubikey, mgmt_key, false)?;
+
+    let cert = self_sign(
+        yubikey,
+        slot,
+        name,
+        key.certificate().subject_pki().clone(),
+        pin_policy,
+        touch_policy,
+    )?;
+
+    let metadata = Metadata::extract(yubikey, slot, &cert, false).unwrap();
+    Ok((
+        Stub::new(yubikey.serial(), slot, &recipient),
+        recipient,
+        metadata,
+    ))
+}
+
+/// Writes a fresh certificate for the key in `slot` whose certificate was removed, or
+/// replaced by another tool, returning the identity again.
+///
+/// The public key and policies of the key come from an attestation, or else from the
+/// slot's metadata (on firmware 5.2.3 and later). Failing both, the public key is
+/// recovered from two signatures made by the slot, and the policies must be given.
+#[allow(clippy::too_many_arguments)]
+pub(crate) fn repair(
+    yubikey: &mut YubiKey,
+    slot: SlotId,
+    name: Option<String>,
+    curve: Option<Curve>,
+    pin_policy: Option<PinPolicy>,
+    touch_policy: Option<TouchPolicy>,
+    force: bool,
+    mgmt_key: Option<MgmKey>,
+) -> Result<(Stub, Recipient, Metadata), Error> {
+    if !force && key::list_compatible(yubikey)?.any(|(_, s, _)| s == slot) {
+        return Err(Error::SlotHasCertificate(slot));
+    }
+
+    let reported = yubikey::piv::attest(yubikey, slot)
+        .ok()
+        .and_then(|buf| {
+            let (_, cert) = x509_parser::parse_x509_certificate(&buf).ok()?;
+            let recipient = Recipient::from_spki_der(cert.public_key().raw)?;
+            Some((recipient, util::extract_policies(&cert)))
+        })
+        .or_else(|| {
+            let metadata = yubikey::piv::metadata(yubikey, slot).ok()?;
+            let recipient = Recipient::from_spki(metadata.public.as_ref()?)?;
+            let (pin_policy, touch_policy) = metadata.policy.unzip();
+            Some((recipient, (pin_policy, touch_policy)))
+        });
+    let (recipient, (reported_pin_policy, reported_touch_policy)) = match reported {
+        Some((recipient, policies)) => (Some(recipient), policies),
+        None => (None, (None, None)),
+    };
+    // What the slot reports about itself trumps what we were told.
+    let (pin_policy, touch_policy) = match (
+        reported_pin_policy.or(pin_policy),
+        reported_touch_policy.or(touch_policy),
+    ) {
+        (Some(pin_policy), Some(touch_policy)) => (pin_policy, touch_policy),
+        _ => return Err(Error::UnknownSlotPolicies(slot)),
+    };
+
+    // Writing the certificate requires the management key.
+    key::manage(yubikey, mgmt_key.clone(), false)?;
+
+    let recipient = match recipient {
+        Some(recipient) => recipient,
+        None => {
+            eprintln!("{}", fl!("builder-recover-key"));
+            recover_by_signing(yubikey, slot, curve, pin_policy, touch_policy)?
+        }
+    };
+
+    finish(
+        yubikey,
+        slot,
+        name,
+        recipient,
+        pin_policy,
+        touch_policy,
+        mgmt_key,
+    )
+}
+
+/// Recovers the public key in `slot` from two signatures made with it, trying each
+/// curve that the slot could hold unless `curve` is given.
+fn recover_by_signing(
+    yubikey: &mut YubiKey,
+    slot: SlotId,
+    curve: Option<Curve>,
+    pin_policy: PinPolicy,
+    touch_policy: TouchPolicy,
+) -> Result<Recipient, Error> {
+    let curves = match curve {
+        Some(curve) => vec![curve],
+        None => vec![Curve::P256, Curve::P384],
+    };
+    'curves: for curve in curves {
+        // RSA keys are not recovered from signatures.
+        let Some(len) = curve.compressed_len() else {
+            continue;
+        };
+        let algorithm = curve.algorithm();
+        // The digests are as long as the curve's field elements.
+        let mut digests = vec![vec![0; len - 1]; 2];
+        let mut signatures = vec![];
+        for digest in &mut digests {
+            OsRng.fill_bytes(digest);
+            before_private_key_op(yubikey, pin_policy, touch_policy)?;
+            match sign_data(yubikey, digest, algorithm, slot) {
+                Ok(signature) => signatures.push(signature),
+                // The slot holds a key for another curve, or none at all.
+                Err(_) => continue 'curves,
+            }
+        }
+        let pairs: Vec<(&[u8], &[u8])> = digests
+            .iter()
+            .zip(&signatures)
+            .map(|(digest, signature)| (&digest[..], &signature[..]))
+            .collect();
+        if let Some(recipient) = Recipient::from_signatures(curve, &pairs) {
+            return Ok(recipient);
+        }
+    }
+    Err(Error::SlotHasNoIdentity(slot))
+}
+
+/// Generates and stores a self-signed certificate for the key in `slot`, which has the
+/// given policies.
+fn self_sign(
+    yubikey: &mut YubiKey,
+    slot: SlotId,
+    name: &str,
+    public_key: PublicKeyInfo,
+    pin_policy: PinPolicy,
+    touch_policy: TouchPolicy,
+) -> Result<Certificate, Error> {
+    eprintln!();
+    eprintln!("{}", fl!("builder-gen-cert"));
+
+    // Pick a random serial for the new self-signed certificate.
+    let mut serial = [0; 20];
+    OsRng.fill_bytes(&mut serial);
+
+    before_private_key_op(yubikey, pin_policy, touch_policy)?;
+
+    Ok(Certificate::generate_self_signed(
+        yubikey,
+        slot,
+        serial,
+        None,
+        &[
+            RelativeDistinguishedName::organization(BINARY_NAME),
+            RelativeDistinguishedName::organizational_unit(env!("CARGO_PKG_VERSION")),
+            RelativeDistinguishedName::common_name(name),
+        ],
+        public_key,
+        &[x509::Extension::regular(
+            POLICY_EXTENSION_OID,
+            &[pin_policy.into(), touch_policy.into()],
+        )],
+    )?)
+}
+
+/// Prepares for an operation with a key with the given policies, right after the PIN
+/// was verified to authenticate with the management key.
+fn before_private_key_op(
+    yubikey: &mut YubiKey,
+    pin_policy: PinPolicy,
+    touch_policy: TouchPolicy,
+) -> Result<(), Error> {
+    if let PinPolicy::Always = pin_policy {
+        // We need to enter the PIN again. It was just verified, so all of its tries are
+        // left; checking them would select the applet again, which drops our management
+        // key authentication.
+        let pin = pin::get_or_prompt(|| {
+            Password::new()
                 .with_prompt(fl!(
                     "plugin-enter-pin",
                     yubikey_serial = yubikey.serial().to_string(),
                 ))
                 .report(true)
-                .interact()?;
-            yubikey.verify_pin(pin.as_bytes())?;
-        }
-        if let TouchPolicy::Never = touch_policy {
-            // No need to touch YubiKey
-        } else {
-            eprintln!("{}", fl!("builder-touch-yk"));
-        }
-
-        let cert = Certificate::generate_self_signed(
-            yubikey,
-            SlotId::Retired(slot),
-            serial,
-            None,
-            &[
-                RelativeDistinguishedName::organization(BINARY_NAME),
-                RelativeDistinguishedName::organizational_unit(env!("CARGO_PKG_VERSION")),
-                RelativeDistinguishedName::common_name(&name),
-            ],
-            generated,
-            &[x509::Extension::regular(
-                POLICY_EXTENSION_OID,
-                &[pin_policy.into(), touch_policy.into()],
-            )],
-        )?;
-
-        let metadata = Metadata::extract(yubikey, slot, &cert, false).unwrap();
-
-        Ok((
-            Stub::new(yubikey.serial(), slot, &recipient),
-            recipient,
-            metadata,
-        ))
+                .interact()
+        })?;
+        yubikey.verify_pin(pin.as_bytes())?;
+    }
+    if let TouchPolicy::Never = touch_policy {
+        // No need to touch YubiKey
+    } else {
+        eprintln!("{}", fl!("builder-touch-yk"));
     }
+    Ok(())
 }
@@ -0,0 +1,255 @@
+//! On-disk cache of where YubiKeys were last found.
+//!
+//! Opening a reader selects the PIV applet and reads the serial, so finding the YubiKey
+//! for a stub gets slow when many smart card readers are attached. We remember which
+//! reader each YubiKey was last seen in, and for YubiKeys that don't expose their serial,
+//! which reader and slot hold the key with a given tag. The cache is `readers.json` in
+//! `$XDG_CACHE_HOME/age-plugin-yubikey` (or `~/.cache/age-plugin-yubikey`), or in
+//! `%LOCALAPPDATA%\age-plugin-yubikey` on Windows.
+//!
+//! Entries are only hints: callers check that the reader still holds the expected
+//! YubiKey or key, and forget the entry if it doesn't. A missing or unreadable cache is
+//! treated as empty.
+
+use std::collections::BTreeMap;
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+use std::process;
+use std::sync::Mutex;
+
+use lazy_static::lazy_static;
+use serde::{Deserialize, Serialize};
+use tracing::debug;
+use yubikey::{piv::SlotId, Serial};
+
+use crate::{key::NO_SERIAL, p256::TAG_BYTES, BINARY_NAME};
+
+const CACHE_FILE: &str = "readers.json";
+
+lazy_static! {
+    static ref CACHE: Mutex<Cache> = Mutex::new(Cache::load());
+}
+
+/// Where the key with a particular tag was last found.
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq, Eq)]
+struct KeyLocation {
+    reader: String,
+    slot: u8,
+}
+
+#[derive(Debug, Default, Deserialize, Serialize, PartialEq, Eq)]
+struct Cache {
+    /// Reader names, by decimal serial.
+    #[serde(default)]
+    readers: BTreeMap<String, String>,
+    /// Key locations, by hex-encoded tag.
+    #[serde(default)]
+    keys: BTreeMap<String, KeyLocation>,
+}
+
+impl Cache {
+    fn path() -> Option<PathBuf> {
+        let dir = if cfg!(windows) {
+            PathBuf::from(env::var_os("LOCALAPPDATA")?)
+        } else {
+            match env::var_os("XDG_CACHE_HOME").filter(|dir| !dir.is_empty()) {
+                Some(dir) => PathBuf::from(dir),
+                None => PathBuf::from(env::var_os("HOME")?).join(".cache"),
+            }
+        };
+        Some(dir.join(BINARY_NAME).join(CACHE_FILE))
+    }
+
+    fn load() -> Self {
+        Self::path()
+            .and_then(|path| fs::read(path).ok())
+            .and_then(|data| serde_json::from_slice(&data).ok())
+            .unwrap_or_default()
+    }
+
+    /// Writes the cache, replacing the file atomically so that concurrent plugin
+    /// instances never read a partial cache.
+    fn save(&self) {
+        let path = match Self::path() {
+            Some(path) => path,
+            None => return,
+        };
+        let tmp = path.with_extension(format!("{}.tmp", process::id()));
+        let res = path
+            .parent()
+            .map_or(Ok(()), fs::create_dir_all)
+            .and_then(|()| fs::write(&tmp, serde_json::to_vec(self)?))
+            .and_then(|()| fs::rename(&tmp, &path));
+        if let Err(e) = res {
+            debug!("Could not write {}: {}", path.display(), e);
+            let _ = fs::remove_file(&tmp);
+        }
+    }
+
+    fn reader_for_serial(&self, serial: Serial) -> Option<String> {
+        self.readers.get(&serial.to_string()).cloned()
+    }
+
+    /// Returns `true` if the cache changed.
+    fn set_reader(&mut self, serial: Serial, reader: &str) -> bool {
+        self.readers
+            .insert(serial.to_string(), reader.into())
+            .as_deref()
+            != Some(reader)
+    }
+
+    /// Returns `true` if the cache changed.
+    fn remove_reader(&mut self, serial: Serial) -> bool {
+        self.readers.remove(&serial.to_string()).is_some()
+    }
+
+    /// Returns the reader holding the key with this tag, if it was last found in `slot`.
+    /// An entry for another slot is stale (the key was moved or replaced), and removed.
+    fn reader_for_key(&mut self, slot: SlotId, tag: [u8; TAG_BYTES]) -> Option<String> {
+        let key = hex::encode(tag);
+        match self.keys.get(&key) {
+            Some(location) if location.slot == u8::from(slot) => Some(location.reader.clone()),
+            Some(_) => {
+                self.keys.remove(&key);
+                None
+            }
+            None => None,
+        }
+    }
+
+    /// Returns `true` if the cache changed.
+    fn set_key(&mut self, slot: SlotId, tag: [u8; TAG_BYTES], reader: &str) -> bool {
+        let location = KeyLocation {
+            reader: reader.into(),
+            slot: slot.into(),
+        };
+        self.keys.insert(hex::encode(tag), location.clone()) != Some(location)
+    }
+
+    /// Returns `true` if the cache changed.
+    fn remove_key(&mut self, tag: [u8; TAG_BYTES]) -> bool {
+        self.keys.remove(&hex::encode(tag)).is_some()
+    }
+}
+
+fn update(f: impl FnOnce(&mut Cache) -> bool) {
+    if let Ok(mut cache) = CACHE.lock() {
+        if f(&mut cache) {
+            cache.save();
+        }
+    }
+}
+
+/// Returns the name of the reader the YubiKey with this serial was last seen in.
+pub(crate) fn reader_for_serial(serial: Serial) -> Option<String> {
+    CACHE
+        .lock()
+        .ok()
+        .and_then(|cache| cache.reader_for_serial(serial))
+}
+
+/// Remembers that the YubiKey with this serial is in `reader`.
+pub(crate) fn remember_reader(serial: Serial, reader: &str) {
+    if serial.0 != NO_SERIAL {
+        update(|cache| cache.set_reader(serial, reader));
+    }
+}
+
+/// Forgets where the YubiKey with this serial is, after it wasn't found there.
+pub(crate) fn forget_reader(serial: Serial) {
+    update(|cache| cache.remove_reader(serial));
+}
+
+/// Returns the name of the reader the key with this tag was last used from, if it was
+/// in `slot`.
+pub(crate) fn reader_for_key(slot: SlotId, tag: [u8; TAG_BYTES]) -> Option<String> {
+    let mut reader = None;
+    update(|cache| {
+        let had_entry = cache.keys.contains_key(&hex::encode(tag));
+        reader = cache.reader_for_key(slot, tag);
+        had_entry && reader.is_none()
+    });
+    reader
+}
+
+/// Remembers that the key with this tag is in `slot` of the YubiKey in `reader`.
+pub(crate) fn remember_key(slot: SlotId, tag: [u8; TAG_BYTES], reader: &str) {
+    update(|cache| cache.set_key(slot, tag, reader));
+}
+
+/// Forgets where the key with this tag is, after it wasn't found there.
+pub(crate) fn forget_key(tag: [u8; TAG_BYTES]) {
+    update(|cache| cache.remove_key(tag));
+}
+
+#[cfg(test)]
+mod tests {
+    use yubikey::{
+        piv::{RetiredSlotId, SlotId},
+        Serial,
+    };
+
+    use super::Cache;
+
+    const READER: &str = "Yubico YubiKey OTP+FIDO+CCID 00 00";
+
+    #[test]
+    fn readers() {
+        let mut cache = Cache::default();
+        let serial = Serial::from(12345678);
+        assert_eq!(cache.reader_for_serial(serial), None);
+
+        assert!(cache.set_reader(serial, READER));
+        assert!(!cache.set_reader(serial, READER));
+        assert_eq!(cache.reader_for_serial(serial).as_deref(), Some(READER));
+
+        assert!(cache.remove_reader(serial));
+        assert!(!cache.remove_reader(serial));
+        assert_eq!(cache.reader_for_serial(serial), None);
+    }
+
+    #[test]
+    fn keys() {
+        let mut cache = Cache::default();
+        let tag = [1, 2, 3, 4];
+        assert!(cache.set_key(SlotId::Retired(RetiredSlotId::R1), tag, READER));
+        assert!(!cache.set_key(SlotId::Retired(RetiredSlotId::R1), tag, READER));
+        assert_eq!(
+            cache
+                .reader_for_key(SlotId::Retired(RetiredSlotId::R1), tag)
+                .as_deref(),
+            Some(READER)
+        );
+
+        // A key found in another slot than the one a stub names is stale.
+        assert_eq!(
+            cache.reader_for_key(SlotId::Retired(RetiredSlotId::R2), tag),
+            None
+        );
+        assert_eq!(
+            cache.reader_for_key(SlotId::Retired(RetiredSlotId::R1), tag),
+            None
+        );
+    }
+
+    #[test]
+    fn round_trip() {
+        let mut cache = Cache::default();
+        cache.set_reader(Serial::from(12345678), READER);
+        cache.set_key(
+            SlotId::Retired(RetiredSlotId::R3),
+            [0xde, 0xad, 0xbe, 0xef],
+            READER,
+        );
+
+        let data = serde_json::to_string(&cache).unwrap();
+        assert!(data.contains(r#""12345678":"#));
+        assert!(data.contains(r#""deadbeef":{"#));
+        assert_eq!(serde_json::from_str::<Cache>(&data).unwrap(), cache);
+        assert_eq!(
+            serde_json::from_str::<Cache>("{}").unwrap(),
+            Cache::default()
+        );
+    }
+}
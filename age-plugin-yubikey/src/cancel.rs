@@ -0,0 +1,55 @@
+//! Aborting operations that could otherwise block forever.
+//!
+//! PC/SC calls made through the `yubikey` crate cannot be interrupted from another
+//! thread, so a hung card or a touch request nobody answers would keep the plugin
+//! running after its age client has given up. Exiting the process is the one reliable
+//! way to abort them: the PC/SC service then cancels the pending transaction.
+
+use std::process;
+use std::sync::mpsc;
+use std::thread;
+use std::time::Duration;
+
+use tracing::debug;
+
+/// How long a single operation on a YubiKey may take, including waiting for a touch.
+pub(crate) const CARD_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Exits the process once the age client that spawned it has gone away.
+///
+/// The plugin only reads from the age client between operations, so it would not
+/// notice a disconnect (or a Ctrl-C delivered to the client alone) while blocked on a
+/// YubiKey.
+pub(crate) fn exit_with_parent() {
+    #[cfg(unix)]
+    {
+        use std::os::unix::process::parent_id;
+
+        let parent = parent_id();
+        thread::spawn(move || loop {
+            thread::sleep(Duration::from_millis(500));
+            if parent_id() != parent {
+                debug!("age client exited, aborting");
+                process::exit(1);
+            }
+        });
+    }
+}
+
+/// Exits the process with an error unless it is dropped within a timeout.
+pub(crate) struct Deadline {
+    _done: mpsc::Sender<()>,
+}
+
+impl Deadline {
+    pub(crate) fn start(timeout: Duration, message: String) -> Self {
+        let (done, expired) = mpsc::channel::<()>();
+        thread::spawn(move || {
+            if let Err(mpsc::RecvTimeoutError::Timeout) = expired.recv_timeout(timeout) {
+                eprintln!("{message}");
+                process::exit(1);
+            }
+        });
+        Deadline { _done: done }
+    }
+}
@@ -0,0 +1,245 @@
+//! Probing which features a YubiKey supports.
+//!
+//! The firmware version that the PIV applet reports tells us which algorithms and
+//! management keys the YubiKey supports. YubiKeys with firmware 5.0 or later also
+//! describe themselves through their management applet: which applets are enabled,
+//! whether they are FIPS models, and whether they enforce PIN complexity.
+
+use std::ffi::CString;
+
+use serde::Serialize;
+use yubikey::{Serial, Version, YubiKey};
+
+use crate::p256::Curve;
+
+/// The AID of the management applet.
+const MGMT_AID: [u8; 8] = [0xa0, 0x00, 0x00, 0x05, 0x27, 0x47, 0x11, 0x17];
+
+const INS_SELECT: u8 = 0xa4;
+const INS_GET_DEVICE_INFO: u8 = 0x1d;
+
+const TAG_USB_SUPPORTED: u8 = 0x01;
+const TAG_USB_ENABLED: u8 = 0x03;
+const TAG_FORM_FACTOR: u8 = 0x04;
+const TAG_FIPS_CAPABLE: u8 = 0x14;
+const TAG_PIN_COMPLEXITY: u8 = 0x16;
+
+/// The form factor bit that marks the FIPS models of the YubiKey 5 series before 5.7.
+const FORM_FACTOR_FIPS: u8 = 0x80;
+
+/// The applets in the capability bitmasks of the device info, as `ykman` names them.
+const APPLETS: [(u16, &str); 7] = [
+    (0x0001, "OTP"),
+    (0x0002, "U2F"),
+    (0x0008, "OpenPGP"),
+    (0x0010, "PIV"),
+    (0x0020, "OATH"),
+    (0x0100, "HSMAUTH"),
+    (0x0200, "FIDO2"),
+];
+
+/// The features that a YubiKey's firmware supports, of those added after the YubiKey 4
+/// series.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) struct Capabilities {
+    /// AES management keys, added in firmware 5.4.
+    pub(crate) aes_mgmt_key: bool,
+    /// Ed25519 and X25519 keys, added in firmware 5.7.
+    pub(crate) curve25519: bool,
+}
+
+impl Capabilities {
+    pub(crate) fn of(version: Version) -> Self {
+        let version = (version.major, version.minor);
+        Capabilities {
+            aes_mgmt_key: version >= (5, 4),
+            curve25519: version >= (5, 7),
+        }
+    }
+
+    /// Returns the names of the algorithms that the firmware can hold keys for. These
+    /// include `x25519` on firmware 5.7 and later, although we can't generate its keys.
+    pub(crate) fn algorithms(&self) -> Vec<&'static str> {
+        let mut algorithms = [Curve::P256, Curve::P384, Curve::Rsa2048]
+            .map(|c| c.name())
+            .to_vec();
+        if self.curve25519 {
+            algorithms.push("x25519");
+        }
+        algorithms
+    }
+}
+
+/// What the management applet of a YubiKey reports about it.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub(crate) struct DeviceInfo {
+    /// The applets enabled over USB.
+    pub(crate) applets: Vec<&'static str>,
+    pub(crate) fips: bool,
+    pub(crate) pin_complexity: bool,
+}
+
+impl DeviceInfo {
+    /// Parses the response to GET DEVICE INFO: a length byte, followed by TLVs.
+    fn parse(data: &[u8]) -> Option<Self> {
+        let (&len, rest) = data.split_first()?;
+        let mut tlvs = rest.get(..len.into())?;
+
+        let mut supported = None;
+        let mut enabled = None;
+        let mut info = DeviceInfo::default();
+        while let [tag, len, rest @ ..] = tlvs {
+            let value = rest.get(..(*len).into())?;
+            let bitmask = || value.iter().fold(0u16, |acc, &b| (acc << 8) | u16::from(b));
+            match *tag {
+                TAG_USB_SUPPORTED => supported = Some(bitmask()),
+                TAG_USB_ENABLED => enabled = Some(bitmask()),
+                TAG_FORM_FACTOR => {
+                    info.fips |= value.first().map_or(false, |ff| ff & FORM_FACTOR_FIPS != 0)
+                }
+                TAG_FIPS_CAPABLE => info.fips |= bitmask() != 0,
+                TAG_PIN_COMPLEXITY => info.pin_complexity = value == [1],
+                _ => (),
+            }
+            tlvs = &rest[value.len()..];
+        }
+
+        // Older firmware only reports the applets it supports, which are all enabled.
+        let applets = enabled.or(supported)?;
+        info.applets = APPLETS
+            .iter()
+            .filter(|(bit, _)| applets & bit != 0)
+            .map(|(_, name)| *name)
+            .collect();
+        Some(info)
+    }
+
+    /// Asks the management applet of the YubiKey in `reader` for its device info.
+    ///
+    /// This uses its own connection, which leaves the management applet selected, so
+    /// it should be the last thing done with the YubiKey.
+    fn read(reader: &str) -> Option<Self> {
+        let ctx = pcsc::Context::establish(pcsc::Scope::User).ok()?;
+        let card = ctx
+            .connect(
+                &CString::new(reader).ok()?,
+                pcsc::ShareMode::Shared,
+                pcsc::Protocols::ANY,
+            )
+            .ok()?;
+
+        let transmit = |apdu: &[u8]| -> Option<Vec<u8>> {
+            let mut buf = [0; pcsc::MAX_BUFFER_SIZE];
+            let response = card.transmit(apdu, &mut buf).ok()?;
+            match response {
+                [data @ .., 0x90, 0x00] => Some(data.to_vec()),
+                _ => None,
+            }
+        };
+
+        let mut select = vec![0x00, INS_SELECT, 0x04, 0x00, MGMT_AID.len() as u8];
+        select.extend_from_slice(&MGMT_AID);
+        transmit(&select)?;
+        Self::parse(&transmit(&[0x00, INS_GET_DEVICE_INFO, 0x00, 0x00, 0x00])?)
+    }
+}
+
+/// Everything we know about a YubiKey's features.
+pub(crate) struct Info {
+    pub(crate) serial: Serial,
+    pub(crate) firmware: Version,
+    pub(crate) capabilities: Capabilities,
+    /// `None` for YubiKeys that don't have the management applet (before firmware
+    /// 5.0), or didn't answer.
+    pub(crate) device: Option<DeviceInfo>,
+}
+
+impl Info {
+    /// Probes `yubikey`. See [`DeviceInfo::read`] for why this should come last.
+    pub(crate) fn probe(yubikey: &YubiKey) -> Self {
+        let firmware = yubikey.version();
+        Info {
+            serial: yubikey.serial(),
+            firmware,
+            capabilities: Capabilities::of(firmware),
+            device: if firmware.major >= 5 {
+                DeviceInfo::read(yubikey.name())
+            } else {
+                None
+            },
+        }
+    }
+
+    pub(crate) fn to_json(&self) -> InfoJson {
+        InfoJson {
+            serial: self.serial.0,
+            firmware: self.firmware.to_string(),
+            algorithms: self.capabilities.algorithms(),
+            aes_mgmt_key: self.capabilities.aes_mgmt_key,
+            applets: self.device.as_ref().map(|d| d.applets.clone()),
+            fips: self.device.as_ref().map(|d| d.fips),
+            pin_complexity: self.device.as_ref().map(|d| d.pin_complexity),
+        }
+    }
+}
+
+/// The form of [`Info`] printed by `--info --json`.
+#[derive(Serialize)]
+pub(crate) struct InfoJson {
+    serial: u32,
+    firmware: String,
+    algorithms: Vec<&'static str>,
+    aes_mgmt_key: bool,
+    /// `None` (as are `fips` and `pin_complexity`) if the YubiKey has no device info.
+    applets: Option<Vec<&'static str>>,
+    fips: Option<bool>,
+    pin_complexity: Option<bool>,
+}
+
+#[cfg(test)]
+mod tests {
+    use yubikey::Version;
+
+    use super::{Capabilities, DeviceInfo};
+
+    #[test]
+    fn capabilities() {
+        let yk4 = Capabilities::of(Version::new([4, 3, 7]));
+        assert!(!yk4.aes_mgmt_key);
+        assert_eq!(yk4.algorithms(), vec!["p256", "p384", "rsa2048"]);
+
+        let yk54 = Capabilities::of(Version::new([5, 4, 3]));
+        assert!(yk54.aes_mgmt_key);
+        assert!(!yk54.curve25519);
+
+        let yk57 = Capabilities::of(Version::new([5, 7, 1]));
+        assert_eq!(yk57.algorithms(), vec!["p256", "p384", "rsa2048", "x25519"]);
+    }
+
+    #[test]
+    fn device_info() {
+        // A YubiKey 5 NFC with OTP disabled over USB.
+        let data = [
+            0x16, 0x01, 0x02, 0x02, 0x3b, 0x03, 0x02, 0x02, 0x3a, 0x04, 0x01, 0x01, 0x05, 0x03,
+            0x05, 0x04, 0x03, 0x02, 0x04, 0x00, 0xbc, 0x61, 0x4e,
+        ];
+        let info = DeviceInfo::parse(&data).unwrap();
+        assert_eq!(info.applets, vec!["U2F", "OpenPGP", "PIV", "OATH", "FIDO2"]);
+        assert!(!info.fips);
+        assert!(!info.pin_complexity);
+
+        // A FIPS YubiKey 5.7 enforcing PIN complexity, which only reports the applets
+        // it supports.
+        let data = [
+            0x0b, 0x01, 0x02, 0x00, 0x10, 0x14, 0x02, 0x00, 0x10, 0x16, 0x01, 0x01,
+        ];
+        let info = DeviceInfo::parse(&data).unwrap();
+        assert_eq!(info.applets, vec!["PIV"]);
+        assert!(info.fips);
+        assert!(info.pin_complexity);
+
+        // Truncated responses are rejected.
+        assert_eq!(DeviceInfo::parse(&data[..8]), None);
+        assert_eq!(DeviceInfo::parse(&[]), None);
+    }
+}
@@ -0,0 +1,584 @@
+//! The plugin's configuration file.
+//!
+//! Settings that apply to every invocation, including those started by age clients
+//! (which cannot pass flags to the plugin), are read from `config.toml` in
+//! `$XDG_CONFIG_HOME/age-plugin-yubikey` (or `~/.config/age-plugin-yubikey`), or in
+//! `%APPDATA%\age-plugin-yubikey` on Windows. A missing file is an empty configuration.
+//!
+//! `--config` reads and writes its settings, keeping the rest of the file (including
+//! comments) as it is.
+
+use std::env;
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use i18n_embed::unic_langid::LanguageIdentifier;
+use lazy_static::lazy_static;
+use serde::Deserialize;
+use toml_edit::{DocumentMut, Item, Table, Value};
+
+use crate::{error::Error, i18n, util, BINARY_NAME};
+
+const CONFIG_FILE: &str = "config.toml";
+const REMOTE_ENV: &str = "AGE_YUBIKEY_REMOTE";
+const PIN_AGENT_TTL_ENV: &str = "AGE_YUBIKEY_PIN_AGENT_TTL";
+/// The longest the PIN agent may cache a PIN for, in seconds (one day).
+pub(crate) const MAX_PIN_AGENT_TTL: u64 = 24 * 60 * 60;
+const WAIT_TIMEOUT_ENV: &str = "AGE_YUBIKEY_WAIT_TIMEOUT";
+const POLL_INTERVAL_ENV: &str = "AGE_YUBIKEY_POLL_INTERVAL_MS";
+const WAIT_UNATTENDED_ENV: &str = "AGE_YUBIKEY_WAIT_UNATTENDED";
+
+const DEFAULT_WAIT_TIMEOUT: u64 = 15;
+const DEFAULT_POLL_INTERVAL: u64 = 1000;
+
+lazy_static! {
+    static ref REMOTE: Mutex<Option<RemoteConfig>> = Mutex::new(None);
+    static ref PIN_AGENT_TTL: Mutex<Option<Duration>> = Mutex::new(None);
+    static ref WAIT: Mutex<Wait> = Mutex::new(Wait::default());
+    static ref DEFAULTS: Mutex<Defaults> = Mutex::new(Defaults::default());
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct Config {
+    pub(crate) defaults: Option<Defaults>,
+    pub(crate) remote: Option<RemoteConfig>,
+    pub(crate) pin_agent: Option<PinAgentConfig>,
+    pub(crate) wait: Option<WaitConfig>,
+}
+
+/// Defaults for flags, which the command line overrides.
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct Defaults {
+    /// The YubiKey to use when several are plugged in (as `--serial`).
+    pub(crate) serial: Option<u32>,
+    /// PIN policy for new identities (as `--pin-policy`).
+    pub(crate) pin_policy: Option<String>,
+    /// Touch policy for new identities (as `--touch-policy`).
+    pub(crate) touch_policy: Option<String>,
+    /// Print JSON from the commands that can (as `--json`).
+    #[serde(default)]
+    pub(crate) json: bool,
+    /// Refuse the commands that change a YubiKey (as `--read-only`).
+    #[serde(default)]
+    pub(crate) read_only: bool,
+    /// Language to print messages in, such as `en-US`, instead of the system's.
+    pub(crate) language: Option<String>,
+}
+
+impl Defaults {
+    /// Checks the values that the file format can't.
+    fn check(&self) -> Result<(), Error> {
+        if let Some(policy) = &self.pin_policy {
+            util::pin_policy_from_string(policy.clone())?;
+        }
+        if let Some(policy) = &self.touch_policy {
+            util::touch_policy_from_string(policy.clone())?;
+        }
+        self.language().map(|_| ())
+    }
+
+    fn language(&self) -> Result<Option<LanguageIdentifier>, Error> {
+        self.language
+            .as_ref()
+            .map(|lang| {
+                lang.parse()
+                    .map_err(|_| Error::InvalidLanguage(lang.clone()))
+            })
+            .transpose()
+    }
+}
+
+/// How the PIN agent caches PINs.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct PinAgentConfig {
+    /// Seconds for which a PIN is cached after it was entered, up to
+    /// [`MAX_PIN_AGENT_TTL`]. 0 disables the agent.
+    pub(crate) ttl: u64,
+}
+
+/// How long to wait for a YubiKey to be inserted.
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct WaitConfig {
+    /// Seconds to wait before giving up. 0 gives up at once.
+    pub(crate) timeout: Option<u64>,
+    /// Milliseconds between looks for the YubiKey.
+    pub(crate) poll_interval_ms: Option<u64>,
+    /// Whether to wait at all when an unattended PIN is used. Defaults to `true`.
+    pub(crate) unattended: Option<bool>,
+}
+
+/// The resolved [`WaitConfig`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) struct Wait {
+    pub(crate) timeout: Duration,
+    pub(crate) poll_interval: Duration,
+    unattended: bool,
+}
+
+impl Default for Wait {
+    fn default() -> Self {
+        Wait {
+            timeout: Duration::from_secs(DEFAULT_WAIT_TIMEOUT),
+            poll_interval: Duration::from_millis(DEFAULT_POLL_INTERVAL),
+            unattended: true,
+        }
+    }
+}
+
+impl Wait {
+    /// Combines the file's `[wait]` section with the environment, which takes
+    /// precedence.
+    fn resolve(
+        config: Option<WaitConfig>,
+        env: impl Fn(&str) -> Option<String>,
+    ) -> Result<Self, Error> {
+        let config = config.unwrap_or_default();
+        let setting = |var: &str, value: Option<u64>| match env(var) {
+            Some(v) => v
+                .parse()
+                .map(Some)
+                .map_err(|_| Error::InvalidWaitSetting(var.into(), v)),
+            None => Ok(value),
+        };
+        let timeout = setting(WAIT_TIMEOUT_ENV, config.timeout)?.unwrap_or(DEFAULT_WAIT_TIMEOUT);
+        let poll_interval = match setting(POLL_INTERVAL_ENV, config.poll_interval_ms)? {
+            Some(0) => {
+                return Err(Error::InvalidWaitSetting(
+                    "poll_interval_ms".into(),
+                    "0".into(),
+                ))
+            }
+            interval => interval.unwrap_or(DEFAULT_POLL_INTERVAL),
+        };
+        let unattended = match env(WAIT_UNATTENDED_ENV).as_deref() {
+            Some("0") => false,
+            Some("1") => true,
+            Some(v) => {
+                return Err(Error::InvalidWaitSetting(
+                    WAIT_UNATTENDED_ENV.into(),
+                    v.into(),
+                ))
+            }
+            None => config.unattended.unwrap_or(true),
+        };
+        Ok(Wait {
+            timeout: Duration::from_secs(timeout),
+            poll_interval: Duration::from_millis(poll_interval),
+            unattended,
+        })
+    }
+}
+
+/// Where to find YubiKeys shared by a remote `yk-agentd`.
+#[cfg_attr(not(feature = "remote"), allow(dead_code))]
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct RemoteConfig {
+    /// `host:port` of the daemon, `unix:PATH` or `pipe:NAME` for a daemon on this
+    /// machine, or `ssh:[user@]host/PATH` for the socket of a daemon reached over SSH.
+    pub(crate) address: String,
+    /// Token to authenticate with, for daemons with an access control policy.
+    pub(crate) token: Option<String>,
+    /// File holding the token instead, read at every connection, such as an OIDC
+    /// access token that another tool keeps fresh.
+    pub(crate) token_file: Option<PathBuf>,
+    /// Bus id of the YubiKey to use. Defaults to every YubiKey the daemon exports.
+    pub(crate) bus_id: Option<String>,
+    /// PEM CA certificates that issued the daemon's certificate. Enables TLS.
+    pub(crate) ca: Option<PathBuf>,
+    /// Connect over QUIC (to a daemon started with `--quic`) rather than TCP. Needs `ca`.
+    #[serde(default)]
+    pub(crate) quic: bool,
+    /// Name the daemon's certificate is valid for. Defaults to the host of `address`.
+    pub(crate) server_name: Option<String>,
+    /// PEM client certificate chain, for daemons that require one.
+    pub(crate) cert: Option<PathBuf>,
+    /// PEM private key of `cert`.
+    pub(crate) key: Option<PathBuf>,
+}
+
+#[cfg_attr(not(feature = "remote"), allow(dead_code))]
+impl RemoteConfig {
+    pub(crate) fn server_name(&self) -> &str {
+        match &self.server_name {
+            Some(name) => name,
+            None => {
+                let host = self
+                    .address
+                    .rsplit_once(':')
+                    .map_or(self.address.as_str(), |(host, _)| host);
+                host.trim_start_matches('[').trim_end_matches(']')
+            }
+        }
+    }
+}
+
+impl Config {
+    /// Returns the path of the configuration file, if we can tell where it would be.
+    pub(crate) fn path() -> Option<PathBuf> {
+        let dir = if cfg!(windows) {
+            PathBuf::from(env::var_os("APPDATA")?)
+        } else {
+            match env::var_os("XDG_CONFIG_HOME").filter(|dir| !dir.is_empty()) {
+                Some(dir) => PathBuf::from(dir),
+                None => PathBuf::from(env::var_os("HOME")?).join(".config"),
+            }
+        };
+        Some(dir.join(BINARY_NAME).join(CONFIG_FILE))
+    }
+
+    /// Reads the configuration file.
+    pub(crate) fn load() -> Result<Self, Error> {
+        match Self::path() {
+            Some(path) => match fs::read_to_string(&path) {
+                Ok(data) => Self::parse(&data)
+                    .map_err(|e| Error::InvalidConfig(path.display().to_string(), e.to_string())),
+                Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(Self::default()),
+                Err(e) => Err(e.into()),
+            },
+            None => Ok(Self::default()),
+        }
+    }
+
+    fn parse(data: &str) -> Result<Self, toml::de::Error> {
+        toml::from_str(data)
+    }
+}
+
+/// Prints the settings in the configuration file, as `SECTION.KEY = VALUE` lines, or
+/// the value of `key`. With a `value`, sets `key` to it instead, or removes `key` if
+/// `value` is empty.
+pub(crate) fn edit(key: Option<&str>, value: Option<&str>) -> Result<(), Error> {
+    let path = Config::path().ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::NotFound,
+            "can't tell where the configuration file is",
+        )
+    })?;
+    let data = match fs::read_to_string(&path) {
+        Ok(data) => data,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => String::new(),
+        Err(e) => return Err(e.into()),
+    };
+    let invalid =
+        |e: &dyn std::fmt::Display| Error::InvalidConfig(path.display().to_string(), e.to_string());
+    let mut doc: DocumentMut = data.parse().map_err(|e| invalid(&e))?;
+
+    match (key, value) {
+        (None, _) => {
+            for (key, value) in settings(&doc) {
+                println!("{key} = {value}");
+            }
+        }
+        (Some(key), None) => {
+            if let Some((_, value)) = settings(&doc).into_iter().find(|(k, _)| k == key) {
+                println!("{value}");
+            }
+        }
+        (Some(key), Some(value)) => {
+            set(&mut doc, key, value)?;
+            let config = Config::parse(&doc.to_string()).map_err(|e| invalid(&e))?;
+            config.defaults.unwrap_or_default().check()?;
+            if let Some(dir) = path.parent() {
+                fs::create_dir_all(dir)?;
+            }
+            fs::write(&path, doc.to_string())?;
+        }
+    }
+    Ok(())
+}
+
+/// Returns the settings in `doc` as `SECTION.KEY` and the value, with strings unquoted.
+fn settings(doc: &DocumentMut) -> Vec<(String, String)> {
+    doc.iter()
+        .filter_map(|(section, item)| Some((section, item.as_table_like()?)))
+        .flat_map(|(section, table)| {
+            table.iter().filter_map(move |(key, item)| {
+                let value = item.as_value()?;
+                let value = match value.as_str() {
+                    Some(s) => s.to_string(),
+                    None => value.to_string().trim().to_string(),
+                };
+                Some((format!("{section}.{key}"), value))
+            })
+        })
+        .collect()
+}
+
+/// Sets `key` (`SECTION.KEY`) to `value`, which is a TOML value such as a number or
+/// `true`, or otherwise a string. An empty `value` removes `key`.
+fn set(doc: &mut DocumentMut, key: &str, value: &str) -> Result<(), Error> {
+    let (section, name) = key
+        .split_once('.')
+        .filter(|(section, name)| !section.is_empty() && !name.is_empty())
+        .ok_or_else(|| Error::InvalidConfigKey(key.into()))?;
+
+    if value.is_empty() {
+        if let Some(table) = doc.get_mut(section).and_then(Item::as_table_like_mut) {
+            table.remove(name);
+            if table.is_empty() {
+                doc.remove(section);
+            }
+        }
+        return Ok(());
+    }
+
+    let value = value
+        .parse::<Value>()
+        .unwrap_or_else(|_| Value::from(value));
+    let table = doc
+        .entry(section)
+        .or_insert_with(|| Item::Table(Table::new()))
+        .as_table_like_mut()
+        .ok_or_else(|| Error::InvalidConfigKey(key.into()))?;
+    table.insert(name, Item::Value(value));
+    Ok(())
+}
+
+/// Loads the configuration file, and decides which remote daemon (if any) to use,
+/// whether to cache PINs in the PIN agent, how to wait for YubiKeys, and the defaults
+/// for flags.
+///
+/// `remote` comes from the command line, and takes precedence over `AGE_YUBIKEY_REMOTE`,
+/// which takes precedence over the configuration file. Both only replace the address;
+/// the other settings of the file's `[remote]` section still apply. Likewise,
+/// `AGE_YUBIKEY_PIN_AGENT_TTL` takes precedence over the file's `[pin_agent]` section,
+/// and `AGE_YUBIKEY_WAIT_TIMEOUT`, `AGE_YUBIKEY_POLL_INTERVAL_MS` and
+/// `AGE_YUBIKEY_WAIT_UNATTENDED` over its `[wait]` section.
+pub(crate) fn configure(remote: Option<String>) -> Result<(), Error> {
+    let config = Config::load()?;
+
+    let defaults = config.defaults.unwrap_or_default();
+    defaults.check()?;
+    if let Some(language) = defaults.language()? {
+        i18n::select(language);
+    }
+    *DEFAULTS.lock().unwrap() = defaults;
+
+    *WAIT.lock().unwrap() = Wait::resolve(config.wait, |var| {
+        env::var(var).ok().filter(|v| !v.is_empty())
+    })?;
+
+    *PIN_AGENT_TTL.lock().unwrap() = resolve_pin_agent_ttl(
+        env::var(PIN_AGENT_TTL_ENV).ok().filter(|t| !t.is_empty()),
+        config.pin_agent,
+    )?;
+
+    let address = remote.or_else(|| env::var(REMOTE_ENV).ok().filter(|a| !a.is_empty()));
+    let remote = match (config.remote, address) {
+        (Some(remote), Some(address)) => Some(RemoteConfig { address, ..remote }),
+        (None, Some(address)) => Some(RemoteConfig {
+            address,
+            ..Default::default()
+        }),
+        (remote, None) => remote,
+    };
+    if remote.is_some() && !cfg!(feature = "remote") {
+        return Err(Error::RemoteNotBuilt);
+    }
+    *REMOTE.lock().unwrap() = remote;
+    Ok(())
+}
+
+/// Decides how long the PIN agent caches PINs, from `AGE_YUBIKEY_PIN_AGENT_TTL` (`env`)
+/// or else the file's `[pin_agent]` section. `None` disables the agent.
+fn resolve_pin_agent_ttl(
+    env: Option<String>,
+    config: Option<PinAgentConfig>,
+) -> Result<Option<Duration>, Error> {
+    let ttl = match env {
+        Some(ttl) => ttl.parse().map_err(|_| Error::InvalidPinAgentTtl(ttl))?,
+        None => config.map_or(0, |agent| agent.ttl),
+    };
+    if ttl > MAX_PIN_AGENT_TTL {
+        return Err(Error::InvalidPinAgentTtl(ttl.to_string()));
+    }
+    Ok(Some(Duration::from_secs(ttl)).filter(|t| !t.is_zero()))
+}
+
+/// Returns the remote daemon to use, if one is configured.
+#[cfg_attr(not(feature = "remote"), allow(dead_code))]
+pub(crate) fn remote() -> Option<RemoteConfig> {
+    REMOTE.lock().unwrap().clone()
+}
+
+/// Returns how long the PIN agent should cache PINs, if it is enabled.
+#[cfg_attr(not(unix), allow(dead_code))]
+pub(crate) fn pin_agent_ttl() -> Option<Duration> {
+    *PIN_AGENT_TTL.lock().unwrap()
+}
+
+/// Returns the defaults for flags.
+pub(crate) fn defaults() -> Defaults {
+    DEFAULTS.lock().unwrap().clone()
+}
+
+/// Returns how to wait for a YubiKey to be inserted. Unattended runs that shouldn't
+/// wait get a zero timeout.
+pub(crate) fn wait() -> Wait {
+    let wait = *WAIT.lock().unwrap();
+    if !wait.unattended && crate::pin::unattended().is_some() {
+        Wait {
+            timeout: Duration::ZERO,
+            ..wait
+        }
+    } else {
+        wait
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use toml_edit::DocumentMut;
+
+    use super::{
+        resolve_pin_agent_ttl, set, settings, Config, PinAgentConfig, Wait, MAX_PIN_AGENT_TTL,
+    };
+    use crate::error::Error;
+
+    #[test]
+    fn pin_agent_ttl() {
+        let file = |ttl| Some(PinAgentConfig { ttl });
+        assert_eq!(resolve_pin_agent_ttl(None, None).unwrap(), None);
+        assert_eq!(resolve_pin_agent_ttl(None, file(0)).unwrap(), None);
+        assert_eq!(
+            resolve_pin_agent_ttl(None, file(300)).unwrap(),
+            Some(Duration::from_secs(300))
+        );
+        // The environment takes precedence over the file.
+        assert_eq!(
+            resolve_pin_agent_ttl(Some("60".into()), file(300)).unwrap(),
+            Some(Duration::from_secs(60))
+        );
+        assert_eq!(
+            resolve_pin_agent_ttl(Some(MAX_PIN_AGENT_TTL.to_string()), None).unwrap(),
+            Some(Duration::from_secs(MAX_PIN_AGENT_TTL))
+        );
+
+        assert!(matches!(
+            resolve_pin_agent_ttl(Some("soon".into()), None),
+            Err(Error::InvalidPinAgentTtl(ttl)) if ttl == "soon"
+        ));
+        assert!(matches!(
+            resolve_pin_agent_ttl(None, file(u64::MAX)),
+            Err(Error::InvalidPinAgentTtl(ttl)) if ttl == u64::MAX.to_string()
+        ));
+        assert!(matches!(
+            resolve_pin_agent_ttl(Some((MAX_PIN_AGENT_TTL + 1).to_string()), None),
+            Err(Error::InvalidPinAgentTtl(_))
+        ));
+    }
+
+    #[test]
+    fn parse() {
+        let config = Config::parse("").unwrap();
+        assert!(config.remote.is_none());
+        assert!(config.pin_agent.is_none());
+
+        let config = Config::parse("[pin_agent]\nttl = 300\n").unwrap();
+        assert_eq!(config.pin_agent.unwrap().ttl, 300);
+
+        let config = Config::parse(
+            r#"
+            [remote]
+            address = "yubikeys.example.com:3240"
+            token = "secret"
+            token_file = "/run/user/1000/sso-token"
+            ca = "/etc/yk-agentd/ca.pem"
+            quic = true
+            "#,
+        )
+        .unwrap();
+        let remote = config.remote.unwrap();
+        assert_eq!(remote.address, "yubikeys.example.com:3240");
+        assert_eq!(remote.token.as_deref(), Some("secret"));
+        assert_eq!(
+            remote.token_file.as_deref(),
+            Some(std::path::Path::new("/run/user/1000/sso-token"))
+        );
+        assert_eq!(remote.server_name(), "yubikeys.example.com");
+        assert!(remote.quic);
+
+        assert!(Config::parse("[remote]\naddress = \"host:3240\"\nport = 1\n").is_err());
+        assert!(Config::parse("[remote]\ntoken = \"secret\"\n").is_err());
+    }
+
+    #[test]
+    fn edit() {
+        let mut doc: DocumentMut =
+            "# Shared by the whole team\n[remote]\naddress = \"host:3240\"\n"
+                .parse()
+                .unwrap();
+        set(&mut doc, "defaults.serial", "12345678").unwrap();
+        set(&mut doc, "defaults.pin_policy", "always").unwrap();
+        set(&mut doc, "remote.address", "unix:/run/yk-agentd.sock").unwrap();
+        assert!(set(&mut doc, "serial", "1").is_err());
+        assert!(doc.to_string().starts_with("# Shared by the whole team\n"));
+        assert_eq!(
+            settings(&doc),
+            vec![
+                ("remote.address".into(), "unix:/run/yk-agentd.sock".into()),
+                ("defaults.serial".into(), "12345678".into()),
+                ("defaults.pin_policy".into(), "always".into()),
+            ]
+        );
+
+        let defaults = Config::parse(&doc.to_string()).unwrap().defaults.unwrap();
+        assert_eq!(defaults.serial, Some(12345678));
+        assert!(defaults.check().is_ok());
+
+        // Removing the last key of a section removes the section.
+        set(&mut doc, "remote.address", "").unwrap();
+        assert_eq!(settings(&doc).len(), 2);
+        assert!(doc.get("remote").is_none());
+
+        set(&mut doc, "defaults.touch_policy", "sometimes").unwrap();
+        let defaults = Config::parse(&doc.to_string()).unwrap().defaults.unwrap();
+        assert!(defaults.check().is_err());
+    }
+
+    #[test]
+    fn wait() {
+        let no_env = |_: &str| None;
+        assert_eq!(Wait::resolve(None, no_env).unwrap(), Wait::default());
+
+        let config = Config::parse("[wait]\ntimeout = 0\nunattended = false\n").unwrap();
+        let wait = Wait::resolve(config.wait, no_env).unwrap();
+        assert_eq!(wait.timeout, Duration::ZERO);
+        assert_eq!(wait.poll_interval, Duration::from_secs(1));
+        assert!(!wait.unattended);
+
+        // The environment takes precedence over the file.
+        let config = Config::parse("[wait]\ntimeout = 60\npoll_interval_ms = 250\n").unwrap();
+        let env = |var: &str| (var == "AGE_YUBIKEY_WAIT_TIMEOUT").then(|| "5".to_string());
+        let wait = Wait::resolve(config.wait, env).unwrap();
+        assert_eq!(wait.timeout, Duration::from_secs(5));
+        assert_eq!(wait.poll_interval, Duration::from_millis(250));
+
+        let env = |_: &str| Some("soon".to_string());
+        assert!(Wait::resolve(None, env).is_err());
+        let config = Config::parse("[wait]\npoll_interval_ms = 0\n").unwrap();
+        assert!(Wait::resolve(config.wait, no_env).is_err());
+        assert!(Config::parse("[wait]\ntimeout = -1\n").is_err());
+    }
+
+    #[test]
+    fn server_name() {
+        let mut remote = Config::parse("[remote]\naddress = \"[::1]:3240\"\n")
+            .unwrap()
+            .remote
+            .unwrap();
+        assert_eq!(remote.server_name(), "::1");
+        remote.server_name = Some("yubikeys.example.com".into());
+        assert_eq!(remote.server_name(), "yubikeys.example.com");
+    }
+}
@@ -1,8 +1,12 @@
+use std::error;
 use std::fmt;
 use std::io;
-use yubikey::{piv::RetiredSlotId, Serial};
+use std::sync::atomic::{AtomicBool, Ordering};
 
-use crate::util::slot_to_ui;
+use serde_json::{json, Map, Value};
+use yubikey::{piv::SlotId, Serial};
+
+use crate::util::{slot_json, slot_name};
 
 macro_rules! wlnfl {
     ($f:ident, $message_id:literal) => {
@@ -13,25 +17,77 @@ macro_rules! wlnfl {
     }};
 }
 
+/// Whether errors print their chain of underlying causes (`--verbose`).
+pub(crate) static VERBOSE: AtomicBool = AtomicBool::new(false);
+
 pub enum Error {
+    AesManagementKey,
+    AllNeedsIdentity,
+    CommandNeedsSlot(String),
     CustomManagementKey,
+    DefaultManagementKey,
+    DefaultPin(Serial),
     Dialog(dialoguer::Error),
+    FileExists(String),
+    FinalPinTry(Serial),
+    FinalPukTry(Serial),
+    FormatNeedsList,
+    ImportNeedsKey,
+    InvalidAlgorithm(String),
+    InvalidAttestationCa(String),
+    InvalidConfig(String, String),
+    InvalidConfigKey(String),
+    InvalidErrorFormat(String),
+    InvalidEscrow(String, String),
     InvalidFlagCommand(String, String),
     InvalidFlagTui(String),
+    InvalidIdentity(String),
+    InvalidLanguage(String),
+    InvalidListFormat(String),
+    InvalidLogFormat(String),
+    InvalidLogLevel(String),
+    InvalidManagementKey,
+    InvalidPinAgentTtl(String),
     InvalidPinPolicy(String),
-    InvalidSlot(u8),
+    InvalidProvisionSpec(String),
+    InvalidPrivateKey,
+    InvalidPublicKey,
+    InvalidRecipient(String),
+    InvalidSlot(String),
     InvalidTouchPolicy(String),
+    InvalidUnattendedPin,
+    InvalidWaitSetting(String, String),
     Io(io::Error),
     ManagementKeyAuth,
     MultipleCommands,
     MultipleYubiKeys,
+    NoAttestation(SlotId),
     NoEmptySlots(Serial),
+    NoIdentities(Serial),
     NoMatchingSerial(Serial),
+    ProvisionFailed(usize),
+    ProvisionNeedsUnattendedPin,
+    ReadOnly(String),
+    ReadOnlyTui,
+    RecipientNotFound(String),
+    RemoteNotBuilt,
     PukLocked,
-    SlotHasNoIdentity(RetiredSlotId),
-    SlotIsNotEmpty(RetiredSlotId),
+    RenameNeedsName,
+    SlotHasCertificate(SlotId),
+    SlotHasNoIdentity(SlotId),
+    SlotIsNotEmpty(SlotId),
+    SlotKeyMismatch(SlotId),
+    #[cfg_attr(unix, allow(dead_code))]
+    SshAgentNotSupported,
+    StubMismatch(SlotId),
     TimedOut,
+    UnattendedDefaultPin,
+    UnattendedPinNotAllowed,
+    UnexpectedArgument(String),
+    UnknownSlotPolicies(SlotId),
     UseListForSingleSlot,
+    VerificationFailed(SlotId),
+    WrongManagementKey(bool),
     WrongPuk(u8),
     YubiKey(yubikey::Error),
 }
@@ -54,15 +110,252 @@ impl From<yubikey::Error> for Error {
     }
 }
 
+impl error::Error for Error {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            Error::Dialog(e) => Some(e),
+            Error::Io(e) => Some(e),
+            Error::YubiKey(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl Error {
+    /// Returns a code identifying the kind of this error, for programs that need to tell
+    /// errors apart (see `--error-format json`). Codes are stable across releases and
+    /// languages, unlike error messages.
+    pub fn code(&self) -> &'static str {
+        match self {
+            Error::AesManagementKey => "aes-mgmt-key",
+            Error::AllNeedsIdentity => "all-needs-identity",
+            Error::CommandNeedsSlot(_) => "command-needs-slot",
+            Error::CustomManagementKey => "custom-mgmt-key",
+            Error::DefaultManagementKey => "default-mgmt-key",
+            Error::DefaultPin(_) => "default-pin",
+            Error::Dialog(_) => "io-user",
+            Error::FileExists(_) => "file-exists",
+            Error::FinalPinTry(_) => "final-pin-try",
+            Error::FinalPukTry(_) => "final-puk-try",
+            Error::FormatNeedsList => "format-needs-list",
+            Error::ImportNeedsKey => "import-needs-key",
+            Error::InvalidAlgorithm(_) => "invalid-algorithm",
+            Error::InvalidAttestationCa(_) => "invalid-attestation-ca",
+            Error::InvalidConfig(_, _) => "invalid-config",
+            Error::InvalidConfigKey(_) => "invalid-config-key",
+            Error::InvalidErrorFormat(_) => "invalid-error-format",
+            Error::InvalidEscrow(_, _) => "invalid-escrow",
+            Error::InvalidFlagCommand(_, _) => "invalid-flag-command",
+            Error::InvalidFlagTui(_) => "invalid-flag-tui",
+            Error::InvalidIdentity(_) => "invalid-identity",
+            Error::InvalidLanguage(_) => "invalid-language",
+            Error::InvalidListFormat(_) => "invalid-list-format",
+            Error::InvalidLogFormat(_) => "invalid-log-format",
+            Error::InvalidLogLevel(_) => "invalid-log-level",
+            Error::InvalidManagementKey => "invalid-mgmt-key",
+            Error::InvalidPinAgentTtl(_) => "invalid-pin-agent-ttl",
+            Error::InvalidPinPolicy(_) => "invalid-pin-policy",
+            Error::InvalidProvisionSpec(_) => "invalid-provision-spec",
+            Error::InvalidPrivateKey => "invalid-private-key",
+            Error::InvalidPublicKey => "invalid-public-key",
+            Error::InvalidRecipient(_) => "invalid-recipient",
+            Error::InvalidSlot(_) => "invalid-slot",
+            Error::InvalidTouchPolicy(_) => "invalid-touch-policy",
+            Error::InvalidUnattendedPin => "invalid-unattended-pin",
+            Error::InvalidWaitSetting(..) => "invalid-wait-setting",
+            Error::Io(_) => "io",
+            Error::ManagementKeyAuth => "mgmt-key-auth",
+            Error::MultipleCommands => "multiple-commands",
+            Error::MultipleYubiKeys => "multiple-yubikeys",
+            Error::NoAttestation(_) => "no-attestation",
+            Error::NoEmptySlots(_) => "no-empty-slots",
+            Error::NoIdentities(_) => "no-identities",
+            Error::NoMatchingSerial(_) => "no-matching-serial",
+            Error::ProvisionFailed(_) => "provision-failed",
+            Error::ProvisionNeedsUnattendedPin => "provision-needs-pin",
+            Error::ReadOnly(_) => "read-only",
+            Error::ReadOnlyTui => "read-only-tui",
+            Error::RecipientNotFound(_) => "recipient-not-found",
+            Error::RemoteNotBuilt => "remote-not-built",
+            Error::PukLocked => "puk-locked",
+            Error::RenameNeedsName => "rename-needs-name",
+            Error::SlotHasCertificate(_) => "slot-has-certificate",
+            Error::SlotHasNoIdentity(_) => "slot-has-no-identity",
+            Error::SlotIsNotEmpty(_) => "slot-is-not-empty",
+            Error::SlotKeyMismatch(_) => "slot-key-mismatch",
+            Error::SshAgentNotSupported => "ssh-agent-not-supported",
+            Error::StubMismatch(_) => "stub-mismatch",
+            Error::TimedOut => "timed-out",
+            Error::UnattendedDefaultPin => "unattended-default-pin",
+            Error::UnattendedPinNotAllowed => "unattended-pin-not-allowed",
+            Error::UnexpectedArgument(_) => "unexpected-argument",
+            Error::UnknownSlotPolicies(_) => "unknown-slot-policies",
+            Error::UseListForSingleSlot => "use-list-for-single",
+            Error::VerificationFailed(_) => "verification-failed",
+            Error::WrongManagementKey(_) => "wrong-mgmt-key",
+            Error::WrongPuk(_) => "wrong-puk",
+            Error::YubiKey(e) => match e {
+                yubikey::Error::NotFound => "yubikey-not-found",
+                yubikey::Error::PcscError {
+                    inner: Some(pcsc::Error::NoService),
+                } => "no-smartcard-service",
+                yubikey::Error::PcscError {
+                    inner: Some(pcsc::Error::SharingViolation),
+                } => "sharing-violation",
+                e if crate::key::is_removed(e) => "yubikey-removed",
+                yubikey::Error::PinLocked => "pin-locked",
+                yubikey::Error::WrongPin { .. } => "wrong-pin",
+                _ => "yubikey",
+            },
+        }
+    }
+
+    /// Returns the values in this error that programs may act on, such as the number of
+    /// PIN retries left.
+    fn details(&self) -> Map<String, Value> {
+        let mut details = Map::new();
+        let mut add = |name: &str, value: Value| {
+            details.insert(name.into(), value);
+        };
+        match self {
+            Error::CommandNeedsSlot(command) | Error::ReadOnly(command) => {
+                add("command", command.as_str().into())
+            }
+            Error::InvalidAlgorithm(value)
+            | Error::InvalidErrorFormat(value)
+            | Error::InvalidLanguage(value)
+            | Error::InvalidListFormat(value)
+            | Error::InvalidLogFormat(value)
+            | Error::InvalidLogLevel(value)
+            | Error::InvalidPinAgentTtl(value)
+            | Error::InvalidPinPolicy(value)
+            | Error::InvalidTouchPolicy(value) => add("value", value.as_str().into()),
+            Error::FileExists(path)
+            | Error::InvalidAttestationCa(path)
+            | Error::InvalidConfig(path, _)
+            | Error::InvalidEscrow(path, _) => add("path", path.as_str().into()),
+            Error::InvalidFlagCommand(flag, command) => {
+                add("flag", flag.as_str().into());
+                add("command", command.as_str().into());
+            }
+            Error::InvalidConfigKey(key) => add("key", key.as_str().into()),
+            Error::InvalidFlagTui(flag) => add("flag", flag.as_str().into()),
+            Error::InvalidWaitSetting(setting, value) => {
+                add("setting", setting.as_str().into());
+                add("value", value.as_str().into());
+            }
+            Error::InvalidIdentity(identity) => add("identity", identity.as_str().into()),
+            Error::InvalidRecipient(recipient) | Error::RecipientNotFound(recipient) => {
+                add("recipient", recipient.as_str().into())
+            }
+            // Like `slot_json`, numbers stay numbers.
+            Error::InvalidSlot(slot) => add(
+                "slot",
+                slot.parse::<u64>()
+                    .map_or_else(|_| slot.as_str().into(), Into::into),
+            ),
+            Error::NoAttestation(slot)
+            | Error::SlotHasCertificate(slot)
+            | Error::SlotHasNoIdentity(slot)
+            | Error::SlotIsNotEmpty(slot)
+            | Error::SlotKeyMismatch(slot)
+            | Error::StubMismatch(slot)
+            | Error::UnknownSlotPolicies(slot)
+            | Error::VerificationFailed(slot) => add("slot", slot_json(*slot)),
+            Error::DefaultPin(serial) => add("serial", serial.0.into()),
+            Error::FinalPinTry(serial) | Error::FinalPukTry(serial) => {
+                add("serial", serial.0.into());
+                add("tries", 1.into());
+            }
+            Error::NoEmptySlots(serial)
+            | Error::NoIdentities(serial)
+            | Error::NoMatchingSerial(serial) => add("serial", serial.0.into()),
+            Error::ProvisionFailed(count) => add("count", (*count).into()),
+            Error::UnexpectedArgument(arg) => add("argument", arg.as_str().into()),
+            Error::WrongPuk(tries) | Error::YubiKey(yubikey::Error::WrongPin { tries }) => {
+                add("tries", (*tries).into())
+            }
+            Error::YubiKey(yubikey::Error::PcscError { inner: Some(e) }) => {
+                add("pcsc_code", (*e as u32).into())
+            }
+            _ => (),
+        }
+        details
+    }
+
+    /// Formats this error as a single-line JSON object with its code, localized message,
+    /// details, and underlying causes.
+    pub(crate) fn to_json(&self) -> String {
+        let mut causes = vec![];
+        let mut cause = error::Error::source(self);
+        while let Some(e) = cause {
+            causes.push(Value::from(describe_cause(e)));
+            cause = e.source();
+        }
+        json!({
+            "code": self.code(),
+            "message": self.to_string().trim_end(),
+            "details": self.details(),
+            "causes": causes,
+        })
+        .to_string()
+    }
+}
+
+/// Describes a single cause, including the raw code for PC/SC errors so that it can
+/// be looked up in platform documentation.
+fn describe_cause(e: &(dyn error::Error + 'static)) -> String {
+    match e.downcast_ref::<pcsc::Error>() {
+        Some(&inner) => format!("{} (PC/SC 0x{:08X})", inner, inner as u32),
+        None => e.to_string(),
+    }
+}
+
 // Rust only supports `fn main() -> Result<(), E: Debug>`, so we implement `Debug`
 // manually to provide the error output we want.
 impl fmt::Debug for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{self}")?;
+        if VERBOSE.load(Ordering::Relaxed) {
+            let mut cause = error::Error::source(self);
+            while let Some(e) = cause {
+                wlnfl!(f, "err-caused-by", err = describe_cause(e))?;
+                cause = e.source();
+            }
+        }
+        writeln!(f)?;
+        writeln!(f, "[ {} ]", crate::fl!("err-ux-A"))?;
+        write!(
+            f,
+            "[ {}: https://str4d.xyz/age-plugin-yubikey/report {} ]",
+            crate::fl!("err-ux-B"),
+            crate::fl!("err-ux-C")
+        )
+    }
+}
+
+/// Prints the localized error message, followed by any remediation hints.
+impl fmt::Display for Error {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         const CHANGE_MGMT_KEY_CMD: &str =
             "ykman piv access change-management-key -a TDES --protect";
         const CHANGE_MGMT_KEY_URL: &str = "https://developers.yubico.com/yubikey-manager/";
+        const AES_URL: &str = "https://github.com/str4d/age-plugin-yubikey/issues/92";
 
         match self {
+            Error::AesManagementKey => {
+                wlnfl!(f, "err-aes-mgmt-key", aes_url = AES_URL)?;
+                wlnfl!(
+                    f,
+                    "rec-change-mgmt-key",
+                    cmd = CHANGE_MGMT_KEY_CMD,
+                    url = CHANGE_MGMT_KEY_URL
+                )?;
+            }
+            Error::AllNeedsIdentity => wlnfl!(f, "err-all-needs-identity")?,
+            Error::CommandNeedsSlot(command) => {
+                wlnfl!(f, "err-command-needs-slot", command = command.as_str())?
+            }
             Error::CustomManagementKey => {
                 wlnfl!(f, "err-custom-mgmt-key")?;
                 wlnfl!(
@@ -72,7 +365,78 @@ impl fmt::Debug for Error {
                     url = CHANGE_MGMT_KEY_URL
                 )?;
             }
+            Error::DefaultManagementKey => wlnfl!(f, "err-default-mgmt-key")?,
+            Error::DefaultPin(serial) => {
+                wlnfl!(f, "err-default-pin", serial = serial.to_string())?;
+                wlnfl!(
+                    f,
+                    "rec-default-pin",
+                    pin_cmd = "age-plugin-yubikey --change-pin",
+                    puk_cmd = "age-plugin-yubikey --change-puk",
+                )?;
+            }
             Error::Dialog(e) => wlnfl!(f, "err-io-user", err = e.to_string())?,
+            Error::FileExists(path) => wlnfl!(f, "err-file-exists", path = path.as_str())?,
+            Error::FinalPinTry(serial) => {
+                wlnfl!(
+                    f,
+                    "err-final-pin-try",
+                    serial = serial.to_string(),
+                    pin_kind = "PIN",
+                )?;
+                wlnfl!(
+                    f,
+                    "rec-final-pin-try",
+                    pin_kind = "PIN",
+                    env = "AGE_YUBIKEY_RISK_LOCKOUT",
+                )?;
+            }
+            Error::FinalPukTry(serial) => {
+                wlnfl!(
+                    f,
+                    "err-final-pin-try",
+                    serial = serial.to_string(),
+                    pin_kind = "PUK",
+                )?;
+                wlnfl!(
+                    f,
+                    "rec-final-pin-try",
+                    pin_kind = "PUK",
+                    env = "AGE_YUBIKEY_RISK_LOCKOUT",
+                )?;
+            }
+            Error::FormatNeedsList => wlnfl!(f, "err-format-needs-list")?,
+            Error::ImportNeedsKey => wlnfl!(f, "err-import-needs-key")?,
+            Error::InvalidAlgorithm(s) => wlnfl!(
+                f,
+                "err-invalid-algorithm",
+                algorithm = s.as_str(),
+                expected = "p256, p384, rsa2048",
+            )?,
+            Error::InvalidAttestationCa(path) => {
+                wlnfl!(f, "err-invalid-attestation-ca", path = path.as_str())?
+            }
+            Error::InvalidConfig(path, e) => wlnfl!(
+                f,
+                "err-invalid-config",
+                path = path.as_str(),
+                err = e.as_str(),
+            )?,
+            Error::InvalidConfigKey(key) => {
+                wlnfl!(f, "err-invalid-config-key", key = key.as_str())?
+            }
+            Error::InvalidErrorFormat(format) => wlnfl!(
+                f,
+                "err-invalid-error-format",
+                format = format.as_str(),
+                expected = "text, json",
+            )?,
+            Error::InvalidEscrow(path, e) => wlnfl!(
+                f,
+                "err-invalid-escrow",
+                path = path.as_str(),
+                err = e.as_str(),
+            )?,
             Error::InvalidFlagCommand(flag, command) => wlnfl!(
                 f,
                 "err-invalid-flag-command",
@@ -80,24 +444,65 @@ impl fmt::Debug for Error {
                 command = command.as_str(),
             )?,
             Error::InvalidFlagTui(flag) => wlnfl!(f, "err-invalid-flag-tui", flag = flag.as_str())?,
+            Error::InvalidIdentity(s) => wlnfl!(f, "err-invalid-identity", identity = s.as_str())?,
+            Error::InvalidLanguage(language) => {
+                wlnfl!(f, "err-invalid-language", language = language.as_str())?
+            }
+            Error::InvalidListFormat(format) => wlnfl!(
+                f,
+                "err-invalid-list-format",
+                format = format.as_str(),
+                expected = "text, pkcs11-uri",
+            )?,
+            Error::InvalidLogFormat(format) => wlnfl!(
+                f,
+                "err-invalid-log-format",
+                format = format.as_str(),
+                expected = "text, json",
+            )?,
+            Error::InvalidLogLevel(level) => {
+                wlnfl!(f, "err-invalid-log-level", level = level.as_str())?
+            }
+            Error::InvalidManagementKey => wlnfl!(f, "err-invalid-mgmt-key")?,
+            Error::InvalidPinAgentTtl(ttl) => wlnfl!(
+                f,
+                "err-invalid-pin-agent-ttl",
+                ttl = ttl.as_str(),
+                max = crate::config::MAX_PIN_AGENT_TTL.to_string(),
+            )?,
             Error::InvalidPinPolicy(s) => wlnfl!(
                 f,
                 "err-invalid-pin-policy",
                 policy = s.as_str(),
                 expected = "always, once, never",
             )?,
-            Error::InvalidSlot(slot) => wlnfl!(f, "err-invalid-slot", slot = slot)?,
+            Error::InvalidProvisionSpec(e) => {
+                wlnfl!(f, "err-invalid-provision-spec", err = e.as_str())?
+            }
+            Error::InvalidPrivateKey => wlnfl!(f, "err-invalid-private-key")?,
+            Error::InvalidPublicKey => wlnfl!(f, "err-invalid-public-key")?,
+            Error::InvalidRecipient(recipient) => {
+                wlnfl!(f, "err-invalid-recipient", recipient = recipient.as_str(),)?
+            }
+            Error::InvalidSlot(slot) => wlnfl!(f, "err-invalid-slot", slot = slot.as_str())?,
             Error::InvalidTouchPolicy(s) => wlnfl!(
                 f,
                 "err-invalid-touch-policy",
                 policy = s.as_str(),
                 expected = "always, cached, never",
             )?,
+            Error::InvalidUnattendedPin => wlnfl!(f, "err-invalid-unattended-pin")?,
+            Error::InvalidWaitSetting(setting, value) => wlnfl!(
+                f,
+                "err-invalid-wait-setting",
+                setting = setting.as_str(),
+                value = value.as_str(),
+                unattended_env = "AGE_YUBIKEY_WAIT_UNATTENDED",
+            )?,
             Error::Io(e) => wlnfl!(f, "err-io", err = e.to_string())?,
             Error::ManagementKeyAuth => {
-                let aes_url = "https://github.com/str4d/age-plugin-yubikey/issues/92";
                 wlnfl!(f, "err-mgmt-key-auth")?;
-                wlnfl!(f, "rec-mgmt-key-auth", aes_url = aes_url)?;
+                wlnfl!(f, "rec-mgmt-key-auth", aes_url = AES_URL)?;
                 wlnfl!(
                     f,
                     "rec-change-mgmt-key",
@@ -107,21 +512,73 @@ impl fmt::Debug for Error {
             }
             Error::MultipleCommands => wlnfl!(f, "err-multiple-commands")?,
             Error::MultipleYubiKeys => wlnfl!(f, "err-multiple-yubikeys")?,
+            Error::NoAttestation(slot) => wlnfl!(f, "err-no-attestation", slot = slot_name(*slot))?,
             Error::NoEmptySlots(serial) => {
                 wlnfl!(f, "err-no-empty-slots", serial = serial.to_string())?
             }
+            Error::NoIdentities(serial) => {
+                wlnfl!(f, "err-no-identities", serial = serial.to_string())?
+            }
             Error::NoMatchingSerial(serial) => {
                 wlnfl!(f, "err-no-matching-serial", serial = serial.to_string())?
             }
-            Error::PukLocked => wlnfl!(f, "err-yk-pin-locked", pin_kind = "PUK")?,
+            Error::ProvisionFailed(count) => wlnfl!(f, "err-provision-failed", count = count)?,
+            Error::ProvisionNeedsUnattendedPin => wlnfl!(f, "err-provision-needs-pin")?,
+            Error::ReadOnly(command) => wlnfl!(f, "err-read-only", command = command.as_str())?,
+            Error::ReadOnlyTui => {
+                wlnfl!(f, "err-read-only-tui")?;
+                wlnfl!(f, "rec-read-only-tui")?;
+            }
+            Error::RecipientNotFound(recipient) => {
+                wlnfl!(f, "err-recipient-not-found", recipient = recipient.as_str())?;
+                wlnfl!(f, "rec-recipient-not-found")?;
+            }
+            Error::PukLocked => {
+                wlnfl!(f, "err-yk-pin-locked", pin_kind = "PUK")?;
+                wlnfl!(f, "rec-yk-puk-locked", cmd = "ykman piv reset")?;
+            }
+            Error::RemoteNotBuilt => wlnfl!(f, "err-remote-not-built")?,
+            Error::RenameNeedsName => wlnfl!(f, "err-rename-needs-name")?,
+            Error::SlotHasCertificate(slot) => {
+                wlnfl!(f, "err-slot-has-certificate", slot = slot_name(*slot))?
+            }
             Error::SlotHasNoIdentity(slot) => {
-                wlnfl!(f, "err-slot-has-no-identity", slot = slot_to_ui(slot))?
+                wlnfl!(f, "err-slot-has-no-identity", slot = slot_name(*slot))?
             }
             Error::SlotIsNotEmpty(slot) => {
-                wlnfl!(f, "err-slot-is-not-empty", slot = slot_to_ui(slot))?
+                wlnfl!(f, "err-slot-is-not-empty", slot = slot_name(*slot))?
             }
+            Error::SlotKeyMismatch(slot) => {
+                wlnfl!(f, "err-slot-key-mismatch", slot = slot_name(*slot))?
+            }
+            Error::SshAgentNotSupported => wlnfl!(f, "err-ssh-agent-not-supported")?,
+            Error::StubMismatch(slot) => wlnfl!(f, "err-stub-mismatch", slot = slot_name(*slot))?,
             Error::TimedOut => wlnfl!(f, "err-timed-out")?,
+            Error::UnattendedDefaultPin => {
+                wlnfl!(f, "err-unattended-default-pin")?;
+                wlnfl!(
+                    f,
+                    "rec-unattended-default-pin",
+                    cmd = "ykman piv access change-pin"
+                )?;
+            }
+            Error::UnattendedPinNotAllowed => wlnfl!(f, "err-unattended-pin-not-allowed")?,
+            Error::UnexpectedArgument(arg) => {
+                wlnfl!(f, "err-unexpected-argument", arg = arg.as_str())?
+            }
+            Error::UnknownSlotPolicies(slot) => {
+                wlnfl!(f, "err-unknown-slot-policies", slot = slot_name(*slot))?
+            }
             Error::UseListForSingleSlot => wlnfl!(f, "err-use-list-for-single")?,
+            Error::VerificationFailed(slot) => {
+                wlnfl!(f, "err-verification-failed", slot = slot_name(*slot))?
+            }
+            Error::WrongManagementKey(may_be_aes) => {
+                wlnfl!(f, "err-wrong-mgmt-key")?;
+                if *may_be_aes {
+                    wlnfl!(f, "rec-mgmt-key-auth", aes_url = AES_URL)?;
+                }
+            }
             Error::WrongPuk(tries) => {
                 wlnfl!(f, "err-yk-wrong-pin", pin_kind = "PUK", tries = tries)?
             }
@@ -168,7 +625,28 @@ impl fmt::Debug for Error {
                         wlnfl!(f, "rec-yk-no-service-pcscd", apt = apt)?;
                     }
                 }
-                yubikey::Error::PinLocked => wlnfl!(f, "err-yk-pin-locked", pin_kind = "PIN")?,
+                yubikey::Error::PcscError {
+                    inner: Some(pcsc::Error::SharingViolation),
+                } => {
+                    wlnfl!(f, "err-yk-sharing-violation")?;
+                    wlnfl!(
+                        f,
+                        "rec-yk-sharing-violation",
+                        cmd = "gpgconf --kill scdaemon"
+                    )?;
+                }
+                e if crate::key::is_removed(e) => {
+                    wlnfl!(f, "err-yk-removed")?;
+                    wlnfl!(f, "rec-yk-removed")?;
+                }
+                yubikey::Error::PinLocked => {
+                    wlnfl!(f, "err-yk-pin-locked", pin_kind = "PIN")?;
+                    wlnfl!(
+                        f,
+                        "rec-yk-pin-locked",
+                        cmd = "age-plugin-yubikey --unblock-pin"
+                    )?;
+                }
                 yubikey::Error::WrongPin { tries } => {
                     wlnfl!(f, "err-yk-wrong-pin", pin_kind = "PIN", tries = tries)?
                 }
@@ -181,13 +659,58 @@ impl fmt::Debug for Error {
                 }
             },
         }
-        writeln!(f)?;
-        writeln!(f, "[ {} ]", crate::fl!("err-ux-A"))?;
-        write!(
-            f,
-            "[ {}: https://str4d.xyz/age-plugin-yubikey/report {} ]",
-            crate::fl!("err-ux-B"),
-            crate::fl!("err-ux-C")
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::{json, Value};
+    use yubikey::{
+        piv::{RetiredSlotId, SlotId},
+        Serial,
+    };
+
+    use super::Error;
+
+    #[test]
+    fn json() {
+        let e = Error::YubiKey(yubikey::Error::WrongPin { tries: 2 });
+        let value: Value = serde_json::from_str(&e.to_json()).unwrap();
+        assert_eq!(value["code"], "wrong-pin");
+        assert_eq!(value["details"], json!({ "tries": 2 }));
+        assert!(value["message"].as_str().unwrap().contains('2'));
+
+        let value: Value = serde_json::from_str(
+            &Error::SlotIsNotEmpty(SlotId::Retired(RetiredSlotId::R3)).to_json(),
         )
+        .unwrap();
+        assert_eq!(value["code"], "slot-is-not-empty");
+        assert_eq!(value["details"], json!({ "slot": 3 }));
+        assert_eq!(value["causes"], json!([]));
+
+        let value: Value =
+            serde_json::from_str(&Error::SlotIsNotEmpty(SlotId::KeyManagement).to_json()).unwrap();
+        assert_eq!(value["details"], json!({ "slot": "9d" }));
+
+        let value: Value =
+            serde_json::from_str(&Error::FinalPinTry(Serial::from(42)).to_json()).unwrap();
+        assert_eq!(value["code"], "final-pin-try");
+        assert_eq!(value["details"], json!({ "serial": 42, "tries": 1 }));
+
+        assert_eq!(
+            Error::YubiKey(yubikey::Error::NotFound).code(),
+            "yubikey-not-found"
+        );
+
+        let e = Error::YubiKey(yubikey::Error::PcscError {
+            inner: Some(pcsc::Error::RemovedCard),
+        });
+        let value: Value = serde_json::from_str(&e.to_json()).unwrap();
+        assert_eq!(value["code"], "yubikey-removed");
+        assert_eq!(
+            value["details"],
+            json!({ "pcsc_code": pcsc::Error::RemovedCard as u32 })
+        );
     }
 }
@@ -0,0 +1,226 @@
+//! Escrow files of identity stubs.
+//!
+//! An identity only refers to its key by the serial of its YubiKey, its slot and the
+//! tag of its recipient, and the plugin finds the recipient, name and policies of a
+//! slot in its certificate. `--export-stubs PATH` backs these up in a JSON file, and
+//! `--import-stubs PATH` prints the identities it holds again, as `--identity` did, so
+//! that they outlive a certificate wiped by another PIV tool:
+//!
+//! ```json
+//! {
+//!   "version": 1,
+//!   "stubs": [
+//!     {
+//!       "serial": 12345678,
+//!       "slot": 1,
+//!       "tag": "0a1b2c3d",
+//!       "name": "age identity 0a1b2c3d",
+//!       "created": "Thu, 01 Jan 2026 00:00:00 +0000",
+//!       "pin_policy": "once",
+//!       "touch_policy": "always",
+//!       "firmware": "5.4.3",
+//!       "recipient": "age1yubikey1...",
+//!       "identity": "AGE-PLUGIN-YUBIKEY-1..."
+//!     }
+//!   ]
+//! }
+//! ```
+//!
+//! The file holds no secrets, but whoever can change it can make the identities it
+//! restores point at other recipients; every stub is checked against its recipient
+//! when the file is read.
+
+use serde::{Deserialize, Serialize};
+use yubikey::piv::SlotId;
+
+use crate::{
+    error::Error,
+    fl,
+    key::{Stub, NO_SERIAL},
+    p256::Recipient,
+    util::{self, Metadata},
+};
+
+/// The version of the file format that we write and read.
+const VERSION: u32 = 1;
+
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct Escrow {
+    version: u32,
+    stubs: Vec<Entry>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+struct Entry {
+    /// `None` for YubiKeys that don't expose their serial.
+    serial: Option<u32>,
+    /// 1 to 20 for the retired slots, or a string such as `"9d"` for the standard slots.
+    slot: serde_json::Value,
+    tag: String,
+    name: String,
+    created: String,
+    pin_policy: String,
+    touch_policy: String,
+    firmware: Option<String>,
+    recipient: String,
+    identity: String,
+}
+
+impl Escrow {
+    pub(crate) fn new() -> Self {
+        Escrow {
+            version: VERSION,
+            stubs: vec![],
+        }
+    }
+
+    /// Adds the identity of `stub` to the file.
+    pub(crate) fn push(&mut self, stub: &Stub, recipient: &Recipient, metadata: &Metadata) {
+        self.stubs.push(Entry {
+            serial: Some(stub.serial.0).filter(|&serial| serial != NO_SERIAL),
+            slot: slot_value(stub.slot),
+            tag: hex::encode(stub.tag),
+            name: metadata.name.clone(),
+            created: metadata.created.clone(),
+            pin_policy: util::pin_policy_to_key(metadata.pin_policy).into(),
+            touch_policy: util::touch_policy_to_key(metadata.touch_policy).into(),
+            firmware: metadata.firmware.clone(),
+            recipient: recipient.to_string(),
+            identity: stub.to_string(),
+        });
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.stubs.len()
+    }
+
+    pub(crate) fn to_json(&self) -> String {
+        serde_json::to_string_pretty(self).expect("Escrow always serializes")
+    }
+
+    /// Parses the escrow file at `path`, with the given `contents`, into the identities
+    /// it holds.
+    ///
+    /// Each entry's identity must refer to the entry's recipient, serial and slot.
+    pub(crate) fn parse(
+        path: &str,
+        contents: &str,
+    ) -> Result<Vec<(Stub, Recipient, Metadata)>, Error> {
+        let escrow: Escrow = serde_json::from_str(contents)
+            .map_err(|e| Error::InvalidEscrow(path.into(), e.to_string()))?;
+        if escrow.version != VERSION {
+            return Err(Error::InvalidEscrow(
+                path.into(),
+                fl!(
+                    "escrow-unsupported-version",
+                    version = escrow.version.to_string(),
+                ),
+            ));
+        }
+
+        escrow
+            .stubs
+            .into_iter()
+            .map(|entry| {
+                let stub = Stub::decode(&entry.identity)
+                    .ok_or_else(|| Error::InvalidIdentity(entry.identity.clone()))?;
+                let recipient: Recipient = entry.recipient.parse()?;
+                if stub.tag != recipient.tag()
+                    || hex::encode(stub.tag) != entry.tag.to_lowercase()
+                    || stub.serial.0 != entry.serial.unwrap_or(NO_SERIAL)
+                    || slot_value(stub.slot) != entry.slot
+                {
+                    return Err(Error::InvalidEscrow(
+                        path.into(),
+                        fl!("escrow-mismatch", identity = entry.identity.as_str()),
+                    ));
+                }
+
+                let metadata = Metadata {
+                    serial: stub.serial,
+                    slot: stub.slot,
+                    name: entry.name,
+                    created: entry.created,
+                    pin_policy: util::pin_policy_from_string(entry.pin_policy).ok(),
+                    touch_policy: util::touch_policy_from_string(entry.touch_policy).ok(),
+                    firmware: entry.firmware,
+                };
+                Ok((stub, recipient, metadata))
+            })
+            .collect()
+    }
+}
+
+/// Returns how `slot` is written in escrow files, as in the output of `--json`.
+fn slot_value(slot: SlotId) -> serde_json::Value {
+    match slot {
+        SlotId::Retired(slot) => util::slot_to_ui(&slot).into(),
+        slot => util::slot_name(slot).into(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use yubikey::{
+        piv::{RetiredSlotId, SlotId},
+        PinPolicy, Serial, TouchPolicy,
+    };
+
+    use super::Escrow;
+    use crate::{error::Error, key::Stub, p256::Recipient, util::Metadata};
+
+    const COMPRESSED: &str = "02a6f4b23e6ba0b2cc2e3a6a6ffab0de5c8dcc0b02d5b5cfbb16ffd1c1bc9ac2e1";
+
+    fn metadata() -> Metadata {
+        Metadata {
+            serial: Serial(12345678),
+            slot: SlotId::Retired(RetiredSlotId::R2),
+            name: "backup".into(),
+            created: "today".into(),
+            pin_policy: Some(PinPolicy::Once),
+            touch_policy: Some(TouchPolicy::Always),
+            firmware: Some("5.4.3".into()),
+        }
+    }
+
+    #[test]
+    fn round_trip() {
+        let recipient = Recipient::from_public_key(COMPRESSED).unwrap();
+        let stub = Stub::new(
+            Serial(12345678),
+            SlotId::Retired(RetiredSlotId::R2),
+            &recipient,
+        );
+        let mut escrow = Escrow::new();
+        escrow.push(&stub, &recipient, &metadata());
+        assert_eq!(escrow.len(), 1);
+
+        let json = escrow.to_json();
+        let identities = Escrow::parse("stubs.json", &json).unwrap();
+        assert_eq!(identities.len(), 1);
+        let (parsed, parsed_recipient, parsed_metadata) = &identities[0];
+        assert_eq!(parsed, &stub);
+        assert_eq!(parsed_recipient.to_string(), recipient.to_string());
+        assert_eq!(parsed_metadata.name, "backup");
+        assert_eq!(parsed_metadata.pin_policy, Some(PinPolicy::Once));
+        assert_eq!(parsed_metadata.touch_policy, Some(TouchPolicy::Always));
+
+        // A stub moved to another slot no longer matches its entry.
+        let moved = Stub::new(
+            Serial(12345678),
+            SlotId::Retired(RetiredSlotId::R3),
+            &recipient,
+        );
+        let tampered = json.replace(&stub.to_string(), &moved.to_string());
+        assert!(matches!(
+            Escrow::parse("stubs.json", &tampered),
+            Err(Error::InvalidEscrow(_, _))
+        ));
+        assert!(matches!(
+            Escrow::parse("stubs.json", "{}"),
+            Err(Error::InvalidEscrow(_, _))
+        ));
+    }
+}
@@ -1,84 +1,93 @@
 use age_core::{
     format::{FileKey, Stanza},
-    primitives::aead_encrypt,
-    secrecy::ExposeSecret,
+    primitives::{aead_encrypt, hkdf},
+    secrecy::{zeroize::Zeroizing, ExposeSecret},
 };
 use base64::{prelude::BASE64_STANDARD_NO_PAD, Engine};
-use p256::{
-    ecdh::EphemeralSecret,
-    elliptic_curve::sec1::{FromEncodedPoint, ToEncodedPoint},
-};
-use rand::rngs::OsRng;
-use sha2::Sha256;
-
-use crate::{p256::Recipient, STANZA_TAG};
 
-pub(crate) const STANZA_KEY_LABEL: &[u8] = b"piv-p256";
+use crate::{
+    oaep,
+    p256::{decompress, Curve, Recipient},
+};
 
 const TAG_BYTES: usize = 4;
-const EPK_BYTES: usize = 33;
 const ENCRYPTED_FILE_KEY_BYTES: usize = 32;
 
-/// The ephemeral key bytes in a piv-p256 stanza.
+/// The ephemeral key bytes in a piv-p256 or piv-p384 stanza.
 ///
 /// The bytes contain a compressed SEC-1 encoding of a valid point.
 #[derive(Debug)]
-pub(crate) struct EphemeralKeyBytes(p256::EncodedPoint);
+pub(crate) struct EphemeralKeyBytes {
+    curve: Curve,
+    bytes: Vec<u8>,
+}
 
 impl EphemeralKeyBytes {
-    fn from_bytes(bytes: [u8; EPK_BYTES]) -> Option<Self> {
-        let encoded = p256::EncodedPoint::from_bytes(bytes).ok()?;
-        if encoded.is_compressed()
-            && p256::PublicKey::from_encoded_point(&encoded)
-                .is_some()
-                .into()
-        {
-            Some(EphemeralKeyBytes(encoded))
-        } else {
-            None
-        }
+    fn from_bytes(curve: Curve, bytes: Vec<u8>) -> Option<Self> {
+        decompress(curve, &bytes).map(|_| EphemeralKeyBytes { curve, bytes })
     }
 
-    fn from_public_key(epk: &p256::PublicKey) -> Self {
-        EphemeralKeyBytes(epk.to_encoded_point(true))
+    pub(crate) fn curve(&self) -> Curve {
+        self.curve
     }
 
     pub(crate) fn as_bytes(&self) -> &[u8] {
-        self.0.as_bytes()
+        &self.bytes
     }
 
-    pub(crate) fn decompress(&self) -> p256::EncodedPoint {
+    pub(crate) fn decompress(&self) -> Vec<u8> {
         // EphemeralKeyBytes is a valid compressed encoding by construction.
-        let p = p256::PublicKey::from_encoded_point(&self.0).unwrap();
-        p.to_encoded_point(false)
+        decompress(self.curve, &self.bytes).unwrap()
     }
 }
 
+/// The file key in a stanza, wrapped to the key in a YubiKey slot.
+#[derive(Debug)]
+pub(crate) enum WrappedKey {
+    /// A piv-p256 or piv-p384 stanza: the file key encrypted under a key derived from
+    /// an ECDH with an ephemeral key.
+    Ecdh {
+        epk_bytes: EphemeralKeyBytes,
+        encrypted_file_key: [u8; ENCRYPTED_FILE_KEY_BYTES],
+    },
+    /// A piv-rsa stanza: the file key encrypted with RSA-OAEP.
+    Rsa { ciphertext: Vec<u8> },
+}
+
 #[derive(Debug)]
 pub(crate) struct RecipientLine {
     pub(crate) tag: [u8; TAG_BYTES],
-    pub(crate) epk_bytes: EphemeralKeyBytes,
-    pub(crate) encrypted_file_key: [u8; ENCRYPTED_FILE_KEY_BYTES],
+    pub(crate) wrapped: WrappedKey,
 }
 
 impl From<RecipientLine> for Stanza {
     fn from(r: RecipientLine) -> Self {
-        Stanza {
-            tag: STANZA_TAG.to_owned(),
-            args: vec![
-                BASE64_STANDARD_NO_PAD.encode(r.tag),
-                BASE64_STANDARD_NO_PAD.encode(r.epk_bytes.as_bytes()),
-            ],
-            body: r.encrypted_file_key.to_vec(),
+        match r.wrapped {
+            WrappedKey::Ecdh {
+                epk_bytes,
+                encrypted_file_key,
+            } => Stanza {
+                tag: epk_bytes.curve().stanza_tag().to_owned(),
+                args: vec![
+                    BASE64_STANDARD_NO_PAD.encode(r.tag),
+                    BASE64_STANDARD_NO_PAD.encode(epk_bytes.as_bytes()),
+                ],
+                body: encrypted_file_key.to_vec(),
+            },
+            WrappedKey::Rsa { ciphertext } => Stanza {
+                tag: oaep::STANZA_TAG.to_owned(),
+                args: vec![BASE64_STANDARD_NO_PAD.encode(r.tag)],
+                body: ciphertext,
+            },
         }
     }
 }
 
 impl RecipientLine {
     pub(super) fn from_stanza(s: &Stanza) -> Option<Result<Self, ()>> {
-        if s.tag != STANZA_TAG {
-            return None;
-        }
+        let curve = [Curve::P256, Curve::P384, Curve::Rsa2048]
+            .into_iter()
+            .find(|curve| s.tag == curve.stanza_tag())?;
 
         fn base64_arg<A: AsRef<[u8]>, B: AsMut<[u8]>>(arg: &A, mut buf: B) -> Option<B> {
             if arg.as_ref().len() != ((4 * buf.as_mut().len()) + 2) / 3 {
@@ -91,10 +100,28 @@ impl RecipientLine {
                 .and_then(|len| (len == buf.as_mut().len()).then_some(buf))
         }
 
+        if curve.is_rsa() {
+            return Some(match &s.args[..] {
+                // The ciphertext is as long as the modulus.
+                [tag] if s.body.len() == 256 => base64_arg(tag, [0; TAG_BYTES])
+                    .map(|tag| RecipientLine {
+                        tag,
+                        wrapped: WrappedKey::Rsa {
+                            ciphertext: s.body.clone(),
+                        },
+                    })
+                    .ok_or(()),
+                _ => Err(()),
+            });
+        }
+
         let (tag, epk_bytes) = match &s.args[..] {
             [tag, epk_bytes] => (
                 base64_arg(tag, [0; TAG_BYTES]),
-                base64_arg(epk_bytes, [0; EPK_BYTES]).and_then(EphemeralKeyBytes::from_bytes),
+                curve
+                    .compressed_len()
+                    .and_then(|len| base64_arg(epk_bytes, vec![0; len]))
+                    .and_then(|bytes| EphemeralKeyBytes::from_bytes(curve, bytes)),
             ),
             _ => (None, None),
         };
@@ -102,8 +129,10 @@ impl RecipientLine {
         Some(match (tag, epk_bytes, s.body[..].try_into()) {
             (Some(tag), Some(epk_bytes), Ok(encrypted_file_key)) => Ok(RecipientLine {
                 tag,
-                epk_bytes,
-                encrypted_file_key,
+                wrapped: WrappedKey::Ecdh {
+                    epk_bytes,
+                    encrypted_file_key,
+                },
             }),
             // Anything else indicates a structurally-invalid stanza.
             _ => Err(()),
@@ -111,24 +140,30 @@ impl RecipientLine {
     }
 
     pub(crate) fn wrap_file_key(file_key: &FileKey, pk: &Recipient) -> Self {
-        let esk = EphemeralSecret::random(&mut OsRng);
-        let epk = esk.public_key();
-        let epk_bytes = EphemeralKeyBytes::from_public_key(&epk);
+        if let Recipient::Rsa(rsa) = pk {
+            return RecipientLine {
+                tag: pk.tag(),
+                wrapped: WrappedKey::Rsa {
+                    ciphertext: oaep::wrap(rsa, file_key),
+                },
+            };
+        }
 
-        let shared_secret = esk.diffie_hellman(pk.public_key());
+        let (epk, shared_secret) = pk.ephemeral_ecdh(true);
+        let epk_bytes = EphemeralKeyBytes {
+            curve: pk.curve(),
+            bytes: epk,
+        };
 
         let mut salt = vec![];
         salt.extend_from_slice(epk_bytes.as_bytes());
-        salt.extend_from_slice(pk.to_encoded().as_bytes());
-
-        let enc_key = {
-            let mut okm = [0; 32];
-            shared_secret
-                .extract::<Sha256>(Some(&salt))
-                .expand(STANZA_KEY_LABEL, &mut okm)
-                .expect("okm is the correct length");
-            okm
-        };
+        salt.extend_from_slice(&pk.to_encoded());
+
+        let enc_key = Zeroizing::new(hkdf(
+            &salt,
+            pk.curve().stanza_tag().as_bytes(),
+            &shared_secret,
+        ));
 
         let encrypted_file_key = {
             let mut key = [0; ENCRYPTED_FILE_KEY_BYTES];
@@ -138,8 +173,19 @@ impl RecipientLine {
 
         RecipientLine {
             tag: pk.tag(),
-            epk_bytes,
-            encrypted_file_key,
+            wrapped: WrappedKey::Ecdh {
+                epk_bytes,
+                encrypted_file_key,
+            },
+        }
+    }
+
+    /// Returns the input for the YubiKey's decryption with the slot's key: the
+    /// ephemeral point in its uncompressed SEC-1 encoding, or the RSA ciphertext.
+    pub(crate) fn card_input(&self) -> Vec<u8> {
+        match &self.wrapped {
+            WrappedKey::Ecdh { epk_bytes, .. } => epk_bytes.decompress(),
+            WrappedKey::Rsa { ciphertext } => ciphertext.clone(),
         }
     }
 }
@@ -0,0 +1,44 @@
+//! Localization of the messages we print.
+
+use i18n_embed::{
+    fluent::{fluent_language_loader, FluentLanguageLoader},
+    unic_langid::LanguageIdentifier,
+    DesktopLanguageRequester,
+};
+use lazy_static::lazy_static;
+use rust_embed::RustEmbed;
+
+#[derive(RustEmbed)]
+#[folder = "i18n"]
+struct Translations;
+
+const TRANSLATIONS: Translations = Translations {};
+
+lazy_static! {
+    /// Loads the translations for the user's preferred languages on first use.
+    pub(crate) static ref LANGUAGE_LOADER: FluentLanguageLoader = {
+        let loader = fluent_language_loader!();
+        let requested_languages = DesktopLanguageRequester::requested_languages();
+        i18n_embed::select(&loader, &TRANSLATIONS, &requested_languages).unwrap();
+        // Unfortunately the common Windows terminals don't support Unicode Directionality
+        // Isolation Marks, so we disable them for now.
+        loader.set_use_isolating(false);
+        loader
+    };
+}
+
+/// Prints messages in `language` instead of the user's preferred languages, falling
+/// back to English if we have no translation for it.
+pub(crate) fn select(language: LanguageIdentifier) {
+    let _ = i18n_embed::select(&*LANGUAGE_LOADER, &TRANSLATIONS, &[language]);
+}
+
+macro_rules! fl {
+    ($message_id:literal) => {{
+        i18n_embed_fl::fl!($crate::i18n::LANGUAGE_LOADER, $message_id)
+    }};
+    ($message_id:literal, $($kwarg:expr),* $(,)*) => {{
+        i18n_embed_fl::fl!($crate::i18n::LANGUAGE_LOADER, $message_id, $($kwarg,)*)
+    }};
+}
+pub(crate) use fl;
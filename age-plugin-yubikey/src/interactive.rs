@@ -0,0 +1,341 @@
+//! Interactive identity management.
+//!
+//! `--interactive` shows the connected YubiKeys and the age identities in their slots,
+//! and lets the user generate, rename and delete identities by picking them with the
+//! arrow keys, instead of combining `--serial`, `--slot` and a command flag. A failed
+//! action is reported and returns the user to the slot menu, so that (for example) a
+//! mistyped PIN doesn't end the session.
+
+use dialoguer::{Confirm, Input, Select};
+use yubikey::{
+    piv::{RetiredSlotId, SlotId},
+    reader::Context,
+    MgmKey, PinPolicy, Serial, TouchPolicy, YubiKey,
+};
+
+use crate::{
+    builder::{self, IdentityBuilder},
+    error::Error,
+    fl, key,
+    p256::{Curve, Recipient},
+    util::{self, Metadata, USABLE_SLOTS},
+};
+
+/// The settings from the command line that apply to every action.
+pub(crate) struct Settings {
+    pub(crate) curve: Option<Curve>,
+    pub(crate) mgmt_key: Option<MgmKey>,
+}
+
+enum SlotState {
+    Empty,
+    Unusable,
+    Identity(key::Stub, Recipient, Box<Metadata>),
+}
+
+/// Runs the interactive menus until the user quits. If `serial` is given, only that
+/// YubiKey is managed.
+pub(crate) fn run(serial: Option<Serial>, settings: Settings) -> Result<(), Error> {
+    eprintln!("{}", fl!("interactive-intro"));
+    eprintln!();
+
+    if let Some(serial) = serial {
+        return manage_yubikey(serial, &settings);
+    }
+
+    loop {
+        let yubikeys = list_yubikeys()?;
+        if yubikeys.is_empty() {
+            eprintln!("{}", fl!("cli-setup-insert-yk"));
+            key::wait_for_readers()?;
+            continue;
+        }
+
+        let mut items: Vec<_> = yubikeys.iter().map(|(_, name)| name.clone()).collect();
+        items.push(fl!("interactive-refresh"));
+
+        match Select::new()
+            .with_prompt(fl!("cli-setup-select-yk"))
+            .items(&items)
+            .default(0)
+            .report(false)
+            .interact_opt()?
+        {
+            Some(i) if i < yubikeys.len() => manage_yubikey(yubikeys[i].0, &settings)?,
+            Some(_) => (),
+            None => return Ok(()),
+        }
+    }
+}
+
+/// Returns the serial of every connected YubiKey, along with a description of it.
+fn list_yubikeys() -> Result<Vec<(Serial, String)>, Error> {
+    let mut readers = Context::open()?;
+
+    let mut yubikeys = vec![];
+    for reader in readers.iter()?.filter(key::filter_connected) {
+        let mut yubikey = key::open_connection(&reader)?;
+        let count = key::list_compatible(&mut yubikey)?.count();
+        yubikeys.push((
+            yubikey.serial(),
+            fl!(
+                "interactive-yk",
+                yubikey_name = reader.name(),
+                yubikey_serial = yubikey.serial().to_string(),
+                count = count,
+            ),
+        ));
+        key::disconnect_without_reset(yubikey);
+    }
+
+    Ok(yubikeys)
+}
+
+/// Reads the state of every usable slot.
+fn read_slots(yubikey: &mut YubiKey) -> Result<Vec<SlotState>, Error> {
+    let keys: Vec<_> = key::list_slots(yubikey)?.collect();
+
+    Ok(USABLE_SLOTS
+        .iter()
+        .map(|&slot| {
+            let slot = SlotId::Retired(slot);
+            match keys.iter().find(|(_, s, _)| s == &slot) {
+                None => SlotState::Empty,
+                Some((key, _, Some(recipient))) => {
+                    match Metadata::extract(yubikey, slot, key.certificate(), true) {
+                        Some(metadata) => SlotState::Identity(
+                            key::Stub::new(yubikey.serial(), slot, recipient),
+                            recipient.clone(),
+                            Box::new(metadata),
+                        ),
+                        None => SlotState::Unusable,
+                    }
+                }
+                Some((_, _, None)) => SlotState::Unusable,
+            }
+        })
+        .collect())
+}
+
+/// Shows the slots of the YubiKey with this serial until the user goes back.
+fn manage_yubikey(serial: Serial, settings: &Settings) -> Result<(), Error> {
+    loop {
+        let mut yubikey = key::open(Some(serial))?;
+        let slots = read_slots(&mut yubikey)?;
+
+        let mut items: Vec<_> = slots
+            .iter()
+            .enumerate()
+            .map(|(i, state)| {
+                // Use 1-indexing in the UI for niceness
+                let slot_index = i + 1;
+                match state {
+                    SlotState::Identity(_, _, metadata) => fl!(
+                        "cli-setup-slot-usable",
+                        slot_index = slot_index,
+                        slot_name = format!("{}, created: {}", metadata.name, metadata.created),
+                    ),
+                    SlotState::Unusable => fl!("cli-setup-slot-unusable", slot_index = slot_index),
+                    SlotState::Empty => fl!("cli-setup-slot-empty", slot_index = slot_index),
+                }
+            })
+            .collect();
+        items.push(fl!("interactive-back"));
+
+        let i = match Select::new()
+            .with_prompt(fl!(
+                "interactive-select-slot",
+                yubikey_serial = serial.to_string()
+            ))
+            .items(&items)
+            .default(0)
+            .report(false)
+            .interact_opt()?
+        {
+            Some(i) if i < slots.len() => i,
+            _ => {
+                key::disconnect_without_reset(yubikey);
+                return Ok(());
+            }
+        };
+
+        let slot = USABLE_SLOTS[i];
+        let res = match slots.into_iter().nth(i).expect("checked above") {
+            SlotState::Empty => generate(yubikey, slot, settings),
+            SlotState::Unusable => delete(yubikey, slot, settings),
+            SlotState::Identity(stub, recipient, metadata) => {
+                manage_identity(yubikey, slot, settings, stub, recipient, *metadata)
+            }
+        };
+        if let Err(e) = res {
+            let err = e.to_string();
+            eprintln!("{}", fl!("interactive-failed", err = err.trim_end()));
+        }
+        eprintln!();
+    }
+}
+
+fn manage_identity(
+    yubikey: YubiKey,
+    slot: RetiredSlotId,
+    settings: &Settings,
+    stub: key::Stub,
+    recipient: Recipient,
+    metadata: Metadata,
+) -> Result<(), Error> {
+    match Select::new()
+        .with_prompt(fl!(
+            "interactive-select-action",
+            slot = util::slot_to_ui(&slot)
+        ))
+        .items(&[
+            fl!("interactive-action-print"),
+            fl!("interactive-action-rename"),
+            fl!("interactive-action-delete"),
+            fl!("interactive-back"),
+        ])
+        .default(0)
+        .report(false)
+        .interact_opt()?
+    {
+        Some(0) => {
+            key::disconnect_without_reset(yubikey);
+            util::print_identity(stub, recipient, metadata);
+            Ok(())
+        }
+        Some(1) => rename(yubikey, slot, settings, &metadata.name),
+        Some(2) => delete(yubikey, slot, settings),
+        _ => {
+            key::disconnect_without_reset(yubikey);
+            Ok(())
+        }
+    }
+}
+
+fn generate(mut yubikey: YubiKey, slot: RetiredSlotId, settings: &Settings) -> Result<(), Error> {
+    let slot_index = util::slot_to_ui(&slot);
+    if !Confirm::new()
+        .with_prompt(fl!("cli-setup-generate-new", slot_index = slot_index))
+        .report(false)
+        .interact()?
+    {
+        key::disconnect_without_reset(yubikey);
+        return Ok(());
+    }
+
+    let name = Input::<String>::new()
+        .with_prompt(format!(
+            "{} [age identity TAG_HEX]",
+            fl!("cli-setup-name-identity")
+        ))
+        .allow_empty(true)
+        .report(true)
+        .interact_text()?;
+
+    let pin_policies = [PinPolicy::Always, PinPolicy::Once, PinPolicy::Never];
+    let pin_policy = match Select::new()
+        .with_prompt(fl!("cli-setup-select-pin-policy"))
+        .items(&[
+            fl!("pin-policy-always"),
+            fl!("pin-policy-once"),
+            fl!("pin-policy-never"),
+        ])
+        .default(
+            pin_policies
+                .iter()
+                .position(|p| p == &builder::DEFAULT_PIN_POLICY)
+                .unwrap(),
+        )
+        .report(true)
+        .interact_opt()?
+    {
+        Some(i) => pin_policies[i],
+        None => {
+            key::disconnect_without_reset(yubikey);
+            return Ok(());
+        }
+    };
+
+    let touch_policies = [TouchPolicy::Always, TouchPolicy::Cached, TouchPolicy::Never];
+    let touch_policy = match Select::new()
+        .with_prompt(fl!("cli-setup-select-touch-policy"))
+        .items(&[
+            fl!("touch-policy-always"),
+            fl!("touch-policy-cached"),
+            fl!("touch-policy-never"),
+        ])
+        .default(
+            touch_policies
+                .iter()
+                .position(|p| p == &builder::DEFAULT_TOUCH_POLICY)
+                .unwrap(),
+        )
+        .report(true)
+        .interact_opt()?
+    {
+        Some(i) => touch_policies[i],
+        None => {
+            key::disconnect_without_reset(yubikey);
+            return Ok(());
+        }
+    };
+
+    eprintln!();
+    let (stub, recipient, metadata) = IdentityBuilder::new(Some(slot))
+        .with_name(Some(name).filter(|name| !name.is_empty()))
+        .with_pin_policy(Some(pin_policy))
+        .with_touch_policy(Some(touch_policy))
+        .with_curve(settings.curve)
+        .with_mgmt_key(settings.mgmt_key.clone())
+        .build(&mut yubikey)?;
+    util::print_identity(stub, recipient, metadata);
+
+    key::finish_after_mgmt_auth(yubikey);
+
+    Ok(())
+}
+
+fn rename(
+    mut yubikey: YubiKey,
+    slot: RetiredSlotId,
+    settings: &Settings,
+    current: &str,
+) -> Result<(), Error> {
+    let name = Input::<String>::new()
+        .with_prompt(fl!("cli-setup-name-identity"))
+        .with_initial_text(current)
+        .report(true)
+        .interact_text()?;
+    if name == current {
+        key::disconnect_without_reset(yubikey);
+        return Ok(());
+    }
+
+    let (stub, recipient, metadata) = builder::rename(
+        &mut yubikey,
+        SlotId::Retired(slot),
+        &name,
+        settings.mgmt_key.clone(),
+    )?;
+    util::print_identity(stub, recipient, metadata);
+
+    Ok(())
+}
+
+fn delete(mut yubikey: YubiKey, slot: RetiredSlotId, settings: &Settings) -> Result<(), Error> {
+    if !Confirm::new()
+        .with_prompt(fl!("delete-confirm", slot = util::slot_to_ui(&slot)))
+        .default(false)
+        .report(true)
+        .interact()?
+    {
+        key::disconnect_without_reset(yubikey);
+        return Ok(());
+    }
+
+    key::manage(&mut yubikey, settings.mgmt_key.clone(), false)?;
+    key::delete_slot(&mut yubikey, SlotId::Retired(slot))?;
+    eprintln!("{}", fl!("delete-done", slot = util::slot_to_ui(&slot)));
+
+    Ok(())
+}
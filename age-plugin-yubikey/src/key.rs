@@ -3,35 +3,46 @@
 use age_core::{
     format::{FileKey, FILE_KEY_BYTES},
     primitives::{aead_decrypt, hkdf},
-    secrecy::{ExposeSecret, SecretString},
+    secrecy::{zeroize::Zeroizing, ExposeSecret, SecretString},
 };
 use age_plugin::{identity, Callbacks};
-use bech32::{ToBase32, Variant};
-use dialoguer::Password;
-use log::{debug, error, warn};
+use bech32::{FromBase32, ToBase32, Variant};
+use dialoguer::{Password, Select};
 use std::convert::Infallible;
 use std::fmt;
 use std::io;
-use std::iter;
 use std::thread::sleep;
 use std::time::{Duration, Instant, SystemTime};
+use tracing::{debug, debug_span, error, warn, Span};
 use yubikey::{
     certificate::Certificate,
-    piv::{decrypt_data, AlgorithmId, RetiredSlotId, SlotId},
+    piv::{self, decrypt_data, AlgorithmId, ManagementSlotId, SlotId},
     reader::{Context, Reader},
-    Key, MgmKey, PinPolicy, Serial, TouchPolicy, YubiKey,
+    Buffer, Key, MgmKey, PinPolicy, Serial, TouchPolicy, YubiKey,
 };
 
 use crate::{
+    backend::{self, IdentityBackend},
+    cache,
+    cancel::{Deadline, CARD_TIMEOUT},
+    capabilities::Capabilities,
+    config,
     error::Error,
     fl,
-    format::{RecipientLine, STANZA_KEY_LABEL},
+    format::{RecipientLine, WrappedKey},
+    oaep,
     p256::{Recipient, TAG_BYTES},
-    util::{otp_serial_prefix, Metadata},
-    IDENTITY_PREFIX,
+    pin,
+    util::{self, otp_serial_prefix, LockedSecret, Metadata},
+    BINARY_NAME, IDENTITY_PREFIX,
 };
 
-const ONE_SECOND: Duration = Duration::from_secs(1);
+/// The serial number reported by YubiKeys that don't expose it (for example due to
+/// their configuration). Stubs for these YubiKeys are matched by key instead.
+pub(crate) const NO_SERIAL: u32 = 0;
+
+const SERIAL_ENV: &str = "AGE_YUBIKEY_SERIAL";
+
 const FIFTEEN_SECONDS: Duration = Duration::from_secs(15);
 
 pub(crate) fn is_connected(reader: Reader) -> bool {
@@ -71,7 +82,8 @@ pub(crate) fn filter_connected(reader: &Reader) -> bool {
 }
 
 pub(crate) fn wait_for_readers() -> Result<Context, Error> {
-    // Start a 15-second timer waiting for a YubiKey to be inserted (if necessary).
+    // Start a timer waiting for a YubiKey to be inserted (if necessary).
+    let wait = config::wait();
     let start = SystemTime::now();
     loop {
         let mut readers = Context::open()?;
@@ -80,8 +92,8 @@ pub(crate) fn wait_for_readers() -> Result<Context, Error> {
         }
 
         match SystemTime::now().duration_since(start) {
-            Ok(end) if end >= FIFTEEN_SECONDS => return Err(Error::TimedOut),
-            _ => sleep(ONE_SECOND),
+            Ok(end) if end >= wait.timeout => return Err(Error::TimedOut),
+            _ => sleep(wait.poll_interval),
         }
     }
 }
@@ -160,11 +172,65 @@ pub(crate) fn open_connection(reader: &Reader) -> Result<YubiKey, yubikey::Error
     open_sesame(|| reader.open())
 }
 
+/// Returns `true` if `yubikey` holds the key with the given tag in `slot`.
+fn holds_key(yubikey: &mut YubiKey, slot: SlotId, tag: [u8; TAG_BYTES]) -> bool {
+    Certificate::read(yubikey, slot)
+        .ok()
+        .and_then(|cert| Recipient::from_certificate(&cert))
+        .map_or(false, |pk| pk.tag() == tag)
+}
+
+/// Opens every connected YubiKey that holds the key with the given tag in `slot`,
+/// returning each alongside the name of its reader.
+///
+/// This is how we find YubiKeys that don't expose their serial number, as their
+/// identities can only be bound to the key itself.
+fn open_by_tag(
+    slot: SlotId,
+    tag: [u8; TAG_BYTES],
+) -> Result<Vec<(String, YubiKey)>, yubikey::Error> {
+    let mut readers = Context::open()?;
+
+    // Try the reader this key was last used from before enumerating all of them.
+    let cached = cache::reader_for_key(slot, tag);
+    if let Some(name) = &cached {
+        if let Some(reader) = readers.iter()?.find(|reader| reader.name() == *name) {
+            if let Ok(mut yubikey) = open_connection(&reader) {
+                if holds_key(&mut yubikey, slot, tag) {
+                    return Ok(vec![(name.clone(), yubikey)]);
+                }
+                disconnect_without_reset(yubikey);
+            }
+        }
+        debug!("Key {} moved away from reader {}", hex::encode(tag), name);
+        cache::forget_key(tag);
+    }
+
+    let mut found = vec![];
+    for reader in readers.iter()?.filter(filter_connected) {
+        if cached.as_deref() == Some(&*reader.name()) {
+            // Already tried above.
+            continue;
+        }
+
+        let mut yubikey = match open_connection(&reader) {
+            Ok(yubikey) => yubikey,
+            Err(_) => continue,
+        };
+        if holds_key(&mut yubikey, slot, tag) {
+            found.push((reader.name().into_owned(), yubikey));
+        } else {
+            disconnect_without_reset(yubikey);
+        }
+    }
+    Ok(found)
+}
+
 /// Opens a YubiKey with a specific serial number.
 ///
 /// This is equivalent to [`YubiKey::open_by_serial`], but additionally handles the
 /// presence of agents (which can indefinitely hold exclusive access to a YubiKey).
-fn open_by_serial(serial: Serial) -> Result<YubiKey, yubikey::Error> {
+pub(crate) fn open_by_serial(serial: Serial) -> Result<YubiKey, yubikey::Error> {
     // `YubiKey::open_by_serial` has a bug where it ignores all opening errors, even if
     // it potentially could have found a matching YubiKey if not for an error, and thus
     // returns `Error::NotFound` if another agent is holding exclusive access to the
@@ -176,9 +242,29 @@ fn open_by_serial(serial: Serial) -> Result<YubiKey, yubikey::Error> {
     open_sesame(|| {
         let mut readers = Context::open()?;
 
+        let cached = cache::reader_for_serial(serial);
+
+        // Try the reader this YubiKey was last seen in before enumerating all of them.
+        if let Some(name) = &cached {
+            if let Some(reader) = readers.iter()?.find(|reader| reader.name() == *name) {
+                match reader.open() {
+                    Ok(yubikey) if yubikey.serial() == serial => return Ok(yubikey),
+                    Ok(yubikey) => disconnect_without_reset(yubikey),
+                    Err(_) => (),
+                }
+            }
+            debug!("YubiKey {} moved away from reader {}", serial, name);
+            cache::forget_reader(serial);
+        }
+
         let mut open_error = None;
 
         for reader in readers.iter()? {
+            if cached.as_deref() == Some(&*reader.name()) {
+                // Already tried above.
+                continue;
+            }
+
             let yubikey = match reader.open() {
                 Ok(yk) => yk,
                 Err(e) => {
@@ -195,6 +281,7 @@ fn open_by_serial(serial: Serial) -> Result<YubiKey, yubikey::Error> {
                     continue;
                 }
             };
+            cache::remember_reader(yubikey.serial(), &reader.name());
 
             if serial == yubikey.serial() {
                 return Ok(yubikey);
@@ -228,7 +315,7 @@ pub(crate) fn open(serial: Option<Serial>) -> Result<YubiKey, Error> {
     let mut readers_iter = readers.iter()?.filter(filter_connected);
 
     // --serial selects the YubiKey to use. If not provided, and more than one YubiKey is
-    // connected, an error is returned.
+    // connected, the user picks one if they can, and otherwise an error is returned.
     let yubikey = match (readers_iter.next(), readers_iter.next(), serial) {
         (None, _, _) => unreachable!(),
         (Some(reader), None, None) => open_connection(&reader)?,
@@ -239,17 +326,13 @@ pub(crate) fn open(serial: Option<Serial>) -> Result<YubiKey, Error> {
             }
             yubikey
         }
-        (Some(a), Some(b), Some(serial)) => {
-            let reader = iter::empty()
-                .chain(Some(a))
-                .chain(Some(b))
-                .chain(readers_iter)
-                .find(|reader| match open_connection(reader) {
-                    Ok(yk) => yk.serial() == serial,
-                    _ => false,
-                })
-                .ok_or(Error::NoMatchingSerial(serial))?;
-            open_connection(&reader)?
+        (Some(_), Some(_), Some(serial)) => match open_by_serial(serial) {
+            Err(yubikey::Error::NotFound) => return Err(Error::NoMatchingSerial(serial)),
+            res => res?,
+        },
+        (Some(first), Some(second), None) if console::user_attended_stderr() => {
+            let readers: Vec<_> = [first, second].into_iter().chain(readers_iter).collect();
+            select(&readers)?.ok_or(Error::MultipleYubiKeys)?
         }
         (Some(_), Some(_), None) => return Err(Error::MultipleYubiKeys),
     };
@@ -257,6 +340,61 @@ pub(crate) fn open(serial: Option<Serial>) -> Result<YubiKey, Error> {
     Ok(yubikey)
 }
 
+/// Asks the user to pick one of the YubiKeys in `readers`, returning `None` if they
+/// cancel.
+pub(crate) fn select(readers: &[Reader]) -> Result<Option<YubiKey>, Error> {
+    let reader_names = readers
+        .iter()
+        .map(|reader| {
+            open_connection(reader).map(|yk| {
+                let name = fl!(
+                    "cli-setup-yk-name",
+                    yubikey_name = reader.name(),
+                    yubikey_serial = yk.serial().to_string(),
+                );
+                disconnect_without_reset(yk);
+                name
+            })
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+
+    match Select::new()
+        .with_prompt(fl!("cli-setup-select-yk"))
+        .items(&reader_names)
+        .default(0)
+        .report(true)
+        .interact_opt()?
+    {
+        Some(yk) => Ok(Some(open_connection(&readers[yk])?)),
+        None => Ok(None),
+    }
+}
+
+/// Returns the serial in `AGE_YUBIKEY_SERIAL`, which selects the YubiKey to use when
+/// `--serial` is not given, including when we are run by an age client.
+pub(crate) fn serial_from_env() -> Option<Serial> {
+    let value = std::env::var(SERIAL_ENV).ok()?;
+    match value.parse::<u32>() {
+        Ok(serial) => Some(serial.into()),
+        Err(_) => {
+            warn!("{}", fl!("warn-invalid-serial-env", env = SERIAL_ENV));
+            None
+        }
+    }
+}
+
+/// Disconnect from the YubiKey after writing to it, resetting it.
+///
+/// Writing means we've authenticated with the management key. Out of an abundance of
+/// caution, we let the YubiKey be reset on disconnect, which will clear its PIN and touch
+/// caches. This has a small negative UX effect, but writes are relatively infrequent,
+/// and users are more likely to see their cached PINs reset due to switching applets
+/// (e.g. from PIV to FIDO2).
+pub(crate) fn finish_after_mgmt_auth(yubikey: YubiKey) {
+    // Dropping the connection resets the YubiKey.
+    drop(yubikey);
+}
+
 /// Disconnect from the YubiKey without resetting it.
 ///
 /// This can be used to preserve the YubiKey's PIN and touch caches. There are two cases
@@ -274,6 +412,68 @@ pub(crate) fn disconnect_without_reset(yubikey: YubiKey) {
     let _ = yubikey.disconnect(pcsc::Disposition::LeaveCard);
 }
 
+/// Returns whether `e` means that the YubiKey (or its reader) went away while we were
+/// using it.
+pub(crate) fn is_removed(e: &yubikey::Error) -> bool {
+    matches!(
+        e,
+        yubikey::Error::PcscError {
+            inner: Some(
+                pcsc::Error::RemovedCard
+                    | pcsc::Error::NoSmartcard
+                    | pcsc::Error::ReaderUnavailable
+                    | pcsc::Error::UnknownReader
+            ),
+        }
+    )
+}
+
+/// Runs `op` on `yubikey`. If the YubiKey is removed meanwhile, waits for it to be
+/// re-inserted and runs `op` again on the new connection, which it is told about so
+/// that it can authenticate again.
+///
+/// `op` must be safe to repeat after it was interrupted at any point.
+pub(crate) fn retry_if_removed<T>(
+    yubikey: &mut YubiKey,
+    mut op: impl FnMut(&mut YubiKey, bool) -> Result<T, Error>,
+) -> Result<T, Error> {
+    let serial = yubikey.serial();
+    let mut reinserted = false;
+    loop {
+        match op(yubikey, reinserted) {
+            // YubiKeys without a serial can't be told apart, so we can't know whether
+            // the one inserted next is the same.
+            Err(Error::YubiKey(e)) if is_removed(&e) && serial.0 != NO_SERIAL => {
+                eprintln!(
+                    "{}",
+                    fl!("open-yk-reinsert", yubikey_serial = serial.to_string())
+                );
+                *yubikey = wait_for_serial(serial)?;
+                reinserted = true;
+            }
+            res => return res,
+        }
+    }
+}
+
+/// Waits for the YubiKey with `serial` to be inserted, for as long as configured.
+fn wait_for_serial(serial: Serial) -> Result<YubiKey, Error> {
+    let wait = config::wait();
+    let start = SystemTime::now();
+    loop {
+        match open_by_serial(serial) {
+            Ok(yubikey) => break Ok(yubikey),
+            Err(e) if e == yubikey::Error::NotFound || is_removed(&e) => (),
+            Err(e) => break Err(e.into()),
+        }
+
+        match SystemTime::now().duration_since(start) {
+            Ok(end) if end >= wait.timeout => return Err(Error::TimedOut),
+            _ => sleep(wait.poll_interval),
+        }
+    }
+}
+
 fn request_pin<E, E2>(
     mut prompt: impl FnMut(Option<String>) -> Result<Result<SecretString, E>, E2>,
     serial: Serial,
@@ -299,92 +499,421 @@ fn request_pin<E, E2>(
     }
 }
 
-pub(crate) fn manage(yubikey: &mut YubiKey) -> Result<(), Error> {
-    const DEFAULT_PIN: &str = "123456";
-    const DEFAULT_PUK: &str = "12345678";
+const DEFAULT_PIN: &str = "123456";
+const DEFAULT_PUK: &str = "12345678";
+
+fn puk_error(e: yubikey::Error) -> Error {
+    match e {
+        yubikey::Error::PinLocked => Error::PukLocked,
+        yubikey::Error::WrongPin { tries } => Error::WrongPuk(tries),
+        _ => Error::YubiKey(e),
+    }
+}
+
+/// Prompts for a new PIN or PUK, with `prompt` and then `repeat`, until the user
+/// chooses a valid one other than the default PIN and PUK.
+fn choose_new_pin(serial: Serial, prompt: String, repeat: String) -> Result<SecretString, Error> {
+    loop {
+        let pin = request_pin(
+            |prev_error| {
+                if let Some(err) = prev_error {
+                    eprintln!("{err}");
+                }
+                Password::new()
+                    .with_prompt(&prompt)
+                    .with_confirmation(&repeat, fl!("mgr-pin-mismatch"))
+                    .interact()
+                    .map(|pin| Result::<_, Infallible>::Ok(SecretString::new(pin)))
+            },
+            serial,
+        )?
+        .unwrap();
+        match pin.expose_secret().as_str() {
+            DEFAULT_PIN => eprintln!("{}", fl!("mgr-nope-default-pin")),
+            DEFAULT_PUK => eprintln!("{}", fl!("mgr-nope-default-puk")),
+            _ => return Ok(pin),
+        }
+    }
+}
+
+/// The credentials of a YubiKey that still have their factory default values.
+#[derive(Debug, Default)]
+pub(crate) struct DefaultCredentials {
+    pin: bool,
+    puk: bool,
+    mgmt_key: bool,
+}
+
+impl DefaultCredentials {
+    /// Reads which credentials are defaults. YubiKeys before firmware 5.3 don't report
+    /// this, and we can't check without using up PIN tries, so none are for them.
+    pub(crate) fn read(yubikey: &mut YubiKey) -> Self {
+        let mut is_default = |slot| {
+            piv::metadata(yubikey, SlotId::Management(slot))
+                .ok()
+                .and_then(|metadata| metadata.default)
+                .unwrap_or(false)
+        };
+        DefaultCredentials {
+            pin: is_default(ManagementSlotId::Pin),
+            puk: is_default(ManagementSlotId::Puk),
+            mgmt_key: is_default(ManagementSlotId::Management),
+        }
+    }
+}
+
+/// Warns about the credentials of `yubikey` that still have their default values.
+///
+/// If `require_nondefault_pin` is set, a default PIN or PUK is an error instead.
+pub(crate) fn check_default_credentials(
+    yubikey: &mut YubiKey,
+    require_nondefault_pin: bool,
+) -> Result<(), Error> {
+    let defaults = DefaultCredentials::read(yubikey);
+    let serial = yubikey.serial();
+    if require_nondefault_pin && (defaults.pin || defaults.puk) {
+        return Err(Error::DefaultPin(serial));
+    }
+
+    let yubikey_serial = serial.to_string();
+    let cmd = |args: &str| format!("{} {}", BINARY_NAME, args);
+    for (is_default, warning) in [
+        (
+            defaults.pin,
+            fl!(
+                "mgr-default-pin",
+                yubikey_serial = yubikey_serial.as_str(),
+                cmd = cmd("--change-pin"),
+            ),
+        ),
+        (
+            defaults.puk,
+            fl!(
+                "mgr-default-puk",
+                yubikey_serial = yubikey_serial.as_str(),
+                cmd = cmd("--change-puk"),
+            ),
+        ),
+        (
+            defaults.mgmt_key,
+            fl!(
+                "mgr-default-mgmt-key",
+                yubikey_serial = yubikey_serial.as_str(),
+                cmd = cmd("--change-mgmt-key --protect-mgmt-key"),
+            ),
+        ),
+    ] {
+        if is_default {
+            eprintln!("{warning}");
+        }
+    }
+    Ok(())
+}
+
+/// Returns the number of PUK tries left, if the YubiKey reports it (from firmware 5.3).
+pub(crate) fn puk_tries(yubikey: &mut YubiKey) -> Option<u8> {
+    piv::metadata(yubikey, SlotId::Management(ManagementSlotId::Puk))
+        .ok()?
+        .retries
+        .map(|retries| retries.remaining_count)
+}
+
+/// Sets a new PIN after verifying the PUK, which also unblocks a blocked PIN.
+pub(crate) fn unblock_pin(yubikey: &mut YubiKey) -> Result<(), Error> {
+    let serial = yubikey.serial();
+    eprintln!(
+        "{}",
+        fl!(
+            "mgr-pin-tries",
+            yubikey_serial = serial.to_string(),
+            tries = yubikey.get_pin_retries()?,
+        )
+    );
+
+    let puk_tries = puk_tries(yubikey);
+    let mut prompt = fl!("mgr-enter-puk");
+    if let Some(tries) = puk_tries {
+        pin::check_puk_tries(serial, tries)?;
+        prompt = pin::prompt_with_tries(prompt, tries);
+    }
+    let puk = LockedSecret::new(Password::new().with_prompt(prompt).interact()?);
+    let new_pin = choose_new_pin(serial, fl!("mgr-choose-pin"), fl!("mgr-repeat-pin"))?;
+
+    yubikey
+        .unblock_pin(puk.as_bytes(), new_pin.expose_secret().as_bytes())
+        .map_err(puk_error)
+}
+
+/// Changes the PIN, after asking for the current one.
+pub(crate) fn change_pin(yubikey: &mut YubiKey) -> Result<(), Error> {
+    let serial = yubikey.serial();
+    let tries = yubikey.get_pin_retries()?;
+    pin::check_tries(serial, tries)?;
+    let current = LockedSecret::new(
+        Password::new()
+            .with_prompt(pin::prompt_with_tries(
+                fl!(
+                    "mgr-enter-pin",
+                    yubikey_serial = serial.to_string(),
+                    default_pin = DEFAULT_PIN,
+                ),
+                tries,
+            ))
+            .interact()?,
+    );
+    let new_pin = choose_new_pin(serial, fl!("mgr-choose-pin"), fl!("mgr-repeat-pin"))?;
+    yubikey.change_pin(current.as_bytes(), new_pin.expose_secret().as_bytes())?;
+    Ok(())
+}
+
+/// Changes the PUK, after asking for the current one.
+pub(crate) fn change_puk(yubikey: &mut YubiKey) -> Result<(), Error> {
+    let serial = yubikey.serial();
+    let mut prompt = fl!("mgr-enter-current-puk", default_puk = DEFAULT_PUK);
+    if let Some(tries) = puk_tries(yubikey) {
+        pin::check_puk_tries(serial, tries)?;
+        prompt = pin::prompt_with_tries(prompt, tries);
+    }
+    let current = LockedSecret::new(Password::new().with_prompt(prompt).interact()?);
+    let new_puk = choose_new_pin(serial, fl!("mgr-choose-puk"), fl!("mgr-repeat-puk"))?;
+    yubikey
+        .change_puk(current.as_bytes(), new_puk.expose_secret().as_bytes())
+        .map_err(puk_error)
+}
+
+/// Changes the management key, after authenticating with the current one.
+///
+/// If `protect` is set, the new key is random and stored on the YubiKey, protected by
+/// the PIN. Otherwise the user chooses it, and it is not stored.
+pub(crate) fn change_mgmt_key(
+    yubikey: &mut YubiKey,
+    mgmt_key: Option<MgmKey>,
+    protect: bool,
+) -> Result<(), Error> {
+    authenticate(yubikey, mgmt_key)?;
+    if protect {
+        return replace_mgmt_key(yubikey, fl!("mgr-rotating-mgmt-key"));
+    }
 
     eprintln!();
-    let pin = Password::new()
-        .with_prompt(fl!(
-            "mgr-enter-pin",
-            yubikey_serial = yubikey.serial().to_string(),
-            default_pin = DEFAULT_PIN,
-        ))
-        .report(true)
-        .interact()?;
+    let new_key = Zeroizing::new(
+        Password::new()
+            .with_prompt(fl!("mgr-choose-mgmt-key"))
+            .with_confirmation(fl!("mgr-repeat-mgmt-key"), fl!("mgr-mgmt-key-mismatch"))
+            .interact()?,
+    );
+    let new_key = parse_mgmt_key(&new_key)?;
+    if new_key.as_ref() == MgmKey::default().as_ref() {
+        return Err(Error::DefaultManagementKey);
+    }
+    // This also removes the PIN-protected copy of the previous key, if there was one.
+    new_key.set_manual(yubikey, false)?;
+    Ok(())
+}
+
+/// Sets the PIN and PUK of a YubiKey that still uses the default PIN and PUK to
+/// `new_pin`, without prompting.
+///
+/// Returns `false` if the YubiKey does not use the default PIN. Checking this costs a
+/// PIN retry, which the next successful PIN verification restores.
+pub(crate) fn replace_default_pin(yubikey: &mut YubiKey, new_pin: &[u8]) -> Result<bool, Error> {
+    pin::check_tries(yubikey.serial(), yubikey.get_pin_retries()?)?;
+    match yubikey.verify_pin(DEFAULT_PIN.as_bytes()) {
+        Ok(()) => (),
+        Err(yubikey::Error::WrongPin { .. }) => return Ok(false),
+        Err(e) => return Err(e.into()),
+    }
+    yubikey
+        .change_puk(DEFAULT_PUK.as_bytes(), new_pin)
+        .map_err(puk_error)?;
+    yubikey.change_pin(DEFAULT_PIN.as_bytes(), new_pin)?;
+    Ok(true)
+}
+
+/// Verifies the PIN of `yubikey`, prompting for it with `prompt` and the number of tries
+/// left unless an unattended PIN was configured. Returns the PIN.
+///
+/// Checking the tries left selects the PIV applet again, so this must happen before
+/// authenticating with the management key.
+pub(crate) fn verify_pin(
+    yubikey: &mut YubiKey,
+    prompt: String,
+) -> Result<LockedSecret<String>, Error> {
+    let tries = yubikey.get_pin_retries()?;
+    pin::check_tries(yubikey.serial(), tries)?;
+    let pin = pin::get_or_prompt(|| {
+        Password::new()
+            .with_prompt(pin::prompt_with_tries(prompt, tries))
+            .report(true)
+            .interact()
+    })?;
     yubikey.verify_pin(pin.as_bytes())?;
+    Ok(pin)
+}
+
+/// Authenticates with the management key, after verifying the PIN.
+///
+/// `mgmt_key` is used if the management key is not PIN-protected, and if `rotate` is
+/// set, the management key is replaced with a new PIN-protected key.
+pub(crate) fn manage(
+    yubikey: &mut YubiKey,
+    mgmt_key: Option<MgmKey>,
+    rotate: bool,
+) -> Result<(), Error> {
+    match authenticate(yubikey, mgmt_key)? {
+        // Migrate the default management key to a PIN-protected management key. We
+        // leave custom management keys alone unless asked to replace them, as they may
+        // be managed by someone else.
+        MgmtKeyKind::Default => replace_mgmt_key(yubikey, fl!("mgr-changing-mgmt-key")),
+        _ if rotate => replace_mgmt_key(yubikey, fl!("mgr-rotating-mgmt-key")),
+        _ => Ok(()),
+    }
+}
+
+/// The kind of management key a YubiKey uses.
+#[derive(Debug, PartialEq, Eq)]
+enum MgmtKeyKind {
+    Default,
+    PinProtected,
+    Custom,
+}
+
+/// Verifies the PIN (helping the user to change it if it is the default PIN), and then
+/// authenticates with the management key, which is `mgmt_key` if it is given and the
+/// management key is not PIN-protected.
+fn authenticate(yubikey: &mut YubiKey, mgmt_key: Option<MgmKey>) -> Result<MgmtKeyKind, Error> {
+    eprintln!();
+    eprintln!();
+    let prompt = fl!(
+        "mgr-enter-pin",
+        yubikey_serial = yubikey.serial().to_string(),
+        default_pin = DEFAULT_PIN,
+    );
+    let pin = verify_pin(yubikey, prompt)?;
 
     // If the user is using the default PIN, help them to change it.
-    if pin == DEFAULT_PIN {
+    if *pin == DEFAULT_PIN {
+        // Choosing a new PIN needs someone at the terminal.
+        if pin::unattended().is_some() {
+            return Err(Error::UnattendedDefaultPin);
+        }
         eprintln!();
         eprintln!("{}", fl!("mgr-change-default-pin"));
         eprintln!();
-        let current_puk = Password::new()
-            .with_prompt(fl!("mgr-enter-current-puk", default_puk = DEFAULT_PUK))
-            .interact()?;
-        let new_pin = loop {
-            let pin = request_pin(
-                |prev_error| {
-                    if let Some(err) = prev_error {
-                        eprintln!("{err}");
-                    }
-                    Password::new()
-                        .with_prompt(fl!("mgr-choose-new-pin"))
-                        .with_confirmation(fl!("mgr-repeat-new-pin"), fl!("mgr-pin-mismatch"))
-                        .interact()
-                        .map(|pin| Result::<_, Infallible>::Ok(SecretString::new(pin)))
-                },
-                yubikey.serial(),
-            )?
-            .unwrap();
-            if pin.expose_secret() == DEFAULT_PIN {
-                eprintln!("{}", fl!("mgr-nope-default-pin"));
-            } else {
-                break pin;
-            }
-        };
+        let current_puk = LockedSecret::new(
+            Password::new()
+                .with_prompt(fl!("mgr-enter-current-puk", default_puk = DEFAULT_PUK))
+                .interact()?,
+        );
+        let new_pin = choose_new_pin(
+            yubikey.serial(),
+            fl!("mgr-choose-new-pin"),
+            fl!("mgr-repeat-new-pin"),
+        )?;
         let new_pin = new_pin.expose_secret();
         yubikey
             .change_puk(current_puk.as_bytes(), new_pin.as_bytes())
-            .map_err(|e| match e {
-                yubikey::Error::PinLocked => Error::PukLocked,
-                yubikey::Error::WrongPin { tries } => Error::WrongPuk(tries),
-                _ => Error::YubiKey(e),
-            })?;
+            .map_err(puk_error)?;
         yubikey.change_pin(pin.as_bytes(), new_pin.as_bytes())?;
     }
 
     match MgmKey::get_protected(yubikey) {
-        Ok(mgm_key) => yubikey.authenticate(mgm_key).map_err(|e| match e {
-            yubikey::Error::AuthenticationError => Error::ManagementKeyAuth,
-            _ => e.into(),
-        })?,
-        Err(yubikey::Error::AuthenticationError) => Err(Error::ManagementKeyAuth)?,
-        _ => {
-            // Try to authenticate with the default management key.
-            yubikey
-                .authenticate(MgmKey::default())
-                .map_err(|_| Error::CustomManagementKey)?;
-
-            // Migrate to a PIN-protected management key.
-            let mgm_key = MgmKey::generate();
-            eprintln!();
-            eprintln!("{}", fl!("mgr-changing-mgmt-key"));
-            eprint!("... ");
-            mgm_key.set_protected(yubikey).map_err(|e| {
-                eprintln!(
-                    "{}",
-                    fl!(
-                        "mgr-changing-mgmt-key-error",
-                        management_key = hex::encode(mgm_key.as_ref()),
-                    )
-                );
-                e
+        Ok(mgm_key) => {
+            yubikey.authenticate(mgm_key).map_err(|e| match e {
+                yubikey::Error::AuthenticationError => Error::ManagementKeyAuth,
+                _ => e.into(),
             })?;
-            eprintln!("{}", fl!("mgr-changing-mgmt-key-success"));
+            Ok(MgmtKeyKind::PinProtected)
         }
+        Err(yubikey::Error::AuthenticationError) => Err(Error::ManagementKeyAuth),
+        _ => {
+            // The management key is not PIN-protected, so use the key we were given, or
+            // else try the default management key.
+            match mgmt_key {
+                Some(mgm_key) => authenticate_custom(yubikey, mgm_key)?,
+                None => match yubikey.authenticate(MgmKey::default()) {
+                    Ok(()) => return Ok(MgmtKeyKind::Default),
+                    Err(_) => {
+                        let mgm_key = request_mgmt_key()?;
+                        authenticate_custom(yubikey, mgm_key)?;
+                    }
+                },
+            }
+            Ok(MgmtKeyKind::Custom)
+        }
+    }
+}
+
+/// Parses a hex-encoded management key.
+pub(crate) fn parse_mgmt_key(s: &str) -> Result<MgmKey, Error> {
+    let bytes = Zeroizing::new(hex::decode(s.trim()).map_err(|_| Error::InvalidManagementKey)?);
+    match bytes.len() {
+        // TDES and AES-192 keys are both 24 bytes, so we can only tell the latter apart
+        // when the YubiKey rejects them.
+        24 => MgmKey::from_bytes(&*bytes).map_err(|_| Error::InvalidManagementKey),
+        16 | 32 => Err(Error::AesManagementKey),
+        _ => Err(Error::InvalidManagementKey),
     }
+}
+
+fn request_mgmt_key() -> Result<MgmKey, Error> {
+    if !console::user_attended() {
+        return Err(Error::CustomManagementKey);
+    }
+    eprintln!();
+    let mgm_key = Zeroizing::new(
+        Password::new()
+            .with_prompt(fl!("mgr-enter-mgmt-key"))
+            .interact()?,
+    );
+    parse_mgmt_key(&mgm_key)
+}
 
+fn authenticate_custom(yubikey: &mut YubiKey, mgm_key: MgmKey) -> Result<(), Error> {
+    yubikey.authenticate(mgm_key).map_err(|e| match e {
+        // Firmware 5.4 added AES management keys, which we can't use yet.
+        yubikey::Error::AuthenticationError => {
+            Error::WrongManagementKey(Capabilities::of(yubikey.version()).aes_mgmt_key)
+        }
+        _ => e.into(),
+    })
+}
+
+/// Replaces the management key with a random PIN-protected one.
+fn replace_mgmt_key(yubikey: &mut YubiKey, intro: String) -> Result<(), Error> {
+    let mgm_key = MgmKey::generate();
+    eprintln!();
+    eprintln!("{intro}");
+    eprint!("... ");
+    mgm_key.set_protected(yubikey).map_err(|e| {
+        eprintln!(
+            "{}",
+            fl!(
+                "mgr-changing-mgmt-key-error",
+                management_key = hex::encode(mgm_key.as_ref()),
+            )
+        );
+        e
+    })?;
+    eprintln!("{}", fl!("mgr-changing-mgmt-key-success"));
+    Ok(())
+}
+
+/// Removes the key and certificate in `slot`. The YubiKey must already be
+/// authenticated with the management key.
+///
+/// PIV has no command to delete a key before firmware 5.7, so we instead overwrite it
+/// with a new key that never leaves the YubiKey, and then delete the certificate. The
+/// slot then no longer shows up as occupied.
+pub(crate) fn delete_slot(yubikey: &mut YubiKey, slot: SlotId) -> Result<(), Error> {
+    piv::generate(
+        yubikey,
+        slot,
+        AlgorithmId::EccP256,
+        PinPolicy::Never,
+        TouchPolicy::Never,
+    )?;
+    Certificate::delete(yubikey, slot)?;
     Ok(())
 }
 
@@ -392,33 +921,126 @@ pub(crate) fn manage(yubikey: &mut YubiKey) -> Result<(), Error> {
 /// corresponding recipient if the key is compatible with this plugin.
 pub(crate) fn list_slots(
     yubikey: &mut YubiKey,
-) -> Result<impl Iterator<Item = (Key, RetiredSlotId, Option<Recipient>)>, Error> {
-    Ok(Key::list(yubikey)?.into_iter().filter_map(|key| {
-        // We only use the retired slots.
-        match key.slot() {
-            SlotId::Retired(slot) => {
-                // Only P-256 keys are compatible with us.
-                let recipient = Recipient::from_certificate(key.certificate());
-                Some((key, slot, recipient))
-            }
-            _ => None,
-        }
+) -> Result<impl Iterator<Item = (Key, SlotId, Option<Recipient>)>, Error> {
+    let slots = util::identity_slots();
+    Ok(Key::list(yubikey)?.into_iter().filter_map(move |key| {
+        // We only use the retired slots, and the standard slots if allowed.
+        let slot = key.slot();
+        slots.contains(&slot).then(|| {
+            // Only P-256 and P-384 keys are compatible with us.
+            let recipient = Recipient::from_certificate(key.certificate());
+            (key, slot, recipient)
+        })
     }))
 }
 
 /// Returns an iterator of keys that are compatible with this plugin.
 pub(crate) fn list_compatible(
     yubikey: &mut YubiKey,
-) -> Result<impl Iterator<Item = (Key, RetiredSlotId, Recipient)>, Error> {
+) -> Result<impl Iterator<Item = (Key, SlotId, Recipient)>, Error> {
     list_slots(yubikey)
         .map(|iter| iter.filter_map(|(key, slot, res)| res.map(|recipient| (key, slot, recipient))))
 }
 
+/// Returns the identities for the compatible keys in `yubikey`. Keys that were not
+/// generated by this plugin are skipped unless `all` is set.
+pub(crate) fn identities(
+    yubikey: &mut YubiKey,
+    all: bool,
+) -> Result<Vec<(Stub, Recipient, Metadata)>, Error> {
+    let mut identities = vec![];
+    for (key, slot, recipient) in list_compatible(yubikey)? {
+        let stub = Stub::new(yubikey.serial(), slot, &recipient);
+        if let Some(metadata) = Metadata::extract(yubikey, slot, key.certificate(), all) {
+            identities.push((stub, recipient, metadata));
+        }
+    }
+    Ok(identities)
+}
+
+/// How [`check_slot_key`] established which key a slot holds.
+pub(crate) enum KeyCheck {
+    /// The YubiKey attested to the public key it generated in the slot.
+    Attestation,
+    /// The slot performed ECDH with a random point, and the result matched.
+    Ecdh,
+    /// The slot decrypted a random file key wrapped to the RSA public key.
+    Decryption,
+}
+
+/// Checks that the private key in `slot` corresponds to `recipient`, which is derived
+/// from the slot's certificate.
+///
+/// The certificate and the key in a slot are stored separately, and drift out of sync
+/// if another tool replaces only one of them.
+pub(crate) fn check_slot_key(
+    yubikey: &mut YubiKey,
+    slot: SlotId,
+    recipient: &Recipient,
+    metadata: Option<&Metadata>,
+) -> Result<KeyCheck, Error> {
+    // Attestations are only available for keys generated on the YubiKey, but don't
+    // require the PIN or a touch.
+    if let Some(attested) = yubikey::piv::attest(yubikey, slot).ok().and_then(|buf| {
+        x509_parser::parse_x509_certificate(&buf)
+            .ok()
+            .and_then(|(_, cert)| Recipient::from_spki_der(cert.public_key().raw))
+    }) {
+        return if attested.to_encoded() == recipient.to_encoded() {
+            Ok(KeyCheck::Attestation)
+        } else {
+            Err(Error::SlotKeyMismatch(slot))
+        };
+    }
+
+    // Otherwise, have the slot perform ECDH with a random point (or decrypt a random
+    // file key, for RSA), and compare the result with what the certificate's public
+    // key gives us.
+    if !matches!(metadata.and_then(|m| m.pin_policy), Some(PinPolicy::Never)) {
+        let prompt = fl!(
+            "plugin-enter-pin",
+            yubikey_serial = yubikey.serial().to_string(),
+        );
+        verify_pin(yubikey, prompt)?;
+    }
+    if !matches!(
+        metadata.and_then(|m| m.touch_policy),
+        Some(TouchPolicy::Never)
+    ) {
+        eprintln!("{}", fl!("builder-touch-yk"));
+    }
+
+    if let Recipient::Rsa(pk) = recipient {
+        let file_key = FileKey::from(rand::random::<[u8; FILE_KEY_BYTES]>());
+        let output = decrypt_data(
+            yubikey,
+            &oaep::wrap(pk, &file_key),
+            recipient.curve().algorithm(),
+            slot,
+        )?;
+        return match oaep::unwrap(pk, &output) {
+            Some(unwrapped) if unwrapped.expose_secret() == file_key.expose_secret() => {
+                Ok(KeyCheck::Decryption)
+            }
+            _ => Err(Error::SlotKeyMismatch(slot)),
+        };
+    }
+
+    let (epk, expected) = recipient.ephemeral_ecdh(false);
+    let shared_secret = decrypt_data(yubikey, &epk, recipient.curve().algorithm(), slot)?;
+
+    if shared_secret == expected {
+        Ok(KeyCheck::Ecdh)
+    } else {
+        Err(Error::SlotKeyMismatch(slot))
+    }
+}
+
 /// A reference to an age key stored in a YubiKey.
 #[derive(Debug)]
 pub struct Stub {
     pub(crate) serial: Serial,
-    pub(crate) slot: RetiredSlotId,
+    pub(crate) slot: SlotId,
     pub(crate) tag: [u8; TAG_BYTES],
     pub(crate) identity_index: usize,
 }
@@ -449,7 +1071,7 @@ impl Stub {
     ///
     /// Does not check that the `PublicKey` matches the given `(Serial, SlotId)` tuple;
     /// this is checked at decryption time.
-    pub(crate) fn new(serial: Serial, slot: RetiredSlotId, recipient: &Recipient) -> Self {
+    pub(crate) fn new(serial: Serial, slot: SlotId, recipient: &Recipient) -> Self {
         Stub {
             serial,
             slot,
@@ -458,12 +1080,23 @@ impl Stub {
         }
     }
 
+    /// Parses a key stub from its Bech32 encoding, as found in identity files.
+    pub fn decode(s: &str) -> Option<Self> {
+        let (hrp, data, variant) = bech32::decode(s).ok()?;
+        if hrp != IDENTITY_PREFIX || variant != Variant::Bech32 {
+            return None;
+        }
+        Self::from_bytes(&Vec::<u8>::from_base32(&data).ok()?, 0)
+    }
+
     pub(crate) fn from_bytes(bytes: &[u8], identity_index: usize) -> Option<Self> {
         if bytes.len() < 9 {
             return None;
         }
         let serial = Serial::from(u32::from_le_bytes(bytes[0..4].try_into().unwrap()));
-        let slot: RetiredSlotId = bytes[4].try_into().ok()?;
+        let slot = SlotId::try_from(bytes[4])
+            .ok()
+            .filter(|&slot| util::is_identity_slot(slot))?;
         Some(Stub {
             serial,
             slot,
@@ -484,23 +1117,102 @@ impl Stub {
         self.tag == line.tag
     }
 
+    /// Returns the serial of this stub's YubiKey for use in messages to the user.
+    pub(crate) fn serial_for_ui(&self) -> String {
+        if self.serial.0 == NO_SERIAL {
+            fl!("unknown-serial", tag = hex::encode(self.tag))
+        } else {
+            self.serial.to_string()
+        }
+    }
+
+    /// Opens the YubiKey for this stub.
+    ///
+    /// Stubs for YubiKeys without an accessible serial number are matched by the tag of
+    /// their key instead. If several connected YubiKeys hold that key, the user picks
+    /// one of them.
+    fn open_backend<E>(
+        &self,
+        callbacks: &mut dyn Callbacks<E>,
+    ) -> io::Result<Result<Box<dyn IdentityBackend>, yubikey::Error>> {
+        if self.serial.0 != NO_SERIAL {
+            return Ok(backend::open(self.serial));
+        }
+
+        let mut candidates = match open_by_tag(self.slot, self.tag) {
+            Ok(candidates) => candidates,
+            Err(e) => return Ok(Err(e)),
+        };
+        while candidates.len() > 1 {
+            let (reader, yubikey) = candidates.remove(0);
+            match callbacks.confirm(
+                &fl!("plugin-use-yk-in-reader", reader = reader.as_str()),
+                &fl!("plugin-use-this-yk"),
+                Some(&fl!("plugin-try-next-yk")),
+            )? {
+                Ok(false) => disconnect_without_reset(yubikey),
+                // Use this YubiKey if the user chose it, or if we can't ask.
+                _ => {
+                    for (_, other) in candidates {
+                        disconnect_without_reset(other);
+                    }
+                    cache::remember_key(self.slot, self.tag, &reader);
+                    return Ok(Ok(Box::new(yubikey)));
+                }
+            }
+        }
+        Ok(candidates
+            .pop()
+            .map(|(reader, yubikey)| {
+                cache::remember_key(self.slot, self.tag, &reader);
+                Box::new(yubikey) as _
+            })
+            .ok_or(yubikey::Error::NotFound))
+    }
+
     /// Returns:
     /// - `Ok(Ok(Some(connection)))` if we successfully connected to this YubiKey.
     /// - `Ok(Ok(None))` if the user told us to skip this YubiKey.
     /// - `Ok(Err(_))` if we encountered an error while trying to connect to the YubiKey.
     /// - `Err(_)` on communication errors with the age client.
+    ///
+    /// `opened` is this stub's YubiKey, if it has already been opened.
     pub(crate) fn connect<E>(
         &self,
+        opened: Option<Box<dyn IdentityBackend>>,
         callbacks: &mut dyn Callbacks<E>,
     ) -> io::Result<Result<Option<Connection>, identity::Error>> {
-        let mut yubikey = match open_by_serial(self.serial) {
-            Ok(yk) => yk,
-            Err(yubikey::Error::NotFound) => {
-                let mut message = fl!("plugin-insert-yk", yubikey_serial = self.serial.to_string());
+        let message = fl!("plugin-insert-yk", yubikey_serial = self.serial_for_ui());
+        self.connect_or_ask(opened, message, callbacks)
+    }
 
+    /// Connects to this stub's YubiKey again after it was removed, asking the user to
+    /// re-insert it. Returns the same as [`Stub::connect`].
+    pub(crate) fn reconnect<E>(
+        &self,
+        callbacks: &mut dyn Callbacks<E>,
+    ) -> io::Result<Result<Option<Connection>, identity::Error>> {
+        let message = fl!("plugin-reinsert-yk", yubikey_serial = self.serial_for_ui());
+        self.connect_or_ask(None, message, callbacks)
+    }
+
+    /// Connects to this stub's YubiKey, showing `message` if it needs to be inserted.
+    fn connect_or_ask<E>(
+        &self,
+        opened: Option<Box<dyn IdentityBackend>>,
+        mut message: String,
+        callbacks: &mut dyn Callbacks<E>,
+    ) -> io::Result<Result<Option<Connection>, identity::Error>> {
+        let opened = match opened {
+            Some(backend) => Ok(backend),
+            None => self.open_backend(callbacks)?,
+        };
+        let mut backend = match opened {
+            Ok(yk) => yk,
+            Err(e) if e == yubikey::Error::NotFound || is_removed(&e) => {
                 // If the `confirm` command is available, we loop until either the YubiKey
                 // we want is inserted, or the used explicitly skips.
-                let yubikey = loop {
+                let backend = loop {
                     match callbacks.confirm(
                         &message,
                         &fl!("plugin-yk-is-plugged-in"),
@@ -511,15 +1223,15 @@ impl Stub {
                         // User told us to skip this key.
                         Ok(false) => return Ok(Ok(None)),
                         // User said they plugged it in; try it.
-                        Ok(true) => match open_by_serial(self.serial) {
-                            Ok(yubikey) => break Some(yubikey),
-                            Err(yubikey::Error::NotFound) => (),
+                        Ok(true) => match self.open_backend(callbacks)? {
+                            Ok(backend) => break Some(backend),
+                            Err(e) if e == yubikey::Error::NotFound || is_removed(&e) => (),
                             Err(_) => {
                                 return Ok(Err(identity::Error::Identity {
                                     index: self.identity_index,
                                     message: fl!(
                                         "plugin-err-yk-opening",
-                                        yubikey_serial = self.serial.to_string(),
+                                        yubikey_serial = self.serial_for_ui(),
                                     ),
                                 }));
                             }
@@ -530,7 +1242,7 @@ impl Stub {
                                 index: self.identity_index,
                                 message: fl!(
                                     "plugin-err-yk-opening",
-                                    yubikey_serial = self.serial.to_string(),
+                                    yubikey_serial = self.serial_for_ui(),
                                 ),
                             }))
                         }
@@ -540,52 +1252,54 @@ impl Stub {
                     // Change the message to indicate this to the user.
                     message = fl!(
                         "plugin-insert-yk-retry",
-                        yubikey_serial = self.serial.to_string(),
+                        yubikey_serial = self.serial_for_ui(),
                     );
                 };
 
-                if let Some(yk) = yubikey {
-                    yk
+                if let Some(backend) = backend {
+                    backend
                 } else {
-                    // `confirm` is not available; fall back to `message` with a timeout.
-                    if callbacks.message(&message)?.is_err() {
+                    // `confirm` is not available; fall back to `message` with a timeout,
+                    // unless we were told not to wait.
+                    let wait = config::wait();
+                    if wait.timeout.is_zero() || callbacks.message(&message)?.is_err() {
                         return Ok(Err(identity::Error::Identity {
                             index: self.identity_index,
                             message: fl!(
                                 "plugin-err-yk-not-found",
-                                yubikey_serial = self.serial.to_string(),
+                                yubikey_serial = self.serial_for_ui(),
                             ),
                         }));
                     }
 
-                    // Start a 15-second timer waiting for the YubiKey to be inserted
+                    // Start a timer waiting for the YubiKey to be inserted
                     let start = SystemTime::now();
                     loop {
-                        match open_by_serial(self.serial) {
-                            Ok(yubikey) => break yubikey,
-                            Err(yubikey::Error::NotFound) => (),
+                        match self.open_backend(callbacks)? {
+                            Ok(backend) => break backend,
+                            Err(e) if e == yubikey::Error::NotFound || is_removed(&e) => (),
                             Err(_) => {
                                 return Ok(Err(identity::Error::Identity {
                                     index: self.identity_index,
                                     message: fl!(
                                         "plugin-err-yk-opening",
-                                        yubikey_serial = self.serial.to_string(),
+                                        yubikey_serial = self.serial_for_ui(),
                                     ),
                                 }));
                             }
                         }
 
                         match SystemTime::now().duration_since(start) {
-                            Ok(end) if end >= FIFTEEN_SECONDS => {
+                            Ok(end) if end >= wait.timeout => {
                                 return Ok(Err(identity::Error::Identity {
                                     index: self.identity_index,
                                     message: fl!(
                                         "plugin-err-yk-timed-out",
-                                        yubikey_serial = self.serial.to_string(),
+                                        yubikey_serial = self.serial_for_ui(),
                                     ),
                                 }))
                             }
-                            _ => sleep(ONE_SECOND),
+                            _ => sleep(wait.poll_interval),
                         }
                     }
                 }
@@ -595,20 +1309,17 @@ impl Stub {
                     index: self.identity_index,
                     message: fl!(
                         "plugin-err-yk-opening",
-                        yubikey_serial = self.serial.to_string(),
+                        yubikey_serial = self.serial_for_ui(),
                     ),
                 }))
             }
         };
 
         // Read the pubkey from the YubiKey slot and check it still matches.
-        let (cert, pk) = match Certificate::read(&mut yubikey, SlotId::Retired(self.slot))
-            .ok()
-            .and_then(|cert| {
-                Recipient::from_certificate(&cert)
-                    .filter(|pk| pk.tag() == self.tag)
-                    .map(|pk| (cert, pk))
-            }) {
+        let pk = match backend
+            .recipient(self.slot)
+            .filter(|pk| pk.tag() == self.tag)
+        {
             Some(pk) => pk,
             None => {
                 return Ok(Err(identity::Error::Identity {
@@ -618,96 +1329,220 @@ impl Stub {
             }
         };
 
-        Ok(Ok(Some(Connection {
-            yubikey,
-            cert,
+        Ok(Ok(Some(Connection::new(
+            backend,
             pk,
-            slot: self.slot,
-            tag: self.tag,
-            identity_index: self.identity_index,
-            cached_metadata: None,
-            last_touch: None,
-        })))
+            self.slot,
+            self.identity_index,
+        ))))
+    }
+}
+
+/// Why a stanza could not be unwrapped.
+#[derive(Debug)]
+pub(crate) enum UnwrapError {
+    /// The YubiKey was removed, so the stanza can be tried again once it is back.
+    Removed,
+    /// The YubiKey can't unwrap the stanza.
+    Failed,
+}
+
+impl From<yubikey::Error> for UnwrapError {
+    fn from(e: yubikey::Error) -> Self {
+        if is_removed(&e) {
+            UnwrapError::Removed
+        } else {
+            UnwrapError::Failed
+        }
     }
 }
 
 pub(crate) struct Connection {
-    yubikey: YubiKey,
-    cert: Certificate,
+    backend: Box<dyn IdentityBackend>,
     pk: Recipient,
-    slot: RetiredSlotId,
+    slot: SlotId,
     tag: [u8; 4],
     identity_index: usize,
     cached_metadata: Option<Metadata>,
     last_touch: Option<Instant>,
+    /// The PIN that unlocked this YubiKey, kept so that slots with the `Always` PIN
+    /// policy can decrypt several stanzas, and other slots of the YubiKey can be used,
+    /// without asking for it again.
+    pin: Option<SecretString>,
+    /// Whether a decryption has used up the last PIN verification, which matters for
+    /// slots with the `Always` PIN policy.
+    pin_used: bool,
 }
 
 impl Connection {
+    pub(crate) fn new(
+        backend: Box<dyn IdentityBackend>,
+        pk: Recipient,
+        slot: SlotId,
+        identity_index: usize,
+    ) -> Self {
+        Connection {
+            backend,
+            tag: pk.tag(),
+            pk,
+            slot,
+            identity_index,
+            cached_metadata: None,
+            last_touch: None,
+            pin: None,
+            pin_used: false,
+        }
+    }
+
     pub(crate) fn recipient(&self) -> &Recipient {
         &self.pk
     }
 
+    pub(crate) fn serial(&self) -> Serial {
+        self.backend.serial()
+    }
+
+    /// Returns the PIN that unlocked this YubiKey, if one was needed.
+    pub(crate) fn pin(&self) -> Option<SecretString> {
+        self.pin
+            .as_ref()
+            .map(|pin| SecretString::new(pin.expose_secret().clone()))
+    }
+
+    /// Tries `pin`, which unlocked another slot of this YubiKey, before asking for one.
+    pub(crate) fn use_pin(&mut self, pin: Option<SecretString>) {
+        self.pin = pin;
+    }
+
+    /// A span over the PIV operation `name` on this slot, whose duration is logged at
+    /// the debug level.
+    fn span(&self, name: &'static str) -> Span {
+        debug_span!("piv", op = name, serial = %self.backend.serial(), slot = ?self.slot)
+    }
+
+    fn identity_error(&self, e: Error) -> identity::Error {
+        identity::Error::Identity {
+            index: self.identity_index,
+            message: format!("{:?}", e),
+        }
+    }
+
     pub(crate) fn request_pin_if_necessary<E>(
         &mut self,
         callbacks: &mut dyn Callbacks<E>,
     ) -> io::Result<Result<(), identity::Error>> {
+        let _span = self.span("piv_verify_pin").entered();
+
         // Check if we can skip requesting a PIN.
         if self.cached_metadata.is_none() {
-            self.cached_metadata =
-                match Metadata::extract(&mut self.yubikey, self.slot, &self.cert, true) {
-                    None => {
-                        return Ok(Err(identity::Error::Identity {
-                            index: self.identity_index,
-                            message: fl!("plugin-err-yk-invalid-pin-policy"),
-                        }))
-                    }
-                    metadata => metadata,
-                };
+            self.cached_metadata = match self.backend.metadata(self.slot) {
+                None => {
+                    return Ok(Err(identity::Error::Identity {
+                        index: self.identity_index,
+                        message: fl!("plugin-err-yk-invalid-pin-policy"),
+                    }))
+                }
+                metadata => metadata,
+            };
         }
         match self.cached_metadata.as_ref().and_then(|m| m.pin_policy) {
             Some(PinPolicy::Never) => return Ok(Ok(())),
-            Some(PinPolicy::Once) if self.yubikey.verify_pin(&[]).is_ok() => return Ok(Ok(())),
+            Some(PinPolicy::Once) if self.backend.verify_pin(&[]).is_ok() => return Ok(Ok(())),
             _ => (),
         }
 
-        // The policy requires a PIN, so request it.
-        let pin = match request_pin(
-            |prev_error| {
-                callbacks.request_secret(&format!(
-                    "{}{}{}",
-                    prev_error.as_deref().unwrap_or(""),
-                    prev_error.as_deref().map(|_| " ").unwrap_or(""),
-                    fl!(
-                        "plugin-enter-pin",
-                        yubikey_serial = self.yubikey.serial().to_string(),
-                    )
-                ))
-            },
-            self.yubikey.serial(),
-        )? {
-            Ok(pin) => pin,
-            Err(_) => {
-                return Ok(Err(identity::Error::Identity {
-                    index: self.identity_index,
-                    message: fl!(
-                        "plugin-err-pin-required",
-                        yubikey_serial = self.yubikey.serial().to_string(),
-                    ),
-                }))
+        // Use the PIN that unlocked another slot of this YubiKey.
+        if let Some(pin) = self.pin.take() {
+            if self
+                .backend
+                .verify_pin(pin.expose_secret().as_bytes())
+                .is_ok()
+            {
+                self.pin = Some(pin);
+                self.pin_used = false;
+                return Ok(Ok(()));
             }
+        }
+
+        // Don't try a PIN that could block the YubiKey.
+        let serial = self.backend.serial();
+        let mut tries = match self
+            .backend
+            .pin_tries()
+            .map_err(Error::YubiKey)
+            .and_then(|tries| pin::check_tries(serial, tries).map(|()| tries))
+        {
+            Ok(tries) => tries,
+            Err(e) => return Ok(Err(self.identity_error(e))),
         };
-        if let Err(e) = self.yubikey.verify_pin(pin.expose_secret().as_bytes()) {
-            return Ok(Err(identity::Error::Identity {
-                index: self.identity_index,
-                message: format!("{:?}", Error::YubiKey(e)),
-            }));
+
+        // Use a PIN cached by the PIN agent, forgetting it if it no longer works.
+        if let Some(pin) = pin::cached(serial) {
+            match self.backend.verify_pin(pin.expose_secret().as_bytes()) {
+                Ok(()) => {
+                    self.pin = Some(pin);
+                    self.pin_used = false;
+                    return Ok(Ok(()));
+                }
+                Err(e) => {
+                    debug!("Cached PIN for {} failed: {}", serial, e);
+                    pin::forget(Some(serial));
+                    if let yubikey::Error::WrongPin { tries: left } = e {
+                        tries = left;
+                        if let Err(e) = pin::check_tries(serial, tries) {
+                            return Ok(Err(self.identity_error(e)));
+                        }
+                    }
+                }
+            }
+        }
+
+        // The policy requires a PIN, so use the unattended PIN or request it.
+        let (pin, prompted) = match pin::unattended() {
+            Some(pin) => (pin, false),
+            None => match request_pin(
+                |prev_error| {
+                    callbacks.request_secret(&format!(
+                        "{}{}{}",
+                        prev_error.as_deref().unwrap_or(""),
+                        prev_error.as_deref().map(|_| " ").unwrap_or(""),
+                        pin::prompt_with_tries(
+                            fl!(
+                                "plugin-enter-pin",
+                                yubikey_serial = self.backend.serial().to_string(),
+                            ),
+                            tries,
+                        )
+                    ))
+                },
+                self.backend.serial(),
+            )? {
+                Ok(pin) => (pin, true),
+                Err(_) => {
+                    return Ok(Err(identity::Error::Identity {
+                        index: self.identity_index,
+                        message: fl!(
+                            "plugin-err-pin-required",
+                            yubikey_serial = self.backend.serial().to_string(),
+                        ),
+                    }))
+                }
+            },
+        };
+        if let Err(e) = self.backend.verify_pin(pin.expose_secret().as_bytes()) {
+            return Ok(Err(self.identity_error(Error::YubiKey(e))));
+        }
+        if prompted {
+            pin::remember(serial, &pin);
         }
+        self.pin = Some(pin);
+        self.pin_used = false;
         Ok(Ok(()))
     }
 
-    pub(crate) fn unwrap_file_key(&mut self, line: &RecipientLine) -> Result<FileKey, ()> {
-        assert_eq!(self.tag, line.tag);
-
+    /// Prepares for an operation with the private key, returning whether the YubiKey
+    /// will wait for a touch.
+    fn before_private_key_op(&mut self) -> Result<bool, UnwrapError> {
         // Check if the touch policy requires a touch.
         let needs_touch = match (
             self.cached_metadata.as_ref().and_then(|m| m.touch_policy),
@@ -718,18 +1553,25 @@ impl Connection {
             _ => false,
         };
 
-        // The YubiKey API for performing scalar multiplication takes the point in its
-        // uncompressed SEC-1 encoding.
-        let shared_secret = match decrypt_data(
-            &mut self.yubikey,
-            line.epk_bytes.decompress().as_bytes(),
-            AlgorithmId::EccP256,
-            SlotId::Retired(self.slot),
-        ) {
-            Ok(res) => res,
-            Err(_) => return Err(()),
-        };
+        // The `Always` PIN policy needs the PIN before each operation, so enter it again
+        // for every operation after the first.
+        let pin_always = matches!(
+            self.cached_metadata.as_ref().and_then(|m| m.pin_policy),
+            Some(PinPolicy::Always)
+        );
+        if pin_always && self.pin_used {
+            let pin = self.pin.as_ref().ok_or(UnwrapError::Failed)?;
+            self.backend
+                .verify_pin(pin.expose_secret().as_bytes())
+                .map_err(UnwrapError::from)?;
+        }
+        self.pin_used = pin_always;
 
+        Ok(needs_touch)
+    }
+
+    /// Records that an operation with the private key succeeded.
+    fn after_private_key_op(&mut self, needs_touch: bool) {
         // If we requested a touch and reached here, the user touched the YubiKey.
         if needs_touch {
             if let Some(TouchPolicy::Cached) =
@@ -738,20 +1580,95 @@ impl Connection {
                 self.last_touch = Some(Instant::now());
             }
         }
+    }
+
+    /// Signs `digest` with the key in this slot, returning the DER-encoded ECDSA
+    /// signature. `on_touch` is called first if the YubiKey will wait for a touch.
+    ///
+    /// Unlike [`Connection::unwrap_file_key`], this doesn't give up on a YubiKey that
+    /// isn't touched, as it is used by long-running processes.
+    pub(crate) fn sign(
+        &mut self,
+        digest: &[u8],
+        on_touch: impl FnOnce(),
+    ) -> Result<Buffer, UnwrapError> {
+        let _span = self.span("piv_sign").entered();
+        let needs_touch = self.before_private_key_op()?;
+        if needs_touch {
+            on_touch();
+        }
+        let signature = self
+            .backend
+            .sign(self.slot, self.pk.curve().algorithm(), digest)?;
+        self.after_private_key_op(needs_touch);
+        Ok(signature)
+    }
+
+    /// Unwraps the file key in `line`, calling `on_touch` if the backend reports that
+    /// the YubiKey is waiting for a touch.
+    pub(crate) fn unwrap_file_key(
+        &mut self,
+        line: &RecipientLine,
+        mut on_touch: impl FnMut(),
+    ) -> Result<FileKey, UnwrapError> {
+        assert_eq!(self.tag, line.tag);
+        let _span = self.span("piv_decrypt").entered();
+
+        let needs_touch = self.before_private_key_op()?;
+
+        // Don't outlive an abandoned touch request or a hung card.
+        let deadline = Deadline::start(
+            CARD_TIMEOUT,
+            fl!(
+                "plugin-err-yk-op-timed-out",
+                yubikey_serial = self.backend.serial().to_string(),
+            ),
+        );
+
+        // The YubiKey either multiplies the ephemeral point by the slot's scalar, or
+        // performs the raw RSA operation on the ciphertext.
+        let output = self
+            .backend
+            .decrypt(self.slot, &line.card_input(), &mut on_touch)?;
+        drop(deadline);
+
+        self.after_private_key_op(needs_touch);
+
+        let (epk_bytes, encrypted_file_key) = match (&line.wrapped, &self.pk) {
+            (
+                WrappedKey::Ecdh {
+                    epk_bytes,
+                    encrypted_file_key,
+                },
+                _,
+            ) => (epk_bytes, encrypted_file_key),
+            // The YubiKey only did the raw RSA operation, which leaves the padding.
+            (WrappedKey::Rsa { .. }, Recipient::Rsa(pk)) => {
+                return oaep::unwrap(pk, &output).ok_or(UnwrapError::Failed)
+            }
+            (WrappedKey::Rsa { .. }, _) => return Err(UnwrapError::Failed),
+        };
 
         let mut salt = vec![];
-        salt.extend_from_slice(line.epk_bytes.as_bytes());
-        salt.extend_from_slice(self.pk.to_encoded().as_bytes());
+        salt.extend_from_slice(epk_bytes.as_bytes());
+        salt.extend_from_slice(&self.pk.to_encoded());
 
-        let enc_key = hkdf(&salt, STANZA_KEY_LABEL, shared_secret.as_ref());
+        let enc_key = Zeroizing::new(hkdf(
+            &salt,
+            epk_bytes.curve().stanza_tag().as_bytes(),
+            output.as_ref(),
+        ));
 
         // A failure to decrypt is fatal, because we assume that we won't
         // encounter 32-bit collisions on the key tag embedded in the header.
-        match aead_decrypt(&enc_key, FILE_KEY_BYTES, &line.encrypted_file_key) {
-            Ok(pt) => Ok(TryInto::<[u8; FILE_KEY_BYTES]>::try_into(&pt[..])
-                .unwrap()
-                .into()),
-            Err(_) => Err(()),
+        match aead_decrypt(&enc_key, FILE_KEY_BYTES, encrypted_file_key) {
+            Ok(pt) => {
+                let pt = LockedSecret::new(pt);
+                Ok(TryInto::<[u8; FILE_KEY_BYTES]>::try_into(&pt[..])
+                    .unwrap()
+                    .into())
+            }
+            Err(_) => Err(UnwrapError::Failed),
         }
     }
 
@@ -759,13 +1676,16 @@ impl Connection {
     ///
     /// This can be used to preserve the YubiKey's PIN and touch caches.
     pub(crate) fn disconnect_without_reset(self) {
-        disconnect_without_reset(self.yubikey);
+        self.backend.disconnect_without_reset();
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use yubikey::{piv::RetiredSlotId, Serial};
+    use yubikey::{
+        piv::{RetiredSlotId, SlotId},
+        Serial,
+    };
 
     use super::Stub;
 
@@ -773,7 +1693,7 @@ mod tests {
     fn stub_round_trip() {
         let stub = Stub {
             serial: Serial::from(42),
-            slot: RetiredSlotId::R1,
+            slot: SlotId::Retired(RetiredSlotId::R1),
             tag: [7; 4],
             identity_index: 0,
         };
@@ -783,4 +1703,37 @@ mod tests {
         assert_eq!(Stub::from_bytes(&encoded, 0), Some(stub));
         assert_eq!(Stub::from_bytes(&encoded[..encoded.len() - 1], 0), None);
     }
+
+    #[test]
+    fn stub_string_round_trip() {
+        let stub = Stub {
+            serial: Serial::from(42),
+            slot: SlotId::Retired(RetiredSlotId::R3),
+            tag: [7; 4],
+            identity_index: 0,
+        };
+
+        let encoded = stub.to_string();
+        assert!(encoded.starts_with("AGE-PLUGIN-YUBIKEY-1"));
+        assert_eq!(Stub::decode(&encoded), Some(stub));
+        assert_eq!(Stub::decode("AGE-SECRET-KEY-1"), None);
+    }
+
+    #[test]
+    fn stub_standard_slot_round_trip() {
+        let stub = Stub {
+            serial: Serial::from(42),
+            slot: SlotId::KeyManagement,
+            tag: [7; 4],
+            identity_index: 0,
+        };
+
+        let encoded = stub.to_bytes();
+        assert_eq!(Stub::from_bytes(&encoded, 0), Some(stub));
+
+        // Slots that can't hold an identity are rejected.
+        let mut encoded = encoded;
+        encoded[4] = u8::from(SlotId::Attestation);
+        assert_eq!(Stub::from_bytes(&encoded, 0), None);
+    }
 }
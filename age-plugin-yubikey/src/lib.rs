@@ -0,0 +1,246 @@
+//! Library API for `age-plugin-yubikey`, enabled with the `library` feature.
+//!
+//! This lets other programs (such as graphical frontends or provisioning tools) find the
+//! age identities on connected YubiKeys, generate new ones, and parse the recipients and
+//! identity stubs that `age-plugin-yubikey` prints, without running the binary and
+//! parsing its output.
+//!
+//! Messages (including those of [`Error`]) are localized for the user's preferred
+//! languages, as in the binary.
+
+#![cfg(feature = "library")]
+#![forbid(unsafe_code)]
+
+use std::str::FromStr;
+
+use yubikey::{
+    certificate::Certificate, piv::SlotId, reader::Context, PinPolicy, Serial, TouchPolicy, YubiKey,
+};
+
+// These modules are shared with the binary, which uses more of them than the library.
+#[cfg(unix)]
+#[allow(dead_code)]
+mod agent;
+#[allow(dead_code)]
+mod backend;
+#[cfg(unix)]
+#[allow(dead_code)]
+mod broker;
+#[allow(dead_code)]
+mod builder;
+#[allow(dead_code)]
+mod cache;
+#[allow(dead_code)]
+mod cancel;
+#[allow(dead_code)]
+mod capabilities;
+#[allow(dead_code)]
+mod config;
+#[allow(dead_code)]
+mod error;
+#[allow(dead_code)]
+mod format;
+mod i18n;
+#[allow(dead_code)]
+mod key;
+#[allow(dead_code)]
+mod oaep;
+#[allow(dead_code)]
+mod p256;
+#[allow(dead_code)]
+mod pin;
+#[cfg(feature = "remote")]
+#[allow(dead_code)]
+mod remote;
+#[allow(dead_code)]
+mod util;
+
+use i18n::fl;
+use util::{BINARY_NAME, IDENTITY_PREFIX, RECIPIENT_PREFIX, USABLE_SLOTS};
+
+pub use builder::IdentityBuilder;
+pub use error::Error;
+pub use key::Stub;
+pub use p256::{Curve, Recipient};
+pub use util::Metadata;
+pub use yubikey;
+
+/// Reads the settings that age clients pass to the plugin through the environment and
+/// the configuration file, such as an unattended PIN (`AGE_YUBIKEY_UNATTENDED_PIN=1`
+/// with `AGE_YUBIKEY_PIN`, `AGE_YUBIKEY_PIN_FILE` or `AGE_YUBIKEY_PIN_FD`), or
+/// permission to use the last PIN try (`AGE_YUBIKEY_RISK_LOCKOUT=1`).
+///
+/// Without this, PINs are prompted for at the terminal.
+pub fn configure() -> Result<(), Error> {
+    pin::configure(false, None, None, false)?;
+    config::configure(None)
+}
+
+/// Sets whether [`identities`] includes keys in the standard PIV slots (9a, 9c, 9d and
+/// 9e), which other tools provision for their own purposes. Only the retired slots are
+/// used by default.
+pub fn allow_standard_slots(allow: bool) {
+    util::allow_standard_slots(allow)
+}
+
+/// Opens every connected YubiKey.
+pub fn connected() -> Result<Vec<YubiKey>, Error> {
+    let mut readers = Context::open()?;
+    let yubikeys = readers
+        .iter()?
+        .filter(key::filter_connected)
+        .map(|reader| key::open_connection(&reader))
+        .collect::<Result<_, _>>()?;
+    Ok(yubikeys)
+}
+
+/// Opens the connected YubiKey with the given serial number.
+pub fn open(serial: Serial) -> Result<YubiKey, Error> {
+    key::open_by_serial(serial).map_err(|e| match e {
+        yubikey::Error::NotFound => Error::NoMatchingSerial(serial),
+        e => e.into(),
+    })
+}
+
+/// Returns the age identities stored in `yubikey`, along with their recipients and
+/// metadata.
+///
+/// If `all` is set, compatible keys that were not generated by `age-plugin-yubikey` are
+/// included too, which is slower as their policies are read from an attestation.
+///
+/// ```no_run
+/// # fn main() -> Result<(), age_plugin_yubikey::Error> {
+/// for mut yubikey in age_plugin_yubikey::connected()? {
+///     for (stub, recipient, metadata) in age_plugin_yubikey::identities(&mut yubikey, false)? {
+///         println!("{}: {} ({})", metadata.name(), recipient, stub);
+///     }
+/// }
+/// # Ok(())
+/// # }
+/// ```
+pub fn identities(
+    yubikey: &mut YubiKey,
+    all: bool,
+) -> Result<Vec<(Stub, Recipient, Metadata)>, Error> {
+    key::identities(yubikey, all)
+}
+
+/// Releases `yubikey` without resetting it, which preserves its PIN and touch caches.
+pub fn disconnect(yubikey: YubiKey) {
+    key::disconnect_without_reset(yubikey)
+}
+
+impl FromStr for Stub {
+    type Err = Error;
+
+    /// Parses an identity stub, as found in identity files.
+    fn from_str(s: &str) -> Result<Self, Error> {
+        Stub::decode(s).ok_or_else(|| Error::InvalidIdentity(s.into()))
+    }
+}
+
+// Accessors that only the library needs live here, so that the binary doesn't carry
+// them as dead code.
+impl Stub {
+    /// The serial number of the YubiKey holding this identity's key, or 0 for YubiKeys
+    /// that don't expose it.
+    pub fn serial(&self) -> Serial {
+        self.serial
+    }
+
+    /// The slot holding this identity's key.
+    pub fn slot(&self) -> SlotId {
+        self.slot
+    }
+}
+
+impl Metadata {
+    /// Reads the metadata of the identity in `slot`, if it holds a compatible key.
+    ///
+    /// If `all` is not set, keys that were not generated by `age-plugin-yubikey` are
+    /// ignored.
+    pub fn read(yubikey: &mut YubiKey, slot: SlotId, all: bool) -> Option<Self> {
+        let cert = Certificate::read(yubikey, slot).ok()?;
+        Recipient::from_certificate(&cert)?;
+        Metadata::extract(yubikey, slot, &cert, all)
+    }
+
+    /// The serial number of the YubiKey holding the key.
+    pub fn serial(&self) -> Serial {
+        self.serial
+    }
+
+    /// The slot holding the key.
+    pub fn slot(&self) -> SlotId {
+        self.slot
+    }
+
+    /// The name of the identity, from its certificate.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// When the identity was created, from its certificate.
+    pub fn created(&self) -> &str {
+        &self.created
+    }
+
+    /// The PIN policy of the key, if it could be determined.
+    pub fn pin_policy(&self) -> Option<PinPolicy> {
+        self.pin_policy
+    }
+
+    /// The touch policy of the key, if it could be determined.
+    pub fn touch_policy(&self) -> Option<TouchPolicy> {
+        self.touch_policy
+    }
+
+    /// The firmware version of the YubiKey, if known.
+    pub fn firmware(&self) -> Option<&str> {
+        self.firmware.as_deref()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use yubikey::{
+        piv::{RetiredSlotId, SlotId},
+        Serial,
+    };
+
+    use super::{Recipient, Stub};
+
+    // The P-256 generator point.
+    const GENERATOR: &str = "036b17d1f2e12c4247f8bce6e563a440f277037d812deb33a0f4a13945d898c296";
+
+    #[test]
+    fn parse_recipient() {
+        let recipient = Recipient::from_public_key(GENERATOR).unwrap();
+        let encoded = recipient.to_string();
+        let parsed: Recipient = encoded.parse().unwrap();
+        assert_eq!(parsed.to_string(), encoded);
+        assert_eq!(parsed.to_spki_der(), recipient.to_spki_der());
+
+        assert!("age1yubikey1".parse::<Recipient>().is_err());
+        assert!(encoded
+            .replace("age1yubikey", "age1other")
+            .parse::<Recipient>()
+            .is_err());
+    }
+
+    #[test]
+    fn parse_stub() {
+        let recipient = Recipient::from_public_key(GENERATOR).unwrap();
+        let stub = Stub::new(
+            Serial::from(42),
+            SlotId::Retired(RetiredSlotId::R3),
+            &recipient,
+        );
+        let parsed: Stub = stub.to_string().parse().unwrap();
+        assert_eq!(parsed, stub);
+        assert_eq!(parsed.serial(), Serial::from(42));
+        assert_eq!(parsed.slot(), SlotId::Retired(RetiredSlotId::R3));
+
+        assert!("AGE-PLUGIN-YUBIKEY-1".parse::<Stub>().is_err());
+    }
+}
@@ -1,77 +1,48 @@
 #![forbid(unsafe_code)]
 
+use std::cell::RefCell;
 use std::fs::{File, OpenOptions};
 use std::io::{self, Write};
+use std::process;
 
+use age_core::secrecy::zeroize::Zeroizing;
 use age_plugin::run_state_machine;
 use dialoguer::{Confirm, Input, Select};
 use gumdrop::Options;
-use i18n_embed::{
-    fluent::{fluent_language_loader, FluentLanguageLoader},
-    DesktopLanguageRequester,
-};
-use lazy_static::lazy_static;
-use rust_embed::RustEmbed;
-use yubikey::{piv::RetiredSlotId, reader::Context, PinPolicy, Serial, TouchPolicy};
-
+use tracing_subscriber::{fmt::format::FmtSpan, EnvFilter};
+use yubikey::{piv::SlotId, reader::Context, MgmKey, PinPolicy, Serial, TouchPolicy};
+
+#[cfg(unix)]
+mod agent;
+mod attest;
+mod backend;
+#[cfg(unix)]
+mod broker;
 mod builder;
+mod cache;
+mod cancel;
+mod capabilities;
+mod config;
 mod error;
+mod escrow;
 mod format;
+mod i18n;
+mod interactive;
 mod key;
+mod oaep;
 mod p256;
+mod pin;
 mod plugin;
+mod provision;
+#[cfg(feature = "remote")]
+mod remote;
+#[cfg(unix)]
+mod ssh_agent;
 mod util;
 
 use error::Error;
-
-const PLUGIN_NAME: &str = "yubikey";
-const BINARY_NAME: &str = "age-plugin-yubikey";
-const RECIPIENT_PREFIX: &str = "age1yubikey";
-const IDENTITY_PREFIX: &str = "age-plugin-yubikey-";
-const STANZA_TAG: &str = "piv-p256";
-
-const USABLE_SLOTS: [RetiredSlotId; 20] = [
-    RetiredSlotId::R1,
-    RetiredSlotId::R2,
-    RetiredSlotId::R3,
-    RetiredSlotId::R4,
-    RetiredSlotId::R5,
-    RetiredSlotId::R6,
-    RetiredSlotId::R7,
-    RetiredSlotId::R8,
-    RetiredSlotId::R9,
-    RetiredSlotId::R10,
-    RetiredSlotId::R11,
-    RetiredSlotId::R12,
-    RetiredSlotId::R13,
-    RetiredSlotId::R14,
-    RetiredSlotId::R15,
-    RetiredSlotId::R16,
-    RetiredSlotId::R17,
-    RetiredSlotId::R18,
-    RetiredSlotId::R19,
-    RetiredSlotId::R20,
-];
-
-#[derive(RustEmbed)]
-#[folder = "i18n"]
-struct Translations;
-
-const TRANSLATIONS: Translations = Translations {};
-
-lazy_static! {
-    static ref LANGUAGE_LOADER: FluentLanguageLoader = fluent_language_loader!();
-}
-
-#[macro_export]
-macro_rules! fl {
-    ($message_id:literal) => {{
-        i18n_embed_fl::fl!($crate::LANGUAGE_LOADER, $message_id)
-    }};
-    ($message_id:literal, $($kwarg:expr),* $(,)*) => {{
-        i18n_embed_fl::fl!($crate::LANGUAGE_LOADER, $message_id, $($kwarg,)*)
-    }};
-}
+use i18n::fl;
+use util::{BINARY_NAME, IDENTITY_PREFIX, PLUGIN_NAME, RECIPIENT_PREFIX, USABLE_SLOTS};
 
 #[derive(Debug, Options)]
 struct PluginOptions {
@@ -88,15 +59,144 @@ struct PluginOptions {
     )]
     age_plugin: Option<String>,
 
-    #[options(help = "Force --generate to overwrite a filled slot.")]
+    #[options(
+        help = "Print the attestation for the key in a slot, and its issuer, as PEM.",
+        no_short
+    )]
+    attest: bool,
+
+    #[options(
+        help = "PEM file holding Yubico's PIV root CA, to check attestations against with --verify.",
+        meta = "PATH",
+        no_short
+    )]
+    attestation_ca: Option<String>,
+
+    #[options(
+        help = "Run the shared YubiKey connection broker. Internal use only.",
+        no_short
+    )]
+    broker: bool,
+
+    #[options(help = "Run the PIN caching agent. Internal use only.", no_short)]
+    pin_agent: bool,
+
+    #[options(help = "Change the management key.", no_short)]
+    change_mgmt_key: bool,
+
+    #[options(help = "Change the PIN.", no_short)]
+    change_pin: bool,
+
+    #[options(help = "Change the PUK.", no_short)]
+    change_puk: bool,
+
+    #[options(
+        help = "Print the settings in the configuration file, or print or set (to a value, or to '' to remove it) the setting given as SECTION.KEY [VALUE].",
+        no_short
+    )]
+    config: bool,
+
+    #[options(help = "Remove the key and certificate in a slot.", no_short)]
+    delete: bool,
+
+    #[options(
+        help = "List the yk-agentd daemons advertised on the local network, and the serials of their YubiKeys.",
+        no_short
+    )]
+    discover: bool,
+
+    #[options(
+        help = "Force --generate to overwrite a filled slot, --repair to replace a certificate, --export-recipients or --export-stubs to overwrite a file, or --delete to skip confirmation."
+    )]
     force: bool,
 
+    #[options(
+        help = "Make the PIN agent forget its cached PINs, or only the PIN for --serial.",
+        no_short
+    )]
+    forget_pins: bool,
+
+    #[options(
+        help = "Read a custom hex-encoded management key from this file descriptor.",
+        meta = "FD",
+        no_short
+    )]
+    mgmt_key_fd: Option<u32>,
+
+    #[options(
+        help = "Replace the management key with a new PIN-protected key when generating.",
+        no_short
+    )]
+    rotate_mgmt_key: bool,
+
+    #[options(
+        help = "With --change-mgmt-key, use a random management key stored PIN-protected on the YubiKey.",
+        no_short
+    )]
+    protect_mgmt_key: bool,
+
     #[options(help = "Generate a new YubiKey identity.")]
     generate: bool,
 
     #[options(help = "Print identities stored in connected YubiKeys.")]
     identity: bool,
 
+    #[options(
+        help = "Print the firmware version and supported features of a YubiKey.",
+        no_short
+    )]
+    info: bool,
+
+    #[options(
+        help = "Write a random CHUID and CCC to a YubiKey that lacks them, as some smart card middleware needs.",
+        no_short
+    )]
+    init_card: bool,
+
+    #[options(
+        help = "Manage the identities in connected YubiKeys from interactive menus.",
+        no_short
+    )]
+    interactive: bool,
+
+    #[options(
+        help = "List identities in the standard PIV slots (9a, 9c, 9d and 9e) too, and let --slot name them.",
+        no_short
+    )]
+    allow_standard_slots: bool,
+
+    #[options(
+        help = "With --identity, print a single identity file for all compatible keys in connected YubiKeys.",
+        no_short
+    )]
+    all: bool,
+
+    #[options(
+        help = "Import the P-256 or P-384 private key given with --key as a new identity.",
+        no_short
+    )]
+    import: bool,
+
+    #[options(
+        help = "PEM file (PKCS #8 or SEC1) holding the private key for --import.",
+        meta = "PATH",
+        no_short
+    )]
+    key: Option<String>,
+
+    #[options(
+        help = "Print --identity, --info, --list and --list-all output as JSON.",
+        no_short
+    )]
+    json: bool,
+
+    #[options(
+        help = "One of [text, pkcs11-uri]. Defaults to 'text'. 'pkcs11-uri' makes --list and --list-all print the PKCS #11 URI of each key instead.",
+        meta = "FORMAT",
+        no_short
+    )]
+    format: Option<String>,
+
     #[options(help = "List recipients for age identities in connected YubiKeys.")]
     list: bool,
 
@@ -106,6 +206,73 @@ struct PluginOptions {
     )]
     list_all: bool,
 
+    #[options(
+        help = "Write the recipients for all compatible keys in a YubiKey to a recipients file.",
+        meta = "PATH",
+        no_short
+    )]
+    export_recipients: Option<String>,
+
+    #[options(
+        help = "Back up the identity stubs for all compatible keys in connected YubiKeys to a JSON escrow file.",
+        meta = "PATH",
+        no_short
+    )]
+    export_stubs: Option<String>,
+
+    #[options(
+        help = "Print the identities backed up in a JSON escrow file by --export-stubs.",
+        meta = "PATH",
+        no_short
+    )]
+    import_stubs: Option<String>,
+
+    #[options(
+        help = "Generate the identities described in a TOML file on every connected YubiKey.",
+        meta = "CONFIG",
+        no_short
+    )]
+    provision: Option<String>,
+
+    #[options(
+        help = "Print the recipient for a P-256 public key given as PEM, or as hex-encoded SEC1 or SPKI.",
+        meta = "PUBKEY",
+        no_short
+    )]
+    recipient_from: Option<String>,
+
+    #[options(
+        help = "Only print the identity for this recipient, to find the YubiKey and slot holding it.",
+        meta = "RECIPIENT",
+        no_short
+    )]
+    recipient: Option<String>,
+
+    #[options(
+        help = "Refuse the commands that change a YubiKey, such as --generate, --delete and --change-pin.",
+        no_short
+    )]
+    read_only: bool,
+
+    #[options(
+        help = "Use the YubiKeys shared by the yk-agentd at this address (HOST:PORT, unix:PATH, pipe:NAME or ssh:[USER@]HOST/PATH), for --identity and --list.",
+        meta = "ADDRESS",
+        no_short
+    )]
+    remote: Option<String>,
+
+    #[options(
+        help = "Change the name of the identity in a slot to the one given with --name.",
+        no_short
+    )]
+    rename: bool,
+
+    #[options(
+        help = "Write a new certificate for the key in a slot whose certificate was wiped, keeping its recipient.",
+        no_short
+    )]
+    repair: bool,
+
     #[options(
         help = "Name for the generated identity. Defaults to 'age identity HEX_TAG'.",
         no_short
@@ -115,6 +282,35 @@ struct PluginOptions {
     #[options(help = "One of [always, once, never]. Defaults to 'once'.", no_short)]
     pin_policy: Option<String>,
 
+    #[options(
+        help = "Read the PIN from this file. Requires --unattended-pin.",
+        meta = "PATH",
+        no_short
+    )]
+    pin_file: Option<String>,
+
+    #[options(
+        help = "Read the PIN from this file descriptor. Requires --unattended-pin.",
+        meta = "FD",
+        no_short
+    )]
+    pin_fd: Option<u32>,
+
+    #[options(help = "One of [p256, p384, rsa2048]. Defaults to 'p256'.", no_short)]
+    algorithm: Option<String>,
+
+    #[options(
+        help = "Refuse to list or generate identities on YubiKeys with the default PIN or PUK.",
+        no_short
+    )]
+    require_nondefault_pin: bool,
+
+    #[options(
+        help = "Try the PIN even if a wrong PIN would block it, because only one try is left.",
+        no_short
+    )]
+    risk_lockout: bool,
+
     #[options(
         help = "Specify which YubiKey to use, if more than one is plugged in.",
         no_short
@@ -122,41 +318,126 @@ struct PluginOptions {
     serial: Option<u32>,
 
     #[options(
-        help = "Specify which slot to use. Defaults to first usable slot.",
+        help = "Specify which slot to use: 1 to 20, or with --allow-standard-slots 9a, 9c, 9d or 9e. Defaults to first usable slot.",
+        no_short,
+        meta = "SLOT"
+    )]
+    slot: Option<String>,
+
+    #[options(
+        help = "Run an ssh-agent that offers the P-256 and P-384 keys in connected YubiKeys as ECDSA SSH keys.",
         no_short
     )]
-    slot: Option<u8>,
+    ssh_agent: bool,
 
     #[options(
         help = "One of [always, cached, never]. Defaults to 'always'.",
         no_short
     )]
     touch_policy: Option<String>,
+
+    #[options(
+        help = "Set a new PIN after entering the PUK, unblocking the PIN.",
+        no_short
+    )]
+    unblock_pin: bool,
+
+    #[options(
+        help = "Use the PIN from --pin-file, --pin-fd or AGE_YUBIKEY_PIN instead of prompting.",
+        no_short
+    )]
+    unattended_pin: bool,
+
+    #[options(help = "Print the underlying causes of errors.")]
+    verbose: bool,
+
+    #[options(
+        help = "One of [text, json]. Defaults to 'text'. 'json' prints errors as a JSON object with a stable code.",
+        meta = "FORMAT",
+        no_short
+    )]
+    error_format: Option<String>,
+
+    #[options(
+        help = "What to log to stderr: a level such as 'debug', or directives such as 'usbip=trace'. Defaults to RUST_LOG, or nothing.",
+        meta = "LEVEL",
+        no_short
+    )]
+    log_level: Option<String>,
+
+    #[options(
+        help = "One of [text, json]. Defaults to 'text'. 'json' logs a JSON object per line.",
+        meta = "FORMAT",
+        no_short
+    )]
+    log_format: Option<String>,
+
+    #[options(
+        help = "Check that the key in a slot matches its certificate, policies and attestation, and any given identities.",
+        no_short
+    )]
+    verify: bool,
+
+    #[options(
+        free,
+        help = "Identities or identity files to check with --verify, or the setting and value for --config."
+    )]
+    args: Vec<String>,
 }
 
 struct PluginFlags {
     serial: Option<Serial>,
-    slot: Option<RetiredSlotId>,
+    slot: Option<SlotId>,
     name: Option<String>,
     pin_policy: Option<PinPolicy>,
     touch_policy: Option<TouchPolicy>,
+    curve: Option<p256::Curve>,
     force: bool,
+    json: bool,
+    recipient: Option<p256::Recipient>,
+    mgmt_key: Option<MgmKey>,
+    rotate_mgmt_key: bool,
+    protect_mgmt_key: bool,
+    require_nondefault_pin: bool,
 }
 
 impl TryFrom<PluginOptions> for PluginFlags {
     type Error = Error;
 
     fn try_from(opts: PluginOptions) -> Result<Self, Self::Error> {
-        let serial = opts.serial.map(|s| s.into());
-        let slot = opts.slot.map(util::ui_to_slot).transpose()?;
+        // Flags take precedence over the environment, which takes precedence over the
+        // defaults in the configuration file.
+        let defaults = config::defaults();
+        let serial = opts
+            .serial
+            .map(|s| s.into())
+            .or_else(key::serial_from_env)
+            .or_else(|| defaults.serial.map(Serial::from));
+        let slot = opts
+            .slot
+            .map(|slot| util::parse_slot(&slot, opts.allow_standard_slots))
+            .transpose()?;
         let pin_policy = opts
             .pin_policy
+            .or(defaults.pin_policy)
             .map(util::pin_policy_from_string)
             .transpose()?;
         let touch_policy = opts
             .touch_policy
+            .or(defaults.touch_policy)
             .map(util::touch_policy_from_string)
             .transpose()?;
+        let curve = opts
+            .algorithm
+            .map(|s| p256::Curve::from_name(&s).ok_or(Error::InvalidAlgorithm(s)))
+            .transpose()?;
+        let recipient = opts.recipient.map(|s| s.parse()).transpose()?;
+        let mgmt_key = opts
+            .mgmt_key_fd
+            .map(|fd| util::read_fd(fd).map(Zeroizing::new))
+            .transpose()?
+            .map(|s| key::parse_mgmt_key(&s))
+            .transpose()?;
 
         Ok(PluginFlags {
             serial,
@@ -164,39 +445,99 @@ impl TryFrom<PluginOptions> for PluginFlags {
             name: opts.name,
             pin_policy,
             touch_policy,
+            curve,
             force: opts.force,
+            json: opts.json,
+            recipient,
+            mgmt_key,
+            rotate_mgmt_key: opts.rotate_mgmt_key,
+            protect_mgmt_key: opts.protect_mgmt_key,
+            require_nondefault_pin: opts.require_nondefault_pin,
         })
     }
 }
 
 fn generate(flags: PluginFlags) -> Result<(), Error> {
+    if flags.json {
+        return Err(Error::InvalidFlagCommand(
+            "--json".into(),
+            "--generate".into(),
+        ));
+    }
     let mut yubikey = key::open(flags.serial)?;
+    if flags.require_nondefault_pin {
+        key::check_default_credentials(&mut yubikey, true)?;
+    }
 
-    let (stub, recipient, metadata) = builder::IdentityBuilder::new(flags.slot)
+    let (stub, recipient, metadata) = builder::IdentityBuilder::new(None)
+        .with_slot(flags.slot)
         .with_name(flags.name)
         .with_pin_policy(flags.pin_policy)
         .with_touch_policy(flags.touch_policy)
+        .with_curve(flags.curve)
+        .with_mgmt_key(flags.mgmt_key)
+        .rotate_mgmt_key(flags.rotate_mgmt_key)
         .force(flags.force)
         .build(&mut yubikey)?;
 
     util::print_identity(stub, recipient, metadata);
 
-    // We have written to the YubiKey, which means we've authenticated with the management
-    // key. Out of an abundance of caution, we let the YubiKey be reset on disconnect,
-    // which will clear its PIN and touch caches. This has as small negative UX effect,
-    // but identity generation is a relatively infrequent occurrence, and users are more
-    // likely to see their cached PINs reset due to switching applets (e.g. from PIV to
-    // FIDO2).
+    // Generating changes a default PIN and management key, but not always the PUK.
+    key::check_default_credentials(&mut yubikey, false)?;
+
+    key::finish_after_mgmt_auth(yubikey);
+
+    Ok(())
+}
+
+fn import(flags: PluginFlags, key_file: Option<String>) -> Result<(), Error> {
+    for (set, flag) in [
+        (flags.curve.is_some(), "--algorithm"),
+        (flags.json, "--json"),
+    ] {
+        if set {
+            return Err(Error::InvalidFlagCommand(flag.into(), "--import".into()));
+        }
+    }
+    let key_file = key_file.ok_or(Error::ImportNeedsKey)?;
+    let secret = p256::SecretKey::from_pem(&Zeroizing::new(std::fs::read_to_string(&key_file)?))
+        .ok_or(Error::InvalidPrivateKey)?;
+
+    let mut yubikey = key::open(flags.serial)?;
+    if flags.require_nondefault_pin {
+        key::check_default_credentials(&mut yubikey, true)?;
+    }
+
+    let (stub, recipient, metadata) = builder::IdentityBuilder::new(None)
+        .with_slot(flags.slot)
+        .with_name(flags.name)
+        .with_pin_policy(flags.pin_policy)
+        .with_touch_policy(flags.touch_policy)
+        .with_mgmt_key(flags.mgmt_key)
+        .rotate_mgmt_key(flags.rotate_mgmt_key)
+        .force(flags.force)
+        .import(&mut yubikey, &secret)?;
+
+    util::print_identity(stub, recipient, metadata);
+
+    // Unlike a generated key, this one also exists outside the YubiKey.
+    eprintln!("{}", fl!("import-key-copy", path = key_file));
+
+    key::check_default_credentials(&mut yubikey, false)?;
+
+    key::finish_after_mgmt_auth(yubikey);
 
     Ok(())
 }
 
 fn print_single(
     serial: Option<Serial>,
-    slot: RetiredSlotId,
+    slot: SlotId,
+    require_nondefault_pin: bool,
     printer: impl Fn(key::Stub, p256::Recipient, util::Metadata),
 ) -> Result<(), Error> {
     let mut yubikey = key::open(serial)?;
+    key::check_default_credentials(&mut yubikey, require_nondefault_pin)?;
 
     let (key, slot, recipient) = key::list_compatible(&mut yubikey)?
         .find(|(_, s, _)| s == &slot)
@@ -216,6 +557,8 @@ fn print_multiple(
     kind: &str,
     serial: Option<Serial>,
     all: bool,
+    json: bool,
+    require_nondefault_pin: bool,
     printer: impl Fn(key::Stub, p256::Recipient, util::Metadata),
 ) -> Result<(), Error> {
     let mut readers = Context::open()?;
@@ -228,24 +571,22 @@ fn print_multiple(
                 continue;
             }
         }
+        key::check_default_credentials(&mut yubikey, require_nondefault_pin)?;
 
-        for (key, slot, recipient) in key::list_compatible(&mut yubikey)? {
-            let stub = key::Stub::new(yubikey.serial(), slot, &recipient);
-            let metadata = match util::Metadata::extract(&mut yubikey, slot, key.certificate(), all)
-            {
-                Some(res) => res,
-                None => continue,
-            };
-
+        for (stub, recipient, metadata) in key::identities(&mut yubikey, all)? {
             printer(stub, recipient, metadata);
             printed += 1;
+            if !json {
+                println!();
+            }
+        }
+        if !json {
             println!();
         }
-        println!();
 
         key::disconnect_without_reset(yubikey);
     }
-    if printed > 1 {
+    if printed > 1 && !json {
         eprintln!("{}", fl!("printed-multiple", kind = kind, count = printed));
     }
 
@@ -258,96 +599,1214 @@ fn print_details(
     all: bool,
     printer: impl Fn(key::Stub, p256::Recipient, util::Metadata),
 ) -> Result<(), Error> {
+    if let Some(recipient) = &flags.recipient {
+        return print_recipient(recipient, &flags, all, printer);
+    }
+    #[cfg(feature = "remote")]
+    if let Some(config) = config::remote() {
+        return print_remote(&config, kind, flags, all, printer);
+    }
     if let Some(slot) = flags.slot {
-        print_single(flags.serial, slot, printer)
+        print_single(flags.serial, slot, flags.require_nondefault_pin, printer)
     } else {
-        print_multiple(kind, flags.serial, all, printer)
+        print_multiple(
+            kind,
+            flags.serial,
+            all,
+            flags.json,
+            flags.require_nondefault_pin,
+            printer,
+        )
     }
 }
 
-fn identity(flags: PluginFlags) -> Result<(), Error> {
-    if flags.force {
-        return Err(Error::InvalidFlagCommand(
-            "--force".into(),
-            "--identity".into(),
-        ));
+/// Like [`print_single`] and [`print_multiple`], for the YubiKeys shared by a remote
+/// daemon.
+#[cfg(feature = "remote")]
+fn print_remote(
+    config: &config::RemoteConfig,
+    kind: &str,
+    flags: PluginFlags,
+    all: bool,
+    printer: impl Fn(key::Stub, p256::Recipient, util::Metadata),
+) -> Result<(), Error> {
+    use backend::IdentityBackend;
+
+    let mut yubikeys = remote::RemoteYubiKey::open_all(config)?;
+    if let Some(serial) = flags.serial {
+        yubikeys.retain(|yubikey| yubikey.serial() == serial);
+        if yubikeys.is_empty() {
+            return Err(Error::NoMatchingSerial(serial));
+        }
     }
-    print_details(
-        &fl!("printed-kind-identities"),
-        flags,
-        false,
-        util::print_identity,
-    )
-}
+    let slots = match flags.slot {
+        Some(slot) => {
+            if yubikeys.len() > 1 {
+                return Err(Error::MultipleYubiKeys);
+            }
+            vec![slot]
+        }
+        None => util::identity_slots(),
+    };
 
-fn list(flags: PluginFlags, all: bool) -> Result<(), Error> {
-    if all && flags.slot.is_some() {
-        return Err(Error::UseListForSingleSlot);
+    let mut printed = 0;
+    for mut yubikey in yubikeys {
+        for (stub, recipient, metadata) in yubikey.identities(&slots, all || flags.slot.is_some()) {
+            printer(stub, recipient, metadata);
+            printed += 1;
+            if !flags.json && flags.slot.is_none() {
+                println!();
+            }
+        }
+        if !flags.json && flags.slot.is_none() {
+            println!();
+        }
     }
-    if flags.force {
-        return Err(Error::InvalidFlagCommand(
-            "--force".into(),
-            format!("--list{}", if all { "-all" } else { "" }),
-        ));
+    match flags.slot {
+        Some(slot) if printed == 0 => return Err(Error::SlotHasNoIdentity(slot)),
+        None if printed > 1 && !flags.json => {
+            eprintln!("{}", fl!("printed-multiple", kind = kind, count = printed))
+        }
+        _ => (),
     }
 
-    print_details(
-        &fl!("printed-kind-recipients"),
-        flags,
-        all,
-        |_, recipient, metadata| {
-            println!("{metadata}");
-            println!("{recipient}");
-        },
-    )
+    Ok(())
 }
 
-fn main() -> Result<(), Error> {
-    env_logger::builder()
-        .format_timestamp(None)
-        .filter_level(log::LevelFilter::Off)
-        .parse_default_env()
-        .init();
-
-    let requested_languages = DesktopLanguageRequester::requested_languages();
-    i18n_embed::select(&*LANGUAGE_LOADER, &TRANSLATIONS, &requested_languages).unwrap();
-    // Unfortunately the common Windows terminals don't support Unicode Directionality
-    // Isolation Marks, so we disable them for now.
-    LANGUAGE_LOADER.set_use_isolating(false);
-
-    let opts = PluginOptions::parse_args_default_or_exit();
-
-    if [opts.generate, opts.identity, opts.list, opts.list_all]
-        .iter()
-        .filter(|&&b| b)
-        .count()
+/// Prints the identity for `recipient`, from whichever YubiKey and slot holds it.
+fn print_recipient(
+    recipient: &p256::Recipient,
+    flags: &PluginFlags,
+    all: bool,
+    printer: impl Fn(key::Stub, p256::Recipient, util::Metadata),
+) -> Result<(), Error> {
+    let recipient = recipient.to_string();
+    let matches = |stub: &key::Stub, r: &p256::Recipient| {
+        flags.slot.map_or(true, |slot| stub.slot == slot) && r.to_string() == recipient
+    };
+
+    #[cfg(feature = "remote")]
+    if let Some(config) = config::remote() {
+        use backend::IdentityBackend;
+
+        for mut yubikey in remote::RemoteYubiKey::open_all(&config)? {
+            if flags
+                .serial
+                .map_or(false, |serial| yubikey.serial() != serial)
+            {
+                continue;
+            }
+            if let Some((stub, r, metadata)) = yubikey
+                .identities(&util::identity_slots(), all)
+                .into_iter()
+                .find(|(stub, r, _)| matches(stub, r))
+            {
+                printer(stub, r, metadata);
+                return Ok(());
+            }
+        }
+        return Err(Error::RecipientNotFound(recipient));
+    }
+
+    let mut readers = Context::open()?;
+    for reader in readers.iter()?.filter(key::filter_connected) {
+        let mut yubikey = key::open_connection(&reader)?;
+        if flags
+            .serial
+            .map_or(false, |serial| yubikey.serial() != serial)
+        {
+            continue;
+        }
+
+        // As on every other path, the YubiKey is disconnected without a reset when
+        // listing it fails.
+        let found = match key::identities(&mut yubikey, all) {
+            Ok(identities) => identities.into_iter().find(|(stub, r, _)| matches(stub, r)),
+            Err(e) => {
+                key::disconnect_without_reset(yubikey);
+                return Err(e);
+            }
+        };
+        if let Some((stub, r, metadata)) = found {
+            let res = key::check_default_credentials(&mut yubikey, flags.require_nondefault_pin)
+                .map(|()| printer(stub, r, metadata));
+            key::disconnect_without_reset(yubikey);
+            return res;
+        }
+
+        key::disconnect_without_reset(yubikey);
+    }
+
+    Err(Error::RecipientNotFound(recipient))
+}
+
+/// Prints the identities that [`print_details`] would find as a single JSON array.
+fn print_json(flags: PluginFlags, all: bool) -> Result<(), Error> {
+    let identities = RefCell::new(vec![]);
+    print_details("", flags, all, |stub, recipient, metadata| {
+        identities
+            .borrow_mut()
+            .push(util::IdentityJson::new(&stub, &recipient, metadata))
+    })?;
+    println!(
+        "{}",
+        serde_json::to_string_pretty(&identities.into_inner())
+            .expect("IdentityJson always serializes")
+    );
+    Ok(())
+}
+
+/// Prints the PKCS #11 URI of each key, one per line.
+fn print_pkcs11_uris(mut flags: PluginFlags, all: bool) -> Result<(), Error> {
+    // Like JSON, the URIs are for programs, so they are printed without the blank lines
+    // and count meant for people.
+    flags.json = true;
+    print_details("", flags, all, |_, _, metadata| {
+        println!("{}", metadata.to_pkcs11_uri())
+    })
+}
+
+fn identity(flags: PluginFlags, all: bool) -> Result<(), Error> {
+    if flags.force {
+        return Err(Error::InvalidFlagCommand(
+            "--force".into(),
+            "--identity".into(),
+        ));
+    }
+    if flags.json {
+        return print_json(flags, all);
+    }
+    if all {
+        return print_identity_file(flags);
+    }
+    print_details(
+        &fl!("printed-kind-identities"),
+        flags,
+        false,
+        util::print_identity,
+    )
+}
+
+/// Prints the identities for all compatible keys as a single identity file.
+fn print_identity_file(mut flags: PluginFlags) -> Result<(), Error> {
+    if flags.slot.is_some() {
+        return Err(Error::InvalidFlagCommand(
+            "--slot".into(),
+            "--identity --all".into(),
+        ));
+    }
+
+    // Collect the identities as for --json, which doesn't print anything in between.
+    flags.json = true;
+    let identities = RefCell::new(vec![]);
+    print_details("", flags, true, |stub, recipient, metadata| {
+        identities
+            .borrow_mut()
+            .push(util::format_identity(&stub, &recipient, &metadata))
+    })?;
+
+    println!(
+        "{}",
+        fl!("identity-file-header", version = env!("CARGO_PKG_VERSION"))
+    );
+    for identity in identities.into_inner() {
+        println!();
+        println!("{identity}");
+    }
+
+    Ok(())
+}
+
+/// Parses the key stubs in `args`, each of which is either an identity or the path to an
+/// identity file.
+fn read_stubs(args: Vec<String>) -> Result<Vec<key::Stub>, Error> {
+    let mut stubs = vec![];
+    for arg in args {
+        let lines = if arg.to_lowercase().starts_with(IDENTITY_PREFIX) {
+            vec![arg]
+        } else {
+            std::fs::read_to_string(arg)?
+                .lines()
+                .map(|line| line.trim().to_owned())
+                .filter(|line| line.to_lowercase().starts_with(IDENTITY_PREFIX))
+                .collect()
+        };
+        for line in lines {
+            stubs.push(key::Stub::decode(&line).ok_or(Error::InvalidIdentity(line))?);
+        }
+    }
+    Ok(stubs)
+}
+
+fn info(flags: PluginFlags) -> Result<(), Error> {
+    for (set, flag) in [(flags.slot.is_some(), "--slot"), (flags.force, "--force")] {
+        if set {
+            return Err(Error::InvalidFlagCommand(flag.into(), "--info".into()));
+        }
+    }
+
+    let yubikey = key::open(flags.serial)?;
+    let info = capabilities::Info::probe(&yubikey);
+    key::disconnect_without_reset(yubikey);
+
+    if flags.json {
+        println!(
+            "{}",
+            serde_json::to_string_pretty(&info.to_json()).expect("InfoJson always serializes")
+        );
+        return Ok(());
+    }
+
+    let yes_no = |b: bool| if b { "yes" } else { "no" };
+    let algorithms = info.capabilities.algorithms().join(", ");
+    println!(
+        "{}",
+        fl!(
+            "info-yubikey",
+            serial = info.serial.to_string(),
+            firmware = info.firmware.to_string(),
+        )
+    );
+    println!("{}", fl!("info-algorithms", algorithms = algorithms));
+    println!(
+        "{}",
+        fl!(
+            "info-aes-mgmt-key",
+            supported = yes_no(info.capabilities.aes_mgmt_key),
+        )
+    );
+    match info.device {
+        Some(device) => {
+            println!(
+                "{}",
+                fl!("info-applets", applets = device.applets.join(", "))
+            );
+            println!("{}", fl!("info-fips", fips = yes_no(device.fips)));
+            println!(
+                "{}",
+                fl!(
+                    "info-pin-complexity",
+                    enforced = yes_no(device.pin_complexity),
+                )
+            );
+        }
+        None => println!("{}", fl!("info-no-device-info")),
+    }
+    Ok(())
+}
+
+fn verify(
+    flags: PluginFlags,
+    identities: Vec<String>,
+    ca_file: Option<String>,
+) -> Result<(), Error> {
+    if flags.force {
+        return Err(Error::InvalidFlagCommand(
+            "--force".into(),
+            "--verify".into(),
+        ));
+    }
+    if flags.json {
+        return Err(Error::InvalidFlagCommand(
+            "--json".into(),
+            "--verify".into(),
+        ));
+    }
+    let slot = flags
+        .slot
+        .ok_or_else(|| Error::CommandNeedsSlot("--verify".into()))?;
+    let stubs = read_stubs(identities)?;
+    let ca = ca_file
+        .map(|path| {
+            attest::parse_ca(&std::fs::read_to_string(&path)?)
+                .ok_or(Error::InvalidAttestationCa(path))
+        })
+        .transpose()?;
+
+    let mut yubikey = key::open(flags.serial)?;
+
+    let (key, _, recipient) = key::list_compatible(&mut yubikey)?
+        .find(|(_, s, _)| s == &slot)
+        .ok_or(Error::SlotHasNoIdentity(slot))?;
+    let metadata = util::Metadata::extract(&mut yubikey, slot, key.certificate(), true);
+
+    let method = match key::check_slot_key(&mut yubikey, slot, &recipient, metadata.as_ref())? {
+        key::KeyCheck::Attestation => "attestation",
+        key::KeyCheck::Ecdh => "ecdh",
+        key::KeyCheck::Decryption => "decryption",
+    };
+    println!(
+        "{}",
+        fl!(
+            "verify-key-matches-cert",
+            slot = util::slot_name(slot),
+            method = method,
+        )
+    );
+
+    let expected = key::Stub::new(yubikey.serial(), slot, &recipient);
+    if stubs.iter().any(|stub| stub != &expected) {
+        return Err(Error::StubMismatch(slot));
+    }
+    if !stubs.is_empty() {
+        println!(
+            "{}",
+            fl!(
+                "verify-stubs-match",
+                count = stubs.len(),
+                slot = util::slot_name(slot),
+            )
+        );
+    }
+
+    // The remaining checks report every mismatch they find before failing.
+    let mut healthy = true;
+    let attestation = attest::Attestation::read(&mut yubikey, slot)
+        .ok()
+        .and_then(|attestation| {
+            let summary = attestation.summarize(Some(&recipient)).ok()?;
+            Some((attestation, summary))
+        });
+
+    // The policies in our certificate must be those the YubiKey enforces, which it
+    // tells us in an attestation, or in the slot's metadata.
+    let (cert_pin_policy, cert_touch_policy) =
+        x509_parser::parse_x509_certificate(key.certificate().as_ref())
+            .map(|(_, cert)| util::extract_policies(&cert))
+            .unwrap_or((None, None));
+    let slot_policies = match &attestation {
+        Some((_, summary)) => summary.pin_policy.zip(summary.touch_policy),
+        None => yubikey::piv::metadata(&mut yubikey, slot)
+            .ok()
+            .and_then(|metadata| metadata.policy),
+    };
+    match (cert_pin_policy.zip(cert_touch_policy), slot_policies) {
+        (None, _) => println!("{}", fl!("verify-policies-no-extension")),
+        (Some(_), None) => println!("{}", fl!("verify-policies-unknown")),
+        (Some(cert), Some(actual)) if cert == actual => {
+            println!("{}", fl!("verify-policies-match"))
+        }
+        (Some((cert_pin, cert_touch)), Some((pin, touch))) => {
+            healthy = false;
+            println!(
+                "{}",
+                fl!(
+                    "verify-policies-mismatch",
+                    cert_pin_policy = util::pin_policy_to_str(Some(cert_pin)),
+                    cert_touch_policy = util::touch_policy_to_str(Some(cert_touch)),
+                    pin_policy = util::pin_policy_to_str(Some(pin)),
+                    touch_policy = util::touch_policy_to_str(Some(touch)),
+                )
+            );
+        }
+    }
+
+    // The attestation must come from this YubiKey, and chain up to Yubico's CA, or the
+    // key may have been provisioned somewhere else.
+    match &attestation {
+        None => println!("{}", fl!("verify-no-attestation")),
+        Some((attestation, summary)) => {
+            match summary.serial {
+                Some(serial) if serial != yubikey.serial().0 => {
+                    healthy = false;
+                    println!(
+                        "{}",
+                        fl!(
+                            "verify-attested-serial-mismatch",
+                            serial = serial.to_string(),
+                            yubikey_serial = yubikey.serial().to_string(),
+                        )
+                    );
+                }
+                _ => (),
+            }
+            if !summary.issued_by_yubikey {
+                healthy = false;
+                println!("{}", fl!("attest-issuer-mismatch"));
+            }
+            match &ca {
+                Some(ca) if attestation.chains_to(ca) => {
+                    println!("{}", fl!("verify-chain-ok"))
+                }
+                Some(_) => {
+                    healthy = false;
+                    println!("{}", fl!("verify-chain-broken"));
+                }
+                None => println!("{}", fl!("verify-chain-no-ca")),
+            }
+        }
+    }
+
+    key::disconnect_without_reset(yubikey);
+
+    if healthy {
+        Ok(())
+    } else {
+        Err(Error::VerificationFailed(slot))
+    }
+}
+
+fn list(flags: PluginFlags, all: bool, pkcs11_uri: bool) -> Result<(), Error> {
+    if all && flags.slot.is_some() {
+        return Err(Error::UseListForSingleSlot);
+    }
+    if flags.force {
+        return Err(Error::InvalidFlagCommand(
+            "--force".into(),
+            format!("--list{}", if all { "-all" } else { "" }),
+        ));
+    }
+    if pkcs11_uri {
+        if flags.json {
+            return Err(Error::InvalidFlagCommand(
+                "--json".into(),
+                "--format pkcs11-uri".into(),
+            ));
+        }
+        return print_pkcs11_uris(flags, all);
+    }
+    if flags.json {
+        return print_json(flags, all);
+    }
+
+    print_details(
+        &fl!("printed-kind-recipients"),
+        flags,
+        all,
+        |_, recipient, metadata| {
+            println!("{metadata}");
+            println!("{recipient}");
+        },
+    )
+}
+
+/// Lists the daemons advertised on the local network, with the address to give
+/// `--remote` and the serials of their YubiKeys.
+#[cfg(feature = "remote")]
+fn discover() -> Result<(), Error> {
+    let servers = remote::discover()?;
+    if servers.is_empty() {
+        eprintln!("{}", fl!("discover-none"));
+    }
+    for server in servers {
+        let Some(address) = server.addresses.first().map(|a| a.to_string()) else {
+            continue;
+        };
+        if server.tls {
+            println!(
+                "{}",
+                fl!("discover-server-tls", name = server.name, address = address)
+            );
+        } else {
+            println!(
+                "{}",
+                fl!("discover-server", name = server.name, address = address)
+            );
+        }
+        if !server.serials.is_empty() {
+            println!(
+                "{}",
+                fl!("discover-serials", serials = server.serials.join(", "))
+            );
+        }
+    }
+    Ok(())
+}
+
+#[cfg(not(feature = "remote"))]
+fn discover() -> Result<(), Error> {
+    Err(Error::RemoteNotBuilt)
+}
+
+fn export_recipients(flags: PluginFlags, path: String) -> Result<(), Error> {
+    for (set, flag) in [(flags.slot.is_some(), "--slot"), (flags.json, "--json")] {
+        if set {
+            return Err(Error::InvalidFlagCommand(
+                flag.into(),
+                "--export-recipients".into(),
+            ));
+        }
+    }
+
+    let mut yubikey = key::open(flags.serial)?;
+    key::check_default_credentials(&mut yubikey, flags.require_nondefault_pin)?;
+    let identities = key::identities(&mut yubikey, true)?;
+    if identities.is_empty() {
+        return Err(Error::NoIdentities(yubikey.serial()));
+    }
+
+    let mut contents = fl!(
+        "export-recipients-header",
+        serial = yubikey.serial().to_string(),
+        version = env!("CARGO_PKG_VERSION"),
+    );
+    for (_, recipient, metadata) in &identities {
+        contents += &format!("\n\n{metadata}\n{recipient}");
+    }
+    contents.push('\n');
+
+    create_file(&path, flags.force)?.write_all(contents.as_bytes())?;
+
+    eprintln!(
+        "{}",
+        fl!(
+            "export-recipients-done",
+            count = identities.len(),
+            path = path.as_str(),
+        )
+    );
+
+    key::disconnect_without_reset(yubikey);
+
+    Ok(())
+}
+
+/// Creates the file at `path`, which must not exist yet unless `force` is set.
+fn create_file(path: &str, force: bool) -> Result<File, Error> {
+    match OpenOptions::new()
+        .create_new(!force)
+        .create(true)
+        .truncate(true)
+        .write(true)
+        .open(path)
+    {
+        Ok(file) => Ok(file),
+        Err(e) if e.kind() == io::ErrorKind::AlreadyExists => Err(Error::FileExists(path.into())),
+        Err(e) => Err(e.into()),
+    }
+}
+
+fn export_stubs(mut flags: PluginFlags, path: String) -> Result<(), Error> {
+    if flags.json {
+        return Err(Error::InvalidFlagCommand(
+            "--json".into(),
+            "--export-stubs".into(),
+        ));
+    }
+    let force = flags.force;
+
+    // Collect the identities as for --json, which doesn't print anything in between.
+    flags.json = true;
+    let escrow = RefCell::new(escrow::Escrow::new());
+    print_details("", flags, true, |stub, recipient, metadata| {
+        escrow.borrow_mut().push(&stub, &recipient, &metadata)
+    })?;
+    let escrow = escrow.into_inner();
+
+    create_file(&path, force)?.write_all(format!("{}\n", escrow.to_json()).as_bytes())?;
+
+    eprintln!(
+        "{}",
+        fl!(
+            "export-stubs-done",
+            count = escrow.len(),
+            path = path.as_str(),
+        )
+    );
+
+    Ok(())
+}
+
+fn import_stubs(flags: PluginFlags, path: String) -> Result<(), Error> {
+    for (set, flag) in [
+        (flags.serial.is_some(), "--serial"),
+        (flags.slot.is_some(), "--slot"),
+        (flags.force, "--force"),
+        (flags.json, "--json"),
+    ] {
+        if set {
+            return Err(Error::InvalidFlagCommand(
+                flag.into(),
+                "--import-stubs".into(),
+            ));
+        }
+    }
+    let identities = escrow::Escrow::parse(&path, &std::fs::read_to_string(&path)?)?;
+
+    println!(
+        "{}",
+        fl!(
+            "import-stubs-header",
+            path = path.as_str(),
+            version = env!("CARGO_PKG_VERSION"),
+        )
+    );
+    for (stub, recipient, metadata) in identities {
+        println!();
+        println!("{}", util::format_identity(&stub, &recipient, &metadata));
+    }
+
+    Ok(())
+}
+
+fn attest(flags: PluginFlags) -> Result<(), Error> {
+    if flags.force {
+        return Err(Error::InvalidFlagCommand(
+            "--force".into(),
+            "--attest".into(),
+        ));
+    }
+    let slot = flags
+        .slot
+        .ok_or_else(|| Error::CommandNeedsSlot("--attest".into()))?;
+
+    let mut yubikey = key::open(flags.serial)?;
+
+    let attestation = attest::Attestation::read(&mut yubikey, slot)?;
+    let recipient = key::list_compatible(&mut yubikey)?
+        .find(|(_, s, _)| s == &slot)
+        .map(|(_, _, recipient)| recipient);
+
+    eprintln!("{}", attestation.summarize(recipient.as_ref())?);
+    print!("{}", attestation.to_pem());
+
+    key::disconnect_without_reset(yubikey);
+
+    Ok(())
+}
+
+fn delete(flags: PluginFlags) -> Result<(), Error> {
+    if flags.json {
+        return Err(Error::InvalidFlagCommand(
+            "--json".into(),
+            "--delete".into(),
+        ));
+    }
+    let slot = flags
+        .slot
+        .ok_or_else(|| Error::CommandNeedsSlot("--delete".into()))?;
+
+    let mut yubikey = key::open(flags.serial)?;
+
+    let (key, _, recipient) = key::list_slots(&mut yubikey)?
+        .find(|(_, s, _)| s == &slot)
+        .ok_or(Error::SlotHasNoIdentity(slot))?;
+    if let Some(metadata) = util::Metadata::extract(&mut yubikey, slot, key.certificate(), true) {
+        eprintln!("{metadata}");
+    }
+    if let Some(recipient) = &recipient {
+        eprintln!(
+            "{}",
+            fl!("print-recipient", recipient = recipient.to_string())
+        );
+    }
+
+    if !flags.force
+        && !Confirm::new()
+            .with_prompt(fl!("delete-confirm", slot = util::slot_name(slot)))
+            .default(false)
+            .report(true)
+            .interact()?
+    {
+        key::disconnect_without_reset(yubikey);
+        return Ok(());
+    }
+
+    key::manage(&mut yubikey, flags.mgmt_key, false)?;
+    key::delete_slot(&mut yubikey, slot)?;
+    eprintln!("{}", fl!("delete-done", slot = util::slot_name(slot)));
+
+    key::finish_after_mgmt_auth(yubikey);
+
+    Ok(())
+}
+
+fn rename(flags: PluginFlags) -> Result<(), Error> {
+    for (set, flag) in [(flags.force, "--force"), (flags.json, "--json")] {
+        if set {
+            return Err(Error::InvalidFlagCommand(flag.into(), "--rename".into()));
+        }
+    }
+    let slot = flags
+        .slot
+        .ok_or_else(|| Error::CommandNeedsSlot("--rename".into()))?;
+    let name = flags.name.ok_or(Error::RenameNeedsName)?;
+
+    let mut yubikey = key::open(flags.serial)?;
+
+    let (stub, recipient, metadata) = builder::rename(&mut yubikey, slot, &name, flags.mgmt_key)?;
+
+    util::print_identity(stub, recipient, metadata);
+
+    key::finish_after_mgmt_auth(yubikey);
+
+    Ok(())
+}
+
+fn repair(flags: PluginFlags) -> Result<(), Error> {
+    if flags.json {
+        return Err(Error::InvalidFlagCommand(
+            "--json".into(),
+            "--repair".into(),
+        ));
+    }
+    let slot = flags
+        .slot
+        .ok_or_else(|| Error::CommandNeedsSlot("--repair".into()))?;
+
+    let mut yubikey = key::open(flags.serial)?;
+
+    let (stub, recipient, metadata) = builder::repair(
+        &mut yubikey,
+        slot,
+        flags.name,
+        flags.curve,
+        flags.pin_policy,
+        flags.touch_policy,
+        flags.force,
+        flags.mgmt_key,
+    )?;
+
+    util::print_identity(stub, recipient, metadata);
+
+    key::finish_after_mgmt_auth(yubikey);
+
+    Ok(())
+}
+
+/// Rejects the flags that don't apply to `command`, which changes a YubiKey's PIN, PUK
+/// or management key.
+fn check_credential_flags(flags: &PluginFlags, command: &str) -> Result<(), Error> {
+    let mgmt = command == "--change-mgmt-key";
+    for (set, flag) in [
+        (flags.slot.is_some(), "--slot"),
+        (flags.name.is_some(), "--name"),
+        (flags.pin_policy.is_some(), "--pin-policy"),
+        (flags.touch_policy.is_some(), "--touch-policy"),
+        (flags.curve.is_some(), "--algorithm"),
+        (flags.force, "--force"),
+        (flags.json, "--json"),
+        (flags.mgmt_key.is_some() && !mgmt, "--mgmt-key-fd"),
+        (flags.rotate_mgmt_key, "--rotate-mgmt-key"),
+        (flags.protect_mgmt_key && !mgmt, "--protect-mgmt-key"),
+    ] {
+        if set {
+            return Err(Error::InvalidFlagCommand(flag.into(), command.into()));
+        }
+    }
+    Ok(())
+}
+
+fn change_pin(flags: PluginFlags) -> Result<(), Error> {
+    check_credential_flags(&flags, "--change-pin")?;
+    let mut yubikey = key::open(flags.serial)?;
+    key::change_pin(&mut yubikey)?;
+    eprintln!("{}", fl!("mgr-changed-pin"));
+    Ok(())
+}
+
+fn change_puk(flags: PluginFlags) -> Result<(), Error> {
+    check_credential_flags(&flags, "--change-puk")?;
+    let mut yubikey = key::open(flags.serial)?;
+    key::change_puk(&mut yubikey)?;
+    eprintln!("{}", fl!("mgr-changed-puk"));
+    Ok(())
+}
+
+fn change_mgmt_key(flags: PluginFlags) -> Result<(), Error> {
+    check_credential_flags(&flags, "--change-mgmt-key")?;
+    let mut yubikey = key::open(flags.serial)?;
+    key::change_mgmt_key(&mut yubikey, flags.mgmt_key, flags.protect_mgmt_key)?;
+    eprintln!("{}", fl!("mgr-changed-mgmt-key"));
+
+    key::finish_after_mgmt_auth(yubikey);
+
+    Ok(())
+}
+
+fn init_card(flags: PluginFlags) -> Result<(), Error> {
+    for (set, flag) in [
+        (flags.slot.is_some(), "--slot"),
+        (flags.name.is_some(), "--name"),
+        (flags.pin_policy.is_some(), "--pin-policy"),
+        (flags.touch_policy.is_some(), "--touch-policy"),
+        (flags.curve.is_some(), "--algorithm"),
+        (flags.force, "--force"),
+        (flags.json, "--json"),
+        (flags.protect_mgmt_key, "--protect-mgmt-key"),
+    ] {
+        if set {
+            return Err(Error::InvalidFlagCommand(flag.into(), "--init-card".into()));
+        }
+    }
+
+    let mut yubikey = key::open(flags.serial)?;
+    key::manage(&mut yubikey, flags.mgmt_key, flags.rotate_mgmt_key)?;
+    match builder::init_card(&mut yubikey)? {
+        (false, false) => eprintln!("{}", fl!("mgr-card-initialized")),
+        (chuid, ccc) => {
+            if chuid {
+                eprintln!("{}", fl!("mgr-wrote-chuid"));
+            }
+            if ccc {
+                eprintln!("{}", fl!("mgr-wrote-ccc"));
+            }
+        }
+    }
+
+    key::finish_after_mgmt_auth(yubikey);
+
+    Ok(())
+}
+
+fn ssh_agent(flags: PluginFlags) -> Result<(), Error> {
+    for (set, flag) in [
+        (flags.slot.is_some(), "--slot"),
+        (flags.force, "--force"),
+        (flags.json, "--json"),
+    ] {
+        if set {
+            return Err(Error::InvalidFlagCommand(flag.into(), "--ssh-agent".into()));
+        }
+    }
+
+    #[cfg(unix)]
+    return ssh_agent::run(flags.serial);
+    // Named pipes would need an async runtime, which only the `remote` feature has.
+    #[cfg(not(unix))]
+    Err(Error::SshAgentNotSupported)
+}
+
+fn unblock_pin(flags: PluginFlags) -> Result<(), Error> {
+    check_credential_flags(&flags, "--unblock-pin")?;
+    let mut yubikey = key::open(flags.serial)?;
+    key::unblock_pin(&mut yubikey)?;
+    eprintln!("{}", fl!("mgr-unblocked-pin"));
+
+    Ok(())
+}
+
+fn interactive(flags: PluginFlags) -> Result<(), Error> {
+    for (set, flag) in [
+        (flags.slot.is_some(), "--slot"),
+        (flags.name.is_some(), "--name"),
+        (flags.pin_policy.is_some(), "--pin-policy"),
+        (flags.touch_policy.is_some(), "--touch-policy"),
+        (flags.force, "--force"),
+        (flags.json, "--json"),
+        (flags.recipient.is_some(), "--recipient"),
+        (flags.rotate_mgmt_key, "--rotate-mgmt-key"),
+        (flags.protect_mgmt_key, "--protect-mgmt-key"),
+    ] {
+        if set {
+            return Err(Error::InvalidFlagCommand(
+                flag.into(),
+                "--interactive".into(),
+            ));
+        }
+    }
+
+    interactive::run(
+        flags.serial,
+        interactive::Settings {
+            curve: flags.curve,
+            mgmt_key: flags.mgmt_key,
+        },
+    )
+}
+
+fn provision(flags: PluginFlags, config: String) -> Result<(), Error> {
+    for (set, flag) in [
+        (flags.slot.is_some(), "--slot"),
+        (flags.name.is_some(), "--name"),
+        (flags.pin_policy.is_some(), "--pin-policy"),
+        (flags.touch_policy.is_some(), "--touch-policy"),
+        (flags.curve.is_some(), "--algorithm"),
+        (flags.force, "--force"),
+        (flags.json, "--json"),
+        (flags.mgmt_key.is_some(), "--mgmt-key-fd"),
+        (flags.rotate_mgmt_key, "--rotate-mgmt-key"),
+    ] {
+        if set {
+            return Err(Error::InvalidFlagCommand(flag.into(), "--provision".into()));
+        }
+    }
+
+    let spec = provision::Spec::parse(&std::fs::read_to_string(config)?)?;
+    match provision::run(&spec, flags.serial)? {
+        0 => Ok(()),
+        failed => Err(Error::ProvisionFailed(failed)),
+    }
+}
+
+/// Logs to stderr as `--log-level` (or `RUST_LOG`) and `--log-format` say, with
+/// how long the spans of PIV operations, stanzas and remote APDUs took.
+fn init_logging(opts: &PluginOptions) -> Result<(), Error> {
+    let filter = match &opts.log_level {
+        Some(level) => {
+            EnvFilter::try_new(level).map_err(|_| Error::InvalidLogLevel(level.clone()))?
+        }
+        None => EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("off")),
+    };
+    let logs = tracing_subscriber::fmt()
+        .without_time()
+        .with_env_filter(filter)
+        .with_span_events(FmtSpan::CLOSE)
+        .with_writer(std::io::stderr);
+    match opts.log_format.as_deref() {
+        None | Some("text") => logs.init(),
+        Some("json") => logs.json().init(),
+        Some(format) => return Err(Error::InvalidLogFormat(format.into())),
+    }
+    Ok(())
+}
+
+fn main() -> Result<(), Error> {
+    let opts = PluginOptions::parse_args_default_or_exit();
+    init_logging(&opts)?;
+    error::VERBOSE.store(opts.verbose, std::sync::atomic::Ordering::Relaxed);
+
+    let json_errors = match opts.error_format.as_deref() {
+        None | Some("text") => false,
+        Some("json") => true,
+        Some(format) => return Err(Error::InvalidErrorFormat(format.into())),
+    };
+
+    match run(opts) {
+        Err(e) if json_errors => {
+            eprintln!("{}", e.to_json());
+            process::exit(1);
+        }
+        res => res,
+    }
+}
+
+fn run(mut opts: PluginOptions) -> Result<(), Error> {
+    if [
+        opts.attest,
+        opts.change_mgmt_key,
+        opts.change_pin,
+        opts.change_puk,
+        opts.config,
+        opts.delete,
+        opts.discover,
+        opts.export_recipients.is_some(),
+        opts.export_stubs.is_some(),
+        opts.forget_pins,
+        opts.generate,
+        opts.identity,
+        opts.import,
+        opts.import_stubs.is_some(),
+        opts.info,
+        opts.init_card,
+        opts.interactive,
+        opts.list,
+        opts.list_all,
+        opts.provision.is_some(),
+        opts.recipient_from.is_some(),
+        opts.rename,
+        opts.repair,
+        opts.ssh_agent,
+        opts.unblock_pin,
+        opts.verify,
+    ]
+    .iter()
+    .filter(|&&b| b)
+    .count()
         > 1
     {
         return Err(Error::MultipleCommands);
     }
 
+    if opts.all && !opts.identity {
+        return Err(Error::AllNeedsIdentity);
+    }
+    let pkcs11_uri = match opts.format.take().as_deref() {
+        None | Some("text") => false,
+        Some("pkcs11-uri") => true,
+        Some(format) => return Err(Error::InvalidListFormat(format.into())),
+    };
+    if pkcs11_uri && !(opts.list || opts.list_all) {
+        return Err(Error::FormatNeedsList);
+    }
+
+    let args = std::mem::take(&mut opts.args);
+    let max_args = if opts.verify {
+        usize::MAX
+    } else if opts.config {
+        2
+    } else {
+        0
+    };
+    if let Some(arg) = args.get(max_args) {
+        return Err(Error::UnexpectedArgument(arg.clone()));
+    }
+
+    util::allow_standard_slots(opts.allow_standard_slots);
+    pin::configure(
+        opts.unattended_pin,
+        opts.pin_fd,
+        opts.pin_file.take(),
+        opts.risk_lockout,
+    )?;
+
+    // Only decryption and the commands that print identities use a remote daemon. The
+    // others work on local YubiKeys, whatever the environment or configuration file say.
+    let remote = opts.remote.take();
+    if remote.is_some() {
+        for (set, command) in [
+            (opts.attest, "--attest"),
+            (opts.change_mgmt_key, "--change-mgmt-key"),
+            (opts.change_pin, "--change-pin"),
+            (opts.change_puk, "--change-puk"),
+            (opts.config, "--config"),
+            (opts.delete, "--delete"),
+            (opts.discover, "--discover"),
+            (opts.export_recipients.is_some(), "--export-recipients"),
+            (opts.forget_pins, "--forget-pins"),
+            (opts.generate, "--generate"),
+            (opts.import, "--import"),
+            (opts.import_stubs.is_some(), "--import-stubs"),
+            (opts.info, "--info"),
+            (opts.init_card, "--init-card"),
+            (opts.interactive, "--interactive"),
+            (opts.provision.is_some(), "--provision"),
+            (opts.recipient_from.is_some(), "--recipient-from"),
+            (opts.rename, "--rename"),
+            (opts.repair, "--repair"),
+            (opts.ssh_agent, "--ssh-agent"),
+            (opts.unblock_pin, "--unblock-pin"),
+            (opts.verify, "--verify"),
+        ] {
+            if set {
+                return Err(Error::InvalidFlagCommand("--remote".into(), command.into()));
+            }
+        }
+    }
+    // Editing the configuration file mustn't need it to be valid already.
+    if opts.config {
+        return config::edit(
+            args.first().map(String::as_str),
+            args.get(1).map(String::as_str),
+        );
+    }
+    let remote_flag = remote.is_some();
+    config::configure(remote)?;
+
+    // Shared YubiKeys can be protected from being reprovisioned by mistake.
+    let read_only_config = !opts.read_only && config::defaults().read_only;
+    if opts.read_only || read_only_config {
+        for (set, command) in [
+            (opts.change_mgmt_key, "--change-mgmt-key"),
+            (opts.change_pin, "--change-pin"),
+            (opts.change_puk, "--change-puk"),
+            (opts.delete, "--delete"),
+            (opts.generate, "--generate"),
+            (opts.import, "--import"),
+            (opts.init_card, "--init-card"),
+            (opts.interactive, "--interactive"),
+            (opts.provision.is_some(), "--provision"),
+            (opts.rename, "--rename"),
+            (opts.repair, "--repair"),
+            (opts.unblock_pin, "--unblock-pin"),
+        ] {
+            if set {
+                return Err(Error::ReadOnly(command.into()));
+            }
+        }
+        opts.read_only = true;
+    }
+
+    // The configuration file can make JSON the output of the commands that have it.
+    if config::defaults().json
+        && !pkcs11_uri
+        && (opts.identity || opts.info || opts.list || opts.list_all)
+    {
+        opts.json = true;
+    }
+
     if let Some(state_machine) = opts.age_plugin {
+        cancel::exit_with_parent();
         run_state_machine(
             &state_machine,
             Some(plugin::RecipientPlugin::default),
             Some(plugin::IdentityPlugin::default),
         )?;
         Ok(())
+    } else if opts.broker {
+        #[cfg(unix)]
+        broker::run()?;
+        Ok(())
+    } else if opts.pin_agent {
+        #[cfg(unix)]
+        agent::run()?;
+        Ok(())
     } else if opts.version {
         println!("age-plugin-yubikey {}", env!("CARGO_PKG_VERSION"));
         Ok(())
+    } else if opts.attest {
+        attest(opts.try_into()?)
+    } else if opts.change_mgmt_key {
+        change_mgmt_key(opts.try_into()?)
+    } else if opts.change_pin {
+        change_pin(opts.try_into()?)
+    } else if opts.change_puk {
+        change_puk(opts.try_into()?)
+    } else if opts.delete {
+        delete(opts.try_into()?)
+    } else if opts.discover {
+        discover()
+    } else if let Some(path) = opts.export_recipients.take() {
+        export_recipients(opts.try_into()?, path)
+    } else if let Some(path) = opts.export_stubs.take() {
+        export_stubs(opts.try_into()?, path)
+    } else if opts.forget_pins {
+        pin::forget(opts.serial.map(Serial::from));
+        Ok(())
     } else if opts.generate {
         generate(opts.try_into()?)
     } else if opts.identity {
-        identity(opts.try_into()?)
+        let all = opts.all;
+        identity(opts.try_into()?, all)
+    } else if opts.import {
+        let key_file = opts.key.take();
+        import(opts.try_into()?, key_file)
+    } else if let Some(path) = opts.import_stubs.take() {
+        import_stubs(opts.try_into()?, path)
+    } else if opts.info {
+        info(opts.try_into()?)
+    } else if opts.init_card {
+        init_card(opts.try_into()?)
+    } else if opts.interactive {
+        interactive(opts.try_into()?)
     } else if opts.list {
-        list(opts.try_into()?, false)
+        list(opts.try_into()?, false, pkcs11_uri)
     } else if opts.list_all {
-        list(opts.try_into()?, true)
+        list(opts.try_into()?, true, pkcs11_uri)
+    } else if let Some(config) = opts.provision.take() {
+        provision(opts.try_into()?, config)
+    } else if opts.rename {
+        rename(opts.try_into()?)
+    } else if opts.repair {
+        repair(opts.try_into()?)
+    } else if opts.ssh_agent {
+        ssh_agent(opts.try_into()?)
+    } else if opts.unblock_pin {
+        unblock_pin(opts.try_into()?)
+    } else if opts.verify {
+        let ca_file = opts.attestation_ca.take();
+        verify(opts.try_into()?, args, ca_file)
+    } else if let Some(public_key) = opts.recipient_from {
+        let recipient =
+            p256::Recipient::from_public_key(&public_key).ok_or(Error::InvalidPublicKey)?;
+        println!("{recipient}");
+        Ok(())
     } else {
         if opts.force {
             return Err(Error::InvalidFlagTui("--force".into()));
         }
+        if opts.json {
+            return Err(Error::InvalidFlagTui("--json".into()));
+        }
+        if remote_flag {
+            return Err(Error::InvalidFlagTui("--remote".into()));
+        }
+        if read_only_config {
+            return Err(Error::ReadOnlyTui);
+        }
+        if opts.read_only {
+            return Err(Error::InvalidFlagTui("--read-only".into()));
+        }
         let flags: PluginFlags = opts.try_into()?;
 
         eprintln!(
@@ -367,28 +1826,8 @@ fn main() -> Result<(), Error> {
         // Filter out readers we can't connect to.
         let readers_list: Vec<_> = readers.iter()?.filter(key::filter_connected).collect();
 
-        let reader_names = readers_list
-            .iter()
-            .map(|reader| {
-                key::open_connection(reader).map(|yk| {
-                    let name = fl!(
-                        "cli-setup-yk-name",
-                        yubikey_name = reader.name(),
-                        yubikey_serial = yk.serial().to_string(),
-                    );
-                    key::disconnect_without_reset(yk);
-                    name
-                })
-            })
-            .collect::<Result<Vec<_>, _>>()?;
-        let mut yubikey = match Select::new()
-            .with_prompt(fl!("cli-setup-select-yk"))
-            .items(&reader_names)
-            .default(0)
-            .report(true)
-            .interact_opt()?
-        {
-            Some(yk) => readers_list[yk].open()?,
+        let mut yubikey = match key::select(&readers_list)? {
+            Some(yk) => yk,
             None => return Ok(()),
         };
 
@@ -399,7 +1838,7 @@ fn main() -> Result<(), Error> {
             .iter()
             .map(|&slot| {
                 keys.iter()
-                    .find(|(_, s, _)| s == &slot)
+                    .find(|(_, s, _)| s == &SlotId::Retired(slot))
                     .map(|(key, _, recipient)| {
                         recipient.as_ref().map(|_| {
                             // Cache the details we need to display to the user.
@@ -457,7 +1896,10 @@ fn main() -> Result<(), Error> {
                 }
             };
 
-            if let Some((key, _, recipient)) = keys.into_iter().find(|(_, s, _)| s == &slot) {
+            if let Some((key, _, recipient)) = keys
+                .into_iter()
+                .find(|(_, s, _)| s == &SlotId::Retired(slot))
+            {
                 let recipient = recipient.expect("We checked this above");
 
                 if Confirm::new()
@@ -465,6 +1907,7 @@ fn main() -> Result<(), Error> {
                     .report(true)
                     .interact()?
                 {
+                    let slot = SlotId::Retired(slot);
                     let stub = key::Stub::new(yubikey.serial(), slot, &recipient);
                     let metadata =
                         util::Metadata::extract(&mut yubikey, slot, key.certificate(), true)
@@ -576,6 +2019,9 @@ fn main() -> Result<(), Error> {
                             })
                             .with_pin_policy(Some(pin_policy))
                             .with_touch_policy(Some(touch_policy))
+                            .with_curve(flags.curve)
+                            .with_mgmt_key(flags.mgmt_key)
+                            .rotate_mgmt_key(flags.rotate_mgmt_key)
                             .build(&mut yubikey)?,
                         true,
                     )
@@ -636,13 +2082,13 @@ fn main() -> Result<(), Error> {
         let identity_usage = format!(
             "$ age-plugin-yubikey -i --serial {} --slot {} > {}",
             stub.serial,
-            util::slot_to_ui(&stub.slot),
+            util::slot_name(stub.slot),
             file_name,
         );
         let recipient_usage = format!(
             "$ age-plugin-yubikey -l --serial {} --slot {}",
             stub.serial,
-            util::slot_to_ui(&stub.slot),
+            util::slot_name(stub.slot),
         );
 
         eprintln!();
@@ -0,0 +1,181 @@
+//! Non-interactive PIN entry.
+//!
+//! Headless provisioning and unattended decryption have nobody to answer a PIN prompt.
+//! The PIN can instead be read from a file, a file descriptor, or the `AGE_YUBIKEY_PIN`
+//! environment variable. PINs supplied this way leak more easily than typed ones (into
+//! shell history, process environments, or backups), so they are only used once the
+//! user has opted in with `--unattended-pin`, or with `AGE_YUBIKEY_UNATTENDED_PIN=1`
+//! when the plugin is started by an age client and cannot be given flags.
+//!
+//! Three wrong PINs in a row block the PIN, so before a PIN is tried we check how many
+//! tries are left, and refuse to use the last one unless the user has accepted the risk
+//! with `--risk-lockout` (or `AGE_YUBIKEY_RISK_LOCKOUT=1`).
+
+use std::env;
+use std::fs;
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Mutex,
+};
+
+use age_core::secrecy::{ExposeSecret, SecretString};
+use lazy_static::lazy_static;
+use tracing::warn;
+
+use yubikey::Serial;
+
+#[cfg(unix)]
+use crate::{agent, config};
+use crate::{
+    error::Error,
+    fl,
+    util::{self, LockedSecret},
+};
+
+const PIN_ENV: &str = "AGE_YUBIKEY_PIN";
+const PIN_FILE_ENV: &str = "AGE_YUBIKEY_PIN_FILE";
+const PIN_FD_ENV: &str = "AGE_YUBIKEY_PIN_FD";
+const ALLOW_ENV: &str = "AGE_YUBIKEY_UNATTENDED_PIN";
+const RISK_LOCKOUT_ENV: &str = "AGE_YUBIKEY_RISK_LOCKOUT";
+
+/// Whether a PIN may be tried when only one try is left.
+static RISK_LOCKOUT: AtomicBool = AtomicBool::new(false);
+
+lazy_static! {
+    static ref UNATTENDED_PIN: Mutex<Option<SecretString>> = Mutex::new(None);
+}
+
+/// Reads the unattended PIN, if the user has opted in to providing one, and whether the
+/// last PIN try may be used.
+///
+/// `allow`, `fd`, `file` and `risk_lockout` come from the command line, and take
+/// precedence over the environment. The PIN is read once, so that a file descriptor can
+/// be used for every PIN entry in this process.
+pub(crate) fn configure(
+    allow: bool,
+    fd: Option<u32>,
+    file: Option<String>,
+    risk_lockout: bool,
+) -> Result<(), Error> {
+    let risk_lockout = risk_lockout || env::var(RISK_LOCKOUT_ENV).map_or(false, |v| v == "1");
+    RISK_LOCKOUT.store(risk_lockout, Ordering::Relaxed);
+
+    if !allow && (fd.is_some() || file.is_some()) {
+        return Err(Error::UnattendedPinNotAllowed);
+    }
+
+    let allow = allow || env::var(ALLOW_ENV).map_or(false, |v| v == "1");
+    if !allow {
+        if [PIN_ENV, PIN_FILE_ENV, PIN_FD_ENV]
+            .iter()
+            .any(|var| env::var_os(var).is_some())
+        {
+            warn!("{}", fl!("warn-unattended-pin-ignored", env = ALLOW_ENV));
+        }
+        return Ok(());
+    }
+
+    let fd = match fd {
+        Some(fd) => Some(fd),
+        None => env::var(PIN_FD_ENV)
+            .ok()
+            .map(|fd| fd.parse().map_err(|_| Error::InvalidUnattendedPin))
+            .transpose()?,
+    };
+    let file = file.or_else(|| env::var(PIN_FILE_ENV).ok());
+
+    let mut pin = match (fd, file) {
+        (Some(fd), _) => util::read_fd(fd)?,
+        (None, Some(path)) => fs::read_to_string(path)?,
+        (None, None) => match env::var(PIN_ENV) {
+            Ok(pin) => pin,
+            Err(_) => return Ok(()),
+        },
+    };
+    // Don't pass the PIN on to processes we start, such as the connection broker.
+    env::remove_var(PIN_ENV);
+
+    // Files and pipes usually end with a newline that isn't part of the PIN.
+    pin.truncate(pin.trim_end_matches(['\r', '\n']).len());
+    let pin = SecretString::new(pin);
+    if !(6..=8).contains(&pin.expose_secret().len()) {
+        return Err(Error::InvalidUnattendedPin);
+    }
+
+    *UNATTENDED_PIN.lock().unwrap() = Some(pin);
+    Ok(())
+}
+
+/// Checks that a PIN may be tried on the YubiKey with this serial, which has `tries` PIN
+/// tries left.
+pub(crate) fn check_tries(serial: Serial, tries: u8) -> Result<(), Error> {
+    match tries {
+        0 => Err(Error::YubiKey(yubikey::Error::PinLocked)),
+        1 if !RISK_LOCKOUT.load(Ordering::Relaxed) => Err(Error::FinalPinTry(serial)),
+        _ => Ok(()),
+    }
+}
+
+/// Like [`check_tries`], for the PUK.
+pub(crate) fn check_puk_tries(serial: Serial, tries: u8) -> Result<(), Error> {
+    match tries {
+        0 => Err(Error::PukLocked),
+        1 if !RISK_LOCKOUT.load(Ordering::Relaxed) => Err(Error::FinalPukTry(serial)),
+        _ => Ok(()),
+    }
+}
+
+/// Appends the number of tries left to a PIN or PUK prompt.
+pub(crate) fn prompt_with_tries(prompt: String, tries: u8) -> String {
+    format!("{} {}", prompt, fl!("pin-tries-remaining", tries = tries))
+}
+
+/// Returns the unattended PIN, if one was configured.
+pub(crate) fn unattended() -> Option<SecretString> {
+    UNATTENDED_PIN
+        .lock()
+        .unwrap()
+        .as_ref()
+        .map(|pin| SecretString::new(pin.expose_secret().clone()))
+}
+
+/// Returns the unattended PIN if one was configured, and otherwise prompts for it.
+pub(crate) fn get_or_prompt(
+    prompt: impl FnOnce() -> dialoguer::Result<String>,
+) -> Result<LockedSecret<String>, Error> {
+    Ok(LockedSecret::new(match unattended() {
+        Some(pin) => pin.expose_secret().clone(),
+        None => prompt()?,
+    }))
+}
+
+/// Returns the PIN for this YubiKey cached by the PIN agent, if it is enabled.
+#[cfg(unix)]
+pub(crate) fn cached(serial: Serial) -> Option<SecretString> {
+    config::pin_agent_ttl().and_then(|_| agent::get(serial))
+}
+
+#[cfg(not(unix))]
+pub(crate) fn cached(_: Serial) -> Option<SecretString> {
+    None
+}
+
+/// Caches a PIN that this YubiKey accepted in the PIN agent, if it is enabled.
+#[cfg(unix)]
+pub(crate) fn remember(serial: Serial, pin: &SecretString) {
+    if let Some(ttl) = config::pin_agent_ttl() {
+        agent::put(serial, pin.expose_secret(), ttl);
+    }
+}
+
+#[cfg(not(unix))]
+pub(crate) fn remember(_: Serial, _: &SecretString) {}
+
+/// Makes the PIN agent forget the PIN for this YubiKey, or every PIN.
+#[cfg(unix)]
+pub(crate) fn forget(serial: Option<Serial>) {
+    agent::forget(serial);
+}
+
+#[cfg(not(unix))]
+pub(crate) fn forget(_: Option<Serial>) {}
@@ -1,4 +1,7 @@
-use age_core::format::{FileKey, Stanza};
+use age_core::{
+    format::{FileKey, Stanza},
+    secrecy::SecretString,
+};
 use age_plugin::{
     identity::{self, IdentityPluginV1},
     recipient::{self, RecipientPluginV1},
@@ -6,8 +9,10 @@ use age_plugin::{
 };
 use std::collections::HashMap;
 use std::io;
+use tracing::debug_span;
+use yubikey::Serial;
 
-use crate::{fl, format, key, p256::Recipient, PLUGIN_NAME};
+use crate::{backend, fl, format, key, p256::Recipient, PLUGIN_NAME};
 
 #[derive(Debug, Default)]
 pub(crate) struct RecipientPlugin {
@@ -63,11 +68,13 @@ impl RecipientPluginV1 for RecipientPlugin {
         file_keys: Vec<FileKey>,
         mut callbacks: impl Callbacks<recipient::Error>,
     ) -> io::Result<Result<Vec<Vec<Stanza>>, Vec<recipient::Error>>> {
+        let _span = debug_span!("wrap_file_keys", files = file_keys.len()).entered();
+
         // Connect to any listed YubiKey identities to obtain the corresponding recipients.
         let mut yk_recipients = vec![];
         let mut yk_errors = vec![];
         for stub in &self.yubikeys {
-            match stub.connect(&mut callbacks)? {
+            match stub.connect(None, &mut callbacks)? {
                 Ok(Some(conn)) => yk_recipients.push(conn.recipient().clone()),
                 Ok(None) => yk_errors.push(recipient::Error::Identity {
                     index: stub.identity_index,
@@ -105,9 +112,19 @@ impl RecipientPluginV1 for RecipientPlugin {
     }
 }
 
-#[derive(Debug, Default)]
+#[derive(Debug)]
 pub(crate) struct IdentityPlugin {
     yubikeys: Vec<key::Stub>,
+    only_serial: Option<Serial>,
+}
+
+impl Default for IdentityPlugin {
+    fn default() -> Self {
+        IdentityPlugin {
+            yubikeys: vec![],
+            only_serial: key::serial_from_env(),
+        }
+    }
 }
 
 impl IdentityPluginV1 for IdentityPlugin {
@@ -122,7 +139,14 @@ impl IdentityPluginV1 for IdentityPlugin {
         } else {
             None
         } {
-            self.yubikeys.push(stub);
+            // If the user selected a YubiKey, ignore identities for other YubiKeys so
+            // that we don't ask for them to be inserted. Identities for YubiKeys without
+            // serials might be for any YubiKey, so we keep them.
+            if !self.only_serial.map_or(false, |serial| {
+                stub.serial.0 != key::NO_SERIAL && stub.serial != serial
+            }) {
+                self.yubikeys.push(stub);
+            }
             Ok(())
         } else {
             Err(identity::Error::Identity {
@@ -137,6 +161,7 @@ impl IdentityPluginV1 for IdentityPlugin {
         files: Vec<Vec<Stanza>>,
         mut callbacks: impl Callbacks<identity::Error>,
     ) -> io::Result<HashMap<usize, Result<FileKey, Vec<identity::Error>>>> {
+        let _span = debug_span!("unwrap_file_keys", files = files.len()).entered();
         let mut file_keys = HashMap::with_capacity(files.len());
 
         // Filter to files / stanzas for which we have matching YubiKeys
@@ -192,24 +217,37 @@ impl IdentityPluginV1 for IdentityPlugin {
         }
 
         // Sort by effectiveness (YubiKey that can trial-decrypt the most stanzas)
-        candidate_stanzas.sort_by_key(|(_, files)| {
-            files
-                .iter()
-                .map(|(_, stanzas)| stanzas.len())
-                .sum::<usize>()
-        });
+        candidate_stanzas
+            .sort_by_key(|(_, files)| files.values().map(|stanzas| stanzas.len()).sum::<usize>());
         candidate_stanzas.reverse();
         // Remove any YubiKeys without stanzas.
-        candidate_stanzas.retain(|(_, files)| {
-            files
-                .iter()
-                .map(|(_, stanzas)| stanzas.len())
-                .sum::<usize>()
-                > 0
-        });
+        candidate_stanzas
+            .retain(|(_, files)| files.values().map(|stanzas| stanzas.len()).sum::<usize>() > 0);
 
-        for (stub, files) in candidate_stanzas.iter() {
-            let mut conn = match stub.connect(&mut callbacks)? {
+        // Open the YubiKeys of all stubs with known serials at once, and try the ones
+        // that are plugged in first, so that the user isn't asked to insert (or kept
+        // waiting on) YubiKeys whose files another YubiKey can decrypt.
+        let mut serials: Vec<Serial> = candidate_stanzas
+            .iter()
+            .map(|(stub, _)| stub.serial)
+            .filter(|serial| serial.0 != key::NO_SERIAL)
+            .collect();
+        serials.sort_by_key(|serial| serial.0);
+        serials.dedup();
+        let mut opened = backend::open_all(&serials);
+        candidate_stanzas.sort_by_key(|(stub, _)| !opened.contains_key(&stub.serial.0));
+
+        // Each YubiKey is unlocked once: the PIN that unlocks one of its slots is tried
+        // for its other slots before asking again.
+        let mut pins: HashMap<u32, SecretString> = HashMap::new();
+
+        'yubikeys: for (stub, files) in candidate_stanzas.iter() {
+            if files.keys().all(|file| file_keys.contains_key(file)) {
+                // We decrypted these files with earlier YubiKeys.
+                continue;
+            }
+
+            let mut conn = match stub.connect(opened.remove(&stub.serial.0), &mut callbacks)? {
                 // The user skipped this YubiKey.
                 Ok(None) => continue,
                 // We connected to this YubiKey.
@@ -221,7 +259,12 @@ impl IdentityPluginV1 for IdentityPlugin {
                 }
             };
 
-            if let Err(e) = conn.request_pin_if_necessary(&mut callbacks)? {
+            conn.use_pin(pins.remove(&conn.serial().0));
+            let unlocked = conn.request_pin_if_necessary(&mut callbacks)?;
+            if let Some(pin) = conn.pin() {
+                pins.insert(conn.serial().0, pin);
+            }
+            if let Err(e) = unlocked {
                 callbacks.error(e)?.unwrap();
                 continue;
             }
@@ -233,7 +276,47 @@ impl IdentityPluginV1 for IdentityPlugin {
                 }
 
                 for (stanza_index, line) in stanzas.iter().enumerate() {
-                    match conn.unwrap_file_key(line) {
+                    let _stanza = debug_span!(
+                        "stanza",
+                        file = file_index,
+                        stanza = stanza_index,
+                        serial = %stub.serial_for_ui(),
+                    )
+                    .entered();
+                    let unwrapped = loop {
+                        let on_touch = || {
+                            // The user may not see the YubiKey flashing, so ask for a
+                            // touch. Not being able to tell them doesn't stop the
+                            // decryption.
+                            let _ = callbacks.message(&fl!(
+                                "plugin-touch-yk",
+                                yubikey_serial = stub.serial_for_ui(),
+                            ));
+                        };
+                        match conn.unwrap_file_key(line, on_touch) {
+                            Err(key::UnwrapError::Removed) => (),
+                            res => break res,
+                        }
+
+                        // The YubiKey was removed; ask for it back, and unlock it again
+                        // to retry this stanza.
+                        let pin = conn.pin();
+                        conn.disconnect_without_reset();
+                        conn = match stub.reconnect(&mut callbacks)? {
+                            Ok(Some(conn)) => conn,
+                            Ok(None) => continue 'yubikeys,
+                            Err(e) => {
+                                callbacks.error(e)?.unwrap();
+                                continue 'yubikeys;
+                            }
+                        };
+                        conn.use_pin(pin);
+                        if let Err(e) = conn.request_pin_if_necessary(&mut callbacks)? {
+                            callbacks.error(e)?.unwrap();
+                            continue 'yubikeys;
+                        }
+                    };
+                    match unwrapped {
                         Ok(file_key) => {
                             // We've managed to decrypt this file!
                             file_keys.entry(file_index).or_insert(Ok(file_key));
@@ -252,6 +335,10 @@ impl IdentityPluginV1 for IdentityPlugin {
 
             conn.disconnect_without_reset();
         }
+
+        for (_, backend) in opened {
+            backend.disconnect_without_reset();
+        }
         Ok(file_keys)
     }
 }
@@ -0,0 +1,311 @@
+//! Provisioning identities on many YubiKeys from a declarative spec.
+//!
+//! `--provision CONFIG` reads a TOML file describing the identities every YubiKey
+//! should hold, and generates them on each connected YubiKey in turn. A failure on one
+//! YubiKey is reported and does not stop the others from being provisioned.
+//!
+//! ```toml
+//! # Defaults for every slot.
+//! pin_policy = "once"
+//! touch_policy = "cached"
+//! algorithm = "p256"
+//!
+//! # Write identity files here instead of printing the identities.
+//! output_dir = "identities"
+//!
+//! # Set the PIN and PUK of YubiKeys still using the defaults to the PIN given with
+//! # --unattended-pin.
+//! replace_default_pin = true
+//!
+//! [[slot]]
+//! slot = 1
+//! name = "backup key for {serial}"
+//! touch_policy = "always"
+//! ```
+
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use age_core::secrecy::{ExposeSecret, SecretString};
+use serde::Deserialize;
+use yubikey::{piv::RetiredSlotId, reader::Context, PinPolicy, Serial, TouchPolicy, YubiKey};
+
+use crate::{
+    builder::IdentityBuilder,
+    error::Error,
+    fl, key,
+    p256::{Curve, Recipient},
+    pin,
+    util::{self, Metadata},
+};
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Config {
+    #[serde(default)]
+    replace_default_pin: bool,
+    output_dir: Option<PathBuf>,
+    pin_policy: Option<String>,
+    touch_policy: Option<String>,
+    algorithm: Option<String>,
+    #[serde(default, rename = "slot")]
+    slots: Vec<SlotConfig>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct SlotConfig {
+    slot: u8,
+    name: Option<String>,
+    pin_policy: Option<String>,
+    touch_policy: Option<String>,
+    algorithm: Option<String>,
+    #[serde(default)]
+    force: bool,
+}
+
+/// A validated provisioning spec.
+#[derive(Debug)]
+pub(crate) struct Spec {
+    replace_default_pin: bool,
+    output_dir: Option<PathBuf>,
+    slots: Vec<SlotSpec>,
+}
+
+#[derive(Debug)]
+struct SlotSpec {
+    slot: RetiredSlotId,
+    /// May contain `{serial}`, which is replaced with the YubiKey's serial.
+    name: Option<String>,
+    pin_policy: Option<PinPolicy>,
+    touch_policy: Option<TouchPolicy>,
+    curve: Option<Curve>,
+    force: bool,
+}
+
+impl Spec {
+    /// Parses and validates a spec, so that mistakes are found before any YubiKey is
+    /// modified.
+    pub(crate) fn parse(s: &str) -> Result<Self, Error> {
+        let config: Config =
+            toml::from_str(s).map_err(|e| Error::InvalidProvisionSpec(e.message().into()))?;
+
+        if config.slots.is_empty() {
+            return Err(Error::InvalidProvisionSpec(fl!("provision-err-no-slots")));
+        }
+
+        let pin_policy = |s: &Option<String>| s.clone().map(util::pin_policy_from_string);
+        let touch_policy = |s: &Option<String>| s.clone().map(util::touch_policy_from_string);
+        let curve = |s: &Option<String>| {
+            s.clone()
+                .map(|s| Curve::from_name(&s).ok_or(Error::InvalidAlgorithm(s)))
+        };
+
+        let mut slots: Vec<SlotSpec> = vec![];
+        for slot in config.slots {
+            let spec = SlotSpec {
+                slot: util::ui_to_slot(slot.slot)?,
+                name: slot.name,
+                pin_policy: pin_policy(&slot.pin_policy)
+                    .or_else(|| pin_policy(&config.pin_policy))
+                    .transpose()?,
+                touch_policy: touch_policy(&slot.touch_policy)
+                    .or_else(|| touch_policy(&config.touch_policy))
+                    .transpose()?,
+                curve: curve(&slot.algorithm)
+                    .or_else(|| curve(&config.algorithm))
+                    .transpose()?,
+                force: slot.force,
+            };
+            if slots.iter().any(|s| s.slot == spec.slot) {
+                return Err(Error::InvalidProvisionSpec(fl!(
+                    "provision-err-duplicate-slot",
+                    slot = slot.slot,
+                )));
+            }
+            slots.push(spec);
+        }
+
+        Ok(Spec {
+            replace_default_pin: config.replace_default_pin,
+            output_dir: config.output_dir,
+            slots,
+        })
+    }
+}
+
+/// Applies `spec` to every connected YubiKey (or only the one with `serial`), returning
+/// the number of YubiKeys that could not be fully provisioned.
+pub(crate) fn run(spec: &Spec, serial: Option<Serial>) -> Result<usize, Error> {
+    let new_pin = if spec.replace_default_pin {
+        Some(pin::unattended().ok_or(Error::ProvisionNeedsUnattendedPin)?)
+    } else {
+        None
+    };
+    if let Some(dir) = &spec.output_dir {
+        fs::create_dir_all(dir)?;
+    }
+
+    let mut readers = Context::open()?;
+
+    let (mut total, mut failed) = (0, 0);
+    for reader in readers.iter()?.filter(key::filter_connected) {
+        let mut yubikey = key::open_connection(&reader)?;
+        if serial.map_or(false, |serial| yubikey.serial() != serial) {
+            key::disconnect_without_reset(yubikey);
+            continue;
+        }
+        total += 1;
+
+        let yubikey_serial = yubikey.serial().to_string();
+        eprintln!();
+        eprintln!(
+            "{}",
+            fl!(
+                "provision-yk-start",
+                yubikey_serial = yubikey_serial.as_str()
+            )
+        );
+
+        let res = provision_yubikey(spec, new_pin.as_ref(), &mut yubikey);
+        if let Err(e) = res {
+            failed += 1;
+            let err = e.to_string();
+            eprintln!(
+                "{}",
+                fl!(
+                    "provision-yk-failed",
+                    yubikey_serial = yubikey_serial,
+                    err = err.trim_end(),
+                )
+            );
+        }
+        // We authenticated with the management key, so let the YubiKey be reset when it
+        // is dropped, as `--generate` does.
+    }
+
+    let succeeded: usize = total - failed;
+    eprintln!();
+    eprintln!(
+        "{}",
+        fl!("provision-summary", succeeded = succeeded, total = total,)
+    );
+    Ok(failed)
+}
+
+fn provision_yubikey(
+    spec: &Spec,
+    new_pin: Option<&SecretString>,
+    yubikey: &mut YubiKey,
+) -> Result<(), Error> {
+    if let Some(pin) = new_pin {
+        if key::replace_default_pin(yubikey, pin.expose_secret().as_bytes())? {
+            eprintln!("{}", fl!("provision-yk-pin-replaced"));
+        }
+    }
+
+    let serial = yubikey.serial().to_string();
+
+    for slot in &spec.slots {
+        let (stub, recipient, metadata) = IdentityBuilder::new(Some(slot.slot))
+            .with_name(slot.name.as_ref().map(|n| n.replace("{serial}", &serial)))
+            .with_pin_policy(slot.pin_policy)
+            .with_touch_policy(slot.touch_policy)
+            .with_curve(slot.curve)
+            .force(slot.force)
+            .build(yubikey)?;
+
+        match &spec.output_dir {
+            Some(dir) => write_identity(dir, &stub, &recipient, &metadata)?,
+            None => util::print_identity(stub, recipient.clone(), metadata),
+        }
+        eprintln!(
+            "{}",
+            fl!(
+                "provision-yk-slot-done",
+                slot = util::slot_to_ui(&slot.slot),
+                recipient = recipient.to_string(),
+            )
+        );
+    }
+
+    Ok(())
+}
+
+fn write_identity(
+    dir: &Path,
+    stub: &key::Stub,
+    recipient: &Recipient,
+    metadata: &Metadata,
+) -> Result<(), Error> {
+    let path = dir.join(format!(
+        "age-yubikey-identity-{}.txt",
+        hex::encode(stub.tag)
+    ));
+    let mut file = OpenOptions::new().create_new(true).write(true).open(path)?;
+    writeln!(
+        file,
+        "{}",
+        fl!(
+            "yubikey-identity",
+            yubikey_metadata = metadata.to_string(),
+            recipient = recipient.to_string(),
+            identity = stub.to_string(),
+        )
+    )?;
+    file.sync_data()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use yubikey::{piv::RetiredSlotId, PinPolicy, TouchPolicy};
+
+    use super::Spec;
+    use crate::p256::Curve;
+
+    #[test]
+    fn slot_settings_override_defaults() {
+        let spec = Spec::parse(
+            r#"
+            pin_policy = "never"
+            touch_policy = "cached"
+
+            [[slot]]
+            slot = 1
+
+            [[slot]]
+            slot = 2
+            name = "backup for {serial}"
+            pin_policy = "always"
+            algorithm = "p384"
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(spec.slots.len(), 2);
+        assert_eq!(spec.slots[0].slot, RetiredSlotId::R1);
+        assert_eq!(spec.slots[0].pin_policy, Some(PinPolicy::Never));
+        assert_eq!(spec.slots[0].touch_policy, Some(TouchPolicy::Cached));
+        assert_eq!(spec.slots[0].curve, None);
+        assert_eq!(spec.slots[1].slot, RetiredSlotId::R2);
+        assert_eq!(spec.slots[1].pin_policy, Some(PinPolicy::Always));
+        assert_eq!(spec.slots[1].touch_policy, Some(TouchPolicy::Cached));
+        assert_eq!(spec.slots[1].curve, Some(Curve::P384));
+    }
+
+    #[test]
+    fn invalid_specs() {
+        // No slots.
+        assert!(Spec::parse(r#"pin_policy = "once""#).is_err());
+        // Unknown field.
+        assert!(Spec::parse("[[slot]]\nslot = 1\ncolour = \"blue\"").is_err());
+        // Invalid slot.
+        assert!(Spec::parse("[[slot]]\nslot = 21").is_err());
+        // Duplicate slot.
+        assert!(Spec::parse("[[slot]]\nslot = 3\n[[slot]]\nslot = 3").is_err());
+        // Invalid policy.
+        assert!(Spec::parse("[[slot]]\nslot = 1\ntouch_policy = \"sometimes\"").is_err());
+    }
+}
@@ -0,0 +1,649 @@
+//! YubiKeys shared over USB/IP by a remote `yk-agentd`.
+//!
+//! The plugin imports a remote YubiKey into its own process, rather than attaching it to
+//! the local USB stack, so this needs no drivers or privileges. It then speaks PIV to the
+//! YubiKey through its CCID interface with the few APDUs that decryption needs. PINs are
+//! entered locally and sent to the YubiKey, as for local YubiKeys. While a YubiKey waits
+//! for a touch it keeps asking the reader for more time, which is how we know to tell
+//! the user to touch it, as they may not be looking at the remote machine.
+
+use std::io;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use age_core::secrecy::zeroize::Zeroizing;
+use lazy_static::lazy_static;
+use tokio::runtime::Runtime;
+use tracing::{debug, warn};
+use usbip::{
+    ccid::RemoteReader,
+    client,
+    local::{self, Address},
+    mdns, quic, tls,
+    wire::ExportedDevice,
+    Transport,
+};
+use yubikey::{
+    certificate::Certificate,
+    piv::{RetiredSlotId, SlotId},
+    Buffer, Serial,
+};
+
+use crate::{
+    backend::IdentityBackend,
+    config::RemoteConfig,
+    key::Stub,
+    p256::{Curve, Recipient},
+    util::Metadata,
+};
+
+const YUBICO_VID: u16 = 0x1050;
+const SMART_CARD_CLASS: u8 = 0x0B;
+
+const PIV_AID: [u8; 5] = [0xA0, 0x00, 0x00, 0x03, 0x08];
+const INS_VERIFY: u8 = 0x20;
+const INS_GENERAL_AUTHENTICATE: u8 = 0x87;
+const INS_SELECT: u8 = 0xA4;
+const INS_GET_RESPONSE: u8 = 0xC0;
+const INS_GET_DATA: u8 = 0xCB;
+const INS_GET_SERIAL: u8 = 0xF8;
+const INS_ATTEST: u8 = 0xF9;
+const PIV_PIN: u8 = 0x80;
+const PIN_LENGTH: usize = 8;
+
+const SW_SUCCESS: u16 = 0x9000;
+const SW_PIN_LOCKED: u16 = 0x6983;
+const SW_NOT_FOUND: u16 = 0x6A82;
+
+/// How long to listen for daemons advertising themselves.
+const DISCOVERY_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// The tag of the first retired key's certificate object; the others follow it.
+const RETIRED_CERT_OBJECT: u32 = 0x5F_C1_0D;
+
+lazy_static! {
+    /// The QUIC client, kept across connections so that reconnecting to the daemon
+    /// resumes the session with 0-RTT.
+    static ref QUIC_CLIENT: Mutex<Option<quic::Client>> = Mutex::new(None);
+}
+
+/// Opens a connection to the daemon, ready for a request.
+///
+/// The address may also name a Unix domain socket (`unix:PATH`) or a Windows named pipe
+/// (`pipe:NAME`) of a daemon on this machine, or the socket of a daemon on a machine we
+/// log in to with SSH (`ssh:[USER@]HOST/PATH`), over which TLS is not used.
+async fn connect(config: &RemoteConfig) -> io::Result<Box<dyn Transport>> {
+    let address: Address = config.address.parse()?;
+    let mut stream: Box<dyn Transport> = match (&config.ca, &config.cert, &config.key) {
+        (Some(_), _, _) if address.is_local() => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "TLS is not used over a local transport",
+            ))
+        }
+        (Some(ca), cert, key) => {
+            let identity = match (cert, key) {
+                (Some(cert), Some(key)) => {
+                    Some((tls::load_certs(cert)?, tls::load_private_key(key)?))
+                }
+                (None, None) => None,
+                _ => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        "cert and key go together",
+                    ))
+                }
+            };
+            if config.quic {
+                let client = {
+                    let mut client = QUIC_CLIENT.lock().unwrap();
+                    match &*client {
+                        Some(client) => client.clone(),
+                        None => client
+                            .insert(quic::Client::new(tls::load_certs(ca)?, identity)?)
+                            .clone(),
+                    }
+                };
+                Box::new(
+                    client
+                        .connect(config.address.as_str(), config.server_name())
+                        .await?,
+                )
+            } else {
+                let connector = tls::connector(tls::load_certs(ca)?, identity)?;
+                Box::new(
+                    tls::connect(config.address.as_str(), config.server_name(), &connector).await?,
+                )
+            }
+        }
+        (None, _, _) if config.quic => {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "QUIC needs ca"))
+        }
+        (None, None, None) => local::connect(&address).await?,
+        _ => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "a client certificate needs ca",
+            ))
+        }
+    };
+    if let Some(path) = &config.token_file {
+        let token = std::fs::read_to_string(path)?;
+        client::send_token(&mut stream, token.trim()).await?;
+    } else if let Some(token) = &config.token {
+        client::send_token(&mut stream, token).await?;
+    }
+    Ok(stream)
+}
+
+fn is_yubikey(device: &ExportedDevice) -> bool {
+    device.vendor_id == YUBICO_VID
+        && device
+            .interfaces
+            .iter()
+            .any(|interface| interface.interface_class == SMART_CARD_CLASS)
+}
+
+fn runtime() -> io::Result<Runtime> {
+    tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+}
+
+/// Returns the bus ids of the YubiKeys to use on the daemon.
+fn bus_ids(config: &RemoteConfig) -> io::Result<Vec<String>> {
+    if let Some(bus_id) = &config.bus_id {
+        return Ok(vec![bus_id.clone()]);
+    }
+    runtime()?.block_on(async {
+        let mut stream = connect(config).await?;
+        Ok(client::list_devices(&mut stream)
+            .await?
+            .into_iter()
+            .filter(is_yubikey)
+            .map(|device| device.bus_id)
+            .collect())
+    })
+}
+
+/// Returns the daemons advertised on the local network.
+pub(crate) fn discover() -> io::Result<Vec<mdns::Server>> {
+    runtime()?.block_on(mdns::discover(DISCOVERY_TIMEOUT))
+}
+
+/// Returns the value of the first BER-TLV with `tag` in `data`.
+fn find_tlv(mut data: &[u8], tag: u8) -> Option<&[u8]> {
+    while data.len() >= 2 {
+        let (len, header) = match data[1] {
+            len @ 0..=0x7F => (len as usize, 2),
+            0x81 => (*data.get(2)? as usize, 3),
+            0x82 => (
+                u16::from_be_bytes([*data.get(2)?, *data.get(3)?]) as usize,
+                4,
+            ),
+            _ => return None,
+        };
+        let value = data.get(header..header + len)?;
+        if data[0] == tag {
+            return Some(value);
+        }
+        data = &data[header + len..];
+    }
+    None
+}
+
+/// Encodes a BER-TLV.
+fn tlv(tag: u8, value: &[u8]) -> Vec<u8> {
+    let mut encoded = vec![tag];
+    match value.len() {
+        len @ 0..=0x7F => encoded.push(len as u8),
+        len @ 0x80..=0xFF => encoded.extend([0x81, len as u8]),
+        len => encoded.extend([0x82, (len >> 8) as u8, len as u8]),
+    }
+    encoded.extend(value);
+    encoded
+}
+
+/// Encodes a short command APDU.
+fn apdu(ins: u8, p1: u8, p2: u8, data: &[u8]) -> Vec<u8> {
+    let mut apdu = vec![0x00, ins, p1, p2];
+    if !data.is_empty() {
+        apdu.push(data.len() as u8);
+        apdu.extend(data);
+    }
+    apdu
+}
+
+/// Returns the error for a failed status word.
+fn status_error(sw: u16) -> yubikey::Error {
+    match sw {
+        SW_NOT_FOUND => yubikey::Error::NotFound,
+        SW_PIN_LOCKED => yubikey::Error::PinLocked,
+        sw if sw & 0xFFF0 == 0x63C0 => yubikey::Error::WrongPin {
+            tries: (sw & 0x0F) as u8,
+        },
+        _ => yubikey::Error::GenericError,
+    }
+}
+
+/// A YubiKey imported from a remote daemon.
+pub(crate) struct RemoteYubiKey {
+    reader: RemoteReader,
+    serial: Serial,
+    // Set when the connection to the daemon dropped. The YubiKey is imported again
+    // over a new one, and its PIV applet must be selected again.
+    reselect: bool,
+    // Runs the import's connection; dropped last.
+    runtime: Runtime,
+}
+
+impl RemoteYubiKey {
+    /// Imports the YubiKey with `bus_id`, and selects its PIV applet.
+    fn import(config: &RemoteConfig, bus_id: &str) -> io::Result<Self> {
+        let runtime = runtime()?;
+        let reader = runtime.block_on(async {
+            let mut stream = connect(config).await?;
+            let device = client::import(&mut stream, bus_id).await?;
+            let reconnect: client::Connect = {
+                let config = config.clone();
+                Box::new(move || {
+                    let config = config.clone();
+                    Box::pin(async move { connect(&config).await })
+                })
+            };
+            let device = client::ImportedDevice::with_reconnect(
+                stream,
+                device,
+                reconnect,
+                client::Backoff::default(),
+            )
+            .with_keep_alive(client::KeepAlive::default());
+            let mut reader = RemoteReader::open(device).await?;
+            reader.power_on().await?;
+            Ok::<_, io::Error>(reader)
+        })?;
+        let mut yubikey = RemoteYubiKey {
+            reader,
+            serial: Serial(0),
+            reselect: false,
+            runtime,
+        };
+        let failed = || {
+            io::Error::new(
+                io::ErrorKind::Other,
+                format!("{} is not a PIV smart card", bus_id),
+            )
+        };
+        yubikey
+            .transmit(&apdu(INS_SELECT, 0x04, 0x00, &PIV_AID), &mut || ())
+            .map_err(|_| failed())?;
+        let serial = yubikey
+            .transmit(&apdu(INS_GET_SERIAL, 0x00, 0x00, &[]), &mut || ())
+            .map_err(|_| failed())?;
+        yubikey.serial = Serial(u32::from_be_bytes(
+            serial[..].try_into().map_err(|_| failed())?,
+        ));
+        debug!("Imported YubiKey {} as {}", yubikey.serial, bus_id);
+        Ok(yubikey)
+    }
+
+    /// Imports every YubiKey the daemon exports to us.
+    pub(crate) fn open_all(config: &RemoteConfig) -> io::Result<Vec<Self>> {
+        bus_ids(config)?
+            .iter()
+            .map(|bus_id| Self::import(config, bus_id))
+            .collect()
+    }
+
+    /// Imports the YubiKey with the given serial.
+    pub(crate) fn open(config: &RemoteConfig, serial: Serial) -> Result<Self, yubikey::Error> {
+        let bus_ids = bus_ids(config).map_err(|e| {
+            warn!("Could not list the YubiKeys of {}: {}", config.address, e);
+            yubikey::Error::NotFound
+        })?;
+        for bus_id in bus_ids {
+            match Self::import(config, &bus_id) {
+                Ok(yubikey) if yubikey.serial == serial => return Ok(yubikey),
+                Ok(_) => (),
+                // Another client may be using it.
+                Err(e) => debug!("Could not import {}: {}", bus_id, e),
+            }
+        }
+        Err(yubikey::Error::NotFound)
+    }
+
+    /// Sends `apdu`, returning the response data if the YubiKey reports success.
+    ///
+    /// `on_wait` is called if the YubiKey waits for a touch.
+    fn transmit(
+        &mut self,
+        apdu: &[u8],
+        on_wait: &mut dyn FnMut(),
+    ) -> Result<Vec<u8>, yubikey::Error> {
+        if std::mem::take(&mut self.reselect) {
+            debug!("Selecting PIV again on remote YubiKey {}", self.serial);
+            let res = self.runtime.block_on(self.reader.power_on());
+            self.check(res)?;
+            self.transmit(&self::apdu(INS_SELECT, 0x04, 0x00, &PIV_AID), &mut || ())?;
+        }
+        let mut data = vec![];
+        let mut command = apdu.to_vec();
+        loop {
+            let res = self
+                .runtime
+                .block_on(self.reader.transmit(&command, &mut *on_wait));
+            let response = self.check(res)?;
+            if response.len() < 2 {
+                return Err(yubikey::Error::GenericError);
+            }
+            let (body, sw) = response.split_at(response.len() - 2);
+            data.extend(body);
+            match u16::from_be_bytes([sw[0], sw[1]]) {
+                SW_SUCCESS => return Ok(data),
+                // More data is available.
+                sw if sw >> 8 == 0x61 => {
+                    command = vec![0x00, INS_GET_RESPONSE, 0x00, 0x00, sw as u8];
+                }
+                sw => return Err(status_error(sw)),
+            }
+        }
+    }
+
+    /// Maps a failed exchange with the reader to a [`yubikey::Error`], noting when the
+    /// connection was lost.
+    fn check<T>(&mut self, res: io::Result<T>) -> Result<T, yubikey::Error> {
+        res.map_err(|e| {
+            warn!("Remote YubiKey {}: {}", self.serial, e);
+            if e.kind() == io::ErrorKind::ConnectionAborted {
+                self.reselect = true;
+            }
+            yubikey::Error::GenericError
+        })
+    }
+
+    fn read_certificate(&mut self, slot: SlotId) -> Result<Certificate, yubikey::Error> {
+        let object = match slot {
+            SlotId::Authentication => 0x5F_C1_05,
+            SlotId::Signature => 0x5F_C1_0A,
+            SlotId::KeyManagement => 0x5F_C1_0B,
+            SlotId::CardAuthentication => 0x5F_C1_01,
+            SlotId::Retired(slot) => {
+                RETIRED_CERT_OBJECT + u32::from(u8::from(slot) - u8::from(RetiredSlotId::R1))
+            }
+            _ => return Err(yubikey::Error::InvalidObject),
+        };
+        let response = self.transmit(
+            &apdu(
+                INS_GET_DATA,
+                0x3F,
+                0xFF,
+                &tlv(0x5C, &object.to_be_bytes()[1..]),
+            ),
+            &mut || (),
+        )?;
+        let object = find_tlv(&response, 0x53).ok_or(yubikey::Error::InvalidObject)?;
+        // Compressed certificates aren't used for keys this plugin can use.
+        if matches!(find_tlv(object, 0x71), Some(info) if info != [0]) {
+            return Err(yubikey::Error::InvalidObject);
+        }
+        let cert = find_tlv(object, 0x70).ok_or(yubikey::Error::InvalidObject)?;
+        Certificate::from_bytes(cert.to_vec())
+    }
+
+    fn attest(&mut self, slot: SlotId) -> Result<Vec<u8>, yubikey::Error> {
+        self.transmit(&apdu(INS_ATTEST, slot.into(), 0x00, &[]), &mut || ())
+    }
+
+    /// Returns the identities in this YubiKey, as [`crate::key::list_compatible`] and
+    /// [`Metadata::extract`] would find them.
+    pub(crate) fn identities(
+        &mut self,
+        slots: &[SlotId],
+        all: bool,
+    ) -> Vec<(Stub, Recipient, Metadata)> {
+        let mut identities = vec![];
+        for &slot in slots {
+            let cert = match self.read_certificate(slot) {
+                Ok(cert) => cert,
+                Err(_) => continue,
+            };
+            let recipient = match Recipient::from_certificate(&cert) {
+                Some(recipient) => recipient,
+                None => continue,
+            };
+            let serial = self.serial;
+            if let Some(metadata) =
+                Metadata::extract_with(serial, slot, &cert, all, || self.attest(slot).ok())
+            {
+                identities.push((Stub::new(serial, slot, &recipient), recipient, metadata));
+            }
+        }
+        identities
+    }
+}
+
+impl IdentityBackend for RemoteYubiKey {
+    fn serial(&self) -> Serial {
+        self.serial
+    }
+
+    fn recipient(&mut self, slot: SlotId) -> Option<Recipient> {
+        self.read_certificate(slot)
+            .ok()
+            .and_then(|cert| Recipient::from_certificate(&cert))
+    }
+
+    fn metadata(&mut self, slot: SlotId) -> Option<Metadata> {
+        let cert = self.read_certificate(slot).ok()?;
+        let serial = self.serial;
+        Metadata::extract_with(serial, slot, &cert, true, || self.attest(slot).ok())
+    }
+
+    fn verify_pin(&mut self, pin: &[u8]) -> Result<(), yubikey::Error> {
+        if pin.len() > PIN_LENGTH {
+            return Err(yubikey::Error::WrongPin { tries: 0 });
+        }
+        let mut data = Zeroizing::new([0xFF; PIN_LENGTH]);
+        data[..pin.len()].copy_from_slice(pin);
+        let data: &[u8] = if pin.is_empty() { &[] } else { &data[..] };
+        self.transmit(&apdu(INS_VERIFY, 0x00, PIV_PIN, data), &mut || ())
+            .map(|_| ())
+    }
+
+    fn pin_tries(&mut self) -> Result<u8, yubikey::Error> {
+        // Selecting the applet again forgets a verified PIN, without which VERIFY would
+        // report success instead of the tries left.
+        self.transmit(&apdu(INS_SELECT, 0x04, 0x00, &PIV_AID), &mut || ())?;
+        match self.verify_pin(&[]) {
+            Ok(()) | Err(yubikey::Error::PinLocked) => Ok(0),
+            Err(yubikey::Error::WrongPin { tries }) => Ok(tries),
+            Err(e) => Err(e),
+        }
+    }
+
+    fn decrypt(
+        &mut self,
+        slot: SlotId,
+        point: &[u8],
+        on_touch: &mut dyn FnMut(),
+    ) -> Result<Buffer, yubikey::Error> {
+        let curve = Curve::from_input_len(point.len()).ok_or(yubikey::Error::SizeError)?;
+        // RSA decryptions send the ciphertext as a challenge, ECDH the peer's point as an
+        // exponentiation.
+        let mut template = tlv(0x82, &[]);
+        template.extend(tlv(if curve.is_rsa() { 0x81 } else { 0x85 }, point));
+        let response = Zeroizing::new(self.transmit(
+            &apdu(
+                INS_GENERAL_AUTHENTICATE,
+                curve.algorithm().into(),
+                slot.into(),
+                &tlv(0x7C, &template),
+            ),
+            on_touch,
+        )?);
+        find_tlv(&response, 0x7C)
+            .and_then(|template| find_tlv(template, 0x82))
+            .map(|secret| Zeroizing::new(secret.to_vec()))
+            .ok_or(yubikey::Error::ParseError)
+    }
+
+    fn disconnect_without_reset(self: Box<Self>) {
+        // Releasing the import leaves the YubiKey as it is.
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{apdu, find_tlv, status_error, tlv};
+    #[cfg(all(unix, feature = "emulated-yubikey"))]
+    use {
+        super::{RemoteYubiKey, RETIRED_CERT_OBJECT},
+        crate::{
+            backend::IdentityBackend, config::RemoteConfig, format::RecipientLine, key::Connection,
+            p256::Recipient, util::BINARY_NAME,
+        },
+        age_core::{format::FileKey, secrecy::ExposeSecret},
+        std::sync::Arc,
+        usbip::{local, piv},
+        yubikey::{
+            piv::{RetiredSlotId, SlotId},
+            PinPolicy, Serial,
+        },
+    };
+
+    #[test]
+    fn tlvs() {
+        let long = vec![7; 300];
+        let mut data = tlv(0x70, &long);
+        data.extend(tlv(0x71, &[0]));
+        data.extend(tlv(0xFE, &[]));
+        assert_eq!(&data[..4], &[0x70, 0x82, 0x01, 0x2C]);
+        assert_eq!(find_tlv(&data, 0x70), Some(&long[..]));
+        assert_eq!(find_tlv(&data, 0x71), Some(&[0][..]));
+        assert_eq!(find_tlv(&data, 0xFE), Some(&[][..]));
+        assert_eq!(find_tlv(&data, 0x53), None);
+        assert_eq!(find_tlv(&data[..10], 0x71), None);
+
+        assert_eq!(tlv(0x85, &[1; 0x90])[..3], [0x85, 0x81, 0x90]);
+    }
+
+    #[test]
+    fn apdus() {
+        assert_eq!(apdu(0xF8, 0, 0, &[]), [0x00, 0xF8, 0x00, 0x00]);
+        assert_eq!(
+            apdu(0x20, 0, 0x80, &[1, 2]),
+            [0x00, 0x20, 0x00, 0x80, 2, 1, 2]
+        );
+    }
+
+    #[test]
+    fn status_errors() {
+        assert!(matches!(
+            status_error(0x63C2),
+            yubikey::Error::WrongPin { tries: 2 }
+        ));
+        assert!(matches!(status_error(0x6983), yubikey::Error::PinLocked));
+        assert!(matches!(status_error(0x6A82), yubikey::Error::NotFound));
+        assert!(matches!(status_error(0x6D00), yubikey::Error::GenericError));
+    }
+
+    /// A certificate for the key with the uncompressed `point`, as `--generate` would
+    /// write it for an identity named `name` with the PIN and touch policies `Never`.
+    /// Its signature is not checked, so it is left out.
+    #[cfg(all(unix, feature = "emulated-yubikey"))]
+    fn certificate(point: &[u8], name: &str) -> Vec<u8> {
+        let seq = |parts: &[Vec<u8>]| tlv(0x30, &parts.concat());
+        let oid = |der: &[u8]| tlv(0x06, der);
+        let rdn =
+            |attr: &[u8], value: &str| tlv(0x31, &seq(&[oid(attr), tlv(0x0C, value.as_bytes())]));
+        let ecdsa_with_sha256 = seq(&[oid(&[0x2A, 0x86, 0x48, 0xCE, 0x3D, 0x04, 0x03, 0x02])]);
+        let name = seq(&[
+            rdn(&[0x55, 0x04, 0x0A], BINARY_NAME),
+            rdn(&[0x55, 0x04, 0x03], name),
+        ]);
+        let spki = seq(&[
+            seq(&[
+                oid(&[0x2A, 0x86, 0x48, 0xCE, 0x3D, 0x02, 0x01]),
+                oid(&[0x2A, 0x86, 0x48, 0xCE, 0x3D, 0x03, 0x01, 0x07]),
+            ]),
+            tlv(0x03, &[&[0][..], point].concat()),
+        ]);
+        // 1.3.6.1.4.1.41482.3.8
+        let policies = seq(&[
+            oid(&[0x2B, 0x06, 0x01, 0x04, 0x01, 0x82, 0xC4, 0x0A, 0x03, 0x08]),
+            tlv(0x04, &[0x01, 0x01]),
+        ]);
+        let tbs = seq(&[
+            tlv(0xA0, &tlv(0x02, &[2])),
+            tlv(0x02, &[1]),
+            ecdsa_with_sha256.clone(),
+            name.clone(),
+            seq(&[tlv(0x17, b"240101000000Z"), tlv(0x17, b"340101000000Z")]),
+            name,
+            spki,
+            tlv(0xA3, &seq(&[policies])),
+        ]);
+        seq(&[
+            tbs,
+            ecdsa_with_sha256,
+            tlv(0x03, &[0, 0x30, 0x06, 0x02, 0x01, 0x01, 0x02, 0x01, 0x01]),
+        ])
+    }
+
+    #[cfg(all(unix, feature = "emulated-yubikey"))]
+    #[test]
+    fn decrypt_with_emulated_yubikey() {
+        let slot = SlotId::Retired(RetiredSlotId::R1);
+        let mut card = piv::PivCard::new(12345678);
+        let point = card.generate(slot.into(), 0x01, 0x01);
+        let mut object = tlv(0x70, &certificate(&point, "emulated"));
+        object.extend(tlv(0x71, &[0]));
+        object.extend(tlv(0xFE, &[]));
+        card.put_object(RETIRED_CERT_OBJECT, object);
+
+        let dir = tempfile::tempdir().unwrap();
+        let socket = dir.path().join("yk-agentd.sock");
+        let (ready, listening) = std::sync::mpsc::channel();
+        let server = Arc::new(usbip::UsbIpServer::new_simulated(vec![piv::device(
+            0, card,
+        )]));
+        {
+            let socket = socket.clone();
+            std::thread::spawn(move || {
+                super::runtime().unwrap().block_on(async move {
+                    let listener = local::bind_unix(&socket).unwrap();
+                    ready.send(()).unwrap();
+                    local::unix_server_with_shutdown(listener, server, std::future::pending()).await
+                })
+            });
+        }
+        listening.recv().unwrap();
+
+        let config = RemoteConfig {
+            address: format!("unix:{}", socket.display()),
+            ..Default::default()
+        };
+        let mut yubikeys = RemoteYubiKey::open_all(&config).unwrap();
+        assert_eq!(yubikeys.len(), 1);
+        let mut yubikey = yubikeys.remove(0);
+        assert_eq!(yubikey.serial(), Serial(12345678));
+        assert_eq!(yubikey.pin_tries().unwrap(), 3);
+
+        let mut identities = yubikey.identities(&[slot], false);
+        assert_eq!(identities.len(), 1);
+        let (_, recipient, metadata) = identities.remove(0);
+        assert_eq!(
+            recipient.to_encoded(),
+            Recipient::from_sec1(&point).unwrap().to_encoded()
+        );
+        assert_eq!(metadata.name, "emulated");
+        assert_eq!(metadata.pin_policy, Some(PinPolicy::Never));
+
+        let file_key = FileKey::from([7; 16]);
+        let line = RecipientLine::wrap_file_key(&file_key, &recipient);
+        let mut conn = Connection::new(Box::new(yubikey), recipient, slot, 0);
+        let unwrapped = conn.unwrap_file_key(&line, || ()).unwrap();
+        assert_eq!(unwrapped.expose_secret(), &[7; 16]);
+        conn.disconnect_without_reset();
+    }
+}
@@ -0,0 +1,431 @@
+//! An ssh-agent for the P-256 and P-384 keys in connected YubiKeys.
+//!
+//! The keys that hold age identities are ordinary PIV keys, so they can also sign. The
+//! agent offers each compatible elliptic curve key in the usable slots to SSH clients
+//! as an ECDSA key, and asks for the PIN and touch the same way decryption does. It
+//! listens on a socket that only the current user can access, and prints the
+//! `SSH_AUTH_SOCK` setting that points SSH clients at it.
+//!
+//! The protocol is that of OpenSSH's agent: each message is a big-endian `u32` length,
+//! followed by a type byte and its contents. Only listing keys and signing are
+//! supported; other requests (such as adding keys) fail.
+
+use std::fs;
+use std::io::{self, Read, Write};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use age_core::secrecy::SecretString;
+use age_plugin::{identity, Callbacks};
+use dialoguer::Password;
+use sha2::{Digest, Sha256, Sha384};
+use tracing::debug;
+use yubikey::{piv::SlotId, reader::Context, Serial};
+
+use crate::{
+    broker::socket_path,
+    error::Error,
+    fl,
+    key::{self, Connection},
+    p256::{Curve, Recipient},
+    util::slot_name,
+};
+
+const SOCKET: &str = "ssh-agent.sock";
+
+const SSH_AGENT_FAILURE: u8 = 5;
+const SSH_AGENTC_REQUEST_IDENTITIES: u8 = 11;
+const SSH_AGENT_IDENTITIES_ANSWER: u8 = 12;
+const SSH_AGENTC_SIGN_REQUEST: u8 = 13;
+const SSH_AGENT_SIGN_RESPONSE: u8 = 14;
+
+/// The longest message that clients may send, as in OpenSSH.
+const MAX_MESSAGE_LEN: usize = 256 * 1024;
+
+/// A key that the agent offers to SSH clients.
+#[derive(Clone)]
+struct SshKey {
+    serial: Serial,
+    slot: SlotId,
+    recipient: Recipient,
+}
+
+impl SshKey {
+    /// Returns the SSH key type and curve name for `recipient`, if SSH can use it.
+    fn names(recipient: &Recipient) -> Option<(&'static str, &'static str)> {
+        match recipient.curve() {
+            Curve::P256 => Some(("ecdsa-sha2-nistp256", "nistp256")),
+            Curve::P384 => Some(("ecdsa-sha2-nistp384", "nistp384")),
+            _ => None,
+        }
+    }
+
+    /// Returns the public key in SSH's wire format.
+    fn blob(&self) -> Vec<u8> {
+        let (key_type, curve) = Self::names(&self.recipient).expect("SSH keys are ECDSA");
+        let mut blob = vec![];
+        put_string(&mut blob, key_type.as_bytes());
+        put_string(&mut blob, curve.as_bytes());
+        put_string(&mut blob, &self.recipient.to_sec1(false));
+        blob
+    }
+
+    fn comment(&self) -> String {
+        format!("YubiKey {} slot {}", self.serial, slot_name(self.slot))
+    }
+
+    /// Returns the hash of `data` that the YubiKey signs, as ECDSA for SSH requires.
+    fn digest(&self, data: &[u8]) -> Vec<u8> {
+        match self.recipient.curve() {
+            Curve::P384 => Sha384::digest(data).to_vec(),
+            _ => Sha256::digest(data).to_vec(),
+        }
+    }
+
+    /// Converts `der`, an ECDSA signature as the YubiKey returns it, into an SSH
+    /// signature.
+    fn signature(&self, der: &[u8]) -> Option<Vec<u8>> {
+        let (key_type, _) = Self::names(&self.recipient)?;
+
+        // The signature is a SEQUENCE of the INTEGERs r and s. DER integers are
+        // minimal two's complement, just like SSH's mpints.
+        let mut der = der;
+        let mut seq = read_der(&mut der, 0x30)?;
+        let r = read_der(&mut seq, 0x02)?;
+        let s = read_der(&mut seq, 0x02)?;
+        if !seq.is_empty() || !der.is_empty() {
+            return None;
+        }
+
+        let mut rs = vec![];
+        put_string(&mut rs, r);
+        put_string(&mut rs, s);
+        let mut signature = vec![];
+        put_string(&mut signature, key_type.as_bytes());
+        put_string(&mut signature, &rs);
+        Some(signature)
+    }
+}
+
+fn put_string(buf: &mut Vec<u8>, data: &[u8]) {
+    buf.extend_from_slice(&(data.len() as u32).to_be_bytes());
+    buf.extend_from_slice(data);
+}
+
+fn read_u32(buf: &mut &[u8]) -> Option<u32> {
+    let len = buf.get(..4)?.try_into().ok()?;
+    *buf = &buf[4..];
+    Some(u32::from_be_bytes(len))
+}
+
+fn read_string<'a>(buf: &mut &'a [u8]) -> Option<&'a [u8]> {
+    let len = read_u32(buf)? as usize;
+    if buf.len() < len {
+        return None;
+    }
+    let (data, rest) = buf.split_at(len);
+    *buf = rest;
+    Some(data)
+}
+
+/// Reads a DER element with the given tag, returning its contents.
+fn read_der<'a>(buf: &mut &'a [u8], tag: u8) -> Option<&'a [u8]> {
+    let (len, rest) = match **buf {
+        [t, len, ref rest @ ..] if t == tag => (len, rest),
+        _ => return None,
+    };
+    let (len, rest) = match len {
+        0x81 => {
+            let (&len, rest) = rest.split_first()?;
+            (len.into(), rest)
+        }
+        len if len < 0x80 => (len.into(), rest),
+        _ => return None,
+    };
+    if rest.len() < len {
+        return None;
+    }
+    let (data, rest) = rest.split_at(len);
+    *buf = rest;
+    Some(data)
+}
+
+/// Asks for PINs on the terminal that the agent runs in.
+struct TerminalCallbacks;
+
+impl Callbacks<identity::Error> for TerminalCallbacks {
+    fn message(&mut self, message: &str) -> age_core::plugin::Result<()> {
+        eprintln!("{}", message);
+        Ok(Ok(()))
+    }
+
+    fn confirm(&mut self, _: &str, _: &str, _: Option<&str>) -> age_core::plugin::Result<bool> {
+        Ok(Err(age_core::plugin::Error::Unsupported))
+    }
+
+    fn request_public(&mut self, _: &str) -> age_core::plugin::Result<String> {
+        Ok(Err(age_core::plugin::Error::Unsupported))
+    }
+
+    fn request_secret(&mut self, message: &str) -> age_core::plugin::Result<SecretString> {
+        Ok(Password::new()
+            .with_prompt(message)
+            .report(true)
+            .interact()
+            .map(SecretString::new)
+            .map_err(|_| age_core::plugin::Error::Fail))
+    }
+
+    fn error(&mut self, _: identity::Error) -> age_core::plugin::Result<()> {
+        Ok(Ok(()))
+    }
+}
+
+struct Agent {
+    serial: Option<Serial>,
+    /// The keys that were last listed, which are the ones clients can ask to sign with.
+    keys: Vec<SshKey>,
+}
+
+impl Agent {
+    /// Finds the keys in the connected YubiKeys (or only the one with [`Agent::serial`]).
+    fn refresh(&mut self) -> Result<(), Error> {
+        let mut readers = Context::open()?;
+        let mut keys = vec![];
+        for reader in readers.iter()?.filter(key::filter_connected) {
+            let mut yubikey = key::open_connection(&reader)?;
+            if self
+                .serial
+                .map_or(false, |serial| yubikey.serial() != serial)
+            {
+                continue;
+            }
+            let serial = yubikey.serial();
+            keys.extend(
+                key::list_compatible(&mut yubikey)?
+                    .filter(|(_, _, recipient)| SshKey::names(recipient).is_some())
+                    .map(|(_, slot, recipient)| SshKey {
+                        serial,
+                        slot,
+                        recipient,
+                    }),
+            );
+            key::disconnect_without_reset(yubikey);
+        }
+        self.keys = keys;
+        Ok(())
+    }
+
+    fn identities(&self) -> Vec<u8> {
+        let mut response = vec![SSH_AGENT_IDENTITIES_ANSWER];
+        response.extend_from_slice(&(self.keys.len() as u32).to_be_bytes());
+        for key in &self.keys {
+            put_string(&mut response, &key.blob());
+            put_string(&mut response, key.comment().as_bytes());
+        }
+        response
+    }
+
+    /// Signs `data` with `key`, asking for its PIN and touch if necessary.
+    fn sign(&self, key: &SshKey, data: &[u8]) -> Result<Vec<u8>, String> {
+        let yubikey = key::open_by_serial(key.serial).map_err(|e| e.to_string())?;
+        let mut conn = Connection::new(Box::new(yubikey), key.recipient.clone(), key.slot, 0);
+        let res = match conn.request_pin_if_necessary(&mut TerminalCallbacks) {
+            Ok(Ok(())) => conn
+                .sign(&key.digest(data), || {
+                    eprintln!(
+                        "{}",
+                        fl!("plugin-touch-yk", yubikey_serial = key.serial.to_string())
+                    )
+                })
+                .map_err(|e| format!("{:?}", e))
+                .and_then(|der| {
+                    key.signature(&der)
+                        .ok_or_else(|| "invalid signature".to_string())
+                }),
+            Ok(Err(
+                identity::Error::Identity { message, .. }
+                | identity::Error::Internal { message }
+                | identity::Error::Stanza { message, .. },
+            )) => Err(message),
+            Err(e) => Err(e.to_string()),
+        };
+        conn.disconnect_without_reset();
+        res
+    }
+
+    /// Returns the response to `request`.
+    fn handle(&mut self, request: &[u8]) -> Vec<u8> {
+        let (&kind, mut body) = match request.split_first() {
+            Some(parts) => parts,
+            None => return vec![SSH_AGENT_FAILURE],
+        };
+        match kind {
+            SSH_AGENTC_REQUEST_IDENTITIES => {
+                if let Err(e) = self.refresh() {
+                    debug!("Could not list YubiKey keys: {}", e);
+                }
+                self.identities()
+            }
+            SSH_AGENTC_SIGN_REQUEST => {
+                // The flags only select RSA signature algorithms, so they are ignored.
+                let (blob, data) = match (read_string(&mut body), read_string(&mut body)) {
+                    (Some(blob), Some(data)) => (blob, data),
+                    _ => return vec![SSH_AGENT_FAILURE],
+                };
+                let key = match self.keys.iter().find(|key| key.blob() == blob) {
+                    Some(key) => key,
+                    None => return vec![SSH_AGENT_FAILURE],
+                };
+                match self.sign(key, data) {
+                    Ok(signature) => {
+                        let mut response = vec![SSH_AGENT_SIGN_RESPONSE];
+                        put_string(&mut response, &signature);
+                        response
+                    }
+                    Err(e) => {
+                        eprintln!(
+                            "{}",
+                            fl!(
+                                "ssh-agent-sign-failed",
+                                slot = slot_name(key.slot),
+                                yubikey_serial = key.serial.to_string(),
+                                err = e,
+                            )
+                        );
+                        vec![SSH_AGENT_FAILURE]
+                    }
+                }
+            }
+            _ => vec![SSH_AGENT_FAILURE],
+        }
+    }
+}
+
+fn serve(mut stream: UnixStream, agent: &Mutex<Agent>) -> io::Result<()> {
+    loop {
+        let mut len = [0; 4];
+        match stream.read_exact(&mut len) {
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(()),
+            res => res?,
+        }
+        let len = u32::from_be_bytes(len) as usize;
+        if len > MAX_MESSAGE_LEN {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "message too long",
+            ));
+        }
+        let mut request = vec![0; len];
+        stream.read_exact(&mut request)?;
+
+        let response = agent.lock().unwrap().handle(&request);
+        stream.write_all(&(response.len() as u32).to_be_bytes())?;
+        stream.write_all(&response)?;
+    }
+}
+
+/// Runs the agent for the YubiKey with `serial`, or all connected YubiKeys, until it
+/// is stopped.
+pub(crate) fn run(serial: Option<Serial>) -> Result<(), Error> {
+    let path = socket_path(SOCKET)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::Other, "no usable socket directory"))?;
+    if UnixStream::connect(&path).is_ok() {
+        return Err(io::Error::new(
+            io::ErrorKind::AddrInUse,
+            format!("an agent is already listening on {}", path.display()),
+        )
+        .into());
+    }
+    let _ = fs::remove_file(&path);
+    let listener = UnixListener::bind(&path)?;
+
+    let mut agent = Agent {
+        serial,
+        keys: vec![],
+    };
+    agent.refresh()?;
+    eprintln!("{}", fl!("ssh-agent-listening", count = agent.keys.len()));
+    println!("SSH_AUTH_SOCK={}; export SSH_AUTH_SOCK;", path.display());
+
+    // Clients such as ssh keep their connection open, so each gets its own thread. The
+    // YubiKeys are used by one client at a time.
+    let agent = Arc::new(Mutex::new(agent));
+    for stream in listener.incoming() {
+        let stream = stream?;
+        let agent = agent.clone();
+        thread::spawn(move || {
+            if let Err(e) = serve(stream, &agent) {
+                debug!("SSH agent client failed: {}", e);
+            }
+        });
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use yubikey::piv::{RetiredSlotId, SlotId};
+
+    use super::{read_string, Agent, SshKey, SSH_AGENT_FAILURE, SSH_AGENT_IDENTITIES_ANSWER};
+    use crate::p256::Recipient;
+
+    fn p256_key() -> SshKey {
+        let point = hex::decode(
+            "046b17d1f2e12c4247f8bce6e563a440f277037d812deb33a0f4a13945d898c296\
+             4fe342e2fe1a7f9b8ee7eb4a7c0f9e162bce33576b315ececbb6406837bf51f5",
+        )
+        .unwrap();
+        SshKey {
+            serial: 12345678.into(),
+            slot: SlotId::Retired(RetiredSlotId::R1),
+            recipient: Recipient::from_public_key(&hex::encode(point)).unwrap(),
+        }
+    }
+
+    #[test]
+    fn encoding() {
+        let key = p256_key();
+        let blob = key.blob();
+        let mut rest = blob.as_slice();
+        assert_eq!(read_string(&mut rest), Some(&b"ecdsa-sha2-nistp256"[..]));
+        assert_eq!(read_string(&mut rest), Some(&b"nistp256"[..]));
+        assert_eq!(read_string(&mut rest).map(<[u8]>::len), Some(65));
+        assert!(rest.is_empty());
+        assert_eq!(key.comment(), "YubiKey 12345678 slot 1");
+
+        // r has its high bit set, so it keeps the DER leading zero as an mpint.
+        let der = [0x30, 0x08, 0x02, 0x03, 0x00, 0x80, 0x01, 0x02, 0x01, 0x7f];
+        let signature = key.signature(&der).unwrap();
+        let mut rest = signature.as_slice();
+        assert_eq!(read_string(&mut rest), Some(&b"ecdsa-sha2-nistp256"[..]));
+        let rs = read_string(&mut rest).unwrap();
+        assert_eq!(rs, [0, 0, 0, 3, 0x00, 0x80, 0x01, 0, 0, 0, 1, 0x7f]);
+        assert!(rest.is_empty());
+
+        // Truncated or trailing data is rejected.
+        assert_eq!(key.signature(&der[..9]), None);
+        assert_eq!(key.signature(&[&der[..], &[0]].concat()), None);
+    }
+
+    #[test]
+    fn handle() {
+        let mut agent = Agent {
+            serial: None,
+            keys: vec![p256_key()],
+        };
+        let identities = agent.identities();
+        assert_eq!(identities[0], SSH_AGENT_IDENTITIES_ANSWER);
+        assert_eq!(identities[1..5], 1u32.to_be_bytes());
+
+        // Unsupported requests, malformed requests and unknown keys fail.
+        assert_eq!(agent.handle(&[]), [SSH_AGENT_FAILURE]);
+        assert_eq!(agent.handle(&[17]), [SSH_AGENT_FAILURE]);
+        assert_eq!(agent.handle(&[13, 0, 0]), [SSH_AGENT_FAILURE]);
+        assert_eq!(
+            agent.handle(&[13, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0]),
+            [SSH_AGENT_FAILURE]
+        );
+    }
+}
@@ -1,5 +1,13 @@
 use std::fmt;
+use std::fs;
+use std::io;
 use std::iter;
+use std::ops::Deref;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use age_core::secrecy::zeroize::{Zeroize, Zeroizing};
+use serde::Serialize;
+use tracing::debug;
 
 use x509_parser::{certificate::X509Certificate, der_parser::oid::Oid};
 use yubikey::{
@@ -8,16 +16,74 @@ use yubikey::{
 };
 
 use crate::fl;
-use crate::{error::Error, key::Stub, p256::Recipient, BINARY_NAME, USABLE_SLOTS};
+use crate::{
+    error::Error,
+    key::{Stub, NO_SERIAL},
+    p256::Recipient,
+};
+
+pub(crate) const PLUGIN_NAME: &str = "yubikey";
+pub(crate) const BINARY_NAME: &str = "age-plugin-yubikey";
+pub(crate) const RECIPIENT_PREFIX: &str = "age1yubikey";
+pub(crate) const IDENTITY_PREFIX: &str = "age-plugin-yubikey-";
+
+pub(crate) const USABLE_SLOTS: [RetiredSlotId; 20] = [
+    RetiredSlotId::R1,
+    RetiredSlotId::R2,
+    RetiredSlotId::R3,
+    RetiredSlotId::R4,
+    RetiredSlotId::R5,
+    RetiredSlotId::R6,
+    RetiredSlotId::R7,
+    RetiredSlotId::R8,
+    RetiredSlotId::R9,
+    RetiredSlotId::R10,
+    RetiredSlotId::R11,
+    RetiredSlotId::R12,
+    RetiredSlotId::R13,
+    RetiredSlotId::R14,
+    RetiredSlotId::R15,
+    RetiredSlotId::R16,
+    RetiredSlotId::R17,
+    RetiredSlotId::R18,
+    RetiredSlotId::R19,
+    RetiredSlotId::R20,
+];
+
+/// The standard PIV slots (9a, 9c, 9d and 9e). Other tools provision keys in these for
+/// their own purposes, so we only use them with `--allow-standard-slots`.
+pub(crate) const STANDARD_SLOTS: [SlotId; 4] = [
+    SlotId::Authentication,
+    SlotId::Signature,
+    SlotId::KeyManagement,
+    SlotId::CardAuthentication,
+];
+
+/// Whether keys in [`STANDARD_SLOTS`] are listed alongside those in [`USABLE_SLOTS`].
+static ALLOW_STANDARD_SLOTS: AtomicBool = AtomicBool::new(false);
 
 pub(crate) const POLICY_EXTENSION_OID: &[u64] = &[1, 3, 6, 1, 4, 1, 41482, 3, 8];
 
 pub(crate) fn ui_to_slot(slot: u8) -> Result<RetiredSlotId, Error> {
     // Use 1-indexing in the UI for niceness
-    USABLE_SLOTS
-        .get(slot as usize - 1)
+    (slot as usize)
+        .checked_sub(1)
+        .and_then(|i| USABLE_SLOTS.get(i))
         .cloned()
-        .ok_or(Error::InvalidSlot(slot))
+        .ok_or_else(|| Error::InvalidSlot(slot.to_string()))
+}
+
+/// Parses a slot as [`slot_name`] shows it: 1 to 20 for the retired slots, or the hex
+/// slot number of a standard slot (such as `9d`) if `allow_standard` is set.
+pub(crate) fn parse_slot(slot: &str, allow_standard: bool) -> Result<SlotId, Error> {
+    if let Ok(number) = slot.parse::<u8>() {
+        return ui_to_slot(number).map(SlotId::Retired);
+    }
+    STANDARD_SLOTS
+        .iter()
+        .find(|&&standard| allow_standard && slot.eq_ignore_ascii_case(&slot_name(standard)))
+        .cloned()
+        .ok_or_else(|| Error::InvalidSlot(slot.into()))
 }
 
 pub(crate) fn slot_to_ui(slot: &RetiredSlotId) -> u8 {
@@ -25,6 +91,52 @@ pub(crate) fn slot_to_ui(slot: &RetiredSlotId) -> u8 {
     USABLE_SLOTS.iter().position(|s| s == slot).unwrap() as u8 + 1
 }
 
+/// Sets whether keys in the standard PIV slots are listed as identities.
+pub(crate) fn allow_standard_slots(allow: bool) {
+    ALLOW_STANDARD_SLOTS.store(allow, Ordering::Relaxed);
+}
+
+/// Returns the slots that can hold identities, which are the retired slots and, if
+/// allowed, the standard slots.
+pub(crate) fn identity_slots() -> Vec<SlotId> {
+    let standard = if ALLOW_STANDARD_SLOTS.load(Ordering::Relaxed) {
+        &STANDARD_SLOTS[..]
+    } else {
+        &[]
+    };
+    USABLE_SLOTS
+        .iter()
+        .map(|&slot| SlotId::Retired(slot))
+        .chain(standard.iter().cloned())
+        .collect()
+}
+
+/// Returns `true` if identity stubs can refer to `slot`.
+///
+/// Stubs for the standard slots were only printed because the user allowed them, so
+/// they are accepted whatever [`allow_standard_slots`] says.
+pub(crate) fn is_identity_slot(slot: SlotId) -> bool {
+    matches!(slot, SlotId::Retired(_)) || STANDARD_SLOTS.contains(&slot)
+}
+
+/// Returns how `slot` is shown to the user: 1 to 20 for the retired slots, and the
+/// hex slot number (such as `9d`) for the standard slots.
+pub(crate) fn slot_name(slot: SlotId) -> String {
+    match slot {
+        SlotId::Retired(slot) => slot_to_ui(&slot).to_string(),
+        slot => format!("{:x}", u8::from(slot)),
+    }
+}
+
+/// Returns how `slot` is shown in JSON: a number for the retired slots, and a string
+/// such as `"9d"` for the standard slots.
+pub(crate) fn slot_json(slot: SlotId) -> serde_json::Value {
+    match slot {
+        SlotId::Retired(slot) => slot_to_ui(&slot).into(),
+        slot => slot_name(slot).into(),
+    }
+}
+
 pub(crate) fn pin_policy_from_string(s: String) -> Result<PinPolicy, Error> {
     match s.as_str() {
         "always" => Ok(PinPolicy::Always),
@@ -61,6 +173,90 @@ pub(crate) fn touch_policy_to_str(policy: Option<TouchPolicy>) -> String {
     }
 }
 
+/// Returns the policy name accepted by `--pin-policy`, for machine-readable output.
+pub(crate) fn pin_policy_to_key(policy: Option<PinPolicy>) -> &'static str {
+    match policy {
+        Some(PinPolicy::Always) => "always",
+        Some(PinPolicy::Once) => "once",
+        Some(PinPolicy::Never) => "never",
+        _ => "unknown",
+    }
+}
+
+/// Returns the policy name accepted by `--touch-policy`, for machine-readable output.
+pub(crate) fn touch_policy_to_key(policy: Option<TouchPolicy>) -> &'static str {
+    match policy {
+        Some(TouchPolicy::Always) => "always",
+        Some(TouchPolicy::Cached) => "cached",
+        Some(TouchPolicy::Never) => "never",
+        _ => "unknown",
+    }
+}
+
+/// A heap-allocated secret (a PIN, or an unwrapped file key) that is zeroized when
+/// dropped, including on early returns and `?` error paths.
+///
+/// Where the platform allows it, the pages holding the secret are also locked into
+/// RAM so that it cannot be written to swap. Locking is best-effort; most systems
+/// cap the amount of memory an unprivileged process can lock.
+pub(crate) struct LockedSecret<T: Zeroize + AsRef<[u8]>> {
+    // Declared first so the pages are unlocked before the buffer is freed, but only
+    // after `drop` has zeroized the secret.
+    _lock: Option<region::LockGuard>,
+    secret: Zeroizing<T>,
+}
+
+impl<T: Zeroize + AsRef<[u8]>> LockedSecret<T> {
+    /// Takes ownership of `secret`.
+    ///
+    /// `T` must own a heap buffer (`String`, `Vec<u8>`), which doesn't move when
+    /// `secret` does.
+    pub(crate) fn new(secret: T) -> Self {
+        let bytes = secret.as_ref();
+        let lock = match bytes.len() {
+            0 => None,
+            len => region::lock(bytes.as_ptr(), len)
+                .map_err(|e| debug!("Could not lock secret in memory: {}", e))
+                .ok(),
+        };
+        LockedSecret {
+            _lock: lock,
+            secret: Zeroizing::new(secret),
+        }
+    }
+}
+
+impl<T: Zeroize + AsRef<[u8]>> Drop for LockedSecret<T> {
+    fn drop(&mut self) {
+        // Fields are dropped in declaration order, so without this the pages would be
+        // unlocked (and could be swapped out) while they still hold the secret.
+        self.secret.zeroize();
+    }
+}
+
+impl<T: Zeroize + AsRef<[u8]>> Deref for LockedSecret<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.secret
+    }
+}
+
+/// Reads everything from the file descriptor `fd`, which the user gave us.
+#[cfg(unix)]
+pub(crate) fn read_fd(fd: u32) -> io::Result<String> {
+    // Opening the descriptor by path avoids needing `unsafe` for `File::from_raw_fd`.
+    fs::read_to_string(format!("/dev/fd/{fd}"))
+}
+
+#[cfg(not(unix))]
+pub(crate) fn read_fd(_: u32) -> io::Result<String> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "reading from file descriptors is only supported on Unix",
+    ))
+}
+
 const MODHEX: &str = "cbdefghijklnrtuv";
 pub(crate) fn otp_serial_prefix(serial: Serial) -> String {
     iter::repeat(0)
@@ -99,78 +295,101 @@ pub(crate) fn extract_name(cert: &X509Certificate, all: bool) -> Option<(String,
     }
 }
 
-pub(crate) struct Metadata {
-    serial: Serial,
-    slot: RetiredSlotId,
-    name: String,
-    created: String,
+/// Extracts the PIN and touch policies from a certificate for an identity, or a PIV
+/// attestation.
+pub(crate) fn extract_policies(c: &X509Certificate) -> (Option<PinPolicy>, Option<TouchPolicy>) {
+    // We store the PIN and touch policies for identities in their certificates
+    // using the same certificate extension as PIV attestations.
+    // https://developers.yubico.com/PIV/Introduction/PIV_attestation.html
+    c.tbs_certificate
+        .get_extension_unique(&Oid::from(POLICY_EXTENSION_OID).unwrap())
+        // If the extension is duplicated, we assume it is invalid.
+        .ok()
+        .flatten()
+        // If the encoded extension doesn't have 2 bytes, we assume it is invalid.
+        .filter(|policy| policy.value.len() >= 2)
+        .map(|policy| {
+            // We should only ever see one of three values for either policy, but
+            // handle unknown values just in case.
+            let pin_policy = match policy.value[0] {
+                0x01 => Some(PinPolicy::Never),
+                0x02 => Some(PinPolicy::Once),
+                0x03 => Some(PinPolicy::Always),
+                _ => None,
+            };
+            let touch_policy = match policy.value[1] {
+                0x01 => Some(TouchPolicy::Never),
+                0x02 => Some(TouchPolicy::Always),
+                0x03 => Some(TouchPolicy::Cached),
+                _ => None,
+            };
+            (pin_policy, touch_policy)
+        })
+        .unwrap_or((None, None))
+}
+
+pub struct Metadata {
+    pub(crate) serial: Serial,
+    pub(crate) slot: SlotId,
+    pub(crate) name: String,
+    pub(crate) created: String,
     pub(crate) pin_policy: Option<PinPolicy>,
     pub(crate) touch_policy: Option<TouchPolicy>,
+    pub(crate) firmware: Option<String>,
 }
 
 impl Metadata {
     pub(crate) fn extract(
         yubikey: &mut YubiKey,
-        slot: RetiredSlotId,
+        slot: SlotId,
         cert: &Certificate,
         all: bool,
     ) -> Option<Self> {
-        let (_, cert) = x509_parser::parse_x509_certificate(cert.as_ref()).ok()?;
-
-        // We store the PIN and touch policies for identities in their certificates
-        // using the same certificate extension as PIV attestations.
-        // https://developers.yubico.com/PIV/Introduction/PIV_attestation.html
-        let policies = |c: &X509Certificate| {
-            c.tbs_certificate
-                .get_extension_unique(&Oid::from(POLICY_EXTENSION_OID).unwrap())
-                // If the extension is duplicated, we assume it is invalid.
+        let serial = yubikey.serial();
+        let firmware = yubikey.version().to_string();
+        Self::extract_with(serial, slot, cert, all, || {
+            yubikey::piv::attest(yubikey, slot)
                 .ok()
-                .flatten()
-                // If the encoded extension doesn't have 2 bytes, we assume it is invalid.
-                .filter(|policy| policy.value.len() >= 2)
-                .map(|policy| {
-                    // We should only ever see one of three values for either policy, but
-                    // handle unknown values just in case.
-                    let pin_policy = match policy.value[0] {
-                        0x01 => Some(PinPolicy::Never),
-                        0x02 => Some(PinPolicy::Once),
-                        0x03 => Some(PinPolicy::Always),
-                        _ => None,
-                    };
-                    let touch_policy = match policy.value[1] {
-                        0x01 => Some(TouchPolicy::Never),
-                        0x02 => Some(TouchPolicy::Always),
-                        0x03 => Some(TouchPolicy::Cached),
-                        _ => None,
-                    };
-                    (pin_policy, touch_policy)
-                })
-                .unwrap_or((None, None))
-        };
+                .map(|buf| buf.to_vec())
+        })
+        .map(|metadata| Metadata {
+            firmware: Some(firmware),
+            ..metadata
+        })
+    }
+
+    /// Extracts metadata from `cert`, using `attest` to obtain an attestation for keys
+    /// that were not generated by this plugin.
+    pub(crate) fn extract_with(
+        serial: Serial,
+        slot: SlotId,
+        cert: &Certificate,
+        all: bool,
+        attest: impl FnOnce() -> Option<Vec<u8>>,
+    ) -> Option<Self> {
+        let (_, cert) = x509_parser::parse_x509_certificate(cert.as_ref()).ok()?;
 
         extract_name(&cert, all)
             .map(|(name, ours)| {
                 if ours {
-                    let (pin_policy, touch_policy) = policies(&cert);
+                    let (pin_policy, touch_policy) = extract_policies(&cert);
                     (name, pin_policy, touch_policy)
                 } else {
                     // We can extract the PIN and touch policies via an attestation. This
                     // is slow, but the user has asked for all compatible keys, so...
-                    let (pin_policy, touch_policy) =
-                        yubikey::piv::attest(yubikey, SlotId::Retired(slot))
-                            .ok()
-                            .and_then(|buf| {
-                                x509_parser::parse_x509_certificate(&buf)
-                                    .map(|(_, c)| policies(&c))
-                                    .ok()
-                            })
-                            .unwrap_or((None, None));
+                    let (pin_policy, touch_policy) = attest()
+                        .and_then(|buf| {
+                            x509_parser::parse_x509_certificate(&buf)
+                                .map(|(_, c)| extract_policies(&c))
+                                .ok()
+                        })
+                        .unwrap_or((None, None));
 
                     (name, pin_policy, touch_policy)
                 }
             })
             .map(|(name, pin_policy, touch_policy)| Metadata {
-                serial: yubikey.serial(),
+                serial,
                 slot,
                 name,
                 created: cert
@@ -180,8 +399,68 @@ impl Metadata {
                     .unwrap_or_else(|e| format!("Invalid date: {e}")),
                 pin_policy,
                 touch_policy,
+                firmware: None,
             })
     }
+
+    /// Returns this metadata as space-separated `key=value` pairs that do not depend
+    /// on the user's language, for scripts that parse our output.
+    pub(crate) fn to_fields(&self) -> String {
+        let mut fields = format!(
+            "serial={} slot={} pin_policy={} touch_policy={}",
+            self.serial,
+            match self.slot {
+                SlotId::Retired(slot) => format!("{:?}", slot),
+                slot => slot_name(slot),
+            },
+            pin_policy_to_key(self.pin_policy),
+            touch_policy_to_key(self.touch_policy),
+        );
+        if let Some(firmware) = &self.firmware {
+            fields.push_str(" firmware=");
+            fields.push_str(firmware);
+        }
+        fields
+    }
+
+    /// Returns the PKCS #11 URI (RFC 7512) of the key that this metadata describes, as
+    /// Yubico's YKCS11 module exposes it to OpenSSL, NSS and other PKCS #11 users.
+    pub(crate) fn to_pkcs11_uri(&self) -> String {
+        // YKCS11 numbers the key objects 1 to 4 for the standard slots, and 5 to 24 for
+        // the retired slots.
+        let (id, label) = match self.slot {
+            SlotId::Authentication => (1, "PIV Authentication".to_string()),
+            SlotId::Signature => (2, "Digital Signature".to_string()),
+            SlotId::KeyManagement => (3, "Key Management".to_string()),
+            SlotId::CardAuthentication => (4, "Card Authentication".to_string()),
+            SlotId::Retired(slot) => {
+                let slot = slot_to_ui(&slot);
+                (4 + slot, format!("Retired Key {}", slot))
+            }
+            slot => (u8::from(slot), slot_name(slot)),
+        };
+        format!(
+            "pkcs11:manufacturer={};serial={};token={};id=%{:02X};object={};type=private",
+            pkcs11_escape("Yubico (www.yubico.com)"),
+            self.serial,
+            pkcs11_escape(&format!("YubiKey PIV #{}", self.serial)),
+            id,
+            pkcs11_escape(&format!("Private key for {}", label)),
+        )
+    }
+}
+
+/// Percent-encodes `value` for a PKCS #11 URI attribute.
+fn pkcs11_escape(value: &str) -> String {
+    value
+        .bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                (b as char).to_string()
+            }
+            b => format!("%{:02X}", b),
+        })
+        .collect()
 }
 
 impl fmt::Display for Metadata {
@@ -192,30 +471,124 @@ impl fmt::Display for Metadata {
             fl!(
                 "yubikey-metadata",
                 serial = self.serial.to_string(),
-                slot = slot_to_ui(&self.slot),
+                slot = slot_name(self.slot),
                 name = self.name.as_str(),
                 created = self.created.as_str(),
                 pin_policy = pin_policy_to_str(self.pin_policy),
                 touch_policy = touch_policy_to_str(self.touch_policy),
             )
-        )
+        )?;
+        write!(f, "\n# {}", self.to_fields())
+    }
+}
+
+/// The machine-readable form of an identity, printed by `--json`.
+///
+/// The field names and values are part of our stable interface, and do not depend on
+/// the user's language.
+#[derive(Serialize)]
+pub(crate) struct IdentityJson {
+    /// `None` for YubiKeys that don't expose their serial.
+    serial: Option<u32>,
+    /// 1 to 20 for the retired slots, or a string such as `"9d"` for the standard slots.
+    slot: serde_json::Value,
+    name: String,
+    created: String,
+    pin_policy: &'static str,
+    touch_policy: &'static str,
+    algorithm: &'static str,
+    firmware: Option<String>,
+    recipient: String,
+    identity: String,
+}
+
+impl IdentityJson {
+    pub(crate) fn new(stub: &Stub, recipient: &Recipient, metadata: Metadata) -> Self {
+        IdentityJson {
+            serial: Some(metadata.serial.0).filter(|&serial| serial != NO_SERIAL),
+            slot: slot_json(metadata.slot),
+            name: metadata.name,
+            created: metadata.created,
+            pin_policy: pin_policy_to_key(metadata.pin_policy),
+            touch_policy: touch_policy_to_key(metadata.touch_policy),
+            algorithm: recipient.curve().name(),
+            firmware: metadata.firmware,
+            recipient: recipient.to_string(),
+            identity: stub.to_string(),
+        }
     }
 }
 
 pub(crate) fn print_identity(stub: Stub, recipient: Recipient, metadata: Metadata) {
-    let recipient = recipient.to_string();
     if !console::user_attended() {
-        let recipient = recipient.as_str();
-        eprintln!("{}", fl!("print-recipient", recipient = recipient));
+        let recipient = recipient.to_string();
+        eprintln!("{}", fl!("print-recipient", recipient = recipient.as_str()));
     }
 
-    println!(
-        "{}",
-        fl!(
-            "yubikey-identity",
-            yubikey_metadata = metadata.to_string(),
-            recipient = recipient,
-            identity = stub.to_string(),
-        )
-    );
+    println!("{}", format_identity(&stub, &recipient, &metadata));
+}
+
+/// Formats an identity as it appears in an identity file, with its metadata and
+/// recipient in comments.
+pub(crate) fn format_identity(stub: &Stub, recipient: &Recipient, metadata: &Metadata) -> String {
+    fl!(
+        "yubikey-identity",
+        yubikey_metadata = metadata.to_string(),
+        recipient = recipient.to_string(),
+        identity = stub.to_string(),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use yubikey::{
+        piv::{RetiredSlotId, SlotId},
+        Serial,
+    };
+
+    use super::{parse_slot, Metadata};
+
+    #[test]
+    fn pkcs11_uri() {
+        let mut metadata = Metadata {
+            serial: Serial(12345678),
+            slot: SlotId::Retired(RetiredSlotId::R3),
+            name: "age identity 1234abcd".into(),
+            created: String::new(),
+            pin_policy: None,
+            touch_policy: None,
+            firmware: None,
+        };
+        assert_eq!(
+            metadata.to_pkcs11_uri(),
+            "pkcs11:manufacturer=Yubico%20%28www.yubico.com%29;serial=12345678;\
+             token=YubiKey%20PIV%20%2312345678;id=%07;\
+             object=Private%20key%20for%20Retired%20Key%203;type=private",
+        );
+
+        metadata.slot = SlotId::KeyManagement;
+        assert!(metadata
+            .to_pkcs11_uri()
+            .ends_with(";id=%03;object=Private%20key%20for%20Key%20Management;type=private"));
+    }
+
+    #[test]
+    fn slot_names() {
+        assert_eq!(
+            parse_slot("3", false).unwrap(),
+            SlotId::Retired(RetiredSlotId::R3)
+        );
+        assert_eq!(
+            parse_slot("20", true).unwrap(),
+            SlotId::Retired(RetiredSlotId::R20)
+        );
+        assert_eq!(parse_slot("9d", true).unwrap(), SlotId::KeyManagement);
+        assert_eq!(parse_slot("9A", true).unwrap(), SlotId::Authentication);
+
+        // The standard slots need --allow-standard-slots.
+        assert!(parse_slot("9d", false).is_err());
+        for slot in ["0", "21", "9b", "f9", "retired"] {
+            assert!(parse_slot(slot, true).is_err(), "{slot}");
+        }
+    }
 }
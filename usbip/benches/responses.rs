@@ -0,0 +1,91 @@
+//! Throughput of writing bulk IN responses to a socket, one [Vec] and write
+//! per URB as before, against batches encoded into buffers of a
+//! [BufferPool]
+//!
+//! ```bash
+//! $ cargo bench --bench responses
+//! ```
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use std::io::{Read, Write};
+use std::net::{TcpListener, TcpStream};
+use usbip::buffer::{BufferPool, BUFFER_CAPACITY};
+use usbip::usbip_protocol::{UsbIpHeaderBasic, UsbIpResponse, USBIP_RET_SUBMIT};
+
+/// URBs written per iteration
+const URBS: usize = 64;
+
+fn responses(length: usize) -> Vec<UsbIpResponse> {
+    (0..URBS as u32)
+        .map(|seqnum| UsbIpResponse::UsbIpRetSubmit {
+            header: UsbIpHeaderBasic {
+                command: USBIP_RET_SUBMIT.into(),
+                seqnum,
+                devid: 0,
+                direction: 1,
+                ep: 1,
+            },
+            status: 0,
+            actual_length: length as u32,
+            start_frame: 0,
+            number_of_packets: 0,
+            error_count: 0,
+            transfer_buffer: vec![0xAA; length],
+            iso_packet_descriptor: vec![],
+        })
+        .collect()
+}
+
+/// A loopback TCP connection whose other end discards what it reads
+fn socket() -> TcpStream {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let socket = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+    socket.set_nodelay(true).unwrap();
+    let (mut peer, _) = listener.accept().unwrap();
+    std::thread::spawn(move || {
+        let mut buf = vec![0; 1 << 20];
+        while matches!(peer.read(&mut buf), Ok(n) if n > 0) {}
+    });
+    socket
+}
+
+fn write_responses(c: &mut Criterion) {
+    let mut group = c.benchmark_group("write_responses");
+    for length in [64, 512, 4096, 65536] {
+        let responses = responses(length);
+        group.throughput(Throughput::Bytes((URBS * length) as u64));
+        group.bench_with_input(
+            BenchmarkId::new("to_bytes", length),
+            &responses,
+            |b, res| {
+                let mut socket = socket();
+                b.iter(|| {
+                    for res in res {
+                        socket.write_all(&res.to_bytes()).unwrap();
+                    }
+                })
+            },
+        );
+        group.bench_with_input(BenchmarkId::new("pooled", length), &responses, |b, res| {
+            let pool = BufferPool::default();
+            let mut socket = socket();
+            b.iter(|| {
+                let mut res = res.iter().peekable();
+                while res.peek().is_some() {
+                    let mut buf = pool.get();
+                    while buf.len() < BUFFER_CAPACITY {
+                        match res.next() {
+                            Some(res) => res.encode(&mut buf),
+                            None => break,
+                        }
+                    }
+                    socket.write_all(&buf).unwrap();
+                    pool.put(buf);
+                }
+            })
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, write_responses);
+criterion_main!(benches);
@@ -0,0 +1,90 @@
+use std::io::Result;
+use std::net::*;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tracing::*;
+use usbip::ccid::{CcidBackend, UsbCcidHandler};
+
+/// A card that only knows SELECT and GET CHALLENGE
+struct VirtualCard {
+    present: bool,
+    counter: u8,
+}
+
+impl CcidBackend for VirtualCard {
+    fn power_on(&mut self) -> Result<Vec<u8>> {
+        // T=1, no historical bytes
+        Ok(vec![0x3B, 0x80, 0x80, 0x01, 0x01])
+    }
+
+    fn power_off(&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    fn transmit(&mut self, apdu: &[u8]) -> Result<Vec<u8>> {
+        info!("APDU {:02x?}", apdu);
+        Ok(match apdu {
+            // SELECT: any application
+            [0x00, 0xA4, 0x04, ..] => vec![0x90, 0x00],
+            // GET CHALLENGE
+            [0x00, 0x84, 0x00, 0x00, le] => {
+                let mut response: Vec<u8> = (0..*le)
+                    .map(|_| {
+                        self.counter = self.counter.wrapping_add(1);
+                        self.counter
+                    })
+                    .collect();
+                response.extend([0x90, 0x00]);
+                response
+            }
+            // INS not supported
+            _ => vec![0x6D, 0x00],
+        })
+    }
+
+    fn present(&mut self) -> bool {
+        self.present
+    }
+}
+
+#[tokio::main]
+async fn main() {
+    env_logger::init();
+    let handler = Arc::new(Mutex::new(Box::new(UsbCcidHandler::new(VirtualCard {
+        present: true,
+        counter: 0,
+    }))
+        as Box<dyn usbip::UsbInterfaceHandler + Send>));
+    let server = Arc::new(usbip::UsbIpServer::new_simulated(vec![
+        usbip::UsbDevice::new(0).with_interface(
+            usbip::ClassCode::SmartCard as u8,
+            usbip::ccid::CCID_SUBCLASS,
+            0x00,
+            "Virtual CCID",
+            UsbCcidHandler::<VirtualCard>::endpoints(),
+            handler.clone(),
+        ),
+    ]));
+    let addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0)), 3240);
+    tokio::spawn(usbip::server(addr, server));
+
+    loop {
+        // sleep 30s
+        tokio::time::sleep(Duration::new(30, 0)).await;
+        let mut handler = handler.lock().unwrap();
+        if let Some(ccid) = handler
+            .as_any()
+            .downcast_mut::<UsbCcidHandler<VirtualCard>>()
+        {
+            ccid.backend.present = !ccid.backend.present;
+            info!(
+                "Simulate card {}",
+                if ccid.backend.present {
+                    "insertion"
+                } else {
+                    "removal"
+                }
+            );
+        }
+    }
+}
@@ -1,7 +1,7 @@
-use log::*;
 use std::net::*;
 use std::sync::{Arc, Mutex};
 use std::time::Duration;
+use tracing::*;
 
 #[tokio::main]
 async fn main() {
@@ -0,0 +1,106 @@
+//! Import a device from a USB/IP server.
+//!
+//! Usage: `client HOST:PORT [BUSID [--vhci] | --events]` or `client discover`
+//!
+//! Without a bus id, lists the devices exported by the server. With one,
+//! imports the device and prints its device descriptor, or with `--vhci`
+//! attaches it to the local vhci-hcd (Linux, as root) like `usbip attach`.
+//! With `--events`, prints the server's events as they come, e.g. to pass
+//! them on to `notify-send` while a YubiKey waits for a touch.
+//!
+//! `client discover` lists the servers advertised on the local network and
+//! the serials of their devices (needs the `mdns` feature).
+use std::io::{Error, ErrorKind, Result};
+use std::net::SocketAddr;
+use tokio::net::TcpStream;
+use usbip::client::{self, ImportedDevice};
+use usbip::events;
+use usbip::wire::DeviceDescriptor;
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    env_logger::init();
+    let usage = || {
+        Error::new(
+            ErrorKind::InvalidInput,
+            "usage: client HOST:PORT [BUSID [--vhci] | --events] | client discover",
+        )
+    };
+    let mut args = std::env::args().skip(1).peekable();
+    if args.peek().map(String::as_str) == Some("discover") {
+        return discover().await;
+    }
+    let addr: SocketAddr = args.next().and_then(|a| a.parse().ok()).ok_or_else(usage)?;
+    let bus_id = args.next();
+    let vhci = match args.next().as_deref() {
+        None => false,
+        Some("--vhci") => true,
+        Some(_) => return Err(usage()),
+    };
+
+    let mut socket = TcpStream::connect(addr).await?;
+    socket.set_nodelay(true)?;
+
+    if bus_id.as_deref() == Some("--events") {
+        let mut socket = tokio::io::BufReader::new(socket);
+        events::subscribe(&mut socket).await?;
+        while let Some(event) = events::next_event(&mut socket).await? {
+            println!("{}", event);
+        }
+        return Ok(());
+    }
+
+    let Some(bus_id) = bus_id else {
+        for device in client::list_devices(&mut socket).await? {
+            println!(
+                "{}: {:04x}:{:04x} ({} interfaces)",
+                device.bus_id, device.vendor_id, device.product_id, device.num_interfaces
+            );
+        }
+        return Ok(());
+    };
+
+    let device = client::import(&mut socket, &bus_id).await?;
+    if vhci {
+        #[cfg(target_os = "linux")]
+        {
+            let port = client::attach_vhci(socket.into_std()?, &device)?;
+            println!("Attached {} to port {}", bus_id, port);
+            return Ok(());
+        }
+        #[cfg(not(target_os = "linux"))]
+        return Err(Error::new(ErrorKind::Unsupported, "vhci-hcd is Linux only"));
+    }
+
+    let device = ImportedDevice::new(socket, device);
+    let desc = device
+        .control_in([0x80, 0x06, 0x00, 0x01, 0x00, 0x00, 0x12, 0x00])
+        .await?;
+    let desc = DeviceDescriptor::from_bytes(&desc)
+        .map_err(|err| Error::new(ErrorKind::InvalidData, err.to_string()))?;
+    println!("{:#x?}", desc);
+    Ok(())
+}
+
+#[cfg(feature = "mdns")]
+async fn discover() -> Result<()> {
+    for server in usbip::mdns::discover(std::time::Duration::from_secs(3)).await? {
+        let addresses: Vec<String> = server.addresses.iter().map(|a| a.to_string()).collect();
+        println!(
+            "{} ({}){}: {}",
+            server.name,
+            addresses.join(", "),
+            if server.tls { " TLS" } else { "" },
+            server.serials.join(", ")
+        );
+    }
+    Ok(())
+}
+
+#[cfg(not(feature = "mdns"))]
+async fn discover() -> Result<()> {
+    Err(Error::new(
+        ErrorKind::Unsupported,
+        "discovery needs the mdns feature",
+    ))
+}
@@ -0,0 +1,56 @@
+use std::io::Result;
+use std::net::*;
+use std::sync::{Arc, Mutex};
+use tracing::*;
+use usbip::ctaphid::{CtapBackend, UsbCtapHidHandler};
+
+const CTAP2_OK: u8 = 0x00;
+const CTAP1_ERR_INVALID_COMMAND: u8 = 0x01;
+const AUTHENTICATOR_GET_INFO: u8 = 0x04;
+
+/// An authenticator that only answers authenticatorGetInfo
+struct InfoOnly;
+
+impl CtapBackend for InfoOnly {
+    fn cbor(&mut self, request: &[u8]) -> Result<Vec<u8>> {
+        info!("CTAP2 request {:02x?}", request);
+        Ok(match request.first() {
+            Some(&AUTHENTICATOR_GET_INFO) => {
+                let mut response = vec![
+                    CTAP2_OK, 0xA2, // map(2)
+                    0x01, 0x81, // versions: array(1)
+                    0x68, // text(8)
+                ];
+                response.extend(b"FIDO_2_0");
+                response.extend([0x03, 0x50]); // aaguid: bytes(16)
+                response.extend([0; 16]);
+                response
+            }
+            _ => vec![CTAP1_ERR_INVALID_COMMAND],
+        })
+    }
+
+    fn wink(&mut self) {
+        info!("Wink");
+    }
+}
+
+#[tokio::main]
+async fn main() {
+    env_logger::init();
+    let handler =
+        Arc::new(Mutex::new(Box::new(UsbCtapHidHandler::new(InfoOnly))
+            as Box<dyn usbip::UsbInterfaceHandler + Send>));
+    let server = Arc::new(usbip::UsbIpServer::new_simulated(vec![
+        usbip::UsbDevice::new(0).with_interface(
+            usbip::ClassCode::HID as u8,
+            0x00,
+            0x00,
+            "Test CTAPHID",
+            UsbCtapHidHandler::<InfoOnly>::endpoints(),
+            handler,
+        ),
+    ]));
+    let addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0)), 3240);
+    usbip::server(addr, server).await;
+}
@@ -1,7 +1,7 @@
-use log::*;
 use std::net::*;
 use std::sync::{Arc, Mutex};
 use std::time::Duration;
+use tracing::*;
 
 #[tokio::main]
 async fn main() {
@@ -10,21 +10,22 @@ async fn main() {
         Box::new(usbip::hid::UsbHidKeyboardHandler::new_keyboard())
             as Box<dyn usbip::UsbInterfaceHandler + Send>,
     ));
-    let server = Arc::new(usbip::UsbIpServer::new_simulated(vec![
-        usbip::UsbDevice::new(0).with_interface(
-            usbip::ClassCode::HID as u8,
-            0x00,
-            0x00,
-            "Test HID",
-            vec![usbip::UsbEndpoint {
-                address: 0x81,         // IN
-                attributes: 0x03,      // Interrupt
-                max_packet_size: 0x08, // 8 bytes
-                interval: 10,
-            }],
-            handler.clone(),
-        ),
-    ]));
+    let device = usbip::UsbDeviceBuilder::new(0)
+        .with_product_name("Test Keyboard")
+        .with_interface(
+            usbip::UsbInterfaceBuilder::new(
+                usbip::ClassCode::HID as u8,
+                0x00,
+                0x00,
+                handler.clone(),
+            )
+            .with_name("Test HID")
+            // 8 byte reports
+            .with_interrupt_in(0x81, 8, 10),
+        )
+        .build()
+        .unwrap();
+    let server = Arc::new(usbip::UsbIpServer::new_simulated(vec![device]));
     let addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0)), 3240);
     tokio::spawn(usbip::server(addr, server));
 
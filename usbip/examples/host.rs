@@ -1,16 +1,42 @@
+//! Share the YubiKeys of this machine.
+//!
+//! `USBIP_DEVICES` (`VID[:PID],...` in hex, or `any`) and
+//! `USBIP_INTERFACE_CLASSES` (hex, or `any`) export other devices instead.
 use std::net::*;
 use std::sync::Arc;
-use std::time::Duration;
+use usbip::DeviceFilter;
 
 #[tokio::main]
 async fn main() {
     env_logger::init();
-    let server = Arc::new(usbip::UsbIpServer::new_from_host());
-    let addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0)), 3240);
-    tokio::spawn(usbip::server(addr, server));
+    let mut filter = DeviceFilter::yubikeys();
+    if let Ok(ids) = std::env::var("USBIP_DEVICES") {
+        filter.ids = DeviceFilter::parse_ids(&ids).expect("parse USBIP_DEVICES");
+    }
+    if let Ok(classes) = std::env::var("USBIP_INTERFACE_CLASSES") {
+        filter.interface_classes =
+            DeviceFilter::parse_classes(&classes).expect("parse USBIP_INTERFACE_CLASSES");
+    }
 
-    loop {
-        // sleep 1s
-        tokio::time::sleep(Duration::new(1, 0)).await;
+    let mut server = usbip::UsbIpServer::new_from_host_with_filter(|dev| filter.matches(dev));
+    // Optionally restrict who may import what, see usbip::acl
+    if let Ok(path) = std::env::var("USBIP_POLICY") {
+        server = server.with_policy(usbip::acl::Policy::load(path).expect("load policy"));
     }
+    let server = Arc::new(server);
+    // Also export devices plugged in later
+    let _watcher = match server.watch_host(move |dev| filter.matches(dev)) {
+        Ok(watcher) => Some(watcher),
+        Err(err) => {
+            tracing::warn!("Not following hotplug events: {}", err);
+            None
+        }
+    };
+    let addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0)), 3240);
+
+    // Release the shared devices on Ctrl-C
+    usbip::server_with_shutdown(addr, server, async {
+        tokio::signal::ctrl_c().await.ok();
+    })
+    .await;
 }
@@ -0,0 +1,34 @@
+//! Share a card through the host's PC/SC stack, so the host keeps using it.
+//!
+//! Usage: `pcsc [READER]`
+//!
+//! Without a reader name, shares the first reader whose name contains
+//! "YubiKey".
+use std::io::{Error, ErrorKind, Result};
+use std::net::*;
+use std::sync::Arc;
+use usbip::pcsc;
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    env_logger::init();
+    let readers = pcsc::list_readers()?;
+    let reader = match std::env::args().nth(1) {
+        Some(reader) => reader,
+        None => readers
+            .into_iter()
+            .find(|reader| reader.contains("YubiKey"))
+            .ok_or_else(|| Error::new(ErrorKind::NotFound, "No YubiKey reader found"))?,
+    };
+    println!("Sharing {}", reader);
+
+    let server = Arc::new(usbip::UsbIpServer::new_simulated(vec![
+        pcsc::reader_device(0, &reader)?,
+    ]));
+    let addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0)), 3240);
+    usbip::server_with_shutdown(addr, server, async {
+        tokio::signal::ctrl_c().await.ok();
+    })
+    .await;
+    Ok(())
+}
@@ -0,0 +1,28 @@
+use std::net::*;
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::*;
+use usbip::piv::{self, PivCard};
+
+/// Export an emulated YubiKey with a blank PIV applet, for CI jobs that
+/// attach it with `usbip attach -r HOST -b 0-0-0` and run PIV clients
+/// against it; the serial number is the first argument, 12345678 by default
+#[tokio::main]
+async fn main() {
+    env_logger::init();
+    let serial = std::env::args()
+        .nth(1)
+        .map(|serial| serial.parse().expect("the serial is a number"))
+        .unwrap_or(12345678);
+    let server = Arc::new(usbip::UsbIpServer::new_simulated(vec![piv::device(
+        0,
+        PivCard::new(serial),
+    )]));
+    let addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0)), 3240);
+    info!("Exporting emulated YubiKey {} on {}", serial, addr);
+    tokio::spawn(usbip::server(addr, server));
+
+    loop {
+        tokio::time::sleep(Duration::new(60, 0)).await;
+    }
+}
@@ -0,0 +1,65 @@
+//! Share the YubiKeys of this machine over TLS, or list the devices of such a
+//! server.
+//!
+//! Usage:
+//! - `tls serve CERT KEY [CLIENT_CA]`
+//! - `tls list HOST:PORT SERVER_NAME CA [CERT KEY]`
+//!
+//! The server listens on port 3240 and, given `CLIENT_CA`, only accepts
+//! clients with a certificate issued by it. All files are PEM-encoded.
+//! `USBIP_POLICY` names an access control file, see `usbip::acl`.
+use std::io::{Error, ErrorKind, Result};
+use std::net::*;
+use std::sync::Arc;
+use usbip::{client, tls};
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    env_logger::init();
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let args: Vec<&str> = args.iter().map(String::as_str).collect();
+    match args[..] {
+        ["serve", cert, key, ref client_ca @ ..] if client_ca.len() <= 1 => {
+            let client_roots = match client_ca.first() {
+                Some(path) => Some(tls::load_certs(path)?),
+                None => None,
+            };
+            let acceptor = tls::acceptor(
+                tls::load_certs(cert)?,
+                tls::load_private_key(key)?,
+                client_roots,
+            )?;
+            let filter = usbip::DeviceFilter::yubikeys();
+            let mut server =
+                usbip::UsbIpServer::new_from_host_with_filter(|dev| filter.matches(dev));
+            if let Ok(path) = std::env::var("USBIP_POLICY") {
+                server = server.with_policy(usbip::acl::Policy::load(path)?);
+            }
+            let server = Arc::new(server);
+            let addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0)), 3240);
+            tls::server_with_shutdown(addr, server, acceptor, async {
+                tokio::signal::ctrl_c().await.ok();
+            })
+            .await;
+        }
+        ["list", addr, server_name, ca, ref identity @ ..] if matches!(identity.len(), 0 | 2) => {
+            let identity = match identity {
+                [cert, key] => Some((tls::load_certs(cert)?, tls::load_private_key(key)?)),
+                _ => None,
+            };
+            let connector = tls::connector(tls::load_certs(ca)?, identity)?;
+            let mut socket = tls::connect(addr, server_name, &connector).await?;
+            for device in client::list_devices(&mut socket).await? {
+                println!(
+                    "{}: {:04x}:{:04x} ({} interfaces)",
+                    device.bus_id, device.vendor_id, device.product_id, device.num_interfaces
+                );
+            }
+        }
+        _ => return Err(Error::new(
+            ErrorKind::InvalidInput,
+            "usage: tls serve CERT KEY [CLIENT_CA] | tls list HOST:PORT SERVER_NAME CA [CERT KEY]",
+        )),
+    }
+    Ok(())
+}
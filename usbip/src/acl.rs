@@ -0,0 +1,530 @@
+//! Access control for imports
+//!
+//! A [Policy] decides which clients may import which devices. It is checked
+//! at OP_REQ_IMPORT: a denied import fails like one of an unknown bus id.
+//! Listing devices stays open to everyone.
+//!
+//! Policies are read from a file of rules, one per line:
+//!
+//! ```text
+//! # action client device
+//! allow cert:5f0c…e1  serial:12345678
+//! allow token:s3cret  bus:1-2
+//! allow cidr:10.0.0.0/8 any
+//! allow uid:1000      serial:12345678
+//! allow claim:groups=yubikey-ci any
+//! deny  any           any
+//! ```
+//!
+//! Clients are matched by the SHA-256 fingerprint of their TLS certificate
+//! (hex, colons optional), a token they sent before the first request (see
+//! [crate::client::send_token]), the network their address is in, or the
+//! user they run as when they connect over a Unix domain socket (see
+//! [crate::local]), or a claim of the bearer token they sent (see
+//! [crate::oidc], with the `oidc` feature). Devices
+//! are matched by bus id, or by their serial number string, which for a
+//! YubiKey is its serial. The first rule matching both decides; if none does,
+//! the import is denied.
+//!
+//! Instead of a device, a rule can name `admin`, the requests of
+//! [crate::admin] such as revoking another client's import. Only `admin`
+//! rules apply to them, `any` doesn't include them:
+//!
+//! ```text
+//! allow uid:0 admin
+//! ```
+//!
+//! An `allow` rule for devices can end with the [Role] the client gets,
+//! which limits the APDUs it may send to the smart card of the device. Any
+//! role but `admin` also keeps it from the other interfaces of the device,
+//! and from CCID messages that can't be checked, e.g. split across URBs.
+//! Without one, it may send anything:
+//!
+//! ```text
+//! allow cert:5f0c…e1  serial:12345678 role:decrypt-only
+//! allow token:audit   any             role:attest-only
+//! ```
+use crate::UsbDevice;
+use std::fmt;
+use std::io::{Error, ErrorKind, Result};
+use std::net::IpAddr;
+use std::path::Path;
+use tokio::io::{AsyncBufRead, AsyncBufReadExt};
+
+/// Sent by a client before its first request to authenticate with a token,
+/// followed by the token and a newline
+///
+/// A USB/IP request starts with the version, 0x0111, so the two can't be
+/// confused.
+pub const TOKEN_PREAMBLE: &[u8] = b"USBIP-TOKEN ";
+
+/// Longest token accepted, in bytes, which leaves room for the JWTs of
+/// identity providers
+pub const MAX_TOKEN_LENGTH: usize = 8192;
+
+/// What is known about the client on the other end of a connection
+#[derive(Clone, Default)]
+pub struct Peer {
+    pub addr: Option<IpAddr>,
+    /// SHA-256 of the client's TLS certificate
+    pub cert_fingerprint: Option<[u8; 32]>,
+    pub token: Option<String>,
+    /// User id of a client on a Unix domain socket
+    pub uid: Option<u32>,
+    /// Claims of the bearer token of the client, once validated, as name
+    /// and value pairs (see [crate::oidc])
+    pub claims: Vec<(String, String)>,
+}
+
+impl fmt::Debug for Peer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Peer")
+            .field("addr", &self.addr)
+            .field("cert_fingerprint", &self.cert_fingerprint.map(hex))
+            .field("token", &self.token.as_ref().map(|_| "<redacted>"))
+            .field("uid", &self.uid)
+            .field("claims", &self.claims)
+            .finish()
+    }
+}
+
+/// Read the token a client may send before its first request
+pub(crate) async fn read_token<T: AsyncBufRead + Unpin>(socket: &mut T) -> Result<Option<String>> {
+    if !socket.fill_buf().await?.starts_with(&TOKEN_PREAMBLE[..1]) {
+        return Ok(None);
+    }
+    let mut line = vec![];
+    let limit = (TOKEN_PREAMBLE.len() + MAX_TOKEN_LENGTH + 1) as u64;
+    tokio::io::AsyncReadExt::take(&mut *socket, limit)
+        .read_until(b'\n', &mut line)
+        .await?;
+    let token = line
+        .strip_prefix(TOKEN_PREAMBLE)
+        .and_then(|line| line.strip_suffix(b"\n"))
+        .and_then(|token| String::from_utf8(token.to_vec()).ok())
+        .ok_or_else(|| Error::new(ErrorKind::InvalidData, "Invalid token"))?;
+    Ok(Some(token))
+}
+
+fn hex(bytes: [u8; 32]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Compare without leaking where the first difference is
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (a, b)| acc | (a ^ b)) == 0
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Action {
+    Allow,
+    Deny,
+}
+
+/// Which clients a [Rule] applies to
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ClientMatch {
+    Any,
+    CertFingerprint([u8; 32]),
+    Token(String),
+    /// Addresses whose first `prefix` bits are those of the address
+    Cidr(IpAddr, u8),
+    Uid(u32),
+    /// A claim of a validated bearer token, by name and value
+    Claim(String, String),
+}
+
+impl ClientMatch {
+    fn matches(&self, peer: &Peer) -> bool {
+        match self {
+            ClientMatch::Any => true,
+            ClientMatch::CertFingerprint(fingerprint) => {
+                peer.cert_fingerprint == Some(*fingerprint)
+            }
+            ClientMatch::Token(token) => peer
+                .token
+                .as_ref()
+                .is_some_and(|t| constant_time_eq(t.as_bytes(), token.as_bytes())),
+            ClientMatch::Cidr(network, prefix) => {
+                let Some(addr) = peer.addr else {
+                    return false;
+                };
+                match (addr.to_canonical(), network) {
+                    (IpAddr::V4(addr), IpAddr::V4(network)) => {
+                        let mask = u32::MAX.checked_shl(32 - *prefix as u32).unwrap_or(0);
+                        u32::from(addr) & mask == u32::from(*network) & mask
+                    }
+                    (IpAddr::V6(addr), IpAddr::V6(network)) => {
+                        let mask = u128::MAX.checked_shl(128 - *prefix as u32).unwrap_or(0);
+                        u128::from(addr) & mask == u128::from(*network) & mask
+                    }
+                    _ => false,
+                }
+            }
+            ClientMatch::Uid(uid) => peer.uid == Some(*uid),
+            ClientMatch::Claim(name, value) => {
+                peer.claims.iter().any(|(n, v)| n == name && v == value)
+            }
+        }
+    }
+}
+
+/// Which devices a [Rule] applies to
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DeviceMatch {
+    Any,
+    BusId(String),
+    Serial(String),
+    /// Not a device, but the requests of [crate::admin]
+    Admin,
+}
+
+impl DeviceMatch {
+    fn matches(&self, device: &UsbDevice) -> bool {
+        match self {
+            DeviceMatch::Any => true,
+            DeviceMatch::BusId(bus_id) => device.bus_id == *bus_id,
+            DeviceMatch::Serial(serial) => device.serial_number() == Some(serial.as_str()),
+            DeviceMatch::Admin => false,
+        }
+    }
+}
+
+/// What a client that imported a device may do with its smart card
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Role {
+    /// Anything, including generating keys, changing PINs and resetting
+    #[default]
+    Admin,
+    /// Read the card, verify the PIN, and sign or decrypt with its keys
+    DecryptOnly,
+    /// Read the card and attest its keys, without a PIN
+    AttestOnly,
+}
+
+impl Role {
+    /// Whether the role may send an APDU with instruction `ins`
+    pub fn allows(&self, ins: u8) -> bool {
+        // SELECT, GET RESPONSE, GET DATA, and the YubiKey's GET METADATA,
+        // GET SERIAL and GET VERSION
+        let reads = matches!(ins, 0xA4 | 0xC0 | 0xCB | 0xF7 | 0xF8 | 0xFD);
+        match self {
+            Role::Admin => true,
+            // VERIFY, GENERAL AUTHENTICATE
+            Role::DecryptOnly => reads || matches!(ins, 0x20 | 0x87),
+            // ATTEST
+            Role::AttestOnly => reads || ins == 0xF9,
+        }
+    }
+
+    fn parse(role: &str) -> Option<Self> {
+        match role {
+            "admin" => Some(Role::Admin),
+            "decrypt-only" => Some(Role::DecryptOnly),
+            "attest-only" => Some(Role::AttestOnly),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Rule {
+    pub action: Action,
+    pub client: ClientMatch,
+    pub device: DeviceMatch,
+    /// What a client this rule allows may do with the device
+    pub role: Role,
+}
+
+impl Rule {
+    fn parse(line: &str) -> std::result::Result<Self, String> {
+        let fields: Vec<_> = line.split_whitespace().collect();
+        let (action, client, device, role) = match fields[..] {
+            [action, client, device] => (action, client, device, None),
+            [action, client, device, role] => (action, client, device, Some(role)),
+            _ => return Err("expected: action client device [role]".to_string()),
+        };
+        let action = match action {
+            "allow" => Action::Allow,
+            "deny" => Action::Deny,
+            _ => return Err(format!("unknown action {}", action)),
+        };
+        let client = match client.split_once(':') {
+            _ if client == "any" => ClientMatch::Any,
+            Some(("cert", fingerprint)) => {
+                let digits = fingerprint.replace(':', "");
+                let bytes = (0..digits.len())
+                    .step_by(2)
+                    .map(|i| {
+                        digits
+                            .get(i..i + 2)
+                            .and_then(|b| u8::from_str_radix(b, 16).ok())
+                    })
+                    .collect::<Option<Vec<u8>>>();
+                ClientMatch::CertFingerprint(
+                    bytes
+                        .and_then(|bytes| bytes.try_into().ok())
+                        .ok_or_else(|| format!("invalid fingerprint {}", fingerprint))?,
+                )
+            }
+            Some(("token", token)) if !token.is_empty() => ClientMatch::Token(token.to_string()),
+            Some(("cidr", cidr)) => {
+                let (addr, prefix) = cidr.split_once('/').unwrap_or((cidr, ""));
+                let addr: IpAddr = addr
+                    .parse()
+                    .map_err(|_| format!("invalid address {}", addr))?;
+                let max = if addr.is_ipv4() { 32 } else { 128 };
+                let prefix = match prefix {
+                    "" => max,
+                    prefix => prefix
+                        .parse()
+                        .ok()
+                        .filter(|prefix| *prefix <= max)
+                        .ok_or_else(|| format!("invalid prefix {}", prefix))?,
+                };
+                ClientMatch::Cidr(addr, prefix)
+            }
+            Some(("uid", uid)) => {
+                ClientMatch::Uid(uid.parse().map_err(|_| format!("invalid uid {}", uid))?)
+            }
+            Some(("claim", claim)) => match claim.split_once('=') {
+                Some((name, value)) if !name.is_empty() => {
+                    ClientMatch::Claim(name.to_string(), value.to_string())
+                }
+                _ => return Err(format!("invalid claim {}", claim)),
+            },
+            _ => return Err(format!("unknown client {}", client)),
+        };
+        let device = match device.split_once(':') {
+            _ if device == "any" => DeviceMatch::Any,
+            _ if device == "admin" => DeviceMatch::Admin,
+            Some(("bus", bus_id)) if !bus_id.is_empty() => DeviceMatch::BusId(bus_id.to_string()),
+            Some(("serial", serial)) if !serial.is_empty() => {
+                DeviceMatch::Serial(serial.to_string())
+            }
+            _ => return Err(format!("unknown device {}", device)),
+        };
+        let role = match role {
+            None => Role::Admin,
+            Some(_) if action == Action::Deny || device == DeviceMatch::Admin => {
+                return Err("only allow rules for devices have a role".to_string())
+            }
+            Some(role) => role
+                .strip_prefix("role:")
+                .and_then(Role::parse)
+                .ok_or_else(|| format!("unknown role {}", role))?,
+        };
+        Ok(Rule {
+            action,
+            client,
+            device,
+            role,
+        })
+    }
+}
+
+/// Rules deciding which clients may import which devices
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Policy {
+    pub rules: Vec<Rule>,
+}
+
+impl Policy {
+    pub fn new(rules: Vec<Rule>) -> Self {
+        Self { rules }
+    }
+
+    /// Parse rules in the format described in the [module docs](self)
+    pub fn parse(config: &str) -> Result<Self> {
+        let mut rules = vec![];
+        for (i, line) in config.lines().enumerate() {
+            let line = line.split('#').next().unwrap().trim();
+            if line.is_empty() {
+                continue;
+            }
+            rules.push(Rule::parse(line).map_err(|err| {
+                Error::new(ErrorKind::InvalidData, format!("line {}: {}", i + 1, err))
+            })?);
+        }
+        Ok(Self { rules })
+    }
+
+    /// Read rules from the file at `path`
+    pub fn load(path: impl AsRef<Path>) -> Result<Self> {
+        Self::parse(&std::fs::read_to_string(path)?)
+    }
+
+    /// Whether `peer` may import `device`
+    pub fn allows(&self, peer: &Peer, device: &UsbDevice) -> bool {
+        self.role(peer, device).is_some()
+    }
+
+    /// The [Role] `peer` gets when it imports `device`, if it may
+    pub fn role(&self, peer: &Peer, device: &UsbDevice) -> Option<Role> {
+        self.rules
+            .iter()
+            .find(|rule| rule.client.matches(peer) && rule.device.matches(device))
+            .filter(|rule| rule.action == Action::Allow)
+            .map(|rule| rule.role)
+    }
+
+    /// Whether `peer` may send [crate::admin] requests
+    pub fn allows_admin(&self, peer: &Peer) -> bool {
+        self.rules
+            .iter()
+            .find(|rule| rule.device == DeviceMatch::Admin && rule.client.matches(peer))
+            .is_some_and(|rule| rule.action == Action::Allow)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::tests::*;
+    use crate::{client, server, UsbIpServer};
+    use std::sync::Arc;
+
+    #[test]
+    fn parse_and_match() {
+        let policy = Policy::parse(
+            "# comment\n\
+             allow cert:00:01:02:03:04:05:06:07:08:09:0a:0b:0c:0d:0e:0f:10:11:12:13:14:15:16:17:18:19:1a:1b:1c:1d:1e:1f serial:1234\n\
+             deny cidr:10.1.0.0/16 any\n\
+             allow cidr:10.0.0.0/8 bus:1-2 # trailing comment\n\
+             allow token:s3cret any\n\
+             allow uid:1000 serial:1234\n\
+             allow claim:groups=ci serial:1234\n\
+             deny uid:1000 admin\n\
+             allow uid:0 admin\n",
+        )
+        .unwrap();
+        assert_eq!(policy.rules.len(), 8);
+
+        let mut yubikey = UsbDevice::new(0);
+        yubikey.bus_id = "1-2".to_string();
+        yubikey.set_serial_number("1234");
+        let other = UsbDevice::new(1);
+
+        let fingerprint: [u8; 32] = std::array::from_fn(|i| i as u8);
+        let cert = Peer {
+            cert_fingerprint: Some(fingerprint),
+            ..Default::default()
+        };
+        assert!(policy.allows(&cert, &yubikey));
+        assert!(!policy.allows(&cert, &other));
+
+        let addr = |addr: &str| Peer {
+            addr: Some(addr.parse().unwrap()),
+            ..Default::default()
+        };
+        assert!(policy.allows(&addr("10.2.3.4"), &yubikey));
+        assert!(policy.allows(&addr("::ffff:10.2.3.4"), &yubikey));
+        assert!(!policy.allows(&addr("10.1.3.4"), &yubikey));
+        assert!(!policy.allows(&addr("10.2.3.4"), &other));
+        assert!(!policy.allows(&addr("192.168.0.1"), &yubikey));
+
+        let token = |token: &str| Peer {
+            token: Some(token.to_string()),
+            ..Default::default()
+        };
+        assert!(policy.allows(&token("s3cret"), &other));
+        assert!(!policy.allows(&token("s3cre"), &other));
+        assert!(!policy.allows(&Peer::default(), &other));
+
+        let uid = |uid| Peer {
+            uid: Some(uid),
+            ..Default::default()
+        };
+        assert!(policy.allows(&uid(1000), &yubikey));
+        assert!(!policy.allows(&uid(1000), &other));
+        assert!(!policy.allows(&uid(0), &yubikey));
+        let claims = |claims: &[(&str, &str)]| Peer {
+            claims: claims
+                .iter()
+                .map(|(n, v)| (n.to_string(), v.to_string()))
+                .collect(),
+            ..Default::default()
+        };
+        assert!(policy.allows(&claims(&[("sub", "x"), ("groups", "ci")]), &yubikey));
+        assert!(!policy.allows(&claims(&[("groups", "ci")]), &other));
+        assert!(!policy.allows(&claims(&[("sub", "ci")]), &yubikey));
+
+        assert!(policy.allows_admin(&uid(0)));
+        assert!(!policy.allows_admin(&uid(1000)));
+        // `any` isn't admin
+        assert!(!policy.allows_admin(&token("s3cret")));
+
+        for invalid in [
+            "allow any",
+            "permit any any",
+            "allow cert:0011 any",
+            "allow cidr:10.0.0.0/33 any",
+            "allow any serial:",
+            "allow uid:root any",
+            "allow claim:groups any",
+            "allow any any role:root",
+            "deny any any role:decrypt-only",
+            "allow uid:0 admin role:admin",
+        ] {
+            assert!(Policy::parse(invalid).is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn roles() {
+        let policy = Policy::parse(
+            "allow token:ci any role:decrypt-only\n\
+             allow token:audit any role:attest-only\n\
+             allow token:admin any role:admin\n\
+             allow any any\n",
+        )
+        .unwrap();
+        let device = UsbDevice::new(0);
+        let role = |token: &str| {
+            let peer = Peer {
+                token: Some(token.to_string()),
+                ..Default::default()
+            };
+            policy.role(&peer, &device).unwrap()
+        };
+        assert_eq!(role("ci"), Role::DecryptOnly);
+        assert_eq!(role("audit"), Role::AttestOnly);
+        assert_eq!(role("admin"), Role::Admin);
+        assert_eq!(role("other"), Role::Admin);
+
+        // GENERAL AUTHENTICATE, ATTEST, GENERATE ASYMMETRIC KEY PAIR
+        assert!(Role::DecryptOnly.allows(0x87));
+        assert!(!Role::DecryptOnly.allows(0xF9));
+        assert!(!Role::DecryptOnly.allows(0x47));
+        assert!(Role::AttestOnly.allows(0xF9));
+        assert!(!Role::AttestOnly.allows(0x87));
+        assert!(Role::Admin.allows(0x47));
+    }
+
+    #[tokio::test]
+    async fn enforced_at_import() {
+        setup_test_logger();
+        let device = UsbDevice::new(0);
+        let bus_id = device.bus_id.clone();
+        let policy = Policy::parse("allow token:s3cret any").unwrap();
+        let addr = get_free_address().await;
+        tokio::spawn(server(
+            addr,
+            Arc::new(UsbIpServer::new_simulated(vec![device]).with_policy(policy)),
+        ));
+
+        // Anyone may list devices, but not import them
+        let mut socket = poll_connect(addr).await;
+        assert_eq!(client::list_devices(&mut socket).await.unwrap().len(), 1);
+        let mut socket = poll_connect(addr).await;
+        assert!(client::import(&mut socket, &bus_id).await.is_err());
+
+        let mut socket = poll_connect(addr).await;
+        client::send_token(&mut socket, "wrong").await.unwrap();
+        assert!(client::import(&mut socket, &bus_id).await.is_err());
+
+        let mut socket = poll_connect(addr).await;
+        client::send_token(&mut socket, "s3cret").await.unwrap();
+        assert!(client::import(&mut socket, &bus_id).await.is_ok());
+    }
+}
@@ -0,0 +1,343 @@
+//! Requests administering a server
+//!
+//! Instead of a USB/IP request (and after its token, if any), a client can
+//! send one line starting with [ADMIN_PREAMBLE], to which the server answers
+//! with `ok` or `error MESSAGE` before closing the connection:
+//!
+//! ```text
+//! ADMIN revoke 1-2
+//! ```
+//!
+//! `revoke BUSID` takes a device away from the client that imported it, see
+//! [crate::UsbIpServer::revoke]. `stats` asks for the latencies of the recent
+//! URBs to each device, see [crate::metrics::Metrics::latencies], which come
+//! before the `ok` a line per device:
+//!
+//! ```text
+//! stats 1-2 12345678 421 850 12000 3100000
+//! ```
+//!
+//! with the bus id, the serial number (or `-`), the URBs handled, and the
+//! 50th, 95th and 99th percentiles of their latencies in microseconds.
+//! `approve ID` and `deny ID` answer a request for the approval of key use,
+//! see [crate::approval].
+//!
+//! Only clients an `admin` rule of the [crate::acl::Policy] allows may send
+//! them. Without a policy, only clients on a Unix domain socket may, whose
+//! access is up to the permissions of the socket file.
+use super::*;
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncWrite};
+
+/// Starts an admin request, instead of a USB/IP request
+///
+/// Its first byte tells it apart from a token, [events::SUBSCRIBE_REQUEST]
+/// and USB/IP requests.
+pub const ADMIN_PREAMBLE: &[u8] = b"ADMIN ";
+
+/// Longest admin request accepted, in bytes
+const MAX_REQUEST_LENGTH: usize = 128;
+
+/// Something an admin asks of the server
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Request {
+    /// See [UsbIpServer::revoke]
+    Revoke { bus_id: String },
+    /// See [metrics::Metrics::latencies]
+    Stats,
+    /// See [UsbIpServer::answer_approval]
+    Approve { id: u64, approved: bool },
+}
+
+impl Request {
+    fn to_line(&self) -> String {
+        match self {
+            Request::Revoke { bus_id } => format!("revoke {}", bus_id),
+            Request::Stats => "stats".to_string(),
+            Request::Approve { id, approved: true } => format!("approve {}", id),
+            Request::Approve {
+                id,
+                approved: false,
+            } => format!("deny {}", id),
+        }
+    }
+
+    fn parse(line: &str) -> Option<Self> {
+        match line.split_whitespace().collect::<Vec<_>>()[..] {
+            ["revoke", bus_id] => Some(Request::Revoke {
+                bus_id: bus_id.to_string(),
+            }),
+            ["stats"] => Some(Request::Stats),
+            [answer @ ("approve" | "deny"), id] => Some(Request::Approve {
+                id: id.parse().ok()?,
+                approved: answer == "approve",
+            }),
+            _ => None,
+        }
+    }
+}
+
+/// Read the admin request a client may send instead of a USB/IP request
+pub(crate) async fn read_request<T: AsyncBufRead + Unpin>(
+    socket: &mut T,
+) -> Result<Option<Request>> {
+    if !socket.fill_buf().await?.starts_with(&ADMIN_PREAMBLE[..1]) {
+        return Ok(None);
+    }
+    let mut line = vec![];
+    tokio::io::AsyncReadExt::take(&mut *socket, MAX_REQUEST_LENGTH as u64)
+        .read_until(b'\n', &mut line)
+        .await?;
+    line.strip_prefix(ADMIN_PREAMBLE)
+        .and_then(|line| line.strip_suffix(b"\n"))
+        .and_then(|line| std::str::from_utf8(line).ok())
+        .and_then(Request::parse)
+        .map(Some)
+        .ok_or_else(|| std::io::Error::new(ErrorKind::InvalidData, "Invalid admin request"))
+}
+
+/// Carry out `request` from `peer`, and answer it
+pub(crate) async fn serve_request<T: AsyncWrite + Unpin>(
+    socket: &mut T,
+    server: &UsbIpServer,
+    peer: &acl::Peer,
+    request: Request,
+) -> Result<()> {
+    let allowed = match &server.policy {
+        Some(policy) => policy.allows_admin(peer),
+        None => peer.uid.is_some(),
+    };
+    let res = if !allowed {
+        warn!("Denied admin request {:?} by {:?}", request, peer);
+        Err(std::io::Error::new(
+            ErrorKind::PermissionDenied,
+            "Permission denied",
+        ))
+    } else {
+        info!("Admin request {:?} by {:?}", request, peer);
+        match &request {
+            Request::Revoke { bus_id } => server.revoke(bus_id).await.map(|()| vec![]),
+            Request::Stats => Ok(server
+                .metrics
+                .latencies()
+                .iter()
+                .map(latencies_line)
+                .collect()),
+            Request::Approve { id, approved } => {
+                server.answer_approval(*id, *approved).map(|()| vec![])
+            }
+        }
+    };
+    let (lines, error) = match res {
+        Ok(lines) => (lines, None),
+        Err(err) => (vec![], Some(err.to_string())),
+    };
+    if let Some(records) = &server.audit {
+        records
+            .send(audit::Record::Admin {
+                peer: peer.clone(),
+                request: request.to_line(),
+                error: error.clone(),
+            })
+            .ok();
+    }
+    let mut reply: String = lines.iter().map(|line| format!("{}\n", line)).collect();
+    match error {
+        None => reply.push_str("ok\n"),
+        Some(err) => reply.push_str(&format!("error {}\n", err)),
+    }
+    socket.write_all(reply.as_bytes()).await?;
+    socket.flush().await
+}
+
+/// Send `request` to the server on the other end of `socket`, after the
+/// token if there is one, and wait for its answer
+pub async fn send<T: AsyncBufRead + AsyncWrite + Unpin>(
+    socket: &mut T,
+    request: &Request,
+) -> Result<()> {
+    exchange(socket, request).await.map(|_| ())
+}
+
+/// Send `request`, returning the lines the server answered before `ok`
+async fn exchange<T: AsyncBufRead + AsyncWrite + Unpin>(
+    socket: &mut T,
+    request: &Request,
+) -> Result<Vec<String>> {
+    let mut line = ADMIN_PREAMBLE.to_vec();
+    line.extend(request.to_line().as_bytes());
+    line.push(b'\n');
+    socket.write_all(&line).await?;
+    socket.flush().await?;
+
+    let mut lines = vec![];
+    loop {
+        let mut reply = String::new();
+        socket.read_line(&mut reply).await?;
+        match reply.trim_end() {
+            "ok" => return Ok(lines),
+            reply if reply.starts_with("stats ") => lines.push(reply.to_string()),
+            reply => {
+                return Err(std::io::Error::other(
+                    reply
+                        .strip_prefix("error ")
+                        .unwrap_or("Server doesn't take admin requests")
+                        .to_string(),
+                ))
+            }
+        }
+    }
+}
+
+fn latencies_line(latencies: &metrics::Latencies) -> String {
+    format!(
+        "stats {} {} {} {} {} {}",
+        latencies.bus_id,
+        if latencies.serial.is_empty() {
+            "-"
+        } else {
+            &latencies.serial
+        },
+        latencies.urbs,
+        latencies.p50.as_micros(),
+        latencies.p95.as_micros(),
+        latencies.p99.as_micros(),
+    )
+}
+
+fn parse_latencies(line: &str) -> Option<metrics::Latencies> {
+    let ["stats", bus_id, serial, urbs, p50, p95, p99] =
+        line.split_whitespace().collect::<Vec<_>>()[..]
+    else {
+        return None;
+    };
+    let micros = |field: &str| field.parse().ok().map(std::time::Duration::from_micros);
+    Some(metrics::Latencies {
+        bus_id: bus_id.to_string(),
+        serial: if serial == "-" { "" } else { serial }.to_string(),
+        urbs: urbs.parse().ok()?,
+        p50: micros(p50)?,
+        p95: micros(p95)?,
+        p99: micros(p99)?,
+    })
+}
+
+/// The latencies of the recent URBs to each device, see
+/// [metrics::Metrics::latencies]
+pub async fn stats<T: AsyncBufRead + AsyncWrite + Unpin>(
+    socket: &mut T,
+) -> Result<Vec<metrics::Latencies>> {
+    exchange(socket, &Request::Stats)
+        .await?
+        .iter()
+        .map(|line| {
+            parse_latencies(line)
+                .ok_or_else(|| std::io::Error::new(ErrorKind::InvalidData, "Invalid stats"))
+        })
+        .collect()
+}
+
+/// Revoke the import of the device with `bus_id`, see [UsbIpServer::revoke]
+pub async fn revoke<T: AsyncBufRead + AsyncWrite + Unpin>(
+    socket: &mut T,
+    bus_id: &str,
+) -> Result<()> {
+    send(
+        socket,
+        &Request::Revoke {
+            bus_id: bus_id.to_string(),
+        },
+    )
+    .await
+}
+
+/// Approve or deny the key use the request for approval `id` asks for, see
+/// [UsbIpServer::answer_approval]
+pub async fn approve<T: AsyncBufRead + AsyncWrite + Unpin>(
+    socket: &mut T,
+    id: u64,
+    approved: bool,
+) -> Result<()> {
+    send(socket, &Request::Approve { id, approved }).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::tests::*;
+
+    #[tokio::test]
+    async fn revoke_import() {
+        setup_test_logger();
+        let device = UsbDevice::new(0);
+        let bus_id = device.bus_id.clone();
+        let policy = acl::Policy::parse(
+            "allow token:admin admin\n\
+             allow any any\n",
+        )
+        .unwrap();
+        let addr = get_free_address().await;
+        tokio::spawn(server(
+            addr,
+            Arc::new(UsbIpServer::new_simulated(vec![device]).with_policy(policy)),
+        ));
+
+        let mut imported = poll_connect(addr).await;
+        client::import(&mut imported, &bus_id).await.unwrap();
+        let mut socket = TcpStream::connect(addr).await.unwrap();
+        let busy = client::import(&mut socket, &bus_id).await;
+        assert_eq!(
+            busy.err().map(|err| err.kind()),
+            Some(ErrorKind::ResourceBusy)
+        );
+
+        // Only an admin may revoke it
+        let mut socket = tokio::io::BufStream::new(TcpStream::connect(addr).await.unwrap());
+        assert!(revoke(&mut socket, &bus_id).await.is_err());
+        let mut socket = tokio::io::BufStream::new(TcpStream::connect(addr).await.unwrap());
+        client::send_token(&mut socket, "admin").await.unwrap();
+        revoke(&mut socket, &bus_id).await.unwrap();
+
+        // The connection holding it is closed, and it can be imported again
+        assert_eq!(imported.read(&mut [0; 1]).await.unwrap(), 0);
+        let mut socket = TcpStream::connect(addr).await.unwrap();
+        assert!(client::import(&mut socket, &bus_id).await.is_ok());
+
+        let mut socket = tokio::io::BufStream::new(TcpStream::connect(addr).await.unwrap());
+        client::send_token(&mut socket, "admin").await.unwrap();
+        assert!(revoke(&mut socket, "1-9").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn latency_stats() {
+        setup_test_logger();
+        let mut device = UsbDevice::new(0);
+        device.set_serial_number("12345678");
+        let other = UsbDevice::new(1);
+        let server = UsbIpServer::new_simulated(vec![device, other]);
+        let loopback = loopback::Loopback::with_server(Arc::new(server));
+        let imported = loopback.import("0-0-0").await.unwrap();
+        imported
+            .control_in([0x80, 0x06, 0x00, 0x01, 0x00, 0x00, 0x12, 0x00])
+            .await
+            .unwrap();
+
+        // Not for anyone
+        let mut socket = tokio::io::BufStream::new(loopback.connect());
+        assert!(stats(&mut socket).await.is_err());
+        let peer = acl::Peer {
+            uid: Some(0),
+            ..Default::default()
+        };
+        let mut socket = tokio::io::BufStream::new(loopback.connect_as(peer));
+        let stats = stats(&mut socket).await.unwrap();
+        assert_eq!(stats.len(), 1);
+        assert_eq!(stats[0].bus_id, "0-0-0");
+        assert_eq!(stats[0].serial, "12345678");
+        assert_eq!(stats[0].urbs, 1);
+        assert!(stats[0].p50 <= stats[0].p99);
+        assert_eq!(
+            parse_latencies(&latencies_line(&stats[0])).as_ref(),
+            Some(&stats[0])
+        );
+    }
+}
@@ -0,0 +1,820 @@
+//! Exchanging APDUs with a smart card instead of forwarding URBs
+//!
+//! Forwarding URBs takes a round trip for every transfer: a command APDU is
+//! a bulk OUT and a bulk IN transfer, more if the card asks for more time,
+//! and USB/IP clients poll and keep devices alive on top of that. Over a slow
+//! link, a client that only speaks to the card can instead send
+//! [APDU_PREAMBLE] and a bus id (after its token, if any) on a connection of
+//! its own:
+//!
+//! ```text
+//! CARD 1-2
+//! ```
+//!
+//! The server imports the device for the client as if it had sent a USB/IP
+//! import, so the [crate::acl::Policy], leases, audit and metrics apply as
+//! usual, powers the card on, and answers with `ok` or `error MESSAGE`. Both
+//! sides then exchange frames: a flags byte, a request id and a length (big
+//! endian `u32`s), and the payload. The client sends command APDUs, and may
+//! send many before the first answer. The server answers each, in order, with
+//! the response APDU, an error message (flag [FLAG_ERROR]), and before that
+//! an empty frame with [FLAG_WAIT] if the card asks for more time, e.g. while
+//! a YubiKey waits for a touch. Payloads may be compressed with deflate (flag
+//! [FLAG_DEFLATE]) whenever that makes them shorter.
+//!
+//! APDUs that a read-only server (see [UsbIpServer::with_read_only]) or the
+//! [acl::Role] of the client forbid are answered with the status word 69 82
+//! instead of being sent to the card.
+//!
+//! A server with [UsbIpServer::with_card_sharing] lets several sessions with
+//! a device share its card rather than refusing all but the first: they take
+//! turns, and each finds the application it selected still selected.
+use super::*;
+use flate2::{read::DeflateDecoder, write::DeflateEncoder, Compression};
+use std::io::{Read, Write};
+use std::sync::atomic::{AtomicU64, Ordering};
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncRead, AsyncWrite};
+
+/// Starts an APDU session, instead of a USB/IP request
+///
+/// Its first byte tells it apart from a token, [events::SUBSCRIBE_REQUEST],
+/// [admin::ADMIN_PREAMBLE] and USB/IP requests.
+pub const APDU_PREAMBLE: &[u8] = b"CARD ";
+
+/// The payload is compressed with deflate
+pub const FLAG_DEFLATE: u8 = 0x01;
+/// The card asked for more time to answer the request
+pub const FLAG_WAIT: u8 = 0x02;
+/// The request failed, and the payload says why
+pub const FLAG_ERROR: u8 = 0x04;
+
+/// Longest request line accepted, in bytes
+const MAX_REQUEST_LENGTH: usize = 64;
+
+/// Longest payload accepted: an extended APDU and its status word
+const MAX_PAYLOAD_LENGTH: usize = 65536 + 8;
+
+/// Payloads shorter than this are sent as they are
+const COMPRESS_MIN_LENGTH: usize = 64;
+
+/// Answers the APDUs the server refuses to send to the card: security status
+/// not satisfied
+const SW_NOT_ALLOWED: [u8; 2] = [0x69, 0x82];
+
+/// Requests read ahead of the one the card works on
+const PIPELINE_DEPTH: usize = 32;
+
+/// How long a session keeps its turn with a shared card after an answer,
+/// waiting for its next APDU, so that e.g. a VERIFY and the operation it
+/// allows aren't split by the APDUs of other sessions
+const SHARED_TURN: std::time::Duration = std::time::Duration::from_millis(100);
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Frame {
+    flags: u8,
+    id: u32,
+    payload: Vec<u8>,
+}
+
+impl Frame {
+    fn new(flags: u8, id: u32, payload: Vec<u8>) -> Self {
+        Self { flags, id, payload }
+    }
+
+    /// The frame on the wire, compressing its payload if that helps
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut flags = self.flags;
+        let mut payload = &self.payload;
+        let compressed;
+        if payload.len() >= COMPRESS_MIN_LENGTH {
+            let mut encoder = DeflateEncoder::new(vec![], Compression::fast());
+            encoder.write_all(payload).ok();
+            compressed = encoder.finish().unwrap_or_default();
+            if !compressed.is_empty() && compressed.len() < payload.len() {
+                flags |= FLAG_DEFLATE;
+                payload = &compressed;
+            }
+        }
+        let mut bytes = Vec::with_capacity(9 + payload.len());
+        bytes.push(flags);
+        bytes.extend(self.id.to_be_bytes());
+        bytes.extend((payload.len() as u32).to_be_bytes());
+        bytes.extend(payload);
+        bytes
+    }
+
+    /// Read a frame, or `None` if the connection was closed before one
+    async fn read<T: AsyncRead + Unpin>(socket: &mut T) -> Result<Option<Self>> {
+        let mut header = [0; 9];
+        match socket.read_exact(&mut header).await {
+            Ok(_) => (),
+            Err(err) if err.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+            Err(err) => return Err(err),
+        }
+        let invalid = |msg: &str| std::io::Error::new(ErrorKind::InvalidData, msg.to_string());
+        let flags = header[0];
+        let id = u32::from_be_bytes(header[1..5].try_into().unwrap());
+        let length = u32::from_be_bytes(header[5..9].try_into().unwrap()) as usize;
+        if length > MAX_PAYLOAD_LENGTH {
+            return Err(invalid("Frame too long"));
+        }
+        let mut payload = vec![0; length];
+        socket.read_exact(&mut payload).await?;
+        if flags & FLAG_DEFLATE != 0 {
+            let mut decompressed = vec![];
+            DeflateDecoder::new(&payload[..])
+                .take(MAX_PAYLOAD_LENGTH as u64 + 1)
+                .read_to_end(&mut decompressed)
+                .map_err(|_| invalid("Invalid compressed frame"))?;
+            if decompressed.len() > MAX_PAYLOAD_LENGTH {
+                return Err(invalid("Frame too long"));
+            }
+            payload = decompressed;
+        }
+        Ok(Some(Self::new(flags & !FLAG_DEFLATE, id, payload)))
+    }
+}
+
+/// Read the bus id of the APDU session a client may ask for instead of a
+/// USB/IP request
+pub(crate) async fn read_request<T: AsyncBufRead + Unpin>(
+    socket: &mut T,
+) -> Result<Option<String>> {
+    if !socket.fill_buf().await?.starts_with(&APDU_PREAMBLE[..1]) {
+        return Ok(None);
+    }
+    let mut line = vec![];
+    tokio::io::AsyncReadExt::take(&mut *socket, MAX_REQUEST_LENGTH as u64)
+        .read_until(b'\n', &mut line)
+        .await?;
+    line.strip_prefix(APDU_PREAMBLE)
+        .and_then(|line| line.strip_suffix(b"\n"))
+        .and_then(|line| std::str::from_utf8(line).ok())
+        .filter(|bus_id| !bus_id.is_empty() && !bus_id.contains(char::is_whitespace))
+        .map(|bus_id| Some(bus_id.to_string()))
+        .ok_or_else(|| std::io::Error::new(ErrorKind::InvalidData, "Invalid APDU request"))
+}
+
+/// Import `bus_id` for `peer` from `server` over an in-memory connection,
+/// and open its smart card reader
+async fn open_reader(
+    server: Arc<UsbIpServer>,
+    peer: &acl::Peer,
+    bus_id: &str,
+    shutdown: Option<watch::Receiver<bool>>,
+) -> Result<ccid::RemoteReader> {
+    let (mut client, mut socket) = tokio::io::duplex(MAX_PAYLOAD_LENGTH);
+    let peer = peer.clone();
+    tokio::spawn(async move {
+        // Sessions ask for approvals themselves, for their own clients
+        if let Err(err) = handler_with_shutdown(&mut socket, server, &peer, shutdown, None).await {
+            debug!("APDU session ended with {}", err);
+        }
+    });
+    let device = client::import(&mut client, bus_id).await?;
+    let mut reader = ccid::RemoteReader::open(client::ImportedDevice::new(client, device)).await?;
+    reader.power_on().await?;
+    Ok(reader)
+}
+
+/// A card the APDU sessions of a server take turns with, see
+/// [UsbIpServer::with_card_sharing]
+///
+/// Without sharing, each session has a card of its own.
+pub(crate) struct SharedCard {
+    reader: tokio::sync::Mutex<SharedReader>,
+    next_session: AtomicU64,
+}
+
+struct SharedReader {
+    reader: ccid::RemoteReader,
+    /// What the logical channels of each session selected
+    selections: ccid::Selections<(u64, u8)>,
+}
+
+impl SharedCard {
+    fn new(reader: ccid::RemoteReader) -> Self {
+        Self {
+            reader: tokio::sync::Mutex::new(SharedReader {
+                reader,
+                selections: Default::default(),
+            }),
+            next_session: Default::default(),
+        }
+    }
+}
+
+/// The card of the device with `bus_id` for the APDU session of `peer`: the
+/// one other sessions share if the server shares cards, or else a card of
+/// its own
+async fn open_card(
+    server: &Arc<UsbIpServer>,
+    peer: &acl::Peer,
+    bus_id: &str,
+    shutdown: Option<watch::Receiver<bool>>,
+) -> Result<Arc<SharedCard>> {
+    let Some(shared_cards) = &server.shared_cards else {
+        let reader = open_reader(server.clone(), peer, bus_id, shutdown).await?;
+        return Ok(Arc::new(SharedCard::new(reader)));
+    };
+    let mut shared_cards = shared_cards.lock().await;
+    if let Some(card) = shared_cards.get(bus_id).and_then(std::sync::Weak::upgrade) {
+        let device = server.devices.imported(bus_id).await.ok_or_else(|| {
+            std::io::Error::new(ErrorKind::NotFound, format!("Device {} is gone", bus_id))
+        })?;
+        if !server.may_import(peer, &device) {
+            return Err(std::io::Error::new(
+                ErrorKind::PermissionDenied,
+                "Permission denied",
+            ));
+        }
+        info!("{:?} joins the APDU sessions with {}", peer, bus_id);
+        return Ok(card);
+    }
+    let reader = open_reader(server.clone(), peer, bus_id, shutdown).await?;
+    let card = Arc::new(SharedCard::new(reader));
+    shared_cards.insert(bus_id.to_string(), Arc::downgrade(&card));
+    Ok(card)
+}
+
+/// Serve the APDU session `peer` asked for on `socket`
+pub(crate) async fn serve_session<T: AsyncRead + AsyncWrite + Unpin>(
+    socket: &mut T,
+    server: Arc<UsbIpServer>,
+    peer: &acl::Peer,
+    bus_id: &str,
+    mut shutdown: Option<watch::Receiver<bool>>,
+) -> Result<()> {
+    let card = match open_card(&server, peer, bus_id, shutdown.clone()).await {
+        Ok(card) => card,
+        Err(err) => {
+            socket
+                .write_all(format!("error {}\n", err).as_bytes())
+                .await?;
+            return socket.flush().await;
+        }
+    };
+    info!("Started an APDU session with {} for {:?}", bus_id, peer);
+    let device = server.devices.imported(bus_id).await;
+    let role = match &device {
+        Some(device) => server.role(peer, device),
+        None => acl::Role::default(),
+    };
+    let approver = approval::Approver::new(&server, peer);
+    socket.write_all(b"ok\n").await?;
+    socket.flush().await?;
+
+    let (mut input, mut output) = tokio::io::split(socket);
+    let (requests_tx, mut requests) = mpsc::channel(PIPELINE_DEPTH);
+    let (frames, mut outgoing) = mpsc::unbounded_channel::<Frame>();
+    let read = async move {
+        while let Some(frame) = Frame::read(&mut input).await? {
+            if requests_tx.send(frame).await.is_err() {
+                break;
+            }
+        }
+        Ok::<_, std::io::Error>(())
+    };
+    let session = card.next_session.fetch_add(1, Ordering::Relaxed);
+    let process = {
+        let card = card.clone();
+        async move {
+            // The logical channels of this session
+            let mut channels = ccid::OpenChannels::default();
+            // The card, while this session has its turn
+            let mut turn = None;
+            loop {
+                let request = match turn {
+                    Some(_) => match tokio::time::timeout(SHARED_TURN, requests.recv()).await {
+                        Ok(request) => request,
+                        Err(_) => {
+                            turn = None;
+                            requests.recv().await
+                        }
+                    },
+                    None => requests.recv().await,
+                };
+                let Some(request) = request else {
+                    break;
+                };
+                let id = request.id;
+                if let Some(&ins) = request
+                    .payload
+                    .get(1)
+                    .filter(|&&ins| server.refuses(role, audit::Transfer::Apdu(ins)))
+                {
+                    debug!("Refusing {} from {:?}", audit::instruction_name(ins), peer);
+                    frames.send(Frame::new(0, id, SW_NOT_ALLOWED.to_vec())).ok();
+                    continue;
+                }
+                if let Some((response, closed)) = channels.manage(&request.payload) {
+                    if let Some(channel) = closed {
+                        card.reader
+                            .lock()
+                            .await
+                            .selections
+                            .close(&(session, channel));
+                    }
+                    frames.send(Frame::new(0, id, response)).ok();
+                    continue;
+                }
+                let channel = (
+                    session,
+                    request
+                        .payload
+                        .first()
+                        .map_or(0, |&cla| ccid::logical_channel(cla)),
+                );
+                if !channels.is_open(channel.1) {
+                    frames
+                        .send(Frame::new(0, id, ccid::SW_CHANNEL_NOT_SUPPORTED.to_vec()))
+                        .ok();
+                    continue;
+                }
+                let apdu = ccid::on_basic_channel(&request.payload);
+                if let (Some(approver), Some(device)) = (&approver, &device) {
+                    // Not with the turn, which would hold up the other
+                    // sessions meanwhile
+                    if approver.asks(&apdu) {
+                        turn = None;
+                    }
+                    if !approver.approve(device, &apdu).await {
+                        frames.send(Frame::new(0, id, SW_NOT_ALLOWED.to_vec())).ok();
+                        continue;
+                    }
+                }
+                let shared = match &mut turn {
+                    Some(shared) => shared,
+                    None => turn.insert(card.reader.lock().await),
+                };
+                // Selecting the application of the channel anew also ends
+                // what the channel the card answered last verified
+                if let Some(select) = shared.selections.switch(&channel, &apdu) {
+                    if let Err(err) = shared.reader.transmit(&select, || ()).await {
+                        debug!("Selecting the application of {:?} failed: {}", channel, err);
+                    }
+                }
+                let res = shared
+                    .reader
+                    .transmit(&apdu, || {
+                        frames.send(Frame::new(FLAG_WAIT, id, vec![])).ok();
+                    })
+                    .await;
+                if let Ok(response) = &res {
+                    shared.selections.answered(&channel, &apdu, response);
+                }
+                let lost = res.as_ref().is_err_and(|err| {
+                    matches!(
+                        err.kind(),
+                        ErrorKind::BrokenPipe
+                            | ErrorKind::NotConnected
+                            | ErrorKind::ConnectionAborted
+                    )
+                });
+                frames
+                    .send(match res {
+                        Ok(response) => Frame::new(0, id, response),
+                        Err(err) => Frame::new(FLAG_ERROR, id, err.to_string().into_bytes()),
+                    })
+                    .ok();
+                if lost {
+                    // The import ended, e.g. the device was unplugged
+                    break;
+                }
+            }
+        }
+    };
+    let write = async move {
+        while let Some(frame) = outgoing.recv().await {
+            output.write_all(&frame.to_bytes()).await?;
+            output.flush().await?;
+        }
+        Ok::<_, std::io::Error>(())
+    };
+    let res = tokio::select! {
+        res = async { tokio::try_join!(read, async { process.await; Ok(()) }, write) } => res.map(|_| ()),
+        _ = shutdown_requested(&mut shutdown) => Ok(()),
+    };
+    card.reader
+        .lock()
+        .await
+        .selections
+        .close_where(|&(owner, _)| owner == session);
+    res
+}
+
+enum Reply {
+    Wait,
+    Done(Result<Vec<u8>>),
+}
+
+type PendingMap = Arc<Mutex<HashMap<u32, mpsc::UnboundedSender<Reply>>>>;
+
+/// The client end of an APDU session, see the [module docs](self)
+///
+/// APDUs may be sent concurrently from several tasks, or many at once with
+/// [Session::transmit_all]; they are sent without waiting for the answers to
+/// the previous ones, and the card answers them in turn.
+pub struct Session {
+    writer: tokio::sync::Mutex<Box<dyn AsyncWrite + Send + Unpin>>,
+    pending: PendingMap,
+    next_id: std::sync::atomic::AtomicU32,
+    reader: tokio::task::JoinHandle<()>,
+}
+
+impl Session {
+    /// Start a session with the card in the device with `bus_id`, on a new
+    /// connection to the server (after the token, if any)
+    pub async fn open<T: AsyncBufRead + AsyncWrite + Send + Unpin + 'static>(
+        mut socket: T,
+        bus_id: &str,
+    ) -> Result<Self> {
+        let mut line = APDU_PREAMBLE.to_vec();
+        line.extend(bus_id.as_bytes());
+        line.push(b'\n');
+        socket.write_all(&line).await?;
+        socket.flush().await?;
+        let mut reply = String::new();
+        socket.read_line(&mut reply).await?;
+        match reply.trim_end() {
+            "ok" => (),
+            reply => {
+                return Err(std::io::Error::other(
+                    reply
+                        .strip_prefix("error ")
+                        .unwrap_or("Server doesn't take APDU sessions")
+                        .to_string(),
+                ))
+            }
+        }
+
+        let (mut input, output) = tokio::io::split(socket);
+        let pending = PendingMap::default();
+        let reader = tokio::spawn({
+            let pending = pending.clone();
+            async move {
+                let err = loop {
+                    let frame = match Frame::read(&mut input).await {
+                        Ok(Some(frame)) => frame,
+                        Ok(None) => {
+                            break std::io::Error::new(ErrorKind::BrokenPipe, "Connection closed")
+                        }
+                        Err(err) => break err,
+                    };
+                    let mut pending = pending.lock().unwrap();
+                    let reply = if frame.flags & FLAG_WAIT != 0 {
+                        Reply::Wait
+                    } else if frame.flags & FLAG_ERROR != 0 {
+                        Reply::Done(Err(std::io::Error::other(
+                            String::from_utf8_lossy(&frame.payload).into_owned(),
+                        )))
+                    } else {
+                        Reply::Done(Ok(frame.payload))
+                    };
+                    let done = matches!(reply, Reply::Done(_));
+                    match pending.get(&frame.id) {
+                        Some(replies) => {
+                            replies.send(reply).ok();
+                        }
+                        None => warn!("Reply to unknown APDU {}", frame.id),
+                    }
+                    if done {
+                        pending.remove(&frame.id);
+                    }
+                };
+                for (_, replies) in pending.lock().unwrap().drain() {
+                    replies
+                        .send(Reply::Done(Err(std::io::Error::new(
+                            err.kind(),
+                            err.to_string(),
+                        ))))
+                        .ok();
+                }
+            }
+        });
+        Ok(Self {
+            writer: tokio::sync::Mutex::new(Box::new(output)),
+            pending,
+            next_id: Default::default(),
+            reader,
+        })
+    }
+
+    /// Send `apdu`, returning the replies to it as they come
+    async fn send(&self, apdu: &[u8]) -> Result<mpsc::UnboundedReceiver<Reply>> {
+        let id = self
+            .next_id
+            .fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+        let (replies, rx) = mpsc::unbounded_channel();
+        self.pending.lock().unwrap().insert(id, replies);
+        let mut writer = self.writer.lock().await;
+        let res = async {
+            writer
+                .write_all(&Frame::new(0, id, apdu.to_vec()).to_bytes())
+                .await?;
+            writer.flush().await
+        }
+        .await;
+        if let Err(err) = res {
+            self.pending.lock().unwrap().remove(&id);
+            return Err(err);
+        }
+        Ok(rx)
+    }
+
+    /// Wait for the response to an APDU sent with [Session::send]
+    async fn response(
+        mut replies: mpsc::UnboundedReceiver<Reply>,
+        mut on_wait: impl FnMut(),
+    ) -> Result<Vec<u8>> {
+        loop {
+            match replies.recv().await {
+                Some(Reply::Wait) => on_wait(),
+                Some(Reply::Done(res)) => return res,
+                None => {
+                    return Err(std::io::Error::new(
+                        ErrorKind::BrokenPipe,
+                        "Connection closed",
+                    ))
+                }
+            }
+        }
+    }
+
+    /// Send a command APDU to the card, returning its response APDU
+    ///
+    /// `on_wait` is called if the card asks for more time before answering,
+    /// which YubiKeys do while they wait for a touch.
+    pub async fn transmit(&self, apdu: &[u8], on_wait: impl FnMut()) -> Result<Vec<u8>> {
+        async {
+            let replies = self.send(apdu).await?;
+            Self::response(replies, on_wait).await
+        }
+        .instrument(ccid::apdu_span(apdu))
+        .await
+    }
+
+    /// Send all of `apdus` at once, returning their responses in order
+    pub async fn transmit_all(&self, apdus: &[Vec<u8>]) -> Vec<Result<Vec<u8>>> {
+        let mut sent = vec![];
+        for apdu in apdus {
+            sent.push(self.send(apdu).await);
+        }
+        let mut responses = vec![];
+        for replies in sent {
+            responses.push(match replies {
+                Ok(replies) => Self::response(replies, || ()).await,
+                Err(err) => Err(err),
+            });
+        }
+        responses
+    }
+}
+
+impl Drop for Session {
+    fn drop(&mut self) {
+        self.reader.abort();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::tests::*;
+
+    /// Echoes APDUs, with 90 00 appended
+    struct EchoCard;
+
+    impl ccid::CcidBackend for EchoCard {
+        fn power_on(&mut self) -> Result<Vec<u8>> {
+            Ok(vec![0x3B, 0x00])
+        }
+
+        fn power_off(&mut self) -> Result<()> {
+            Ok(())
+        }
+
+        fn transmit(&mut self, apdu: &[u8]) -> Result<Vec<u8>> {
+            Ok([apdu, &[0x90, 0x00]].concat())
+        }
+
+        fn present(&mut self) -> bool {
+            true
+        }
+    }
+
+    /// Answers APDUs with the AID it selected last, and 90 00
+    #[derive(Default)]
+    struct SelectingCard {
+        selected: Vec<u8>,
+    }
+
+    impl ccid::CcidBackend for SelectingCard {
+        fn power_on(&mut self) -> Result<Vec<u8>> {
+            Ok(vec![0x3B, 0x00])
+        }
+
+        fn power_off(&mut self) -> Result<()> {
+            Ok(())
+        }
+
+        fn transmit(&mut self, apdu: &[u8]) -> Result<Vec<u8>> {
+            if let [0x00, 0xA4, 0x04, 0x00, _, aid @ ..] = apdu {
+                self.selected = aid.to_vec();
+            }
+            Ok([&self.selected[..], &[0x90, 0x00]].concat())
+        }
+
+        fn present(&mut self) -> bool {
+            true
+        }
+    }
+
+    #[test]
+    fn frames() {
+        let frame = Frame::new(0, 7, vec![0; 1000]);
+        let bytes = frame.to_bytes();
+        assert_eq!(bytes[0], FLAG_DEFLATE);
+        assert!(bytes.len() < 100);
+        let mut socket = &bytes[..];
+        let read = tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap()
+            .block_on(Frame::read(&mut socket))
+            .unwrap();
+        assert_eq!(read, Some(frame));
+
+        // Short payloads aren't worth compressing
+        assert_eq!(
+            Frame::new(FLAG_WAIT, 1, vec![]).to_bytes(),
+            [FLAG_WAIT, 0, 0, 0, 1, 0, 0, 0, 0]
+        );
+    }
+
+    #[tokio::test]
+    async fn apdu_session() {
+        setup_test_logger();
+        let device = UsbDevice::new(0).with_interface(
+            ClassCode::SmartCard as u8,
+            ccid::CCID_SUBCLASS,
+            0x00,
+            "Test CCID",
+            ccid::UsbCcidHandler::<EchoCard>::endpoints(),
+            Arc::new(Mutex::new(Box::new(ccid::UsbCcidHandler::new(EchoCard))
+                as Box<dyn UsbInterfaceHandler + Send>)),
+        );
+        let bus_id = device.bus_id.clone();
+        let server = Arc::new(UsbIpServer::new_simulated(vec![device]));
+        let addr = get_free_address().await;
+        tokio::spawn(crate::server(addr, server.clone()));
+
+        let socket = tokio::io::BufStream::new(poll_connect(addr).await);
+        let session = Session::open(socket, &bus_id).await.unwrap();
+        let apdus: Vec<Vec<u8>> = (0..10u8)
+            .map(|n| vec![0x00, 0xCA, 0x00, n, 0x00])
+            .chain([vec![0xAB; 600]])
+            .collect();
+        let responses = session.transmit_all(&apdus).await;
+        for (apdu, response) in apdus.iter().zip(responses) {
+            assert_eq!(response.unwrap(), [&apdu[..], &[0x90, 0x00]].concat());
+        }
+        let response = session.transmit(&[0x00, 0xA4, 0x04, 0x00], || ()).await;
+        assert_eq!(response.unwrap(), [0x00, 0xA4, 0x04, 0x00, 0x90, 0x00]);
+
+        // The device is imported while the session lasts
+        let mut socket = TcpStream::connect(addr).await.unwrap();
+        let busy = client::import(&mut socket, &bus_id).await;
+        assert_eq!(
+            busy.err().map(|err| err.kind()),
+            Some(ErrorKind::ResourceBusy)
+        );
+
+        let socket = tokio::io::BufStream::new(TcpStream::connect(addr).await.unwrap());
+        assert!(Session::open(socket, "1-9").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn read_only_session() {
+        setup_test_logger();
+        let device = UsbDevice::new(0).with_interface(
+            ClassCode::SmartCard as u8,
+            ccid::CCID_SUBCLASS,
+            0x00,
+            "Test CCID",
+            ccid::UsbCcidHandler::<EchoCard>::endpoints(),
+            Arc::new(Mutex::new(Box::new(ccid::UsbCcidHandler::new(EchoCard))
+                as Box<dyn UsbInterfaceHandler + Send>)),
+        );
+        let bus_id = device.bus_id.clone();
+        let server = Arc::new(UsbIpServer::new_simulated(vec![device]).with_read_only(true));
+        let addr = get_free_address().await;
+        tokio::spawn(crate::server(addr, server));
+
+        let socket = tokio::io::BufStream::new(poll_connect(addr).await);
+        let session = Session::open(socket, &bus_id).await.unwrap();
+        // Reading and signing reach the card, generating a key doesn't
+        let sign = [0x00, 0x87, 0x11, 0x9A, 0x00];
+        let response = session.transmit(&sign, || ()).await;
+        assert_eq!(response.unwrap(), [&sign[..], &[0x90, 0x00]].concat());
+        let generate = [0x00, 0x47, 0x00, 0x9A, 0x00];
+        let response = session.transmit(&generate, || ()).await;
+        assert_eq!(response.unwrap(), SW_NOT_ALLOWED);
+        // Nor do the writes of the other applications: OpenPGP PUT DATA and
+        // TERMINATE DF, OATH PUT and RESET
+        for apdu in [
+            &[0x00, 0xDA, 0x00, 0x5B, 0x01, 0x41][..],
+            &[0x00, 0xE6, 0x00, 0x00],
+            &[0x00, 0x01, 0x00, 0x00, 0x03, 0x71, 0x01, 0x41],
+            &[0x00, 0x04, 0xDE, 0xAD],
+        ] {
+            let response = session.transmit(apdu, || ()).await;
+            assert_eq!(response.unwrap(), SW_NOT_ALLOWED);
+        }
+    }
+
+    #[tokio::test]
+    async fn role_session() {
+        setup_test_logger();
+        let device = UsbDevice::new(0).with_interface(
+            ClassCode::SmartCard as u8,
+            ccid::CCID_SUBCLASS,
+            0x00,
+            "Test CCID",
+            ccid::UsbCcidHandler::<EchoCard>::endpoints(),
+            Arc::new(Mutex::new(Box::new(ccid::UsbCcidHandler::new(EchoCard))
+                as Box<dyn UsbInterfaceHandler + Send>)),
+        );
+        let bus_id = device.bus_id.clone();
+        let policy = acl::Policy::parse("allow token:audit any role:attest-only").unwrap();
+        let server = Arc::new(UsbIpServer::new_simulated(vec![device]).with_policy(policy));
+        let addr = get_free_address().await;
+        tokio::spawn(crate::server(addr, server));
+
+        let mut socket = tokio::io::BufStream::new(poll_connect(addr).await);
+        client::send_token(&mut socket, "audit").await.unwrap();
+        let session = Session::open(socket, &bus_id).await.unwrap();
+        let attest = [0x00, 0xF9, 0x9A, 0x00, 0x00];
+        let response = session.transmit(&attest, || ()).await;
+        assert_eq!(response.unwrap(), [&attest[..], &[0x90, 0x00]].concat());
+        let sign = [0x00, 0x87, 0x11, 0x9A, 0x00];
+        let response = session.transmit(&sign, || ()).await;
+        assert_eq!(response.unwrap(), SW_NOT_ALLOWED);
+    }
+
+    #[tokio::test]
+    async fn shared_session() {
+        setup_test_logger();
+        let device = UsbDevice::new(0).with_interface(
+            ClassCode::SmartCard as u8,
+            ccid::CCID_SUBCLASS,
+            0x00,
+            "Test CCID",
+            ccid::UsbCcidHandler::<SelectingCard>::endpoints(),
+            Arc::new(Mutex::new(
+                Box::new(ccid::UsbCcidHandler::new(SelectingCard::default()))
+                    as Box<dyn UsbInterfaceHandler + Send>,
+            )),
+        );
+        let bus_id = device.bus_id.clone();
+        let server = Arc::new(UsbIpServer::new_simulated(vec![device]).with_card_sharing(true));
+        let addr = get_free_address().await;
+        tokio::spawn(crate::server(addr, server));
+
+        let socket = tokio::io::BufStream::new(poll_connect(addr).await);
+        let first = Session::open(socket, &bus_id).await.unwrap();
+        let socket = tokio::io::BufStream::new(TcpStream::connect(addr).await.unwrap());
+        let second = Session::open(socket, &bus_id).await.unwrap();
+
+        let response = first
+            .transmit(&[0x00, 0xA4, 0x04, 0x00, 0x01, 0xA1], || ())
+            .await;
+        assert_eq!(response.unwrap(), [0xA1, 0x90, 0x00]);
+        let response = second
+            .transmit(&[0x00, 0xA4, 0x04, 0x00, 0x01, 0xA2], || ())
+            .await;
+        assert_eq!(response.unwrap(), [0xA2, 0x90, 0x00]);
+        // Each session finds its own application selected
+        let response = first.transmit(&[0x00, 0xCA, 0x00, 0x00], || ()).await;
+        assert_eq!(response.unwrap(), [0xA1, 0x90, 0x00]);
+        let response = second.transmit(&[0x00, 0xCA, 0x00, 0x00], || ()).await;
+        assert_eq!(response.unwrap(), [0xA2, 0x90, 0x00]);
+
+        // And each logical channel of a session
+        let response = first.transmit(&[0x00, 0x70, 0x00, 0x00, 0x01], || ()).await;
+        assert_eq!(response.unwrap(), [0x01, 0x90, 0x00]);
+        let response = first
+            .transmit(&[0x01, 0xA4, 0x04, 0x00, 0x01, 0xA3], || ())
+            .await;
+        assert_eq!(response.unwrap(), [0xA3, 0x90, 0x00]);
+        let response = first.transmit(&[0x00, 0xCA, 0x00, 0x00], || ()).await;
+        assert_eq!(response.unwrap(), [0xA1, 0x90, 0x00]);
+        let response = second.transmit(&[0x01, 0xCA, 0x00, 0x00], || ()).await;
+        assert_eq!(response.unwrap(), ccid::SW_CHANNEL_NOT_SUPPORTED);
+
+        // Both sessions end, and the device can be imported again
+        drop((first, second));
+        tokio::time::sleep(std::time::Duration::from_millis(200)).await;
+        let socket = tokio::io::BufStream::new(TcpStream::connect(addr).await.unwrap());
+        assert!(Session::open(socket, &bus_id).await.is_ok());
+    }
+}
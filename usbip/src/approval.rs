@@ -0,0 +1,234 @@
+//! Approval of key use on the host
+//!
+//! A touch policy proves that someone is at the YubiKey, but not that it is
+//! its owner approving what a remote client does with it. A server with
+//! [crate::UsbIpServer::with_approval] holds each APDU that uses a key of a
+//! smart card, GENERAL AUTHENTICATE, which signs, decrypts and is what waits
+//! for a touch, until it is approved on the host. The server publishes an
+//! [events::Event::ApprovalRequired] with an id for each:
+//!
+//! ```text
+//! approve 7 1-2 12345678
+//! ```
+//!
+//! which [crate::UsbIpServer::answer_approval], or the admin requests
+//! `approve ID` and `deny ID` (see [crate::admin]), answer. A request that
+//! isn't answered in time is denied. The URB of a denied APDU fails as if
+//! the server refused it, and in APDU sessions it is answered with 69 82.
+//!
+//! The commands of a chain need only one approval, for the first.
+use super::*;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use tokio::sync::{broadcast, oneshot};
+
+/// GENERAL AUTHENTICATE
+const INS_GENERAL_AUTHENTICATE: u8 = 0x87;
+
+/// Whether an APDU with instruction `ins` is held for approval
+pub fn needs_approval(ins: u8) -> bool {
+    ins == INS_GENERAL_AUTHENTICATE
+}
+
+/// The approvals a server waits for
+pub(crate) struct Approvals {
+    timeout: std::time::Duration,
+    next_id: AtomicU64,
+    pending: Mutex<HashMap<u64, oneshot::Sender<bool>>>,
+}
+
+impl Approvals {
+    pub(crate) fn new(timeout: std::time::Duration) -> Self {
+        Self {
+            timeout,
+            next_id: AtomicU64::new(1),
+            pending: Default::default(),
+        }
+    }
+
+    /// Ask for the approval of key use on `device` by `peer`, and wait for
+    /// it
+    async fn request(
+        &self,
+        device: &UsbDevice,
+        peer: &acl::Peer,
+        events: &broadcast::Sender<events::Event>,
+    ) -> bool {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let (answer, answered) = oneshot::channel();
+        self.pending.lock().unwrap().insert(id, answer);
+        info!(
+            "Waiting for approval {} of key use on {} by {:?}",
+            id, device.bus_id, peer
+        );
+        events
+            .send(events::Event::ApprovalRequired {
+                id,
+                bus_id: device.bus_id.clone(),
+                serial: device.serial_number().map(str::to_string),
+            })
+            .ok();
+        let approved = match tokio::time::timeout(self.timeout, answered).await {
+            Ok(Ok(approved)) => approved,
+            _ => {
+                self.pending.lock().unwrap().remove(&id);
+                warn!("Approval {} timed out", id);
+                false
+            }
+        };
+        if !approved {
+            warn!(
+                "Denied key use on {} by {:?} (approval {})",
+                device.bus_id, peer, id
+            );
+        }
+        approved
+    }
+
+    /// Approve or deny the request with `id`
+    pub(crate) fn answer(&self, id: u64, approved: bool) -> Result<()> {
+        let answer = self.pending.lock().unwrap().remove(&id).ok_or_else(|| {
+            std::io::Error::new(ErrorKind::NotFound, format!("No approval {}", id))
+        })?;
+        answer
+            .send(approved)
+            .map_err(|_| std::io::Error::new(ErrorKind::NotFound, format!("No approval {}", id)))
+    }
+}
+
+/// Asks for the approvals of one connection or APDU session
+#[derive(Clone)]
+pub(crate) struct Approver {
+    approvals: Arc<Approvals>,
+    events: broadcast::Sender<events::Event>,
+    peer: acl::Peer,
+    /// Whether the last APDU approved is continued by the next one
+    chained: Arc<AtomicBool>,
+}
+
+impl Approver {
+    /// Ask `server` for approvals of the key use of `peer`, if it wants them
+    pub(crate) fn new(server: &UsbIpServer, peer: &acl::Peer) -> Option<Self> {
+        Some(Self {
+            approvals: server.approvals.clone()?,
+            events: server.events.clone(),
+            peer: peer.clone(),
+            chained: Default::default(),
+        })
+    }
+
+    /// Whether `apdu` waits for an approval
+    #[cfg(feature = "apdu")]
+    pub(crate) fn asks(&self, apdu: &[u8]) -> bool {
+        apdu.get(1).is_some_and(|&ins| needs_approval(ins)) && !self.chained.load(Ordering::SeqCst)
+    }
+
+    /// Whether `apdu` to `device` may be sent to the card, after waiting for
+    /// its approval if it needs one
+    pub(crate) async fn approve(&self, device: &UsbDevice, apdu: &[u8]) -> bool {
+        let &[cla, ins, ..] = apdu else {
+            return true;
+        };
+        if !needs_approval(ins) {
+            self.chained.store(false, Ordering::SeqCst);
+            return true;
+        }
+        if self.chained.swap(cla & 0x10 != 0, Ordering::SeqCst) {
+            return true;
+        }
+        let approved = self
+            .approvals
+            .request(device, &self.peer, &self.events)
+            .await;
+        if !approved {
+            self.chained.store(false, Ordering::SeqCst);
+        }
+        approved
+    }
+
+    /// Whether the URB with `header` and `data` to `device` may be handled,
+    /// after waiting for the approval of the APDU it carries if it needs one
+    pub(crate) async fn approve_urb(
+        &self,
+        device: &UsbDevice,
+        header: &UsbIpHeaderBasic,
+        data: &[u8],
+    ) -> bool {
+        match audit::apdu_instruction(device, header, data) {
+            // Past the header of the PC_to_RDR_XfrBlock
+            Some(_) => self.approve(device, &data[10..]).await,
+            None => true,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::tests::*;
+
+    /// Echoes APDUs, with 90 00 appended
+    struct EchoCard;
+
+    impl ccid::CcidBackend for EchoCard {
+        fn power_on(&mut self) -> Result<Vec<u8>> {
+            Ok(vec![0x3B, 0x00])
+        }
+
+        fn power_off(&mut self) -> Result<()> {
+            Ok(())
+        }
+
+        fn transmit(&mut self, apdu: &[u8]) -> Result<Vec<u8>> {
+            Ok([apdu, &[0x90, 0x00]].concat())
+        }
+    }
+
+    #[tokio::test]
+    async fn approve_key_use() {
+        setup_test_logger();
+        let mut device = UsbDevice::new(0).with_interface(
+            ClassCode::SmartCard as u8,
+            ccid::CCID_SUBCLASS,
+            0x00,
+            "Test CCID",
+            ccid::UsbCcidHandler::<EchoCard>::endpoints(),
+            Arc::new(Mutex::new(Box::new(ccid::UsbCcidHandler::new(EchoCard))
+                as Box<dyn UsbInterfaceHandler + Send>)),
+        );
+        device.set_serial_number("12345678");
+        let server = Arc::new(
+            UsbIpServer::new_simulated(vec![device])
+                .with_approval(std::time::Duration::from_millis(500)),
+        );
+        let loopback = loopback::Loopback::with_server(server.clone());
+        let mut reader = ccid::RemoteReader::open(loopback.import("0-0-0").await.unwrap())
+            .await
+            .unwrap();
+        let mut events = server.subscribe();
+        reader.power_on().await.unwrap();
+
+        // Other APDUs aren't held
+        let select = [0x00, 0xA4, 0x04, 0x00];
+        let response = reader.transmit(&select, || ()).await.unwrap();
+        assert_eq!(response, [&select[..], &[0x90, 0x00]].concat());
+
+        let sign = [0x00, 0x87, 0x11, 0x9A, 0x00];
+        let approve = async {
+            let events::Event::ApprovalRequired { id, bus_id, serial } =
+                events.recv().await.unwrap()
+            else {
+                panic!("Not an approval");
+            };
+            assert_eq!(bus_id, "0-0-0");
+            assert_eq!(serial.as_deref(), Some("12345678"));
+            server.answer_approval(id, true).unwrap();
+            id
+        };
+        let (response, id) = tokio::join!(reader.transmit(&sign, || ()), approve);
+        assert_eq!(response.unwrap(), [&sign[..], &[0x90, 0x00]].concat());
+        assert!(server.answer_approval(id, true).is_err());
+
+        // Unanswered requests are denied, failing the URB
+        assert!(reader.transmit(&sign, || ()).await.is_err());
+    }
+}
@@ -0,0 +1,374 @@
+//! Audit records of what clients do
+//!
+//! A server given a sender with [crate::UsbIpServer::with_audit] sends it a
+//! [Record] when a client connects and disconnects, imports a device or fails
+//! to, loses it, uses it and when the device waits for a touch, for a log of
+//! who used which device. What clients ask the devices is only counted: URBs,
+//! and for smart cards the APDUs by instruction, never their data.
+use super::*;
+use std::collections::BTreeMap;
+
+/// Something a client did, or that happened to it
+#[derive(Debug, Clone)]
+pub enum Record {
+    Connected {
+        connection: u64,
+        peer: acl::Peer,
+    },
+    Imported {
+        connection: u64,
+        bus_id: String,
+        /// The serial number string of the device, which for a YubiKey is
+        /// its serial, if it exposes it
+        serial: Option<String>,
+    },
+    /// An import failed: the device is unknown or denied to the client, or
+    /// `busy` with another
+    ImportFailed {
+        connection: u64,
+        bus_id: String,
+        busy: bool,
+    },
+    /// A device the client imported waits for a touch
+    TouchRequired {
+        connection: u64,
+        bus_id: String,
+    },
+    /// A PIN or PUK check the client sent to a smart card failed, the
+    /// `failures`th in a row (see [crate::throttle])
+    PinFailed {
+        connection: u64,
+        bus_id: String,
+        failures: u32,
+    },
+    /// The device was taken away from the client: unplugged, revoked, or its
+    /// lease expired
+    Evicted {
+        connection: u64,
+        bus_id: String,
+        reason: String,
+    },
+    /// The client let go of a device, having used it so much
+    Released {
+        connection: u64,
+        bus_id: String,
+        usage: Usage,
+    },
+    Disconnected {
+        connection: u64,
+        /// Why the connection failed, if it did
+        error: Option<String>,
+    },
+    /// A request of [crate::admin], and why it failed if it did
+    Admin {
+        peer: acl::Peer,
+        request: String,
+        error: Option<String>,
+    },
+}
+
+/// How much a client used a device it imported
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Usage {
+    pub urbs: u64,
+    /// URBs that failed or stalled
+    pub failed_urbs: u64,
+    /// APDUs sent to a smart card, by the name of their instruction (see
+    /// [instruction_name])
+    pub apdus: BTreeMap<String, u64>,
+}
+
+/// The name of the instruction `ins` of an APDU, for the ones of ISO 7816-4,
+/// PIV and the YubiKey's PIV extensions, or its value in hex
+pub fn instruction_name(ins: u8) -> String {
+    match ins {
+        0x20 => "VERIFY",
+        0x24 => "CHANGE REFERENCE DATA",
+        0x2C => "RESET RETRY COUNTER",
+        0x47 => "GENERATE ASYMMETRIC KEY PAIR",
+        0x87 => "GENERAL AUTHENTICATE",
+        0xA4 => "SELECT",
+        0xC0 => "GET RESPONSE",
+        0xCB => "GET DATA",
+        0xDB => "PUT DATA",
+        0xF6 => "MOVE KEY",
+        0xF7 => "GET METADATA",
+        0xF8 => "GET SERIAL",
+        0xF9 => "ATTEST",
+        0xFA => "SET PIN RETRIES",
+        0xFB => "RESET",
+        0xFD => "GET VERSION",
+        0xFE => "IMPORT KEY",
+        0xFF => "SET MANAGEMENT KEY",
+        ins => return format!("INS {:02X}", ins),
+    }
+    .to_string()
+}
+
+/// Whether an APDU with instruction `ins` may change the card: its PINs,
+/// keys, certificates, credentials or configuration, as refused by
+/// [UsbIpServer::with_read_only]
+///
+/// The applications of a YubiKey (PIV, OpenPGP, OATH, management, OTP) number
+/// their instructions independently, so only the ones known to leave all of
+/// them unchanged are let through, whichever application is selected.
+pub fn changes_card(ins: u8) -> bool {
+    !matches!(
+        ins,
+        // VERIFY, PERFORM SECURITY OPERATION (OpenPGP signing and decryption),
+        // GET CHALLENGE, GENERAL AUTHENTICATE, INTERNAL AUTHENTICATE, OATH LIST,
+        // CALCULATE and VALIDATE, SELECT (and OATH CALCULATE ALL), SEND
+        // REMAINING, READ BINARY, GET RESPONSE, GET DATA (OpenPGP and PIV),
+        // the management application's READ CONFIG, and the YubiKey's GET
+        // METADATA, GET SERIAL, ATTEST and GET VERSION
+        0x20 | 0x2A
+            | 0x84
+            | 0x87
+            | 0x88
+            | 0xA1
+            | 0xA2
+            | 0xA3
+            | 0xA4
+            | 0xA5
+            | 0xB0
+            | 0xC0
+            | 0xCA
+            | 0xCB
+            | 0x1D
+            | 0xF7
+            | 0xF8
+            | 0xF9
+            | 0xFD
+    )
+}
+
+/// What a URB asks of a device, for [UsbIpServer::with_read_only] and
+/// [acl::Role] to judge
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Transfer {
+    /// A PC_to_RDR_XfrBlock to a smart card reader, carrying an APDU with
+    /// this instruction
+    Apdu(u8),
+    /// Standard requests, and what a smart card reader answers itself:
+    /// powering the card, its slot status and parameters, and reading it
+    Other,
+    /// Reading another interface than a smart card reader, e.g. the
+    /// responses of a FIDO authenticator, or a class or vendor request for
+    /// data from it
+    Read,
+    /// Writing to another interface than a smart card reader, e.g. a FIDO
+    /// authenticator or the configuration of an OTP keyboard, a class or
+    /// vendor request to it, or a message to a smart card reader that can't
+    /// be checked: split across URBs, or one of its escapes to the vendor's
+    /// commands
+    Write,
+}
+
+impl std::fmt::Display for Transfer {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Transfer::Apdu(ins) => f.write_str(&instruction_name(*ins)),
+            Transfer::Other => f.write_str("a transfer"),
+            Transfer::Read => f.write_str("a read of another interface than a smart card's"),
+            Transfer::Write => f.write_str("a write other than a whole CCID message"),
+        }
+    }
+}
+
+/// What the URB with `header`, `setup` and `data` asks of `device`
+pub(crate) fn transfer(
+    device: &UsbDevice,
+    header: &UsbIpHeaderBasic,
+    setup: &[u8; 8],
+    data: &[u8],
+) -> Transfer {
+    let smart_card = |intf: &UsbInterface| intf.interface_class == ClassCode::SmartCard as u8;
+    if header.ep == 0 {
+        // Standard requests are the business of the host's USB stack, and
+        // smart card readers only take ABORT and reads as class requests.
+        let [request_type, _, _, _, index, ..] = *setup;
+        let interface = (request_type & 0x1F == 0x01)
+            .then(|| device.interfaces.get(index as usize))
+            .flatten();
+        return if request_type & 0x60 == 0 || interface.is_some_and(smart_card) {
+            Transfer::Other
+        } else if request_type & 0x80 != 0 {
+            Transfer::Read
+        } else {
+            Transfer::Write
+        };
+    }
+    let address = if header.direction == 0 {
+        header.ep as u8
+    } else {
+        header.ep as u8 | 0x80
+    };
+    match device.find_ep(address) {
+        Some((_, Some(intf))) if smart_card(intf) && header.direction != 0 => Transfer::Other,
+        Some((ep, Some(intf)))
+            if smart_card(intf) && ep.attributes == EndpointAttributes::Bulk as u8 =>
+        {
+            ccid_message(data)
+        }
+        _ if header.direction != 0 => Transfer::Read,
+        _ => Transfer::Write,
+    }
+}
+
+/// What the CCID message `data` asks of a smart card reader, if it is a whole
+/// one
+fn ccid_message(data: &[u8]) -> Transfer {
+    // A 10 byte header: bMessageType, dwLength, bSlot, bSeq, then for a
+    // PC_to_RDR_XfrBlock bBWI and wLevelParameter; then dwLength bytes
+    let [kind, l0, l1, l2, l3, _, _, _, level0, level1, rest @ ..] = data else {
+        return Transfer::Write;
+    };
+    if usize::try_from(u32::from_le_bytes([*l0, *l1, *l2, *l3])).ok() != Some(rest.len()) {
+        return Transfer::Write;
+    }
+    match (kind, u16::from_le_bytes([*level0, *level1]), rest) {
+        // A PC_to_RDR_XfrBlock holding a whole APDU, or the start of one
+        // (whose continuations are refused): CLA INS P1 P2
+        (0x6F, 0x0000 | 0x0001, [_, ins, _, _, ..]) => Transfer::Apdu(*ins),
+        // PC_to_RDR_SetParameters, IccPowerOn, IccPowerOff, GetSlotStatus,
+        // GetParameters, ResetParameters, Abort and
+        // SetDataRateAndClockFrequency
+        (0x61 | 0x62 | 0x63 | 0x65 | 0x6C | 0x6D | 0x72 | 0x73, _, _) => Transfer::Other,
+        _ => Transfer::Write,
+    }
+}
+
+/// The instruction of the APDU a URB to a bulk OUT endpoint of a smart card
+/// reader carries, if it carries one
+pub(crate) fn apdu_instruction(
+    device: &UsbDevice,
+    header: &UsbIpHeaderBasic,
+    data: &[u8],
+) -> Option<u8> {
+    if header.ep == 0 {
+        return None;
+    }
+    match transfer(device, header, &[0; 8], data) {
+        Transfer::Apdu(ins) => Some(ins),
+        _ => None,
+    }
+}
+
+/// Sends the records of one connection, if the server keeps any
+#[derive(Clone)]
+pub(crate) struct Auditor {
+    records: Option<mpsc::UnboundedSender<Record>>,
+    pub(crate) connection: u64,
+    usage: Arc<Mutex<Usage>>,
+}
+
+impl Auditor {
+    pub(crate) fn new(server: &UsbIpServer, connection: u64) -> Self {
+        Self {
+            records: server.audit.clone(),
+            connection,
+            usage: Default::default(),
+        }
+    }
+
+    pub(crate) fn record(&self, record: Record) {
+        if let Some(records) = &self.records {
+            records.send(record).ok();
+        }
+    }
+
+    /// The instruction of the APDU in a URB to `device`, to [Self::count]
+    /// along with its response, if it is one and we keep records
+    pub(crate) fn apdu(
+        &self,
+        device: &UsbDevice,
+        header: &UsbIpHeaderBasic,
+        data: &[u8],
+    ) -> Option<u8> {
+        self.records.as_ref()?;
+        apdu_instruction(device, header, data)
+    }
+
+    /// Count a URB, carrying the APDU with instruction `apdu` if any, and
+    /// its response
+    pub(crate) fn count(&self, apdu: Option<u8>, res: &UsbIpResponse) {
+        if self.records.is_none() {
+            return;
+        }
+        let failed = !matches!(res, UsbIpResponse::UsbIpRetSubmit { status: 0, .. });
+        let mut usage = self.usage.lock().unwrap();
+        usage.urbs += 1;
+        usage.failed_urbs += failed as u64;
+        if let Some(ins) = apdu {
+            *usage.apdus.entry(instruction_name(ins)).or_default() += 1;
+        }
+    }
+
+    /// Record that the client let go of `bus_id`, and start counting anew
+    pub(crate) fn released(&self, bus_id: &str) {
+        let usage = std::mem::take(&mut *self.usage.lock().unwrap());
+        self.record(Record::Released {
+            connection: self.connection,
+            bus_id: bus_id.to_string(),
+            usage,
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::tests::*;
+
+    #[tokio::test]
+    async fn records_connection() {
+        setup_test_logger();
+        let device = UsbDevice::new(0).with_interface(
+            ClassCode::SmartCard as u8,
+            ccid::CCID_SUBCLASS,
+            0x00,
+            "Test CCID",
+            vec![UsbEndpoint {
+                address: 0x02,
+                attributes: EndpointAttributes::Bulk as u8,
+                max_packet_size: 64,
+                interval: 0,
+            }],
+            Arc::new(Mutex::new(
+                Box::new(cdc::UsbCdcAcmHandler::new()) as Box<dyn UsbInterfaceHandler + Send>
+            )),
+        );
+        let bus_id = device.bus_id.clone();
+        let (records, mut rx) = mpsc::unbounded_channel();
+        let addr = get_free_address().await;
+        tokio::spawn(server(
+            addr,
+            Arc::new(UsbIpServer::new_simulated(vec![device]).with_audit(records)),
+        ));
+
+        let mut socket = poll_connect(addr).await;
+        let device = client::import(&mut socket, &bus_id).await.unwrap();
+        let device = client::ImportedDevice::new(socket, device);
+        // A SELECT in a PC_to_RDR_XfrBlock
+        let mut xfr_block = vec![0x6F, 5, 0, 0, 0, 0, 0, 0, 0, 0];
+        xfr_block.extend([0x00, 0xA4, 0x04, 0x00, 0x00]);
+        device.transfer_out(0x02, xfr_block).await.unwrap();
+        drop(device);
+
+        assert!(matches!(rx.recv().await, Some(Record::Connected { .. })));
+        assert!(matches!(
+            rx.recv().await,
+            Some(Record::Imported { bus_id: b, .. }) if b == bus_id
+        ));
+        let Some(Record::Released { usage, .. }) = rx.recv().await else {
+            panic!("expected a release");
+        };
+        assert_eq!(usage.urbs, 1);
+        assert_eq!(usage.failed_urbs, 0);
+        assert_eq!(usage.apdus.get("SELECT"), Some(&1));
+        assert!(matches!(
+            rx.recv().await,
+            Some(Record::Disconnected { error: None, .. })
+        ));
+    }
+}
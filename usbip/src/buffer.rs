@@ -0,0 +1,88 @@
+//! Reusable buffers for writing responses
+//!
+//! Encoding each response into a [Vec] of its own costs an allocation per
+//! URB, and for bulk IN transfers a second copy of their data. A connection
+//! instead encodes the responses it has ready with
+//! [UsbIpResponse::encode](crate::usbip_protocol::UsbIpResponse::encode)
+//! into one buffer taken from the [BufferPool] of its server, writes them at
+//! once and gives the buffer back, so that a busy server allocates about as
+//! many buffers as it has connections writing at the same time.
+use bytes::BytesMut;
+use std::sync::{Arc, Mutex};
+
+/// Capacity of new buffers, enough for a few full-speed bulk transfers
+pub const BUFFER_CAPACITY: usize = 16 * 1024;
+
+/// Buffers kept for reuse
+const MAX_POOLED_BUFFERS: usize = 64;
+
+/// Buffers that grew larger than this are dropped rather than kept, so one
+/// large transfer doesn't pin its memory for good
+const MAX_POOLED_CAPACITY: usize = 1024 * 1024;
+
+/// A pool of [BytesMut] buffers, cheap to clone and shared by its clones
+#[derive(Clone, Default)]
+pub struct BufferPool {
+    free: Arc<Mutex<Vec<BytesMut>>>,
+}
+
+impl BufferPool {
+    /// Take an empty buffer from the pool, or allocate one
+    pub fn get(&self) -> BytesMut {
+        self.free
+            .lock()
+            .unwrap()
+            .pop()
+            .unwrap_or_else(|| BytesMut::with_capacity(BUFFER_CAPACITY))
+    }
+
+    /// Give `buf` back for reuse
+    pub fn put(&self, mut buf: BytesMut) {
+        if buf.capacity() > MAX_POOLED_CAPACITY {
+            return;
+        }
+        buf.clear();
+        let mut free = self.free.lock().unwrap();
+        if free.len() < MAX_POOLED_BUFFERS {
+            free.push(buf);
+        }
+    }
+
+    /// Number of buffers waiting for reuse
+    pub fn len(&self) -> usize {
+        self.free.lock().unwrap().len()
+    }
+
+    /// Whether no buffer waits for reuse
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bytes::BufMut;
+
+    #[test]
+    fn reuse() {
+        let pool = BufferPool::default();
+        let mut buf = pool.get();
+        buf.put_slice(b"data");
+        let ptr = buf.as_ptr();
+        pool.put(buf);
+        assert_eq!(pool.len(), 1);
+
+        // The same memory comes back, emptied
+        let buf = pool.get();
+        assert!(buf.is_empty());
+        assert_eq!(buf.as_ptr(), ptr);
+        assert!(pool.is_empty());
+
+        // Buffers that grew too large are not kept
+        let mut buf = buf;
+        buf.reserve(2 * MAX_POOLED_CAPACITY);
+        pool.put(buf);
+        assert!(pool.is_empty());
+    }
+}
@@ -0,0 +1,654 @@
+//! Building simulated devices
+//!
+//! [UsbDeviceBuilder] puts a [UsbDevice] together from its identity, strings
+//! and [UsbInterfaceBuilder]s, and checks on [UsbDeviceBuilder::build] that
+//! the descriptors the server will make of them are ones a host accepts:
+//! endpoint addresses, packet sizes and intervals that fit the transfer type
+//! and speed, well-formed class specific descriptors, and strings short
+//! enough for a string descriptor. A mistake there otherwise only shows when
+//! the kernel of a client refuses the device.
+//!
+//! ```
+//! # use std::sync::{Arc, Mutex};
+//! # use usbip::*;
+//! let handler = Arc::new(Mutex::new(
+//!     Box::new(hid::UsbHidKeyboardHandler::new_keyboard()) as Box<dyn UsbInterfaceHandler + Send>
+//! ));
+//! let device = UsbDeviceBuilder::new(0)
+//!     .with_ids(0x1209, 0x0001)
+//!     .with_product_name("Keyboard")
+//!     .with_interface(
+//!         UsbInterfaceBuilder::new(ClassCode::HID as u8, 0x00, 0x00, handler)
+//!             .with_interrupt_in(0x81, 8, 10),
+//!     )
+//!     .build()
+//!     .unwrap();
+//! ```
+use super::*;
+use crate::device::Version;
+
+/// Why [UsbDeviceBuilder::build] refused a device
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BuildError {
+    /// The device has no interface
+    NoInterfaces,
+    /// More interfaces than a configuration descriptor can count
+    TooManyInterfaces(usize),
+    /// Endpoint 0, which every device has already, given to an interface
+    Ep0(u8),
+    /// Two endpoints with the same address
+    DuplicateEndpoint(u8),
+    /// An endpoint whose address has reserved bits set
+    InvalidAddress(u8),
+    /// A control endpoint given to an interface
+    ControlEndpoint(u8),
+    /// A packet size that the transfer type doesn't allow at the speed of
+    /// the device
+    InvalidPacketSize { address: u8, max_packet_size: u16 },
+    /// A polling interval that the transfer type doesn't allow at the speed
+    /// of the device
+    InvalidInterval { address: u8, interval: u8 },
+    /// A class specific descriptor whose lengths don't add up
+    InvalidClassDescriptor { interface: usize },
+    /// A string too long for a string descriptor
+    StringTooLong(String),
+    /// The configuration descriptor would be longer than 64 KiB
+    ConfigurationTooLong(usize),
+}
+
+impl std::fmt::Display for BuildError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            BuildError::NoInterfaces => write!(f, "The device has no interface"),
+            BuildError::TooManyInterfaces(n) => write!(f, "{} interfaces are too many", n),
+            BuildError::Ep0(address) => {
+                write!(f, "Endpoint {:#04x} is endpoint 0", address)
+            }
+            BuildError::DuplicateEndpoint(address) => {
+                write!(f, "Endpoint {:#04x} is used twice", address)
+            }
+            BuildError::InvalidAddress(address) => {
+                write!(f, "Invalid endpoint address {:#04x}", address)
+            }
+            BuildError::ControlEndpoint(address) => {
+                write!(f, "Endpoint {:#04x} is a control endpoint", address)
+            }
+            BuildError::InvalidPacketSize {
+                address,
+                max_packet_size,
+            } => write!(
+                f,
+                "Invalid packet size {} for endpoint {:#04x}",
+                max_packet_size, address
+            ),
+            BuildError::InvalidInterval { address, interval } => write!(
+                f,
+                "Invalid interval {} for endpoint {:#04x}",
+                interval, address
+            ),
+            BuildError::InvalidClassDescriptor { interface } => write!(
+                f,
+                "Invalid class specific descriptor for interface {}",
+                interface
+            ),
+            BuildError::StringTooLong(s) => write!(f, "String too long: {:?}", s),
+            BuildError::ConfigurationTooLong(len) => {
+                write!(f, "Configuration descriptor of {} bytes too long", len)
+            }
+        }
+    }
+}
+
+impl std::error::Error for BuildError {}
+
+impl From<BuildError> for std::io::Error {
+    fn from(err: BuildError) -> Self {
+        std::io::Error::new(ErrorKind::InvalidInput, err)
+    }
+}
+
+/// Longest string a string descriptor holds, in UTF-16 code units
+const MAX_STRING_LENGTH: usize = (255 - 2) / 2;
+
+/// Length of a configuration descriptor, of an interface descriptor and of
+/// an endpoint descriptor
+const CONFIGURATION_LENGTH: usize = 9;
+const INTERFACE_LENGTH: usize = 9;
+const ENDPOINT_LENGTH: usize = 7;
+
+/// An interface of a [UsbDeviceBuilder]
+pub struct UsbInterfaceBuilder {
+    interface_class: u8,
+    interface_subclass: u8,
+    interface_protocol: u8,
+    name: String,
+    endpoints: Vec<UsbEndpoint>,
+    class_specific_descriptor: Option<Vec<u8>>,
+    handler: Arc<Mutex<Box<dyn UsbInterfaceHandler + Send>>>,
+}
+
+impl UsbInterfaceBuilder {
+    /// An interface of the given class, whose URBs `handler` handles
+    pub fn new(
+        interface_class: u8,
+        interface_subclass: u8,
+        interface_protocol: u8,
+        handler: Arc<Mutex<Box<dyn UsbInterfaceHandler + Send>>>,
+    ) -> Self {
+        Self {
+            interface_class,
+            interface_subclass,
+            interface_protocol,
+            name: "Interface".to_string(),
+            endpoints: vec![],
+            class_specific_descriptor: None,
+            handler,
+        }
+    }
+
+    /// Name the interface in its string descriptor
+    pub fn with_name(mut self, name: &str) -> Self {
+        self.name = name.to_string();
+        self
+    }
+
+    /// Add an endpoint
+    pub fn with_endpoint(mut self, endpoint: UsbEndpoint) -> Self {
+        self.endpoints.push(endpoint);
+        self
+    }
+
+    /// Add endpoints, e.g. those a handler needs
+    pub fn with_endpoints(mut self, endpoints: impl IntoIterator<Item = UsbEndpoint>) -> Self {
+        self.endpoints.extend(endpoints);
+        self
+    }
+
+    /// Add a bulk IN endpoint
+    pub fn with_bulk_in(self, address: u8, max_packet_size: u16) -> Self {
+        self.with_endpoint(endpoint(
+            address | 0x80,
+            EndpointAttributes::Bulk,
+            max_packet_size,
+            0,
+        ))
+    }
+
+    /// Add a bulk OUT endpoint
+    pub fn with_bulk_out(self, address: u8, max_packet_size: u16) -> Self {
+        self.with_endpoint(endpoint(
+            address & 0x7F,
+            EndpointAttributes::Bulk,
+            max_packet_size,
+            0,
+        ))
+    }
+
+    /// Add an interrupt IN endpoint polled every `interval` (in frames, or
+    /// as an exponent at high speed)
+    pub fn with_interrupt_in(self, address: u8, max_packet_size: u16, interval: u8) -> Self {
+        self.with_endpoint(endpoint(
+            address | 0x80,
+            EndpointAttributes::Interrupt,
+            max_packet_size,
+            interval,
+        ))
+    }
+
+    /// Add an interrupt OUT endpoint
+    pub fn with_interrupt_out(self, address: u8, max_packet_size: u16, interval: u8) -> Self {
+        self.with_endpoint(endpoint(
+            address & 0x7F,
+            EndpointAttributes::Interrupt,
+            max_packet_size,
+            interval,
+        ))
+    }
+
+    /// Use `descriptor` as class specific descriptor, instead of the one
+    /// from [UsbInterfaceHandler::get_class_specific_descriptor]
+    pub fn with_class_descriptor(mut self, descriptor: Vec<u8>) -> Self {
+        self.class_specific_descriptor = Some(descriptor);
+        self
+    }
+}
+
+fn endpoint(
+    address: u8,
+    attributes: EndpointAttributes,
+    max_packet_size: u16,
+    interval: u8,
+) -> UsbEndpoint {
+    UsbEndpoint {
+        address,
+        attributes: attributes as u8,
+        max_packet_size,
+        interval,
+    }
+}
+
+/// Builds a simulated [UsbDevice], see the [module docs](self)
+pub struct UsbDeviceBuilder {
+    index: u32,
+    speed: UsbSpeed,
+    vendor_id: u16,
+    product_id: u16,
+    device_bcd: Version,
+    usb_version: Version,
+    device_class: u8,
+    device_subclass: u8,
+    device_protocol: u8,
+    manufacturer: String,
+    product: String,
+    serial: String,
+    configuration: String,
+    interfaces: Vec<UsbInterfaceBuilder>,
+    device_handler: Option<Arc<Mutex<Box<dyn UsbDeviceHandler + Send>>>>,
+}
+
+impl UsbDeviceBuilder {
+    /// A high speed USB 2.0 device, named `0-0-{index}` like those of
+    /// [UsbDevice::new]
+    pub fn new(index: u32) -> Self {
+        Self {
+            index,
+            speed: UsbSpeed::High,
+            vendor_id: 0,
+            product_id: 0,
+            device_bcd: Version::default(),
+            usb_version: Version {
+                major: 2,
+                minor: 0,
+                patch: 0,
+            },
+            device_class: ClassCode::SeeInterface as u8,
+            device_subclass: 0,
+            device_protocol: 0,
+            manufacturer: "Manufacturer".to_string(),
+            product: "Product".to_string(),
+            serial: "Serial".to_string(),
+            configuration: "Default Configuration".to_string(),
+            interfaces: vec![],
+            device_handler: None,
+        }
+    }
+
+    /// Set the speed, which decides the packet sizes and intervals allowed
+    pub fn with_speed(mut self, speed: UsbSpeed) -> Self {
+        self.speed = speed;
+        self
+    }
+
+    /// Set the vendor and product id
+    pub fn with_ids(mut self, vendor_id: u16, product_id: u16) -> Self {
+        self.vendor_id = vendor_id;
+        self.product_id = product_id;
+        self
+    }
+
+    /// Set the release number of the device (bcdDevice)
+    pub fn with_device_version(mut self, version: Version) -> Self {
+        self.device_bcd = version;
+        self
+    }
+
+    /// Set the USB version the device claims (bcdUSB)
+    pub fn with_usb_version(mut self, version: Version) -> Self {
+        self.usb_version = version;
+        self
+    }
+
+    /// Set the class of the device, by default left to its interfaces
+    pub fn with_class(mut self, class: u8, subclass: u8, protocol: u8) -> Self {
+        self.device_class = class;
+        self.device_subclass = subclass;
+        self.device_protocol = protocol;
+        self
+    }
+
+    /// Set the manufacturer string
+    pub fn with_manufacturer_name(mut self, name: &str) -> Self {
+        self.manufacturer = name.to_string();
+        self
+    }
+
+    /// Set the product string
+    pub fn with_product_name(mut self, name: &str) -> Self {
+        self.product = name.to_string();
+        self
+    }
+
+    /// Set the serial number string
+    pub fn with_serial_number(mut self, serial: &str) -> Self {
+        self.serial = serial.to_string();
+        self
+    }
+
+    /// Set the name of the configuration
+    pub fn with_configuration_name(mut self, name: &str) -> Self {
+        self.configuration = name.to_string();
+        self
+    }
+
+    /// Add an interface, numbered in the order they are added
+    pub fn with_interface(mut self, interface: UsbInterfaceBuilder) -> Self {
+        self.interfaces.push(interface);
+        self
+    }
+
+    /// Handle the control requests to the device that the server doesn't
+    pub fn with_device_handler(
+        mut self,
+        handler: Arc<Mutex<Box<dyn UsbDeviceHandler + Send>>>,
+    ) -> Self {
+        self.device_handler = Some(handler);
+        self
+    }
+
+    /// Check the device and build it
+    pub fn build(self) -> std::result::Result<UsbDevice, BuildError> {
+        self.validate()?;
+
+        let mut device = UsbDevice::new(self.index);
+        device.speed = self.speed as u32;
+        device.vendor_id = self.vendor_id;
+        device.product_id = self.product_id;
+        device.device_bcd = self.device_bcd;
+        device.usb_version = self.usb_version;
+        device.device_class = self.device_class;
+        device.device_subclass = self.device_subclass;
+        device.device_protocol = self.device_protocol;
+        device.set_manufacturer_name(&self.manufacturer);
+        device.set_product_name(&self.product);
+        device.set_serial_number(&self.serial);
+        device.set_configuration_name(&self.configuration);
+        device.device_handler = self.device_handler;
+        for intf in self.interfaces {
+            device = device.with_interface(
+                intf.interface_class,
+                intf.interface_subclass,
+                intf.interface_protocol,
+                &intf.name,
+                intf.endpoints,
+                intf.handler,
+            );
+            if let Some(descriptor) = intf.class_specific_descriptor {
+                device
+                    .interfaces
+                    .last_mut()
+                    .unwrap()
+                    .class_specific_descriptor = descriptor;
+            }
+        }
+        Ok(device)
+    }
+
+    fn validate(&self) -> std::result::Result<(), BuildError> {
+        if self.interfaces.is_empty() {
+            return Err(BuildError::NoInterfaces);
+        }
+        if self.interfaces.len() > u8::MAX as usize {
+            return Err(BuildError::TooManyInterfaces(self.interfaces.len()));
+        }
+
+        let strings = [
+            &self.manufacturer,
+            &self.product,
+            &self.serial,
+            &self.configuration,
+        ];
+        for s in strings
+            .into_iter()
+            .chain(self.interfaces.iter().map(|intf| &intf.name))
+        {
+            if s.encode_utf16().count() > MAX_STRING_LENGTH {
+                return Err(BuildError::StringTooLong(s.clone()));
+            }
+        }
+
+        let mut addresses = HashSet::new();
+        let mut total_length = CONFIGURATION_LENGTH;
+        for (i, intf) in self.interfaces.iter().enumerate() {
+            let class_descriptor = match &intf.class_specific_descriptor {
+                Some(descriptor) => descriptor.clone(),
+                None => intf.handler.lock().unwrap().get_class_specific_descriptor(),
+            };
+            if !well_formed(&class_descriptor) {
+                return Err(BuildError::InvalidClassDescriptor { interface: i });
+            }
+            total_length +=
+                INTERFACE_LENGTH + class_descriptor.len() + ENDPOINT_LENGTH * intf.endpoints.len();
+
+            for ep in &intf.endpoints {
+                if ep.is_ep0() {
+                    return Err(BuildError::Ep0(ep.address));
+                }
+                if ep.address & 0x70 != 0 {
+                    return Err(BuildError::InvalidAddress(ep.address));
+                }
+                if !addresses.insert(ep.address) {
+                    return Err(BuildError::DuplicateEndpoint(ep.address));
+                }
+                self.validate_endpoint(ep)?;
+            }
+        }
+        if total_length > u16::MAX as usize {
+            return Err(BuildError::ConfigurationTooLong(total_length));
+        }
+        Ok(())
+    }
+
+    /// Check the packet size and interval of `ep` against USB 2.0 5.6 to 5.8
+    fn validate_endpoint(&self, ep: &UsbEndpoint) -> std::result::Result<(), BuildError> {
+        use EndpointAttributes::*;
+
+        let high_speed = matches!(
+            self.speed,
+            UsbSpeed::High | UsbSpeed::Super | UsbSpeed::SuperPlus
+        );
+        let size = ep.max_packet_size & 0x7FF;
+        // Additional transactions per microframe, only for periodic
+        // endpoints at high speed
+        let transactions = ep.max_packet_size >> 11;
+        let (sizes_ok, interval_ok) = match FromPrimitive::from_u8(ep.attributes & 0x03) {
+            Some(Control) => return Err(BuildError::ControlEndpoint(ep.address)),
+            Some(Bulk) => (
+                match self.speed {
+                    UsbSpeed::Low => false,
+                    UsbSpeed::Full => [8, 16, 32, 64].contains(&size),
+                    _ => size == 512,
+                } && transactions == 0,
+                true,
+            ),
+            Some(Interrupt) => match self.speed {
+                UsbSpeed::Low => (size <= 8 && transactions == 0, ep.interval >= 1),
+                UsbSpeed::Full => (size <= 64 && transactions == 0, ep.interval >= 1),
+                _ => (
+                    size <= 1024 && transactions <= 2 && (transactions == 0 || size > 128),
+                    (1..=16).contains(&ep.interval),
+                ),
+            },
+            Some(Isochronous) => match self.speed {
+                UsbSpeed::Low => (false, true),
+                UsbSpeed::Full => (size <= 1023 && transactions == 0, ep.interval == 1),
+                _ => (
+                    size <= 1024 && transactions <= 2 && (transactions == 0 || size > 128),
+                    (1..=16).contains(&ep.interval),
+                ),
+            },
+            None => unreachable!(),
+        };
+        let sizes_ok = sizes_ok && size > 0 || !high_speed && size == 0;
+        if !sizes_ok {
+            return Err(BuildError::InvalidPacketSize {
+                address: ep.address,
+                max_packet_size: ep.max_packet_size,
+            });
+        }
+        if !interval_ok {
+            return Err(BuildError::InvalidInterval {
+                address: ep.address,
+                interval: ep.interval,
+            });
+        }
+        Ok(())
+    }
+}
+
+/// Whether `desc` is a sequence of descriptors, each starting with its length
+/// and type
+fn well_formed(desc: &[u8]) -> bool {
+    let mut offset = 0;
+    while offset < desc.len() {
+        let len = desc[offset] as usize;
+        if len < 2 {
+            return false;
+        }
+        offset += len;
+    }
+    offset == desc.len()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ccid::{CcidBackend, UsbCcidHandler, CCID_SUBCLASS};
+    use crate::cdc::UsbCdcAcmHandler;
+    use crate::hid::UsbHidKeyboardHandler;
+
+    struct NoCard;
+
+    impl CcidBackend for NoCard {
+        fn power_on(&mut self) -> Result<Vec<u8>> {
+            Err(ErrorKind::NotConnected.into())
+        }
+
+        fn power_off(&mut self) -> Result<()> {
+            Ok(())
+        }
+
+        fn transmit(&mut self, _apdu: &[u8]) -> Result<Vec<u8>> {
+            Err(ErrorKind::NotConnected.into())
+        }
+    }
+
+    fn handler<T: UsbInterfaceHandler + Send + 'static>(
+        handler: T,
+    ) -> Arc<Mutex<Box<dyn UsbInterfaceHandler + Send>>> {
+        Arc::new(Mutex::new(Box::new(handler)))
+    }
+
+    fn keyboard() -> UsbInterfaceBuilder {
+        UsbInterfaceBuilder::new(
+            ClassCode::HID as u8,
+            0x00,
+            0x00,
+            handler(UsbHidKeyboardHandler::new_keyboard()),
+        )
+    }
+
+    #[test]
+    fn build() {
+        let device = UsbDeviceBuilder::new(3)
+            .with_ids(0x1050, 0x0407)
+            .with_serial_number("12345678")
+            .with_interface(keyboard().with_name("Keyboard").with_interrupt_in(1, 8, 10))
+            .with_interface(
+                UsbInterfaceBuilder::new(
+                    ClassCode::CDC as u8,
+                    cdc::CDC_ACM_SUBCLASS,
+                    0x00,
+                    handler(UsbCdcAcmHandler::new()),
+                )
+                .with_bulk_in(2, 512)
+                .with_bulk_out(2, 512),
+            )
+            .build()
+            .unwrap();
+        assert_eq!(device.bus_id, "0-0-3");
+        assert_eq!(device.vendor_id, 0x1050);
+        assert_eq!(device.serial_number(), Some("12345678"));
+        assert_eq!(device.interfaces.len(), 2);
+        let name = device.interfaces[0].string_interface;
+        assert_eq!(device.string_pool[&name], "Keyboard");
+        assert_eq!(device.interfaces[1].endpoints[0].address, 0x82);
+        assert_eq!(device.interfaces[1].endpoints[1].address, 0x02);
+        // The class specific descriptor comes from the handler
+        assert!(!device.interfaces[1].class_specific_descriptor.is_empty());
+
+        let ccid = UsbInterfaceBuilder::new(
+            ClassCode::SmartCard as u8,
+            CCID_SUBCLASS,
+            0x00,
+            handler(UsbCcidHandler::new(NoCard)),
+        )
+        .with_endpoints(UsbCcidHandler::<NoCard>::endpoints());
+        UsbDeviceBuilder::new(0)
+            .with_speed(UsbSpeed::Full)
+            .with_interface(ccid)
+            .build()
+            .unwrap();
+    }
+
+    #[test]
+    fn validation() {
+        let err = |builder: UsbDeviceBuilder| builder.build().err().unwrap();
+
+        assert_eq!(err(UsbDeviceBuilder::new(0)), BuildError::NoInterfaces);
+        assert_eq!(
+            err(UsbDeviceBuilder::new(0).with_interface(keyboard().with_interrupt_in(0, 8, 10))),
+            BuildError::Ep0(0x80)
+        );
+        assert_eq!(
+            err(UsbDeviceBuilder::new(0)
+                .with_interface(keyboard().with_interrupt_in(1, 8, 10))
+                .with_interface(keyboard().with_interrupt_in(1, 8, 10))),
+            BuildError::DuplicateEndpoint(0x81)
+        );
+        assert_eq!(
+            err(UsbDeviceBuilder::new(0).with_interface(keyboard().with_interrupt_in(0x11, 8, 10))),
+            BuildError::InvalidAddress(0x91)
+        );
+        // Bulk endpoints have 512 byte packets at high speed, at most 64 at
+        // full speed
+        assert_eq!(
+            err(UsbDeviceBuilder::new(0).with_interface(keyboard().with_bulk_in(1, 64))),
+            BuildError::InvalidPacketSize {
+                address: 0x81,
+                max_packet_size: 64
+            }
+        );
+        assert_eq!(
+            err(UsbDeviceBuilder::new(0)
+                .with_speed(UsbSpeed::Full)
+                .with_interface(keyboard().with_bulk_in(1, 512))),
+            BuildError::InvalidPacketSize {
+                address: 0x81,
+                max_packet_size: 512
+            }
+        );
+        // High speed intervals are exponents
+        assert_eq!(
+            err(UsbDeviceBuilder::new(0).with_interface(keyboard().with_interrupt_in(1, 8, 32))),
+            BuildError::InvalidInterval {
+                address: 0x81,
+                interval: 32
+            }
+        );
+        assert_eq!(
+            err(UsbDeviceBuilder::new(0).with_interface(
+                keyboard()
+                    .with_interrupt_in(1, 8, 10)
+                    .with_class_descriptor(vec![0x09, 0x21, 0x11])
+            )),
+            BuildError::InvalidClassDescriptor { interface: 0 }
+        );
+        let long = "x".repeat(MAX_STRING_LENGTH + 1);
+        assert_eq!(
+            err(UsbDeviceBuilder::new(0)
+                .with_product_name(&long)
+                .with_interface(keyboard().with_interrupt_in(1, 8, 10))),
+            BuildError::StringTooLong(long)
+        );
+    }
+}
@@ -0,0 +1,1105 @@
+//! Implement CCID (smart card reader) device
+//!
+//! The reader has a single slot and exchanges APDUs with the card, as
+//! YubiKeys and most modern readers do. APDUs go to a [CcidBackend]: a
+//! virtual card implemented in software, or e.g. a card in a PC/SC reader of
+//! the host with the `pcsc` feature, see [crate::pcsc].
+//!
+//! Cards with only the basic logical channel, as YubiKeys have, get the
+//! others from [LogicalChannels].
+use super::*;
+
+// reference:
+// CCID 1.1: https://www.usb.org/sites/default/files/DWG_Smart-Card_CCID_Rev110.pdf
+
+/// Sub class code for CCID
+pub const CCID_SUBCLASS: u8 = 0x00;
+
+/// Length of the header of every CCID message
+pub const CCID_HEADER_LENGTH: usize = 10;
+
+/// Longest message the reader takes, header included
+pub const CCID_MAX_MESSAGE_LENGTH: usize = 3072;
+
+/// Clock frequency of the reader in kHz
+const CLOCK_FREQUENCY: u32 = 4000;
+
+/// Data rate of the reader in bps
+const DATA_RATE: u32 = 9600;
+
+// PC_to_RDR messages
+const PC_TO_RDR_SET_PARAMETERS: u8 = 0x61;
+const PC_TO_RDR_ICC_POWER_ON: u8 = 0x62;
+const PC_TO_RDR_ICC_POWER_OFF: u8 = 0x63;
+const PC_TO_RDR_GET_SLOT_STATUS: u8 = 0x65;
+const PC_TO_RDR_ESCAPE: u8 = 0x6B;
+const PC_TO_RDR_GET_PARAMETERS: u8 = 0x6C;
+const PC_TO_RDR_RESET_PARAMETERS: u8 = 0x6D;
+const PC_TO_RDR_ICC_CLOCK: u8 = 0x6E;
+const PC_TO_RDR_XFR_BLOCK: u8 = 0x6F;
+const PC_TO_RDR_ABORT: u8 = 0x72;
+const PC_TO_RDR_SET_DATA_RATE_AND_CLOCK_FREQUENCY: u8 = 0x73;
+
+// RDR_to_PC messages
+const RDR_TO_PC_DATA_BLOCK: u8 = 0x80;
+const RDR_TO_PC_SLOT_STATUS: u8 = 0x81;
+const RDR_TO_PC_PARAMETERS: u8 = 0x82;
+const RDR_TO_PC_ESCAPE: u8 = 0x83;
+const RDR_TO_PC_DATA_RATE_AND_CLOCK_FREQUENCY: u8 = 0x84;
+const RDR_TO_PC_NOTIFY_SLOT_CHANGE: u8 = 0x50;
+
+// Class specific requests
+const REQUEST_ABORT: u8 = 0x01;
+const REQUEST_GET_CLOCK_FREQUENCIES: u8 = 0x02;
+const REQUEST_GET_DATA_RATES: u8 = 0x03;
+
+// bStatus
+const ICC_ACTIVE: u8 = 0x00;
+const ICC_INACTIVE: u8 = 0x01;
+const ICC_NOT_PRESENT: u8 = 0x02;
+const COMMAND_FAILED: u8 = 0x40;
+const TIME_EXTENSION: u8 = 0x80;
+
+// bError
+const CMD_NOT_SUPPORTED: u8 = 0x00;
+const HW_ERROR: u8 = 0xFB;
+const ICC_MUTE: u8 = 0xFE;
+
+/// MANAGE CHANNEL, which opens and closes logical channels
+const INS_MANAGE_CHANNEL: u8 = 0x70;
+
+/// Logical channels a card can have (ISO 7816-4): the basic one, 0, and
+/// 1 to 19
+const LOGICAL_CHANNELS: u8 = 20;
+
+// Status words
+const SW_SUCCESS: [u8; 2] = [0x90, 0x00];
+/// Answers the APDUs on logical channels that aren't open
+pub(crate) const SW_CHANNEL_NOT_SUPPORTED: [u8; 2] = [0x68, 0x81];
+const SW_NO_CHANNEL_LEFT: [u8; 2] = [0x6A, 0x81];
+const SW_WRONG_P1P2: [u8; 2] = [0x6A, 0x86];
+
+/// The card behind a [UsbCcidHandler]
+pub trait CcidBackend: Send {
+    /// Power the card up (or reset it), returning its ATR
+    fn power_on(&mut self) -> Result<Vec<u8>>;
+
+    /// Power the card down
+    fn power_off(&mut self) -> Result<()>;
+
+    /// Send a command APDU to the card, returning its response APDU
+    fn transmit(&mut self, apdu: &[u8]) -> Result<Vec<u8>>;
+
+    /// Whether a card is in the slot; changes are reported to the host on
+    /// the interrupt endpoint
+    fn present(&mut self) -> bool {
+        true
+    }
+}
+
+/// A handler of a CCID interface, forwarding APDUs to a [CcidBackend]
+pub struct UsbCcidHandler<B> {
+    pub backend: B,
+    powered: bool,
+    /// Bytes of a PC_to_RDR message split over several bulk OUT transfers
+    request: Vec<u8>,
+    /// RDR_to_PC messages not read yet
+    response: VecDeque<u8>,
+    /// Card presence last reported on the interrupt endpoint
+    reported_present: Option<bool>,
+}
+
+impl<B: CcidBackend> UsbCcidHandler<B> {
+    pub fn new(backend: B) -> Self {
+        Self {
+            backend,
+            powered: false,
+            request: vec![],
+            response: VecDeque::new(),
+            reported_present: None,
+        }
+    }
+
+    pub fn endpoints() -> Vec<UsbEndpoint> {
+        vec![
+            // bulk out
+            UsbEndpoint {
+                address: 0x02,                              // OUT
+                attributes: EndpointAttributes::Bulk as u8, // Bulk
+                max_packet_size: 64,                        // 64 bytes
+                interval: 0,
+            },
+            // bulk in
+            UsbEndpoint {
+                address: 0x82,                              // IN
+                attributes: EndpointAttributes::Bulk as u8, // Bulk
+                max_packet_size: 64,                        // 64 bytes
+                interval: 0,
+            },
+            // interrupt in
+            UsbEndpoint {
+                address: 0x83,                                   // IN
+                attributes: EndpointAttributes::Interrupt as u8, // Interrupt
+                max_packet_size: 8,                              // 8 bytes
+                interval: 255,
+            },
+        ]
+    }
+
+    fn slot_status(&mut self) -> u8 {
+        if !self.backend.present() {
+            self.powered = false;
+            ICC_NOT_PRESENT
+        } else if self.powered {
+            ICC_ACTIVE
+        } else {
+            ICC_INACTIVE
+        }
+    }
+
+    /// bProtocolNum and abProtocolDataStructure of the parameters for T=1
+    fn parameters() -> (u8, [u8; 7]) {
+        // bmFindexDindex, bmTCCKST1, bGuardTimeT1, bmWaitingIntegersT1,
+        // bClockStop, bIFSC, bNadValue
+        (0x01, [0x11, 0x10, 0x00, 0x45, 0x00, 0xFE, 0x00])
+    }
+
+    /// Handle a complete PC_to_RDR message, and queue the reply
+    fn handle_message(&mut self, message: &[u8]) {
+        let (header, data) = message.split_at(CCID_HEADER_LENGTH);
+        let (kind, slot, seq) = (header[0], header[5], header[6]);
+        trace!("CCID message {:02x} seq {}", kind, seq);
+
+        let reply = |kind: u8, status: u8, error: u8, specific: u8, data: &[u8]| {
+            let mut reply = vec![kind];
+            reply.extend((data.len() as u32).to_le_bytes());
+            reply.extend([slot, seq, status, error, specific]);
+            reply.extend(data);
+            reply
+        };
+        let reply = match kind {
+            _ if slot != 0 => reply(
+                RDR_TO_PC_SLOT_STATUS,
+                COMMAND_FAILED | ICC_NOT_PRESENT,
+                // bad bSlot
+                5,
+                0,
+                &[],
+            ),
+            PC_TO_RDR_ICC_POWER_ON if !self.backend.present() => {
+                self.powered = false;
+                reply(
+                    RDR_TO_PC_DATA_BLOCK,
+                    COMMAND_FAILED | ICC_NOT_PRESENT,
+                    ICC_MUTE,
+                    0,
+                    &[],
+                )
+            }
+            PC_TO_RDR_ICC_POWER_ON => match self.backend.power_on() {
+                Ok(atr) => {
+                    self.powered = true;
+                    reply(RDR_TO_PC_DATA_BLOCK, ICC_ACTIVE, 0, 0, &atr)
+                }
+                Err(err) => {
+                    warn!("Failed to power the card on: {}", err);
+                    self.powered = false;
+                    reply(
+                        RDR_TO_PC_DATA_BLOCK,
+                        COMMAND_FAILED | ICC_NOT_PRESENT,
+                        ICC_MUTE,
+                        0,
+                        &[],
+                    )
+                }
+            },
+            PC_TO_RDR_ICC_POWER_OFF => {
+                if self.powered {
+                    if let Err(err) = self.backend.power_off() {
+                        warn!("Failed to power the card off: {}", err);
+                    }
+                }
+                self.powered = false;
+                // bClockStatus: stopped
+                reply(RDR_TO_PC_SLOT_STATUS, ICC_INACTIVE, 0, 1, &[])
+            }
+            PC_TO_RDR_GET_SLOT_STATUS | PC_TO_RDR_ICC_CLOCK | PC_TO_RDR_ABORT => {
+                let status = self.slot_status();
+                reply(RDR_TO_PC_SLOT_STATUS, status, 0, 0, &[])
+            }
+            PC_TO_RDR_XFR_BLOCK if self.slot_status() != ICC_ACTIVE => {
+                let status = self.slot_status();
+                reply(
+                    RDR_TO_PC_DATA_BLOCK,
+                    COMMAND_FAILED | status,
+                    ICC_MUTE,
+                    0,
+                    &[],
+                )
+            }
+            PC_TO_RDR_XFR_BLOCK => {
+                let _span = apdu_span(data).entered();
+                match self.backend.transmit(data) {
+                    Ok(response) => reply(RDR_TO_PC_DATA_BLOCK, ICC_ACTIVE, 0, 0, &response),
+                    Err(err) => {
+                        warn!("Failed to transmit APDU: {}", err);
+                        reply(
+                            RDR_TO_PC_DATA_BLOCK,
+                            COMMAND_FAILED | ICC_ACTIVE,
+                            HW_ERROR,
+                            0,
+                            &[],
+                        )
+                    }
+                }
+            }
+            // Parameters are negotiated automatically, only T=1 is supported
+            PC_TO_RDR_SET_PARAMETERS if header[7] != 0x01 => {
+                let status = self.slot_status();
+                let (protocol, parameters) = Self::parameters();
+                reply(
+                    RDR_TO_PC_PARAMETERS,
+                    COMMAND_FAILED | status,
+                    // bad bProtocolNum
+                    7,
+                    protocol,
+                    &parameters,
+                )
+            }
+            PC_TO_RDR_GET_PARAMETERS | PC_TO_RDR_RESET_PARAMETERS | PC_TO_RDR_SET_PARAMETERS => {
+                let status = self.slot_status();
+                let (protocol, parameters) = Self::parameters();
+                reply(RDR_TO_PC_PARAMETERS, status, 0, protocol, &parameters)
+            }
+            PC_TO_RDR_ESCAPE => {
+                let status = self.slot_status();
+                reply(
+                    RDR_TO_PC_ESCAPE,
+                    COMMAND_FAILED | status,
+                    CMD_NOT_SUPPORTED,
+                    0,
+                    &[],
+                )
+            }
+            PC_TO_RDR_SET_DATA_RATE_AND_CLOCK_FREQUENCY => {
+                let status = self.slot_status();
+                let mut data = CLOCK_FREQUENCY.to_le_bytes().to_vec();
+                data.extend(DATA_RATE.to_le_bytes());
+                reply(RDR_TO_PC_DATA_RATE_AND_CLOCK_FREQUENCY, status, 0, 0, &data)
+            }
+            _ => {
+                warn!("Unsupported CCID message {:02x}", kind);
+                let status = self.slot_status();
+                reply(
+                    RDR_TO_PC_SLOT_STATUS,
+                    COMMAND_FAILED | status,
+                    CMD_NOT_SUPPORTED,
+                    0,
+                    &[],
+                )
+            }
+        };
+        self.response.extend(reply);
+    }
+}
+
+impl<B: CcidBackend + 'static> UsbInterfaceHandler for UsbCcidHandler<B> {
+    fn handle_urb(
+        &mut self,
+        _interface: &UsbInterface,
+        ep: UsbEndpoint,
+        transfer_buffer_length: u32,
+        setup: SetupPacket,
+        req: &[u8],
+    ) -> UrbResult {
+        if ep.is_ep0() {
+            // control transfers
+            return match (setup.request_type, setup.request) {
+                (0b00100001, REQUEST_ABORT) => {
+                    // the PC_to_RDR_Abort that follows gets the reply
+                    self.request.clear();
+                    Ok(vec![])
+                }
+                (0b10100001, REQUEST_GET_CLOCK_FREQUENCIES) => {
+                    Ok(CLOCK_FREQUENCY.to_le_bytes().to_vec())
+                }
+                (0b10100001, REQUEST_GET_DATA_RATES) => Ok(DATA_RATE.to_le_bytes().to_vec()),
+                _ => {
+                    warn!("Unsupported CCID request {:?}", setup);
+                    Err(UrbError::Stall)
+                }
+            };
+        }
+        if ep.attributes == EndpointAttributes::Interrupt as u8 {
+            // interrupt in: RDR_to_PC_NotifySlotChange when the card comes
+            // or goes
+            let present = self.backend.present();
+            if self.reported_present == Some(present) {
+                return Ok(vec![]);
+            }
+            self.reported_present = Some(present);
+            if !present {
+                self.powered = false;
+            }
+            // bmSlotICCState: present, changed
+            return Ok(vec![RDR_TO_PC_NOTIFY_SLOT_CHANGE, present as u8 | 0b10]);
+        }
+        if let Direction::Out = ep.direction() {
+            // bulk out: a message, or part of it
+            self.request.extend_from_slice(req);
+            while self.request.len() >= CCID_HEADER_LENGTH {
+                let length = u32::from_le_bytes(self.request[1..5].try_into().unwrap()) as usize;
+                if CCID_HEADER_LENGTH + length > CCID_MAX_MESSAGE_LENGTH {
+                    self.request.clear();
+                    return Err(UrbError::Other(std::io::Error::new(
+                        ErrorKind::InvalidData,
+                        "CCID message too long",
+                    )));
+                }
+                if self.request.len() < CCID_HEADER_LENGTH + length {
+                    break;
+                }
+                let message: Vec<u8> = self.request.drain(..CCID_HEADER_LENGTH + length).collect();
+                self.handle_message(&message);
+            }
+            Ok(vec![])
+        } else {
+            // bulk in: as much of the replies as fits
+            let length = self.response.len().min(transfer_buffer_length as usize);
+            Ok(self.response.drain(..length).collect())
+        }
+    }
+
+    fn get_class_specific_descriptor(&self) -> Vec<u8> {
+        let mut desc = vec![
+            0x36, // bLength
+            0x21, // CCID functional descriptor
+            0x10, 0x01, // CCID 1.1
+            0x00, // bMaxSlotIndex
+            0x07, // bVoltageSupport: 5V, 3V, 1.8V
+        ];
+        desc.extend(2u32.to_le_bytes()); // dwProtocols: T=1
+        desc.extend(CLOCK_FREQUENCY.to_le_bytes()); // dwDefaultClock: 4 MHz
+        desc.extend(CLOCK_FREQUENCY.to_le_bytes()); // dwMaximumClock
+        desc.push(1); // bNumClockSupported
+        desc.extend(DATA_RATE.to_le_bytes()); // dwDataRate
+        desc.extend(DATA_RATE.to_le_bytes()); // dwMaxDataRate
+        desc.push(1); // bNumDataRatesSupported
+        desc.extend(254u32.to_le_bytes()); // dwMaxIFSD
+        desc.extend(0u32.to_le_bytes()); // dwSynchProtocols
+        desc.extend(0u32.to_le_bytes()); // dwMechanical
+                                         // dwFeatures: automatic parameters, activation, voltage, clock,
+                                         // baud rate and PPS; short and extended APDU level exchanges
+        desc.extend(0x0004_00FEu32.to_le_bytes());
+        desc.extend((CCID_MAX_MESSAGE_LENGTH as u32).to_le_bytes()); // dwMaxCCIDMessageLength
+        desc.extend([
+            0xFF, // bClassGetResponse: echo
+            0xFF, // bClassEnvelope: echo
+            0x00, 0x00, // wLcdLayout: none
+            0x00, // bPINSupport: none
+            0x01, // bMaxCCIDBusySlots
+        ]);
+        desc
+    }
+
+    fn disconnected(&mut self) {
+        // Don't leave the card, and the PIN verified on it, to the next client
+        if self.powered {
+            self.powered = false;
+            if let Err(err) = self.backend.power_off() {
+                warn!("Powering the card off failed: {}", err);
+            }
+        }
+        self.request.clear();
+        self.response.clear();
+    }
+
+    fn as_any(&mut self) -> &mut dyn Any {
+        self
+    }
+}
+
+/// The logical channel a command with the class byte `cla` is sent on
+///
+/// Proprietary classes, with the high bit set, are taken to be on the basic
+/// channel, as their coding is up to the card.
+pub fn logical_channel(cla: u8) -> u8 {
+    match cla {
+        0x80.. => 0,
+        0x40.. => (cla & 0x0F) + 4,
+        _ => cla & 0x03,
+    }
+}
+
+/// `apdu` as sent on the basic channel, with the secure messaging and
+/// command chaining bits of its class byte kept
+pub fn on_basic_channel(apdu: &[u8]) -> Vec<u8> {
+    let mut apdu = apdu.to_vec();
+    if let Some(cla) = apdu.first_mut() {
+        *cla = match *cla {
+            0x80.. => *cla,
+            0x40.. => (*cla & 0x10) | if *cla & 0x20 != 0 { 0x08 } else { 0 },
+            _ => *cla & !0x03,
+        };
+    }
+    apdu
+}
+
+/// The logical channels opened with MANAGE CHANNEL, by a host or by one of
+/// the sessions sharing a card
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct OpenChannels(u32);
+
+impl Default for OpenChannels {
+    /// Only the basic channel, which is always open
+    fn default() -> Self {
+        Self(1)
+    }
+}
+
+impl OpenChannels {
+    pub fn is_open(&self, channel: u8) -> bool {
+        channel < LOGICAL_CHANNELS && self.0 & (1 << channel) != 0
+    }
+
+    /// Answer `apdu` if it is a MANAGE CHANNEL, along with the channel it
+    /// closed, if any
+    pub fn manage(&mut self, apdu: &[u8]) -> Option<(Vec<u8>, Option<u8>)> {
+        let &[cla, INS_MANAGE_CHANNEL, p1, p2, ..] = apdu else {
+            return None;
+        };
+        if !self.is_open(logical_channel(cla)) {
+            return Some((SW_CHANNEL_NOT_SUPPORTED.to_vec(), None));
+        }
+        Some(match (p1, p2) {
+            // Open the first channel free
+            (0x00, 0x00) => match (1..LOGICAL_CHANNELS).find(|&channel| !self.is_open(channel)) {
+                Some(channel) => {
+                    self.0 |= 1 << channel;
+                    ([&[channel][..], &SW_SUCCESS].concat(), None)
+                }
+                None => (SW_NO_CHANNEL_LEFT.to_vec(), None),
+            },
+            (0x00, channel) if channel < LOGICAL_CHANNELS && !self.is_open(channel) => {
+                self.0 |= 1 << channel;
+                (SW_SUCCESS.to_vec(), None)
+            }
+            // Close the channel in P2, or else the one it was sent on
+            (0x80, channel) => {
+                let channel = if channel == 0 {
+                    logical_channel(cla)
+                } else {
+                    channel
+                };
+                if channel == 0 || !self.is_open(channel) {
+                    (SW_WRONG_P1P2.to_vec(), None)
+                } else {
+                    self.0 &= !(1 << channel);
+                    (SW_SUCCESS.to_vec(), Some(channel))
+                }
+            }
+            _ => (SW_WRONG_P1P2.to_vec(), None),
+        })
+    }
+}
+
+/// The application each logical channel selected last on a card that has
+/// only the basic channel, to select it anew whenever the card answers
+/// another channel in between
+///
+/// Channels are keyed by `K`, e.g. the channel number, or the session and
+/// channel number where sessions share a card.
+pub(crate) struct Selections<K> {
+    selects: HashMap<K, Vec<u8>>,
+    current: Option<K>,
+}
+
+impl<K> Default for Selections<K> {
+    fn default() -> Self {
+        Self {
+            selects: HashMap::new(),
+            current: None,
+        }
+    }
+}
+
+impl<K: Eq + std::hash::Hash + Clone> Selections<K> {
+    /// The SELECT to send before `apdu` on `channel`, if another channel had
+    /// the card and `apdu` doesn't select an application itself
+    pub fn switch(&mut self, channel: &K, apdu: &[u8]) -> Option<Vec<u8>> {
+        if self.current.as_ref() == Some(channel) {
+            return None;
+        }
+        self.current = Some(channel.clone());
+        if is_select(apdu) {
+            return None;
+        }
+        self.selects.get(channel).cloned()
+    }
+
+    /// Note the `response` of the card to `apdu`, sent on the basic channel
+    /// for `channel`
+    pub fn answered(&mut self, channel: &K, apdu: &[u8], response: &[u8]) {
+        if is_select(apdu) && response.ends_with(&SW_SUCCESS) {
+            self.selects.insert(channel.clone(), apdu.to_vec());
+        }
+    }
+
+    /// Forget what `channel` selected
+    pub fn close(&mut self, channel: &K) {
+        self.selects.remove(channel);
+    }
+
+    /// Forget what the channels for which `closed` holds selected
+    #[cfg(feature = "apdu")]
+    pub fn close_where(&mut self, mut closed: impl FnMut(&K) -> bool) {
+        self.selects.retain(|channel, _| !closed(channel));
+    }
+}
+
+/// Whether `apdu` selects an application by its AID
+fn is_select(apdu: &[u8]) -> bool {
+    matches!(apdu, [_, 0xA4, 0x04, ..])
+}
+
+/// Logical channels for a [CcidBackend] whose card has only the basic one,
+/// such as YubiKeys
+///
+/// MANAGE CHANNEL is answered here, and the commands on each channel are
+/// sent to the card on the basic channel, after selecting anew the
+/// application the channel selected whenever the card last answered another
+/// channel. Hosts can so keep several applications selected at once, e.g.
+/// PIV and OpenPGP, though selecting one again ends what was verified on the
+/// other, as the card has one security status.
+pub struct LogicalChannels<B> {
+    pub backend: B,
+    open: OpenChannels,
+    selections: Selections<u8>,
+}
+
+impl<B: CcidBackend> LogicalChannels<B> {
+    pub fn new(backend: B) -> Self {
+        Self {
+            backend,
+            open: Default::default(),
+            selections: Default::default(),
+        }
+    }
+}
+
+impl<B: CcidBackend> CcidBackend for LogicalChannels<B> {
+    fn power_on(&mut self) -> Result<Vec<u8>> {
+        self.open = Default::default();
+        self.selections = Default::default();
+        self.backend.power_on()
+    }
+
+    fn power_off(&mut self) -> Result<()> {
+        self.backend.power_off()
+    }
+
+    fn transmit(&mut self, apdu: &[u8]) -> Result<Vec<u8>> {
+        if let Some((response, closed)) = self.open.manage(apdu) {
+            if let Some(channel) = closed {
+                self.selections.close(&channel);
+            }
+            return Ok(response);
+        }
+        let channel = apdu.first().map_or(0, |&cla| logical_channel(cla));
+        if !self.open.is_open(channel) {
+            return Ok(SW_CHANNEL_NOT_SUPPORTED.to_vec());
+        }
+        let apdu = on_basic_channel(apdu);
+        if let Some(select) = self.selections.switch(&channel, &apdu) {
+            trace!("Selecting the application of channel {} anew", channel);
+            self.backend.transmit(&select)?;
+        }
+        let response = self.backend.transmit(&apdu)?;
+        self.selections.answered(&channel, &apdu, &response);
+        Ok(response)
+    }
+
+    fn present(&mut self) -> bool {
+        self.backend.present()
+    }
+}
+
+/// A span over the round trip of `apdu`, which names its instruction but
+/// not its data
+pub(crate) fn apdu_span(apdu: &[u8]) -> Span {
+    debug_span!(
+        "apdu",
+        ins = %apdu.get(1).map_or("none".into(), |&ins| audit::instruction_name(ins)),
+    )
+}
+
+/// Whether `reply`, a RDR_to_PC message, asks for more time, which YubiKeys
+/// do while they wait for a touch
+pub(crate) fn is_time_extension(reply: &[u8]) -> bool {
+    reply.len() >= CCID_HEADER_LENGTH && reply[7] & 0xC0 == TIME_EXTENSION
+}
+
+/// How long [RemoteReader] waits for a reply, including the time extensions
+/// the card asks for, e.g. while waiting for a touch
+pub const REMOTE_REPLY_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(60);
+
+/// The other end of a [UsbCcidHandler]: a driver for the CCID reader of a
+/// device imported with [crate::client], to exchange APDUs with its card
+/// in-process
+pub struct RemoteReader {
+    device: crate::client::ImportedDevice,
+    bulk_in: u8,
+    bulk_out: u8,
+    seq: u8,
+}
+
+impl RemoteReader {
+    /// Find the CCID interface of `device`
+    pub async fn open(device: crate::client::ImportedDevice) -> Result<Self> {
+        let get_config = |length: u16| {
+            let length = length.to_le_bytes();
+            [0x80, 0x06, 0x00, 0x02, 0x00, 0x00, length[0], length[1]]
+        };
+        let header = device.control_in(get_config(9)).await?;
+        if header.len() < 4 {
+            return Err(std::io::Error::new(
+                ErrorKind::InvalidData,
+                "Invalid configuration descriptor",
+            ));
+        }
+        let total = u16::from_le_bytes([header[2], header[3]]);
+        let desc = device.control_in(get_config(total)).await?;
+        let endpoints = crate::webusb::CcidEndpoints::find(&desc).ok_or_else(|| {
+            std::io::Error::new(ErrorKind::NotFound, "Device has no CCID interface")
+        })?;
+        Ok(Self {
+            device,
+            bulk_in: endpoints.bulk_in,
+            bulk_out: endpoints.bulk_out,
+            seq: 0,
+        })
+    }
+
+    /// The device the reader belongs to
+    pub fn device(&self) -> &crate::client::ImportedDevice {
+        &self.device
+    }
+
+    /// Send a PC_to_RDR message and wait for its reply, calling `on_wait`
+    /// once if the card asks for more time
+    async fn command(
+        &mut self,
+        message_type: u8,
+        data: &[u8],
+        mut on_wait: impl FnMut(),
+    ) -> Result<Vec<u8>> {
+        let seq = self.seq;
+        self.seq = self.seq.wrapping_add(1);
+        let mut message = vec![message_type];
+        message.extend((data.len() as u32).to_le_bytes());
+        message.extend([0, seq, 0, 0, 0]);
+        message.extend(data);
+        self.device.transfer_out(self.bulk_out, message).await?;
+
+        let deadline = std::time::Instant::now() + REMOTE_REPLY_TIMEOUT;
+        let mut waiting = false;
+        loop {
+            match self.device.transfer_in(self.bulk_in, 65536).await {
+                Ok(reply) if reply.len() >= CCID_HEADER_LENGTH && reply[6] == seq => {
+                    let status = reply[7];
+                    if is_time_extension(&reply) {
+                        if !waiting {
+                            waiting = true;
+                            on_wait();
+                        }
+                    } else if status & COMMAND_FAILED != 0 {
+                        return Err(std::io::Error::other(format!(
+                            "CCID command failed: status {:#04x}, error {:#04x}",
+                            status, reply[8]
+                        )));
+                    } else {
+                        return Ok(reply[CCID_HEADER_LENGTH..].to_vec());
+                    }
+                }
+                // nothing yet, or a stale reply
+                Ok(_) => {}
+                Err(err) if err.kind() == ErrorKind::TimedOut => {}
+                Err(err) => return Err(err),
+            }
+            if std::time::Instant::now() >= deadline {
+                return Err(std::io::Error::new(
+                    ErrorKind::TimedOut,
+                    "CCID reply timed out",
+                ));
+            }
+        }
+    }
+
+    /// Power the card up, returning its ATR
+    pub async fn power_on(&mut self) -> Result<Vec<u8>> {
+        self.command(PC_TO_RDR_ICC_POWER_ON, &[], || {}).await
+    }
+
+    /// Power the card down
+    pub async fn power_off(&mut self) -> Result<()> {
+        self.command(PC_TO_RDR_ICC_POWER_OFF, &[], || {})
+            .await
+            .map(|_| ())
+    }
+
+    /// Send a command APDU to the card, returning its response APDU
+    ///
+    /// `on_wait` is called if the card asks for more time before answering,
+    /// which YubiKeys do while they wait for a touch.
+    pub async fn transmit(&mut self, apdu: &[u8], on_wait: impl FnMut()) -> Result<Vec<u8>> {
+        self.command(PC_TO_RDR_XFR_BLOCK, apdu, on_wait)
+            .instrument(apdu_span(apdu))
+            .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::util::tests::*;
+
+    use super::*;
+
+    /// Echoes APDUs, with 90 00 appended
+    struct EchoCard {
+        present: bool,
+    }
+
+    impl Default for EchoCard {
+        fn default() -> Self {
+            Self { present: true }
+        }
+    }
+
+    impl CcidBackend for EchoCard {
+        fn power_on(&mut self) -> Result<Vec<u8>> {
+            Ok(vec![0x3B, 0x00])
+        }
+
+        fn power_off(&mut self) -> Result<()> {
+            Ok(())
+        }
+
+        fn transmit(&mut self, apdu: &[u8]) -> Result<Vec<u8>> {
+            Ok([apdu, &[0x90, 0x00]].concat())
+        }
+
+        fn present(&mut self) -> bool {
+            self.present
+        }
+    }
+
+    fn message(kind: u8, seq: u8, data: &[u8]) -> Vec<u8> {
+        let mut message = vec![kind];
+        message.extend((data.len() as u32).to_le_bytes());
+        message.extend([0, seq, 0, 0, 0]);
+        message.extend(data);
+        message
+    }
+
+    fn exchange(
+        handler: &mut UsbCcidHandler<EchoCard>,
+        interface: &UsbInterface,
+        request: &[u8],
+    ) -> Vec<u8> {
+        let endpoints = UsbCcidHandler::<EchoCard>::endpoints();
+        for chunk in request.chunks(64) {
+            handler
+                .handle_urb(interface, endpoints[0], 0, SetupPacket::default(), chunk)
+                .unwrap();
+        }
+        handler
+            .handle_urb(interface, endpoints[1], 4096, SetupPacket::default(), &[])
+            .unwrap()
+    }
+
+    fn test_device() -> UsbDevice {
+        UsbDevice::new(0).with_interface(
+            ClassCode::SmartCard as u8,
+            CCID_SUBCLASS,
+            0x00,
+            "Test CCID",
+            UsbCcidHandler::<EchoCard>::endpoints(),
+            Arc::new(Mutex::new(
+                Box::new(UsbCcidHandler::new(EchoCard::default()))
+                    as Box<dyn UsbInterfaceHandler + Send>,
+            )),
+        )
+    }
+
+    #[test]
+    fn desc_verify() {
+        setup_test_logger();
+        let handler = UsbCcidHandler::new(EchoCard::default());
+        let desc = handler.get_class_specific_descriptor();
+        assert_eq!(desc.len(), 0x36);
+        verify_descriptor(&desc);
+    }
+
+    #[test]
+    fn exchange_apdus() {
+        setup_test_logger();
+        let device = test_device();
+        let interface = &device.interfaces[0];
+        let mut handler = UsbCcidHandler::new(EchoCard::default());
+
+        // APDUs need a powered card
+        let reply = exchange(&mut handler, interface, &message(0x6F, 1, &[0x00]));
+        assert_eq!(reply[0], RDR_TO_PC_DATA_BLOCK);
+        assert_eq!(reply[7], COMMAND_FAILED | ICC_INACTIVE);
+
+        let reply = exchange(&mut handler, interface, &message(0x62, 2, &[]));
+        assert_eq!(reply, message(RDR_TO_PC_DATA_BLOCK, 2, &[0x3B, 0x00]));
+
+        // A long APDU split over several transfers
+        let apdu: Vec<u8> = (0..200).map(|i| i as u8).collect();
+        let reply = exchange(&mut handler, interface, &message(0x6F, 3, &apdu));
+        assert_eq!(
+            reply,
+            message(
+                RDR_TO_PC_DATA_BLOCK,
+                3,
+                &[&apdu[..], &[0x90, 0x00]].concat()
+            )
+        );
+
+        let reply = exchange(&mut handler, interface, &message(0x65, 4, &[]));
+        assert_eq!(reply, message(RDR_TO_PC_SLOT_STATUS, 4, &[]));
+
+        // Unknown messages fail without affecting the slot
+        let reply = exchange(&mut handler, interface, &message(0x71, 5, &[]));
+        assert_eq!(reply[..7], message(RDR_TO_PC_SLOT_STATUS, 5, &[])[..7]);
+        assert_eq!(
+            reply[7..9],
+            [COMMAND_FAILED | ICC_ACTIVE, CMD_NOT_SUPPORTED]
+        );
+
+        let reply = exchange(&mut handler, interface, &message(0x63, 6, &[]));
+        assert_eq!(reply[7], ICC_INACTIVE);
+    }
+
+    #[test]
+    fn reader_messages() {
+        setup_test_logger();
+        let device = test_device();
+        let interface = &device.interfaces[0];
+        let mut handler = UsbCcidHandler::new(EchoCard::default());
+
+        let parameters = [0x11, 0x10, 0x00, 0x45, 0x00, 0xFE, 0x00];
+        let reply = exchange(&mut handler, interface, &message(0x6C, 1, &[]));
+        assert_eq!(
+            reply[..7],
+            message(RDR_TO_PC_PARAMETERS, 1, &parameters)[..7]
+        );
+        assert_eq!(reply[9], 0x01);
+        assert_eq!(reply[10..], parameters);
+
+        // T=0 is refused
+        let mut set_t0 = message(0x61, 2, &[0x11, 0x00, 0x00, 0x0A, 0x00]);
+        set_t0[7] = 0x00;
+        let reply = exchange(&mut handler, interface, &set_t0);
+        assert_eq!(reply[7..9], [COMMAND_FAILED | ICC_INACTIVE, 7]);
+
+        let reply = exchange(&mut handler, interface, &message(0x6D, 3, &[]));
+        assert_eq!(reply[0], RDR_TO_PC_PARAMETERS);
+        assert_eq!(reply[7..9], [ICC_INACTIVE, 0]);
+
+        let reply = exchange(&mut handler, interface, &message(0x6B, 4, &[0x01]));
+        assert_eq!(reply[0], RDR_TO_PC_ESCAPE);
+        assert_eq!(reply[8], CMD_NOT_SUPPORTED);
+
+        let reply = exchange(&mut handler, interface, &message(0x73, 5, &[0; 8]));
+        let mut rates = 4000u32.to_le_bytes().to_vec();
+        rates.extend(9600u32.to_le_bytes());
+        let mut expected = message(RDR_TO_PC_DATA_RATE_AND_CLOCK_FREQUENCY, 5, &rates);
+        expected[7] = ICC_INACTIVE;
+        assert_eq!(reply, expected);
+
+        // Class requests on the control endpoint
+        let control = UsbEndpoint {
+            address: 0x80,
+            attributes: EndpointAttributes::Control as u8,
+            max_packet_size: 64,
+            interval: 0,
+        };
+        let get_clock = SetupPacket {
+            request_type: 0b10100001,
+            request: 0x02,
+            value: 0,
+            index: 0,
+            length: 4,
+        };
+        assert_eq!(
+            handler
+                .handle_urb(interface, control, 4, get_clock, &[])
+                .unwrap(),
+            4000u32.to_le_bytes()
+        );
+        let abort = SetupPacket {
+            request_type: 0b00100001,
+            request: 0x01,
+            value: 0x0600,
+            index: 0,
+            length: 0,
+        };
+        handler
+            .handle_urb(interface, control, 0, abort, &[])
+            .unwrap();
+        let reply = exchange(&mut handler, interface, &message(0x72, 6, &[]));
+        assert_eq!(reply[..7], message(RDR_TO_PC_SLOT_STATUS, 6, &[])[..7]);
+        assert_eq!(reply[7..9], [ICC_INACTIVE, 0]);
+    }
+
+    #[test]
+    fn card_removal() {
+        setup_test_logger();
+        let device = test_device();
+        let interface = &device.interfaces[0];
+        let interrupt = UsbCcidHandler::<EchoCard>::endpoints()[2];
+        let mut handler = UsbCcidHandler::new(EchoCard::default());
+        let notify = |handler: &mut UsbCcidHandler<EchoCard>| {
+            handler
+                .handle_urb(interface, interrupt, 8, SetupPacket::default(), &[])
+                .unwrap()
+        };
+
+        // The initial state is reported once
+        assert_eq!(notify(&mut handler), [RDR_TO_PC_NOTIFY_SLOT_CHANGE, 0b11]);
+        assert!(notify(&mut handler).is_empty());
+
+        exchange(&mut handler, interface, &message(0x62, 1, &[]));
+        handler.backend.present = false;
+        assert_eq!(notify(&mut handler), [RDR_TO_PC_NOTIFY_SLOT_CHANGE, 0b10]);
+        let reply = exchange(&mut handler, interface, &message(0x6F, 2, &[0x00]));
+        assert_eq!(reply[7], COMMAND_FAILED | ICC_NOT_PRESENT);
+        let reply = exchange(&mut handler, interface, &message(0x62, 3, &[]));
+        assert_eq!(reply[7], COMMAND_FAILED | ICC_NOT_PRESENT);
+
+        // A card inserted again has to be powered on
+        handler.backend.present = true;
+        assert_eq!(notify(&mut handler), [RDR_TO_PC_NOTIFY_SLOT_CHANGE, 0b11]);
+        let reply = exchange(&mut handler, interface, &message(0x65, 4, &[]));
+        assert_eq!(reply[7], ICC_INACTIVE);
+    }
+
+    #[test]
+    fn logical_channels() {
+        /// Remembers the APDUs it answers with 90 00
+        #[derive(Default)]
+        struct LoggingCard(Vec<Vec<u8>>);
+
+        impl CcidBackend for LoggingCard {
+            fn power_on(&mut self) -> Result<Vec<u8>> {
+                Ok(vec![0x3B, 0x00])
+            }
+
+            fn power_off(&mut self) -> Result<()> {
+                Ok(())
+            }
+
+            fn transmit(&mut self, apdu: &[u8]) -> Result<Vec<u8>> {
+                self.0.push(apdu.to_vec());
+                Ok(SW_SUCCESS.to_vec())
+            }
+        }
+
+        assert_eq!(logical_channel(0x00), 0);
+        assert_eq!(logical_channel(0x13), 3);
+        assert_eq!(logical_channel(0x40), 4);
+        assert_eq!(logical_channel(0x6F), 19);
+        assert_eq!(logical_channel(0x83), 0);
+        assert_eq!(on_basic_channel(&[0x13, 0xA4]), [0x10, 0xA4]);
+        assert_eq!(on_basic_channel(&[0x61, 0xA4]), [0x08, 0xA4]);
+        assert_eq!(on_basic_channel(&[0x83, 0xA4]), [0x83, 0xA4]);
+
+        let mut card = LogicalChannels::new(LoggingCard::default());
+        card.power_on().unwrap();
+        let select_piv = [0x00, 0xA4, 0x04, 0x00, 0x01, 0xA1];
+        let select_otp = [0x00, 0xA4, 0x04, 0x00, 0x01, 0xA2];
+        let get_data = |channel: u8| [channel, 0xCB, 0x3F, 0xFF];
+
+        // Channels that aren't open can't be used
+        assert_eq!(
+            card.transmit(&get_data(1)).unwrap(),
+            SW_CHANNEL_NOT_SUPPORTED
+        );
+        assert_eq!(
+            card.transmit(&[0x00, 0x70, 0x00, 0x00, 0x01]).unwrap(),
+            [1, 0x90, 0x00]
+        );
+        assert_eq!(
+            card.transmit(&[0x00, 0x70, 0x00, 0x03]).unwrap(),
+            SW_SUCCESS
+        );
+        assert_eq!(
+            card.transmit(&[0x00, 0x70, 0x00, 0x03]).unwrap(),
+            SW_WRONG_P1P2
+        );
+        assert!(card.backend.0.is_empty());
+
+        card.transmit(&select_piv).unwrap();
+        card.transmit(&[0x01, 0xA4, 0x04, 0x00, 0x01, 0xA2])
+            .unwrap();
+        card.backend.0.clear();
+        // Each channel finds its application selected
+        card.transmit(&get_data(0)).unwrap();
+        card.transmit(&get_data(0)).unwrap();
+        card.transmit(&get_data(1)).unwrap();
+        assert_eq!(
+            card.backend.0,
+            [
+                select_piv.to_vec(),
+                get_data(0).to_vec(),
+                get_data(0).to_vec(),
+                select_otp.to_vec(),
+                get_data(0).to_vec()
+            ]
+        );
+
+        // Closed channels are forgotten
+        assert_eq!(
+            card.transmit(&[0x01, 0x70, 0x80, 0x00]).unwrap(),
+            SW_SUCCESS
+        );
+        assert_eq!(
+            card.transmit(&get_data(1)).unwrap(),
+            SW_CHANNEL_NOT_SUPPORTED
+        );
+        assert_eq!(
+            card.transmit(&[0x00, 0x70, 0x80, 0x00]).unwrap(),
+            SW_WRONG_P1P2
+        );
+        card.power_on().unwrap();
+        assert_eq!(
+            card.transmit(&get_data(3)).unwrap(),
+            SW_CHANNEL_NOT_SUPPORTED
+        );
+    }
+
+    #[tokio::test]
+    async fn remote_reader() {
+        setup_test_logger();
+        let addr = get_free_address().await;
+        let server = Arc::new(UsbIpServer::new_simulated(vec![test_device()]));
+        tokio::spawn(crate::server(addr, server));
+
+        let mut socket = poll_connect(addr).await;
+        let device = crate::client::import(&mut socket, "0-0-0").await.unwrap();
+        let device = crate::client::ImportedDevice::new(socket, device);
+        let mut reader = RemoteReader::open(device).await.unwrap();
+
+        assert_eq!(reader.power_on().await.unwrap(), [0x3B, 0x00]);
+        let mut waited = false;
+        let reply = reader
+            .transmit(&[0x00, 0xA4, 0x04, 0x00], || waited = true)
+            .await
+            .unwrap();
+        assert_eq!(reply, [0x00, 0xA4, 0x04, 0x00, 0x90, 0x00]);
+        assert!(!waited);
+        reader.power_off().await.unwrap();
+    }
+}
@@ -56,7 +56,7 @@ impl UsbInterfaceHandler for UsbCdcAcmHandler {
         _transfer_buffer_length: u32,
         _setup: SetupPacket,
         req: &[u8],
-    ) -> Result<Vec<u8>> {
+    ) -> UrbResult {
         if ep.attributes == EndpointAttributes::Interrupt as u8 {
             // interrupt
             if let Direction::In = ep.direction() {
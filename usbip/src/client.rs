@@ -0,0 +1,669 @@
+//! USB/IP client
+//!
+//! The other end of [crate::server]: list the devices exported by a remote
+//! server, import one, and then either use it in-process through an
+//! [ImportedDevice], or (on Linux) hand the connection to vhci-hcd with
+//! [attach_vhci] so the device shows up like a local one.
+use crate::acl::{MAX_TOKEN_LENGTH, TOKEN_PREAMBLE};
+use crate::usbip_protocol::{UsbIpCommand, UsbIpHeaderBasic};
+use crate::wire::{
+    ExportedDevice, UsbIpReply, WireError, OP_REP_IMPORT_BUSY, USBIP_CMD_SUBMIT, USBIP_CMD_UNLINK,
+};
+use crate::Transport;
+use crate::UrbError;
+use std::future::Future;
+use std::io::{Error, ErrorKind, Result};
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::sync::{oneshot, Notify};
+use tokio::task::JoinHandle;
+use tracing::{debug, info, trace, warn};
+
+/// Read one reply from `socket`, buffering partial reads in `input`
+async fn read_reply<T: AsyncRead + Unpin>(
+    socket: &mut T,
+    input: &mut Vec<u8>,
+    is_in: impl Fn(u32) -> bool,
+) -> Result<UsbIpReply> {
+    loop {
+        match UsbIpReply::decode(input, &is_in) {
+            Ok((reply, len)) => {
+                input.drain(..len);
+                return Ok(reply);
+            }
+            Err(WireError::Truncated { needed }) => {
+                let start = input.len();
+                input.resize(start + needed, 0);
+                socket.read_exact(&mut input[start..]).await?;
+            }
+            Err(err) => return Err(Error::new(ErrorKind::InvalidData, err.to_string())),
+        }
+    }
+}
+
+/// List the devices exported by the server on the other end of `socket`
+///
+/// Servers usually close the connection afterwards, like the Linux usbipd.
+pub async fn list_devices<T: AsyncRead + AsyncWrite + Unpin>(
+    socket: &mut T,
+) -> Result<Vec<ExportedDevice>> {
+    socket
+        .write_all(&UsbIpCommand::OpReqDevlist { status: 0 }.to_bytes())
+        .await?;
+    match read_reply(socket, &mut vec![], |_| false).await? {
+        UsbIpReply::OpRepDevlist { status: 0, devices } => Ok(devices),
+        UsbIpReply::OpRepDevlist { status, .. } => Err(Error::other(format!(
+            "Server failed to list devices: {}",
+            status
+        ))),
+        _ => Err(Error::new(ErrorKind::InvalidData, "Unexpected reply")),
+    }
+}
+
+/// Authenticate with `token` to servers with an [crate::acl::Policy]
+///
+/// Must be sent before the first request on `socket`.
+pub async fn send_token<T: AsyncWrite + Unpin>(socket: &mut T, token: &str) -> Result<()> {
+    if token.is_empty() || token.len() > MAX_TOKEN_LENGTH || token.contains('\n') {
+        return Err(Error::new(ErrorKind::InvalidInput, "Invalid token"));
+    }
+    let mut line = TOKEN_PREAMBLE.to_vec();
+    line.extend(token.as_bytes());
+    line.push(b'\n');
+    socket.write_all(&line).await
+}
+
+/// Import the device with `bus_id` over `socket`
+///
+/// Afterwards `socket` carries the URBs of the device, see [ImportedDevice]
+/// and [attach_vhci].
+pub async fn import<T: AsyncRead + AsyncWrite + Unpin>(
+    socket: &mut T,
+    bus_id: &str,
+) -> Result<ExportedDevice> {
+    let mut busid = [0; 32];
+    if bus_id.len() >= busid.len() {
+        return Err(Error::new(ErrorKind::InvalidInput, "Bus id too long"));
+    }
+    busid[..bus_id.len()].copy_from_slice(bus_id.as_bytes());
+    socket
+        .write_all(&UsbIpCommand::OpReqImport { status: 0, busid }.to_bytes())
+        .await?;
+    match read_reply(socket, &mut vec![], |_| false).await? {
+        UsbIpReply::OpRepImport {
+            device: Some(device),
+            ..
+        } => Ok(device),
+        UsbIpReply::OpRepImport {
+            status: OP_REP_IMPORT_BUSY,
+            ..
+        } => Err(Error::new(
+            ErrorKind::ResourceBusy,
+            format!("{} is in use by another client", bus_id),
+        )),
+        UsbIpReply::OpRepImport { .. } => Err(Error::new(
+            ErrorKind::NotFound,
+            format!("Server refused to export {}", bus_id),
+        )),
+        _ => Err(Error::new(ErrorKind::InvalidData, "Unexpected reply")),
+    }
+}
+
+/// A URB waiting for its USBIP_RET_SUBMIT
+struct Pending {
+    is_in: bool,
+    done: oneshot::Sender<Result<Vec<u8>>>,
+}
+
+type PendingMap = Arc<Mutex<std::collections::HashMap<u32, Pending>>>;
+
+type Writer = Box<dyn AsyncWrite + Send + Unpin>;
+
+/// Opens a new connection to the server, ready for an import, e.g. with TLS
+/// and a token
+pub type Connect =
+    Box<dyn Fn() -> Pin<Box<dyn Future<Output = Result<Box<dyn Transport>>> + Send>> + Send + Sync>;
+
+/// How an [ImportedDevice] reconnects, see [ImportedDevice::with_reconnect]
+#[derive(Debug, Clone)]
+pub struct Backoff {
+    /// Wait before the first attempt, doubled after each failed attempt
+    pub initial: Duration,
+    /// Longest wait between two attempts
+    pub max: Duration,
+    /// Attempts before giving up
+    pub attempts: u32,
+}
+
+impl Default for Backoff {
+    fn default() -> Self {
+        Self {
+            initial: Duration::from_millis(100),
+            max: Duration::from_secs(5),
+            attempts: 8,
+        }
+    }
+}
+
+/// How an [ImportedDevice] checks that the server is still there, see
+/// [ImportedDevice::with_keep_alive]
+#[derive(Debug, Clone)]
+pub struct KeepAlive {
+    /// Time between two pings
+    pub interval: Duration,
+    /// Silence from the server after which the connection is given up
+    pub timeout: Duration,
+}
+
+impl Default for KeepAlive {
+    fn default() -> Self {
+        Self {
+            interval: Duration::from_secs(15),
+            timeout: Duration::from_secs(45),
+        }
+    }
+}
+
+/// Seqnum the pings of [ImportedDevice::with_keep_alive] unlink, which no
+/// URB has
+const PING_SEQNUM: u32 = 0;
+
+/// A device imported from a USB/IP server, used in-process
+///
+/// Transfers may be started concurrently from several tasks; each waits for
+/// its own reply, so a transfer blocked on one endpoint doesn't hold up the
+/// others.
+///
+/// When the connection drops, the URBs sent on it fail with
+/// [ErrorKind::ConnectionAborted]: whether the device got them is unknown,
+/// so they are never sent again. Without [ImportedDevice::with_reconnect],
+/// later transfers fail too. With it, they wait while the device is imported
+/// again on a new connection, and are then sent on that one. Devices may lose
+/// their state in between, e.g. a smart card its selected application.
+pub struct ImportedDevice {
+    device: ExportedDevice,
+    /// The device id URBs are sent to, which changes if the server gives it
+    /// another one when it is imported again
+    devid: Arc<AtomicU32>,
+    /// `None` once the connection is lost for good; held while reconnecting
+    writer: Arc<tokio::sync::Mutex<Option<Writer>>>,
+    pending: PendingMap,
+    next_seqnum: Arc<AtomicU32>,
+    /// When the last reply arrived
+    last_heard: Arc<Mutex<Instant>>,
+    /// Notified when the server stopped answering pings
+    silent: Arc<Notify>,
+    reader: JoinHandle<()>,
+    pinger: Option<JoinHandle<()>>,
+}
+
+/// Read the replies to the URBs in `pending` from `reader`, until it fails
+/// or `silent` is notified
+async fn read_replies<T: AsyncRead + Unpin>(
+    reader: &mut T,
+    pending: &PendingMap,
+    last_heard: &Mutex<Instant>,
+    silent: &Notify,
+) -> Error {
+    let mut input = vec![];
+    loop {
+        let is_in = |seqnum| {
+            let pending = pending.lock().unwrap();
+            pending.get(&seqnum).is_some_and(|p: &Pending| p.is_in)
+        };
+        let reply = tokio::select! {
+            reply = read_reply(reader, &mut input, is_in) => reply,
+            _ = silent.notified() => {
+                return Error::new(ErrorKind::TimedOut, "Server stopped responding");
+            }
+        };
+        if reply.is_ok() {
+            *last_heard.lock().unwrap() = Instant::now();
+        }
+        match reply {
+            Ok(UsbIpReply::UsbIpRetSubmit {
+                header,
+                status,
+                transfer_buffer,
+                ..
+            }) => {
+                let Some(urb) = pending.lock().unwrap().remove(&header.seqnum) else {
+                    warn!("Reply to unknown URB {}", header.seqnum);
+                    continue;
+                };
+                let res = if status == 0 {
+                    Ok(transfer_buffer)
+                } else {
+                    Err(UrbError::from_status(status).into())
+                };
+                urb.done.send(res).ok();
+            }
+            Ok(UsbIpReply::UsbIpRetUnlink { .. }) => trace!("Got a pong"),
+            Ok(reply) => warn!("Unexpected reply {:?}", reply),
+            Err(err) => return err,
+        }
+    }
+}
+
+/// Import `device` again over a new connection from `connect`, retrying with
+/// `backoff`, e.g. while the server still holds the old import
+async fn reimport(
+    connect: &Connect,
+    device: &ExportedDevice,
+    backoff: &Backoff,
+) -> Result<(Box<dyn Transport>, ExportedDevice)> {
+    let mut wait = backoff.initial;
+    let mut attempt = 1;
+    loop {
+        tokio::time::sleep(wait).await;
+        let res = async {
+            let mut socket = connect().await?;
+            let imported = import(&mut socket, &device.bus_id).await?;
+            if (imported.vendor_id, imported.product_id) != (device.vendor_id, device.product_id) {
+                return Err(Error::new(
+                    ErrorKind::NotFound,
+                    format!("{} is another device now", device.bus_id),
+                ));
+            }
+            Ok((socket, imported))
+        }
+        .await;
+        match res {
+            Ok(res) => return Ok(res),
+            Err(err) if attempt >= backoff.attempts || err.kind() == ErrorKind::NotFound => {
+                return Err(err)
+            }
+            Err(err) => debug!("Importing {} again failed: {}", device.bus_id, err),
+        }
+        attempt += 1;
+        wait = (wait * 2).min(backoff.max);
+    }
+}
+
+impl ImportedDevice {
+    /// Use `device`, which was imported over `socket` with [import]
+    pub fn new<T: Transport + 'static>(socket: T, device: ExportedDevice) -> Self {
+        Self::start(Box::new(socket), device, None)
+    }
+
+    /// Like [ImportedDevice::new], but when the connection drops, import the
+    /// device again over a connection from `connect`, retrying with `backoff`
+    pub fn with_reconnect<T: Transport + 'static>(
+        socket: T,
+        device: ExportedDevice,
+        connect: Connect,
+        backoff: Backoff,
+    ) -> Self {
+        Self::start(Box::new(socket), device, Some((connect, backoff)))
+    }
+
+    fn start(
+        socket: Box<dyn Transport>,
+        device: ExportedDevice,
+        reconnect: Option<(Connect, Backoff)>,
+    ) -> Self {
+        let (mut reader, writer) = tokio::io::split(socket);
+        let pending = PendingMap::default();
+        let devid = Arc::new(AtomicU32::new(device.bus_num << 16 | device.dev_num));
+        let writer = Arc::new(tokio::sync::Mutex::new(Some(Box::new(writer) as Writer)));
+        let last_heard = Arc::new(Mutex::new(Instant::now()));
+        let silent = Arc::new(Notify::new());
+        let reader = tokio::spawn({
+            let pending = pending.clone();
+            let devid = devid.clone();
+            let writer = writer.clone();
+            let device = device.clone();
+            let last_heard = last_heard.clone();
+            let silent = silent.clone();
+            async move {
+                loop {
+                    let err = read_replies(&mut reader, &pending, &last_heard, &silent).await;
+                    // Hold off new URBs, so the ones failed here are exactly
+                    // those sent on the lost connection
+                    let mut writer = writer.lock().await;
+                    *writer = None;
+                    for (_, urb) in pending.lock().unwrap().drain() {
+                        urb.done
+                            .send(Err(Error::new(
+                                ErrorKind::ConnectionAborted,
+                                format!("Connection lost: {}", err),
+                            )))
+                            .ok();
+                    }
+                    let Some((connect, backoff)) = &reconnect else {
+                        return;
+                    };
+                    warn!("Connection to {} lost: {}", device.bus_id, err);
+                    match reimport(connect, &device, backoff).await {
+                        Ok((socket, imported)) => {
+                            info!("Imported {} again", device.bus_id);
+                            devid.store(
+                                imported.bus_num << 16 | imported.dev_num,
+                                Ordering::Relaxed,
+                            );
+                            let (new_reader, new_writer) = tokio::io::split(socket);
+                            reader = new_reader;
+                            *writer = Some(Box::new(new_writer));
+                            *last_heard.lock().unwrap() = Instant::now();
+                        }
+                        Err(err) => {
+                            warn!("Giving up on {}: {}", device.bus_id, err);
+                            return;
+                        }
+                    }
+                }
+            }
+        });
+        Self {
+            device,
+            devid,
+            writer,
+            pending,
+            next_seqnum: Arc::new(AtomicU32::new(1)),
+            last_heard,
+            silent,
+            reader,
+            pinger: None,
+        }
+    }
+
+    /// Ping the server every `keep_alive.interval`, and give up on the
+    /// connection when it didn't answer for `keep_alive.timeout`
+    ///
+    /// Otherwise a connection that silently died, e.g. when a NAT dropped its
+    /// mapping during a long idle time, is only noticed when TCP gives up on
+    /// a transfer. The pings also keep such mappings from expiring. It is
+    /// then handled like any lost connection, see [ImportedDevice]. Pings
+    /// unlink a URB that doesn't exist, which servers simply acknowledge.
+    pub fn with_keep_alive(mut self, keep_alive: KeepAlive) -> Self {
+        let writer = self.writer.clone();
+        let devid = self.devid.clone();
+        let next_seqnum = self.next_seqnum.clone();
+        let last_heard = self.last_heard.clone();
+        let silent = self.silent.clone();
+        self.pinger = Some(tokio::spawn(async move {
+            let mut ticks = tokio::time::interval(keep_alive.interval);
+            loop {
+                ticks.tick().await;
+                // Waits while reconnecting
+                let mut writer = writer.lock().await;
+                let Some(writer) = writer.as_mut() else {
+                    return;
+                };
+                if last_heard.lock().unwrap().elapsed() >= keep_alive.timeout {
+                    silent.notify_one();
+                    continue;
+                }
+                let ping = UsbIpCommand::UsbIpCmdUnlink {
+                    header: UsbIpHeaderBasic {
+                        command: USBIP_CMD_UNLINK.into(),
+                        seqnum: next_seqnum.fetch_add(1, Ordering::Relaxed),
+                        devid: devid.load(Ordering::Relaxed),
+                        direction: 0,
+                        ep: 0,
+                    },
+                    unlink_seqnum: PING_SEQNUM,
+                };
+                if let Err(err) = writer.write_all(&ping.to_bytes()).await {
+                    debug!("Sending a ping failed: {}", err);
+                }
+            }
+        }));
+        self
+    }
+
+    /// The device as described by the server
+    pub fn device(&self) -> &ExportedDevice {
+        &self.device
+    }
+
+    /// Submit a URB to `ep` (including the direction bit) and wait for its reply
+    ///
+    /// `length` is the transfer buffer length of IN transfers; OUT transfers
+    /// send `data`. Returns the data received.
+    pub async fn submit(
+        &self,
+        ep: u8,
+        setup: [u8; 8],
+        data: Vec<u8>,
+        length: u32,
+    ) -> Result<Vec<u8>> {
+        let is_in = ep & 0x80 != 0;
+        let seqnum = self.next_seqnum.fetch_add(1, Ordering::Relaxed);
+
+        let (done, reply) = oneshot::channel();
+        {
+            // Waits while reconnecting
+            let mut writer = self.writer.lock().await;
+            let writer = writer
+                .as_mut()
+                .ok_or_else(|| Error::new(ErrorKind::NotConnected, "Connection closed"))?;
+            let cmd = UsbIpCommand::UsbIpCmdSubmit {
+                header: UsbIpHeaderBasic {
+                    command: USBIP_CMD_SUBMIT.into(),
+                    seqnum,
+                    devid: self.devid.load(Ordering::Relaxed),
+                    direction: is_in as u32,
+                    ep: (ep & 0x7F).into(),
+                },
+                transfer_flags: 0,
+                transfer_buffer_length: if is_in { length } else { data.len() as u32 },
+                start_frame: 0,
+                number_of_packets: 0,
+                interval: 0,
+                setup,
+                data: if is_in { vec![] } else { data },
+                iso_packet_descriptor: vec![],
+            };
+            self.pending
+                .lock()
+                .unwrap()
+                .insert(seqnum, Pending { is_in, done });
+            if let Err(err) = writer.write_all(&cmd.to_bytes()).await {
+                self.pending.lock().unwrap().remove(&seqnum);
+                return Err(err);
+            }
+        }
+        reply
+            .await
+            .map_err(|_| Error::new(ErrorKind::BrokenPipe, "Connection closed"))?
+    }
+
+    /// Control transfer from the device, `setup` includes the length
+    pub async fn control_in(&self, setup: [u8; 8]) -> Result<Vec<u8>> {
+        let length = u16::from_le_bytes([setup[6], setup[7]]);
+        self.submit(0x80, setup, vec![], length.into()).await
+    }
+
+    /// Control transfer to the device
+    pub async fn control_out(&self, setup: [u8; 8], data: Vec<u8>) -> Result<()> {
+        self.submit(0x00, setup, data, 0).await.map(|_| ())
+    }
+
+    /// Bulk or interrupt transfer from `ep`
+    pub async fn transfer_in(&self, ep: u8, length: u32) -> Result<Vec<u8>> {
+        self.submit(ep | 0x80, [0; 8], vec![], length).await
+    }
+
+    /// Bulk or interrupt transfer to `ep`
+    pub async fn transfer_out(&self, ep: u8, data: Vec<u8>) -> Result<()> {
+        self.submit(ep & 0x7F, [0; 8], data, 0).await.map(|_| ())
+    }
+}
+
+impl Drop for ImportedDevice {
+    fn drop(&mut self) {
+        self.reader.abort();
+        if let Some(pinger) = &self.pinger {
+            pinger.abort();
+        }
+    }
+}
+
+/// Attach `device`, imported over `socket` with [import], to vhci-hcd
+///
+/// The kernel takes over the connection, and the device appears on the
+/// returned port of the virtual host controller until it is detached (e.g.
+/// with `usbip detach -p PORT`). Needs the vhci-hcd module and root.
+#[cfg(target_os = "linux")]
+pub fn attach_vhci(socket: std::net::TcpStream, device: &ExportedDevice) -> Result<u32> {
+    use std::os::unix::io::AsRawFd;
+
+    const VHCI: &str = "/sys/devices/platform/vhci_hcd.0";
+    // VDEV_ST_NULL, a free port
+    const PORT_FREE: u32 = 4;
+
+    // SuperSpeed devices need a port on the SuperSpeed root hub
+    let hub = if device.speed >= crate::UsbSpeed::Super as u32 {
+        "ss"
+    } else {
+        "hs"
+    };
+    let mut port = None;
+    for entry in std::fs::read_dir(VHCI)? {
+        let entry = entry?;
+        if !entry.file_name().to_string_lossy().starts_with("status") {
+            continue;
+        }
+        // hub port sta spd dev sockfd local_busid
+        for line in std::fs::read_to_string(entry.path())?.lines().skip(1) {
+            let fields: Vec<_> = line.split_whitespace().collect();
+            if let [h, p, status, ..] = fields[..] {
+                if h == hub && status.parse() == Ok(PORT_FREE) {
+                    port = p.parse::<u32>().ok();
+                    break;
+                }
+            }
+        }
+        if port.is_some() {
+            break;
+        }
+    }
+    let port = port.ok_or_else(|| Error::other("No free vhci-hcd port"))?;
+
+    // e.g. after tokio::net::TcpStream::into_std
+    socket.set_nonblocking(false)?;
+    let devid = device.bus_num << 16 | device.dev_num;
+    std::fs::write(
+        format!("{}/attach", VHCI),
+        format!("{} {} {} {}", port, socket.as_raw_fd(), devid, device.speed),
+    )?;
+    // the kernel holds its own reference to the socket now
+    Ok(port)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::tests::*;
+    use crate::{cdc, server, ClassCode, UsbDevice, UsbInterfaceHandler, UsbIpServer};
+
+    fn new_server() -> UsbIpServer {
+        UsbIpServer::new_simulated(vec![UsbDevice::new(0).with_interface(
+            ClassCode::CDC as u8,
+            cdc::CDC_ACM_SUBCLASS,
+            0x00,
+            "Test CDC ACM",
+            cdc::UsbCdcAcmHandler::endpoints(),
+            Arc::new(Mutex::new(
+                Box::new(cdc::UsbCdcAcmHandler::new()) as Box<dyn UsbInterfaceHandler + Send>
+            )),
+        )])
+    }
+
+    #[tokio::test]
+    async fn list_and_import() {
+        setup_test_logger();
+        let addr = get_free_address().await;
+        tokio::spawn(server(addr, Arc::new(new_server())));
+
+        let mut socket = poll_connect(addr).await;
+        let devices = list_devices(&mut socket).await.unwrap();
+        assert_eq!(devices.len(), 1);
+        assert_eq!(
+            devices[0].interfaces[0].interface_class,
+            ClassCode::CDC as u8
+        );
+
+        let mut socket = poll_connect(addr).await;
+        assert!(import(&mut socket, "1-1").await.is_err());
+        let device = import(&mut socket, &devices[0].bus_id).await.unwrap();
+        let device = ImportedDevice::new(socket, device);
+
+        // GetDescriptor(Device) on two tasks at once
+        let setup = [0x80, 0x06, 0x00, 0x01, 0x00, 0x00, 0x12, 0x00];
+        let (a, b) = tokio::join!(device.control_in(setup), device.control_in(setup));
+        assert_eq!(a.unwrap().len(), 0x12);
+        assert_eq!(b.unwrap()[1], 0x01);
+
+        // Unknown endpoints fail the URB, not the connection
+        assert!(device.transfer_in(0x0F, 8).await.is_err());
+        assert_eq!(device.control_in(setup).await.unwrap().len(), 0x12);
+    }
+
+    #[tokio::test]
+    async fn keep_alive() {
+        setup_test_logger();
+        let server = new_server().with_dead_peer_timeout(Duration::from_millis(200));
+        let addr = get_free_address().await;
+        tokio::spawn(crate::server(addr, Arc::new(server)));
+
+        let mut socket = poll_connect(addr).await;
+        let device = import(&mut socket, "0-0-0").await.unwrap();
+        let device = ImportedDevice::new(socket, device).with_keep_alive(KeepAlive {
+            interval: Duration::from_millis(50),
+            timeout: Duration::from_secs(1),
+        });
+
+        // The pings keep an idle import from being cut off
+        tokio::time::sleep(Duration::from_millis(500)).await;
+        let setup = [0x80, 0x06, 0x00, 0x01, 0x00, 0x00, 0x12, 0x00];
+        assert_eq!(device.control_in(setup).await.unwrap().len(), 0x12);
+    }
+
+    #[tokio::test]
+    async fn reconnect() {
+        setup_test_logger();
+        let server = Arc::new(new_server());
+        let addr = get_free_address().await;
+        tokio::spawn(crate::server(addr, server.clone()));
+
+        let mut socket = poll_connect(addr).await;
+        let device = import(&mut socket, "0-0-0").await.unwrap();
+        let connect: Connect = Box::new(move || {
+            Box::pin(async move {
+                Ok(Box::new(tokio::net::TcpStream::connect(addr).await?) as Box<dyn Transport>)
+            })
+        });
+        let backoff = Backoff {
+            initial: Duration::from_millis(10),
+            ..Default::default()
+        };
+        let device = ImportedDevice::with_reconnect(socket, device, connect, backoff);
+        let setup = [0x80, 0x06, 0x00, 0x01, 0x00, 0x00, 0x12, 0x00];
+        // URBs sent before the client noticed the connection is gone fail
+        let control_in = || async {
+            loop {
+                match device.control_in(setup).await {
+                    Err(err) if err.kind() == ErrorKind::ConnectionAborted => continue,
+                    res => return res,
+                }
+            }
+        };
+        assert_eq!(control_in().await.unwrap().len(), 0x12);
+
+        // The device is imported again after the connection is closed
+        server.revoke("0-0-0").await.unwrap();
+        assert_eq!(control_in().await.unwrap().len(), 0x12);
+
+        // Unless it is gone
+        server.unplug_device("0-0-0").await.unwrap();
+        assert_eq!(
+            control_in().await.err().map(|err| err.kind()),
+            Some(ErrorKind::NotConnected)
+        );
+    }
+}
@@ -1,7 +1,10 @@
 use super::*;
 
 /// A list of known USB speeds
-#[derive(Copy, Clone, Debug)]
+///
+/// The values are those of `enum usb_device_speed` in Linux, which the
+/// `usbip` tools and vhci-hcd expect.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
 #[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub enum UsbSpeed {
     Unknown = 0x0,
@@ -13,6 +16,34 @@ pub enum UsbSpeed {
     SuperPlus,
 }
 
+impl From<rusb::Speed> for UsbSpeed {
+    fn from(speed: rusb::Speed) -> Self {
+        // rusb has no wireless USB, so its values are off by one from Super on
+        match speed {
+            rusb::Speed::Low => UsbSpeed::Low,
+            rusb::Speed::Full => UsbSpeed::Full,
+            rusb::Speed::High => UsbSpeed::High,
+            rusb::Speed::Super => UsbSpeed::Super,
+            rusb::Speed::SuperPlus => UsbSpeed::SuperPlus,
+            _ => UsbSpeed::Unknown,
+        }
+    }
+}
+
+#[cfg(feature = "nusb")]
+impl From<nusb::Speed> for UsbSpeed {
+    fn from(speed: nusb::Speed) -> Self {
+        match speed {
+            nusb::Speed::Low => UsbSpeed::Low,
+            nusb::Speed::Full => UsbSpeed::Full,
+            nusb::Speed::High => UsbSpeed::High,
+            nusb::Speed::Super => UsbSpeed::Super,
+            nusb::Speed::SuperPlus => UsbSpeed::SuperPlus,
+            _ => UsbSpeed::Unknown,
+        }
+    }
+}
+
 /// A list of defined USB class codes
 // https://www.usb.org/defined-class-codes
 #[derive(Copy, Clone, Debug)]
@@ -59,6 +90,9 @@ pub use rusb::Direction;
 /// Emulated max packet size of EP0
 pub const EP0_MAX_PACKET_SIZE: u16 = 64;
 
+/// Feature selector of CLEAR_FEATURE and SET_FEATURE for a halted endpoint
+pub const ENDPOINT_HALT: u16 = 0;
+
 /// A list of defined USB standard requests
 /// from USB 2.0 standard Table 9.4. Standard Request Codes
 #[derive(Copy, Clone, Debug, FromPrimitive)]
@@ -0,0 +1,547 @@
+//! Implement CTAPHID (FIDO2/U2F authenticator) device
+//!
+//! The handler takes care of the HID report framing and channels of CTAPHID,
+//! and hands complete CTAP2 CBOR requests, and U2F APDUs if supported, to a
+//! [CtapBackend], e.g. a remote authenticator.
+use super::*;
+use crate::hid::HidDescriptorType;
+
+// reference:
+// CTAP 2.1, section 11.2: https://fidoalliance.org/specs/fido-v2.1-ps-20210615/fido-client-to-authenticator-protocol-v2.1-ps-20210615.html#usb
+
+/// Size of every CTAPHID report
+pub const CTAPHID_REPORT_SIZE: usize = 64;
+
+/// Largest message a CTAPHID transaction can carry: an initialization packet
+/// followed by 128 continuation packets
+pub const CTAPHID_MAX_MESSAGE_LENGTH: usize =
+    (CTAPHID_REPORT_SIZE - 7) + 128 * (CTAPHID_REPORT_SIZE - 5);
+
+/// Channel id used to allocate channels
+const BROADCAST_CID: u32 = 0xFFFF_FFFF;
+
+// Commands
+const CTAPHID_PING: u8 = 0x01;
+const CTAPHID_MSG: u8 = 0x03;
+const CTAPHID_LOCK: u8 = 0x04;
+const CTAPHID_INIT: u8 = 0x06;
+const CTAPHID_WINK: u8 = 0x08;
+const CTAPHID_CBOR: u8 = 0x10;
+const CTAPHID_CANCEL: u8 = 0x11;
+const CTAPHID_ERROR: u8 = 0x3F;
+
+// Errors
+const ERR_INVALID_CMD: u8 = 0x01;
+const ERR_INVALID_LEN: u8 = 0x03;
+const ERR_INVALID_SEQ: u8 = 0x04;
+const ERR_CHANNEL_BUSY: u8 = 0x06;
+const ERR_INVALID_CHANNEL: u8 = 0x0B;
+const ERR_OTHER: u8 = 0x7F;
+
+// Capabilities
+const CAPABILITY_WINK: u8 = 0x01;
+const CAPABILITY_CBOR: u8 = 0x04;
+const CAPABILITY_NMSG: u8 = 0x08;
+
+/// The authenticator behind a [UsbCtapHidHandler]
+pub trait CtapBackend: Send {
+    /// Handle a CTAP2 request (command byte and CBOR parameters), returning
+    /// the response (status byte and CBOR data)
+    fn cbor(&mut self, request: &[u8]) -> Result<Vec<u8>>;
+
+    /// Handle a U2F (CTAP1) request APDU, returning the response APDU
+    ///
+    /// Only called if [CtapBackend::supports_u2f] is true.
+    fn msg(&mut self, _apdu: &[u8]) -> Result<Vec<u8>> {
+        Err(std::io::Error::new(
+            ErrorKind::Unsupported,
+            "U2F is not supported",
+        ))
+    }
+
+    /// Whether U2F requests are supported
+    fn supports_u2f(&self) -> bool {
+        false
+    }
+
+    /// Make the authenticator identify itself to the user, e.g. by blinking
+    fn wink(&mut self) {}
+
+    /// Cancel the request in progress, if any
+    fn cancel(&mut self) {}
+}
+
+/// A message being received
+struct Transaction {
+    cid: u32,
+    command: u8,
+    length: usize,
+    data: Vec<u8>,
+    /// Sequence number of the next continuation packet
+    seq: u8,
+}
+
+/// A handler of a CTAPHID interface, forwarding requests to a [CtapBackend]
+///
+/// Requests are handled as soon as they are complete, so the backend can't
+/// send keep-alive messages while waiting for user presence, and a
+/// CTAPHID_CANCEL only reaches it after the request it cancels.
+pub struct UsbCtapHidHandler<B> {
+    pub backend: B,
+    pub report_descriptor: Vec<u8>,
+    /// Version of the authenticator reported by CTAPHID_INIT: major, minor,
+    /// build
+    pub version: [u8; 3],
+    transaction: Option<Transaction>,
+    /// Last allocated channel
+    last_cid: u32,
+    /// Reports not read yet
+    reports: VecDeque<Vec<u8>>,
+}
+
+impl<B: CtapBackend> UsbCtapHidHandler<B> {
+    pub fn new(backend: B) -> Self {
+        Self {
+            backend,
+            report_descriptor: vec![
+                0x06, 0xD0, 0xF1, // Usage Page (FIDO Alliance)
+                0x09, 0x01, // Usage (CTAPHID)
+                0xA1, 0x01, // Collection (Application)
+                // Input report
+                0x09, 0x20, // Usage (Input Report Data)
+                0x15, 0x00, // Logic Min
+                0x26, 0xFF, 0x00, // Logic Max
+                0x75, 0x08, // Report Size (8)
+                0x95, 0x40, // Report Count (64)
+                0x81, 0x02, // Input (Data, Variable, Absolute)
+                // Output report
+                0x09, 0x21, // Usage (Output Report Data)
+                0x15, 0x00, // Logic Min
+                0x26, 0xFF, 0x00, // Logic Max
+                0x75, 0x08, // Report Size (8)
+                0x95, 0x40, // Report Count (64)
+                0x91, 0x02, // Output (Data, Variable, Absolute)
+                0xC0, // End collection
+            ],
+            version: [0, 0, 0],
+            transaction: None,
+            last_cid: 0,
+            reports: VecDeque::new(),
+        }
+    }
+
+    pub fn endpoints() -> Vec<UsbEndpoint> {
+        vec![
+            // interrupt out
+            UsbEndpoint {
+                address: 0x01,                                   // OUT
+                attributes: EndpointAttributes::Interrupt as u8, // Interrupt
+                max_packet_size: CTAPHID_REPORT_SIZE as u16,     // 64 bytes
+                interval: 5,
+            },
+            // interrupt in
+            UsbEndpoint {
+                address: 0x81,                                   // IN
+                attributes: EndpointAttributes::Interrupt as u8, // Interrupt
+                max_packet_size: CTAPHID_REPORT_SIZE as u16,     // 64 bytes
+                interval: 5,
+            },
+        ]
+    }
+
+    /// Queue `data` as the reply to `command` on channel `cid`
+    fn reply(&mut self, cid: u32, command: u8, data: &[u8]) {
+        let mut report = cid.to_be_bytes().to_vec();
+        report.push(0x80 | command);
+        report.extend((data.len() as u16).to_be_bytes());
+        let (first, mut rest) = data.split_at(data.len().min(CTAPHID_REPORT_SIZE - 7));
+        report.extend(first);
+        report.resize(CTAPHID_REPORT_SIZE, 0);
+        self.reports.push_back(report);
+
+        let mut seq = 0;
+        while !rest.is_empty() {
+            let (chunk, remaining) = rest.split_at(rest.len().min(CTAPHID_REPORT_SIZE - 5));
+            let mut report = cid.to_be_bytes().to_vec();
+            report.push(seq);
+            report.extend(chunk);
+            report.resize(CTAPHID_REPORT_SIZE, 0);
+            self.reports.push_back(report);
+            rest = remaining;
+            seq += 1;
+        }
+    }
+
+    fn error(&mut self, cid: u32, error: u8) {
+        self.reply(cid, CTAPHID_ERROR, &[error]);
+    }
+
+    /// Handle an output report
+    fn handle_report(&mut self, report: &[u8]) {
+        if report.len() < 5 {
+            warn!("CTAPHID report too short: {:02x?}", report);
+            return;
+        }
+        let cid = u32::from_be_bytes(report[..4].try_into().unwrap());
+        if cid == 0 {
+            self.error(cid, ERR_INVALID_CHANNEL);
+            return;
+        }
+
+        if report[4] & 0x80 == 0 {
+            // continuation packet
+            let seq = report[4];
+            let Some(transaction) = self.transaction.as_mut().filter(|t| t.cid == cid) else {
+                // not for a transaction in progress, ignored
+                return;
+            };
+            if seq != transaction.seq {
+                self.transaction = None;
+                self.error(cid, ERR_INVALID_SEQ);
+                return;
+            }
+            transaction.seq += 1;
+            let remaining = transaction.length - transaction.data.len();
+            let data = &report[5..];
+            transaction
+                .data
+                .extend_from_slice(&data[..data.len().min(remaining)]);
+        } else {
+            // initialization packet
+            if report.len() < 7 {
+                warn!("CTAPHID report too short: {:02x?}", report);
+                return;
+            }
+            let command = report[4] & 0x7F;
+            let length = u16::from_be_bytes([report[5], report[6]]) as usize;
+            match &self.transaction {
+                Some(transaction) if transaction.cid != cid => {
+                    self.error(cid, ERR_CHANNEL_BUSY);
+                    return;
+                }
+                // A channel may start over, e.g. to resynchronize with INIT
+                _ => self.transaction = None,
+            }
+            if length > CTAPHID_MAX_MESSAGE_LENGTH {
+                self.error(cid, ERR_INVALID_LEN);
+                return;
+            }
+            let data = &report[7..];
+            self.transaction = Some(Transaction {
+                cid,
+                command,
+                length,
+                data: data[..data.len().min(length)].to_vec(),
+                seq: 0,
+            });
+        }
+
+        if self
+            .transaction
+            .as_ref()
+            .is_some_and(|t| t.data.len() == t.length)
+        {
+            let transaction = self.transaction.take().unwrap();
+            self.handle_message(transaction.cid, transaction.command, &transaction.data);
+        }
+    }
+
+    /// Handle a complete message
+    fn handle_message(&mut self, cid: u32, command: u8, data: &[u8]) {
+        trace!("CTAPHID command {:02x} on channel {:08x}", command, cid);
+        if cid == BROADCAST_CID && command != CTAPHID_INIT {
+            self.error(cid, ERR_INVALID_CHANNEL);
+            return;
+        }
+        match command {
+            CTAPHID_INIT => {
+                if data.len() != 8 {
+                    self.error(cid, ERR_INVALID_LEN);
+                    return;
+                }
+                let new_cid = if cid == BROADCAST_CID {
+                    // skip 0 and the broadcast channel on wrap around
+                    self.last_cid = self.last_cid.wrapping_add(1).max(1);
+                    if self.last_cid == BROADCAST_CID {
+                        self.last_cid = 1;
+                    }
+                    self.last_cid
+                } else {
+                    cid
+                };
+                let mut capabilities = CAPABILITY_WINK | CAPABILITY_CBOR;
+                if !self.backend.supports_u2f() {
+                    capabilities |= CAPABILITY_NMSG;
+                }
+                let mut response = data.to_vec();
+                response.extend(new_cid.to_be_bytes());
+                // CTAPHID protocol version
+                response.push(2);
+                response.extend(self.version);
+                response.push(capabilities);
+                self.reply(cid, CTAPHID_INIT, &response);
+            }
+            CTAPHID_PING => self.reply(cid, CTAPHID_PING, data),
+            CTAPHID_WINK => {
+                self.backend.wink();
+                self.reply(cid, CTAPHID_WINK, &[]);
+            }
+            // A single client talks to the device, nothing to lock against
+            CTAPHID_LOCK => self.reply(cid, CTAPHID_LOCK, &[]),
+            // No response, the request it cancels replies instead
+            CTAPHID_CANCEL => self.backend.cancel(),
+            CTAPHID_CBOR => match self.backend.cbor(data) {
+                Ok(response) => self.reply(cid, CTAPHID_CBOR, &response),
+                Err(err) => {
+                    warn!("Failed to handle CTAP2 request: {}", err);
+                    self.error(cid, ERR_OTHER);
+                }
+            },
+            CTAPHID_MSG if self.backend.supports_u2f() => match self.backend.msg(data) {
+                Ok(response) => self.reply(cid, CTAPHID_MSG, &response),
+                Err(err) => {
+                    warn!("Failed to handle U2F request: {}", err);
+                    self.error(cid, ERR_OTHER);
+                }
+            },
+            _ => {
+                warn!("Unsupported CTAPHID command {:02x}", command);
+                self.error(cid, ERR_INVALID_CMD);
+            }
+        }
+    }
+}
+
+impl<B: CtapBackend + 'static> UsbInterfaceHandler for UsbCtapHidHandler<B> {
+    fn handle_urb(
+        &mut self,
+        _interface: &UsbInterface,
+        ep: UsbEndpoint,
+        _transfer_buffer_length: u32,
+        setup: SetupPacket,
+        req: &[u8],
+    ) -> UrbResult {
+        if ep.is_ep0() {
+            // control transfers
+            return match (setup.request_type, setup.request) {
+                (0b10000001, 0x06) if setup.value >> 8 == HidDescriptorType::Report as u16 => {
+                    // GET_DESCRIPTOR
+                    Ok(self.report_descriptor.clone())
+                }
+                (0b00100001, 0x0A) => {
+                    // SET_IDLE
+                    Ok(vec![])
+                }
+                (0b00100001, 0x09) => {
+                    // SET_REPORT: an output report without the interrupt
+                    // endpoint
+                    self.handle_report(req);
+                    Ok(vec![])
+                }
+                _ => {
+                    warn!("Unsupported CTAPHID request {:?}", setup);
+                    Err(UrbError::Stall)
+                }
+            };
+        }
+        if let Direction::Out = ep.direction() {
+            // interrupt out
+            self.handle_report(req);
+            Ok(vec![])
+        } else {
+            // interrupt in
+            Ok(self.reports.pop_front().unwrap_or_default())
+        }
+    }
+
+    fn get_class_specific_descriptor(&self) -> Vec<u8> {
+        vec![
+            0x09,                         // bLength
+            HidDescriptorType::Hid as u8, // bDescriptorType: HID
+            0x11,
+            0x01,                            // bcdHID 1.11
+            0x00,                            // bCountryCode
+            0x01,                            // bNumDescriptors
+            HidDescriptorType::Report as u8, // bDescriptorType[0] HID
+            self.report_descriptor.len() as u8,
+            (self.report_descriptor.len() >> 8) as u8, // wDescriptorLength[0]
+        ]
+    }
+
+    fn as_any(&mut self) -> &mut dyn Any {
+        self
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::util::tests::*;
+
+    use super::*;
+
+    /// Replies to every CTAP2 request with CTAP2_OK and the request reversed
+    struct ReverseAuthenticator {
+        cancelled: bool,
+    }
+
+    impl CtapBackend for ReverseAuthenticator {
+        fn cbor(&mut self, request: &[u8]) -> Result<Vec<u8>> {
+            let mut response = vec![0x00];
+            response.extend(request.iter().rev());
+            Ok(response)
+        }
+
+        fn cancel(&mut self) {
+            self.cancelled = true;
+        }
+    }
+
+    fn reports(cid: u32, command: u8, data: &[u8]) -> Vec<Vec<u8>> {
+        let mut handler = UsbCtapHidHandler::new(ReverseAuthenticator { cancelled: false });
+        handler.reply(cid, command, data);
+        handler.reports.into()
+    }
+
+    struct Device {
+        handler: UsbCtapHidHandler<ReverseAuthenticator>,
+        device: UsbDevice,
+    }
+
+    impl Device {
+        fn new() -> Self {
+            let handler = UsbCtapHidHandler::new(ReverseAuthenticator { cancelled: false });
+            let device = UsbDevice::new(0).with_interface(
+                ClassCode::HID as u8,
+                0x00,
+                0x00,
+                "Test CTAPHID",
+                UsbCtapHidHandler::<ReverseAuthenticator>::endpoints(),
+                Arc::new(Mutex::new(
+                    Box::new(UsbCtapHidHandler::new(ReverseAuthenticator {
+                        cancelled: false,
+                    })) as Box<dyn UsbInterfaceHandler + Send>,
+                )),
+            );
+            Self { handler, device }
+        }
+
+        fn send(&mut self, reports: Vec<Vec<u8>>) {
+            let endpoints = UsbCtapHidHandler::<ReverseAuthenticator>::endpoints();
+            for report in reports {
+                self.handler
+                    .handle_urb(
+                        &self.device.interfaces[0],
+                        endpoints[0],
+                        0,
+                        SetupPacket::default(),
+                        &report,
+                    )
+                    .unwrap();
+            }
+        }
+
+        fn receive(&mut self) -> Vec<Vec<u8>> {
+            let endpoints = UsbCtapHidHandler::<ReverseAuthenticator>::endpoints();
+            let mut reports = vec![];
+            loop {
+                let report = self
+                    .handler
+                    .handle_urb(
+                        &self.device.interfaces[0],
+                        endpoints[1],
+                        64,
+                        SetupPacket::default(),
+                        &[],
+                    )
+                    .unwrap();
+                if report.is_empty() {
+                    return reports;
+                }
+                reports.push(report);
+            }
+        }
+    }
+
+    #[test]
+    fn desc_verify() {
+        setup_test_logger();
+        let handler = UsbCtapHidHandler::new(ReverseAuthenticator { cancelled: false });
+        verify_descriptor(&handler.get_class_specific_descriptor());
+    }
+
+    #[test]
+    fn framing() {
+        let data: Vec<u8> = (0..200).map(|i| i as u8).collect();
+        let reports = reports(0x01020304, CTAPHID_CBOR, &data);
+        // 57 + 59 + 59 + 25
+        assert_eq!(reports.len(), 4);
+        assert!(reports.iter().all(|report| report.len() == 64));
+        assert_eq!(reports[0][..7], [1, 2, 3, 4, 0x90, 0, 200]);
+        assert_eq!(reports[0][7..], data[..57]);
+        assert_eq!(reports[1][..5], [1, 2, 3, 4, 0]);
+        assert_eq!(reports[3][4], 2);
+        assert_eq!(reports[3][5..30], data[175..]);
+        assert!(reports[3][30..].iter().all(|byte| *byte == 0));
+    }
+
+    #[test]
+    fn init_and_cbor() {
+        setup_test_logger();
+        let mut device = Device::new();
+
+        // Allocate a channel
+        let nonce = [1, 2, 3, 4, 5, 6, 7, 8];
+        device.send(reports(BROADCAST_CID, CTAPHID_INIT, &nonce));
+        let reply = device.receive();
+        assert_eq!(reply.len(), 1);
+        assert_eq!(reply[0][..7], [0xFF, 0xFF, 0xFF, 0xFF, 0x86, 0, 17]);
+        assert_eq!(reply[0][7..15], nonce);
+        let cid = u32::from_be_bytes(reply[0][15..19].try_into().unwrap());
+        assert_ne!(cid, 0);
+        assert_ne!(cid, BROADCAST_CID);
+        assert_eq!(reply[0][19], 2);
+        assert_eq!(
+            reply[0][23],
+            CAPABILITY_WINK | CAPABILITY_CBOR | CAPABILITY_NMSG
+        );
+
+        // A request split over several reports
+        let request: Vec<u8> = (0..100).collect();
+        device.send(reports(cid, CTAPHID_CBOR, &request));
+        let mut response = vec![0x00];
+        response.extend(request.iter().rev());
+        assert_eq!(device.receive(), reports(cid, CTAPHID_CBOR, &response));
+
+        // Other channels wait for the transaction in progress
+        let other = reports(cid + 1, CTAPHID_PING, &[0; 2]);
+        let partial = reports(cid, CTAPHID_PING, &request);
+        device.send(vec![partial[0].clone()]);
+        device.send(other);
+        assert_eq!(
+            device.receive(),
+            reports(cid + 1, CTAPHID_ERROR, &[ERR_CHANNEL_BUSY])
+        );
+        device.send(partial[1..].to_vec());
+        assert_eq!(device.receive(), reports(cid, CTAPHID_PING, &request));
+
+        // Out of order continuation packets
+        let long: Vec<u8> = (0..200).map(|i| i as u8).collect();
+        let ping = reports(cid, CTAPHID_PING, &long);
+        device.send(vec![ping[0].clone(), ping[2].clone()]);
+        assert_eq!(
+            device.receive(),
+            reports(cid, CTAPHID_ERROR, &[ERR_INVALID_SEQ])
+        );
+
+        // U2F isn't supported by the backend
+        device.send(reports(cid, CTAPHID_MSG, &[0x00, 0x03, 0x00, 0x00]));
+        assert_eq!(
+            device.receive(),
+            reports(cid, CTAPHID_ERROR, &[ERR_INVALID_CMD])
+        );
+
+        device.send(reports(cid, CTAPHID_CANCEL, &[]));
+        assert!(device.receive().is_empty());
+        assert!(device.handler.backend.cancelled);
+    }
+}
@@ -1,4 +1,7 @@
 use super::*;
+use crate::wire::{
+    ConfigurationDescriptor, DeviceDescriptor, EndpointDescriptor, InterfaceDescriptor,
+};
 use rusb::Version as rusbVersion;
 
 #[derive(Clone, Default)]
@@ -57,13 +60,17 @@ pub struct UsbDevice {
     pub(crate) string_manufacturer: u8,
     pub(crate) string_product: u8,
     pub(crate) string_serial: u8,
+
+    /// Endpoints which stalled, and fail their URBs until the halt is cleared
+    #[cfg_attr(feature = "serde", serde(skip))]
+    pub(crate) halted: Arc<Mutex<HashSet<u8>>>,
 }
 
 impl UsbDevice {
     pub fn new(index: u32) -> Self {
         let mut res = Self {
             path: "/sys/bus/0/0/0".to_string(),
-            bus_id: "0-0-0".to_string(),
+            bus_id: format!("0-0-{}", index),
             dev_num: index,
             speed: UsbSpeed::High as u32,
             ep0_in: UsbEndpoint {
@@ -102,6 +109,21 @@ impl UsbDevice {
             .insert(self.string_serial, name.to_string())
     }
 
+    /// Tell the handlers of the interfaces that the client let go of the
+    /// device, see [UsbInterfaceHandler::disconnected]
+    pub(crate) fn disconnected(&self) {
+        for intf in &self.interfaces {
+            intf.handler.lock().unwrap().disconnected();
+        }
+    }
+
+    /// The serial number string, if the device has one
+    pub fn serial_number(&self) -> Option<&str> {
+        self.string_pool
+            .get(&self.string_serial)
+            .map(String::as_str)
+    }
+
     /// Returns the old value, if present.
     pub fn set_product_name(&mut self, name: &str) -> Option<String> {
         self.string_pool
@@ -147,8 +169,8 @@ impl UsbDevice {
 
     pub(crate) fn new_string(&mut self, s: &str) -> u8 {
         for i in 1.. {
-            if self.string_pool.get(&i).is_none() {
-                self.string_pool.insert(i, s.to_string());
+            if let std::collections::hash_map::Entry::Vacant(e) = self.string_pool.entry(i) {
+                e.insert(s.to_string());
                 return i;
             }
         }
@@ -216,14 +238,18 @@ impl UsbDevice {
         result
     }
 
-    pub(crate) async fn handle_urb(
+    /// Handle a URB to `ep`
+    ///
+    /// This calls into the handlers, which may block, so the server runs it on
+    /// the blocking thread pool.
+    pub(crate) fn handle_urb(
         &self,
         ep: UsbEndpoint,
         intf: Option<&UsbInterface>,
         transfer_buffer_length: u32,
         setup_packet: SetupPacket,
         out_data: &[u8],
-    ) -> Result<Vec<u8>> {
+    ) -> UrbResult {
         use DescriptorType::*;
         use Direction::*;
         use EndpointAttributes::*;
@@ -242,26 +268,24 @@ impl UsbDevice {
                         match FromPrimitive::from_u16(setup_packet.value >> 8) {
                             Some(Device) => {
                                 debug!("Get device descriptor");
-                                let mut desc = vec![
-                                    0x12,         // bLength
-                                    Device as u8, // bDescriptorType: Device
-                                    self.usb_version.minor,
-                                    self.usb_version.major, // bcdUSB: USB 2.0
-                                    self.device_class,      // bDeviceClass
-                                    self.device_subclass,   // bDeviceSubClass
-                                    self.device_protocol,   // bDeviceProtocol
-                                    self.ep0_in.max_packet_size as u8, // bMaxPacketSize0
-                                    self.vendor_id as u8,   // idVendor
-                                    (self.vendor_id >> 8) as u8,
-                                    self.product_id as u8, // idProduct
-                                    (self.product_id >> 8) as u8,
-                                    self.device_bcd.minor, // bcdDevice
-                                    self.device_bcd.major,
-                                    self.string_manufacturer, // iManufacturer
-                                    self.string_product,      // iProduct
-                                    self.string_serial,       // iSerial
-                                    self.num_configurations,
-                                ];
+                                let mut desc = DeviceDescriptor {
+                                    usb_version: (self.usb_version.major as u16) << 8
+                                        | self.usb_version.minor as u16,
+                                    device_class: self.device_class,
+                                    device_subclass: self.device_subclass,
+                                    device_protocol: self.device_protocol,
+                                    max_packet_size0: self.ep0_in.max_packet_size as u8,
+                                    vendor_id: self.vendor_id,
+                                    product_id: self.product_id,
+                                    device_version: (self.device_bcd.major as u16) << 8
+                                        | self.device_bcd.minor as u16,
+                                    manufacturer_string: self.string_manufacturer,
+                                    product_string: self.string_product,
+                                    serial_string: self.string_serial,
+                                    num_configurations: self.num_configurations,
+                                }
+                                .to_bytes()
+                                .to_vec();
 
                                 // requested len too short: wLength < real length
                                 if setup_packet.length < desc.len() as u16 {
@@ -286,46 +310,43 @@ impl UsbDevice {
                             }
                             Some(Configuration) => {
                                 debug!("Get configuration descriptor");
-                                let mut desc = vec![
-                                    0x09,                // bLength
-                                    Configuration as u8, // bDescriptorType: Configuration
-                                    0x00,
-                                    0x00, // wTotalLength: to be filled below
-                                    self.interfaces.len() as u8, // bNumInterfaces
-                                    self.configuration_value, // bConfigurationValue
-                                    self.string_configuration, // iConfiguration
-                                    0x80, // bmAttributes Bus Powered
-                                    0x32, // bMaxPower 100mA
-                                ];
+                                let mut desc = ConfigurationDescriptor {
+                                    total_length: 0, // to be filled below
+                                    num_interfaces: self.interfaces.len() as u8,
+                                    configuration_value: self.configuration_value,
+                                    configuration_string: self.string_configuration,
+                                    attributes: 0x80, // Bus Powered
+                                    max_power: 0x32,  // 100mA
+                                }
+                                .to_bytes()
+                                .to_vec();
                                 for (i, intf) in self.interfaces.iter().enumerate() {
-                                    let mut intf_desc = vec![
-                                        0x09,                       // bLength
-                                        Interface as u8,            // bDescriptorType: Interface
-                                        i as u8,                    // bInterfaceNum
-                                        0x00,                       // bAlternateSettings
-                                        intf.endpoints.len() as u8, // bNumEndpoints
-                                        intf.interface_class,       // bInterfaceClass
-                                        intf.interface_subclass,    // bInterfaceSubClass
-                                        intf.interface_protocol,    // bInterfaceProtocol
-                                        intf.string_interface,      //iInterface
-                                    ];
+                                    desc.extend_from_slice(
+                                        &InterfaceDescriptor {
+                                            interface_number: i as u8,
+                                            alternate_setting: 0,
+                                            num_endpoints: intf.endpoints.len() as u8,
+                                            interface_class: intf.interface_class,
+                                            interface_subclass: intf.interface_subclass,
+                                            interface_protocol: intf.interface_protocol,
+                                            interface_string: intf.string_interface,
+                                        }
+                                        .to_bytes(),
+                                    );
                                     // class specific endpoint
-                                    let mut specific = intf.class_specific_descriptor.clone();
-                                    intf_desc.append(&mut specific);
+                                    desc.extend_from_slice(&intf.class_specific_descriptor);
                                     // endpoint descriptors
                                     for endpoint in &intf.endpoints {
-                                        let mut ep_desc = vec![
-                                            0x07,                // bLength
-                                            Endpoint as u8,      // bDescriptorType: Endpoint
-                                            endpoint.address,    // bEndpointAddress
-                                            endpoint.attributes, // bmAttributes
-                                            endpoint.max_packet_size as u8,
-                                            (endpoint.max_packet_size >> 8) as u8, // wMaxPacketSize
-                                            endpoint.interval,                     // bInterval
-                                        ];
-                                        intf_desc.append(&mut ep_desc);
+                                        desc.extend_from_slice(
+                                            &EndpointDescriptor {
+                                                address: endpoint.address,
+                                                attributes: endpoint.attributes,
+                                                max_packet_size: endpoint.max_packet_size,
+                                                interval: endpoint.interval,
+                                            }
+                                            .to_bytes(),
+                                        );
                                     }
-                                    desc.append(&mut intf_desc);
                                 }
                                 // length
                                 let len = desc.len() as u16;
@@ -371,10 +392,8 @@ impl UsbDevice {
                                     }
                                     Ok(desc)
                                 } else {
-                                    Err(std::io::Error::new(
-                                        std::io::ErrorKind::InvalidInput,
-                                        format!("Invalid string index: {}", index),
-                                    ))
+                                    warn!("Invalid string index: {}", index);
+                                    Err(UrbError::Stall)
                                 }
                             }
                             Some(DeviceQualifier) => {
@@ -409,8 +428,16 @@ impl UsbDevice {
                         // see https://www.beyondlogic.org/usbnutshell/usb6.shtml
                         // only low 8 bits are valid
                         let intf = &self.interfaces[setup_packet.index as usize & 0xFF];
-                        let mut handler = intf.handler.lock().unwrap();
-                        handler.handle_urb(intf, ep, transfer_buffer_length, setup_packet, out_data)
+                        intf.handle_urb(ep, transfer_buffer_length, setup_packet, out_data)
+                    }
+                    (0b10000010, Some(GetStatus)) => {
+                        // to endpoint: whether it is halted
+                        let halted = self
+                            .halted
+                            .lock()
+                            .unwrap()
+                            .contains(&(setup_packet.index as u8));
+                        Ok(vec![halted as u8, 0])
                     }
                     _ if setup_packet.request_type & 0xF == 0 && self.device_handler.is_some() => {
                         // to device
@@ -419,7 +446,10 @@ impl UsbDevice {
                         let mut handler = lock.lock().unwrap();
                         handler.handle_urb(transfer_buffer_length, setup_packet, out_data)
                     }
-                    _ => unimplemented!("control in"),
+                    _ => {
+                        warn!("Unsupported control IN request {:x?}", setup_packet);
+                        Err(UrbError::Stall)
+                    }
                 }
             }
             (Some(Control), Out) => {
@@ -445,8 +475,26 @@ impl UsbDevice {
                         // see https://www.beyondlogic.org/usbnutshell/usb6.shtml
                         // only low 8 bits are valid
                         let intf = &self.interfaces[setup_packet.index as usize & 0xFF];
-                        let mut handler = intf.handler.lock().unwrap();
-                        handler.handle_urb(intf, ep, transfer_buffer_length, setup_packet, out_data)
+                        intf.handle_urb(ep, transfer_buffer_length, setup_packet, out_data)
+                    }
+                    (0b00000010, Some(request @ (ClearFeature | SetFeature)))
+                        if setup_packet.value == ENDPOINT_HALT =>
+                    {
+                        // to endpoint
+                        let address = setup_packet.index as u8;
+                        let Some((ep, Some(intf))) = self.find_ep(address) else {
+                            warn!("Endpoint {:02x?} not found", address);
+                            return Err(UrbError::Stall);
+                        };
+                        let mut halted = self.halted.lock().unwrap();
+                        if let ClearFeature = request {
+                            debug!("Clear halt of endpoint {:02x?}", address);
+                            intf.clear_halt(ep)?;
+                            halted.remove(&address);
+                        } else {
+                            halted.insert(address);
+                        }
+                        Ok(vec![])
                     }
                     _ if setup_packet.request_type & 0xF == 0 && self.device_handler.is_some() => {
                         // to device
@@ -455,16 +503,29 @@ impl UsbDevice {
                         let mut handler = lock.lock().unwrap();
                         handler.handle_urb(transfer_buffer_length, setup_packet, out_data)
                     }
-                    _ => unimplemented!("control out"),
+                    _ => {
+                        warn!("Unsupported control OUT request {:x?}", setup_packet);
+                        Err(UrbError::Stall)
+                    }
                 }
             }
             (Some(_), _) => {
                 // others
+                if self.halted.lock().unwrap().contains(&ep.address) {
+                    return Err(UrbError::Stall);
+                }
                 let intf = intf.unwrap();
-                let mut handler = intf.handler.lock().unwrap();
-                handler.handle_urb(intf, ep, transfer_buffer_length, setup_packet, out_data)
+                let res = intf.handle_urb(ep, transfer_buffer_length, setup_packet, out_data);
+                if let Err(UrbError::Stall) = res {
+                    debug!("Endpoint {:02x?} halted", ep.address);
+                    self.halted.lock().unwrap().insert(ep.address);
+                }
+                res
+            }
+            _ => {
+                warn!("Unsupported transfer to {:?}", ep);
+                Err(UrbError::Stall)
             }
-            _ => unimplemented!("transfer to {:?}", ep),
         }
     }
 }
@@ -480,7 +541,7 @@ pub trait UsbDeviceHandler {
         transfer_buffer_length: u32,
         setup: SetupPacket,
         req: &[u8],
-    ) -> Result<Vec<u8>>;
+    ) -> UrbResult;
 
     /// Helper to downcast to actual struct
     ///
@@ -519,32 +580,131 @@ mod test {
         assert_eq!(device.string_pool[&4], "test");
     }
 
-    #[tokio::test]
-    async fn test_invalid_string_index() {
+    #[test]
+    fn test_invalid_string_index() {
         setup_test_logger();
         let device = UsbDevice::new(0);
-        let res = device
-            .handle_urb(
-                UsbEndpoint {
-                    address: 0x80, // IN
-                    attributes: EndpointAttributes::Control as u8,
-                    max_packet_size: EP0_MAX_PACKET_SIZE,
-                    interval: 0,
-                },
-                None,
-                0,
-                SetupPacket {
-                    request_type: 0b10000000,
-                    request: StandardRequest::GetDescriptor as u8,
-                    // string pool only contains 4 strings, 5 should be invalid
-                    value: (DescriptorType::String as u16) << 8 | 5,
-                    index: 0,
-                    length: 0,
-                },
-                &[],
-            )
-            .await;
+        let res = device.handle_urb(
+            UsbEndpoint {
+                address: 0x80, // IN
+                attributes: EndpointAttributes::Control as u8,
+                max_packet_size: EP0_MAX_PACKET_SIZE,
+                interval: 0,
+            },
+            None,
+            0,
+            SetupPacket {
+                request_type: 0b10000000,
+                request: StandardRequest::GetDescriptor as u8,
+                // string pool only contains 4 strings, 5 should be invalid
+                value: (DescriptorType::String as u16) << 8 | 5,
+                index: 0,
+                length: 0,
+            },
+            &[],
+        );
 
         assert!(res.is_err());
     }
+
+    /// Stalls bulk transfers while `stall` is set
+    struct StallHandler {
+        stall: bool,
+        halt_cleared: bool,
+    }
+
+    impl UsbInterfaceHandler for StallHandler {
+        fn get_class_specific_descriptor(&self) -> Vec<u8> {
+            vec![]
+        }
+
+        fn handle_urb(
+            &mut self,
+            _interface: &UsbInterface,
+            _ep: UsbEndpoint,
+            _transfer_buffer_length: u32,
+            _setup: SetupPacket,
+            _req: &[u8],
+        ) -> UrbResult {
+            if self.stall {
+                Err(UrbError::Stall)
+            } else {
+                Ok(vec![1])
+            }
+        }
+
+        fn clear_halt(&mut self, _ep: UsbEndpoint) -> UrbResult<()> {
+            self.halt_cleared = true;
+            Ok(())
+        }
+
+        fn as_any(&mut self) -> &mut dyn Any {
+            self
+        }
+    }
+
+    #[test]
+    fn test_stall_and_clear_halt() {
+        setup_test_logger();
+        let bulk_in = UsbEndpoint {
+            address: 0x81,
+            attributes: EndpointAttributes::Bulk as u8,
+            max_packet_size: 64,
+            interval: 0,
+        };
+        let handler = Arc::new(Mutex::new(Box::new(StallHandler {
+            stall: true,
+            halt_cleared: false,
+        })
+            as Box<dyn UsbInterfaceHandler + Send>));
+        let device =
+            UsbDevice::new(0).with_interface(0xFF, 0, 0, "Test", vec![bulk_in], handler.clone());
+        let intf = Some(&device.interfaces[0]);
+        let control = |request_type, request, length| {
+            let ep = if request_type & 0x80 != 0 {
+                device.ep0_in
+            } else {
+                device.ep0_out
+            };
+            let setup = SetupPacket {
+                request_type,
+                request: request as u8,
+                value: ENDPOINT_HALT,
+                index: bulk_in.address as u16,
+                length,
+            };
+            device.handle_urb(ep, None, length as u32, setup, &[])
+        };
+        let stall = |handler: &Arc<Mutex<Box<dyn UsbInterfaceHandler + Send>>>, stall| {
+            let mut handler = handler.lock().unwrap();
+            let handler = handler.as_any().downcast_mut::<StallHandler>().unwrap();
+            handler.stall = stall;
+            handler.halt_cleared
+        };
+
+        let res = device.handle_urb(bulk_in, intf, 1, SetupPacket::default(), &[]);
+        assert_eq!(res.unwrap_err().status(), -32);
+        assert_eq!(
+            control(0b10000010, StandardRequest::GetStatus, 2).unwrap(),
+            [1, 0]
+        );
+
+        // The endpoint stays halted even once the handler recovers
+        stall(&handler, false);
+        let res = device.handle_urb(bulk_in, intf, 1, SetupPacket::default(), &[]);
+        assert!(matches!(res, Err(UrbError::Stall)));
+
+        control(0b00000010, StandardRequest::ClearFeature, 0).unwrap();
+        assert!(stall(&handler, false));
+        assert_eq!(
+            control(0b10000010, StandardRequest::GetStatus, 2).unwrap(),
+            [0, 0]
+        );
+        let res = device.handle_urb(bulk_in, intf, 1, SetupPacket::default(), &[]);
+        assert_eq!(res.unwrap(), [1]);
+
+        // Unknown requests stall ep0
+        let res = control(0b00000010, StandardRequest::SetAddress, 0);
+        assert!(matches!(res, Err(UrbError::Stall)));
+    }
 }
@@ -0,0 +1,463 @@
+//! Out-of-band events
+//!
+//! USB/IP only carries URBs, so whoever uses an imported device can't tell
+//! that it waits for the user, e.g. a YubiKey waiting for a touch behind a
+//! kernel attached with [crate::client::attach_vhci], and whoever owns it
+//! can't tell that it is in use. The server watches the imports and the
+//! replies of the devices it exports, and tells clients that subscribe on a
+//! connection of their own, by sending [SUBSCRIBE_REQUEST] (after their token,
+//! if any) instead of a USB/IP request. The server acknowledges with a line
+//! `ok`, then writes one line per [Event]:
+//!
+//! ```text
+//! import 1-2 12345678
+//! touch 1-2 12345678
+//! touch 1-3 -
+//! approve 7 1-2 12345678
+//! ```
+//!
+//! Clients only see the events of devices the [crate::acl::Policy] lets them
+//! import.
+use super::*;
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncWrite};
+use tokio::sync::broadcast;
+
+/// Sent by a client to subscribe to events, instead of a USB/IP request
+///
+/// Its first byte tells it apart from a token and from USB/IP requests.
+pub const SUBSCRIBE_REQUEST: &[u8] = b"EVENTS\n";
+
+const SUBSCRIBED: &[u8] = b"ok\n";
+
+/// Events queued per subscriber before it misses some
+pub(crate) const EVENT_QUEUE_LENGTH: usize = 16;
+
+/// Something a user should know about an exported device
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Event {
+    /// A client imported the device, and starts using it
+    Imported {
+        bus_id: String,
+        /// The serial number string of the device, if it exposes it
+        serial: Option<String>,
+    },
+    /// The smart card in the device asked for more time to answer, which
+    /// YubiKeys do while they wait for a touch
+    TouchRequired {
+        bus_id: String,
+        /// The serial number string of the device, which for a YubiKey is
+        /// its serial, if it exposes it
+        serial: Option<String>,
+    },
+    /// A client wants to use a key of the smart card in the device, which
+    /// waits for approval on the host, see [crate::approval]
+    ApprovalRequired {
+        /// What to approve or deny, see [crate::UsbIpServer::answer_approval]
+        id: u64,
+        bus_id: String,
+        serial: Option<String>,
+    },
+}
+
+impl Event {
+    fn touch_required(device: &UsbDevice) -> Self {
+        Event::TouchRequired {
+            bus_id: device.bus_id.clone(),
+            serial: device.serial_number().map(str::to_string),
+        }
+    }
+
+    fn bus_id(&self) -> &str {
+        match self {
+            Event::Imported { bus_id, .. }
+            | Event::TouchRequired { bus_id, .. }
+            | Event::ApprovalRequired { bus_id, .. } => bus_id,
+        }
+    }
+
+    /// The line sent to subscribers, without its newline
+    pub fn to_line(&self) -> String {
+        match self {
+            Event::Imported { bus_id, serial } => {
+                format!("import {} {}", bus_id, serial.as_deref().unwrap_or("-"))
+            }
+            Event::TouchRequired { bus_id, serial } => {
+                format!("touch {} {}", bus_id, serial.as_deref().unwrap_or("-"))
+            }
+            Event::ApprovalRequired { id, bus_id, serial } => format!(
+                "approve {} {} {}",
+                id,
+                bus_id,
+                serial.as_deref().unwrap_or("-")
+            ),
+        }
+    }
+
+    /// Parse a line sent to subscribers, or `None` for one of an unknown event
+    pub fn parse(line: &str) -> Option<Self> {
+        match line.split_whitespace().collect::<Vec<_>>()[..] {
+            ["import", bus_id, serial] => Some(Event::Imported {
+                bus_id: bus_id.to_string(),
+                serial: (serial != "-").then(|| serial.to_string()),
+            }),
+            ["touch", bus_id, serial] => Some(Event::TouchRequired {
+                bus_id: bus_id.to_string(),
+                serial: (serial != "-").then(|| serial.to_string()),
+            }),
+            ["approve", id, bus_id, serial] => Some(Event::ApprovalRequired {
+                id: id.parse().ok()?,
+                bus_id: bus_id.to_string(),
+                serial: (serial != "-").then(|| serial.to_string()),
+            }),
+            _ => None,
+        }
+    }
+}
+
+impl std::fmt::Display for Event {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Event::Imported {
+                bus_id,
+                serial: Some(serial),
+            } => write!(f, "Device #{} ({}) is in use", serial, bus_id),
+            Event::Imported { bus_id, .. } => write!(f, "Device {} is in use", bus_id),
+            Event::TouchRequired {
+                bus_id,
+                serial: Some(serial),
+            } => write!(f, "Touch required on device #{} ({})", serial, bus_id),
+            Event::TouchRequired { bus_id, .. } => write!(f, "Touch required on {}", bus_id),
+            Event::ApprovalRequired {
+                id,
+                bus_id,
+                serial: Some(serial),
+            } => write!(
+                f,
+                "Approval {} required for key use on device #{} ({})",
+                id, serial, bus_id
+            ),
+            Event::ApprovalRequired { id, bus_id, .. } => {
+                write!(f, "Approval {} required for key use on {}", id, bus_id)
+            }
+        }
+    }
+}
+
+/// Whether `res` answers a URB to the bulk IN endpoint of a smart card
+/// reader: `Some(true)` if the card asked for more time, `Some(false)` if it
+/// answered
+pub(crate) fn ccid_waiting(
+    device: &UsbDevice,
+    header: &UsbIpHeaderBasic,
+    res: &UsbIpResponse,
+) -> Option<bool> {
+    if header.direction == 0 {
+        return None;
+    }
+    let (ep, intf) = device.find_ep(header.ep as u8 | 0x80)?;
+    if intf?.interface_class != ClassCode::SmartCard as u8
+        || ep.attributes != EndpointAttributes::Bulk as u8
+    {
+        return None;
+    }
+    match res {
+        UsbIpResponse::UsbIpRetSubmit {
+            status: 0,
+            transfer_buffer,
+            ..
+        } if !transfer_buffer.is_empty() => Some(ccid::is_time_extension(transfer_buffer)),
+        _ => None,
+    }
+}
+
+/// Follows the replies of one endpoint of an imported device, publishing an
+/// event when it starts waiting for the user
+#[derive(Default)]
+pub(crate) struct WaitWatcher {
+    waiting: bool,
+}
+
+impl WaitWatcher {
+    /// Returns whether the device just started waiting
+    pub(crate) fn observe(
+        &mut self,
+        device: &UsbDevice,
+        header: &UsbIpHeaderBasic,
+        res: &UsbIpResponse,
+        events: &broadcast::Sender<Event>,
+    ) -> bool {
+        let Some(waiting) = ccid_waiting(device, header, res) else {
+            return false;
+        };
+        // Cards ask again every few hundred milliseconds
+        let started = waiting && !self.waiting;
+        if started {
+            info!("Device {} is waiting for a touch", device.bus_id);
+            events.send(Event::touch_required(device)).ok();
+        }
+        self.waiting = waiting;
+        started
+    }
+}
+
+/// Read the subscription a client may send instead of its first request
+pub(crate) async fn read_subscribe<T: AsyncBufRead + Unpin>(socket: &mut T) -> Result<bool> {
+    if !socket
+        .fill_buf()
+        .await?
+        .starts_with(&SUBSCRIBE_REQUEST[..1])
+    {
+        return Ok(false);
+    }
+    let mut line = vec![];
+    tokio::io::AsyncReadExt::take(&mut *socket, SUBSCRIBE_REQUEST.len() as u64)
+        .read_until(b'\n', &mut line)
+        .await?;
+    if line != SUBSCRIBE_REQUEST {
+        return Err(std::io::Error::new(
+            ErrorKind::InvalidData,
+            "Invalid request",
+        ));
+    }
+    Ok(true)
+}
+
+/// Send the events `peer` may see over `socket`, until it is closed or
+/// `shutdown` is set
+pub(crate) async fn serve_subscriber<T: AsyncReadExt + AsyncWriteExt + Unpin>(
+    socket: &mut T,
+    server: &UsbIpServer,
+    peer: &acl::Peer,
+    mut shutdown: Option<watch::Receiver<bool>>,
+) -> Result<()> {
+    let mut events = server.events.subscribe();
+    socket.write_all(SUBSCRIBED).await?;
+    let mut buf = [0; 64];
+    loop {
+        let event = tokio::select! {
+            event = events.recv() => event,
+            // Subscribers only listen; this notices when they go away
+            read = socket.read(&mut buf) => match read? {
+                0 => return Ok(()),
+                _ => continue,
+            },
+            _ = shutdown_requested(&mut shutdown) => return Ok(()),
+        };
+        let event = match event {
+            Ok(event) => event,
+            Err(broadcast::error::RecvError::Lagged(missed)) => {
+                warn!("Subscriber {:?} missed {} events", peer.addr, missed);
+                continue;
+            }
+            Err(broadcast::error::RecvError::Closed) => return Ok(()),
+        };
+        if may_see(server, peer, event.bus_id()).await {
+            let mut line = event.to_line();
+            line.push('\n');
+            socket.write_all(line.as_bytes()).await?;
+        }
+    }
+}
+
+/// Whether `peer` may know about the device with `bus_id`, which is in use
+async fn may_see(server: &UsbIpServer, peer: &acl::Peer, bus_id: &str) -> bool {
+    let Some(policy) = &server.policy else {
+        return true;
+    };
+    server
+        .devices
+        .imported(bus_id)
+        .await
+        .is_some_and(|device| policy.allows(peer, &device))
+}
+
+/// Subscribe to the events of the server on the other end of `socket`
+///
+/// Must be sent instead of the first request, after the token if there is
+/// one. Afterwards the socket only carries events, see [next_event].
+pub async fn subscribe<T: AsyncBufRead + AsyncWrite + Unpin>(socket: &mut T) -> Result<()> {
+    socket.write_all(SUBSCRIBE_REQUEST).await?;
+    let mut line = vec![];
+    socket.read_until(b'\n', &mut line).await?;
+    if line != SUBSCRIBED {
+        return Err(std::io::Error::new(
+            ErrorKind::InvalidData,
+            "Server doesn't send events",
+        ));
+    }
+    Ok(())
+}
+
+/// Wait for the next event on a `socket` that [subscribe]d, or `None` once
+/// the server closed it
+pub async fn next_event<T: AsyncBufRead + Unpin>(socket: &mut T) -> Result<Option<Event>> {
+    loop {
+        let mut line = String::new();
+        if socket.read_line(&mut line).await? == 0 {
+            return Ok(None);
+        }
+        match Event::parse(&line) {
+            Some(event) => return Ok(Some(event)),
+            None => debug!("Ignoring unknown event {:?}", line.trim_end()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use crate::util::tests::*;
+
+    use super::*;
+
+    /// A reader whose card asks for more time twice before each answer
+    struct SlowCard {
+        replies: u8,
+    }
+
+    impl UsbInterfaceHandler for SlowCard {
+        fn get_class_specific_descriptor(&self) -> Vec<u8> {
+            vec![]
+        }
+
+        fn handle_urb(
+            &mut self,
+            _: &UsbInterface,
+            ep: UsbEndpoint,
+            _: u32,
+            _: SetupPacket,
+            _: &[u8],
+        ) -> UrbResult {
+            if ep.direction() == Direction::Out {
+                return Ok(vec![]);
+            }
+            self.replies += 1;
+            let status = if self.replies.is_multiple_of(3) {
+                0
+            } else {
+                0x80
+            };
+            Ok(vec![0x80, 0, 0, 0, 0, 0, 0, status, 1, 0])
+        }
+
+        fn as_any(&mut self) -> &mut dyn Any {
+            self
+        }
+    }
+
+    fn slow_card() -> UsbDevice {
+        let mut device = UsbDevice::new(0).with_interface(
+            ClassCode::SmartCard as u8,
+            ccid::CCID_SUBCLASS,
+            0x00,
+            "Slow card",
+            vec![
+                UsbEndpoint {
+                    address: 0x81,
+                    attributes: EndpointAttributes::Bulk as u8,
+                    max_packet_size: 64,
+                    interval: 0,
+                },
+                UsbEndpoint {
+                    address: 0x02,
+                    attributes: EndpointAttributes::Bulk as u8,
+                    max_packet_size: 64,
+                    interval: 0,
+                },
+            ],
+            Arc::new(Mutex::new(
+                Box::new(SlowCard { replies: 0 }) as Box<dyn UsbInterfaceHandler + Send>
+            )),
+        );
+        device.set_serial_number("12345678");
+        device
+    }
+
+    #[test]
+    fn lines() {
+        let event = Event::TouchRequired {
+            bus_id: "1-2".to_string(),
+            serial: None,
+        };
+        assert_eq!(event.to_line(), "touch 1-2 -");
+        assert_eq!(Event::parse("touch 1-2 -\n"), Some(event));
+        assert_eq!(
+            Event::parse("touch 1-2 12345678").unwrap().to_string(),
+            "Touch required on device #12345678 (1-2)"
+        );
+        assert_eq!(
+            Event::parse("approve 7 1-2 -").unwrap().to_string(),
+            "Approval 7 required for key use on 1-2"
+        );
+        assert_eq!(Event::parse("approve x 1-2 -"), None);
+        assert_eq!(
+            Event::parse("import 1-2 12345678").unwrap().to_string(),
+            "Device #12345678 (1-2) is in use"
+        );
+        assert_eq!(Event::parse("wink 1-2"), None);
+    }
+
+    #[tokio::test]
+    async fn touch_events() {
+        setup_test_logger();
+        let addr = get_free_address().await;
+        let server = Arc::new(UsbIpServer::new_simulated(vec![slow_card()]));
+        tokio::spawn(crate::server(addr, server));
+
+        let mut events = tokio::io::BufReader::new(poll_connect(addr).await);
+        subscribe(&mut events).await.unwrap();
+
+        let mut socket = poll_connect(addr).await;
+        let device = client::import(&mut socket, "0-0-0").await.unwrap();
+        let device = client::ImportedDevice::new(socket, device);
+        for _ in 0..6 {
+            device.transfer_in(1, 64).await.unwrap();
+        }
+        assert_eq!(
+            next_event(&mut events).await.unwrap(),
+            Some(Event::Imported {
+                bus_id: "0-0-0".to_string(),
+                serial: Some("12345678".to_string()),
+            })
+        );
+        // Once per answer, however often the card asks for more time
+        for _ in 0..2 {
+            assert_eq!(
+                next_event(&mut events).await.unwrap(),
+                Some(Event::TouchRequired {
+                    bus_id: "0-0-0".to_string(),
+                    serial: Some("12345678".to_string()),
+                })
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn policy() {
+        setup_test_logger();
+        let addr = get_free_address().await;
+        let server = Arc::new(
+            UsbIpServer::new_simulated(vec![slow_card()])
+                .with_policy(acl::Policy::parse("allow token:owner serial:12345678").unwrap()),
+        );
+        tokio::spawn(crate::server(addr, server));
+
+        let mut stranger = tokio::io::BufReader::new(poll_connect(addr).await);
+        subscribe(&mut stranger).await.unwrap();
+        let mut owner = tokio::io::BufReader::new(poll_connect(addr).await);
+        client::send_token(&mut owner, "owner").await.unwrap();
+        subscribe(&mut owner).await.unwrap();
+
+        let mut socket = poll_connect(addr).await;
+        client::send_token(&mut socket, "owner").await.unwrap();
+        let device = client::import(&mut socket, "0-0-0").await.unwrap();
+        let device = client::ImportedDevice::new(socket, device);
+        device.transfer_in(1, 64).await.unwrap();
+
+        assert!(next_event(&mut owner).await.unwrap().is_some());
+        let nothing =
+            tokio::time::timeout(Duration::from_millis(200), next_event(&mut stranger)).await;
+        assert!(nothing.is_err());
+    }
+}
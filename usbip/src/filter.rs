@@ -0,0 +1,158 @@
+//! Choosing which host devices to export
+use super::*;
+
+/// Yubico's USB vendor id
+pub const YUBICO_VENDOR_ID: u16 = 0x1050;
+
+/// Which host devices to export, for [UsbIpServer::new_from_host_with_filter]
+/// and [UsbIpServer::watch_host]
+///
+/// The default only matches YubiKeys: Yubico devices whose interfaces are all
+/// HID (OTP and FIDO) or smart card (CCID) interfaces, so that a server
+/// scanning every bus doesn't export keyboards, disks or webcams by accident.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DeviceFilter {
+    /// Vendor ids, each with an optional product id; empty matches any device
+    pub ids: Vec<(u16, Option<u16>)>,
+    /// Classes that every interface must have; empty allows any interface
+    pub interface_classes: Vec<u8>,
+}
+
+impl Default for DeviceFilter {
+    fn default() -> Self {
+        Self::yubikeys()
+    }
+}
+
+impl DeviceFilter {
+    /// Match YubiKeys only
+    pub fn yubikeys() -> Self {
+        Self {
+            ids: vec![(YUBICO_VENDOR_ID, None)],
+            interface_classes: vec![ClassCode::HID as u8, ClassCode::SmartCard as u8],
+        }
+    }
+
+    /// Match every device
+    pub fn any() -> Self {
+        Self {
+            ids: vec![],
+            interface_classes: vec![],
+        }
+    }
+
+    /// Parse `VID[:PID]` pairs in hex separated by commas, e.g.
+    /// `1050,20a0:4108`, or `any`
+    pub fn parse_ids(spec: &str) -> Result<Vec<(u16, Option<u16>)>> {
+        if spec == "any" {
+            return Ok(vec![]);
+        }
+        spec.split(',')
+            .map(|id| {
+                let (vendor, product) = match id.trim().split_once(':') {
+                    Some((vendor, product)) => (vendor, Some(product)),
+                    None => (id.trim(), None),
+                };
+                Ok((parse_hex(vendor)?, product.map(parse_hex).transpose()?))
+            })
+            .collect()
+    }
+
+    /// Parse interface classes in hex separated by commas, e.g. `03,0b`, or
+    /// `any`
+    pub fn parse_classes(spec: &str) -> Result<Vec<u8>> {
+        if spec == "any" {
+            return Ok(vec![]);
+        }
+        spec.split(',')
+            .map(|class| {
+                u8::from_str_radix(class.trim(), 16).map_err(|_| {
+                    std::io::Error::new(
+                        ErrorKind::InvalidInput,
+                        format!("Invalid interface class {}", class),
+                    )
+                })
+            })
+            .collect()
+    }
+
+    /// Whether `dev` should be exported
+    pub fn matches<T: UsbContext>(&self, dev: &Device<T>) -> bool {
+        let Ok(desc) = dev.device_descriptor() else {
+            return false;
+        };
+        if !self.matches_ids(desc.vendor_id(), desc.product_id()) {
+            return false;
+        }
+        if self.interface_classes.is_empty() {
+            return true;
+        }
+        match dev.active_config_descriptor() {
+            Ok(cfg) => self.matches_classes(
+                cfg.interfaces()
+                    .flat_map(|intf| intf.descriptors())
+                    .map(|intf_desc| intf_desc.class_code()),
+            ),
+            Err(_) => false,
+        }
+    }
+
+    /// Whether the device nusb enumerated as `info` should be exported, for
+    /// [UsbIpServer::new_from_nusb_host_with_filter]
+    #[cfg(feature = "nusb")]
+    pub fn matches_nusb(&self, info: &nusb::DeviceInfo) -> bool {
+        self.matches_ids(info.vendor_id(), info.product_id())
+            && self.matches_classes(info.interfaces().map(|intf| intf.class()))
+    }
+
+    fn matches_ids(&self, vendor_id: u16, product_id: u16) -> bool {
+        self.ids.is_empty()
+            || self.ids.iter().any(|(vendor, product)| {
+                *vendor == vendor_id && product.is_none_or(|product| product == product_id)
+            })
+    }
+
+    fn matches_classes(&self, mut classes: impl Iterator<Item = u8>) -> bool {
+        self.interface_classes.is_empty()
+            || classes.all(|class| self.interface_classes.contains(&class))
+    }
+}
+
+fn parse_hex(id: &str) -> Result<u16> {
+    u16::from_str_radix(id, 16)
+        .map_err(|_| std::io::Error::new(ErrorKind::InvalidInput, format!("Invalid USB id {}", id)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn yubikeys_only() {
+        let filter = DeviceFilter::default();
+        assert!(filter.matches_ids(YUBICO_VENDOR_ID, 0x0407));
+        assert!(!filter.matches_ids(0x046d, 0xc31c));
+        // OTP keyboard and FIDO are HID, PIV and OpenPGP are CCID
+        assert!(filter.matches_classes([0x03, 0x03, 0x0b].into_iter()));
+        assert!(!filter.matches_classes([0x03, 0x08].into_iter()));
+
+        let any = DeviceFilter::any();
+        assert!(any.matches_ids(0x046d, 0xc31c));
+        assert!(any.matches_classes([0x0e].into_iter()));
+    }
+
+    #[test]
+    fn parse() {
+        assert_eq!(
+            DeviceFilter::parse_ids("1050, 20a0:4108").unwrap(),
+            vec![(0x1050, None), (0x20a0, Some(0x4108))]
+        );
+        assert!(DeviceFilter::parse_ids("any").unwrap().is_empty());
+        assert!(DeviceFilter::parse_ids("1050:").is_err());
+        assert!(DeviceFilter::parse_ids("yubico").is_err());
+
+        assert_eq!(DeviceFilter::parse_classes("03,0b").unwrap(), vec![3, 0x0b]);
+        assert!(DeviceFilter::parse_classes("any").unwrap().is_empty());
+        assert!(DeviceFilter::parse_classes("100").is_err());
+    }
+}
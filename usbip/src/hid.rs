@@ -95,7 +95,7 @@ impl UsbInterfaceHandler for UsbHidKeyboardHandler {
         _transfer_buffer_length: u32,
         setup: SetupPacket,
         _req: &[u8],
-    ) -> Result<Vec<u8>> {
+    ) -> UrbResult {
         if ep.is_ep0() {
             // control transfers
             match (setup.request_type, setup.request) {
@@ -106,14 +106,20 @@ impl UsbInterfaceHandler for UsbHidKeyboardHandler {
                         Some(HidDescriptorType::Report) => {
                             return Ok(self.report_descriptor.clone());
                         }
-                        _ => unimplemented!("hid descriptor {:?}", setup),
+                        _ => {
+                            warn!("Unsupported HID descriptor {:?}", setup);
+                            return Err(UrbError::Stall);
+                        }
                     }
                 }
                 (0b00100001, 0x0A) => {
                     // SET_IDLE
                     return Ok(vec![]);
                 }
-                _ => unimplemented!("hid request {:?}", setup),
+                _ => {
+                    warn!("Unsupported HID request {:?}", setup);
+                    return Err(UrbError::Stall);
+                }
             }
         } else {
             // interrupt transfer
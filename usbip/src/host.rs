@@ -1,88 +1,763 @@
 //! Host USB
 use super::*;
+use std::time::Duration;
 
-/// A handler to pass requests to a USB device of the host
-#[derive(Clone)]
-pub struct UsbHostInterfaceHandler {
-    handle: Arc<Mutex<DeviceHandle<GlobalContext>>>,
+/// Direct access to a USB device of the host
+///
+/// [UsbHostInterfaceHandler] and [UsbHostDeviceHandler] forward URBs through
+/// this trait, so the USB stack used to reach the device can be swapped
+/// without touching the USB/IP side. Endpoint addresses include the direction
+/// bit, and all methods return the number of bytes transferred.
+///
+/// Methods may be called concurrently for different endpoints, so that a
+/// transfer waiting on one endpoint doesn't stall the others.
+pub trait UsbBackend: Send + Sync {
+    fn read_control(&self, setup: &SetupPacket, buf: &mut [u8], timeout: Duration)
+        -> Result<usize>;
+    fn write_control(&self, setup: &SetupPacket, data: &[u8], timeout: Duration) -> Result<usize>;
+    fn read_interrupt(&self, endpoint: u8, buf: &mut [u8], timeout: Duration) -> Result<usize>;
+    fn write_interrupt(&self, endpoint: u8, data: &[u8], timeout: Duration) -> Result<usize>;
+    fn read_bulk(&self, endpoint: u8, buf: &mut [u8], timeout: Duration) -> Result<usize>;
+    fn write_bulk(&self, endpoint: u8, data: &[u8], timeout: Duration) -> Result<usize>;
+
+    /// Clear the halt of `endpoint` after it stalled
+    ///
+    /// The default sends CLEAR_FEATURE(ENDPOINT_HALT) to the device.
+    fn clear_halt(&self, endpoint: u8) -> Result<()> {
+        let setup = SetupPacket {
+            request_type: 0b00000010,
+            request: StandardRequest::ClearFeature as u8,
+            value: ENDPOINT_HALT,
+            index: endpoint as u16,
+            length: 0,
+        };
+        self.write_control(&setup, &[], Duration::from_secs(1))
+            .map(|_| ())
+    }
+
+    /// Transfer the packets of an isochronous URB
+    ///
+    /// `buf` is laid out as described by `packets`, whose `actual_length` and
+    /// `status` are filled in. The default fails, for backends which can't do
+    /// isochronous transfers.
+    fn iso_transfer(
+        &self,
+        _endpoint: u8,
+        _buf: &mut [u8],
+        _packets: &mut [IsoPacketDescriptor],
+        _timeout: Duration,
+    ) -> Result<()> {
+        Err(std::io::Error::new(
+            ErrorKind::Unsupported,
+            "Isochronous transfers are not supported",
+        ))
+    }
+}
+
+/// Timeouts and retries of the transfers [UsbHostInterfaceHandler] and
+/// [UsbHostDeviceHandler] make to the host device
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TransferPolicy {
+    pub control_timeout: Duration,
+    /// How long an interrupt IN transfer waits for the device to report
+    /// something; it then completes with no data
+    pub interrupt_timeout: Duration,
+    pub bulk_timeout: Duration,
+    pub iso_timeout: Duration,
+    /// How many times a transfer failing with a transient error is retried
+    ///
+    /// Transient errors are interrupted or busy transfers, and timeouts of IN
+    /// transfers other than interrupt ones, which can be repeated without side
+    /// effects.
+    pub retries: u32,
+    /// Delay before the first retry, doubled before each next one
+    pub backoff: Duration,
 }
 
-impl UsbHostInterfaceHandler {
-    pub fn new(handle: Arc<Mutex<DeviceHandle<GlobalContext>>>) -> Self {
-        Self { handle }
+impl Default for TransferPolicy {
+    fn default() -> Self {
+        Self {
+            control_timeout: Duration::from_secs(1),
+            interrupt_timeout: Duration::from_secs(1),
+            bulk_timeout: Duration::from_secs(1),
+            iso_timeout: Duration::from_secs(1),
+            retries: 2,
+            backoff: Duration::from_millis(10),
+        }
     }
 }
 
-impl UsbInterfaceHandler for UsbHostInterfaceHandler {
-    fn handle_urb(
+impl TransferPolicy {
+    /// The timeout of transfers to endpoints with `attributes`
+    pub fn timeout(&self, attributes: u8) -> Duration {
+        match attributes & 0x03 {
+            0 => self.control_timeout,
+            1 => self.iso_timeout,
+            2 => self.bulk_timeout,
+            _ => self.interrupt_timeout,
+        }
+    }
+
+    /// Run `transfer`, retrying it after transient errors
+    ///
+    /// Timeouts are only retried if `repeatable`.
+    fn run<T>(&self, repeatable: bool, mut transfer: impl FnMut() -> Result<T>) -> Result<T> {
+        let mut backoff = self.backoff;
+        let mut retries = self.retries;
+        loop {
+            match transfer() {
+                Err(err) if retries > 0 && Self::transient(&err, repeatable) => {
+                    debug!("Retrying transfer in {:?}: {}", backoff, err);
+                    std::thread::sleep(backoff);
+                    backoff *= 2;
+                    retries -= 1;
+                }
+                res => return res,
+            }
+        }
+    }
+
+    fn transient(err: &std::io::Error, repeatable: bool) -> bool {
+        match err.kind() {
+            ErrorKind::Interrupted | ErrorKind::ResourceBusy => true,
+            ErrorKind::TimedOut => repeatable,
+            _ => false,
+        }
+    }
+}
+
+fn from_rusb(err: rusb::Error) -> std::io::Error {
+    let kind = match err {
+        rusb::Error::Timeout => ErrorKind::TimedOut,
+        rusb::Error::NoDevice | rusb::Error::NotFound => ErrorKind::NotFound,
+        rusb::Error::Access => ErrorKind::PermissionDenied,
+        rusb::Error::Interrupted => ErrorKind::Interrupted,
+        rusb::Error::Busy => ErrorKind::ResourceBusy,
+        rusb::Error::Pipe => ErrorKind::BrokenPipe,
+        _ => ErrorKind::Other,
+    };
+    std::io::Error::new(kind, err)
+}
+
+fn from_libusb(err: std::os::raw::c_int) -> std::io::Error {
+    use rusb::constants::*;
+    from_rusb(match err {
+        LIBUSB_ERROR_INVALID_PARAM => rusb::Error::InvalidParam,
+        LIBUSB_ERROR_NO_DEVICE => rusb::Error::NoDevice,
+        LIBUSB_ERROR_BUSY => rusb::Error::Busy,
+        LIBUSB_ERROR_NO_MEM => rusb::Error::NoMem,
+        LIBUSB_ERROR_NOT_SUPPORTED => rusb::Error::NotSupported,
+        _ => rusb::Error::Io,
+    })
+}
+
+/// The status of an isochronous packet as a negated errno, as the kernel reports it
+fn iso_packet_status(status: std::os::raw::c_int) -> i32 {
+    use rusb::constants::*;
+    match status {
+        LIBUSB_TRANSFER_COMPLETED => 0,
+        LIBUSB_TRANSFER_TIMED_OUT => -62, // ETIME
+        LIBUSB_TRANSFER_CANCELLED => -2,  // ENOENT
+        LIBUSB_TRANSFER_STALL => -32,     // EPIPE
+        LIBUSB_TRANSFER_NO_DEVICE => -19, // ENODEV
+        LIBUSB_TRANSFER_OVERFLOW => -75,  // EOVERFLOW
+        _ => -71,                         // EPROTO
+    }
+}
+
+extern "system" fn iso_transfer_done(transfer: *mut rusb::ffi::libusb_transfer) {
+    // SAFETY: user_data points to the completion flag of the waiting libusb_iso_transfer
+    unsafe { *((*transfer).user_data as *mut std::os::raw::c_int) = 1 }
+}
+
+/// Submit an isochronous transfer of `data`, packed back to back, and wait for it
+///
+/// rusb only wraps the synchronous libusb API, which has no isochronous
+/// transfers, so this drives the asynchronous API itself. Other threads may
+/// handle events of the same context meanwhile, which libusb allows.
+fn libusb_iso_transfer<T: UsbContext>(
+    handle: &DeviceHandle<T>,
+    endpoint: u8,
+    data: &mut [u8],
+    packets: &mut [IsoPacketDescriptor],
+    timeout: Duration,
+) -> Result<()> {
+    use rusb::{constants::*, ffi::*};
+    use std::os::raw::{c_int, c_uint, c_void};
+
+    let context = handle.context().as_raw();
+    let mut completed: c_int = 0;
+    let completed: *mut c_int = &mut completed;
+    // SAFETY: the transfer, `data` and `completed` outlive the transfer, as
+    // it is only freed once the callback has run
+    unsafe {
+        let transfer = libusb_alloc_transfer(packets.len() as c_int);
+        if transfer.is_null() {
+            return Err(from_libusb(LIBUSB_ERROR_NO_MEM));
+        }
+        libusb_fill_iso_transfer(
+            transfer,
+            handle.as_raw(),
+            endpoint,
+            data.as_mut_ptr(),
+            data.len() as c_int,
+            packets.len() as c_int,
+            iso_transfer_done,
+            completed as *mut c_void,
+            timeout.as_millis() as c_uint,
+        );
+        let descriptors = (*transfer).iso_packet_desc.as_mut_ptr();
+        for (i, packet) in packets.iter().enumerate() {
+            (*descriptors.add(i)).length = packet.length;
+        }
+
+        let res = libusb_submit_transfer(transfer);
+        if res < 0 {
+            libusb_free_transfer(transfer);
+            return Err(from_libusb(res));
+        }
+        let mut cancelled = false;
+        while std::ptr::read_volatile(completed) == 0 {
+            let res = libusb_handle_events_completed(context, completed);
+            if res < 0 && res != LIBUSB_ERROR_INTERRUPTED && !cancelled {
+                warn!("Error handling libusb events: {}", res);
+                libusb_cancel_transfer(transfer);
+                cancelled = true;
+            }
+        }
+
+        let status = (*transfer).status;
+        for (i, packet) in packets.iter_mut().enumerate() {
+            let descriptor = &*descriptors.add(i);
+            packet.actual_length = descriptor.actual_length;
+            packet.status = iso_packet_status(descriptor.status);
+        }
+        libusb_free_transfer(transfer);
+
+        match status {
+            LIBUSB_TRANSFER_COMPLETED => Ok(()),
+            LIBUSB_TRANSFER_NO_DEVICE => Err(from_rusb(rusb::Error::NoDevice)),
+            LIBUSB_TRANSFER_TIMED_OUT => Err(from_rusb(rusb::Error::Timeout)),
+            _ => Err(from_rusb(rusb::Error::Io)),
+        }
+    }
+}
+
+/// The libusb backend
+impl<T: UsbContext> UsbBackend for DeviceHandle<T> {
+    fn read_control(
+        &self,
+        setup: &SetupPacket,
+        buf: &mut [u8],
+        timeout: Duration,
+    ) -> Result<usize> {
+        DeviceHandle::read_control(
+            self,
+            setup.request_type,
+            setup.request,
+            setup.value,
+            setup.index,
+            buf,
+            timeout,
+        )
+        .map_err(from_rusb)
+    }
+
+    fn write_control(&self, setup: &SetupPacket, data: &[u8], timeout: Duration) -> Result<usize> {
+        DeviceHandle::write_control(
+            self,
+            setup.request_type,
+            setup.request,
+            setup.value,
+            setup.index,
+            data,
+            timeout,
+        )
+        .map_err(from_rusb)
+    }
+
+    fn read_interrupt(&self, endpoint: u8, buf: &mut [u8], timeout: Duration) -> Result<usize> {
+        DeviceHandle::read_interrupt(self, endpoint, buf, timeout).map_err(from_rusb)
+    }
+
+    fn write_interrupt(&self, endpoint: u8, data: &[u8], timeout: Duration) -> Result<usize> {
+        DeviceHandle::write_interrupt(self, endpoint, data, timeout).map_err(from_rusb)
+    }
+
+    fn read_bulk(&self, endpoint: u8, buf: &mut [u8], timeout: Duration) -> Result<usize> {
+        DeviceHandle::read_bulk(self, endpoint, buf, timeout).map_err(from_rusb)
+    }
+
+    fn write_bulk(&self, endpoint: u8, data: &[u8], timeout: Duration) -> Result<usize> {
+        DeviceHandle::write_bulk(self, endpoint, data, timeout).map_err(from_rusb)
+    }
+
+    fn clear_halt(&self, endpoint: u8) -> Result<()> {
+        // Also resets the data toggle on the host side
+        DeviceHandle::clear_halt(self, endpoint).map_err(from_rusb)
+    }
+
+    fn iso_transfer(
+        &self,
+        endpoint: u8,
+        buf: &mut [u8],
+        packets: &mut [IsoPacketDescriptor],
+        timeout: Duration,
+    ) -> Result<()> {
+        // libusb expects the packets back to back
+        let range = |p: &IsoPacketDescriptor| p.offset as usize..(p.offset + p.length) as usize;
+        let mut data = if endpoint & 0x80 == 0 {
+            packets
+                .iter()
+                .flat_map(|p| buf[range(p)].to_vec())
+                .collect()
+        } else {
+            vec![0; packets.iter().map(|p| p.length as usize).sum()]
+        };
+
+        libusb_iso_transfer(self, endpoint, &mut data, packets, timeout)?;
+
+        if endpoint & 0x80 != 0 {
+            let mut start = 0;
+            for packet in packets.iter() {
+                let len = packet.actual_length as usize;
+                buf[packet.offset as usize..][..len].copy_from_slice(&data[start..][..len]);
+                start += packet.length as usize;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(feature = "nusb")]
+fn from_nusb(err: nusb::transfer::TransferError) -> std::io::Error {
+    use nusb::transfer::TransferError;
+    let kind = match err {
+        // nusb cancels the transfers that time out
+        TransferError::Cancelled => ErrorKind::TimedOut,
+        TransferError::Disconnected => ErrorKind::NotFound,
+        TransferError::Stall => ErrorKind::BrokenPipe,
+        TransferError::InvalidArgument => ErrorKind::InvalidInput,
+        _ => ErrorKind::Other,
+    };
+    std::io::Error::new(kind, err)
+}
+
+/// The type and recipient nusb takes for the `bmRequestType` of a setup packet
+#[cfg(feature = "nusb")]
+fn nusb_request_type(
+    request_type: u8,
+) -> Result<(nusb::transfer::ControlType, nusb::transfer::Recipient)> {
+    use nusb::transfer::{ControlType, Recipient};
+    let control_type = match (request_type >> 5) & 0b11 {
+        0 => ControlType::Standard,
+        1 => ControlType::Class,
+        2 => ControlType::Vendor,
+        _ => return Err(ErrorKind::InvalidInput.into()),
+    };
+    let recipient = match request_type & 0b11111 {
+        0 => Recipient::Device,
+        1 => Recipient::Interface,
+        2 => Recipient::Endpoint,
+        3 => Recipient::Other,
+        _ => return Err(ErrorKind::InvalidInput.into()),
+    };
+    Ok((control_type, recipient))
+}
+
+/// The length to request for an IN transfer of up to `len` bytes, which nusb
+/// wants to be a nonzero multiple of the packet size
+#[cfg(feature = "nusb")]
+fn nusb_in_len(len: usize, max_packet_size: usize) -> usize {
+    let max_packet_size = max_packet_size.max(1);
+    len.max(1).div_ceil(max_packet_size) * max_packet_size
+}
+
+/// An endpoint opened with nusb, whose type and direction are part of its type
+#[cfg(feature = "nusb")]
+enum NusbEndpoint {
+    BulkIn(nusb::Endpoint<nusb::transfer::Bulk, nusb::transfer::In>),
+    BulkOut(nusb::Endpoint<nusb::transfer::Bulk, nusb::transfer::Out>),
+    InterruptIn(nusb::Endpoint<nusb::transfer::Interrupt, nusb::transfer::In>),
+    InterruptOut(nusb::Endpoint<nusb::transfer::Interrupt, nusb::transfer::Out>),
+}
+
+#[cfg(feature = "nusb")]
+impl NusbEndpoint {
+    fn open(
+        interface: &nusb::Interface,
+        address: u8,
+        transfer_type: nusb::descriptors::TransferType,
+    ) -> Result<Self> {
+        use nusb::descriptors::TransferType;
+        let endpoint = match (transfer_type, address & 0x80 != 0) {
+            (TransferType::Bulk, true) => interface.endpoint(address).map(Self::BulkIn),
+            (TransferType::Bulk, false) => interface.endpoint(address).map(Self::BulkOut),
+            (TransferType::Interrupt, true) => interface.endpoint(address).map(Self::InterruptIn),
+            (TransferType::Interrupt, false) => interface.endpoint(address).map(Self::InterruptOut),
+            _ => {
+                return Err(std::io::Error::new(
+                    ErrorKind::Unsupported,
+                    "Only bulk and interrupt endpoints are supported",
+                ))
+            }
+        };
+        endpoint.map_err(std::io::Error::from)
+    }
+
+    fn max_packet_size(&self) -> usize {
+        match self {
+            Self::BulkIn(endpoint) => endpoint.max_packet_size(),
+            Self::BulkOut(endpoint) => endpoint.max_packet_size(),
+            Self::InterruptIn(endpoint) => endpoint.max_packet_size(),
+            Self::InterruptOut(endpoint) => endpoint.max_packet_size(),
+        }
+    }
+
+    fn transfer(
         &mut self,
+        buf: nusb::transfer::Buffer,
+        timeout: Duration,
+    ) -> nusb::transfer::Completion {
+        match self {
+            Self::BulkIn(endpoint) => endpoint.transfer_blocking(buf, timeout),
+            Self::BulkOut(endpoint) => endpoint.transfer_blocking(buf, timeout),
+            Self::InterruptIn(endpoint) => endpoint.transfer_blocking(buf, timeout),
+            Self::InterruptOut(endpoint) => endpoint.transfer_blocking(buf, timeout),
+        }
+    }
+
+    fn clear_halt(&mut self) -> Result<()> {
+        use nusb::MaybeFuture;
+        match self {
+            Self::BulkIn(endpoint) => endpoint.clear_halt().wait(),
+            Self::BulkOut(endpoint) => endpoint.clear_halt().wait(),
+            Self::InterruptIn(endpoint) => endpoint.clear_halt().wait(),
+            Self::InterruptOut(endpoint) => endpoint.clear_halt().wait(),
+        }
+        .map_err(std::io::Error::from)
+    }
+}
+
+/// The nusb backend, which needs no libusb
+///
+/// It claims every interface of the device, detaching their kernel drivers,
+/// and opens the endpoints the first time they're used. Isochronous transfers
+/// are not supported.
+#[cfg(feature = "nusb")]
+pub struct NusbBackend {
+    device: nusb::Device,
+    interfaces: Vec<nusb::Interface>,
+    endpoints: Mutex<HashMap<u8, Arc<Mutex<NusbEndpoint>>>>,
+}
+
+#[cfg(feature = "nusb")]
+impl NusbBackend {
+    /// Claim the interfaces of the active configuration of `device`
+    pub fn new(device: nusb::Device) -> Result<Self> {
+        use nusb::MaybeFuture;
+        let numbers: Vec<_> = device
+            .active_configuration()?
+            .interfaces()
+            .map(|intf| intf.interface_number())
+            .collect();
+        let mut interfaces = vec![];
+        for number in numbers {
+            interfaces.push(device.detach_and_claim_interface(number).wait()?);
+        }
+        Ok(Self {
+            device,
+            interfaces,
+            endpoints: Mutex::new(HashMap::new()),
+        })
+    }
+
+    /// The device this backend transfers to
+    pub fn device(&self) -> &nusb::Device {
+        &self.device
+    }
+
+    /// The interface to send the control transfer `setup` through, since
+    /// WinUSB only has control transfers on interfaces
+    fn control_interface(&self, setup: &SetupPacket) -> Result<&nusb::Interface> {
+        let to_interface = setup.request_type & 0b11111 == 1;
+        self.interfaces
+            .iter()
+            .find(|intf| to_interface && intf.interface_number() == setup.index as u8)
+            .or(self.interfaces.first())
+            .ok_or_else(|| std::io::Error::new(ErrorKind::NotFound, "No interface claimed"))
+    }
+
+    fn endpoint(&self, address: u8) -> Result<Arc<Mutex<NusbEndpoint>>> {
+        let mut endpoints = self.endpoints.lock().unwrap();
+        if let Some(endpoint) = endpoints.get(&address) {
+            return Ok(endpoint.clone());
+        }
+        for intf in &self.interfaces {
+            let Some(transfer_type) = intf.descriptor().and_then(|desc| {
+                desc.endpoints()
+                    .find(|ep| ep.address() == address)
+                    .map(|ep| ep.transfer_type())
+            }) else {
+                continue;
+            };
+            let endpoint = Arc::new(Mutex::new(NusbEndpoint::open(
+                intf,
+                address,
+                transfer_type,
+            )?));
+            endpoints.insert(address, endpoint.clone());
+            return Ok(endpoint);
+        }
+        Err(std::io::Error::new(
+            ErrorKind::NotFound,
+            format!("No endpoint {address:#04x}"),
+        ))
+    }
+
+    fn read(&self, endpoint: u8, buf: &mut [u8], timeout: Duration) -> Result<usize> {
+        let endpoint = self.endpoint(endpoint)?;
+        let mut endpoint = endpoint.lock().unwrap();
+        let len = nusb_in_len(buf.len(), endpoint.max_packet_size());
+        let completion = endpoint.transfer(nusb::transfer::Buffer::new(len), timeout);
+        completion.status.map_err(from_nusb)?;
+        let data = &completion.buffer[..];
+        if data.len() > buf.len() {
+            // as libusb reports it
+            return Err(std::io::Error::other("Overflow"));
+        }
+        buf[..data.len()].copy_from_slice(data);
+        Ok(data.len())
+    }
+
+    fn write(&self, endpoint: u8, data: &[u8], timeout: Duration) -> Result<usize> {
+        let endpoint = self.endpoint(endpoint)?;
+        let completion = endpoint.lock().unwrap().transfer(data.into(), timeout);
+        completion.status.map_err(from_nusb)?;
+        Ok(completion.actual_len)
+    }
+}
+
+#[cfg(feature = "nusb")]
+impl UsbBackend for NusbBackend {
+    fn read_control(
+        &self,
+        setup: &SetupPacket,
+        buf: &mut [u8],
+        timeout: Duration,
+    ) -> Result<usize> {
+        use nusb::MaybeFuture;
+        let (control_type, recipient) = nusb_request_type(setup.request_type)?;
+        let control = nusb::transfer::ControlIn {
+            control_type,
+            recipient,
+            request: setup.request,
+            value: setup.value,
+            index: setup.index,
+            length: buf.len().min(u16::MAX as usize) as u16,
+        };
+        let data = self
+            .control_interface(setup)?
+            .control_in(control, timeout)
+            .wait()
+            .map_err(from_nusb)?;
+        let len = data.len().min(buf.len());
+        buf[..len].copy_from_slice(&data[..len]);
+        Ok(len)
+    }
+
+    fn write_control(&self, setup: &SetupPacket, data: &[u8], timeout: Duration) -> Result<usize> {
+        use nusb::MaybeFuture;
+        let (control_type, recipient) = nusb_request_type(setup.request_type)?;
+        let control = nusb::transfer::ControlOut {
+            control_type,
+            recipient,
+            request: setup.request,
+            value: setup.value,
+            index: setup.index,
+            data,
+        };
+        self.control_interface(setup)?
+            .control_out(control, timeout)
+            .wait()
+            .map_err(from_nusb)?;
+        Ok(data.len())
+    }
+
+    fn read_interrupt(&self, endpoint: u8, buf: &mut [u8], timeout: Duration) -> Result<usize> {
+        self.read(endpoint, buf, timeout)
+    }
+
+    fn write_interrupt(&self, endpoint: u8, data: &[u8], timeout: Duration) -> Result<usize> {
+        self.write(endpoint, data, timeout)
+    }
+
+    fn read_bulk(&self, endpoint: u8, buf: &mut [u8], timeout: Duration) -> Result<usize> {
+        self.read(endpoint, buf, timeout)
+    }
+
+    fn write_bulk(&self, endpoint: u8, data: &[u8], timeout: Duration) -> Result<usize> {
+        self.write(endpoint, data, timeout)
+    }
+
+    fn clear_halt(&self, endpoint: u8) -> Result<()> {
+        // Also resets the data toggle on the host side
+        self.endpoint(endpoint)?.lock().unwrap().clear_halt()
+    }
+}
+
+/// A handler to pass requests to a USB device of the host
+///
+/// Transfers that fail fail their URB, except interrupt IN transfers timing
+/// out, which complete with no data.
+pub struct UsbHostInterfaceHandler<B = DeviceHandle<GlobalContext>> {
+    handle: Arc<B>,
+    policy: TransferPolicy,
+}
+
+impl<B> Clone for UsbHostInterfaceHandler<B> {
+    fn clone(&self) -> Self {
+        Self {
+            handle: self.handle.clone(),
+            policy: self.policy.clone(),
+        }
+    }
+}
+
+impl<B: UsbBackend> UsbHostInterfaceHandler<B> {
+    pub fn new(handle: Arc<B>, policy: TransferPolicy) -> Self {
+        Self { handle, policy }
+    }
+}
+
+impl<B: UsbBackend + 'static> UsbConcurrentHandler for UsbHostInterfaceHandler<B> {
+    fn handle_concurrent_urb(
+        &self,
         _interface: &UsbInterface,
         ep: UsbEndpoint,
         transfer_buffer_length: u32,
         setup: SetupPacket,
         req: &[u8],
-    ) -> Result<Vec<u8>> {
+    ) -> UrbResult {
         debug!(
             "To host device: ep={:?} setup={:?} req={:?}",
             ep, setup, req
         );
         let mut buffer = vec![0u8; transfer_buffer_length as usize];
-        let timeout = std::time::Duration::new(1, 0);
-        let handle = self.handle.lock().unwrap();
+        let policy = &self.policy;
+        let timeout = policy.timeout(ep.attributes);
+        let handle = &self.handle;
         if ep.attributes == EndpointAttributes::Control as u8 {
             // control
             if let Direction::In = ep.direction() {
                 // control in
-                if let Ok(len) = handle.read_control(
-                    setup.request_type,
-                    setup.request,
-                    setup.value,
-                    setup.index,
-                    &mut buffer,
-                    timeout,
-                ) {
-                    return Ok(Vec::from(&buffer[..len]));
-                }
+                let len = policy.run(true, || handle.read_control(&setup, &mut buffer, timeout))?;
+                return Ok(Vec::from(&buffer[..len]));
             } else {
                 // control out
-                handle
-                    .write_control(
-                        setup.request_type,
-                        setup.request,
-                        setup.value,
-                        setup.index,
-                        req,
-                        timeout,
-                    )
-                    .ok();
+                policy.run(false, || handle.write_control(&setup, req, timeout))?;
             }
         } else if ep.attributes == EndpointAttributes::Interrupt as u8 {
             // interrupt
             if let Direction::In = ep.direction() {
                 // interrupt in
-                if let Ok(len) = handle.read_interrupt(ep.address, &mut buffer, timeout) {
-                    info!("intr in {:?}", &buffer[..len]);
-                    return Ok(Vec::from(&buffer[..len]));
+                match policy.run(false, || {
+                    handle.read_interrupt(ep.address, &mut buffer, timeout)
+                }) {
+                    Ok(len) => {
+                        info!("intr in {:?}", &buffer[..len]);
+                        return Ok(Vec::from(&buffer[..len]));
+                    }
+                    // nothing to report
+                    Err(err) if err.kind() == ErrorKind::TimedOut => {}
+                    Err(err) => return Err(err.into()),
                 }
             } else {
                 // interrupt out
-                handle.write_interrupt(ep.address, req, timeout).ok();
+                policy.run(false, || handle.write_interrupt(ep.address, req, timeout))?;
             }
         } else if ep.attributes == EndpointAttributes::Bulk as u8 {
             // bulk
             if let Direction::In = ep.direction() {
                 // bulk in
-                if let Ok(len) = handle.read_bulk(ep.address, &mut buffer, timeout) {
-                    return Ok(Vec::from(&buffer[..len]));
-                }
+                let len =
+                    policy.run(true, || handle.read_bulk(ep.address, &mut buffer, timeout))?;
+                return Ok(Vec::from(&buffer[..len]));
             } else {
                 // bulk out
-                handle.write_bulk(ep.address, req, timeout).ok();
+                policy.run(false, || handle.write_bulk(ep.address, req, timeout))?;
             }
         }
         Ok(vec![])
     }
 
+    fn handle_concurrent_iso_urb(
+        &self,
+        _interface: &UsbInterface,
+        ep: UsbEndpoint,
+        transfer_buffer_length: u32,
+        packets: &[IsoPacketDescriptor],
+        req: &[u8],
+    ) -> UrbResult<(Vec<u8>, Vec<IsoPacketDescriptor>)> {
+        debug!(
+            "To host device: ep={:?} iso packets={} req={:?}",
+            ep,
+            packets.len(),
+            req
+        );
+        let mut buffer = if let Direction::In = ep.direction() {
+            vec![0u8; transfer_buffer_length as usize]
+        } else {
+            req.to_vec()
+        };
+        if packets
+            .iter()
+            .any(|p| p.offset as usize + p.length as usize > buffer.len())
+        {
+            return Err(UrbError::Other(std::io::Error::new(
+                ErrorKind::InvalidInput,
+                "Isochronous packet outside of the transfer buffer",
+            )));
+        }
+        let mut packets = packets.to_vec();
+        let timeout = self.policy.iso_timeout;
+        self.handle
+            .iso_transfer(ep.address, &mut buffer, &mut packets, timeout)?;
+        Ok((buffer, packets))
+    }
+}
+
+impl<B: UsbBackend + 'static> UsbInterfaceHandler for UsbHostInterfaceHandler<B> {
+    fn handle_urb(
+        &mut self,
+        interface: &UsbInterface,
+        ep: UsbEndpoint,
+        transfer_buffer_length: u32,
+        setup: SetupPacket,
+        req: &[u8],
+    ) -> UrbResult {
+        self.handle_concurrent_urb(interface, ep, transfer_buffer_length, setup, req)
+    }
+
+    fn handle_iso_urb(
+        &mut self,
+        interface: &UsbInterface,
+        ep: UsbEndpoint,
+        transfer_buffer_length: u32,
+        packets: &[IsoPacketDescriptor],
+        req: &[u8],
+    ) -> UrbResult<(Vec<u8>, Vec<IsoPacketDescriptor>)> {
+        self.handle_concurrent_iso_urb(interface, ep, transfer_buffer_length, packets, req)
+    }
+
+    fn clear_halt(&mut self, ep: UsbEndpoint) -> UrbResult<()> {
+        Ok(self.handle.clear_halt(ep.address)?)
+    }
+
+    fn concurrent(&self) -> Option<Arc<dyn UsbConcurrentHandler>> {
+        Some(Arc::new(self.clone()))
+    }
+
     fn get_class_specific_descriptor(&self) -> Vec<u8> {
         vec![]
     }
@@ -93,53 +768,46 @@ impl UsbInterfaceHandler for UsbHostInterfaceHandler {
 }
 
 /// A handler to pass requests to a USB device of the host
-#[derive(Clone)]
-pub struct UsbHostDeviceHandler {
-    handle: Arc<Mutex<DeviceHandle<GlobalContext>>>,
+pub struct UsbHostDeviceHandler<B = DeviceHandle<GlobalContext>> {
+    handle: Arc<B>,
+    policy: TransferPolicy,
 }
 
-impl UsbHostDeviceHandler {
-    pub fn new(handle: Arc<Mutex<DeviceHandle<GlobalContext>>>) -> Self {
-        Self { handle }
+impl<B> Clone for UsbHostDeviceHandler<B> {
+    fn clone(&self) -> Self {
+        Self {
+            handle: self.handle.clone(),
+            policy: self.policy.clone(),
+        }
     }
 }
 
-impl UsbDeviceHandler for UsbHostDeviceHandler {
+impl<B: UsbBackend> UsbHostDeviceHandler<B> {
+    pub fn new(handle: Arc<B>, policy: TransferPolicy) -> Self {
+        Self { handle, policy }
+    }
+}
+
+impl<B: UsbBackend + 'static> UsbDeviceHandler for UsbHostDeviceHandler<B> {
     fn handle_urb(
         &mut self,
         transfer_buffer_length: u32,
         setup: SetupPacket,
         req: &[u8],
-    ) -> Result<Vec<u8>> {
+    ) -> UrbResult {
         debug!("To host device: setup={:?} req={:?}", setup, req);
         let mut buffer = vec![0u8; transfer_buffer_length as usize];
-        let timeout = std::time::Duration::new(1, 0);
-        let handle = self.handle.lock().unwrap();
+        let policy = &self.policy;
+        let timeout = policy.control_timeout;
+        let handle = &self.handle;
         // control
         if setup.request_type & 0x80 == 0 {
             // control out
-            handle
-                .write_control(
-                    setup.request_type,
-                    setup.request,
-                    setup.value,
-                    setup.index,
-                    req,
-                    timeout,
-                )
-                .ok();
+            policy.run(false, || handle.write_control(&setup, req, timeout))?;
         } else {
             // control in
-            if let Ok(len) = handle.read_control(
-                setup.request_type,
-                setup.request,
-                setup.value,
-                setup.index,
-                &mut buffer,
-                timeout,
-            ) {
-                return Ok(Vec::from(&buffer[..len]));
-            }
+            let len = policy.run(true, || handle.read_control(&setup, &mut buffer, timeout))?;
+            return Ok(Vec::from(&buffer[..len]));
         }
         Ok(vec![])
     }
@@ -148,3 +816,231 @@ impl UsbDeviceHandler for UsbHostDeviceHandler {
         self
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::util::tests::*;
+
+    use super::*;
+
+    /// Echoes OUT transfers back on the next IN transfer of the same kind
+    #[derive(Default)]
+    struct LoopbackBackend {
+        last: Mutex<Vec<u8>>,
+    }
+
+    impl LoopbackBackend {
+        fn read(&self, buf: &mut [u8]) -> Result<usize> {
+            let last = self.last.lock().unwrap();
+            let len = last.len().min(buf.len());
+            buf[..len].copy_from_slice(&last[..len]);
+            Ok(len)
+        }
+
+        fn write(&self, data: &[u8]) -> Result<usize> {
+            *self.last.lock().unwrap() = data.to_vec();
+            Ok(data.len())
+        }
+    }
+
+    impl UsbBackend for LoopbackBackend {
+        fn read_control(&self, _: &SetupPacket, buf: &mut [u8], _: Duration) -> Result<usize> {
+            self.read(buf)
+        }
+
+        fn write_control(&self, _: &SetupPacket, data: &[u8], _: Duration) -> Result<usize> {
+            self.write(data)
+        }
+
+        fn read_interrupt(&self, _: u8, buf: &mut [u8], _: Duration) -> Result<usize> {
+            self.read(buf)
+        }
+
+        fn write_interrupt(&self, _: u8, data: &[u8], _: Duration) -> Result<usize> {
+            self.write(data)
+        }
+
+        fn read_bulk(&self, _: u8, buf: &mut [u8], _: Duration) -> Result<usize> {
+            self.read(buf)
+        }
+
+        fn write_bulk(&self, _: u8, data: &[u8], _: Duration) -> Result<usize> {
+            self.write(data)
+        }
+    }
+
+    #[cfg(feature = "nusb")]
+    #[test]
+    fn nusb_like_libusb() {
+        use nusb::transfer::{ControlType, Recipient, TransferError};
+        // the handlers look at the kinds of the errors, so both backends must
+        // fail alike
+        for (nusb, rusb) in [
+            (TransferError::Cancelled, rusb::Error::Timeout),
+            (TransferError::Stall, rusb::Error::Pipe),
+            (TransferError::Disconnected, rusb::Error::NoDevice),
+            (TransferError::Fault, rusb::Error::Io),
+        ] {
+            assert_eq!(from_nusb(nusb).kind(), from_rusb(rusb).kind());
+        }
+
+        let (control_type, recipient) = nusb_request_type(0b10100001).unwrap();
+        assert_eq!(control_type, ControlType::Class);
+        assert_eq!(recipient, Recipient::Interface);
+        let (control_type, recipient) = nusb_request_type(0b00000010).unwrap();
+        assert_eq!(control_type, ControlType::Standard);
+        assert_eq!(recipient, Recipient::Endpoint);
+        assert!(nusb_request_type(0b01100000).is_err());
+        assert!(nusb_request_type(0b00000100).is_err());
+
+        // whole packets, and at least one
+        assert_eq!(nusb_in_len(0, 64), 64);
+        assert_eq!(nusb_in_len(10, 64), 64);
+        assert_eq!(nusb_in_len(64, 64), 64);
+        assert_eq!(nusb_in_len(65, 64), 128);
+    }
+
+    #[test]
+    fn forwards_to_backend() {
+        setup_test_logger();
+        let backend = Arc::new(LoopbackBackend::default());
+        let mut handler = UsbHostInterfaceHandler::new(backend.clone(), TransferPolicy::default());
+        let intf = UsbInterface {
+            interface_class: 0,
+            interface_subclass: 0,
+            interface_protocol: 0,
+            endpoints: vec![],
+            string_interface: 0,
+            class_specific_descriptor: vec![],
+            handler: Arc::new(Mutex::new(Box::new(handler.clone()))),
+        };
+        let bulk = |address| UsbEndpoint {
+            address,
+            attributes: EndpointAttributes::Bulk as u8,
+            max_packet_size: 64,
+            interval: 0,
+        };
+
+        let res = handler.handle_urb(&intf, bulk(0x02), 0, SetupPacket::default(), &[1, 2, 3]);
+        assert!(res.unwrap().is_empty());
+        let res = handler.handle_urb(&intf, bulk(0x82), 2, SetupPacket::default(), &[]);
+        assert_eq!(res.unwrap(), [1, 2]);
+
+        let mut handler = UsbHostDeviceHandler::new(backend, TransferPolicy::default());
+        let setup = SetupPacket {
+            request_type: 0x80,
+            ..Default::default()
+        };
+        assert_eq!(handler.handle_urb(8, setup, &[]).unwrap(), [1, 2, 3]);
+    }
+
+    /// Fails bulk transfers with `error` `failures` times, and interrupt IN
+    /// transfers with timeouts
+    struct FlakyBackend {
+        error: ErrorKind,
+        failures: Mutex<u32>,
+    }
+
+    impl FlakyBackend {
+        fn fail(&self) -> Result<()> {
+            let mut failures = self.failures.lock().unwrap();
+            if *failures > 0 {
+                *failures -= 1;
+                return Err(self.error.into());
+            }
+            Ok(())
+        }
+    }
+
+    impl UsbBackend for FlakyBackend {
+        fn read_control(&self, _: &SetupPacket, _: &mut [u8], _: Duration) -> Result<usize> {
+            Ok(0)
+        }
+
+        fn write_control(&self, _: &SetupPacket, data: &[u8], _: Duration) -> Result<usize> {
+            Ok(data.len())
+        }
+
+        fn read_interrupt(&self, _: u8, _: &mut [u8], _: Duration) -> Result<usize> {
+            Err(ErrorKind::TimedOut.into())
+        }
+
+        fn write_interrupt(&self, _: u8, data: &[u8], _: Duration) -> Result<usize> {
+            Ok(data.len())
+        }
+
+        fn read_bulk(&self, _: u8, buf: &mut [u8], _: Duration) -> Result<usize> {
+            self.fail()?;
+            buf.fill(1);
+            Ok(buf.len())
+        }
+
+        fn write_bulk(&self, _: u8, data: &[u8], _: Duration) -> Result<usize> {
+            self.fail()?;
+            Ok(data.len())
+        }
+    }
+
+    #[test]
+    fn retries_transient_errors() {
+        setup_test_logger();
+        let policy = TransferPolicy {
+            retries: 2,
+            backoff: Duration::from_millis(1),
+            ..Default::default()
+        };
+        let endpoint = |address, attributes| UsbEndpoint {
+            address,
+            attributes: attributes as u8,
+            max_packet_size: 64,
+            interval: 0,
+        };
+        let handler = |error, failures| {
+            UsbHostInterfaceHandler::new(
+                Arc::new(FlakyBackend {
+                    error,
+                    failures: Mutex::new(failures),
+                }),
+                policy.clone(),
+            )
+        };
+        let intf = UsbInterface {
+            interface_class: 0,
+            interface_subclass: 0,
+            interface_protocol: 0,
+            endpoints: vec![],
+            string_interface: 0,
+            class_specific_descriptor: vec![],
+            handler: Arc::new(Mutex::new(Box::new(handler(ErrorKind::Other, 0)))),
+        };
+        let bulk_in = endpoint(0x82, EndpointAttributes::Bulk);
+        let bulk_out = endpoint(0x02, EndpointAttributes::Bulk);
+
+        // Up to two retries
+        let mut flaky = handler(ErrorKind::ResourceBusy, 2);
+        let res = flaky.handle_urb(&intf, bulk_in, 2, SetupPacket::default(), &[]);
+        assert_eq!(res.unwrap(), [1, 1]);
+        let mut flaky = handler(ErrorKind::Interrupted, 3);
+        let res = flaky.handle_urb(&intf, bulk_in, 2, SetupPacket::default(), &[]);
+        assert!(matches!(res, Err(UrbError::Other(err)) if err.kind() == ErrorKind::Interrupted));
+
+        // Timeouts are only retried for IN transfers
+        let mut flaky = handler(ErrorKind::TimedOut, 1);
+        let res = flaky.handle_urb(&intf, bulk_in, 2, SetupPacket::default(), &[]);
+        assert_eq!(res.unwrap(), [1, 1]);
+        let mut flaky = handler(ErrorKind::TimedOut, 1);
+        let res = flaky.handle_urb(&intf, bulk_out, 0, SetupPacket::default(), &[1]);
+        assert!(matches!(res, Err(UrbError::Timeout)));
+
+        // Other errors fail the URB right away
+        let mut flaky = handler(ErrorKind::NotFound, 1);
+        let res = flaky.handle_urb(&intf, bulk_out, 0, SetupPacket::default(), &[1]);
+        assert!(matches!(res, Err(UrbError::NoDevice)));
+
+        // An interrupt IN timing out has nothing to report
+        let mut flaky = handler(ErrorKind::Other, 0);
+        let interrupt_in = endpoint(0x81, EndpointAttributes::Interrupt);
+        let res = flaky.handle_urb(&intf, interrupt_in, 8, SetupPacket::default(), &[]);
+        assert!(res.unwrap().is_empty());
+    }
+}
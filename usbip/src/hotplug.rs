@@ -0,0 +1,212 @@
+//! Following devices as they are plugged into and removed from the host
+use super::*;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+
+enum HotplugEvent {
+    Arrived(Device<GlobalContext>),
+    Left(Device<GlobalContext>),
+}
+
+/// Forwards libusb hotplug callbacks, in which devices can't be opened, to
+/// the task that exports them
+struct Forward<F> {
+    filter: F,
+    events: mpsc::UnboundedSender<HotplugEvent>,
+}
+
+impl<F> rusb::Hotplug<GlobalContext> for Forward<F>
+where
+    F: FnMut(&Device<GlobalContext>) -> bool + Send,
+{
+    fn device_arrived(&mut self, device: Device<GlobalContext>) {
+        if (self.filter)(&device) {
+            self.events.send(HotplugEvent::Arrived(device)).ok();
+        }
+    }
+
+    fn device_left(&mut self, device: Device<GlobalContext>) {
+        self.events.send(HotplugEvent::Left(device)).ok();
+    }
+}
+
+/// Keeps the devices of a [UsbIpServer] in sync with the host, see
+/// [UsbIpServer::watch_host]
+///
+/// Stops when dropped.
+pub struct HostWatcher {
+    _registration: rusb::Registration<GlobalContext>,
+    stop: Arc<AtomicBool>,
+    event_thread: Option<std::thread::JoinHandle<()>>,
+    task: tokio::task::JoinHandle<()>,
+}
+
+impl Drop for HostWatcher {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        if let Some(thread) = self.event_thread.take() {
+            thread.join().ok();
+        }
+        self.task.abort();
+    }
+}
+
+impl UsbIpServer {
+    /// Export host devices matching `filter` as they are plugged in, and
+    /// unplug them from the server as they are removed
+    ///
+    /// Devices already present that the server doesn't export yet are
+    /// added too. A device removed while imported fails its URBs in flight,
+    /// then the connection that imported it is closed, see
+    /// [UsbIpServer::unplug_device]. Must be called from a tokio runtime, and
+    /// needs libusb hotplug support (not available on Windows).
+    pub fn watch_host<F>(self: &Arc<Self>, filter: F) -> Result<HostWatcher>
+    where
+        F: FnMut(&Device<GlobalContext>) -> bool + Send + 'static,
+    {
+        if !rusb::has_hotplug() {
+            return Err(std::io::Error::new(
+                ErrorKind::Unsupported,
+                "libusb has no hotplug support",
+            ));
+        }
+
+        let (events, mut pending) = mpsc::unbounded_channel();
+        let registration = rusb::HotplugBuilder::new()
+            .enumerate(true)
+            .register(
+                GlobalContext::default(),
+                Box::new(Forward { filter, events }),
+            )
+            .map_err(std::io::Error::other)?;
+
+        // libusb only calls back while events are handled
+        let stop = Arc::new(AtomicBool::new(false));
+        let event_thread = std::thread::spawn({
+            let stop = stop.clone();
+            move || {
+                while !stop.load(Ordering::Relaxed) {
+                    if let Err(err) =
+                        GlobalContext::default().handle_events(Some(Duration::from_millis(100)))
+                    {
+                        warn!("Error handling libusb events: {}", err);
+                        std::thread::sleep(Duration::from_millis(100));
+                    }
+                }
+            }
+        });
+
+        let server = self.clone();
+        let task = tokio::spawn(async move {
+            while let Some(event) = pending.recv().await {
+                match event {
+                    HotplugEvent::Arrived(dev) => {
+                        let bus_id = Self::bus_id(&dev);
+                        if server.has_device(&bus_id).await {
+                            continue;
+                        }
+                        let devices =
+                            tokio::task::spawn_blocking(move || Self::with_devices(vec![dev]))
+                                .await
+                                .unwrap_or_default();
+                        for device in devices {
+                            info!("Exporting {}", device.bus_id);
+                            server.add_device(device).await;
+                        }
+                    }
+                    HotplugEvent::Left(dev) => {
+                        let bus_id = Self::bus_id(&dev);
+                        if server.unplug_device(&bus_id).await.is_ok() {
+                            info!("{} was removed", bus_id);
+                        }
+                    }
+                }
+            }
+        });
+
+        Ok(HostWatcher {
+            _registration: registration,
+            stop,
+            event_thread: Some(event_thread),
+            task,
+        })
+    }
+
+    /// Bring the devices exported from the host in line with it once: export
+    /// the devices matching `filter` that the server doesn't have yet, and
+    /// unplug those that were removed
+    ///
+    /// This is what [UsbIpServer::watch_host] does as events arrive, for hosts
+    /// without hotplug support, or to catch up with devices that couldn't be
+    /// opened when they were plugged in.
+    pub async fn rescan_host<F>(&self, filter: F) -> Result<()>
+    where
+        F: FnMut(&Device<GlobalContext>) -> bool,
+    {
+        // The list can't be held across awaits
+        let (present, matching) = {
+            let list = rusb::devices().map_err(std::io::Error::other)?;
+            let present: HashSet<String> = list.iter().map(|dev| Self::bus_id(&dev)).collect();
+            let matching: Vec<_> = list.iter().filter(filter).collect();
+            (present, matching)
+        };
+
+        let mut arrived = vec![];
+        for dev in matching {
+            if !self.has_device(&Self::bus_id(&dev)).await {
+                arrived.push(dev);
+            }
+        }
+        if !arrived.is_empty() {
+            let devices = tokio::task::spawn_blocking(move || Self::with_devices(arrived))
+                .await
+                .unwrap_or_default();
+            for device in devices {
+                info!("Exporting {}", device.bus_id);
+                self.add_device(device).await;
+            }
+        }
+
+        for bus_id in self.devices.bus_ids().await {
+            // Simulated devices aren't on the host's buses
+            if Self::is_host_bus_id(&bus_id)
+                && !present.contains(&bus_id)
+                && self.unplug_device(&bus_id).await.is_ok()
+            {
+                info!("{} was removed", bus_id);
+            }
+        }
+        Ok(())
+    }
+
+    /// Whether `bus_id` is one [UsbIpServer::bus_id] gives, as opposed to
+    /// the `0-0-0` of simulated devices
+    fn is_host_bus_id(bus_id: &str) -> bool {
+        match bus_id.split_once('-') {
+            Some((bus, ports)) => {
+                bus.parse::<u8>().is_ok()
+                    && !ports.is_empty()
+                    && ports.split('.').all(|port| port.parse::<u8>().is_ok())
+            }
+            None => bus_id.starts_with("usb"),
+        }
+    }
+
+    async fn has_device(&self, bus_id: &str) -> bool {
+        self.devices.contains(bus_id).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn host_bus_ids() {
+        assert!(UsbIpServer::is_host_bus_id("1-2"));
+        assert!(UsbIpServer::is_host_bus_id("3-1.4.2"));
+        assert!(UsbIpServer::is_host_bus_id("usb1"));
+        assert!(!UsbIpServer::is_host_bus_id("0-0-0"));
+        assert!(!UsbIpServer::is_host_bus_id("1-"));
+    }
+}
@@ -15,6 +15,69 @@ pub struct UsbInterface {
     pub handler: Arc<Mutex<Box<dyn UsbInterfaceHandler + Send>>>,
 }
 
+impl UsbInterface {
+    /// Pass a URB to the handler of this interface
+    ///
+    /// The handler is only locked for the whole transfer if it has no
+    /// [UsbInterfaceHandler::concurrent] handler.
+    pub(crate) fn handle_urb(
+        &self,
+        ep: UsbEndpoint,
+        transfer_buffer_length: u32,
+        setup: SetupPacket,
+        req: &[u8],
+    ) -> UrbResult {
+        let concurrent = self.handler.lock().unwrap().concurrent();
+        match concurrent {
+            Some(handler) => {
+                handler.handle_concurrent_urb(self, ep, transfer_buffer_length, setup, req)
+            }
+            None => self.handler.lock().unwrap().handle_urb(
+                self,
+                ep,
+                transfer_buffer_length,
+                setup,
+                req,
+            ),
+        }
+    }
+
+    /// Clear the halt of `ep` in the handler of this interface
+    pub(crate) fn clear_halt(&self, ep: UsbEndpoint) -> UrbResult<()> {
+        self.handler.lock().unwrap().clear_halt(ep)
+    }
+
+    /// Pass an isochronous URB to the handler of this interface
+    pub(crate) fn handle_iso_urb(
+        &self,
+        ep: UsbEndpoint,
+        transfer_buffer_length: u32,
+        packets: &[IsoPacketDescriptor],
+        req: &[u8],
+    ) -> UrbResult<(Vec<u8>, Vec<IsoPacketDescriptor>)> {
+        let concurrent = self.handler.lock().unwrap().concurrent();
+        match concurrent {
+            Some(handler) => {
+                handler.handle_concurrent_iso_urb(self, ep, transfer_buffer_length, packets, req)
+            }
+            None => self.handler.lock().unwrap().handle_iso_urb(
+                self,
+                ep,
+                transfer_buffer_length,
+                packets,
+                req,
+            ),
+        }
+    }
+}
+
+fn iso_unsupported() -> UrbError {
+    UrbError::Other(std::io::Error::new(
+        ErrorKind::Unsupported,
+        "Isochronous transfers are not supported",
+    ))
+}
+
 /// A handler of a custom usb interface
 pub trait UsbInterfaceHandler {
     /// Return the class specific descriptor which is inserted between interface descriptor and endpoint descriptor
@@ -31,7 +94,54 @@ pub trait UsbInterfaceHandler {
         transfer_buffer_length: u32,
         setup: SetupPacket,
         req: &[u8],
-    ) -> Result<Vec<u8>>;
+    ) -> UrbResult;
+
+    /// Handle an isochronous URB targeting at this interface
+    ///
+    /// `packets` locate each packet in `req` for OUT transfers, or in the
+    /// returned buffer of `transfer_buffer_length` bytes for IN transfers. The
+    /// returned descriptors carry the `actual_length` and `status` of each
+    /// packet. The default fails the URB, which is fine for interfaces without
+    /// isochronous endpoints.
+    fn handle_iso_urb(
+        &mut self,
+        _interface: &UsbInterface,
+        _ep: UsbEndpoint,
+        _transfer_buffer_length: u32,
+        _packets: &[IsoPacketDescriptor],
+        _req: &[u8],
+    ) -> UrbResult<(Vec<u8>, Vec<IsoPacketDescriptor>)> {
+        Err(iso_unsupported())
+    }
+
+    /// Clear the halt of `ep`, after it stalled
+    ///
+    /// Called on CLEAR_FEATURE(ENDPOINT_HALT) from the client. The server
+    /// fails URBs to `ep` itself while it is halted, so the default does
+    /// nothing.
+    fn clear_halt(&mut self, _ep: UsbEndpoint) -> UrbResult<()> {
+        Ok(())
+    }
+
+    /// The client that imported the device let go of it
+    ///
+    /// Called once the connection ended (closed, cut off after
+    /// [crate::UsbIpServer::with_dead_peer_timeout], or evicted) or the client
+    /// imported another device, after its URBs completed. Handlers keeping
+    /// state for a client, such as a powered card with a verified PIN, should
+    /// drop it here so the next client starts afresh. The default does
+    /// nothing.
+    fn disconnected(&mut self) {}
+
+    /// Return a handler which can take URBs without this one being locked
+    ///
+    /// By default an interface handles one URB at a time, so a transfer that
+    /// blocks (e.g. an interrupt IN waiting for a touch) holds up the other
+    /// endpoints of the interface. Handlers whose state can be shared should
+    /// return a [UsbConcurrentHandler] here.
+    fn concurrent(&self) -> Option<Arc<dyn UsbConcurrentHandler>> {
+        None
+    }
 
     /// Helper to downcast to actual struct
     ///
@@ -43,3 +153,30 @@ pub trait UsbInterfaceHandler {
     /// ```
     fn as_any(&mut self) -> &mut dyn Any;
 }
+
+/// A handler of a usb interface which can handle URBs to different endpoints at the same time
+///
+/// URBs to the same endpoint are still handled one at a time, in order.
+pub trait UsbConcurrentHandler: Send + Sync {
+    /// Like [UsbInterfaceHandler::handle_urb], without exclusive access to the handler
+    fn handle_concurrent_urb(
+        &self,
+        interface: &UsbInterface,
+        ep: UsbEndpoint,
+        transfer_buffer_length: u32,
+        setup: SetupPacket,
+        req: &[u8],
+    ) -> UrbResult;
+
+    /// Like [UsbInterfaceHandler::handle_iso_urb], without exclusive access to the handler
+    fn handle_concurrent_iso_urb(
+        &self,
+        _interface: &UsbInterface,
+        _ep: UsbEndpoint,
+        _transfer_buffer_length: u32,
+        _packets: &[IsoPacketDescriptor],
+        _req: &[u8],
+    ) -> UrbResult<(Vec<u8>, Vec<IsoPacketDescriptor>)> {
+        Err(iso_unsupported())
+    }
+}
@@ -1,57 +1,435 @@
 //! A library for running a USB/IP server
 
-use log::*;
+// num-derive 0.3 expands `FromPrimitive` inside an anonymous const.
+#![allow(non_local_definitions)]
+
+extern crate alloc;
+
 use num_derive::FromPrimitive;
 use num_traits::FromPrimitive;
 use rusb::*;
 use std::any::Any;
-use std::collections::{HashMap, VecDeque};
+use std::collections::{HashMap, HashSet, VecDeque};
 use std::io::{ErrorKind, Result};
 use std::net::SocketAddr;
 use std::sync::{Arc, Mutex};
 use tokio::io::AsyncReadExt;
 use tokio::io::AsyncWriteExt;
-use tokio::net::TcpListener;
-use tokio::sync::RwLock;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::{mpsc, watch};
+use tokio::task::JoinSet;
+use tracing::*;
 use usbip_protocol::UsbIpCommand;
 
 #[cfg(feature = "serde")]
 use serde::{Deserialize, Serialize};
 
+pub mod acl;
+pub mod admin;
+#[cfg(feature = "apdu")]
+pub mod apdu;
+pub mod approval;
+pub mod audit;
+pub mod buffer;
+mod builder;
+pub mod ccid;
 pub mod cdc;
+pub mod client;
 mod consts;
+pub mod ctaphid;
 mod device;
 mod endpoint;
+pub mod events;
+mod filter;
 pub mod hid;
 mod host;
+mod hotplug;
 mod interface;
-mod setup;
+pub mod local;
+pub mod loopback;
+#[cfg(feature = "mdns")]
+pub mod mdns;
+pub mod metrics;
+#[cfg(feature = "oidc")]
+pub mod oidc;
+pub mod otp;
+pub mod pcap;
+#[cfg(feature = "pcsc")]
+pub mod pcsc;
+#[cfg(feature = "piv")]
+pub mod piv;
+#[cfg(feature = "quic")]
+pub mod quic;
+pub mod record;
+mod registry;
+pub mod throttle;
+#[cfg(feature = "tls")]
+pub mod tls;
+pub mod transport;
+mod urb;
 pub mod usbip_protocol;
 mod util;
+pub mod webusb;
+pub mod wire;
+pub use builder::*;
 pub use consts::*;
 pub use device::*;
 pub use endpoint::*;
+pub use filter::*;
 pub use host::*;
+pub use hotplug::*;
 pub use interface::*;
-pub use setup::*;
+pub use transport::Transport;
+pub use urb::*;
 pub use util::*;
+pub use wire::{IsoPacketDescriptor, SetupPacket};
 
-use crate::usbip_protocol::{UsbIpResponse, USBIP_RET_SUBMIT, USBIP_RET_UNLINK};
+use crate::usbip_protocol::{UsbIpHeaderBasic, UsbIpResponse, USBIP_RET_SUBMIT, USBIP_RET_UNLINK};
 
 /// Main struct of a USB/IP server
-#[derive(Default)]
 pub struct UsbIpServer {
-    available_devices: RwLock<Vec<UsbDevice>>,
-    used_devices: RwLock<HashMap<String, UsbDevice>>,
+    devices: registry::Registry,
+    policy: Option<acl::Policy>,
+    /// How long a connection may hold a device it imported
+    max_lease: Option<std::time::Duration>,
+    /// Whether APDUs that change a smart card are refused
+    read_only: bool,
+    /// The approvals of key use waited for, if they are asked for
+    approvals: Option<Arc<approval::Approvals>>,
+    /// How long a client may stay silent before its connection is closed
+    dead_peer_timeout: Option<std::time::Duration>,
+    /// Changed whenever a connection loses the device it imported, because
+    /// it was unplugged or the import revoked
+    evicted: watch::Sender<()>,
+    events: tokio::sync::broadcast::Sender<events::Event>,
+    audit: Option<mpsc::UnboundedSender<audit::Record>>,
+    metrics: metrics::Metrics,
+    /// Rate limits of clients, and throttling of their PIN guesses
+    throttle: throttle::Throttle,
+    /// Buffers the responses of connections are written from
+    buffers: buffer::BufferPool,
+    /// Where the URBs to the devices are recorded
+    trace: Option<Arc<record::Trace>>,
+    /// Where the URBs clients submit are captured for Wireshark
+    capture: Option<Arc<pcap::Capture>>,
+    /// How long URBs may take before they are warned about
+    latency_budget: Option<metrics::LatencyBudget>,
+    /// The cards APDU sessions share, by bus id, if they may
+    #[cfg(feature = "apdu")]
+    shared_cards: Option<tokio::sync::Mutex<HashMap<String, std::sync::Weak<apdu::SharedCard>>>>,
+    #[cfg(feature = "oidc")]
+    oidc: Option<oidc::Validator>,
+}
+
+impl Default for UsbIpServer {
+    fn default() -> Self {
+        Self {
+            devices: Default::default(),
+            policy: None,
+            max_lease: None,
+            read_only: false,
+            approvals: None,
+            dead_peer_timeout: None,
+            evicted: Default::default(),
+            events: tokio::sync::broadcast::channel(events::EVENT_QUEUE_LENGTH).0,
+            audit: None,
+            metrics: Default::default(),
+            throttle: Default::default(),
+            buffers: Default::default(),
+            trace: None,
+            capture: None,
+            latency_budget: None,
+            #[cfg(feature = "apdu")]
+            shared_cards: None,
+            #[cfg(feature = "oidc")]
+            oidc: None,
+        }
+    }
 }
 
 impl UsbIpServer {
     /// Create a [UsbIpServer] with simulated devices
     pub fn new_simulated(devices: Vec<UsbDevice>) -> Self {
         Self {
-            available_devices: RwLock::new(devices),
-            used_devices: RwLock::new(HashMap::new()),
+            devices: registry::Registry::new(devices),
+            ..Default::default()
+        }
+    }
+
+    /// Follow the [events::Event]s of the exported devices, as subscribed
+    /// clients get them
+    pub fn subscribe(&self) -> tokio::sync::broadcast::Receiver<events::Event> {
+        self.events.subscribe()
+    }
+
+    /// What the server did so far, see [metrics]
+    pub fn metrics(&self) -> &metrics::Metrics {
+        &self.metrics
+    }
+
+    /// Only let clients import the devices `policy` allows them to
+    pub fn with_policy(mut self, policy: acl::Policy) -> Self {
+        self.policy = Some(policy);
+        self
+    }
+
+    /// Send [audit::Record]s of what clients do to `records`
+    pub fn with_audit(mut self, records: mpsc::UnboundedSender<audit::Record>) -> Self {
+        self.audit = Some(records);
+        self
+    }
+
+    /// Validate the bearer tokens clients send with `validator`, so that
+    /// [acl::Policy] rules can match their claims
+    #[cfg(feature = "oidc")]
+    pub fn with_oidc(mut self, validator: oidc::Validator) -> Self {
+        self.oidc = Some(validator);
+        self
+    }
+
+    /// Revoke imports after `max_lease`, so that a client that hangs or
+    /// forgets a device doesn't keep it from others
+    ///
+    /// The connection is closed, and the client may import the device again.
+    pub fn with_max_lease(mut self, max_lease: std::time::Duration) -> Self {
+        self.max_lease = Some(max_lease);
+        self
+    }
+
+    /// Refuse the APDUs that may change a smart card (see
+    /// [audit::changes_card]), e.g. generating keys or changing PINs, and
+    /// writes to the other interfaces of devices, e.g. resetting a FIDO
+    /// authenticator or configuring an OTP keyboard, for devices clients
+    /// share but must not reprovision
+    ///
+    /// Cards can still be listed, and their keys used to sign and decrypt.
+    pub fn with_read_only(mut self, read_only: bool) -> Self {
+        self.read_only = read_only;
+        self
+    }
+
+    /// Hold the APDUs that use the keys of smart cards until they are
+    /// approved on the host, for up to `timeout`, see [approval]
+    pub fn with_approval(mut self, timeout: std::time::Duration) -> Self {
+        self.approvals = Some(Arc::new(approval::Approvals::new(timeout)));
+        self
+    }
+
+    /// Let the APDU sessions with a device share its card, instead of each
+    /// importing it exclusively, e.g. for a team to decrypt with one key
+    ///
+    /// Sessions take turns with the card: each has its APDUs answered in
+    /// order, and the application it selected selected anew whenever the card
+    /// answered another session in between, which also ends what the other
+    /// session verified. Who may join is decided as for imports, and what
+    /// they may send by their [acl::Role]; see also [Self::with_read_only].
+    #[cfg(feature = "apdu")]
+    pub fn with_card_sharing(mut self, share: bool) -> Self {
+        self.shared_cards = share.then(Default::default);
+        self
+    }
+
+    /// Close the connections of clients that sent nothing for `timeout`,
+    /// releasing the device they imported
+    ///
+    /// A client whose connection silently died, e.g. when a NAT dropped the
+    /// mapping of an idle import, would otherwise keep the device until TCP
+    /// gives up, which can take hours. Clients must send something more
+    /// often, such as the pings of [client::ImportedDevice::with_keep_alive];
+    /// the Linux vhci-hcd doesn't, so leave this off for its clients.
+    pub fn with_dead_peer_timeout(mut self, timeout: std::time::Duration) -> Self {
+        self.dead_peer_timeout = Some(timeout);
+        self
+    }
+
+    /// Make clients wait once they sent more commands than `limit` allows,
+    /// with a token bucket for each, see [throttle]
+    pub fn with_rate_limit(mut self, limit: throttle::RateLimit) -> Self {
+        self.throttle.rate_limit = Some(limit);
+        self
+    }
+
+    /// Hold up the PIN and PUK checks to a smart card after failed ones,
+    /// first for `delay`, then twice as long after each further failure
+    /// until one succeeds, see [throttle]
+    pub fn with_pin_throttle(mut self, delay: std::time::Duration) -> Self {
+        self.throttle.pin_delay = Some(delay);
+        self
+    }
+
+    /// Record the URBs to the devices to `trace`, those exported now and
+    /// those added later, see [record]
+    pub fn with_trace(mut self, trace: record::Trace) -> Self {
+        let trace = Arc::new(trace);
+        for device in self.devices.get_mut() {
+            record::record(device, &trace);
+        }
+        self.trace = Some(trace);
+        self
+    }
+
+    /// Capture the URBs clients submit, and their completions, to
+    /// `capture`, for Wireshark, see [pcap]
+    pub fn with_capture(mut self, capture: pcap::Capture) -> Self {
+        self.capture = Some(Arc::new(capture));
+        self
+    }
+
+    /// Warn about the URBs whose handlers take longer than `budget` allows:
+    /// slow devices, slow links to the cards they forward to, or devices
+    /// waiting for a touch, see [metrics::LatencyBudget]
+    pub fn with_latency_budget(mut self, budget: metrics::LatencyBudget) -> Self {
+        self.latency_budget = Some(budget);
+        self
+    }
+
+    /// Make the device with `bus_id` available again, if `connection` still
+    /// has it imported, and tell its handlers
+    async fn release(&self, bus_id: &str, connection: registry::ConnectionId) {
+        if let Some(device) = self.devices.release(bus_id, connection).await {
+            tokio::task::spawn_blocking(move || device.disconnected())
+                .await
+                .ok();
+        }
+    }
+
+    /// The bus id of `dev` as Linux names it, e.g. `1-2.3` for port 3 of a hub
+    /// on port 2 of bus 1, so it can be used with the `usbip` tools
+    fn bus_id(dev: &Device<GlobalContext>) -> String {
+        Self::format_bus_id(dev.bus_number(), &dev.port_numbers().unwrap_or_default())
+    }
+
+    fn format_bus_id(bus_number: u8, ports: &[u8]) -> String {
+        if ports.is_empty() {
+            // root hubs have no port
+            return format!("usb{}", bus_number);
         }
+        let ports: Vec<_> = ports.iter().map(|port| port.to_string()).collect();
+        format!("{}-{}", bus_number, ports.join("."))
+    }
+
+    /// The bus number of the device nusb enumerated as `info`
+    #[cfg(feature = "nusb")]
+    fn nusb_bus_number(info: &nusb::DeviceInfo) -> u8 {
+        #[cfg(target_os = "linux")]
+        return info.busnum();
+        // macOS numbers buses in hex
+        #[cfg(not(target_os = "linux"))]
+        return u8::from_str_radix(info.bus_id(), 16).unwrap_or(0);
+    }
+
+    /// Like [UsbIpServer::with_devices], with nusb
+    #[cfg(feature = "nusb")]
+    fn with_nusb_devices(device_list: Vec<nusb::DeviceInfo>) -> Vec<UsbDevice> {
+        use nusb::MaybeFuture;
+        let mut devices = vec![];
+
+        for info in device_list {
+            let backend = match info.open().wait().map_err(std::io::Error::from) {
+                Ok(device) => host::NusbBackend::new(device),
+                Err(err) => Err(err),
+            };
+            let backend = match backend {
+                Ok(backend) => Arc::new(backend),
+                Err(err) => {
+                    warn!("Impossible to share {:?}: {}, ignoring device", info, err);
+                    continue;
+                }
+            };
+            let desc = backend.device().device_descriptor();
+            let Ok(cfg) = backend.device().active_configuration() else {
+                warn!(
+                    "Impossible to get config descriptor for {:?}, ignoring device",
+                    info
+                );
+                continue;
+            };
+
+            let mut interfaces = vec![];
+            for intf in cfg.interfaces() {
+                // ignore alternate settings
+                let intf_desc = intf.first_alt_setting();
+                let endpoints = intf_desc
+                    .endpoints()
+                    .map(|ep_desc| UsbEndpoint {
+                        address: ep_desc.address(),
+                        attributes: ep_desc.transfer_type() as u8,
+                        max_packet_size: ep_desc.max_packet_size() as u16,
+                        interval: ep_desc.interval(),
+                    })
+                    .collect();
+                // the class specific descriptors come before the endpoints
+                let class_specific_descriptor = intf_desc
+                    .descriptors()
+                    .take_while(|desc| desc.descriptor_type() != 5)
+                    .flat_map(|desc| desc.to_vec())
+                    .collect();
+
+                let handler = Arc::new(Mutex::new(Box::new(UsbHostInterfaceHandler::new(
+                    backend.clone(),
+                    TransferPolicy::default(),
+                ))
+                    as Box<dyn UsbInterfaceHandler + Send>));
+                interfaces.push(UsbInterface {
+                    interface_class: intf_desc.class(),
+                    interface_subclass: intf_desc.subclass(),
+                    interface_protocol: intf_desc.protocol(),
+                    endpoints,
+                    string_interface: intf_desc.string_index().map_or(0, |index| index.get()),
+                    class_specific_descriptor,
+                    handler,
+                });
+            }
+            let bus_number = Self::nusb_bus_number(&info);
+            let bus_id = Self::format_bus_id(bus_number, info.port_chain());
+            let mut device = UsbDevice {
+                path: format!("/sys/bus/usb/devices/{}", bus_id),
+                bus_id,
+                bus_num: bus_number as u32,
+                dev_num: info.device_address() as u32,
+                speed: info.speed().map_or(UsbSpeed::Unknown, UsbSpeed::from) as u32,
+                vendor_id: desc.vendor_id(),
+                product_id: desc.product_id(),
+                device_class: desc.class(),
+                device_subclass: desc.subclass(),
+                device_protocol: desc.protocol(),
+                device_bcd: rusb::Version::from_bcd(desc.device_version()).into(),
+                configuration_value: cfg.configuration_value(),
+                num_configurations: desc.num_configurations(),
+                ep0_in: UsbEndpoint {
+                    address: 0x80,
+                    attributes: EndpointAttributes::Control as u8,
+                    max_packet_size: desc.max_packet_size_0() as u16,
+                    interval: 0,
+                },
+                ep0_out: UsbEndpoint {
+                    address: 0x00,
+                    attributes: EndpointAttributes::Control as u8,
+                    max_packet_size: desc.max_packet_size_0() as u16,
+                    interval: 0,
+                },
+                interfaces,
+                device_handler: Some(Arc::new(Mutex::new(Box::new(UsbHostDeviceHandler::new(
+                    backend.clone(),
+                    TransferPolicy::default(),
+                ))))),
+                usb_version: rusb::Version::from_bcd(desc.usb_version()).into(),
+                ..UsbDevice::default()
+            };
+
+            // nusb reads the strings while enumerating
+            if let Some(manufacturer) = info.manufacturer_string() {
+                device.string_manufacturer = device.new_string(manufacturer)
+            }
+            if let Some(product) = info.product_string() {
+                device.string_product = device.new_string(product)
+            }
+            if let Some(serial) = info.serial_number() {
+                device.string_serial = device.new_string(serial)
+            }
+            devices.push(device);
+        }
+        devices
     }
 
     fn with_devices(device_list: Vec<Device<GlobalContext>>) -> Vec<UsbDevice> {
@@ -86,21 +464,12 @@ impl UsbIpServer {
                 }
             };
 
-            let handle = Arc::new(Mutex::new(open_device));
+            open_device.set_auto_detach_kernel_driver(true).ok();
+            let handle = Arc::new(open_device);
             let mut interfaces = vec![];
-            handle
-                .lock()
-                .unwrap()
-                .set_auto_detach_kernel_driver(true)
-                .ok();
             for intf in cfg.interfaces() {
                 // ignore alternate settings
                 let intf_desc = intf.descriptors().next().unwrap();
-                handle
-                    .lock()
-                    .unwrap()
-                    .set_auto_detach_kernel_driver(true)
-                    .ok();
                 let mut endpoints = vec![];
 
                 for ep_desc in intf_desc.endpoint_descriptors() {
@@ -114,6 +483,7 @@ impl UsbIpServer {
 
                 let handler = Arc::new(Mutex::new(Box::new(UsbHostInterfaceHandler::new(
                     handle.clone(),
+                    TransferPolicy::default(),
                 ))
                     as Box<dyn UsbInterfaceHandler + Send>));
                 interfaces.push(UsbInterface {
@@ -126,22 +496,13 @@ impl UsbIpServer {
                     handler,
                 });
             }
+            let bus_id = Self::bus_id(&dev);
             let mut device = UsbDevice {
-                path: format!(
-                    "/sys/bus/{}/{}/{}",
-                    dev.bus_number(),
-                    dev.address(),
-                    dev.port_number()
-                ),
-                bus_id: format!(
-                    "{}-{}-{}",
-                    dev.bus_number(),
-                    dev.address(),
-                    dev.port_number()
-                ),
+                path: format!("/sys/bus/usb/devices/{}", bus_id),
+                bus_id,
                 bus_num: dev.bus_number() as u32,
-                dev_num: dev.port_number() as u32,
-                speed: dev.speed() as u32,
+                dev_num: dev.address() as u32,
+                speed: UsbSpeed::from(dev.speed()) as u32,
                 vendor_id: desc.vendor_id(),
                 product_id: desc.product_id(),
                 device_class: desc.class_code(),
@@ -165,6 +526,7 @@ impl UsbIpServer {
                 interfaces,
                 device_handler: Some(Arc::new(Mutex::new(Box::new(UsbHostDeviceHandler::new(
                     handle.clone(),
+                    TransferPolicy::default(),
                 ))))),
                 usb_version: desc.usb_version().into(),
                 ..UsbDevice::default()
@@ -172,31 +534,16 @@ impl UsbIpServer {
 
             // set strings
             if let Some(index) = desc.manufacturer_string_index() {
-                device.string_manufacturer = device.new_string(
-                    &handle
-                        .lock()
-                        .unwrap()
-                        .read_string_descriptor_ascii(index)
-                        .unwrap(),
-                )
+                device.string_manufacturer =
+                    device.new_string(&handle.read_string_descriptor_ascii(index).unwrap())
             }
             if let Some(index) = desc.product_string_index() {
-                device.string_product = device.new_string(
-                    &handle
-                        .lock()
-                        .unwrap()
-                        .read_string_descriptor_ascii(index)
-                        .unwrap(),
-                )
+                device.string_product =
+                    device.new_string(&handle.read_string_descriptor_ascii(index).unwrap())
             }
             if let Some(index) = desc.serial_number_string_index() {
-                device.string_serial = device.new_string(
-                    &handle
-                        .lock()
-                        .unwrap()
-                        .read_string_descriptor_ascii(index)
-                        .unwrap(),
-                )
+                device.string_serial =
+                    device.new_string(&handle.read_string_descriptor_ascii(index).unwrap())
             }
             devices.push(device);
         }
@@ -204,6 +551,9 @@ impl UsbIpServer {
     }
 
     /// Create a [UsbIpServer] exposing devices in the host, and redirect all USB transfers to them using libusb
+    ///
+    /// This exports every device, keyboards and disks included; see
+    /// [UsbIpServer::new_from_host_with_filter] and [DeviceFilter].
     pub fn new_from_host() -> Self {
         match rusb::devices() {
             Ok(list) => {
@@ -212,7 +562,7 @@ impl UsbIpServer {
                     devs.push(d)
                 }
                 Self {
-                    available_devices: RwLock::new(Self::with_devices(devs)),
+                    devices: registry::Registry::new(Self::with_devices(devs)),
                     ..Default::default()
                 }
             }
@@ -220,6 +570,8 @@ impl UsbIpServer {
         }
     }
 
+    /// Like [UsbIpServer::new_from_host], but only exposing the devices `filter`
+    /// accepts, e.g. `|dev| DeviceFilter::yubikeys().matches(dev)`
     pub fn new_from_host_with_filter<F>(filter: F) -> Self
     where
         F: FnMut(&Device<GlobalContext>) -> bool,
@@ -231,7 +583,7 @@ impl UsbIpServer {
                     devs.push(d)
                 }
                 Self {
-                    available_devices: RwLock::new(Self::with_devices(devs)),
+                    devices: registry::Registry::new(Self::with_devices(devs)),
                     ..Default::default()
                 }
             }
@@ -239,159 +591,658 @@ impl UsbIpServer {
         }
     }
 
+    /// Like [UsbIpServer::new_from_host_with_filter], but reaching the
+    /// devices with nusb instead of libusb, e.g.
+    /// `|info| DeviceFilter::yubikeys().matches_nusb(info)`
+    #[cfg(feature = "nusb")]
+    pub fn new_from_nusb_host_with_filter<F>(filter: F) -> Self
+    where
+        F: FnMut(&nusb::DeviceInfo) -> bool,
+    {
+        use nusb::MaybeFuture;
+        match nusb::list_devices().wait() {
+            Ok(list) => Self {
+                devices: registry::Registry::new(Self::with_nusb_devices(
+                    list.filter(filter).collect(),
+                )),
+                ..Default::default()
+            },
+            Err(_) => Default::default(),
+        }
+    }
+
+    /// Whether the [acl::Policy] lets `peer` import `device`
+    fn may_import(&self, peer: &acl::Peer, device: &UsbDevice) -> bool {
+        let allowed = self
+            .policy
+            .as_ref()
+            .is_none_or(|policy| policy.allows(peer, device));
+        if !allowed {
+            warn!("Denied import of {} by {:?}", device.bus_id, peer);
+        }
+        allowed
+    }
+
+    /// The [acl::Role] `peer` gets when it imports `device`
+    fn role(&self, peer: &acl::Peer, device: &UsbDevice) -> acl::Role {
+        self.policy
+            .as_ref()
+            .and_then(|policy| policy.role(peer, device))
+            .unwrap_or_default()
+    }
+
+    /// Whether to refuse `transfer` from a client with `role`, see
+    /// [Self::with_read_only] and [acl::Role]
+    fn refuses(&self, role: acl::Role, transfer: audit::Transfer) -> bool {
+        match transfer {
+            audit::Transfer::Apdu(ins) => {
+                (self.read_only && audit::changes_card(ins)) || !role.allows(ins)
+            }
+            audit::Transfer::Other => false,
+            // Roles only know the APDUs of smart cards
+            audit::Transfer::Read => role != acl::Role::Admin,
+            audit::Transfer::Write => self.read_only || role != acl::Role::Admin,
+        }
+    }
+
+    /// Export `device`, replacing an available device with the same bus id
     pub async fn add_device(&self, device: UsbDevice) {
-        self.available_devices.write().await.push(device);
+        if let Some(trace) = &self.trace {
+            record::record(&device, trace);
+        }
+        self.devices.add(device).await;
     }
 
+    /// Stop exporting the device with `bus_id`, unless it is imported
     pub async fn remove_device(&self, bus_id: &str) -> Result<()> {
-        let mut available_devices = self.available_devices.write().await;
+        self.devices.remove(bus_id).await
+    }
 
-        if let Some(device) = available_devices.iter().position(|d| d.bus_id == bus_id) {
-            available_devices.remove(device);
-            Ok(())
-        } else if let Some(device) = self
-            .used_devices
-            .read()
-            .await
-            .values()
-            .find(|d| d.bus_id == bus_id)
+    /// Remove the device with `bus_id`, even while it is imported
+    ///
+    /// The connection that imported it answers the URBs it has in flight,
+    /// which fail if the device is gone, and is then closed.
+    pub async fn unplug_device(&self, bus_id: &str) -> Result<()> {
+        if self.devices.unplug(bus_id).await? {
+            self.evicted.send_replace(());
+        }
+        Ok(())
+    }
+
+    /// Approve or deny the key use the [events::Event::ApprovalRequired]
+    /// with `id` asks for, see [Self::with_approval]
+    pub fn answer_approval(&self, id: u64, approved: bool) -> Result<()> {
+        match &self.approvals {
+            Some(approvals) => approvals.answer(id, approved),
+            None => Err(std::io::Error::new(
+                ErrorKind::Unsupported,
+                "Approvals aren't asked for",
+            )),
+        }
+    }
+
+    /// Take the device with `bus_id` away from the connection that imported
+    /// it, e.g. because its client crashed without closing the connection
+    ///
+    /// The connection answers the URBs it has in flight and is closed, and
+    /// the device is then available again.
+    pub async fn revoke(&self, bus_id: &str) -> Result<()> {
+        self.devices.revoke(bus_id).await?;
+        info!("Revoked the import of {}", bus_id);
+        self.evicted.send_replace(());
+        Ok(())
+    }
+}
+
+/// Speak USB/IP with the client on the other end of `socket`, known to
+/// [acl::Policy] as [Transport::peer] tells, see also [serve_connection]
+pub async fn handler<T: Transport>(socket: &mut T, server: Arc<UsbIpServer>) -> Result<()> {
+    let peer = socket.peer();
+    let approver = approval::Approver::new(&server, &peer);
+    handler_with_shutdown(socket, server, &peer, None, approver).await
+}
+
+/// Resolves once `shutdown` is set, or never if there is no sender left
+async fn shutdown_requested(shutdown: &mut Option<watch::Receiver<bool>>) {
+    if let Some(shutdown) = shutdown {
+        while !*shutdown.borrow_and_update() {
+            if shutdown.changed().await.is_err() {
+                break;
+            }
+        }
+        if *shutdown.borrow() {
+            return;
+        }
+    }
+    std::future::pending().await
+}
+
+/// Resolves at `end`, or never if there is none
+async fn deadline(end: Option<tokio::time::Instant>) {
+    match end {
+        Some(end) => tokio::time::sleep_until(end).await,
+        None => std::future::pending().await,
+    }
+}
+
+/// A USBIP_CMD_SUBMIT waiting to be handled
+struct Urb {
+    header: UsbIpHeaderBasic,
+    transfer_buffer_length: u32,
+    start_frame: u32,
+    setup: [u8; 8],
+    data: Vec<u8>,
+    iso_packet_descriptor: Vec<u8>,
+    /// Spans the URB from its submission to its completion, entered while
+    /// its handler runs
+    span: Span,
+}
+
+/// Handle `urb` and build the USBIP_RET_SUBMIT for it
+fn submit_urb(device: &UsbDevice, mut urb: Urb) -> UsbIpResponse {
+    let out = urb.header.direction == 0;
+    let real_ep = if out {
+        urb.header.ep
+    } else {
+        urb.header.ep | 0x80
+    };
+
+    urb.header.command = USBIP_RET_SUBMIT.into();
+
+    match device.find_ep(real_ep as u8) {
+        None => {
+            warn!("Endpoint {:02x?} not found", real_ep);
+            UsbIpResponse::usbip_ret_submit_fail(&urb.header)
+        }
+        Some((ep, Some(intf))) if ep.attributes == EndpointAttributes::Isochronous as u8 => {
+            trace!("->Endpoint {:02x?}", ep);
+            trace!("->Request {:02x?}", urb.data);
+            match submit_iso_urb(intf, ep, &urb) {
+                Ok(res) => res,
+                Err(err) => {
+                    warn!("Error handling isochronous URB: {}", err);
+                    UsbIpResponse::usbip_ret_submit_error(&urb.header, err.status())
+                }
+            }
+        }
+        Some((ep, intf)) => {
+            trace!("->Endpoint {:02x?}", ep);
+            trace!("->Setup {:02x?}", urb.setup);
+            trace!("->Request {:02x?}", urb.data);
+            let resp = device.handle_urb(
+                ep,
+                intf,
+                urb.transfer_buffer_length,
+                SetupPacket::parse(&urb.setup),
+                &urb.data,
+            );
+
+            match resp {
+                Ok(resp) => {
+                    if out {
+                        trace!("<-Wrote {}", urb.data.len());
+                    } else {
+                        trace!("<-Resp {:02x?}", resp);
+                    }
+                    UsbIpResponse::usbip_ret_submit_success(&urb.header, 0, 0, resp, vec![])
+                }
+                Err(err) => {
+                    warn!("Error handling URB: {}", err);
+                    UsbIpResponse::usbip_ret_submit_error(&urb.header, err.status())
+                }
+            }
+        }
+    }
+}
+
+/// Handle an isochronous `urb` to `ep` of `intf`
+fn submit_iso_urb(intf: &UsbInterface, ep: UsbEndpoint, urb: &Urb) -> UrbResult<UsbIpResponse> {
+    let packets = IsoPacketDescriptor::decode_all(&urb.iso_packet_descriptor)
+        .map_err(|err| std::io::Error::new(ErrorKind::InvalidData, err.to_string()))?;
+    let (buffer, mut packets) =
+        intf.handle_iso_urb(ep, urb.transfer_buffer_length, &packets, &urb.data)?;
+
+    // IN data goes back without the gaps between packets
+    let mut data = vec![];
+    for packet in packets.iter_mut() {
+        packet.actual_length = packet.actual_length.min(packet.length);
+        if let Direction::In = ep.direction() {
+            let start = (packet.offset as usize).min(buffer.len());
+            let end = (start + packet.actual_length as usize).min(buffer.len());
+            data.extend_from_slice(&buffer[start..end]);
+        }
+    }
+    trace!("<-Iso packets {:?}", packets);
+    Ok(UsbIpResponse::usbip_ret_submit_iso(
+        &urb.header,
+        urb.start_frame,
+        data,
+        &packets,
+    ))
+}
+
+/// Sequence numbers of the URBs of a connection that were submitted, and
+/// neither completed nor unlinked yet
+type InFlight = Arc<Mutex<HashSet<u32>>>;
+
+/// Handle the URBs queued for one endpoint, in order
+///
+/// Each endpoint of an imported device gets its own worker, and handlers run
+/// on the blocking thread pool, so a transfer that waits on the device (e.g.
+/// for a touch) only holds up later URBs to the same endpoint.
+///
+/// URBs unlinked while queued are skipped. A handler already running can't
+/// be interrupted, so a URB unlinked meanwhile still holds up the endpoint
+/// until it returns, and its result is dropped.
+#[allow(clippy::too_many_arguments)]
+async fn endpoint_worker(
+    device: Arc<UsbDevice>,
+    mut urbs: mpsc::UnboundedReceiver<Urb>,
+    responses: mpsc::UnboundedSender<UsbIpResponse>,
+    in_flight: InFlight,
+    events: tokio::sync::broadcast::Sender<events::Event>,
+    auditor: audit::Auditor,
+    metrics: Arc<metrics::DeviceMetrics>,
+    pins: Option<throttle::PinWatcher>,
+    approver: Option<approval::Approver>,
+    capture: Option<Arc<pcap::Capture>>,
+    latency_budget: Option<metrics::LatencyBudget>,
+) {
+    let mut watcher = events::WaitWatcher::default();
+    while let Some(urb) = urbs.recv().await {
+        let header = urb.header.clone();
+        let seqnum = header.seqnum;
+        if let Some(delay) = pins
+            .as_ref()
+            .and_then(|pins| pins.before(&device, &header, &urb.data))
         {
-            Err(std::io::Error::new(
-                ErrorKind::Other,
-                format!("Device {} is in use", device.bus_id),
-            ))
-        } else {
-            Err(std::io::Error::new(
-                ErrorKind::NotFound,
-                format!("Device {} not found", bus_id),
-            ))
+            warn!(
+                "Holding up a PIN check to {} for {:?} after failed ones",
+                device.bus_id, delay
+            );
+            tokio::time::sleep(delay).await;
+        }
+        if let Some(approver) = &approver {
+            if !approver.approve_urb(&device, &header, &urb.data).await {
+                let mut header = header.clone();
+                header.command = USBIP_RET_SUBMIT.into();
+                if in_flight.lock().unwrap().remove(&seqnum)
+                    && responses
+                        .send(UsbIpResponse::usbip_ret_submit_fail(&header))
+                        .is_err()
+                {
+                    return;
+                }
+                continue;
+            }
+        }
+        if !in_flight.lock().unwrap().contains(&seqnum) {
+            trace!("Skipping unlinked URB {}", seqnum);
+            continue;
+        }
+        let apdu = auditor.apdu(&device, &header, &urb.data);
+        // Unique to the connection and URB
+        let id = auditor.connection << 32 | seqnum as u64;
+        if let Some(capture) = &capture {
+            capture.submit(id, &device, &urb);
+        }
+        let handler_device = device.clone();
+        let span = urb.span.clone();
+        let start = std::time::Instant::now();
+        let res = match tokio::task::spawn_blocking(move || {
+            let span = urb.span.clone();
+            span.in_scope(|| submit_urb(&handler_device, urb))
+        })
+        .await
+        {
+            Ok(res) => res,
+            Err(err) => {
+                warn!("Handler for URB {} panicked: {}", header.seqnum, err);
+                let mut header = header.clone();
+                header.command = USBIP_RET_SUBMIT.into();
+                UsbIpResponse::usbip_ret_submit_fail(&header)
+            }
+        };
+        let elapsed = start.elapsed();
+        metrics.observe(&header, &res, elapsed);
+        if let UsbIpResponse::UsbIpRetSubmit { status, .. } = &res {
+            debug!(parent: &span, status = *status as i32, elapsed = ?elapsed, "Completed URB");
+        }
+        if let Some(budget) = &latency_budget {
+            budget.check(&device, &header, elapsed);
+        }
+        if let Some(capture) = &capture {
+            capture.complete(id, &device, &header, &res);
+        }
+        auditor.count(apdu, &res);
+        if watcher.observe(&device, &header, &res, &events) {
+            auditor.record(audit::Record::TouchRequired {
+                connection: auditor.connection,
+                bus_id: device.bus_id.clone(),
+            });
+        }
+        if let Some(failures) = pins
+            .as_ref()
+            .and_then(|pins| pins.after(&device, &header, &res))
+        {
+            warn!(
+                "{} failed PIN checks in a row on {}",
+                failures, device.bus_id
+            );
+            auditor.record(audit::Record::PinFailed {
+                connection: auditor.connection,
+                bus_id: device.bus_id.clone(),
+                failures,
+            });
+        }
+        // Under the lock, so that a USBIP_RET_UNLINK for this URB can't
+        // overtake its USBIP_RET_SUBMIT
+        let mut in_flight = in_flight.lock().unwrap();
+        if !in_flight.remove(&seqnum) {
+            debug!("Dropping the response to unlinked URB {}", seqnum);
+            continue;
+        }
+        if responses.send(res).is_err() {
+            // The connection is gone
+            return;
         }
     }
 }
 
-pub async fn handler<T: AsyncReadExt + AsyncWriteExt + Unpin>(
-    mut socket: &mut T,
+/// Like [handler], but returns between two commands once `shutdown` is set,
+/// releasing the imported device
+///
+/// URBs are handed to [endpoint_worker]s, and their responses are written
+/// back as they complete, so they may be out of order. URBs still in flight
+/// when the connection ends are completed before the device is released.
+/// Key use waits for approval if there is an `approver`, see [approval].
+async fn handler_with_shutdown<T: AsyncReadExt + AsyncWriteExt + Unpin>(
+    socket: &mut T,
     server: Arc<UsbIpServer>,
+    peer: &acl::Peer,
+    shutdown: Option<watch::Receiver<bool>>,
+    approver: Option<approval::Approver>,
 ) -> Result<()> {
+    let (mut reader, mut writer) = tokio::io::split(socket);
+    let (responses, mut pending) = mpsc::unbounded_channel::<UsbIpResponse>();
+    let connection = server.devices.connection_id();
+    let auditor = audit::Auditor::new(&server, connection);
+    let _session = server.metrics.session();
+    auditor.record(audit::Record::Connected {
+        connection,
+        peer: peer.clone(),
+    });
     let mut current_import_device_id: Option<String> = None;
-    loop {
-        let command = UsbIpCommand::read_from_socket(&mut socket).await;
-        if let Err(err) = command {
-            if let Some(dev_id) = current_import_device_id {
-                let mut used_devices = server.used_devices.write().await;
-                let mut available_devices = server.available_devices.write().await;
-                match used_devices.remove(&dev_id) {
-                    Some(dev) => available_devices.push(dev),
-                    None => unreachable!(),
+
+    let res = tokio::try_join!(
+        read_commands(
+            &mut reader,
+            &server,
+            peer,
+            shutdown,
+            responses,
+            &auditor,
+            approver,
+            &mut current_import_device_id,
+        ),
+        async {
+            while let Some(res) = pending.recv().await {
+                // Write the responses that are ready together, from a
+                // buffer of the pool
+                let mut buf = server.buffers.get();
+                res.encode(&mut buf);
+                while buf.len() < buffer::BUFFER_CAPACITY {
+                    match pending.try_recv() {
+                        Ok(res) => res.encode(&mut buf),
+                        Err(_) => break,
+                    }
                 }
+                let res = writer.write_all(&buf).await;
+                server.buffers.put(buf);
+                res?;
             }
+            Ok(())
+        },
+    );
 
-            if err.kind() == ErrorKind::UnexpectedEof {
+    if let Some(dev_id) = current_import_device_id {
+        server.release(&dev_id, connection).await;
+        auditor.released(&dev_id);
+    }
+    auditor.record(audit::Record::Disconnected {
+        connection,
+        error: res.as_ref().err().map(|err| err.to_string()),
+    });
+    res.map(|_| ())
+}
+
+/// Read commands from `socket` until it is closed or `shutdown` is set,
+/// queueing their responses to `responses`
+#[allow(clippy::too_many_arguments)]
+async fn read_commands<T: AsyncReadExt + Unpin>(
+    mut socket: &mut T,
+    server: &UsbIpServer,
+    peer: &acl::Peer,
+    mut shutdown: Option<watch::Receiver<bool>>,
+    responses: mpsc::UnboundedSender<UsbIpResponse>,
+    auditor: &audit::Auditor,
+    approver: Option<approval::Approver>,
+    current_import_device_id: &mut Option<String>,
+) -> Result<()> {
+    let connection = auditor.connection;
+    let mut current_import_device: Option<Arc<UsbDevice>> = None;
+    let mut role = acl::Role::default();
+    let mut endpoints: HashMap<u8, mpsc::UnboundedSender<Urb>> = HashMap::new();
+    let in_flight = InFlight::default();
+    let mut evicted = server.evicted.subscribe();
+    let mut lease_end = None;
+    let mut last_heard = tokio::time::Instant::now();
+    let client = throttle::Client::from(peer);
+    let pins = server.throttle.pin_watcher();
+    let send = |res| {
+        responses
+            .send(res)
+            .map_err(|_| std::io::Error::new(ErrorKind::BrokenPipe, "Connection closed"))
+    };
+
+    loop {
+        let command = tokio::select! {
+            command = UsbIpCommand::read_from_socket(&mut socket) => command,
+            _ = shutdown_requested(&mut shutdown) => Err(std::io::Error::new(
+                ErrorKind::Interrupted,
+                "Server is shutting down",
+            )),
+            Ok(()) = evicted.changed() => match current_import_device_id {
+                Some(dev_id) if !server.devices.is_imported_by(dev_id, connection).await => {
+                    Err(std::io::Error::new(
+                        ErrorKind::NotConnected,
+                        "Device was unplugged or revoked",
+                    ))
+                }
+                _ => continue,
+            },
+            _ = deadline(lease_end) => Err(std::io::Error::new(
+                ErrorKind::TimedOut,
+                "Lease expired",
+            )),
+            _ = deadline(server.dead_peer_timeout.map(|timeout| last_heard + timeout)) => {
+                Err(std::io::Error::new(ErrorKind::TimedOut, "Client stopped responding"))
+            }
+        };
+        last_heard = tokio::time::Instant::now();
+        server.throttle.admit(&client).await;
+        let command = match command {
+            Ok(command) => command,
+            Err(err) if err.kind() == ErrorKind::UnexpectedEof => {
                 info!("Remote closed the connection");
                 return Ok(());
-            } else {
-                return Err(err);
             }
-        }
-
-        let used_devices = server.used_devices.read().await;
-        let mut current_import_device = current_import_device_id
-            .clone()
-            .and_then(|ref id| used_devices.get(id));
+            Err(err)
+                if matches!(
+                    err.kind(),
+                    ErrorKind::Interrupted | ErrorKind::NotConnected | ErrorKind::TimedOut
+                ) =>
+            {
+                info!("Closing the connection: {}", err);
+                if let (Some(dev_id), false) = (
+                    current_import_device_id.as_ref(),
+                    err.kind() == ErrorKind::Interrupted,
+                ) {
+                    auditor.record(audit::Record::Evicted {
+                        connection,
+                        bus_id: dev_id.clone(),
+                        reason: err.to_string(),
+                    });
+                }
+                return Ok(());
+            }
+            Err(err) => return Err(err),
+        };
 
-        match command.unwrap() {
+        match command {
             UsbIpCommand::OpReqDevlist { .. } => {
                 trace!("Got OP_REQ_DEVLIST");
-                let devices = server.available_devices.read().await;
+                let devices = server.devices.available().await;
 
                 // OP_REP_DEVLIST
-                UsbIpResponse::op_rep_devlist(&devices)
-                    .write_to_socket(socket)
-                    .await?;
+                send(UsbIpResponse::op_rep_devlist(&devices))?;
                 trace!("Sent OP_REP_DEVLIST");
             }
             UsbIpCommand::OpReqImport { busid, .. } => {
                 trace!("Got OP_REQ_IMPORT");
 
-                current_import_device_id = None;
-                current_import_device = None;
-                std::mem::drop(used_devices);
-
-                let mut used_devices = server.used_devices.write().await;
-                let mut available_devices = server.available_devices.write().await;
-                let busid_compare =
-                    &busid[..busid.iter().position(|&x| x == 0).unwrap_or(busid.len())];
-                for (i, dev) in available_devices.iter().enumerate() {
-                    if busid_compare == dev.bus_id.as_bytes() {
-                        let dev = available_devices.remove(i);
-                        let dev_id = dev.bus_id.clone();
-                        used_devices.insert(dev.bus_id.clone(), dev);
-                        current_import_device_id = dev_id.clone().into();
-                        current_import_device = Some(used_devices.get(&dev_id).unwrap());
-                        break;
-                    }
-                }
+                endpoints.clear();
+                lease_end = None;
 
-                let res = if let Some(dev) = current_import_device {
-                    UsbIpResponse::op_rep_import_success(dev)
-                } else {
-                    UsbIpResponse::op_rep_import_fail()
+                if let Some(dev_id) = current_import_device_id.take() {
+                    server.release(&dev_id, connection).await;
+                    auditor.released(&dev_id);
+                }
+                let busid = &busid[..busid.iter().position(|&x| x == 0).unwrap_or(busid.len())];
+                let import = server
+                    .devices
+                    .import(busid, connection, |dev| server.may_import(peer, dev))
+                    .await;
+                let res = match &import {
+                    Ok(dev) => {
+                        // a new client starts with no endpoint halted
+                        dev.halted.lock().unwrap().clear();
+                        *current_import_device_id = Some(dev.bus_id.clone());
+                        role = server.role(peer, dev);
+                        lease_end = server
+                            .max_lease
+                            .map(|max_lease| tokio::time::Instant::now() + max_lease);
+                        auditor.record(audit::Record::Imported {
+                            connection,
+                            bus_id: dev.bus_id.clone(),
+                            serial: dev.serial_number().map(str::to_string),
+                        });
+                        server
+                            .events
+                            .send(events::Event::Imported {
+                                bus_id: dev.bus_id.clone(),
+                                serial: dev.serial_number().map(str::to_string),
+                            })
+                            .ok();
+                        UsbIpResponse::op_rep_import_success(dev)
+                    }
+                    Err(err) => {
+                        let busy = err.kind() == ErrorKind::ResourceBusy;
+                        auditor.record(audit::Record::ImportFailed {
+                            connection,
+                            bus_id: String::from_utf8_lossy(busid).into_owned(),
+                            busy,
+                        });
+                        if busy {
+                            UsbIpResponse::op_rep_import_busy()
+                        } else {
+                            UsbIpResponse::op_rep_import_fail()
+                        }
+                    }
                 };
-                res.write_to_socket(socket).await?;
+                current_import_device = import.ok();
+                send(res)?;
                 trace!("Sent OP_REP_IMPORT");
             }
             UsbIpCommand::UsbIpCmdSubmit {
-                mut header,
+                header,
                 transfer_buffer_length,
+                start_frame,
                 setup,
                 data,
+                iso_packet_descriptor,
                 ..
             } => {
                 trace!("Got USBIP_CMD_SUBMIT");
-                let device = current_import_device.unwrap();
-
-                let out = header.direction == 0;
-                let real_ep = if out { header.ep } else { header.ep | 0x80 };
+                let Some(device) = current_import_device.as_ref() else {
+                    // The client never imported a device, or was denied it
+                    warn!(
+                        "Refusing URB {} from {:?}, which imported no device",
+                        header.seqnum, peer
+                    );
+                    let mut header = header;
+                    header.command = USBIP_RET_SUBMIT.into();
+                    send(UsbIpResponse::usbip_ret_submit_fail(&header))?;
+                    continue;
+                };
 
-                header.command = USBIP_RET_SUBMIT.into();
+                let transfer = audit::transfer(device, &header, &setup, &data);
+                if server.refuses(role, transfer) {
+                    warn!(
+                        "Refusing {} in URB {} to {} for {:?}",
+                        transfer, header.seqnum, device.bus_id, peer
+                    );
+                    let mut header = header;
+                    header.command = USBIP_RET_SUBMIT.into();
+                    responses
+                        .send(UsbIpResponse::usbip_ret_submit_fail(&header))
+                        .ok();
+                    continue;
+                }
 
-                let res = match device.find_ep(real_ep as u8) {
-                    None => {
-                        warn!("Endpoint {:02x?} not found", real_ep);
-                        UsbIpResponse::usbip_ret_submit_fail(&header)
-                    }
-                    Some((ep, intf)) => {
-                        trace!("->Endpoint {:02x?}", ep);
-                        trace!("->Setup {:02x?}", setup);
-                        trace!("->Request {:02x?}", data);
-                        let resp = device
-                            .handle_urb(
-                                ep,
-                                intf,
-                                transfer_buffer_length,
-                                SetupPacket::parse(&setup),
-                                &data,
-                            )
-                            .await;
-
-                        match resp {
-                            Ok(resp) => {
-                                if out {
-                                    trace!("<-Wrote {}", data.len());
-                                } else {
-                                    trace!("<-Resp {:02x?}", resp);
-                                }
-                                UsbIpResponse::usbip_ret_submit_success(&header, 0, 0, resp, vec![])
-                            }
-                            Err(err) => {
-                                warn!("Error handling URB: {}", err);
-                                UsbIpResponse::usbip_ret_submit_fail(&header)
-                            }
-                        }
-                    }
+                // Both directions of a control endpoint share a pipe
+                let queue = if header.ep == 0 {
+                    0
+                } else if header.direction == 0 {
+                    header.ep as u8
+                } else {
+                    header.ep as u8 | 0x80
                 };
-                res.write_to_socket(socket).await?;
-                trace!("Sent USBIP_RET_SUBMIT");
+                let worker = endpoints.entry(queue).or_insert_with(|| {
+                    let (tx, rx) = mpsc::unbounded_channel();
+                    tokio::spawn(endpoint_worker(
+                        device.clone(),
+                        rx,
+                        responses.clone(),
+                        in_flight.clone(),
+                        server.events.clone(),
+                        auditor.clone(),
+                        server.metrics.device(device),
+                        pins.clone(),
+                        approver.clone(),
+                        server.capture.clone(),
+                        server.latency_budget,
+                    ));
+                    tx
+                });
+                in_flight.lock().unwrap().insert(header.seqnum);
+                worker
+                    .send(Urb {
+                        transfer_buffer_length,
+                        start_frame,
+                        setup,
+                        data,
+                        iso_packet_descriptor,
+                        span: debug_span!(
+                            "urb",
+                            bus_id = %device.bus_id,
+                            seqnum = header.seqnum,
+                            ep = header.ep,
+                            direction = if header.direction == 0 { "out" } else { "in" },
+                        ),
+                        header,
+                    })
+                    .ok();
             }
             UsbIpCommand::UsbIpCmdUnlink {
                 mut header,
@@ -401,8 +1252,13 @@ pub async fn handler<T: AsyncReadExt + AsyncWriteExt + Unpin>(
 
                 header.command = USBIP_RET_UNLINK.into();
 
-                let res = UsbIpResponse::usbip_ret_unlink_success(&header);
-                res.write_to_socket(socket).await?;
+                // A URB that completed already got its USBIP_RET_SUBMIT
+                let cancelled = in_flight.lock().unwrap().remove(&unlink_seqnum);
+                send(if cancelled {
+                    UsbIpResponse::usbip_ret_unlink_cancelled(&header)
+                } else {
+                    UsbIpResponse::usbip_ret_unlink_success(&header)
+                })?;
                 trace!("Sent USBIP_RET_UNLINK");
             }
         }
@@ -411,36 +1267,163 @@ pub async fn handler<T: AsyncReadExt + AsyncWriteExt + Unpin>(
 
 /// Spawn a USB/IP server at `addr` using [TcpListener]
 pub async fn server(addr: SocketAddr, server: Arc<UsbIpServer>) {
+    server_with_shutdown(addr, server, std::future::pending()).await
+}
+
+/// Spawn a USB/IP server at `addr` using [TcpListener], until `shutdown` completes
+///
+/// On shutdown the server stops accepting connections, asks every connection
+/// to close after its current URB and waits for them, so all imported devices
+/// are released when this returns.
+pub async fn server_with_shutdown(
+    addr: SocketAddr,
+    server: Arc<UsbIpServer>,
+    shutdown: impl std::future::Future<Output = ()>,
+) {
     let listener = TcpListener::bind(addr).await.expect("bind to addr");
+    listener_server_with_shutdown(listener, server, shutdown).await
+}
+
+/// Like [server_with_shutdown], on a `listener` bound elsewhere, e.g. a socket
+/// the service manager passed us
+pub async fn listener_server_with_shutdown(
+    listener: TcpListener,
+    server: Arc<UsbIpServer>,
+    shutdown: impl std::future::Future<Output = ()>,
+) {
+    serve(
+        listener,
+        server,
+        shutdown,
+        |socket| async move { Ok(socket) },
+    )
+    .await
+}
 
-    let server = async move {
-        loop {
-            match listener.accept().await {
-                Ok((mut socket, _addr)) => {
-                    info!("Got connection from {:?}", socket.peer_addr());
+/// Where [serve] takes its connections from
+pub(crate) trait Listener {
+    type Stream;
+
+    /// Wait for the next connection, and tell what is known about the client
+    /// from it so far, for the logs
+    fn accept(
+        &mut self,
+    ) -> impl std::future::Future<Output = Result<(Self::Stream, acl::Peer)>> + Send;
+}
+
+impl Listener for TcpListener {
+    type Stream = TcpStream;
+
+    async fn accept(&mut self) -> Result<(TcpStream, acl::Peer)> {
+        let (socket, _) = TcpListener::accept(self).await?;
+        let peer = socket.peer();
+        Ok((socket, peer))
+    }
+}
+
+/// Serve the client on the other end of `socket` until it closes the
+/// connection, as [server] does each of its clients
+///
+/// The client is known to [acl::Policy] as [Transport::peer] tells, with the
+/// token it may send first. Besides USB/IP, it may subscribe to [events], make
+/// [admin] requests or, with the `apdu` feature, open an APDU session.
+pub async fn serve_connection<T: Transport>(socket: T, server: Arc<UsbIpServer>) -> Result<()> {
+    serve_connection_with_shutdown(socket, server, None).await
+}
+
+/// Like [serve_connection], but returns between two commands once `shutdown`
+/// is set
+async fn serve_connection_with_shutdown<T: Transport>(
+    socket: T,
+    server: Arc<UsbIpServer>,
+    shutdown: Option<watch::Receiver<bool>>,
+) -> Result<()> {
+    let mut peer = socket.peer();
+    let mut socket = tokio::io::BufReader::new(socket);
+    peer.token = acl::read_token(&mut socket).await?;
+    #[cfg(feature = "oidc")]
+    if let (Some(validator), Some(token)) = (&server.oidc, peer.token.as_deref()) {
+        if oidc::is_jwt(token) {
+            match validator.validate(token).await {
+                Ok(claims) => peer.claims = claims,
+                Err(err) => warn!("Rejected the bearer token of {:?}: {}", peer, err),
+            }
+        }
+    }
+    if events::read_subscribe(&mut socket).await? {
+        return events::serve_subscriber(&mut socket, &server, &peer, shutdown).await;
+    }
+    if let Some(request) = admin::read_request(&mut socket).await? {
+        return admin::serve_request(&mut socket, &server, &peer, request).await;
+    }
+    #[cfg(feature = "apdu")]
+    if let Some(bus_id) = apdu::read_request(&mut socket).await? {
+        return apdu::serve_session(&mut socket, server, &peer, &bus_id, shutdown).await;
+    }
+    let approver = approval::Approver::new(&server, &peer);
+    handler_with_shutdown(&mut socket, server, &peer, shutdown, approver).await
+}
+
+/// Accept connections from `listener` until `shutdown` completes, and run
+/// [serve_connection_with_shutdown] on each once `wrap` (e.g. a TLS
+/// handshake) is done
+pub(crate) async fn serve<L, W, F, S>(
+    mut listener: L,
+    server: Arc<UsbIpServer>,
+    shutdown: impl std::future::Future<Output = ()>,
+    wrap: W,
+) where
+    L: Listener,
+    W: Fn(L::Stream) -> F,
+    F: std::future::Future<Output = Result<S>> + Send + 'static,
+    S: Transport + 'static,
+{
+    let (shutdown_tx, shutdown_rx) = watch::channel(false);
+    let mut handlers = JoinSet::new();
+    tokio::pin!(shutdown);
+
+    loop {
+        tokio::select! {
+            _ = &mut shutdown => break,
+            Some(_) = handlers.join_next() => {}
+            res = listener.accept() => match res {
+                Ok((socket, client)) => {
+                    info!("Got connection from {:?}", client);
+                    let socket = wrap(socket);
                     let new_server = server.clone();
-                    tokio::spawn(async move {
-                        let res = handler(&mut socket, new_server).await;
+                    let shutdown = Some(shutdown_rx.clone());
+                    handlers.spawn(async move {
+                        let socket = match socket.await {
+                            Ok(socket) => socket,
+                            Err(err) => {
+                                warn!("Connection from {:?} failed: {}", client, err);
+                                return;
+                            }
+                        };
+                        let res = serve_connection_with_shutdown(socket, new_server, shutdown).await;
                         info!("Handler ended with {:?}", res);
                     });
                 }
                 Err(err) => {
                     warn!("Got error {:?}", err);
                 }
-            }
+            },
         }
-    };
+    }
 
-    server.await
+    info!("Shutting down, waiting for {} connections", handlers.len());
+    shutdown_tx.send(true).ok();
+    while handlers.join_next().await.is_some() {}
 }
 
 #[cfg(test)]
 mod tests {
-    use tokio::{net::TcpStream, task::JoinSet};
+    use std::time::Duration;
+    use tokio::net::TcpStream;
 
     use super::*;
     use crate::{
-        usbip_protocol::{UsbIpHeaderBasic, USBIP_CMD_SUBMIT},
+        usbip_protocol::{UsbIpHeaderBasic, USBIP_CMD_SUBMIT, USBIP_CMD_UNLINK},
         util::tests::*,
     };
 
@@ -511,6 +1494,47 @@ mod tests {
         assert_eq!(mock_socket.output.len(), 0xC + 0x138 + 4);
     }
 
+    #[tokio::test]
+    async fn devlist_decodes_like_linux_client() {
+        setup_test_logger();
+        let server = new_server_with_single_device();
+        let req = UsbIpCommand::OpReqDevlist { status: 0 };
+
+        let mut mock_socket = MockSocket::new(req.to_bytes());
+        handler(&mut mock_socket, Arc::new(server)).await.ok();
+
+        let (reply, len) = wire::UsbIpReply::decode(&mock_socket.output, |_| false).unwrap();
+        assert_eq!(len, mock_socket.output.len());
+        let wire::UsbIpReply::OpRepDevlist { status, devices } = reply else {
+            panic!("unexpected reply {:?}", reply);
+        };
+        assert_eq!(status, 0);
+        assert_eq!(devices.len(), 1);
+        assert_eq!(devices[0].bus_id, SINGLE_DEVICE_BUSID);
+        assert_eq!(devices[0].speed, UsbSpeed::High as u32);
+        assert_eq!(devices[0].num_interfaces, 1);
+        assert_eq!(
+            devices[0].interfaces[0].interface_class,
+            ClassCode::CDC as u8
+        );
+    }
+
+    #[test]
+    fn speeds_match_linux() {
+        // enum usb_device_speed
+        assert_eq!(UsbSpeed::from(rusb::Speed::Full) as u32, 2);
+        assert_eq!(UsbSpeed::from(rusb::Speed::High) as u32, 3);
+        assert_eq!(UsbSpeed::from(rusb::Speed::Super) as u32, 5);
+        assert_eq!(UsbSpeed::from(rusb::Speed::SuperPlus) as u32, 6);
+        #[cfg(feature = "nusb")]
+        {
+            assert_eq!(UsbSpeed::from(nusb::Speed::Full) as u32, 2);
+            assert_eq!(UsbSpeed::from(nusb::Speed::High) as u32, 3);
+            assert_eq!(UsbSpeed::from(nusb::Speed::Super) as u32, 5);
+            assert_eq!(UsbSpeed::from(nusb::Speed::SuperPlus) as u32, 6);
+        }
+    }
+
     #[tokio::test]
     async fn req_import() {
         setup_test_logger();
@@ -552,7 +1576,7 @@ mod tests {
 
         while join_set.join_next().await.is_some() {}
 
-        let device_len = server_.clone().available_devices.read().await.len();
+        let device_len = server_.devices.available().await.len();
 
         assert_eq!(device_len, 0);
     }
@@ -643,7 +1667,133 @@ mod tests {
         assert_eq!(result, 0);
 
         let result = attach_device(&mut second_connection, SINGLE_DEVICE_BUSID).await;
-        assert_eq!(result, 1);
+        assert_eq!(result, usbip_protocol::OP_REP_IMPORT_BUSY);
+    }
+
+    #[tokio::test]
+    async fn lease_expires() {
+        setup_test_logger();
+        let server_ =
+            Arc::new(new_server_with_single_device().with_max_lease(Duration::from_millis(100)));
+
+        let addr = get_free_address().await;
+        tokio::spawn(server(addr, server_.clone()));
+
+        let mut connection = poll_connect(addr).await;
+        let result = attach_device(&mut connection, SINGLE_DEVICE_BUSID).await;
+        assert_eq!(result, 0);
+
+        // The connection is closed once the lease is over, and the device is
+        // available again
+        assert_eq!(connection.read(&mut [0; 1]).await.unwrap(), 0);
+        let mut connection = TcpStream::connect(addr).await.unwrap();
+        let result = attach_device(&mut connection, SINGLE_DEVICE_BUSID).await;
+        assert_eq!(result, 0);
+    }
+
+    #[tokio::test]
+    async fn dead_peer_timeout() {
+        setup_test_logger();
+        let server_ = Arc::new(
+            new_server_with_single_device().with_dead_peer_timeout(Duration::from_millis(100)),
+        );
+
+        let addr = get_free_address().await;
+        tokio::spawn(server(addr, server_.clone()));
+
+        let mut connection = poll_connect(addr).await;
+        let result = attach_device(&mut connection, SINGLE_DEVICE_BUSID).await;
+        assert_eq!(result, 0);
+
+        // A client that stays silent is cut off, and the device released
+        assert_eq!(connection.read(&mut [0; 1]).await.unwrap(), 0);
+        let mut connection = TcpStream::connect(addr).await.unwrap();
+        let result = attach_device(&mut connection, SINGLE_DEVICE_BUSID).await;
+        assert_eq!(result, 0);
+    }
+
+    #[tokio::test]
+    async fn clients_import_different_devices() {
+        setup_test_logger();
+        let server_ = Arc::new(UsbIpServer::new_simulated(
+            (0..3).map(UsbDevice::new).collect(),
+        ));
+
+        let addr = get_free_address().await;
+        tokio::spawn(server(addr, server_.clone()));
+
+        let busids: Vec<_> = (0..3).map(|i| format!("0-0-{}", i)).collect();
+        let imports: Vec<_> = busids
+            .iter()
+            .cloned()
+            .map(|busid| {
+                tokio::spawn(async move {
+                    let mut connection = poll_connect(addr).await;
+                    let result = attach_device(&mut connection, &busid).await;
+                    (connection, result)
+                })
+            })
+            .collect();
+        let mut connections = vec![];
+        for import in imports {
+            let (connection, result) = import.await.unwrap();
+            assert_eq!(result, 0);
+            connections.push(connection);
+        }
+        assert!(server_.devices.available().await.is_empty());
+
+        // Each device is released on its own
+        connections.pop();
+        let mut connection = TcpStream::connect(addr).await.unwrap();
+        assert_eq!(
+            attach_device(&mut connection, &busids[0]).await,
+            usbip_protocol::OP_REP_IMPORT_BUSY
+        );
+        assert_eq!(attach_device(&mut connection, &busids[2]).await, 0);
+    }
+
+    #[tokio::test]
+    async fn device_gets_released_on_shutdown() {
+        setup_test_logger();
+        let server_ = Arc::new(new_server_with_single_device());
+
+        let addr = get_free_address().await;
+        let (tx, rx) = tokio::sync::oneshot::channel::<()>();
+        let task = tokio::spawn(server_with_shutdown(addr, server_.clone(), async {
+            rx.await.ok();
+        }));
+
+        let mut connection = poll_connect(addr).await;
+        let result = attach_device(&mut connection, SINGLE_DEVICE_BUSID).await;
+        assert_eq!(result, 0);
+        assert!(server_.devices.available().await.is_empty());
+
+        tx.send(()).unwrap();
+        task.await.unwrap();
+
+        // The connection was closed by the server
+        assert_eq!(connection.read(&mut [0; 1]).await.unwrap(), 0);
+        assert_eq!(server_.devices.available().await.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn unplugging_imported_device_closes_connection() {
+        setup_test_logger();
+        let server_ = Arc::new(new_server_with_single_device());
+
+        let addr = get_free_address().await;
+        tokio::spawn(server(addr, server_.clone()));
+
+        let mut connection = poll_connect(addr).await;
+        let result = attach_device(&mut connection, SINGLE_DEVICE_BUSID).await;
+        assert_eq!(result, 0);
+
+        server_.unplug_device(SINGLE_DEVICE_BUSID).await.unwrap();
+        assert_eq!(connection.read(&mut [0; 1]).await.unwrap(), 0);
+
+        // and it isn't exported again
+        assert!(server_.devices.available().await.is_empty());
+        assert!(server_.unplug_device(SINGLE_DEVICE_BUSID).await.is_err());
     }
 
     #[tokio::test]
@@ -698,4 +1848,468 @@ mod tests {
         // OP_REQ_IMPORT + USBIP_CMD_SUBMIT + Device Descriptor
         assert_eq!(mock_socket.output.len(), 0x140 + 0x30 + 0x12);
     }
+
+    #[tokio::test]
+    async fn submit_without_import_is_refused() {
+        setup_test_logger();
+        // No rule allows anyone to import the device
+        let server = new_server_with_single_device().with_policy(acl::Policy::new(vec![]));
+
+        let mut req = op_req_import(SINGLE_DEVICE_BUSID);
+        req.extend(
+            UsbIpCommand::UsbIpCmdSubmit {
+                header: UsbIpHeaderBasic {
+                    command: USBIP_CMD_SUBMIT.into(),
+                    seqnum: 1,
+                    devid: 0,
+                    direction: 1, // IN
+                    ep: 0,
+                },
+                transfer_flags: 0,
+                transfer_buffer_length: 0,
+                start_frame: 0,
+                number_of_packets: 0,
+                interval: 0,
+                setup: [0x80, 0x06, 0x00, 0x01, 0x00, 0x00, 0x40, 0x00],
+                data: vec![],
+                iso_packet_descriptor: vec![],
+            }
+            .to_bytes(),
+        );
+
+        let mut mock_socket = MockSocket::new(req);
+        handler(&mut mock_socket, Arc::new(server)).await.ok();
+        // A failed OP_REP_IMPORT, then a failed USBIP_RET_SUBMIT
+        assert_eq!(mock_socket.output.len(), 0x8 + 0x30);
+        assert_ne!(mock_socket.output[8 + 0x14..8 + 0x18], [0; 4]);
+    }
+
+    /// A smart card reader with bulk OUT endpoint 0x02, and a HID interface
+    /// with interrupt endpoints 0x03 and 0x83, as a YubiKey's FIDO interface
+    fn new_ccid_and_hid_device() -> UsbDevice {
+        let handler = || {
+            Arc::new(Mutex::new(
+                Box::new(cdc::UsbCdcAcmHandler::new()) as Box<dyn UsbInterfaceHandler + Send>
+            ))
+        };
+        let endpoint = |address, attributes: EndpointAttributes| UsbEndpoint {
+            address,
+            attributes: attributes as u8,
+            max_packet_size: 64,
+            interval: 0,
+        };
+        UsbDevice::new(0)
+            .with_interface(
+                ClassCode::SmartCard as u8,
+                ccid::CCID_SUBCLASS,
+                0x00,
+                "Test CCID",
+                vec![endpoint(0x02, EndpointAttributes::Bulk)],
+                handler(),
+            )
+            .with_interface(
+                ClassCode::HID as u8,
+                0x00,
+                0x00,
+                "Test FIDO",
+                vec![
+                    endpoint(0x03, EndpointAttributes::Interrupt),
+                    endpoint(0x83, EndpointAttributes::Interrupt),
+                ],
+                handler(),
+            )
+    }
+
+    /// A PC_to_RDR_XfrBlock carrying `apdu`
+    fn xfr_block(apdu: &[u8]) -> Vec<u8> {
+        let mut msg = vec![0x6F];
+        msg.extend((apdu.len() as u32).to_le_bytes());
+        msg.extend([0; 5]);
+        msg.extend(apdu);
+        msg
+    }
+
+    #[tokio::test]
+    async fn read_only_refuses_writes() {
+        setup_test_logger();
+        let device = new_ccid_and_hid_device();
+        let bus_id = device.bus_id.clone();
+        let read_only = UsbIpServer::new_simulated(vec![device]).with_read_only(true);
+        let addr = get_free_address().await;
+        tokio::spawn(server(addr, Arc::new(read_only)));
+
+        let mut socket = poll_connect(addr).await;
+        let device = client::import(&mut socket, &bus_id).await.unwrap();
+        let device = client::ImportedDevice::new(socket, device);
+
+        // SELECT, GET DATA, VERIFY and signing reach the card
+        for apdu in [
+            &[0x00, 0xA4, 0x04, 0x00, 0x00][..],
+            &[0x00, 0xCA, 0x00, 0x6E, 0x00],
+            &[0x00, 0x20, 0x00, 0x82, 0x00],
+            &[0x00, 0x2A, 0x9E, 0x9A, 0x00],
+        ] {
+            device.transfer_out(0x02, xfr_block(apdu)).await.unwrap();
+        }
+        // OpenPGP PUT DATA, TERMINATE DF and ACTIVATE FILE, OATH PUT, DELETE
+        // and RESET, and the management application's WRITE CONFIG don't
+        for apdu in [
+            &[0x00, 0xDA, 0x00, 0x5B, 0x01, 0x41][..],
+            &[0x00, 0xE6, 0x00, 0x00],
+            &[0x00, 0x44, 0x00, 0x00],
+            &[0x00, 0x01, 0x00, 0x00, 0x03, 0x71, 0x01, 0x41],
+            &[0x00, 0x02, 0x00, 0x00, 0x03, 0x71, 0x01, 0x41],
+            &[0x00, 0x04, 0xDE, 0xAD],
+            &[0x00, 0x1C, 0x00, 0x00, 0x01, 0x00],
+        ] {
+            assert!(device.transfer_out(0x02, xfr_block(apdu)).await.is_err());
+        }
+
+        // Reading the FIDO interface's descriptors works, writing to it doesn't
+        let get_descriptor = [0x80, 0x06, 0x00, 0x01, 0x00, 0x00, 0x12, 0x00];
+        device.control_in(get_descriptor).await.unwrap();
+        assert!(device.transfer_out(0x03, vec![0xFF; 64]).await.is_err());
+        let set_report = [0x21, 0x09, 0x00, 0x03, 0x01, 0x00, 0x08, 0x00];
+        assert!(device.control_out(set_report, vec![0; 8]).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn roles_refuse_what_they_cant_check() {
+        setup_test_logger();
+        let device = new_ccid_and_hid_device();
+        let bus_id = device.bus_id.clone();
+        let policy = acl::Policy::parse("allow token:ci any role:decrypt-only").unwrap();
+        let restricted = UsbIpServer::new_simulated(vec![device]).with_policy(policy);
+        let addr = get_free_address().await;
+        tokio::spawn(server(addr, Arc::new(restricted)));
+
+        let mut socket = poll_connect(addr).await;
+        client::send_token(&mut socket, "ci").await.unwrap();
+        let device = client::import(&mut socket, &bus_id).await.unwrap();
+        let device = client::ImportedDevice::new(socket, device);
+
+        // Powering the card and whole APDUs the role allows reach it
+        let power_on = vec![0x62, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+        device.transfer_out(0x02, power_on).await.unwrap();
+        let sign = xfr_block(&[0x00, 0x87, 0x11, 0x9A, 0x00]);
+        device.transfer_out(0x02, sign.clone()).await.unwrap();
+
+        // The same APDU split across two URBs doesn't, nor do escapes to the
+        // reader's vendor commands
+        let (header, apdu) = sign.split_at(12);
+        assert!(device.transfer_out(0x02, header.to_vec()).await.is_err());
+        assert!(device.transfer_out(0x02, apdu.to_vec()).await.is_err());
+        let escape = vec![0x6B, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0x00];
+        assert!(device.transfer_out(0x02, escape).await.is_err());
+
+        // Nor anything to the FIDO interface but its descriptors
+        let get_descriptor = [0x80, 0x06, 0x00, 0x01, 0x00, 0x00, 0x12, 0x00];
+        device.control_in(get_descriptor).await.unwrap();
+        assert!(device.transfer_out(0x03, vec![0xFF; 64]).await.is_err());
+        assert!(device.transfer_in(0x03, 64).await.is_err());
+        let get_report = [0xA1, 0x01, 0x00, 0x03, 0x01, 0x00, 0x08, 0x00];
+        assert!(device.control_in(get_report).await.is_err());
+    }
+
+    /// Blocks interrupt IN transfers until a bulk OUT transfer arrives
+    #[derive(Default)]
+    struct TouchBackend {
+        touched: Mutex<bool>,
+        touch: std::sync::Condvar,
+    }
+
+    impl UsbBackend for TouchBackend {
+        fn read_control(&self, _: &SetupPacket, _: &mut [u8], _: Duration) -> Result<usize> {
+            Ok(0)
+        }
+
+        fn write_control(&self, _: &SetupPacket, data: &[u8], _: Duration) -> Result<usize> {
+            Ok(data.len())
+        }
+
+        fn read_interrupt(&self, _: u8, buf: &mut [u8], _: Duration) -> Result<usize> {
+            let touched = self.touched.lock().unwrap();
+            let (_touched, res) = self
+                .touch
+                .wait_timeout_while(touched, Duration::from_secs(5), |touched| !*touched)
+                .unwrap();
+            if res.timed_out() {
+                return Err(ErrorKind::TimedOut.into());
+            }
+            buf[0] = 1;
+            Ok(1)
+        }
+
+        fn write_interrupt(&self, _: u8, data: &[u8], _: Duration) -> Result<usize> {
+            Ok(data.len())
+        }
+
+        fn read_bulk(&self, _: u8, _: &mut [u8], _: Duration) -> Result<usize> {
+            Ok(0)
+        }
+
+        fn write_bulk(&self, _: u8, data: &[u8], _: Duration) -> Result<usize> {
+            *self.touched.lock().unwrap() = true;
+            self.touch.notify_all();
+            Ok(data.len())
+        }
+    }
+
+    #[tokio::test]
+    async fn blocked_endpoint_does_not_stall_others() {
+        setup_test_logger();
+        let endpoint = |address, attributes| UsbEndpoint {
+            address,
+            attributes: attributes as u8,
+            max_packet_size: 64,
+            interval: 0,
+        };
+        let server = UsbIpServer::new_simulated(vec![UsbDevice::new(0).with_interface(
+            0xFF,
+            0,
+            0,
+            "Test",
+            vec![
+                endpoint(0x81, EndpointAttributes::Interrupt),
+                endpoint(0x02, EndpointAttributes::Bulk),
+            ],
+            Arc::new(Mutex::new(Box::new(UsbHostInterfaceHandler::new(
+                Arc::new(TouchBackend::default()),
+                TransferPolicy::default(),
+            ))
+                as Box<dyn UsbInterfaceHandler + Send>)),
+        )]);
+        let submit = |seqnum, direction, ep| UsbIpCommand::UsbIpCmdSubmit {
+            header: UsbIpHeaderBasic {
+                command: USBIP_CMD_SUBMIT.into(),
+                seqnum,
+                devid: 0,
+                direction,
+                ep,
+            },
+            transfer_flags: 0,
+            transfer_buffer_length: 1,
+            start_frame: 0,
+            number_of_packets: 0,
+            interval: 0,
+            setup: [0; 8],
+            data: if direction == 0 { vec![0] } else { vec![] },
+            iso_packet_descriptor: vec![],
+        };
+
+        let mut req = op_req_import(SINGLE_DEVICE_BUSID);
+        // The interrupt IN only completes once the bulk OUT behind it is handled
+        req.extend(submit(1, 1, 1).to_bytes());
+        req.extend(submit(2, 0, 2).to_bytes());
+
+        let mut mock_socket = MockSocket::new(req);
+        handler(&mut mock_socket, Arc::new(server)).await.unwrap();
+
+        // Both complete, in whichever order their workers reply
+        let output = &mock_socket.output[0x140..];
+        assert_eq!(output.len(), 0x30 * 2 + 1);
+        let (bulk, interrupt) = if output[4..8] == 2u32.to_be_bytes() {
+            output.split_at(0x30)
+        } else {
+            let (interrupt, bulk) = output.split_at(0x31);
+            (bulk, interrupt)
+        };
+        assert_eq!(bulk[4..8], 2u32.to_be_bytes());
+        assert_eq!(interrupt[4..8], 1u32.to_be_bytes());
+        // The interrupt IN got its data
+        assert_eq!(interrupt[0x30..], [1]);
+    }
+
+    #[tokio::test]
+    async fn unlink_pending_urb() {
+        setup_test_logger();
+        let endpoint = |address, attributes| UsbEndpoint {
+            address,
+            attributes: attributes as u8,
+            max_packet_size: 64,
+            interval: 0,
+        };
+        let server = UsbIpServer::new_simulated(vec![UsbDevice::new(0).with_interface(
+            0xFF,
+            0,
+            0,
+            "Test",
+            vec![
+                endpoint(0x81, EndpointAttributes::Interrupt),
+                endpoint(0x02, EndpointAttributes::Bulk),
+            ],
+            Arc::new(Mutex::new(Box::new(UsbHostInterfaceHandler::new(
+                Arc::new(TouchBackend::default()),
+                TransferPolicy::default(),
+            ))
+                as Box<dyn UsbInterfaceHandler + Send>)),
+        )]);
+        let submit = |seqnum, direction, ep| UsbIpCommand::UsbIpCmdSubmit {
+            header: UsbIpHeaderBasic {
+                command: USBIP_CMD_SUBMIT.into(),
+                seqnum,
+                devid: 0,
+                direction,
+                ep,
+            },
+            transfer_flags: 0,
+            transfer_buffer_length: 1,
+            start_frame: 0,
+            number_of_packets: 0,
+            interval: 0,
+            setup: [0; 8],
+            data: if direction == 0 { vec![0] } else { vec![] },
+            iso_packet_descriptor: vec![],
+        };
+        let unlink = |seqnum, unlink_seqnum| UsbIpCommand::UsbIpCmdUnlink {
+            header: UsbIpHeaderBasic {
+                command: USBIP_CMD_UNLINK.into(),
+                seqnum,
+                devid: 0,
+                direction: 0,
+                ep: 0,
+            },
+            unlink_seqnum,
+        };
+
+        let mut req = op_req_import(SINGLE_DEVICE_BUSID);
+        // The interrupt IN waits for a touch, and is cancelled meanwhile
+        req.extend(submit(1, 1, 1).to_bytes());
+        req.extend(unlink(2, 1).to_bytes());
+        // Nothing to cancel
+        req.extend(unlink(3, 99).to_bytes());
+        // The touch, after which the interrupt IN completes too late
+        req.extend(submit(4, 0, 2).to_bytes());
+
+        let mut mock_socket = MockSocket::new(req);
+        handler(&mut mock_socket, Arc::new(server)).await.unwrap();
+
+        let output = &mock_socket.output[0x140..];
+        let replies: HashMap<u32, &[u8]> = output
+            .chunks(0x30)
+            .map(|reply| (u32::from_be_bytes(reply[4..8].try_into().unwrap()), reply))
+            .collect();
+        assert_eq!(output.len(), 0x30 * 3);
+        assert!(!replies.contains_key(&1));
+        assert_eq!(replies[&2][..4], (USBIP_RET_UNLINK as u32).to_be_bytes());
+        assert_eq!(
+            replies[&2][20..24],
+            (-usbip_protocol::ECONNRESET).to_be_bytes()
+        );
+        assert_eq!(replies[&3][20..24], [0; 4]);
+        assert_eq!(replies[&4][..4], (USBIP_RET_SUBMIT as u32).to_be_bytes());
+    }
+
+    /// Fills the first half of each isochronous IN packet with its index
+    struct IsoBackend;
+
+    impl UsbBackend for IsoBackend {
+        fn read_control(&self, _: &SetupPacket, _: &mut [u8], _: Duration) -> Result<usize> {
+            Ok(0)
+        }
+
+        fn write_control(&self, _: &SetupPacket, data: &[u8], _: Duration) -> Result<usize> {
+            Ok(data.len())
+        }
+
+        fn read_interrupt(&self, _: u8, _: &mut [u8], _: Duration) -> Result<usize> {
+            Ok(0)
+        }
+
+        fn write_interrupt(&self, _: u8, data: &[u8], _: Duration) -> Result<usize> {
+            Ok(data.len())
+        }
+
+        fn read_bulk(&self, _: u8, _: &mut [u8], _: Duration) -> Result<usize> {
+            Ok(0)
+        }
+
+        fn write_bulk(&self, _: u8, data: &[u8], _: Duration) -> Result<usize> {
+            Ok(data.len())
+        }
+
+        fn iso_transfer(
+            &self,
+            _: u8,
+            buf: &mut [u8],
+            packets: &mut [IsoPacketDescriptor],
+            _: Duration,
+        ) -> Result<()> {
+            for (i, packet) in packets.iter_mut().enumerate() {
+                packet.actual_length = packet.length / 2;
+                buf[packet.offset as usize..][..packet.actual_length as usize].fill(i as u8);
+            }
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn iso_in_transfer() {
+        setup_test_logger();
+        let server = UsbIpServer::new_simulated(vec![UsbDevice::new(0).with_interface(
+            0xFF,
+            0,
+            0,
+            "Test",
+            vec![UsbEndpoint {
+                address: 0x83,
+                attributes: EndpointAttributes::Isochronous as u8,
+                max_packet_size: 8,
+                interval: 1,
+            }],
+            Arc::new(Mutex::new(Box::new(UsbHostInterfaceHandler::new(
+                Arc::new(IsoBackend),
+                TransferPolicy::default(),
+            ))
+                as Box<dyn UsbInterfaceHandler + Send>)),
+        )]);
+        let packets: Vec<_> = (0..3)
+            .map(|i| IsoPacketDescriptor {
+                offset: i * 8,
+                length: 8,
+                ..Default::default()
+            })
+            .collect();
+
+        let mut req = op_req_import(SINGLE_DEVICE_BUSID);
+        req.extend(
+            UsbIpCommand::UsbIpCmdSubmit {
+                header: UsbIpHeaderBasic {
+                    command: USBIP_CMD_SUBMIT.into(),
+                    seqnum: 1,
+                    devid: 0,
+                    direction: 1, // IN
+                    ep: 3,
+                },
+                transfer_flags: 0,
+                transfer_buffer_length: 24,
+                start_frame: 5,
+                number_of_packets: 3,
+                interval: 1,
+                setup: [0; 8],
+                data: vec![],
+                iso_packet_descriptor: IsoPacketDescriptor::encode_all(&packets),
+            }
+            .to_bytes(),
+        );
+
+        let mut mock_socket = MockSocket::new(req);
+        handler(&mut mock_socket, Arc::new(server)).await.unwrap();
+
+        let output = &mock_socket.output[0x140..];
+        assert_eq!(output.len(), 0x30 + 12 + 3 * 16);
+        // actual_length, start_frame, number_of_packets, error_count
+        assert_eq!(output[24..28], 12u32.to_be_bytes());
+        assert_eq!(output[28..32], 5u32.to_be_bytes());
+        assert_eq!(output[32..36], 3u32.to_be_bytes());
+        assert_eq!(output[36..40], 0u32.to_be_bytes());
+        // The packets are packed without gaps
+        assert_eq!(
+            output[0x30..0x30 + 12],
+            [0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2]
+        );
+        let packets = IsoPacketDescriptor::decode_all(&output[0x30 + 12..]).unwrap();
+        assert_eq!(packets[2].offset, 16);
+        assert_eq!(packets[2].actual_length, 4);
+    }
 }
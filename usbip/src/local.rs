@@ -0,0 +1,436 @@
+//! Local transports
+//!
+//! A server and its clients on the same machine, e.g. a daemon that runs as a
+//! service and owns the devices and a CLI that runs as the user, don't need
+//! TCP: they can talk over a Unix domain socket, or a named pipe on Windows.
+//! Who may connect is then up to the permissions of the socket file or the
+//! pipe, and on Unix an [crate::acl::Policy] can also match clients by the
+//! user they run as (`uid:1000`).
+//!
+//! An [Address] names either transport or a TCP address, so that both ends
+//! can take it from the same setting:
+//!
+//! - `host:port`, over TCP
+//! - `unix:/run/yk-agentd.sock`, a Unix domain socket
+//! - `pipe:yk-agentd`, the named pipe `\\.\pipe\yk-agentd`
+//! - `ssh:alice@host/run/yk-agentd.sock`, a Unix domain socket on another
+//!   machine, reached through `ssh` (clients only)
+//!
+//! The last one spares clients that can already log in to the server's
+//! machine with SSH a TLS setup: [connect] runs `ssh -W` to forward the
+//! socket over their SSH connection, with their usual keys, agent and
+//! `~/.ssh/config`. The server sees them as the user they log in as.
+//!
+//! ```no_run
+//! # #[cfg(unix)]
+//! # async fn run(server: std::sync::Arc<usbip::UsbIpServer>) -> std::io::Result<()> {
+//! use usbip::local;
+//!
+//! let listener = local::bind_unix("/run/yk-agentd.sock")?;
+//! local::unix_server_with_shutdown(listener, server, std::future::pending()).await;
+//! # Ok(())
+//! # }
+//! ```
+use crate::acl::Peer;
+use crate::{Transport, UsbIpServer};
+use std::fmt;
+use std::io::{Error, ErrorKind, Result};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::Arc;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::TcpStream;
+
+/// Where a server listens, or where a client finds it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Address {
+    /// `host:port`
+    Tcp(String),
+    /// A Unix domain socket, `unix:PATH`
+    Unix(PathBuf),
+    /// A Windows named pipe, `pipe:NAME` for `\\.\pipe\NAME`
+    Pipe(String),
+    /// A Unix domain socket on the machine `ssh` reaches as `[user@]host[:port]`,
+    /// `ssh:DESTINATION/PATH`
+    Ssh(String, PathBuf),
+}
+
+impl Address {
+    /// Whether this is a local transport rather than TCP
+    pub fn is_local(&self) -> bool {
+        !matches!(self, Address::Tcp(_))
+    }
+}
+
+impl FromStr for Address {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let addr = if let Some(path) = s.strip_prefix("unix:") {
+            Address::Unix(path.into())
+        } else if let Some(name) = s.strip_prefix("pipe:") {
+            Address::Pipe(name.trim_start_matches(r"\\.\pipe\").to_string())
+        } else if let Some(remote) = s.strip_prefix("ssh:") {
+            match remote.find('/') {
+                // Destinations starting with `-` would be taken for options
+                Some(i) if i > 0 && !remote.starts_with('-') => {
+                    Address::Ssh(remote[..i].to_string(), remote[i..].into())
+                }
+                _ => {
+                    return Err(Error::new(
+                        ErrorKind::InvalidInput,
+                        format!("Invalid address {:?}, expected ssh:[USER@]HOST/PATH", s),
+                    ))
+                }
+            }
+        } else {
+            Address::Tcp(s.to_string())
+        };
+        let empty = match &addr {
+            Address::Tcp(addr) => addr.is_empty(),
+            Address::Unix(path) => path.as_os_str().is_empty(),
+            Address::Pipe(name) => name.is_empty(),
+            Address::Ssh(..) => false,
+        };
+        if empty {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!("Invalid address {:?}", s),
+            ));
+        }
+        Ok(addr)
+    }
+}
+
+impl fmt::Display for Address {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Address::Tcp(addr) => write!(f, "{}", addr),
+            Address::Unix(path) => write!(f, "unix:{}", path.display()),
+            Address::Pipe(name) => write!(f, "pipe:{}", name),
+            Address::Ssh(destination, path) => write!(f, "ssh:{}{}", destination, path.display()),
+        }
+    }
+}
+
+/// Connect to the server at `addr`, without TLS
+///
+/// The stream can be used with [crate::client::list_devices],
+/// [crate::client::import] and [crate::client::ImportedDevice]. Transports
+/// this platform lacks fail with [ErrorKind::Unsupported].
+pub async fn connect(addr: &Address) -> Result<Box<dyn Transport>> {
+    match addr {
+        Address::Tcp(addr) => {
+            let socket = TcpStream::connect(addr.as_str()).await?;
+            socket.set_nodelay(true)?;
+            Ok(Box::new(socket))
+        }
+        #[cfg(unix)]
+        Address::Unix(path) => Ok(Box::new(tokio::net::UnixStream::connect(path).await?)),
+        #[cfg(windows)]
+        Address::Pipe(name) => Ok(Box::new(connect_pipe(&pipe_path(name)).await?)),
+        Address::Ssh(destination, path) => Ok(Box::new(connect_ssh(destination, path)?)),
+        _ => Err(Error::new(
+            ErrorKind::Unsupported,
+            format!("{} is not supported on this platform", addr),
+        )),
+    }
+}
+
+/// The standard input and output of an `ssh -W`, which is killed once
+/// dropped
+struct SshStream {
+    _child: tokio::process::Child,
+    stdin: tokio::process::ChildStdin,
+    stdout: tokio::process::ChildStdout,
+}
+
+/// Reach the Unix domain socket at `path` on `destination` with `ssh`
+fn connect_ssh(destination: &str, path: &Path) -> Result<SshStream> {
+    let mut child = tokio::process::Command::new("ssh")
+        .arg("-W")
+        .arg(path)
+        .arg(format!("ssh://{}", destination))
+        .stdin(std::process::Stdio::piped())
+        .stdout(std::process::Stdio::piped())
+        .kill_on_drop(true)
+        .spawn()
+        .map_err(|err| Error::new(err.kind(), format!("Can't run ssh: {}", err)))?;
+    let stdin = child.stdin.take().expect("stdin is piped");
+    let stdout = child.stdout.take().expect("stdout is piped");
+    Ok(SshStream {
+        _child: child,
+        stdin,
+        stdout,
+    })
+}
+
+impl Transport for SshStream {}
+
+impl AsyncRead for SshStream {
+    fn poll_read(
+        mut self: std::pin::Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+        buf: &mut tokio::io::ReadBuf<'_>,
+    ) -> std::task::Poll<Result<()>> {
+        std::pin::Pin::new(&mut self.stdout).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for SshStream {
+    fn poll_write(
+        mut self: std::pin::Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+        buf: &[u8],
+    ) -> std::task::Poll<Result<usize>> {
+        std::pin::Pin::new(&mut self.stdin).poll_write(cx, buf)
+    }
+
+    fn poll_flush(
+        mut self: std::pin::Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<Result<()>> {
+        std::pin::Pin::new(&mut self.stdin).poll_flush(cx)
+    }
+
+    fn poll_shutdown(
+        mut self: std::pin::Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<Result<()>> {
+        std::pin::Pin::new(&mut self.stdin).poll_shutdown(cx)
+    }
+}
+
+#[cfg(unix)]
+impl crate::Listener for tokio::net::UnixListener {
+    type Stream = tokio::net::UnixStream;
+
+    async fn accept(&mut self) -> Result<(tokio::net::UnixStream, Peer)> {
+        let (socket, _) = tokio::net::UnixListener::accept(self).await?;
+        let peer = socket.peer();
+        Ok((socket, peer))
+    }
+}
+
+#[cfg(unix)]
+impl Transport for tokio::net::UnixStream {
+    fn peer(&self) -> Peer {
+        Peer {
+            uid: self.peer_cred().ok().map(|cred| cred.uid()),
+            ..Default::default()
+        }
+    }
+}
+
+/// Listen on the Unix domain socket at `path`
+///
+/// A socket file left behind by a server that is gone is replaced. The file
+/// is created with the process's umask; change its permissions before
+/// serving to let other users in.
+#[cfg(unix)]
+pub fn bind_unix(path: impl AsRef<Path>) -> Result<tokio::net::UnixListener> {
+    let path = path.as_ref();
+    match tokio::net::UnixListener::bind(path) {
+        Err(err)
+            if err.kind() == ErrorKind::AddrInUse
+                && std::os::unix::net::UnixStream::connect(path).is_err() =>
+        {
+            std::fs::remove_file(path)?;
+            tokio::net::UnixListener::bind(path)
+        }
+        res => res,
+    }
+}
+
+/// Spawn a USB/IP server on `listener` (see [bind_unix]) until `shutdown`
+/// completes, see [crate::server_with_shutdown]
+///
+/// Clients are known to an [crate::acl::Policy] by their user id. The socket
+/// file is removed on shutdown.
+#[cfg(unix)]
+pub async fn unix_server_with_shutdown(
+    listener: tokio::net::UnixListener,
+    server: Arc<UsbIpServer>,
+    shutdown: impl std::future::Future<Output = ()>,
+) {
+    let path = listener
+        .local_addr()
+        .ok()
+        .and_then(|addr| addr.as_pathname().map(Path::to_path_buf));
+    inherited_unix_server_with_shutdown(listener, server, shutdown).await;
+    if let Some(path) = path {
+        std::fs::remove_file(path).ok();
+    }
+}
+
+/// Like [unix_server_with_shutdown], on a socket someone else owns, e.g. the
+/// service manager: its file is left in place on shutdown
+#[cfg(unix)]
+pub async fn inherited_unix_server_with_shutdown(
+    listener: tokio::net::UnixListener,
+    server: Arc<UsbIpServer>,
+    shutdown: impl std::future::Future<Output = ()>,
+) {
+    crate::serve(
+        listener,
+        server,
+        shutdown,
+        |socket| async move { Ok(socket) },
+    )
+    .await
+}
+
+/// The full name of the named pipe `name`
+#[cfg(windows)]
+fn pipe_path(name: &str) -> String {
+    format!(r"\\.\pipe\{}", name)
+}
+
+/// Open the client end of the named pipe `path`, waiting while every
+/// instance of it is busy
+#[cfg(windows)]
+async fn connect_pipe(path: &str) -> Result<tokio::net::windows::named_pipe::NamedPipeClient> {
+    // winerror.h
+    const ERROR_PIPE_BUSY: i32 = 231;
+
+    loop {
+        match tokio::net::windows::named_pipe::ClientOptions::new().open(path) {
+            Err(err) if err.raw_os_error() == Some(ERROR_PIPE_BUSY) => {}
+            res => return res,
+        }
+        tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+    }
+}
+
+/// The instances of a named pipe: one waits for the next client while the
+/// others serve theirs
+#[cfg(windows)]
+struct PipeListener {
+    path: String,
+    next: tokio::net::windows::named_pipe::NamedPipeServer,
+}
+
+#[cfg(windows)]
+impl Transport for tokio::net::windows::named_pipe::NamedPipeServer {}
+
+#[cfg(windows)]
+impl Transport for tokio::net::windows::named_pipe::NamedPipeClient {}
+
+#[cfg(windows)]
+impl crate::Listener for PipeListener {
+    type Stream = tokio::net::windows::named_pipe::NamedPipeServer;
+
+    async fn accept(&mut self) -> Result<(Self::Stream, Peer)> {
+        self.next.connect().await?;
+        let next = tokio::net::windows::named_pipe::ServerOptions::new().create(&self.path)?;
+        Ok((std::mem::replace(&mut self.next, next), Peer::default()))
+    }
+}
+
+/// Spawn a USB/IP server on the named pipe `\\.\pipe\NAME` until `shutdown`
+/// completes, see [crate::server_with_shutdown]
+///
+/// Fails if another server already has a pipe with this name.
+#[cfg(windows)]
+pub async fn pipe_server_with_shutdown(
+    name: &str,
+    server: Arc<UsbIpServer>,
+    shutdown: impl std::future::Future<Output = ()>,
+) -> Result<()> {
+    let path = pipe_path(name);
+    let next = tokio::net::windows::named_pipe::ServerOptions::new()
+        .first_pipe_instance(true)
+        .create(&path)?;
+    crate::serve(
+        PipeListener { path, next },
+        server,
+        shutdown,
+        |socket| async move { Ok(socket) },
+    )
+    .await;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_address() {
+        for (s, addr) in [
+            ("localhost:3240", Address::Tcp("localhost:3240".into())),
+            (
+                "unix:/run/yk-agentd.sock",
+                Address::Unix("/run/yk-agentd.sock".into()),
+            ),
+            ("pipe:yk-agentd", Address::Pipe("yk-agentd".into())),
+            (
+                "ssh:alice@host:2222/run/yk-agentd.sock",
+                Address::Ssh("alice@host:2222".into(), "/run/yk-agentd.sock".into()),
+            ),
+        ] {
+            assert_eq!(s.parse::<Address>().unwrap(), addr);
+            assert_eq!(addr.to_string(), s);
+        }
+        assert_eq!(
+            r"pipe:\\.\pipe\yk-agentd".parse::<Address>().unwrap(),
+            Address::Pipe("yk-agentd".into())
+        );
+        assert!(!"[::1]:3240".parse::<Address>().unwrap().is_local());
+        assert!("ssh:host/run/yk-agentd.sock"
+            .parse::<Address>()
+            .unwrap()
+            .is_local());
+        for invalid in [
+            "",
+            "unix:",
+            "pipe:",
+            "ssh:",
+            "ssh:host",
+            "ssh:/run/x",
+            "ssh:-oX/x",
+        ] {
+            assert!(invalid.parse::<Address>().is_err(), "{}", invalid);
+        }
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn unix_socket() {
+        use crate::acl::Policy;
+        use crate::{client, util::tests::setup_test_logger, UsbDevice};
+        use std::os::unix::fs::MetadataExt;
+
+        setup_test_logger();
+        let path = std::env::temp_dir().join(format!("usbip-test-{}.sock", std::process::id()));
+        let device = UsbDevice::new(0);
+        let bus_id = device.bus_id.clone();
+
+        // A socket file left behind is replaced
+        drop(std::os::unix::net::UnixListener::bind(&path).unwrap());
+        let listener = bind_unix(&path).unwrap();
+
+        // Only we may import the device
+        let uid = std::fs::metadata(&path).unwrap().uid();
+        let policy = Policy::parse(&format!("allow uid:{} any", uid)).unwrap();
+        let (tx, rx) = tokio::sync::oneshot::channel::<()>();
+        let task = tokio::spawn(unix_server_with_shutdown(
+            listener,
+            Arc::new(UsbIpServer::new_simulated(vec![device]).with_policy(policy)),
+            async {
+                rx.await.ok();
+            },
+        ));
+
+        let addr = Address::Unix(path.clone());
+        let mut socket = connect(&addr).await.unwrap();
+        assert_eq!(client::list_devices(&mut socket).await.unwrap().len(), 1);
+        let mut socket = connect(&addr).await.unwrap();
+        assert!(client::import(&mut socket, &bus_id).await.is_ok());
+        drop(socket);
+
+        tx.send(()).unwrap();
+        task.await.unwrap();
+        assert!(!path.exists());
+    }
+}
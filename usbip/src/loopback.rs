@@ -0,0 +1,277 @@
+//! In-process devices, for tests
+//!
+//! A [Loopback] serves the devices of a [UsbIpServer] to clients in the same
+//! process, over in-memory pipes, so that tests of device handlers and of the
+//! server itself (its protocol logic, the URBs it counts, unlinking) run fast
+//! and need neither USB hardware nor sockets:
+//!
+//! ```
+//! # #[tokio::main(flavor = "current_thread")]
+//! # async fn main() -> std::io::Result<()> {
+//! use usbip::{loopback::Loopback, UsbDevice};
+//!
+//! let loopback = Loopback::new(vec![UsbDevice::new(0)]);
+//! let device = loopback.import("0-0-0").await?;
+//! // GET_DESCRIPTOR of the device descriptor
+//! let descriptor = device
+//!     .control_in([0x80, 0x06, 0x00, 0x01, 0x00, 0x00, 0x12, 0x00])
+//!     .await?;
+//! assert_eq!(descriptor[1], 0x01);
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! Each connection is served as [crate::serve_connection] does, by a task of
+//! its own, so the tokio runtime of the test must be running.
+use crate::acl::Peer;
+use crate::client::{self, ImportedDevice};
+use crate::transport::WithPeer;
+use crate::{UsbDevice, UsbIpServer};
+use std::io::Result;
+use std::sync::Arc;
+use tokio::io::DuplexStream;
+use tracing::debug;
+
+/// Bytes a pipe buffers in each direction, as much as a socket would
+const PIPE_CAPACITY: usize = 256 * 1024;
+
+/// A server whose clients are in the same process, see the
+/// [module docs](self)
+#[derive(Clone)]
+pub struct Loopback {
+    server: Arc<UsbIpServer>,
+}
+
+impl Loopback {
+    /// Serve simulated `devices`
+    pub fn new(devices: Vec<UsbDevice>) -> Self {
+        Self::with_server(Arc::new(UsbIpServer::new_simulated(devices)))
+    }
+
+    /// Serve the devices of `server`, as it is configured, e.g. with a
+    /// policy or audit records
+    pub fn with_server(server: Arc<UsbIpServer>) -> Self {
+        Self { server }
+    }
+
+    pub fn server(&self) -> &Arc<UsbIpServer> {
+        &self.server
+    }
+
+    /// Open a connection, on which the client is nobody in particular
+    pub fn connect(&self) -> DuplexStream {
+        self.connect_as(Peer::default())
+    }
+
+    /// Open a connection, on which the client is known to the server as
+    /// `peer`
+    pub fn connect_as(&self, peer: Peer) -> DuplexStream {
+        let (socket, server_end) = tokio::io::duplex(PIPE_CAPACITY);
+        let server = self.server.clone();
+        tokio::spawn(async move {
+            let res = crate::serve_connection(WithPeer::new(server_end, peer), server).await;
+            debug!("Loopback connection ended with {:?}", res);
+        });
+        socket
+    }
+
+    /// Import the device with `bus_id` over a new connection
+    pub async fn import(&self, bus_id: &str) -> Result<ImportedDevice> {
+        let mut socket = self.connect();
+        let device = client::import(&mut socket, bus_id).await?;
+        Ok(ImportedDevice::new(socket, device))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::usbip_protocol::{UsbIpCommand, UsbIpHeaderBasic, ECONNRESET};
+    use crate::util::tests::*;
+    use crate::wire::{UsbIpReply, USBIP_CMD_SUBMIT, USBIP_CMD_UNLINK};
+    use crate::{
+        acl, audit, EndpointAttributes, SetupPacket, UrbResult, UsbEndpoint, UsbInterface,
+        UsbInterfaceHandler,
+    };
+    use std::any::Any;
+    use std::sync::Mutex;
+    use std::time::Duration;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    /// Answers IN transfers with their length after a while, and takes OUT
+    /// transfers at once
+    struct SlowHandler;
+
+    impl UsbInterfaceHandler for SlowHandler {
+        fn get_class_specific_descriptor(&self) -> Vec<u8> {
+            vec![]
+        }
+
+        fn handle_urb(
+            &mut self,
+            _: &UsbInterface,
+            ep: UsbEndpoint,
+            transfer_buffer_length: u32,
+            _: SetupPacket,
+            _: &[u8],
+        ) -> UrbResult {
+            if ep.direction() == crate::Direction::Out {
+                return Ok(vec![]);
+            }
+            std::thread::sleep(Duration::from_millis(200));
+            Ok(vec![transfer_buffer_length as u8])
+        }
+
+        fn as_any(&mut self) -> &mut dyn Any {
+            self
+        }
+    }
+
+    fn slow_device() -> UsbDevice {
+        let endpoint = |address, attributes| UsbEndpoint {
+            address,
+            attributes: attributes as u8,
+            max_packet_size: 64,
+            interval: 0,
+        };
+        UsbDevice::new(0).with_interface(
+            0xFF,
+            0,
+            0,
+            "Slow",
+            vec![
+                endpoint(0x81, EndpointAttributes::Interrupt),
+                endpoint(0x02, EndpointAttributes::Bulk),
+            ],
+            Arc::new(Mutex::new(
+                Box::new(SlowHandler) as Box<dyn UsbInterfaceHandler + Send>
+            )),
+        )
+    }
+
+    fn submit(seqnum: u32, ep: u8) -> Vec<u8> {
+        let is_in = ep & 0x80 != 0;
+        UsbIpCommand::UsbIpCmdSubmit {
+            header: UsbIpHeaderBasic {
+                command: USBIP_CMD_SUBMIT.into(),
+                seqnum,
+                devid: 0,
+                direction: is_in as u32,
+                ep: (ep & 0x7F).into(),
+            },
+            transfer_flags: 0,
+            transfer_buffer_length: 4,
+            start_frame: 0,
+            number_of_packets: 0,
+            interval: 0,
+            setup: [0; 8],
+            data: if is_in { vec![] } else { vec![0; 4] },
+            iso_packet_descriptor: vec![],
+        }
+        .to_bytes()
+    }
+
+    fn unlink(seqnum: u32, unlink_seqnum: u32) -> Vec<u8> {
+        UsbIpCommand::UsbIpCmdUnlink {
+            header: UsbIpHeaderBasic {
+                command: USBIP_CMD_UNLINK.into(),
+                seqnum,
+                devid: 0,
+                direction: 0,
+                ep: 0,
+            },
+            unlink_seqnum,
+        }
+        .to_bytes()
+    }
+
+    /// Read the next reply from `socket`, to URBs that are all IN
+    async fn reply(socket: &mut DuplexStream, input: &mut Vec<u8>) -> UsbIpReply {
+        loop {
+            if let Ok((reply, len)) = UsbIpReply::decode(input, |_| true) {
+                input.drain(..len);
+                return reply;
+            }
+            let mut buf = [0; 4096];
+            let n = socket.read(&mut buf).await.unwrap();
+            assert!(n > 0, "Connection closed");
+            input.extend_from_slice(&buf[..n]);
+        }
+    }
+
+    #[tokio::test]
+    async fn urbs_are_counted() {
+        setup_test_logger();
+        let (records, mut audit) = tokio::sync::mpsc::unbounded_channel();
+        let server = UsbIpServer::new_simulated(vec![slow_device()]).with_audit(records);
+        let loopback = Loopback::with_server(Arc::new(server));
+
+        let device = loopback.import("0-0-0").await.unwrap();
+        assert_eq!(device.transfer_in(0x81, 3).await.unwrap(), [3]);
+        device.transfer_out(0x02, vec![1, 2]).await.unwrap();
+        drop(device);
+
+        let usage = loop {
+            match audit.recv().await.unwrap() {
+                audit::Record::Released { usage, .. } => break usage,
+                _ => continue,
+            }
+        };
+        assert_eq!(usage.urbs, 2);
+        assert_eq!(usage.failed_urbs, 0);
+        let metrics = loopback.server().metrics().render();
+        let urbs = metrics
+            .lines()
+            .find(|line| line.starts_with("usbip_urbs_total{bus_id=\"0-0-0\""))
+            .unwrap();
+        assert!(urbs.ends_with(" 2"));
+    }
+
+    #[tokio::test]
+    async fn unlink_in_flight() {
+        setup_test_logger();
+        let loopback = Loopback::new(vec![slow_device()]);
+        let mut socket = loopback.connect();
+        client::import(&mut socket, "0-0-0").await.unwrap();
+        let mut input = vec![];
+
+        socket.write_all(&submit(1, 0x81)).await.unwrap();
+        socket.write_all(&unlink(2, 1)).await.unwrap();
+        match reply(&mut socket, &mut input).await {
+            UsbIpReply::UsbIpRetUnlink { header, status } => {
+                assert_eq!(header.seqnum, 2);
+                assert_eq!(status, -ECONNRESET);
+            }
+            reply => panic!("Unexpected reply {:?}", reply),
+        }
+
+        // The unlinked URB never completes, and the connection goes on
+        socket.write_all(&submit(3, 0x81)).await.unwrap();
+        match reply(&mut socket, &mut input).await {
+            UsbIpReply::UsbIpRetSubmit {
+                header,
+                transfer_buffer,
+                ..
+            } => {
+                assert_eq!(header.seqnum, 3);
+                assert_eq!(transfer_buffer, [4]);
+            }
+            reply => panic!("Unexpected reply {:?}", reply),
+        }
+    }
+
+    #[tokio::test]
+    async fn clients_are_who_they_say() {
+        setup_test_logger();
+        let policy = acl::Policy::parse("allow uid:0 any").unwrap();
+        let server = UsbIpServer::new_simulated(vec![slow_device()]).with_policy(policy);
+        let loopback = Loopback::with_server(Arc::new(server));
+
+        assert!(loopback.import("0-0-0").await.is_err());
+        let mut socket = loopback.connect_as(acl::Peer {
+            uid: Some(0),
+            ..Default::default()
+        });
+        client::import(&mut socket, "0-0-0").await.unwrap();
+    }
+}
@@ -0,0 +1,246 @@
+//! Finding servers on the local network
+//!
+//! [advertise] announces a server over multicast DNS as a DNS-SD service of
+//! type [SERVICE_TYPE], also registered under [YUBIKEY_SERVICE_TYPE] while
+//! it exports a YubiKey, and [discover] browses for them, so that clients on
+//! a LAN needn't be told its host and port. The TXT record of a server says
+//! whether it only speaks TLS (`tls=1`) and lists the serial numbers of its
+//! devices, separated by commas (`serials=12345678,23456789`), as far as they
+//! fit in a TXT string. It is brought up to date as devices come and go.
+//!
+//! Anyone on the network can advertise a server, so discovery only says
+//! where to look: a client still needs the TLS settings and token to trust
+//! and use the server it found.
+use super::*;
+use mdns_sd::{ResolvedService, ServiceDaemon, ServiceEvent, ServiceInfo};
+use std::collections::BTreeMap;
+use std::time::Duration;
+
+/// The DNS-SD service type of USB/IP servers
+pub const SERVICE_TYPE: &str = "_usbip._tcp.local.";
+
+/// The subtype of [SERVICE_TYPE] for servers exporting YubiKeys
+pub const YUBIKEY_SERVICE_TYPE: &str = "_yubikey._sub._usbip._tcp.local.";
+
+const YUBICO_VID: u16 = 0x1050;
+
+/// How often the advertised devices are brought up to date
+const REFRESH_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Longest value of the `serials` property, for it to fit in a TXT string
+const MAX_SERIALS_LENGTH: usize = 255 - "serials=".len();
+
+fn mdns_error(err: mdns_sd::Error) -> std::io::Error {
+    std::io::Error::other(err.to_string())
+}
+
+/// What is advertised of the devices of a server
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+struct Devices {
+    yubikeys: bool,
+    serials: Vec<String>,
+}
+
+impl Devices {
+    async fn of(server: &UsbIpServer) -> Self {
+        let devices = server.devices.all().await;
+        Self {
+            yubikeys: devices.iter().any(|dev| dev.vendor_id == YUBICO_VID),
+            serials: devices
+                .iter()
+                .filter_map(|dev| dev.serial_number())
+                .filter(|serial| !serial.is_empty())
+                .map(str::to_string)
+                .collect(),
+        }
+    }
+}
+
+/// The service of server `instance` on `port`
+fn service_info(instance: &str, port: u16, tls: bool, devices: &Devices) -> Result<ServiceInfo> {
+    let ty = if devices.yubikeys {
+        YUBIKEY_SERVICE_TYPE
+    } else {
+        SERVICE_TYPE
+    };
+    let host: String = instance
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '-' })
+        .collect();
+    let mut serials = String::new();
+    for serial in &devices.serials {
+        if serials.len() + serial.len() + 1 > MAX_SERIALS_LENGTH {
+            warn!("Not advertising every serial, they don't fit in a TXT record");
+            break;
+        }
+        if !serials.is_empty() {
+            serials.push(',');
+        }
+        serials.push_str(serial);
+    }
+    let properties = [("tls", if tls { "1" } else { "0" }), ("serials", &serials)];
+    ServiceInfo::new(
+        ty,
+        instance,
+        &format!("{}.local.", host),
+        "",
+        port,
+        &properties[..],
+    )
+    .map(ServiceInfo::enable_addr_auto)
+    .map_err(mdns_error)
+}
+
+/// Advertises a server until dropped, see [advertise]
+pub struct Advertisement {
+    daemon: ServiceDaemon,
+    fullname: String,
+    refresh: tokio::task::JoinHandle<()>,
+}
+
+impl Drop for Advertisement {
+    fn drop(&mut self) {
+        self.refresh.abort();
+        self.daemon.unregister(&self.fullname).ok();
+        self.daemon.shutdown().ok();
+    }
+}
+
+/// Advertise `server`, listening on `port` and only speaking TLS if `tls`,
+/// as `instance` (e.g. the host name) on the local network
+pub async fn advertise(
+    server: Arc<UsbIpServer>,
+    instance: &str,
+    port: u16,
+    tls: bool,
+) -> Result<Advertisement> {
+    let daemon = ServiceDaemon::new().map_err(mdns_error)?;
+    let mut devices = Devices::of(&server).await;
+    let info = service_info(instance, port, tls, &devices)?;
+    let fullname = info.get_fullname().to_string();
+    daemon.register(info).map_err(mdns_error)?;
+    info!("Advertising {} over mDNS", fullname);
+
+    let refresh = tokio::spawn({
+        let daemon = daemon.clone();
+        let instance = instance.to_string();
+        async move {
+            let mut ticks = tokio::time::interval(REFRESH_INTERVAL);
+            loop {
+                ticks.tick().await;
+                let now = Devices::of(&server).await;
+                if now == devices {
+                    continue;
+                }
+                let res = service_info(&instance, port, tls, &now)
+                    .and_then(|info| daemon.register(info).map_err(mdns_error));
+                match res {
+                    Ok(()) => debug!("Advertising devices {:?}", now.serials),
+                    Err(err) => warn!("Updating the mDNS advertisement failed: {}", err),
+                }
+                devices = now;
+            }
+        }
+    });
+    Ok(Advertisement {
+        daemon,
+        fullname,
+        refresh,
+    })
+}
+
+/// A server found by [discover]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Server {
+    /// The instance name it is advertised as
+    pub name: String,
+    /// Its host name, e.g. `host.local.`
+    pub host: String,
+    /// Where it listens, IPv4 first and loopback last
+    pub addresses: Vec<SocketAddr>,
+    /// Whether it only speaks TLS
+    pub tls: bool,
+    /// The serial numbers of its devices
+    pub serials: Vec<String>,
+}
+
+impl Server {
+    fn from_resolved(service: &ResolvedService) -> Self {
+        let mut addresses: Vec<SocketAddr> = service
+            .addresses
+            .iter()
+            .map(|addr| SocketAddr::new(addr.to_ip_addr(), service.port))
+            .collect();
+        addresses.sort_by_key(|addr| (addr.ip().is_loopback(), addr.is_ipv6(), *addr));
+        Self {
+            name: service
+                .fullname
+                .strip_suffix(&format!(".{}", SERVICE_TYPE))
+                .unwrap_or(&service.fullname)
+                .to_string(),
+            host: service.host.clone(),
+            addresses,
+            tls: service.get_property_val_str("tls") == Some("1"),
+            serials: service
+                .get_property_val_str("serials")
+                .unwrap_or_default()
+                .split(',')
+                .filter(|serial| !serial.is_empty())
+                .map(str::to_string)
+                .collect(),
+        }
+    }
+}
+
+/// Browse the local network for servers during `timeout`
+pub async fn discover(timeout: Duration) -> Result<Vec<Server>> {
+    let daemon = ServiceDaemon::new().map_err(mdns_error)?;
+    let events = daemon.browse(SERVICE_TYPE).map_err(mdns_error)?;
+    let deadline = tokio::time::Instant::now() + timeout;
+    let mut servers = BTreeMap::new();
+    while let Ok(Ok(event)) = tokio::time::timeout_at(deadline, events.recv_async()).await {
+        match event {
+            ServiceEvent::ServiceResolved(service) => {
+                servers.insert(service.fullname.clone(), Server::from_resolved(&service));
+            }
+            ServiceEvent::ServiceRemoved(_, fullname) => {
+                servers.remove(&fullname);
+            }
+            _ => (),
+        }
+    }
+    daemon.shutdown().ok();
+    Ok(servers.into_values().collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn advertised_service() {
+        let devices = Devices {
+            yubikeys: true,
+            serials: vec!["12345678".to_string(), "23456789".to_string()],
+        };
+        let info = service_info("yubikeys at desk 3", 3240, true, &devices).unwrap();
+        assert_eq!(info.get_subtype().as_deref(), Some(YUBIKEY_SERVICE_TYPE));
+        assert_eq!(info.get_hostname(), "yubikeys-at-desk-3.local.");
+
+        let server = Server::from_resolved(&info.as_resolved_service());
+        assert_eq!(server.name, "yubikeys at desk 3");
+        assert!(server.tls);
+        assert_eq!(server.serials, devices.serials);
+
+        // Only the serials that fit are advertised
+        let devices = Devices {
+            yubikeys: false,
+            serials: (0..100).map(|n| format!("{:08}", n)).collect(),
+        };
+        let info = service_info("host", 3240, false, &devices).unwrap();
+        assert_eq!(info.get_subtype(), &None);
+        let server = Server::from_resolved(&info.as_resolved_service());
+        assert!(!server.tls);
+        assert_eq!(server.serials, devices.serials[..27]);
+    }
+}
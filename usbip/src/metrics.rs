@@ -0,0 +1,414 @@
+//! Metrics of a server
+//!
+//! A [crate::UsbIpServer] counts its sessions, and per device the URBs it
+//! handled, the bytes they moved, the ones that failed and how long they
+//! took. [Metrics::render] writes them in the Prometheus text format:
+//!
+//! ```text
+//! usbip_sessions_active 1
+//! usbip_urbs_total{bus_id="1-2",serial="12345678"} 42
+//! usbip_urb_duration_seconds_bucket{bus_id="1-2",serial="12345678",le="0.01"} 40
+//! ```
+//!
+//! Latencies include the time a device waits for the user, so URBs that
+//! wait for a touch land in the top buckets. [Metrics::latencies] gives the
+//! percentiles of the latencies of the recent URBs of each device, which
+//! admins can ask for with [crate::admin::stats], and a [LatencyBudget]
+//! makes the server warn about URBs that take too long.
+use std::collections::{BTreeMap, VecDeque};
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use crate::usbip_protocol::{UsbIpHeaderBasic, UsbIpResponse};
+use crate::{EndpointAttributes, UsbDevice};
+use num_traits::FromPrimitive;
+use tracing::warn;
+
+/// Upper bounds of the latency histogram buckets, in seconds
+const BUCKETS: [f64; 10] = [0.001, 0.005, 0.01, 0.05, 0.1, 0.5, 1.0, 5.0, 10.0, 30.0];
+
+/// URBs per device whose latencies [Metrics::latencies] looks at
+const RECENT_URBS: usize = 1000;
+
+/// The metrics of a server, see the [module docs](self)
+#[derive(Default)]
+pub struct Metrics {
+    sessions_active: AtomicU64,
+    sessions_total: AtomicU64,
+    devices: Mutex<BTreeMap<String, Arc<DeviceMetrics>>>,
+}
+
+/// The metrics of one device, kept for as long as the server runs
+#[derive(Default)]
+pub(crate) struct DeviceMetrics {
+    serial: String,
+    urbs: AtomicU64,
+    errors: AtomicU64,
+    bytes_in: AtomicU64,
+    bytes_out: AtomicU64,
+    /// URBs that took at most the bound of each of [BUCKETS]
+    buckets: [AtomicU64; BUCKETS.len()],
+    duration_micros: AtomicU64,
+    /// How long the last [RECENT_URBS] URBs took
+    recent: Mutex<VecDeque<Duration>>,
+}
+
+/// Percentiles of how long the recent URBs to a device took, see
+/// [Metrics::latencies]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Latencies {
+    pub bus_id: String,
+    /// Empty if the device has none
+    pub serial: String,
+    /// URBs handled since the server started
+    pub urbs: u64,
+    pub p50: Duration,
+    pub p95: Duration,
+    pub p99: Duration,
+}
+
+/// How long URBs may take, by transfer type, before the server warns about
+/// them, see [crate::UsbIpServer::with_latency_budget]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LatencyBudget {
+    pub control: Duration,
+    pub bulk: Duration,
+    pub interrupt: Duration,
+    pub isochronous: Duration,
+}
+
+impl LatencyBudget {
+    /// The same budget for all transfer types
+    pub fn uniform(budget: Duration) -> Self {
+        Self {
+            control: budget,
+            bulk: budget,
+            interrupt: budget,
+            isochronous: budget,
+        }
+    }
+
+    /// Warn if the URB `header` started, to `device`, took longer than its
+    /// budget
+    pub(crate) fn check(&self, device: &UsbDevice, header: &UsbIpHeaderBasic, elapsed: Duration) {
+        let (address, direction) = if header.direction == 0 {
+            (header.ep as u8, "OUT")
+        } else {
+            (header.ep as u8 | 0x80, "IN")
+        };
+        let attributes = device
+            .find_ep(address)
+            .map_or(EndpointAttributes::Control as u8, |(ep, _)| ep.attributes);
+        let (kind, budget) = match EndpointAttributes::from_u8(attributes & 0x03) {
+            Some(EndpointAttributes::Bulk) => ("bulk", self.bulk),
+            Some(EndpointAttributes::Interrupt) => ("interrupt", self.interrupt),
+            Some(EndpointAttributes::Isochronous) => ("isochronous", self.isochronous),
+            _ => ("control", self.control),
+        };
+        if elapsed > budget {
+            warn!(
+                "{} {} URB to {} took {:.1?}: remote link or touch wait?",
+                kind, direction, device.bus_id, elapsed
+            );
+        }
+    }
+}
+
+impl DeviceMetrics {
+    /// Count a URB that `header` starts, answered with `res` after `duration`
+    pub(crate) fn observe(
+        &self,
+        header: &UsbIpHeaderBasic,
+        res: &UsbIpResponse,
+        duration: Duration,
+    ) {
+        self.urbs.fetch_add(1, Ordering::Relaxed);
+        if let UsbIpResponse::UsbIpRetSubmit {
+            status,
+            actual_length,
+            ..
+        } = res
+        {
+            if *status != 0 {
+                self.errors.fetch_add(1, Ordering::Relaxed);
+            }
+            let bytes = if header.direction == 0 {
+                &self.bytes_out
+            } else {
+                &self.bytes_in
+            };
+            bytes.fetch_add(*actual_length as u64, Ordering::Relaxed);
+        }
+        let seconds = duration.as_secs_f64();
+        for (bound, bucket) in BUCKETS.iter().zip(&self.buckets) {
+            if seconds <= *bound {
+                bucket.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+        self.duration_micros
+            .fetch_add(duration.as_micros() as u64, Ordering::Relaxed);
+        let mut recent = self.recent.lock().unwrap();
+        if recent.len() == RECENT_URBS {
+            recent.pop_front();
+        }
+        recent.push_back(duration);
+    }
+}
+
+/// Counts a session for as long as it is alive
+pub(crate) struct Session<'a>(&'a Metrics);
+
+impl Drop for Session<'_> {
+    fn drop(&mut self) {
+        self.0.sessions_active.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+impl Metrics {
+    pub(crate) fn session(&self) -> Session<'_> {
+        self.sessions_active.fetch_add(1, Ordering::Relaxed);
+        self.sessions_total.fetch_add(1, Ordering::Relaxed);
+        Session(self)
+    }
+
+    /// The metrics of `device`
+    pub(crate) fn device(&self, device: &UsbDevice) -> Arc<DeviceMetrics> {
+        self.devices
+            .lock()
+            .unwrap()
+            .entry(device.bus_id.clone())
+            .or_insert_with(|| {
+                Arc::new(DeviceMetrics {
+                    serial: device.serial_number().unwrap_or_default().to_string(),
+                    ..Default::default()
+                })
+            })
+            .clone()
+    }
+
+    /// The percentiles of the latencies of the last URBs to each device, by
+    /// bus id
+    pub fn latencies(&self) -> Vec<Latencies> {
+        self.devices
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(bus_id, device)| {
+                let mut recent: Vec<_> = device.recent.lock().unwrap().iter().copied().collect();
+                recent.sort();
+                // The nearest rank
+                let percentile = |p: usize| {
+                    let rank = (recent.len() * p).div_ceil(100);
+                    recent
+                        .get(rank.saturating_sub(1))
+                        .copied()
+                        .unwrap_or_default()
+                };
+                Latencies {
+                    bus_id: bus_id.clone(),
+                    serial: device.serial.clone(),
+                    urbs: device.urbs.load(Ordering::Relaxed),
+                    p50: percentile(50),
+                    p95: percentile(95),
+                    p99: percentile(99),
+                }
+            })
+            .collect()
+    }
+
+    /// The metrics in the Prometheus text exposition format
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        let devices = self.devices.lock().unwrap();
+        let load = |counter: &AtomicU64| counter.load(Ordering::Relaxed);
+
+        header(
+            &mut out,
+            "usbip_sessions_active",
+            "Connections handling USB/IP requests.",
+            "gauge",
+        );
+        writeln!(out, "usbip_sessions_active {}", load(&self.sessions_active)).unwrap();
+        header(
+            &mut out,
+            "usbip_sessions_total",
+            "Connections that made USB/IP requests.",
+            "counter",
+        );
+        writeln!(out, "usbip_sessions_total {}", load(&self.sessions_total)).unwrap();
+
+        header(&mut out, "usbip_urbs_total", "URBs handled.", "counter");
+        for (bus_id, device) in devices.iter() {
+            let labels = labels(bus_id, &device.serial);
+            writeln!(out, "usbip_urbs_total{{{}}} {}", labels, load(&device.urbs)).unwrap();
+        }
+        header(
+            &mut out,
+            "usbip_urb_errors_total",
+            "URBs that failed.",
+            "counter",
+        );
+        for (bus_id, device) in devices.iter() {
+            let labels = labels(bus_id, &device.serial);
+            writeln!(
+                out,
+                "usbip_urb_errors_total{{{}}} {}",
+                labels,
+                load(&device.errors)
+            )
+            .unwrap();
+        }
+        header(
+            &mut out,
+            "usbip_transfer_bytes_total",
+            "Bytes transferred by URBs.",
+            "counter",
+        );
+        for (bus_id, device) in devices.iter() {
+            let labels = labels(bus_id, &device.serial);
+            for (direction, bytes) in [("in", &device.bytes_in), ("out", &device.bytes_out)] {
+                writeln!(
+                    out,
+                    "usbip_transfer_bytes_total{{{},direction=\"{}\"}} {}",
+                    labels,
+                    direction,
+                    load(bytes)
+                )
+                .unwrap();
+            }
+        }
+
+        let name = "usbip_urb_duration_seconds";
+        header(&mut out, name, "Time taken to answer URBs.", "histogram");
+        for (bus_id, device) in devices.iter() {
+            let labels = labels(bus_id, &device.serial);
+            for (bound, bucket) in BUCKETS.iter().zip(&device.buckets) {
+                writeln!(
+                    out,
+                    "{}_bucket{{{},le=\"{}\"}} {}",
+                    name,
+                    labels,
+                    bound,
+                    load(bucket)
+                )
+                .unwrap();
+            }
+            let count = load(&device.urbs);
+            writeln!(out, "{}_bucket{{{},le=\"+Inf\"}} {}", name, labels, count).unwrap();
+            let sum = load(&device.duration_micros) as f64 / 1e6;
+            writeln!(out, "{}_sum{{{}}} {}", name, labels, sum).unwrap();
+            writeln!(out, "{}_count{{{}}} {}", name, labels, count).unwrap();
+        }
+        out
+    }
+}
+
+fn header(out: &mut String, name: &str, help: &str, kind: &str) {
+    writeln!(out, "# HELP {} {}", name, help).unwrap();
+    writeln!(out, "# TYPE {} {}", name, kind).unwrap();
+}
+
+/// The labels of a device's metrics
+fn labels(bus_id: &str, serial: &str) -> String {
+    let escape = |value: &str| value.replace('\\', "\\\\").replace('"', "\\\"");
+    format!(
+        "bus_id=\"{}\",serial=\"{}\"",
+        escape(bus_id),
+        escape(serial)
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::usbip_protocol::USBIP_CMD_SUBMIT;
+
+    #[test]
+    fn render() {
+        let metrics = Metrics::default();
+        let mut device = UsbDevice::new(0);
+        device.set_serial_number("12345678");
+        let session = metrics.session();
+
+        let header = UsbIpHeaderBasic {
+            command: USBIP_CMD_SUBMIT.into(),
+            seqnum: 1,
+            devid: 0,
+            direction: 1,
+            ep: 0,
+        };
+        let ok = UsbIpResponse::usbip_ret_submit_success(&header, 0, 0, vec![0; 18], vec![]);
+        let failed = UsbIpResponse::usbip_ret_submit_fail(&header);
+        let device_metrics = metrics.device(&device);
+        device_metrics.observe(&header, &ok, Duration::from_millis(2));
+        device_metrics.observe(&header, &failed, Duration::from_secs(2));
+
+        let rendered = metrics.render();
+        let labels = "bus_id=\"0-0-0\",serial=\"12345678\"";
+        for line in [
+            "usbip_sessions_active 1".to_string(),
+            "usbip_sessions_total 1".to_string(),
+            format!("usbip_urbs_total{{{}}} 2", labels),
+            format!("usbip_urb_errors_total{{{}}} 1", labels),
+            format!(
+                "usbip_transfer_bytes_total{{{},direction=\"in\"}} 18",
+                labels
+            ),
+            format!(
+                "usbip_urb_duration_seconds_bucket{{{},le=\"0.001\"}} 0",
+                labels
+            ),
+            format!(
+                "usbip_urb_duration_seconds_bucket{{{},le=\"0.005\"}} 1",
+                labels
+            ),
+            format!("usbip_urb_duration_seconds_bucket{{{},le=\"5\"}} 2", labels),
+            format!("usbip_urb_duration_seconds_sum{{{}}} 2.002", labels),
+        ] {
+            assert!(
+                rendered.lines().any(|l| l == line),
+                "{}\n{}",
+                line,
+                rendered
+            );
+        }
+
+        drop(session);
+        assert!(metrics.render().contains("usbip_sessions_active 0\n"));
+    }
+
+    #[test]
+    fn latencies() {
+        let metrics = Metrics::default();
+        let device = UsbDevice::new(0);
+        assert!(metrics.latencies().is_empty());
+
+        let header = UsbIpHeaderBasic {
+            command: USBIP_CMD_SUBMIT.into(),
+            seqnum: 1,
+            devid: 0,
+            direction: 1,
+            ep: 0,
+        };
+        let ok = UsbIpResponse::usbip_ret_submit_success(&header, 0, 0, vec![], vec![]);
+        let device_metrics = metrics.device(&device);
+        // Only the last URBs count
+        device_metrics.observe(&header, &ok, Duration::from_secs(60));
+        for millis in 1..=RECENT_URBS as u64 {
+            device_metrics.observe(&header, &ok, Duration::from_millis(millis));
+        }
+        assert_eq!(
+            metrics.latencies(),
+            [Latencies {
+                bus_id: "0-0-0".into(),
+                serial: "Serial".into(),
+                urbs: RECENT_URBS as u64 + 1,
+                p50: Duration::from_millis(500),
+                p95: Duration::from_millis(950),
+                p99: Duration::from_millis(990),
+            }]
+        );
+    }
+}
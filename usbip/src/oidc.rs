@@ -0,0 +1,308 @@
+//! Bearer tokens from an OpenID Connect issuer
+//!
+//! Instead of a static token, clients can send (see
+//! [crate::client::send_token]) a JWT that the identity provider of an
+//! organisation issued them, e.g. an OAuth2 access token or an OIDC ID token.
+//! With the `oidc` feature, a [Validator] checks it against the issuer: its
+//! signature with the keys the issuer publishes (its JWKS), that it was
+//! issued by it, for the server's audience, and hasn't expired. The claims of
+//! a valid token then become part of the [crate::acl::Peer], so that policy
+//! rules can match them and give clients a [crate::acl::Role]:
+//!
+//! ```text
+//! allow claim:groups=yubikey-ci serial:12345678 role:decrypt-only
+//! allow claim:groups=yubikey-admins any
+//! ```
+//!
+//! String claims match their value, arrays of strings (such as `groups` or
+//! `roles`) any of their elements, and numbers and booleans their JSON form.
+//! Tokens that fail validation are logged and give no claims.
+//!
+//! ```no_run
+//! # async fn run() -> std::io::Result<()> {
+//! use usbip::{oidc, UsbIpServer};
+//!
+//! let validator = oidc::Validator::discover("https://login.example.com", "yk-agentd").await?;
+//! let server = UsbIpServer::new_from_host().with_oidc(validator);
+//! # Ok(())
+//! # }
+//! ```
+use jsonwebtoken::jwk::JwkSet;
+use jsonwebtoken::{Algorithm, DecodingKey, Validation};
+use serde_json::Value;
+use std::io::{Error, ErrorKind, Result};
+use std::str::FromStr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tracing::*;
+
+/// How long fetching the configuration or keys of the issuer may take
+const FETCH_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Shortest time between two fetches of the keys, which a token signed with
+/// an unknown key triggers
+const MIN_REFRESH_INTERVAL: Duration = Duration::from_secs(60);
+
+fn invalid(err: impl std::fmt::Display) -> Error {
+    Error::new(ErrorKind::InvalidData, err.to_string())
+}
+
+/// Whether `token` looks like a JWT rather than a static token
+pub fn is_jwt(token: &str) -> bool {
+    token.split('.').count() == 3
+}
+
+/// Fetch the JSON document at `url`, which must be HTTPS unless it is on
+/// this machine
+fn fetch_json(url: &str) -> Result<Value> {
+    let local = ["http://localhost", "http://127.0.0.1", "http://[::1]"]
+        .iter()
+        .any(|prefix| url.starts_with(prefix));
+    if !url.starts_with("https://") && !local {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            format!("{} isn't an HTTPS URL", url),
+        ));
+    }
+    let body = ureq::get(url)
+        .timeout(FETCH_TIMEOUT)
+        .call()
+        .map_err(Error::other)?
+        .into_string()?;
+    serde_json::from_str(&body).map_err(invalid)
+}
+
+/// The claims of a token, as the name and value pairs [crate::acl] matches
+fn flatten_claims(claims: serde_json::Map<String, Value>) -> Vec<(String, String)> {
+    let mut flat = vec![];
+    for (name, value) in claims {
+        match value {
+            Value::String(value) => flat.push((name, value)),
+            Value::Array(values) => flat.extend(
+                values
+                    .into_iter()
+                    .filter_map(|value| Some((name.clone(), value.as_str()?.to_string()))),
+            ),
+            Value::Bool(_) | Value::Number(_) => flat.push((name, value.to_string())),
+            Value::Null | Value::Object(_) => (),
+        }
+    }
+    flat
+}
+
+struct Keys {
+    set: JwkSet,
+    fetched: Instant,
+}
+
+/// Checks the bearer tokens of an OpenID Connect issuer, see the
+/// [module docs](self)
+pub struct Validator {
+    issuer: String,
+    audience: String,
+    /// Where the keys are fetched again from when a token is signed with one
+    /// we don't know, if anywhere
+    jwks_uri: Option<String>,
+    keys: Mutex<Keys>,
+}
+
+impl Validator {
+    /// Validate tokens of `issuer` for `audience` with the keys in `jwks`,
+    /// which are never fetched again
+    pub fn new(issuer: &str, audience: &str, jwks: JwkSet) -> Self {
+        Self {
+            issuer: issuer.to_string(),
+            audience: audience.to_string(),
+            jwks_uri: None,
+            keys: Mutex::new(Keys {
+                set: jwks,
+                fetched: Instant::now(),
+            }),
+        }
+    }
+
+    /// Validate tokens of `issuer` for `audience`, fetching its keys from the
+    /// `jwks_uri` of its discovery document
+    /// (`{issuer}/.well-known/openid-configuration`)
+    pub async fn discover(issuer: &str, audience: &str) -> Result<Self> {
+        let url = format!(
+            "{}/.well-known/openid-configuration",
+            issuer.trim_end_matches('/')
+        );
+        let config = tokio::task::spawn_blocking(move || fetch_json(&url))
+            .await
+            .map_err(Error::other)??;
+        if config["issuer"].as_str() != Some(issuer) {
+            return Err(invalid(format!(
+                "The discovery document is for issuer {}",
+                config["issuer"]
+            )));
+        }
+        let jwks_uri = config["jwks_uri"]
+            .as_str()
+            .ok_or_else(|| invalid("The discovery document has no jwks_uri"))?
+            .to_string();
+        let jwks = Self::fetch_keys(&jwks_uri).await?;
+        info!(
+            "Validating tokens of {} with {} keys",
+            issuer,
+            jwks.keys.len()
+        );
+        Ok(Self {
+            jwks_uri: Some(jwks_uri),
+            ..Self::new(issuer, audience, jwks)
+        })
+    }
+
+    async fn fetch_keys(jwks_uri: &str) -> Result<JwkSet> {
+        let jwks_uri = jwks_uri.to_string();
+        let jwks = tokio::task::spawn_blocking(move || fetch_json(&jwks_uri))
+            .await
+            .map_err(Error::other)??;
+        serde_json::from_value(jwks).map_err(invalid)
+    }
+
+    /// The key with id `kid`, or the only one if the token doesn't say
+    fn find_key(&self, kid: Option<&str>) -> Option<jsonwebtoken::jwk::Jwk> {
+        let keys = self.keys.lock().unwrap();
+        match kid {
+            Some(kid) => keys.set.find(kid).cloned(),
+            None if keys.set.keys.len() == 1 => keys.set.keys.first().cloned(),
+            None => None,
+        }
+    }
+
+    /// Fetch the keys again, unless that was done a moment ago
+    async fn refresh(&self) -> Result<()> {
+        let Some(jwks_uri) = &self.jwks_uri else {
+            return Ok(());
+        };
+        if self.keys.lock().unwrap().fetched.elapsed() < MIN_REFRESH_INTERVAL {
+            return Ok(());
+        }
+        debug!("Fetching the keys of {} again", self.issuer);
+        let set = Self::fetch_keys(jwks_uri).await?;
+        *self.keys.lock().unwrap() = Keys {
+            set,
+            fetched: Instant::now(),
+        };
+        Ok(())
+    }
+
+    /// Check `token`, returning its claims as name and value pairs
+    pub async fn validate(&self, token: &str) -> Result<Vec<(String, String)>> {
+        let header = jsonwebtoken::decode_header(token).map_err(invalid)?;
+        if matches!(
+            header.alg,
+            Algorithm::HS256 | Algorithm::HS384 | Algorithm::HS512
+        ) {
+            return Err(invalid(
+                "Tokens signed with a shared secret aren't accepted",
+            ));
+        }
+        let kid = header.kid.as_deref();
+        let jwk = match self.find_key(kid) {
+            Some(jwk) => jwk,
+            None => {
+                self.refresh().await?;
+                self.find_key(kid)
+                    .ok_or_else(|| invalid(format!("Unknown key {:?}", kid)))?
+            }
+        };
+        if let Some(alg) = jwk.common.key_algorithm {
+            if Algorithm::from_str(&alg.to_string()).ok() != Some(header.alg) {
+                return Err(invalid(format!("Key {:?} is for {}", kid, alg)));
+            }
+        }
+
+        let key = DecodingKey::from_jwk(&jwk).map_err(invalid)?;
+        let mut validation = Validation::new(header.alg);
+        validation.set_issuer(&[&self.issuer]);
+        validation.set_audience(&[&self.audience]);
+        validation.set_required_spec_claims(&["exp", "iss", "aud"]);
+        let data = jsonwebtoken::decode::<serde_json::Map<String, Value>>(token, &key, &validation)
+            .map_err(invalid)?;
+        Ok(flatten_claims(data.claims))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+    use base64::Engine;
+    use jsonwebtoken::{EncodingKey, Header};
+    use serde_json::json;
+
+    const ISSUER: &str = "https://login.example.com";
+
+    /// A validator and the key its issuer signs with
+    fn issuer() -> (Validator, EncodingKey) {
+        let key = rcgen::KeyPair::generate_for(&rcgen::PKCS_ECDSA_P256_SHA256).unwrap();
+        let point = key.public_key_raw();
+        let jwks = json!({
+            "keys": [{
+                "kty": "EC",
+                "crv": "P-256",
+                "kid": "k1",
+                "alg": "ES256",
+                "x": URL_SAFE_NO_PAD.encode(&point[1..33]),
+                "y": URL_SAFE_NO_PAD.encode(&point[33..]),
+            }]
+        });
+        let validator = Validator::new(ISSUER, "yk-agentd", serde_json::from_value(jwks).unwrap());
+        let key = EncodingKey::from_ec_pem(key.serialize_pem().as_bytes()).unwrap();
+        (validator, key)
+    }
+
+    fn token(key: &EncodingKey, kid: &str, claims: Value) -> String {
+        let mut header = Header::new(Algorithm::ES256);
+        header.kid = Some(kid.to_string());
+        jsonwebtoken::encode(&header, &claims, key).unwrap()
+    }
+
+    #[tokio::test]
+    async fn validate() {
+        let (validator, key) = issuer();
+        let exp = jsonwebtoken::get_current_timestamp() + 300;
+        let claims = json!({
+            "iss": ISSUER,
+            "aud": "yk-agentd",
+            "exp": exp,
+            "sub": "ci-runner",
+            "groups": ["yubikey-ci", "developers"],
+            "email_verified": true,
+        });
+        let token = token(&key, "k1", claims.clone());
+        assert!(is_jwt(&token));
+        let claims = validator.validate(&token).await.unwrap();
+        assert!(claims.contains(&("sub".into(), "ci-runner".into())));
+        assert!(claims.contains(&("groups".into(), "yubikey-ci".into())));
+        assert!(claims.contains(&("groups".into(), "developers".into())));
+        assert!(claims.contains(&("email_verified".into(), "true".into())));
+
+        for claims in [
+            json!({"iss": ISSUER, "aud": "other", "exp": exp}),
+            json!({"iss": "https://evil.example.com", "aud": "yk-agentd", "exp": exp}),
+            json!({"iss": ISSUER, "aud": "yk-agentd", "exp": exp - 3600}),
+            json!({"iss": ISSUER, "aud": "yk-agentd"}),
+        ] {
+            let token = self::token(&key, "k1", claims.clone());
+            assert!(validator.validate(&token).await.is_err(), "{}", claims);
+        }
+
+        // Another key, and tampered tokens
+        let (_, other_key) = issuer();
+        let claims = json!({"iss": ISSUER, "aud": "yk-agentd", "exp": exp});
+        assert!(validator
+            .validate(&self::token(&other_key, "k1", claims.clone()))
+            .await
+            .is_err());
+        assert!(validator
+            .validate(&self::token(&key, "k2", claims))
+            .await
+            .is_err());
+        assert!(validator.validate(&format!("{}x", token)).await.is_err());
+        assert!(!is_jwt("s3cret"));
+    }
+}
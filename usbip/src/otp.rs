@@ -0,0 +1,295 @@
+//! Typing YubiKey OTPs on the client
+//!
+//! Touching a YubiKey makes its OTP interface, a keyboard, type a one-time
+//! password. When the YubiKey itself is exported, clients get that keyboard
+//! with the rest of the device; when only its card is shared (see
+//! `pcsc::reader_device`), the OTP would be typed on the server instead. A
+//! [Forwarder] then reads what the YubiKey types on the host, and an
+//! [OtpKeyboard], a simulated keyboard clients import like any device, types
+//! it again on the client.
+use super::*;
+use crate::hid::{UsbHidKeyboardHandler, UsbHidKeyboardReport};
+use rusb::{DeviceHandle, GlobalContext};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+
+/// Product id of a YubiKey with only its OTP interface enabled
+pub const YUBIKEY_OTP_PRODUCT_ID: u16 = 0x0401;
+
+/// The modhex alphabet OTPs are typed in, whose letters sit on the same
+/// keys in most keyboard layouts
+pub const MODHEX: &[u8; 16] = b"cbdefghijklnrtuv";
+
+/// Shortest and longest OTP typed: a Yubico OTP without and with a 16 byte
+/// public id
+const OTP_LENGTHS: std::ops::RangeInclusive<usize> = 32..=64;
+
+/// How long reads of the keyboard of the YubiKey wait for a key, so that
+/// [Forwarder]s stop soon after they are dropped
+const READ_TIMEOUT: Duration = Duration::from_millis(500);
+
+/// HID usage of Enter, and the left and right shift bits of the modifiers
+const KEY_ENTER: u8 = 40;
+const SHIFT: u8 = 0x22;
+
+/// Whether `s` is a modhex string
+pub fn is_modhex(s: &str) -> bool {
+    s.bytes().all(|c| MODHEX.contains(&c))
+}
+
+/// A simulated keyboard typing the OTPs given to [OtpKeyboard::type_otp]
+///
+/// Clones type on the same keyboard.
+#[derive(Clone)]
+pub struct OtpKeyboard {
+    handler: Arc<Mutex<Box<dyn UsbInterfaceHandler + Send>>>,
+}
+
+impl Default for OtpKeyboard {
+    fn default() -> Self {
+        Self {
+            handler: Arc::new(Mutex::new(Box::new(UsbHidKeyboardHandler::new_keyboard()))),
+        }
+    }
+}
+
+impl OtpKeyboard {
+    /// The device to export, named `0-0-{index}` and carrying the serial
+    /// number of the YubiKey it types for, if known
+    pub fn device(&self, index: u32, serial: Option<&str>) -> UsbDevice {
+        let mut builder = UsbDeviceBuilder::new(index)
+            .with_speed(UsbSpeed::Full)
+            .with_ids(YUBICO_VENDOR_ID, YUBIKEY_OTP_PRODUCT_ID)
+            .with_manufacturer_name("Yubico")
+            .with_product_name("YubiKey OTP (remote)")
+            .with_interface(
+                UsbInterfaceBuilder::new(ClassCode::HID as u8, 0x01, 0x01, self.handler.clone())
+                    .with_name("Keyboard")
+                    .with_interrupt_in(0x81, 8, 10),
+            );
+        if let Some(serial) = serial {
+            builder = builder.with_serial_number(serial);
+        }
+        builder.build().expect("the OTP keyboard is a valid device")
+    }
+
+    /// Type `otp` and Enter on the clients that imported the keyboard
+    pub fn type_otp(&self, otp: &str) -> Result<()> {
+        if !OTP_LENGTHS.contains(&otp.len()) || !is_modhex(otp) {
+            return Err(std::io::Error::new(
+                ErrorKind::InvalidInput,
+                "Not a modhex OTP",
+            ));
+        }
+        let mut handler = self.handler.lock().unwrap();
+        let keyboard = handler
+            .as_any()
+            .downcast_mut::<UsbHidKeyboardHandler>()
+            .expect("an OtpKeyboard holds a keyboard");
+        keyboard.pending_key_events.extend(
+            otp.bytes()
+                .chain(Some(b'\n'))
+                .map(UsbHidKeyboardReport::from_ascii),
+        );
+        Ok(())
+    }
+}
+
+/// Turns the reports of a boot protocol keyboard back into the lines it
+/// typed
+#[derive(Debug, Default)]
+pub struct KeyboardDecoder {
+    line: String,
+    pressed: Vec<u8>,
+}
+
+impl KeyboardDecoder {
+    /// Decode `report` (modifiers, a reserved byte and six keys), returning
+    /// the line typed once Enter is pressed
+    pub fn feed(&mut self, report: &[u8]) -> Option<String> {
+        let (&modifiers, keys) = report.split_first()?;
+        let keys: Vec<u8> = keys.iter().skip(1).copied().filter(|&k| k != 0).collect();
+        let mut line = None;
+        for &key in keys.iter().filter(|key| !self.pressed.contains(key)) {
+            let shift = modifiers & SHIFT != 0;
+            match key {
+                4..=29 if shift => self.line.push((b'A' + key - 4) as char),
+                4..=29 => self.line.push((b'a' + key - 4) as char),
+                30..=38 => self.line.push((b'1' + key - 30) as char),
+                39 => self.line.push('0'),
+                KEY_ENTER => line = Some(std::mem::take(&mut self.line)),
+                _ => trace!("Ignoring key {}", key),
+            }
+        }
+        self.pressed = keys;
+        line
+    }
+}
+
+/// Forwards what the OTP interface of a YubiKey of the host types to an
+/// [OtpKeyboard], until dropped
+pub struct Forwarder {
+    stop: Arc<AtomicBool>,
+    thread: Option<std::thread::JoinHandle<()>>,
+}
+
+impl Drop for Forwarder {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        if let Some(thread) = self.thread.take() {
+            thread.join().ok();
+        }
+    }
+}
+
+/// The number and interrupt IN endpoint of the keyboard interface of `dev`
+fn keyboard_interface(dev: &Device<GlobalContext>) -> Option<(u8, u8)> {
+    let cfg = dev.active_config_descriptor().ok()?;
+    cfg.interfaces()
+        .flat_map(|intf| intf.descriptors())
+        .filter(|desc| {
+            desc.class_code() == ClassCode::HID as u8
+                && desc.sub_class_code() == 0x01
+                && desc.protocol_code() == 0x01
+        })
+        .find_map(|desc| {
+            let ep = desc.endpoint_descriptors().find(|ep| {
+                ep.direction() == rusb::Direction::In
+                    && ep.transfer_type() == rusb::TransferType::Interrupt
+            })?;
+            Some((desc.interface_number(), ep.address()))
+        })
+}
+
+/// Forward the OTPs `dev` types to `keyboard`
+///
+/// This claims the keyboard interface of `dev`, so that OTPs are no longer
+/// typed on the host.
+pub fn forward(dev: &Device<GlobalContext>, keyboard: OtpKeyboard) -> Result<Forwarder> {
+    let (interface, endpoint) = keyboard_interface(dev).ok_or_else(|| {
+        std::io::Error::new(ErrorKind::NotFound, "The device has no OTP keyboard")
+    })?;
+    let handle = dev.open().map_err(std::io::Error::other)?;
+    handle.set_auto_detach_kernel_driver(true).ok();
+    handle
+        .claim_interface(interface)
+        .map_err(std::io::Error::other)?;
+
+    let stop = Arc::new(AtomicBool::new(false));
+    let thread = std::thread::spawn({
+        let stop = stop.clone();
+        move || read_otps(handle, endpoint, keyboard, &stop)
+    });
+    Ok(Forwarder {
+        stop,
+        thread: Some(thread),
+    })
+}
+
+/// Forward the OTPs of every YubiKey of the host with an OTP interface, each
+/// to an [OtpKeyboard] of its own, exported as `0-0-{first_index}` onwards
+pub fn forward_host(first_index: u32) -> Result<Vec<(UsbDevice, Forwarder)>> {
+    let mut keyboards = vec![];
+    for dev in rusb::devices().map_err(std::io::Error::other)?.iter() {
+        let Ok(desc) = dev.device_descriptor() else {
+            continue;
+        };
+        if desc.vendor_id() != YUBICO_VENDOR_ID || keyboard_interface(&dev).is_none() {
+            continue;
+        }
+        let serial = dev
+            .open()
+            .and_then(|handle| handle.read_serial_number_string_ascii(&desc))
+            .ok();
+        let keyboard = OtpKeyboard::default();
+        let index = first_index + keyboards.len() as u32;
+        match forward(&dev, keyboard.clone()) {
+            Ok(forwarder) => {
+                info!(
+                    "Forwarding the OTPs of YubiKey {} to 0-0-{}",
+                    serial.as_deref().unwrap_or("?"),
+                    index
+                );
+                keyboards.push((keyboard.device(index, serial.as_deref()), forwarder));
+            }
+            Err(err) => warn!("Not forwarding the OTPs of a YubiKey: {}", err),
+        }
+    }
+    Ok(keyboards)
+}
+
+fn read_otps(
+    handle: DeviceHandle<GlobalContext>,
+    endpoint: u8,
+    keyboard: OtpKeyboard,
+    stop: &AtomicBool,
+) {
+    let mut decoder = KeyboardDecoder::default();
+    let mut report = [0; 8];
+    while !stop.load(Ordering::Relaxed) {
+        match handle.read_interrupt(endpoint, &mut report, READ_TIMEOUT) {
+            Ok(len) => {
+                if let Some(otp) = decoder.feed(&report[..len]) {
+                    match keyboard.type_otp(&otp) {
+                        Ok(()) => info!("Typing an OTP on the clients"),
+                        Err(err) => warn!("Not forwarding what the YubiKey typed: {}", err),
+                    }
+                }
+            }
+            Err(rusb::Error::Timeout) => (),
+            Err(err) => {
+                warn!("Reading OTPs failed: {}", err);
+                return;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const OTP: &str = "ccccccbchvthlivuitriujjifivbvtrjkjfirllluurf";
+
+    fn report(modifiers: u8, key: u8) -> [u8; 8] {
+        [modifiers, 0, key, 0, 0, 0, 0, 0]
+    }
+
+    #[test]
+    fn decode() {
+        let mut decoder = KeyboardDecoder::default();
+        for c in OTP.bytes() {
+            let key = UsbHidKeyboardReport::from_ascii(c).keys[0];
+            assert_eq!(decoder.feed(&report(0, key)), None);
+            assert_eq!(decoder.feed(&[0; 8]), None);
+        }
+        assert_eq!(decoder.feed(&report(0, KEY_ENTER)).as_deref(), Some(OTP));
+
+        // Held keys are typed once, shift makes capitals
+        assert_eq!(decoder.feed(&report(0x02, 4)), None);
+        assert_eq!(decoder.feed(&report(0x02, 4)), None);
+        assert_eq!(decoder.feed(&[0, 0, 4, 30, 0, 0, 0, 0]), None);
+        assert_eq!(decoder.feed(&report(0, KEY_ENTER)).as_deref(), Some("A1"));
+    }
+
+    #[test]
+    fn type_otp() {
+        let keyboard = OtpKeyboard::default();
+        let device = keyboard.device(0, Some("12345678"));
+        assert_eq!(device.serial_number(), Some("12345678"));
+
+        assert!(keyboard.type_otp("not an otp").is_err());
+        assert!(keyboard.type_otp(OTP).is_ok());
+
+        // Read the keys back as a client would, and decode them
+        let intf = &device.interfaces[0];
+        let ep = intf.endpoints[0];
+        let mut decoder = KeyboardDecoder::default();
+        let mut typed = None;
+        for _ in 0..2 * 45 {
+            let res = intf.handle_urb(ep, 8, SetupPacket::default(), &[]).unwrap();
+            typed = typed.or(decoder.feed(&res));
+        }
+        assert_eq!(typed.as_deref(), Some(OTP));
+    }
+}
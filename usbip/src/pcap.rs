@@ -0,0 +1,277 @@
+//! Wireshark captures
+//!
+//! A [Capture] writes the URBs clients submit to a server, and their
+//! completions, as a pcapng file in the format of the Linux usbmon (link type
+//! `LINKTYPE_USB_LINUX_MMAPPED`), which Wireshark's USB dissector decodes
+//! along with the class protocols on top, such as CCID:
+//!
+//! ```no_run
+//! # fn main() -> std::io::Result<()> {
+//! use usbip::{pcap::Capture, UsbIpServer};
+//!
+//! let server = UsbIpServer::new_from_host().with_capture(Capture::create("usbip.pcapng")?);
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! Each URB shows up as a submission, with its setup packet and OUT data, and
+//! a completion, with its status and IN data, tagged with an id unique to
+//! the connection and sequence number. The bus and device numbers are those
+//! of the device id the client used. Unlike [crate::record], nothing is
+//! redacted: a capture holds the PINs sent to smart cards.
+use crate::usbip_protocol::{UsbIpHeaderBasic, UsbIpResponse};
+use crate::{EndpointAttributes, Urb, UsbDevice};
+use std::io::{Result, Write};
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tracing::*;
+
+/// `LINKTYPE_USB_LINUX_MMAPPED`: a 64 byte usbmon header, then the data
+const LINKTYPE_USB_LINUX_MMAPPED: u16 = 220;
+
+const SECTION_HEADER_BLOCK: u32 = 0x0A0D0D0A;
+const INTERFACE_DESCRIPTION_BLOCK: u32 = 1;
+const ENHANCED_PACKET_BLOCK: u32 = 6;
+
+/// The status of a URB that was submitted and not completed yet
+const EINPROGRESS: i32 = 115;
+
+/// A pcapng file URBs are written to, see the [module docs](self)
+pub struct Capture {
+    out: Mutex<Box<dyn Write + Send>>,
+}
+
+/// The fields of a usbmon header
+struct Event<'a> {
+    id: u64,
+    kind: u8,
+    transfer_type: u8,
+    endpoint: u8,
+    devid: u32,
+    setup: Option<[u8; 8]>,
+    /// `0` if `data` is what the URB carried, or why it carries none
+    data_flag: u8,
+    status: i32,
+    length: u32,
+    start_frame: u32,
+    data: &'a [u8],
+}
+
+impl Capture {
+    /// Write a capture to `out`
+    pub fn new(out: impl Write + Send + 'static) -> Result<Self> {
+        let mut out: Box<dyn Write + Send> = Box::new(out);
+        // Host byte order, which readers tell from the magic
+        let mut section = 0x1A2B3C4Du32.to_ne_bytes().to_vec();
+        section.extend_from_slice(&1u16.to_ne_bytes());
+        section.extend_from_slice(&0u16.to_ne_bytes());
+        // Unknown length
+        section.extend_from_slice(&(-1i64).to_ne_bytes());
+        write_block(&mut out, SECTION_HEADER_BLOCK, &section)?;
+        let mut interface = LINKTYPE_USB_LINUX_MMAPPED.to_ne_bytes().to_vec();
+        interface.extend_from_slice(&0u16.to_ne_bytes());
+        // No snapshot length limit
+        interface.extend_from_slice(&0u32.to_ne_bytes());
+        write_block(&mut out, INTERFACE_DESCRIPTION_BLOCK, &interface)?;
+        out.flush()?;
+        Ok(Self {
+            out: Mutex::new(out),
+        })
+    }
+
+    /// Write a capture to a new file at `path`
+    pub fn create(path: impl AsRef<std::path::Path>) -> Result<Self> {
+        let file = std::fs::File::create(path)?;
+        Self::new(std::io::BufWriter::new(file))
+    }
+
+    /// Capture the submission of `urb` to `device`, tagged with `id`
+    pub(crate) fn submit(&self, id: u64, device: &UsbDevice, urb: &Urb) {
+        let (endpoint, transfer_type) = endpoint(device, &urb.header);
+        let out = urb.header.direction == 0;
+        self.write(Event {
+            id,
+            kind: b'S',
+            transfer_type,
+            endpoint,
+            devid: urb.header.devid,
+            setup: (transfer_type == 2).then_some(urb.setup),
+            data_flag: if out { 0 } else { b'<' },
+            status: -EINPROGRESS,
+            length: urb.transfer_buffer_length,
+            start_frame: urb.start_frame,
+            data: if out { &urb.data } else { &[] },
+        });
+    }
+
+    /// Capture the completion of the URB with `header` to `device`, tagged
+    /// with `id`, which `res` answered
+    pub(crate) fn complete(
+        &self,
+        id: u64,
+        device: &UsbDevice,
+        header: &UsbIpHeaderBasic,
+        res: &UsbIpResponse,
+    ) {
+        let UsbIpResponse::UsbIpRetSubmit {
+            status,
+            actual_length,
+            start_frame,
+            transfer_buffer,
+            ..
+        } = res
+        else {
+            return;
+        };
+        let (endpoint, transfer_type) = endpoint(device, header);
+        let out = header.direction == 0;
+        self.write(Event {
+            id,
+            kind: if *status == 0 { b'C' } else { b'E' },
+            transfer_type,
+            endpoint,
+            devid: header.devid,
+            setup: None,
+            data_flag: if out { b'>' } else { 0 },
+            status: *status as i32,
+            length: *actual_length,
+            start_frame: *start_frame,
+            data: if out { &[] } else { transfer_buffer },
+        });
+    }
+
+    fn write(&self, event: Event) {
+        let time = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        let mut packet = Vec::with_capacity(64 + event.data.len());
+        packet.extend_from_slice(&event.id.to_ne_bytes());
+        packet.extend_from_slice(&[event.kind, event.transfer_type, event.endpoint]);
+        packet.push(event.devid as u8);
+        packet.extend_from_slice(&((event.devid >> 16) as u16).to_ne_bytes());
+        packet.push(if event.setup.is_some() { 0 } else { b'-' });
+        packet.push(event.data_flag);
+        packet.extend_from_slice(&(time.as_secs() as i64).to_ne_bytes());
+        packet.extend_from_slice(&(time.subsec_micros() as i32).to_ne_bytes());
+        packet.extend_from_slice(&event.status.to_ne_bytes());
+        packet.extend_from_slice(&event.length.to_ne_bytes());
+        packet.extend_from_slice(&(event.data.len() as u32).to_ne_bytes());
+        packet.extend_from_slice(&event.setup.unwrap_or_default());
+        // Interval, start frame, transfer flags and isochronous descriptors
+        packet.extend_from_slice(&0i32.to_ne_bytes());
+        packet.extend_from_slice(&event.start_frame.to_ne_bytes());
+        packet.extend_from_slice(&0u32.to_ne_bytes());
+        packet.extend_from_slice(&0u32.to_ne_bytes());
+        packet.extend_from_slice(event.data);
+
+        let micros = time.as_micros() as u64;
+        let mut block = 0u32.to_ne_bytes().to_vec();
+        block.extend_from_slice(&((micros >> 32) as u32).to_ne_bytes());
+        block.extend_from_slice(&(micros as u32).to_ne_bytes());
+        block.extend_from_slice(&(packet.len() as u32).to_ne_bytes());
+        block.extend_from_slice(&(packet.len() as u32).to_ne_bytes());
+        block.extend_from_slice(&packet);
+        let mut out = self.out.lock().unwrap();
+        if let Err(err) = write_block(&mut *out, ENHANCED_PACKET_BLOCK, &block).and(out.flush()) {
+            warn!("Writing the capture failed: {}", err);
+        }
+    }
+}
+
+/// The endpoint address the URB with `header` went to, and the usbmon
+/// transfer type of that endpoint
+fn endpoint(device: &UsbDevice, header: &UsbIpHeaderBasic) -> (u8, u8) {
+    let address = if header.direction == 0 {
+        header.ep as u8
+    } else {
+        header.ep as u8 | 0x80
+    };
+    let attributes = device
+        .find_ep(address)
+        .map_or(EndpointAttributes::Control as u8, |(ep, _)| ep.attributes);
+    // usbmon numbers them in another order than descriptors
+    let transfer_type = match attributes & 0x03 {
+        0 => 2,
+        1 => 0,
+        2 => 3,
+        _ => 1,
+    };
+    (address, transfer_type)
+}
+
+/// Write a pcapng block of `kind` around `body`, padded to 32 bits
+fn write_block(out: &mut dyn Write, kind: u32, body: &[u8]) -> Result<()> {
+    let padding = (4 - body.len() % 4) % 4;
+    let length = (12 + body.len() + padding) as u32;
+    out.write_all(&kind.to_ne_bytes())?;
+    out.write_all(&length.to_ne_bytes())?;
+    out.write_all(body)?;
+    out.write_all(&[0; 3][..padding])?;
+    out.write_all(&length.to_ne_bytes())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::loopback::Loopback;
+    use crate::util::tests::*;
+    use crate::UsbIpServer;
+    use std::sync::Arc;
+
+    /// The blocks of a pcapng file, by kind
+    fn blocks(mut file: &[u8]) -> Vec<(u32, &[u8])> {
+        let mut blocks = vec![];
+        while !file.is_empty() {
+            let kind = u32::from_ne_bytes(file[..4].try_into().unwrap());
+            let length = u32::from_ne_bytes(file[4..8].try_into().unwrap()) as usize;
+            assert_eq!(file[length - 4..length], file[4..8]);
+            blocks.push((kind, &file[8..length - 4]));
+            file = &file[length..];
+        }
+        blocks
+    }
+
+    #[tokio::test]
+    async fn capture() {
+        setup_test_logger();
+        let out = SharedBuffer::default();
+        let server = UsbIpServer::new_simulated(vec![UsbDevice::new(0)])
+            .with_capture(Capture::new(out.clone()).unwrap());
+        let loopback = Loopback::with_server(Arc::new(server));
+        let device = loopback.import("0-0-0").await.unwrap();
+        let setup = [0x80, 0x06, 0x00, 0x01, 0x00, 0x00, 0x12, 0x00];
+        let descriptor = device.control_in(setup).await.unwrap();
+
+        let file = out.0.lock().unwrap().clone();
+        let blocks = blocks(&file);
+        assert_eq!(blocks[0].0, SECTION_HEADER_BLOCK);
+        assert_eq!(blocks[0].1[..4], 0x1A2B3C4Du32.to_ne_bytes());
+        assert_eq!(blocks[1].0, INTERFACE_DESCRIPTION_BLOCK);
+        assert_eq!(blocks[1].1[..2], LINKTYPE_USB_LINUX_MMAPPED.to_ne_bytes());
+
+        let packets: Vec<_> = blocks[2..]
+            .iter()
+            .map(|(kind, block)| {
+                assert_eq!(*kind, ENHANCED_PACKET_BLOCK);
+                let length = u32::from_ne_bytes(block[12..16].try_into().unwrap()) as usize;
+                &block[20..20 + length]
+            })
+            .collect();
+        let [submit, complete] = packets[..] else {
+            panic!("{} packets", packets.len());
+        };
+        assert_eq!(submit[..8], complete[..8]);
+        assert_eq!(submit[8..12], [b'S', 2, 0x80, 0]);
+        assert_eq!(submit[14..16], [0, b'<']);
+        assert_eq!(submit[28..32], (-EINPROGRESS).to_ne_bytes());
+        assert_eq!(submit[32..36], 18u32.to_ne_bytes());
+        assert_eq!(submit[40..48], setup);
+        assert_eq!(submit.len(), 64);
+
+        assert_eq!(complete[8..12], [b'C', 2, 0x80, 0]);
+        assert_eq!(complete[14..16], [b'-', 0]);
+        assert_eq!(complete[28..32], 0i32.to_ne_bytes());
+        assert_eq!(complete[36..40], 18u32.to_ne_bytes());
+        assert_eq!(complete[64..], descriptor);
+    }
+}
@@ -0,0 +1,149 @@
+//! PC/SC passthrough
+//!
+//! Exporting a YubiKey with [UsbIpServer::new_from_host] claims its CCID
+//! interface, which takes it away from the host's pcscd. Instead, a
+//! [PcscBackend] talks to the card through the host's PC/SC stack, and
+//! [reader_device] emulates a CCID reader holding it, so that the host and
+//! USB/IP clients can use the card at the same time.
+//!
+//! The card is opened in shared mode. Applications on either side that need
+//! several APDUs in a row (e.g. a PIN verification followed by a signature)
+//! can still be interleaved with the other side's, as with any shared reader.
+use crate::ccid::{CcidBackend, UsbCcidHandler, CCID_SUBCLASS};
+use crate::{ClassCode, UsbDevice, UsbInterfaceHandler, YUBICO_VENDOR_ID};
+use pcsc::{Context, Disposition, Protocols, Scope, ShareMode};
+use std::ffi::{CStr, CString};
+use std::io::{Error, ErrorKind, Result};
+use std::sync::{Arc, Mutex};
+use tracing::*;
+
+/// Product id of a YubiKey with only its CCID interface enabled
+pub const YUBIKEY_CCID_PRODUCT_ID: u16 = 0x0404;
+
+fn pcsc_error(err: pcsc::Error) -> Error {
+    Error::other(format!("PC/SC: {}", err))
+}
+
+/// Names of the PC/SC readers of the host
+pub fn list_readers() -> Result<Vec<String>> {
+    let ctx = Context::establish(Scope::User).map_err(pcsc_error)?;
+    Ok(ctx
+        .list_readers_owned()
+        .map_err(pcsc_error)?
+        .into_iter()
+        .map(|reader| reader.to_string_lossy().into_owned())
+        .collect())
+}
+
+/// A [CcidBackend] for the card in a PC/SC reader of the host
+pub struct PcscBackend {
+    ctx: Context,
+    reader: CString,
+    card: Option<pcsc::Card>,
+}
+
+impl PcscBackend {
+    /// Use the card in the reader named `reader`
+    pub fn new(reader: &str) -> Result<Self> {
+        let reader = CString::new(reader)
+            .map_err(|_| Error::new(ErrorKind::InvalidInput, "Invalid reader name"))?;
+        Ok(Self {
+            ctx: Context::establish(Scope::User).map_err(pcsc_error)?,
+            reader,
+            card: None,
+        })
+    }
+
+    fn reader(&self) -> &CStr {
+        &self.reader
+    }
+
+    fn connect(&mut self) -> Result<&pcsc::Card> {
+        if self.card.is_none() {
+            let card = self
+                .ctx
+                .connect(self.reader(), ShareMode::Shared, Protocols::ANY)
+                .map_err(pcsc_error)?;
+            self.card = Some(card);
+        }
+        Ok(self.card.as_ref().unwrap())
+    }
+}
+
+impl CcidBackend for PcscBackend {
+    fn power_on(&mut self) -> Result<Vec<u8>> {
+        // A reset card loses the state another application left in it
+        if let Some(card) = &mut self.card {
+            card.reconnect(ShareMode::Shared, Protocols::ANY, Disposition::ResetCard)
+                .map_err(pcsc_error)?;
+        }
+        let card = self.connect()?;
+        Ok(card.status2_owned().map_err(pcsc_error)?.atr().to_vec())
+    }
+
+    fn power_off(&mut self) -> Result<()> {
+        if let Some(card) = self.card.take() {
+            // Don't leave a verified PIN behind for the next user
+            card.disconnect(Disposition::ResetCard)
+                .map_err(|(_, err)| pcsc_error(err))?;
+        }
+        Ok(())
+    }
+
+    fn transmit(&mut self, apdu: &[u8]) -> Result<Vec<u8>> {
+        let mut buf = vec![0; pcsc::MAX_BUFFER_SIZE_EXTENDED];
+        let card = self.connect()?;
+        let res = match card.transmit(apdu, &mut buf) {
+            // Someone else reset the card, go on with the reset card
+            Err(pcsc::Error::ResetCard) => {
+                debug!("Card was reset, reconnecting");
+                self.card
+                    .as_mut()
+                    .unwrap()
+                    .reconnect(ShareMode::Shared, Protocols::ANY, Disposition::LeaveCard)
+                    .map_err(pcsc_error)?;
+                self.card
+                    .as_ref()
+                    .unwrap()
+                    .transmit(apdu, &mut buf)
+                    .map(<[u8]>::len)
+            }
+            res => res.map(<[u8]>::len),
+        };
+        match res {
+            Ok(len) => {
+                buf.truncate(len);
+                Ok(buf)
+            }
+            Err(err) => {
+                if matches!(err, pcsc::Error::RemovedCard | pcsc::Error::NoSmartcard) {
+                    self.card = None;
+                }
+                Err(pcsc_error(err))
+            }
+        }
+    }
+}
+
+/// A device with a CCID reader holding the card in the PC/SC reader named
+/// `reader`
+///
+/// It uses the ids of a YubiKey with only CCID enabled, so that host drivers
+/// and tools recognise it.
+pub fn reader_device(index: u32, reader: &str) -> Result<UsbDevice> {
+    let handler = UsbCcidHandler::new(PcscBackend::new(reader)?);
+    let mut device = UsbDevice::new(index).with_interface(
+        ClassCode::SmartCard as u8,
+        CCID_SUBCLASS,
+        0x00,
+        reader,
+        UsbCcidHandler::<PcscBackend>::endpoints(),
+        Arc::new(Mutex::new(
+            Box::new(handler) as Box<dyn UsbInterfaceHandler + Send>
+        )),
+    );
+    device.vendor_id = YUBICO_VENDOR_ID;
+    device.product_id = YUBIKEY_CCID_PRODUCT_ID;
+    device.set_product_name(reader);
+    Ok(device)
+}
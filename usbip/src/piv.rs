@@ -0,0 +1,907 @@
+//! An emulated YubiKey PIV applet, for tests
+//!
+//! A [PivCard] answers the APDUs of the PIV applet of a YubiKey 5 in
+//! software, behind a [UsbCcidHandler]: selecting the applet, its serial
+//! number and version, the PIN and PUK and their retries, authentication with
+//! the 3DES management key, P-256 keys generated or imported in any slot,
+//! signatures and ECDH with them under their PIN policies, and the data
+//! objects that hold e.g. certificates. Commands and responses longer than an
+//! APDU are chained as a YubiKey chains them.
+//!
+//! [device] exports the card as a YubiKey, so that PIV clients, e.g. the
+//! `yubikey` crate over PC/SC once the device is attached with `usbip
+//! attach`, or [crate::ccid::RemoteReader] in the same process, run against
+//! it in CI without a physical YubiKey. A new card has the default PIN
+//! ([DEFAULT_PIN]), PUK ([DEFAULT_PUK]) and management key
+//! ([DEFAULT_MANAGEMENT_KEY]). Its reader emulates logical channels, see
+//! [LogicalChannels].
+//!
+//! Only P-256 keys are supported; other algorithms, attestations and moving
+//! keys between slots are refused. Touches are never waited for: a slot with
+//! a touch policy is touched at once.
+use crate::ccid::{CcidBackend, LogicalChannels, UsbCcidHandler, CCID_SUBCLASS};
+use crate::{ClassCode, UsbDevice, UsbInterfaceHandler, YUBICO_VENDOR_ID};
+use des::cipher::{generic_array::GenericArray, BlockEncrypt, KeyInit};
+use des::TdesEde3;
+use p256::ecdsa::{signature::hazmat::PrehashSigner, Signature, SigningKey};
+use p256::elliptic_curve::sec1::ToEncodedPoint;
+use p256::{PublicKey, SecretKey};
+use rand_core::{OsRng, RngCore};
+use std::collections::HashMap;
+use std::io::Result;
+use std::sync::{Arc, Mutex};
+use tracing::*;
+
+/// PIN of a new card
+pub const DEFAULT_PIN: &[u8] = b"123456";
+
+/// PUK of a new card
+pub const DEFAULT_PUK: &[u8] = b"12345678";
+
+/// Management key of a new card
+pub const DEFAULT_MANAGEMENT_KEY: [u8; 24] = [
+    1, 2, 3, 4, 5, 6, 7, 8, 1, 2, 3, 4, 5, 6, 7, 8, 1, 2, 3, 4, 5, 6, 7, 8,
+];
+
+/// Firmware version the card claims
+pub const VERSION: [u8; 3] = [5, 4, 3];
+
+/// Product id of a YubiKey with its OTP, FIDO and CCID interfaces enabled
+const YUBIKEY_PRODUCT_ID: u16 = 0x0407;
+
+/// ATR of a YubiKey 5
+const ATR: &[u8] = &[
+    0x3B, 0xFD, 0x13, 0x00, 0x00, 0x81, 0x31, 0xFE, 0x15, 0x80, 0x73, 0xC0, 0x21, 0xC0, 0x57, 0x59,
+    0x75, 0x62, 0x69, 0x4B, 0x65, 0x79, 0x40,
+];
+
+/// AID of the PIV applet; selecting a prefix of it selects it too
+const PIV_AID: &[u8] = &[
+    0xA0, 0x00, 0x00, 0x03, 0x08, 0x00, 0x00, 0x10, 0x00, 0x01, 0x00,
+];
+
+/// Longest response data sent at once, the rest waits for GET RESPONSE
+const MAX_RESPONSE: usize = 256;
+
+// Instructions
+const INS_VERIFY: u8 = 0x20;
+const INS_CHANGE_REFERENCE: u8 = 0x24;
+const INS_RESET_RETRY: u8 = 0x2C;
+const INS_GENERATE: u8 = 0x47;
+const INS_AUTHENTICATE: u8 = 0x87;
+const INS_SELECT: u8 = 0xA4;
+const INS_GET_RESPONSE: u8 = 0xC0;
+const INS_GET_DATA: u8 = 0xCB;
+const INS_PUT_DATA: u8 = 0xDB;
+const INS_GET_METADATA: u8 = 0xF7;
+const INS_GET_SERIAL: u8 = 0xF8;
+const INS_SET_PIN_RETRIES: u8 = 0xFA;
+const INS_RESET: u8 = 0xFB;
+const INS_GET_VERSION: u8 = 0xFD;
+const INS_IMPORT_KEY: u8 = 0xFE;
+const INS_SET_MANAGEMENT_KEY: u8 = 0xFF;
+
+// Key references and algorithms
+const PIN: u8 = 0x80;
+const PUK: u8 = 0x81;
+const MANAGEMENT_KEY: u8 = 0x9B;
+const ALGORITHM_3DES: u8 = 0x03;
+const ALGORITHM_P256: u8 = 0x11;
+const ALGORITHM_PIN: u8 = 0xFF;
+
+// PIN and touch policies
+const POLICY_DEFAULT: u8 = 0x00;
+const POLICY_NEVER: u8 = 0x01;
+const PIN_POLICY_ONCE: u8 = 0x02;
+const PIN_POLICY_ALWAYS: u8 = 0x03;
+
+/// Data object of the printed information, which only a verified PIN reads
+const PRINTED_OBJECT: u32 = 0x5F_C1_09;
+
+// Status words
+const SW_SUCCESS: u16 = 0x9000;
+const SW_BYTES_REMAINING: u16 = 0x6100;
+const SW_WRONG_PIN: u16 = 0x63C0;
+const SW_WRONG_LENGTH: u16 = 0x6700;
+const SW_SECURITY_STATUS: u16 = 0x6982;
+const SW_AUTH_BLOCKED: u16 = 0x6983;
+const SW_CONDITIONS_NOT_SATISFIED: u16 = 0x6985;
+const SW_INCORRECT_DATA: u16 = 0x6A80;
+const SW_NOT_FOUND: u16 = 0x6A82;
+const SW_INCORRECT_P1P2: u16 = 0x6A86;
+const SW_INS_NOT_SUPPORTED: u16 = 0x6D00;
+
+/// A PIN or PUK
+struct Reference {
+    /// Padded to 8 bytes with 0xFF, as it is sent
+    value: [u8; 8],
+    retries: u8,
+    max_retries: u8,
+}
+
+impl Reference {
+    fn new(value: &[u8]) -> Self {
+        Self {
+            value: pad(value).expect("the default PIN and PUK are short"),
+            retries: 3,
+            max_retries: 3,
+        }
+    }
+
+    /// Check `value`, counting a failure against the retries
+    fn check(&mut self, value: &[u8]) -> std::result::Result<(), u16> {
+        if self.retries == 0 {
+            return Err(SW_AUTH_BLOCKED);
+        }
+        if value != self.value {
+            self.retries -= 1;
+            return Err(SW_WRONG_PIN | u16::from(self.retries));
+        }
+        self.retries = self.max_retries;
+        Ok(())
+    }
+
+    fn is_default(&self, default: &[u8]) -> bool {
+        pad(default) == Some(self.value)
+    }
+}
+
+/// The key in a slot
+struct Key {
+    secret: SecretKey,
+    pin_policy: u8,
+    touch_policy: u8,
+    generated: bool,
+}
+
+/// A software YubiKey PIV applet, see the [module docs](self)
+pub struct PivCard {
+    serial: u32,
+    pin: Reference,
+    puk: Reference,
+    management_key: [u8; 24],
+    pin_verified: bool,
+    /// Whether the management key was authenticated with since the applet
+    /// was selected
+    authenticated: bool,
+    /// The witness the host must decrypt to authenticate with the management
+    /// key
+    witness: Option<[u8; 8]>,
+    keys: HashMap<u8, Key>,
+    objects: HashMap<u32, Vec<u8>>,
+    /// Data of a command chained over several APDUs
+    command: Vec<u8>,
+    /// Response data left for GET RESPONSE
+    response: Vec<u8>,
+}
+
+impl PivCard {
+    /// A new card with `serial`, holding no keys or objects
+    pub fn new(serial: u32) -> Self {
+        Self {
+            serial,
+            pin: Reference::new(DEFAULT_PIN),
+            puk: Reference::new(DEFAULT_PUK),
+            management_key: DEFAULT_MANAGEMENT_KEY,
+            pin_verified: false,
+            authenticated: false,
+            witness: None,
+            keys: HashMap::new(),
+            objects: HashMap::new(),
+            command: vec![],
+            response: vec![],
+        }
+    }
+
+    pub fn serial(&self) -> u32 {
+        self.serial
+    }
+
+    /// Generate a P-256 key in `slot` with the PIN and touch policies as
+    /// YubiKeys number them, as a holder of the management key would,
+    /// returning its public key as an uncompressed SEC1 point
+    pub fn generate(&mut self, slot: u8, pin_policy: u8, touch_policy: u8) -> Vec<u8> {
+        let secret = SecretKey::random(&mut OsRng);
+        let public = secret
+            .public_key()
+            .to_encoded_point(false)
+            .as_bytes()
+            .to_vec();
+        self.keys.insert(
+            slot,
+            Key {
+                secret,
+                pin_policy: effective_pin_policy(slot, pin_policy),
+                touch_policy: effective_touch_policy(touch_policy),
+                generated: true,
+            },
+        );
+        public
+    }
+
+    /// Store `data` in the data object with `tag`, e.g. the certificate of a
+    /// slot, as a holder of the management key would
+    pub fn put_object(&mut self, tag: u32, data: Vec<u8>) {
+        self.objects.insert(tag, data);
+    }
+
+    /// The data object with `tag`
+    pub fn object(&self, tag: u32) -> Option<&[u8]> {
+        self.objects.get(&tag).map(Vec::as_slice)
+    }
+
+    /// Forget what was verified and authenticated, as selecting the applet or
+    /// resetting the card does
+    fn deselect(&mut self) {
+        self.pin_verified = false;
+        self.authenticated = false;
+        self.witness = None;
+        self.command.clear();
+        self.response.clear();
+    }
+
+    /// Send the first part of `data`, leaving the rest for GET RESPONSE
+    fn respond(&mut self, mut data: Vec<u8>) -> Vec<u8> {
+        let sw = if data.len() > MAX_RESPONSE {
+            self.response = data.split_off(MAX_RESPONSE);
+            SW_BYTES_REMAINING | self.response.len().min(0xFF) as u16
+        } else {
+            SW_SUCCESS
+        };
+        data.extend(sw.to_be_bytes());
+        data
+    }
+
+    fn execute(
+        &mut self,
+        ins: u8,
+        p1: u8,
+        p2: u8,
+        data: &[u8],
+    ) -> std::result::Result<Vec<u8>, u16> {
+        match ins {
+            INS_SELECT if p1 == 0x04 => {
+                if data.len() < 5 || !PIV_AID.starts_with(data) {
+                    return Err(SW_NOT_FOUND);
+                }
+                self.deselect();
+                // Application property template: the PIX and authority of
+                // the applet
+                let mut aid = tlv(0x4F, &PIV_AID[5..]);
+                aid.extend(tlv(0x79, &tlv(0x4F, &PIV_AID[..5])));
+                Ok(tlv(0x61, &aid))
+            }
+            INS_GET_VERSION => Ok(VERSION.to_vec()),
+            INS_GET_SERIAL => Ok(self.serial.to_be_bytes().to_vec()),
+            INS_VERIFY => self.verify(p1, p2, data),
+            INS_CHANGE_REFERENCE => {
+                let reference = match p2 {
+                    PIN => &mut self.pin,
+                    PUK => &mut self.puk,
+                    _ => return Err(SW_INCORRECT_P1P2),
+                };
+                let (old, new) = split_references(data)?;
+                reference.check(old)?;
+                reference.value = new;
+                Ok(vec![])
+            }
+            INS_RESET_RETRY if p2 == PIN => {
+                let (puk, pin) = split_references(data)?;
+                self.puk.check(puk)?;
+                self.pin.value = pin;
+                self.pin.retries = self.pin.max_retries;
+                Ok(vec![])
+            }
+            INS_GENERATE => {
+                self.check_authenticated()?;
+                check_key_slot(p2)?;
+                let template = find_tlv(data, 0xAC).ok_or(SW_INCORRECT_DATA)?;
+                if find_tlv(template, 0x80) != Some(&[ALGORITHM_P256]) {
+                    return Err(SW_INCORRECT_DATA);
+                }
+                let policy = |tag| find_tlv(template, tag).map_or(POLICY_DEFAULT, |p| p[0]);
+                let public = self.generate(p2, policy(0xAA), policy(0xAB));
+                let mut response = vec![0x7F, 0x49];
+                response.extend(&tlv(0x86, &public)[..]);
+                // The length of the template
+                response.insert(2, response.len() as u8 - 2);
+                Ok(response)
+            }
+            INS_AUTHENTICATE if p2 == MANAGEMENT_KEY => self.authenticate(p1, data),
+            INS_AUTHENTICATE => self.use_key(p1, p2, data),
+            INS_GET_RESPONSE => {
+                if self.response.is_empty() {
+                    return Err(SW_CONDITIONS_NOT_SATISFIED);
+                }
+                Ok(std::mem::take(&mut self.response))
+            }
+            INS_GET_DATA if (p1, p2) == (0x3F, 0xFF) => {
+                let tag = object_tag(data)?;
+                if tag == PRINTED_OBJECT && !self.pin_verified {
+                    return Err(SW_SECURITY_STATUS);
+                }
+                let object = self.objects.get(&tag).ok_or(SW_NOT_FOUND)?;
+                Ok(tlv(0x53, object))
+            }
+            INS_PUT_DATA if (p1, p2) == (0x3F, 0xFF) => {
+                self.check_authenticated()?;
+                let tag = object_tag(data)?;
+                let object = find_tlv(data, 0x53).ok_or(SW_INCORRECT_DATA)?;
+                if object.is_empty() {
+                    self.objects.remove(&tag);
+                } else {
+                    self.objects.insert(tag, object.to_vec());
+                }
+                Ok(vec![])
+            }
+            INS_GET_METADATA => self.metadata(p2),
+            INS_SET_MANAGEMENT_KEY if p1 == 0xFF => {
+                self.check_authenticated()?;
+                match data {
+                    [ALGORITHM_3DES, MANAGEMENT_KEY, 24, key @ ..] if key.len() == 24 => {
+                        self.management_key.copy_from_slice(key);
+                        Ok(vec![])
+                    }
+                    _ => Err(SW_INCORRECT_DATA),
+                }
+            }
+            INS_IMPORT_KEY => {
+                self.check_authenticated()?;
+                check_key_slot(p2)?;
+                if p1 != ALGORITHM_P256 {
+                    return Err(SW_INCORRECT_DATA);
+                }
+                let scalar = find_tlv(data, 0x06).ok_or(SW_INCORRECT_DATA)?;
+                let secret = SecretKey::from_slice(scalar).map_err(|_| SW_INCORRECT_DATA)?;
+                let policy = |tag| find_tlv(data, tag).map_or(POLICY_DEFAULT, |p| p[0]);
+                self.keys.insert(
+                    p2,
+                    Key {
+                        secret,
+                        pin_policy: effective_pin_policy(p2, policy(0xAA)),
+                        touch_policy: effective_touch_policy(policy(0xAB)),
+                        generated: false,
+                    },
+                );
+                Ok(vec![])
+            }
+            INS_SET_PIN_RETRIES => {
+                self.check_authenticated()?;
+                if !self.pin_verified {
+                    return Err(SW_SECURITY_STATUS);
+                }
+                if p1 == 0 || p2 == 0 {
+                    return Err(SW_INCORRECT_P1P2);
+                }
+                // As on a YubiKey, the PIN and PUK go back to their defaults
+                self.pin = Reference::new(DEFAULT_PIN);
+                self.pin.retries = p1;
+                self.pin.max_retries = p1;
+                self.puk = Reference::new(DEFAULT_PUK);
+                self.puk.retries = p2;
+                self.puk.max_retries = p2;
+                Ok(vec![])
+            }
+            INS_RESET => {
+                if self.pin.retries != 0 || self.puk.retries != 0 {
+                    return Err(SW_CONDITIONS_NOT_SATISFIED);
+                }
+                *self = Self::new(self.serial);
+                Ok(vec![])
+            }
+            _ => Err(SW_INS_NOT_SUPPORTED),
+        }
+    }
+
+    fn verify(&mut self, p1: u8, p2: u8, data: &[u8]) -> std::result::Result<Vec<u8>, u16> {
+        if p2 != PIN {
+            return Err(SW_INCORRECT_P1P2);
+        }
+        if p1 == 0xFF {
+            self.pin_verified = false;
+            return Ok(vec![]);
+        }
+        if data.is_empty() {
+            // Asking for the retries left
+            return match self.pin.retries {
+                _ if self.pin_verified => Ok(vec![]),
+                0 => Err(SW_AUTH_BLOCKED),
+                retries => Err(SW_WRONG_PIN | u16::from(retries)),
+            };
+        }
+        if data.len() != 8 {
+            return Err(SW_WRONG_LENGTH);
+        }
+        let res = self.pin.check(data);
+        self.pin_verified = res.is_ok();
+        res.map(|()| vec![])
+    }
+
+    /// Mutual authentication with the management key, in two steps: the
+    /// card encrypts a witness, and the host returns it decrypted along with
+    /// a challenge of its own for the card to encrypt
+    fn authenticate(&mut self, algorithm: u8, data: &[u8]) -> std::result::Result<Vec<u8>, u16> {
+        if algorithm != ALGORITHM_3DES {
+            return Err(SW_INCORRECT_DATA);
+        }
+        let template = find_tlv(data, 0x7C).ok_or(SW_INCORRECT_DATA)?;
+        let cipher =
+            TdesEde3::new_from_slice(&self.management_key).expect("3DES keys are 24 bytes");
+        match (find_tlv(template, 0x80), find_tlv(template, 0x81)) {
+            (Some([]), None) => {
+                let mut witness = [0; 8];
+                OsRng.fill_bytes(&mut witness);
+                self.witness = Some(witness);
+                let mut block = GenericArray::from(witness);
+                cipher.encrypt_block(&mut block);
+                Ok(tlv(0x7C, &tlv(0x80, &block)))
+            }
+            (Some(decrypted), Some(challenge)) if challenge.len() == 8 => {
+                let witness = self.witness.take().ok_or(SW_CONDITIONS_NOT_SATISFIED)?;
+                if decrypted != witness {
+                    self.authenticated = false;
+                    return Err(SW_SECURITY_STATUS);
+                }
+                self.authenticated = true;
+                let mut block = GenericArray::clone_from_slice(challenge);
+                cipher.encrypt_block(&mut block);
+                Ok(tlv(0x7C, &tlv(0x82, &block)))
+            }
+            _ => Err(SW_INCORRECT_DATA),
+        }
+    }
+
+    /// Sign a hash (tag 0x81) or agree on a secret with a peer's point (tag
+    /// 0x85) with the key in `slot`
+    fn use_key(
+        &mut self,
+        algorithm: u8,
+        slot: u8,
+        data: &[u8],
+    ) -> std::result::Result<Vec<u8>, u16> {
+        let key = self.keys.get(&slot).ok_or(SW_NOT_FOUND)?;
+        if algorithm != ALGORITHM_P256 {
+            return Err(SW_INCORRECT_DATA);
+        }
+        match key.pin_policy {
+            POLICY_NEVER => (),
+            _ if !self.pin_verified => return Err(SW_SECURITY_STATUS),
+            PIN_POLICY_ALWAYS => self.pin_verified = false,
+            _ => (),
+        }
+        if key.touch_policy != POLICY_NEVER {
+            debug!(
+                "Touching slot {:02x} of emulated PIV card {}",
+                slot, self.serial
+            );
+        }
+        let template = find_tlv(data, 0x7C).ok_or(SW_INCORRECT_DATA)?;
+        let output = if let Some(point) = find_tlv(template, 0x85) {
+            let point = PublicKey::from_sec1_bytes(point).map_err(|_| SW_INCORRECT_DATA)?;
+            let shared =
+                p256::ecdh::diffie_hellman(key.secret.to_nonzero_scalar(), point.as_affine());
+            shared.raw_secret_bytes().to_vec()
+        } else if let Some(hash) = find_tlv(template, 0x81) {
+            if hash.len() > 32 {
+                return Err(SW_INCORRECT_DATA);
+            }
+            let signature: Signature = SigningKey::from(&key.secret)
+                .sign_prehash(hash)
+                .map_err(|_| SW_INCORRECT_DATA)?;
+            signature.to_der().as_bytes().to_vec()
+        } else {
+            return Err(SW_INCORRECT_DATA);
+        };
+        Ok(tlv(0x7C, &tlv(0x82, &output)))
+    }
+
+    fn metadata(&self, slot: u8) -> std::result::Result<Vec<u8>, u16> {
+        let mut metadata = vec![];
+        match slot {
+            PIN | PUK => {
+                let (reference, default) = match slot {
+                    PIN => (&self.pin, DEFAULT_PIN),
+                    _ => (&self.puk, DEFAULT_PUK),
+                };
+                metadata.extend(tlv(0x01, &[ALGORITHM_PIN]));
+                metadata.extend(tlv(0x05, &[reference.is_default(default) as u8]));
+                metadata.extend(tlv(0x06, &[reference.max_retries, reference.retries]));
+            }
+            MANAGEMENT_KEY => {
+                metadata.extend(tlv(0x01, &[ALGORITHM_3DES]));
+                metadata.extend(tlv(0x02, &[POLICY_DEFAULT, POLICY_NEVER]));
+                metadata.extend(tlv(
+                    0x05,
+                    &[(self.management_key == DEFAULT_MANAGEMENT_KEY) as u8],
+                ));
+            }
+            slot => {
+                let key = self.keys.get(&slot).ok_or(SW_NOT_FOUND)?;
+                let public = key.secret.public_key().to_encoded_point(false);
+                metadata.extend(tlv(0x01, &[ALGORITHM_P256]));
+                metadata.extend(tlv(0x02, &[key.pin_policy, key.touch_policy]));
+                metadata.extend(tlv(0x03, &[if key.generated { 1 } else { 2 }]));
+                metadata.extend(tlv(0x04, &tlv(0x86, public.as_bytes())));
+            }
+        }
+        Ok(metadata)
+    }
+
+    fn check_authenticated(&self) -> std::result::Result<(), u16> {
+        self.authenticated.then_some(()).ok_or(SW_SECURITY_STATUS)
+    }
+}
+
+impl CcidBackend for PivCard {
+    fn power_on(&mut self) -> Result<Vec<u8>> {
+        self.deselect();
+        Ok(ATR.to_vec())
+    }
+
+    fn power_off(&mut self) -> Result<()> {
+        self.deselect();
+        Ok(())
+    }
+
+    fn transmit(&mut self, apdu: &[u8]) -> Result<Vec<u8>> {
+        let Some(([cla, ins, p1, p2], data)) = parse_apdu(apdu) else {
+            return Ok(SW_WRONG_LENGTH.to_be_bytes().to_vec());
+        };
+        if ins != INS_GET_RESPONSE {
+            self.response.clear();
+        }
+        // More of the command follows
+        if cla & 0x10 != 0 {
+            self.command.extend(data);
+            return Ok(SW_SUCCESS.to_be_bytes().to_vec());
+        }
+        let data = [std::mem::take(&mut self.command).as_slice(), data].concat();
+        trace!("PIV command {:02x} {:02x} {:02x}", ins, p1, p2);
+        Ok(match self.execute(ins, p1, p2, &data) {
+            Ok(response) => self.respond(response),
+            Err(sw) => sw.to_be_bytes().to_vec(),
+        })
+    }
+}
+
+/// A YubiKey, named `0-0-{index}`, whose CCID interface holds `card`
+pub fn device(index: u32, card: PivCard) -> UsbDevice {
+    let serial = card.serial().to_string();
+    let handler = UsbCcidHandler::new(LogicalChannels::new(card));
+    let mut device = UsbDevice::new(index).with_interface(
+        ClassCode::SmartCard as u8,
+        CCID_SUBCLASS,
+        0x00,
+        "Emulated PIV",
+        UsbCcidHandler::<LogicalChannels<PivCard>>::endpoints(),
+        Arc::new(Mutex::new(
+            Box::new(handler) as Box<dyn UsbInterfaceHandler + Send>
+        )),
+    );
+    device.vendor_id = YUBICO_VENDOR_ID;
+    device.product_id = YUBIKEY_PRODUCT_ID;
+    device.set_manufacturer_name("Yubico");
+    device.set_product_name("YubiKey OTP+FIDO+CCID (emulated)");
+    device.set_serial_number(&serial);
+    device
+}
+
+/// The header and data of a short or extended command APDU
+fn parse_apdu(apdu: &[u8]) -> Option<([u8; 4], &[u8])> {
+    let header = apdu.get(..4)?.try_into().ok()?;
+    let data = match &apdu[4..] {
+        // No data, maybe an Le
+        [] | [_] | [0, _, _] => &[][..],
+        [0, hi, lo, data @ ..] => data.get(..usize::from(u16::from_be_bytes([*hi, *lo])))?,
+        [lc, data @ ..] => data.get(..usize::from(*lc))?,
+    };
+    Some((header, data))
+}
+
+/// `value` padded to 8 bytes with 0xFF, if it fits
+fn pad(value: &[u8]) -> Option<[u8; 8]> {
+    let mut padded = [0xFF; 8];
+    padded.get_mut(..value.len())?.copy_from_slice(value);
+    Some(padded)
+}
+
+/// The current and new value of a PIN or PUK, each padded to 8 bytes; the
+/// new one must have 6 to 8 bytes
+fn split_references(data: &[u8]) -> std::result::Result<(&[u8], [u8; 8]), u16> {
+    if data.len() != 16 {
+        return Err(SW_INCORRECT_DATA);
+    }
+    let (old, new) = data.split_at(8);
+    let len = new.iter().position(|&b| b == 0xFF).unwrap_or(8);
+    if len < 6 {
+        return Err(SW_INCORRECT_DATA);
+    }
+    Ok((old, new.try_into().unwrap()))
+}
+
+/// Whether `slot` holds a key: the four standard slots, the retired ones and
+/// the attestation slot
+fn check_key_slot(slot: u8) -> std::result::Result<(), u16> {
+    match slot {
+        0x9A | 0x9C | 0x9D | 0x9E | 0x82..=0x95 | 0xF9 => Ok(()),
+        _ => Err(SW_INCORRECT_P1P2),
+    }
+}
+
+fn effective_pin_policy(slot: u8, policy: u8) -> u8 {
+    match (policy, slot) {
+        (POLICY_DEFAULT, 0x9C) => PIN_POLICY_ALWAYS,
+        (POLICY_DEFAULT, 0x9E) => POLICY_NEVER,
+        (POLICY_DEFAULT, _) => PIN_POLICY_ONCE,
+        (policy, _) => policy,
+    }
+}
+
+fn effective_touch_policy(policy: u8) -> u8 {
+    match policy {
+        POLICY_DEFAULT => POLICY_NEVER,
+        policy => policy,
+    }
+}
+
+/// The tag of the data object GET DATA or PUT DATA names
+fn object_tag(data: &[u8]) -> std::result::Result<u32, u16> {
+    match find_tlv(data, 0x5C) {
+        Some(tag) if (1..=3).contains(&tag.len()) => {
+            Ok(tag.iter().fold(0, |acc, &b| acc << 8 | u32::from(b)))
+        }
+        _ => Err(SW_NOT_FOUND),
+    }
+}
+
+/// The value of the first BER-TLV with the one-byte `tag` in `data`
+fn find_tlv(mut data: &[u8], tag: u8) -> Option<&[u8]> {
+    while let [t, rest @ ..] = data {
+        let (len, rest) = match rest {
+            [0x81, len, rest @ ..] => (usize::from(*len), rest),
+            [0x82, hi, lo, rest @ ..] => (usize::from(u16::from_be_bytes([*hi, *lo])), rest),
+            [len @ 0x00..=0x7F, rest @ ..] => (usize::from(*len), rest),
+            _ => return None,
+        };
+        let value = rest.get(..len)?;
+        if *t == tag {
+            return Some(value);
+        }
+        data = &rest[len..];
+    }
+    None
+}
+
+fn tlv(tag: u8, value: &[u8]) -> Vec<u8> {
+    let mut encoded = vec![tag];
+    match value.len() {
+        len @ 0x00..=0x7F => encoded.push(len as u8),
+        len @ 0x80..=0xFF => encoded.extend([0x81, len as u8]),
+        len => encoded.extend([0x82, (len >> 8) as u8, len as u8]),
+    }
+    encoded.extend(value);
+    encoded
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ccid::RemoteReader;
+    use crate::loopback::Loopback;
+    use crate::util::tests::*;
+    use des::cipher::BlockDecrypt;
+    use p256::ecdsa::{signature::hazmat::PrehashVerifier, VerifyingKey};
+
+    fn apdu(ins: u8, p1: u8, p2: u8, data: &[u8]) -> Vec<u8> {
+        let mut apdu = vec![0x00, ins, p1, p2];
+        if !data.is_empty() {
+            apdu.push(data.len() as u8);
+            apdu.extend(data);
+        }
+        apdu
+    }
+
+    /// Send `apdu`, following GET RESPONSEs, returning the data or the
+    /// status word of a failure
+    fn send(card: &mut PivCard, apdu: &[u8]) -> std::result::Result<Vec<u8>, u16> {
+        let mut data = vec![];
+        let mut response = card.transmit(apdu).unwrap();
+        loop {
+            let sw = response.split_off(response.len() - 2);
+            data.extend(response);
+            match u16::from_be_bytes([sw[0], sw[1]]) {
+                SW_SUCCESS => return Ok(data),
+                sw if sw & 0xFF00 == SW_BYTES_REMAINING => {
+                    response = card.transmit(&[0x00, INS_GET_RESPONSE, 0, 0, 0]).unwrap();
+                }
+                sw => return Err(sw),
+            }
+        }
+    }
+
+    fn select(card: &mut PivCard) {
+        send(card, &apdu(INS_SELECT, 0x04, 0x00, &PIV_AID[..5])).unwrap();
+    }
+
+    fn authenticate(card: &mut PivCard, key: &[u8; 24]) -> std::result::Result<Vec<u8>, u16> {
+        let cipher = TdesEde3::new_from_slice(key).unwrap();
+        let witness = send(
+            card,
+            &apdu(
+                INS_AUTHENTICATE,
+                ALGORITHM_3DES,
+                MANAGEMENT_KEY,
+                &[0x7C, 0x02, 0x80, 0x00],
+            ),
+        )?;
+        let mut block = GenericArray::clone_from_slice(&witness[4..12]);
+        cipher.decrypt_block(&mut block);
+        let mut template = tlv(0x80, &block);
+        template.extend(tlv(0x81, &[7; 8]));
+        let response = send(
+            card,
+            &apdu(
+                INS_AUTHENTICATE,
+                ALGORITHM_3DES,
+                MANAGEMENT_KEY,
+                &tlv(0x7C, &template),
+            ),
+        )?;
+        let mut expected = GenericArray::from([7; 8]);
+        cipher.encrypt_block(&mut expected);
+        assert_eq!(response[4..12], expected[..]);
+        Ok(response)
+    }
+
+    fn verify(card: &mut PivCard, pin: &[u8]) -> std::result::Result<Vec<u8>, u16> {
+        send(card, &apdu(INS_VERIFY, 0x00, PIN, &pad(pin).unwrap()))
+    }
+
+    #[test]
+    fn pins() {
+        let mut card = PivCard::new(42);
+        select(&mut card);
+        assert_eq!(
+            send(&mut card, &apdu(INS_GET_SERIAL, 0, 0, &[])),
+            Ok(vec![0, 0, 0, 42])
+        );
+        assert_eq!(send(&mut card, &apdu(INS_VERIFY, 0, PIN, &[])), Err(0x63C3));
+        assert_eq!(verify(&mut card, b"000000"), Err(0x63C2));
+        assert_eq!(verify(&mut card, DEFAULT_PIN), Ok(vec![]));
+        // A verified PIN has no retries to report, until the applet is
+        // selected again
+        assert_eq!(send(&mut card, &apdu(INS_VERIFY, 0, PIN, &[])), Ok(vec![]));
+        select(&mut card);
+        assert_eq!(send(&mut card, &apdu(INS_VERIFY, 0, PIN, &[])), Err(0x63C3));
+
+        let change = [&pad(DEFAULT_PIN).unwrap()[..], &pad(b"654321").unwrap()].concat();
+        send(&mut card, &apdu(INS_CHANGE_REFERENCE, 0, PIN, &change)).unwrap();
+        for _ in 0..3 {
+            assert!(verify(&mut card, DEFAULT_PIN).is_err());
+        }
+        assert_eq!(verify(&mut card, b"654321"), Err(SW_AUTH_BLOCKED));
+        // The PUK unblocks it
+        let unblock = [&pad(DEFAULT_PUK).unwrap()[..], &pad(b"111111").unwrap()].concat();
+        send(&mut card, &apdu(INS_RESET_RETRY, 0, PIN, &unblock)).unwrap();
+        assert_eq!(verify(&mut card, b"111111"), Ok(vec![]));
+    }
+
+    #[test]
+    fn management_key() {
+        let mut card = PivCard::new(42);
+        select(&mut card);
+        let generate = apdu(
+            INS_GENERATE,
+            0,
+            0x82,
+            &[0xAC, 0x03, 0x80, 0x01, ALGORITHM_P256],
+        );
+        assert_eq!(send(&mut card, &generate), Err(SW_SECURITY_STATUS));
+        assert_eq!(authenticate(&mut card, &[0; 24]), Err(SW_SECURITY_STATUS));
+        authenticate(&mut card, &DEFAULT_MANAGEMENT_KEY).unwrap();
+        let response = send(&mut card, &generate).unwrap();
+        assert_eq!(response[..5], [0x7F, 0x49, 0x43, 0x86, 0x41]);
+
+        let metadata = send(&mut card, &apdu(INS_GET_METADATA, 0, 0x82, &[])).unwrap();
+        assert_eq!(
+            metadata[..12],
+            [0x01, 0x01, 0x11, 0x02, 0x02, 0x02, 0x01, 0x03, 0x01, 0x01, 0x04, 0x43]
+        );
+        let metadata = send(&mut card, &apdu(INS_GET_METADATA, 0, MANAGEMENT_KEY, &[])).unwrap();
+        assert_eq!(metadata[metadata.len() - 3..], [0x05, 0x01, 0x01]);
+    }
+
+    #[test]
+    fn keys_need_the_pin() {
+        let mut card = PivCard::new(42);
+        let public = card.generate(0x9C, POLICY_DEFAULT, POLICY_DEFAULT);
+        let public = VerifyingKey::from_sec1_bytes(&public).unwrap();
+        select(&mut card);
+
+        let hash = [3; 32];
+        let sign = apdu(
+            INS_AUTHENTICATE,
+            ALGORITHM_P256,
+            0x9C,
+            &tlv(0x7C, &[&tlv(0x82, &[])[..], &tlv(0x81, &hash)].concat()),
+        );
+        assert_eq!(send(&mut card, &sign), Err(SW_SECURITY_STATUS));
+        verify(&mut card, DEFAULT_PIN).unwrap();
+        let response = send(&mut card, &sign).unwrap();
+        let signature =
+            Signature::from_der(find_tlv(find_tlv(&response, 0x7C).unwrap(), 0x82).unwrap())
+                .unwrap();
+        public.verify_prehash(&hash, &signature).unwrap();
+        // The signature slot needs the PIN every time
+        assert_eq!(send(&mut card, &sign), Err(SW_SECURITY_STATUS));
+    }
+
+    #[test]
+    fn chaining() {
+        let mut card = PivCard::new(42);
+        select(&mut card);
+        authenticate(&mut card, &DEFAULT_MANAGEMENT_KEY).unwrap();
+        let cert = vec![9; 600];
+        let mut data = tlv(0x5C, &[0x5F, 0xC1, 0x0D]);
+        data.extend(tlv(0x53, &cert));
+        for (i, chunk) in data.chunks(255).enumerate() {
+            let last = (i + 1) * 255 >= data.len();
+            let mut apdu = apdu(INS_PUT_DATA, 0x3F, 0xFF, chunk);
+            if !last {
+                apdu[0] |= 0x10;
+            }
+            send(&mut card, &apdu).unwrap();
+        }
+        assert_eq!(card.object(0x5F_C1_0D), Some(&cert[..]));
+
+        let get = apdu(INS_GET_DATA, 0x3F, 0xFF, &tlv(0x5C, &[0x5F, 0xC1, 0x0D]));
+        let response = card.transmit(&get).unwrap();
+        assert_eq!(response.len(), MAX_RESPONSE + 2);
+        assert_eq!(response[MAX_RESPONSE], 0x61);
+        assert_eq!(send(&mut card, &get).unwrap(), tlv(0x53, &cert));
+    }
+
+    #[tokio::test]
+    async fn ecdh_over_usbip() {
+        setup_test_logger();
+        let mut card = PivCard::new(42);
+        let public = card.generate(0x82, POLICY_NEVER, POLICY_NEVER);
+        let loopback = Loopback::new(vec![device(0, card)]);
+        let device = loopback.import("0-0-0").await.unwrap();
+        let mut reader = RemoteReader::open(device).await.unwrap();
+        assert_eq!(reader.power_on().await.unwrap(), ATR);
+        let response = reader
+            .transmit(&apdu(INS_SELECT, 0x04, 0x00, &PIV_AID[..5]), || ())
+            .await
+            .unwrap();
+        assert_eq!(response[response.len() - 2..], [0x90, 0x00]);
+
+        let peer = SecretKey::random(&mut OsRng);
+        let point = peer.public_key().to_encoded_point(false);
+        let template = [&tlv(0x82, &[])[..], &tlv(0x85, point.as_bytes())].concat();
+        let response = reader
+            .transmit(
+                &apdu(
+                    INS_AUTHENTICATE,
+                    ALGORITHM_P256,
+                    0x82,
+                    &tlv(0x7C, &template),
+                ),
+                || (),
+            )
+            .await
+            .unwrap();
+        let expected = p256::ecdh::diffie_hellman(
+            peer.to_nonzero_scalar(),
+            PublicKey::from_sec1_bytes(&public).unwrap().as_affine(),
+        );
+        assert_eq!(
+            response,
+            [
+                &tlv(0x7C, &tlv(0x82, expected.raw_secret_bytes()))[..],
+                &[0x90, 0x00]
+            ]
+            .concat()
+        );
+    }
+}
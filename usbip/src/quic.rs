@@ -0,0 +1,638 @@
+//! QUIC transport
+//!
+//! Over a single TCP stream, a lost packet holds up everything sent after it:
+//! on a lossy Wi-Fi or VPN link, one stalled bulk transfer delays the
+//! interrupt transfers of the same device. With the `quic` feature, clients
+//! can connect over QUIC instead, where each URB travels on a stream of its
+//! own:
+//!
+//! - The first bidirectional stream the client opens is the control stream.
+//!   It carries the token, `OP_REQ_*` and their replies, and whole sessions
+//!   that aren't USB/IP (APDUs, events and admin requests), like a TCP
+//!   connection would.
+//! - Once a device is imported, every `USBIP_CMD_SUBMIT` is sent on a new
+//!   bidirectional stream, which gets its `USBIP_RET_SUBMIT`. A
+//!   `USBIP_CMD_UNLINK` for the URB follows it on the same stream, and a
+//!   successful `USBIP_RET_UNLINK` replaces the `USBIP_RET_SUBMIT`. Unlinks
+//!   of URBs that are done, such as the pings of
+//!   [crate::client::ImportedDevice::with_keep_alive], go on the control
+//!   stream.
+//!
+//! Both ends translate between their streams and the byte stream USB/IP is
+//! written for, so the server handles connections like any other and
+//! clients use [crate::client] as usual. TLS 1.3 is part of QUIC, so this
+//! uses the same certificates as [crate::tls]. A [Client] that connected
+//! before resumes its session with 0-RTT when it connects again, e.g. from
+//! [crate::client::ImportedDevice::with_reconnect], so that its requests
+//! leave with the first packet. The server only acts on them once the
+//! handshake completes, so they can't be replayed.
+//!
+//! ```no_run
+//! # async fn run(server: std::sync::Arc<usbip::UsbIpServer>) -> std::io::Result<()> {
+//! use usbip::{quic, tls};
+//!
+//! let config = quic::server_config(
+//!     tls::load_certs("server.pem")?,
+//!     tls::load_private_key("server.key")?,
+//!     Some(tls::load_certs("clients-ca.pem")?),
+//! )?;
+//! quic::server("0.0.0.0:3240".parse().unwrap(), server, config).await;
+//! # Ok(())
+//! # }
+//! ```
+use crate::acl::{Peer, TOKEN_PREAMBLE};
+use crate::tls;
+use crate::transport::WithPeer;
+use crate::wire::{Direction, UsbIpCommand, UsbIpReply, WireError, OP_REQ_IMPORT, USBIP_VERSION};
+use crate::UsbIpServer;
+use quinn::crypto::rustls::{QuicClientConfig, QuicServerConfig};
+use quinn::{Connection, Endpoint, Incoming, RecvStream, SendStream, ZeroRttAccepted};
+use rustls_pki_types::{CertificateDer, PrivateKeyDer};
+use std::collections::HashMap;
+use std::io::{Error, ErrorKind, Result};
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt, DuplexStream};
+use tokio::net::ToSocketAddrs;
+use tokio::sync::mpsc;
+use tracing::*;
+
+pub use quinn;
+
+/// The ALPN protocol both ends agree on
+const ALPN: &[u8] = b"usbip";
+
+/// How many URBs a client may have in flight, each on a stream of its own
+const MAX_URB_STREAMS: u32 = 1024;
+
+/// How often clients make sure that the connection is still there when
+/// otherwise idle, e.g. while waiting for a touch
+const KEEP_ALIVE_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Size of the buffers between the streams of a connection and the byte
+/// stream USB/IP is handled on
+const BUFFER_SIZE: usize = 256 * 1024;
+
+/// How long the other end gets to read the rest of the control stream
+/// before the connection is closed
+const LINGER: Duration = Duration::from_secs(5);
+
+fn invalid(err: impl std::fmt::Display) -> Error {
+    Error::new(ErrorKind::InvalidInput, err.to_string())
+}
+
+/// Server side of the handshake, presenting `certs` signed by `key`, see
+/// [tls::acceptor]
+pub fn server_config(
+    certs: Vec<CertificateDer<'static>>,
+    key: PrivateKeyDer<'static>,
+    client_roots: Option<Vec<CertificateDer<'static>>>,
+) -> Result<quinn::ServerConfig> {
+    let mut crypto = tls::server_config(certs, key, client_roots)?;
+    crypto.alpn_protocols = vec![ALPN.to_vec()];
+    crypto.max_early_data_size = u32::MAX;
+    let crypto = QuicServerConfig::try_from(crypto).map_err(invalid)?;
+    let mut transport = quinn::TransportConfig::default();
+    transport.max_concurrent_bidi_streams(MAX_URB_STREAMS.into());
+    transport.max_concurrent_uni_streams(0u8.into());
+    let mut config = quinn::ServerConfig::with_crypto(Arc::new(crypto));
+    config.transport_config(Arc::new(transport));
+    Ok(config)
+}
+
+/// Spawn a USB/IP server at `addr` that speaks QUIC
+pub async fn server(addr: SocketAddr, server: Arc<UsbIpServer>, config: quinn::ServerConfig) {
+    server_with_shutdown(addr, server, config, std::future::pending()).await
+}
+
+/// Like [server], until `shutdown` completes, see [crate::server_with_shutdown]
+pub async fn server_with_shutdown(
+    addr: SocketAddr,
+    server: Arc<UsbIpServer>,
+    config: quinn::ServerConfig,
+    shutdown: impl std::future::Future<Output = ()>,
+) {
+    let endpoint = Endpoint::server(config, addr).expect("bind to addr");
+    serve(endpoint, server, shutdown).await
+}
+
+impl crate::Listener for Endpoint {
+    type Stream = Incoming;
+
+    async fn accept(&mut self) -> Result<(Incoming, Peer)> {
+        let incoming = Endpoint::accept(self)
+            .await
+            .ok_or_else(|| Error::new(ErrorKind::NotConnected, "The endpoint is closed"))?;
+        let peer = Peer {
+            addr: Some(incoming.remote_address().ip()),
+            ..Default::default()
+        };
+        Ok((incoming, peer))
+    }
+}
+
+pub(crate) async fn serve(
+    endpoint: Endpoint,
+    server: Arc<UsbIpServer>,
+    shutdown: impl std::future::Future<Output = ()>,
+) {
+    crate::serve(endpoint.clone(), server, shutdown, |incoming| async move {
+        let connection = incoming.await?;
+        let (control, control_recv) = connection.accept_bi().await?;
+        let certs = connection
+            .peer_identity()
+            .and_then(|identity| identity.downcast::<Vec<CertificateDer<'static>>>().ok());
+        let peer = Peer {
+            addr: Some(connection.remote_address().ip()),
+            cert_fingerprint: tls::fingerprint(certs.as_deref().map(Vec::as_slice)),
+            ..Default::default()
+        };
+        let (socket, streams) = tokio::io::duplex(BUFFER_SIZE);
+        tokio::spawn(serve_connection(connection, control, control_recv, streams));
+        Ok(WithPeer::new(socket, peer))
+    })
+    .await;
+    endpoint.close(0u8.into(), b"");
+}
+
+/// Read the next frame that `decode` finds in `recv`, buffering partial
+/// reads in `input`, or `None` once `recv` ends between two frames
+async fn read_frame(
+    recv: &mut RecvStream,
+    input: &mut Vec<u8>,
+    decode: impl Fn(&[u8]) -> std::result::Result<usize, WireError>,
+) -> Result<Option<Vec<u8>>> {
+    loop {
+        match decode(input) {
+            Ok(len) => return Ok(Some(input.drain(..len).collect())),
+            Err(WireError::Truncated { needed }) => {
+                let start = input.len();
+                input.resize(start + needed, 0);
+                match recv.read(&mut input[start..]).await? {
+                    Some(len) => input.truncate(start + len),
+                    None if start == 0 => return Ok(None),
+                    None => return Err(ErrorKind::UnexpectedEof.into()),
+                }
+            }
+            Err(err) => return Err(Error::new(ErrorKind::InvalidData, err.to_string())),
+        }
+    }
+}
+
+fn decode_command(bytes: &[u8]) -> std::result::Result<usize, WireError> {
+    UsbIpCommand::decode(bytes).map(|(_, len)| len)
+}
+
+/// A command the client sent on the stream of its URB
+enum Urb {
+    Submit {
+        seqnum: u32,
+        is_in: bool,
+        stream: SendStream,
+        bytes: Vec<u8>,
+    },
+    Unlink {
+        seqnum: u32,
+        target: u32,
+        bytes: Vec<u8>,
+    },
+}
+
+/// Read the `USBIP_CMD_SUBMIT` on a stream the client opened, and the
+/// `USBIP_CMD_UNLINK` that may follow it
+async fn read_urb(stream: SendStream, mut recv: RecvStream, urbs: mpsc::Sender<Urb>) -> Result<()> {
+    let mut input = vec![];
+    let unexpected = || Error::new(ErrorKind::InvalidData, "Unexpected command on a URB stream");
+    let Some(bytes) = read_frame(&mut recv, &mut input, decode_command).await? else {
+        return Ok(());
+    };
+    let Ok((UsbIpCommand::UsbIpCmdSubmit { header, .. }, _)) = UsbIpCommand::decode(&bytes) else {
+        return Err(unexpected());
+    };
+    let target = header.seqnum;
+    let submit = Urb::Submit {
+        seqnum: target,
+        is_in: header.direction == Direction::In as u32,
+        stream,
+        bytes,
+    };
+    urbs.send(submit).await.map_err(|_| unexpected())?;
+    while let Some(bytes) = read_frame(&mut recv, &mut input, decode_command).await? {
+        match UsbIpCommand::decode(&bytes) {
+            Ok((
+                UsbIpCommand::UsbIpCmdUnlink {
+                    header,
+                    unlink_seqnum,
+                },
+                _,
+            )) if unlink_seqnum == target => {
+                let unlink = Urb::Unlink {
+                    seqnum: header.seqnum,
+                    target,
+                    bytes,
+                };
+                urbs.send(unlink).await.map_err(|_| unexpected())?;
+            }
+            _ => return Err(unexpected()),
+        }
+    }
+    Ok(())
+}
+
+async fn accept_urbs(connection: Connection, urbs: mpsc::Sender<Urb>) {
+    while let Ok((stream, recv)) = connection.accept_bi().await {
+        let urbs = urbs.clone();
+        tokio::spawn(async move {
+            if let Err(err) = read_urb(stream, recv, urbs).await {
+                debug!("Reading a URB stream failed: {}", err);
+            }
+        });
+    }
+}
+
+/// The streams of the URBs in flight, by seqnum, and whether they are IN
+type Routes = HashMap<u32, (bool, SendStream)>;
+
+/// Send the complete replies in `output` on the streams of their URBs,
+/// returning those for the control stream
+fn route(
+    output: &mut Vec<u8>,
+    routes: &mut Routes,
+    unlinks: &mut HashMap<u32, u32>,
+) -> Result<Vec<u8>> {
+    let mut control = vec![];
+    loop {
+        let is_in = |seqnum| routes.get(&seqnum).is_some_and(|(is_in, _)| *is_in);
+        let (reply, bytes) = match UsbIpReply::decode(output, is_in) {
+            Ok((reply, len)) => (reply, output.drain(..len).collect::<Vec<u8>>()),
+            Err(WireError::Truncated { .. }) => return Ok(control),
+            Err(err) => return Err(Error::new(ErrorKind::InvalidData, err.to_string())),
+        };
+        let route = match reply {
+            UsbIpReply::UsbIpRetSubmit { header, .. } => routes.remove(&header.seqnum),
+            // An unlinked URB gets no USBIP_RET_SUBMIT, this ends its stream
+            UsbIpReply::UsbIpRetUnlink { header, status } => unlinks
+                .remove(&header.seqnum)
+                .filter(|_| status != 0)
+                .and_then(|target| routes.remove(&target)),
+            _ => None,
+        };
+        match route {
+            Some((_, mut stream)) => {
+                tokio::spawn(async move {
+                    if stream.write_all(&bytes).await.is_ok() {
+                        stream.finish().ok();
+                    }
+                });
+            }
+            None => control.extend(bytes),
+        }
+    }
+}
+
+/// Translate between the streams of `connection` and `socket`, which the
+/// server handles like any other connection
+async fn serve_connection(
+    connection: Connection,
+    mut control: SendStream,
+    mut control_recv: RecvStream,
+    socket: DuplexStream,
+) {
+    let (mut reader, mut writer) = tokio::io::split(socket);
+    let (urbs_tx, mut urbs) = mpsc::channel(MAX_URB_STREAMS as usize);
+    let accept = tokio::spawn(accept_urbs(connection.clone(), urbs_tx));
+    let mut routes = Routes::new();
+    // The URBs that USBIP_CMD_UNLINKs are for, by their own seqnum
+    let mut unlinks = HashMap::new();
+    // Set by the first URB stream; until then replies go on the control stream
+    let mut importing = true;
+    let mut control_done = false;
+    let mut input = vec![0; BUFFER_SIZE];
+    let mut buf = vec![0; BUFFER_SIZE];
+    let mut output = vec![];
+    let mut control_input = vec![];
+    let res = async {
+        loop {
+            tokio::select! {
+                res = control_recv.read(&mut input), if !control_done => match res? {
+                    Some(len) if importing => writer.write_all(&input[..len]).await?,
+                    // Whole commands, so that those of URB streams don't land
+                    // in between
+                    Some(len) => {
+                        control_input.extend_from_slice(&input[..len]);
+                        while let Ok(len) = decode_command(&control_input) {
+                            let bytes: Vec<u8> = control_input.drain(..len).collect();
+                            writer.write_all(&bytes).await?;
+                        }
+                    }
+                    None => {
+                        control_done = true;
+                        writer.shutdown().await?;
+                    }
+                },
+                Some(urb) = urbs.recv() => match urb {
+                    Urb::Submit { seqnum, is_in, stream, bytes } => {
+                        importing = false;
+                        routes.insert(seqnum, (is_in, stream));
+                        writer.write_all(&bytes).await?;
+                    }
+                    Urb::Unlink { seqnum, target, bytes } => {
+                        unlinks.insert(seqnum, target);
+                        writer.write_all(&bytes).await?;
+                    }
+                },
+                res = reader.read(&mut buf) => match res? {
+                    0 => return Ok::<_, Error>(()),
+                    len if importing => control.write_all(&buf[..len]).await?,
+                    len => {
+                        output.extend_from_slice(&buf[..len]);
+                        let rest = route(&mut output, &mut routes, &mut unlinks)?;
+                        control.write_all(&rest).await?;
+                    }
+                },
+            }
+        }
+    }
+    .await;
+    if let Err(err) = res {
+        debug!(
+            "QUIC connection from {} ended: {}",
+            connection.remote_address(),
+            err
+        );
+    }
+    accept.abort();
+    control.finish().ok();
+    tokio::time::timeout(LINGER, control.stopped()).await.ok();
+    connection.close(0u8.into(), b"");
+}
+
+/// How what a client writes is sent
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Mode {
+    /// Not known yet
+    Start,
+    /// On the control stream, as written
+    Raw,
+    /// USB/IP commands of an imported device, each URB on its own stream
+    Urbs,
+}
+
+/// Tell how much of what the client wrote first belongs to the control
+/// stream, and how what follows is sent, or `None` until it wrote more
+fn classify(output: &[u8]) -> Option<(usize, Mode)> {
+    let len = output.len().min(TOKEN_PREAMBLE.len());
+    if output[..len] == TOKEN_PREAMBLE[..len] {
+        let end = output.iter().position(|&b| b == b'\n')?;
+        return Some((end + 1, Mode::Start));
+    }
+    let mut import = USBIP_VERSION.to_be_bytes().to_vec();
+    import.extend(OP_REQ_IMPORT.to_be_bytes());
+    let len = output.len().min(import.len());
+    if output[..len] != import[..len] {
+        return Some((output.len(), Mode::Raw));
+    }
+    let (_, len) = UsbIpCommand::decode(output).ok()?;
+    Some((len, Mode::Urbs))
+}
+
+/// Connects to QUIC servers, resuming the session with 0-RTT when it
+/// connects to one again
+#[derive(Clone)]
+pub struct Client {
+    config: quinn::ClientConfig,
+}
+
+impl Client {
+    /// Trust servers issued by `roots`, presenting `identity` to those that
+    /// ask for a certificate, see [tls::connector]
+    pub fn new(
+        roots: Vec<CertificateDer<'static>>,
+        identity: Option<(Vec<CertificateDer<'static>>, PrivateKeyDer<'static>)>,
+    ) -> Result<Self> {
+        let mut crypto = tls::client_config(roots, identity)?;
+        crypto.alpn_protocols = vec![ALPN.to_vec()];
+        crypto.enable_early_data = true;
+        let crypto = QuicClientConfig::try_from(crypto).map_err(invalid)?;
+        let mut transport = quinn::TransportConfig::default();
+        transport.keep_alive_interval(Some(KEEP_ALIVE_INTERVAL));
+        let mut config = quinn::ClientConfig::new(Arc::new(crypto));
+        config.transport_config(Arc::new(transport));
+        Ok(Self { config })
+    }
+
+    /// Connect to the server at `addr`, whose certificate must be valid for
+    /// `server_name`
+    ///
+    /// The stream can be used with [crate::client::list_devices],
+    /// [crate::client::import] and [crate::client::ImportedDevice].
+    pub async fn connect(
+        &self,
+        addr: impl ToSocketAddrs,
+        server_name: &str,
+    ) -> Result<DuplexStream> {
+        let addr = tokio::net::lookup_host(addr)
+            .await?
+            .next()
+            .ok_or_else(|| Error::new(ErrorKind::NotFound, "No address to connect to"))?;
+        let bind: SocketAddr = match addr {
+            SocketAddr::V4(_) => (Ipv4Addr::UNSPECIFIED, 0).into(),
+            SocketAddr::V6(_) => (Ipv6Addr::UNSPECIFIED, 0).into(),
+        };
+        let mut endpoint = Endpoint::client(bind)?;
+        endpoint.set_default_client_config(self.config.clone());
+        let connecting = endpoint.connect(addr, server_name).map_err(invalid)?;
+        let (connection, zero_rtt) = match connecting.into_0rtt() {
+            Ok((connection, accepted)) => {
+                debug!("Resuming the session with {} with 0-RTT", addr);
+                (connection, Some(accepted))
+            }
+            Err(connecting) => (connecting.await?, None),
+        };
+        let (control, control_recv) = connection.open_bi().await?;
+        let (socket, streams) = tokio::io::duplex(BUFFER_SIZE);
+        tokio::spawn(async move {
+            let res = run_client(&connection, control, control_recv, zero_rtt, streams).await;
+            if let Err(err) = res {
+                debug!("QUIC connection to {} ended: {}", addr, err);
+            }
+            connection.close(0u8.into(), b"");
+            endpoint.wait_idle().await;
+        });
+        Ok(socket)
+    }
+}
+
+/// Read the replies on the stream of the URB with `seqnum` for `replies`,
+/// followed by `None` once it ends
+async fn read_replies(
+    mut recv: RecvStream,
+    seqnum: u32,
+    is_in: bool,
+    replies: mpsc::Sender<(u32, Option<Vec<u8>>)>,
+) {
+    let mut input = vec![];
+    let decode = |bytes: &[u8]| UsbIpReply::decode(bytes, |_| is_in).map(|(_, len)| len);
+    loop {
+        match read_frame(&mut recv, &mut input, decode).await {
+            Ok(Some(reply)) => {
+                if replies.send((seqnum, Some(reply))).await.is_err() {
+                    return;
+                }
+            }
+            Ok(None) => break,
+            Err(err) => {
+                debug!("Reading the reply to URB {} failed: {}", seqnum, err);
+                break;
+            }
+        }
+    }
+    replies.send((seqnum, None)).await.ok();
+}
+
+/// Translate between `socket`, which the client uses like a TCP connection,
+/// and the streams of `connection`
+async fn run_client(
+    connection: &Connection,
+    mut control: SendStream,
+    mut control_recv: RecvStream,
+    mut zero_rtt: Option<ZeroRttAccepted>,
+    socket: DuplexStream,
+) -> Result<()> {
+    let (mut reader, mut writer) = tokio::io::split(socket);
+    let (replies_tx, mut replies) =
+        mpsc::channel::<(u32, Option<Vec<u8>>)>(MAX_URB_STREAMS as usize);
+    // What went on the control stream as 0-RTT data, which is lost if the
+    // server rejects it
+    let mut early = vec![];
+    let mut streams = HashMap::new();
+    let mut mode = Mode::Start;
+    let mut input = vec![0; BUFFER_SIZE];
+    let mut buf = vec![0; BUFFER_SIZE];
+    let mut output = vec![];
+    loop {
+        tokio::select! {
+            accepted = async { zero_rtt.as_mut().unwrap().await }, if zero_rtt.is_some() => {
+                zero_rtt = None;
+                if !accepted {
+                    debug!("The server rejected 0-RTT data, sending it again");
+                    (control, control_recv) = connection.open_bi().await?;
+                    control.write_all(&early).await?;
+                }
+                early = vec![];
+            },
+            // Nothing arrives before the handshake completes
+            res = control_recv.read(&mut input), if zero_rtt.is_none() => match res? {
+                Some(len) => writer.write_all(&input[..len]).await?,
+                None => break,
+            },
+            Some((seqnum, reply)) = replies.recv() => match reply {
+                Some(reply) => writer.write_all(&reply).await?,
+                None => {
+                    streams.remove(&seqnum);
+                }
+            },
+            res = reader.read(&mut buf) => {
+                let len = res?;
+                if len == 0 {
+                    break;
+                }
+                output.extend_from_slice(&buf[..len]);
+                loop {
+                    let (len, next) = match mode {
+                        Mode::Start => match classify(&output) {
+                            Some(res) => res,
+                            None => break,
+                        },
+                        Mode::Raw if !output.is_empty() => (output.len(), Mode::Raw),
+                        Mode::Raw => break,
+                        Mode::Urbs => match UsbIpCommand::decode(&output) {
+                            Ok((UsbIpCommand::UsbIpCmdSubmit { header, .. }, len)) => {
+                                let bytes: Vec<u8> = output.drain(..len).collect();
+                                let (mut stream, recv) = connection.open_bi().await?;
+                                stream.write_all(&bytes).await?;
+                                streams.insert(header.seqnum, stream);
+                                let is_in = header.direction == Direction::In as u32;
+                                tokio::spawn(read_replies(recv, header.seqnum, is_in, replies_tx.clone()));
+                                continue;
+                            }
+                            Ok((UsbIpCommand::UsbIpCmdUnlink { unlink_seqnum, .. }, len)) => {
+                                let bytes: Vec<u8> = output.drain(..len).collect();
+                                // Nothing else is sent on its stream, dropping it finishes it.
+                                // Unlinks of URBs that are done, such as pings, get their
+                                // reply on the control stream.
+                                match streams.remove(&unlink_seqnum) {
+                                    Some(mut stream) => stream.write_all(&bytes).await?,
+                                    None => control.write_all(&bytes).await?,
+                                }
+                                continue;
+                            }
+                            Ok(_) => return Err(Error::new(ErrorKind::InvalidData, "Unexpected command after import")),
+                            Err(WireError::Truncated { .. }) => break,
+                            Err(err) => return Err(Error::new(ErrorKind::InvalidData, err.to_string())),
+                        },
+                    };
+                    let bytes: Vec<u8> = output.drain(..len).collect();
+                    control.write_all(&bytes).await?;
+                    if zero_rtt.is_some() {
+                        early.extend(bytes);
+                    }
+                    mode = next;
+                }
+            },
+        }
+    }
+    control.finish().ok();
+    tokio::time::timeout(LINGER, connection.closed()).await.ok();
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client;
+    use crate::tls::tests::{issue, new_server};
+    use crate::util::tests::*;
+
+    #[tokio::test]
+    async fn urb_streams() {
+        setup_test_logger();
+        let (server_ca, (server_certs, server_key)) = issue("server ca");
+        let config = server_config(server_certs, server_key, None).unwrap();
+        let endpoint = Endpoint::server(config, "127.0.0.1:0".parse().unwrap()).unwrap();
+        let addr = endpoint.local_addr().unwrap();
+        let server = new_server().with_dead_peer_timeout(Duration::from_millis(200));
+        tokio::spawn(serve(endpoint, Arc::new(server), std::future::pending()));
+
+        let client = Client::new(vec![server_ca], None).unwrap();
+        let mut socket = client.connect(addr, "localhost").await.unwrap();
+        let devices = client::list_devices(&mut socket).await.unwrap();
+        assert_eq!(devices.len(), 1);
+
+        // Connecting again resumes the session
+        let mut socket = client.connect(addr, "localhost").await.unwrap();
+        let device = client::import(&mut socket, &devices[0].bus_id)
+            .await
+            .unwrap();
+        let keep_alive = client::KeepAlive {
+            interval: Duration::from_millis(50),
+            timeout: Duration::from_secs(1),
+        };
+        let device = client::ImportedDevice::new(socket, device).with_keep_alive(keep_alive);
+        let device = Arc::new(device);
+
+        // Pings go on the control stream
+        tokio::time::sleep(Duration::from_millis(500)).await;
+        let setup = [0x80, 0x06, 0x00, 0x01, 0x00, 0x00, 0x12, 0x00];
+        let transfers: Vec<_> = (0..8)
+            .map(|_| {
+                let device = device.clone();
+                tokio::spawn(async move { device.control_in(setup).await })
+            })
+            .collect();
+        for transfer in transfers {
+            assert_eq!(transfer.await.unwrap().unwrap().len(), 0x12);
+        }
+    }
+}
@@ -0,0 +1,547 @@
+//! Recording and replaying traffic
+//!
+//! A [Recorder] stands in front of the handler of an interface and writes
+//! each URB it passes on, with the result, to a [Trace]: a text file with a
+//! line per URB, which can be attached to a bug report. [record] puts
+//! recorders in front of all interfaces of a device, and
+//! [crate::UsbIpServer::with_trace] in front of all devices a server exports.
+//!
+//! ```text
+//! # usbip trace 1
+//! 0.000412 1-2 0 02 0000000000000000 271 6f0d00000000040000000020008008xxxxxxxxxxxxxxxx 0 -
+//! 0.000530 1-2 0 82 0000000000000000 271 - 0 800200000000040000009000
+//! ```
+//!
+//! The fields are the seconds since the trace started, the bus id of the
+//! device, the number of the interface, the endpoint address, the setup
+//! packet, the length of the transfer buffer, the data sent to the device,
+//! the status of the URB (0, or a negated errno) and the data received, with
+//! `-` for none. A trace can redact PINs: the PIN, PUK or new PIN of an APDU
+//! that checks or changes one, sent to a smart card reader, is then written
+//! as `xx` bytes.
+//!
+//! [replay] gives the interfaces of a device handlers that answer from a
+//! trace instead, so that a client can be run against what a device did
+//! without the device. Each URB gets the reply to the first URB still in the
+//! trace with the same endpoint, setup packet and data, where redacted bytes
+//! and, for smart card readers, the sequence number of a CCID message match
+//! anything. Only URBs to interfaces are recorded: the standard requests to
+//! the device itself are answered from its descriptors.
+use crate::{
+    ClassCode, EndpointAttributes, IsoPacketDescriptor, SetupPacket, UrbError, UrbResult,
+    UsbDevice, UsbEndpoint, UsbInterface, UsbInterfaceHandler,
+};
+use std::any::Any;
+use std::collections::{HashMap, VecDeque};
+use std::io::{BufRead, Error, ErrorKind, Result, Write};
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+use tracing::*;
+
+/// The first line of a trace
+const HEADER: &str = "# usbip trace 1";
+
+/// Offset of the sequence number in a CCID message
+const CCID_SEQUENCE: usize = 6;
+
+/// A file URBs are recorded to, see the [module docs](self)
+pub struct Trace {
+    out: Mutex<Box<dyn Write + Send>>,
+    start: Instant,
+    redact_pins: bool,
+}
+
+impl Trace {
+    /// Record to `out`, redacting PINs if `redact_pins`
+    pub fn new(out: impl Write + Send + 'static, redact_pins: bool) -> Result<Self> {
+        let mut out: Box<dyn Write + Send> = Box::new(out);
+        writeln!(out, "{}", HEADER)?;
+        Ok(Self {
+            out: Mutex::new(out),
+            start: Instant::now(),
+            redact_pins,
+        })
+    }
+
+    /// Record to a new file at `path`
+    pub fn create(path: impl AsRef<std::path::Path>, redact_pins: bool) -> Result<Self> {
+        let file = std::fs::File::create(path)?;
+        Self::new(std::io::LineWriter::new(file), redact_pins)
+    }
+
+    fn write(
+        &self,
+        bus_id: &str,
+        interface: usize,
+        urb: &Urb,
+        req: &[Option<u8>],
+        res: &UrbResult,
+    ) {
+        let status = match res {
+            Ok(_) => 0,
+            Err(err) => err.status(),
+        };
+        let line = format!(
+            "{:.6} {} {} {:02x} {} {} {} {} {}",
+            self.start.elapsed().as_secs_f64(),
+            bus_id,
+            interface,
+            urb.ep.address,
+            hex(urb.setup.to_bytes().iter().copied().map(Some)),
+            urb.transfer_buffer_length,
+            hex(req.iter().copied()),
+            status,
+            hex(res.as_deref().unwrap_or_default().iter().copied().map(Some)),
+        );
+        if let Err(err) = writeln!(self.out.lock().unwrap(), "{}", line) {
+            warn!("Writing the trace failed: {}", err);
+        }
+    }
+}
+
+/// What a URB asked for
+struct Urb {
+    ep: UsbEndpoint,
+    setup: SetupPacket,
+    transfer_buffer_length: u32,
+}
+
+/// A handler that records the URBs another one handles, see the
+/// [module docs](self)
+pub struct Recorder {
+    inner: Box<dyn UsbInterfaceHandler + Send>,
+    trace: Arc<Trace>,
+    bus_id: String,
+    interface: usize,
+}
+
+impl UsbInterfaceHandler for Recorder {
+    fn get_class_specific_descriptor(&self) -> Vec<u8> {
+        self.inner.get_class_specific_descriptor()
+    }
+
+    fn handle_urb(
+        &mut self,
+        interface: &UsbInterface,
+        ep: UsbEndpoint,
+        transfer_buffer_length: u32,
+        setup: SetupPacket,
+        req: &[u8],
+    ) -> UrbResult {
+        let res = self
+            .inner
+            .handle_urb(interface, ep, transfer_buffer_length, setup, req);
+        let mut recorded: Vec<_> = req.iter().copied().map(Some).collect();
+        if self.trace.redact_pins {
+            if let Some(pin) = pin_range(interface, &ep, req) {
+                recorded[pin].fill(None);
+            }
+        }
+        let urb = Urb {
+            ep,
+            setup,
+            transfer_buffer_length,
+        };
+        self.trace
+            .write(&self.bus_id, self.interface, &urb, &recorded, &res);
+        res
+    }
+
+    // Isochronous URBs are passed on without being recorded
+    fn handle_iso_urb(
+        &mut self,
+        interface: &UsbInterface,
+        ep: UsbEndpoint,
+        transfer_buffer_length: u32,
+        packets: &[IsoPacketDescriptor],
+        req: &[u8],
+    ) -> UrbResult<(Vec<u8>, Vec<IsoPacketDescriptor>)> {
+        self.inner
+            .handle_iso_urb(interface, ep, transfer_buffer_length, packets, req)
+    }
+
+    fn clear_halt(&mut self, ep: UsbEndpoint) -> UrbResult<()> {
+        self.inner.clear_halt(ep)
+    }
+
+    fn disconnected(&mut self) {
+        self.inner.disconnected()
+    }
+
+    // Concurrent URBs would bypass the recorder, so there are none
+
+    fn as_any(&mut self) -> &mut dyn Any {
+        self.inner.as_any()
+    }
+}
+
+/// Stands in for a handler while it is being replaced
+struct Detached;
+
+impl UsbInterfaceHandler for Detached {
+    fn get_class_specific_descriptor(&self) -> Vec<u8> {
+        vec![]
+    }
+
+    fn handle_urb(
+        &mut self,
+        _: &UsbInterface,
+        _: UsbEndpoint,
+        _: u32,
+        _: SetupPacket,
+        _: &[u8],
+    ) -> UrbResult {
+        Err(UrbError::NoDevice)
+    }
+
+    fn as_any(&mut self) -> &mut dyn Any {
+        self
+    }
+}
+
+/// Replace the handler of each interface of `device` by what `wrap` makes of
+/// it and the number of the interface
+///
+/// The handlers are shared by the clones of `device`, which change too.
+fn wrap_handlers(
+    device: &UsbDevice,
+    mut wrap: impl FnMut(
+        usize,
+        Box<dyn UsbInterfaceHandler + Send>,
+    ) -> Box<dyn UsbInterfaceHandler + Send>,
+) {
+    for (number, interface) in device.interfaces.iter().enumerate() {
+        let mut handler = interface.handler.lock().unwrap();
+        let inner = std::mem::replace(&mut *handler, Box::new(Detached));
+        *handler = wrap(number, inner);
+    }
+}
+
+/// Record the URBs to the interfaces of `device` to `trace`
+///
+/// The handlers of the interfaces are shared by the clones of `device`, and
+/// the URBs of those are recorded too. Concurrent handlers (see
+/// [UsbInterfaceHandler::concurrent]) then take one URB at a time.
+pub fn record(device: &UsbDevice, trace: &Arc<Trace>) {
+    wrap_handlers(device, |interface, inner| {
+        Box::new(Recorder {
+            inner,
+            trace: trace.clone(),
+            bus_id: device.bus_id.clone(),
+            interface,
+        })
+    });
+}
+
+/// The bytes of `req` to a smart card reader that hold a PIN or PUK, if it
+/// carries an APDU checking or changing one
+fn pin_range(
+    interface: &UsbInterface,
+    ep: &UsbEndpoint,
+    req: &[u8],
+) -> Option<std::ops::Range<usize>> {
+    if !is_ccid_bulk_out(interface, ep) {
+        return None;
+    }
+    // A PC_to_RDR_XfrBlock: a 10 byte header, then CLA INS P1 P2 Lc
+    match req {
+        [0x6F, _, _, _, _, _, _, _, _, _, apdu @ ..] if crate::throttle::checks_pin(apdu) => {
+            Some(15..req.len())
+        }
+        _ => None,
+    }
+}
+
+fn is_ccid(interface: &UsbInterface) -> bool {
+    interface.interface_class == ClassCode::SmartCard as u8
+}
+
+fn is_ccid_bulk_out(interface: &UsbInterface, ep: &UsbEndpoint) -> bool {
+    is_ccid(interface) && ep.attributes == EndpointAttributes::Bulk as u8 && ep.address & 0x80 == 0
+}
+
+/// A URB in a trace, and its reply
+#[derive(Debug)]
+struct Entry {
+    setup: [u8; 8],
+    /// `None` for redacted bytes
+    req: Vec<Option<u8>>,
+    status: i32,
+    res: Vec<u8>,
+}
+
+impl Entry {
+    fn matches(&self, setup: &[u8; 8], req: &[u8], ccid: bool) -> bool {
+        self.setup == *setup
+            && self.req.len() == req.len()
+            && self
+                .req
+                .iter()
+                .zip(req)
+                .enumerate()
+                .all(|(i, (recorded, byte))| {
+                    (ccid && i == CCID_SEQUENCE)
+                        || recorded.is_none_or(|recorded| recorded == *byte)
+                })
+    }
+}
+
+/// A handler answering from a trace, see the [module docs](self)
+pub struct Replay {
+    class_specific_descriptor: Vec<u8>,
+    /// Recorded URBs not replayed yet, by endpoint address
+    entries: HashMap<u8, VecDeque<Entry>>,
+    /// Sequence number of the last CCID message, which the reply echoes
+    ccid_sequence: Option<u8>,
+}
+
+impl UsbInterfaceHandler for Replay {
+    fn get_class_specific_descriptor(&self) -> Vec<u8> {
+        self.class_specific_descriptor.clone()
+    }
+
+    fn handle_urb(
+        &mut self,
+        interface: &UsbInterface,
+        ep: UsbEndpoint,
+        transfer_buffer_length: u32,
+        setup: SetupPacket,
+        req: &[u8],
+    ) -> UrbResult {
+        let ccid = is_ccid(interface) && ep.attributes == EndpointAttributes::Bulk as u8;
+        let setup = setup.to_bytes();
+        let entries = self.entries.entry(ep.address).or_default();
+        let Some(found) = entries
+            .iter()
+            .position(|entry| entry.matches(&setup, req, ccid))
+        else {
+            warn!("URB to endpoint {:02x} is not in the trace", ep.address);
+            return Err(UrbError::Other(Error::other("URB is not in the trace")));
+        };
+        let entry = entries.remove(found).unwrap();
+        if entry.status != 0 {
+            return Err(UrbError::from_status(entry.status));
+        }
+        let mut res = entry.res;
+        res.truncate(transfer_buffer_length as usize);
+        if ccid {
+            if ep.address & 0x80 == 0 {
+                self.ccid_sequence = req.get(CCID_SEQUENCE).copied();
+            } else if let (Some(seq), Some(sequence)) =
+                (res.get_mut(CCID_SEQUENCE), self.ccid_sequence)
+            {
+                *seq = sequence;
+            }
+        }
+        Ok(res)
+    }
+
+    fn as_any(&mut self) -> &mut dyn Any {
+        self
+    }
+}
+
+/// Answer the URBs to the interfaces of `device` from `trace`, replacing
+/// their handlers, see the [module docs](self)
+///
+/// The URBs recorded for the bus id of `device` are replayed.
+pub fn replay(device: &UsbDevice, trace: impl BufRead) -> Result<()> {
+    let mut interfaces: HashMap<usize, HashMap<u8, VecDeque<Entry>>> = HashMap::new();
+    let mut lines = trace.lines();
+    if lines.next().transpose()?.as_deref() != Some(HEADER) {
+        return Err(invalid("Not a usbip trace"));
+    }
+    for (number, line) in lines.enumerate() {
+        let line = line?;
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let fields: Vec<_> = line.split(' ').collect();
+        let [_, bus_id, interface, ep, setup, _, req, status, res] = fields[..] else {
+            return Err(invalid(format!(
+                "Line {} of the trace is invalid",
+                number + 2
+            )));
+        };
+        if bus_id != device.bus_id {
+            continue;
+        }
+        let parse = || -> Option<_> {
+            let interface = interface.parse().ok()?;
+            let ep = u8::from_str_radix(ep, 16).ok()?;
+            let setup = unhex(setup)?.into_iter().collect::<Option<Vec<_>>>()?;
+            let entry = Entry {
+                setup: setup.try_into().ok()?,
+                req: unhex(req)?,
+                status: status.parse().ok()?,
+                res: unhex(res)?.into_iter().collect::<Option<_>>()?,
+            };
+            Some((interface, ep, entry))
+        };
+        let (interface, ep, entry) = parse()
+            .ok_or_else(|| invalid(format!("Line {} of the trace is invalid", number + 2)))?;
+        interfaces
+            .entry(interface)
+            .or_default()
+            .entry(ep)
+            .or_default()
+            .push_back(entry);
+    }
+    wrap_handlers(device, |number, _| {
+        Box::new(Replay {
+            class_specific_descriptor: device.interfaces[number].class_specific_descriptor.clone(),
+            entries: interfaces.remove(&number).unwrap_or_default(),
+            ccid_sequence: None,
+        })
+    });
+    Ok(())
+}
+
+fn invalid(msg: impl Into<String>) -> Error {
+    Error::new(ErrorKind::InvalidData, msg.into())
+}
+
+/// Hex digits of `bytes`, `xx` for redacted ones, or `-` for none
+fn hex(bytes: impl ExactSizeIterator<Item = Option<u8>>) -> String {
+    if bytes.len() == 0 {
+        return "-".to_string();
+    }
+    bytes
+        .map(|byte| byte.map_or("xx".to_string(), |byte| format!("{:02x}", byte)))
+        .collect()
+}
+
+/// The bytes [hex] wrote
+fn unhex(s: &str) -> Option<Vec<Option<u8>>> {
+    if s == "-" {
+        return Some(vec![]);
+    }
+    if !s.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..s.len())
+        .step_by(2)
+        .map(|i| match s.get(i..i + 2)? {
+            "xx" => Some(None),
+            byte => u8::from_str_radix(byte, 16).ok().map(Some),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ccid::{CcidBackend, RemoteReader, UsbCcidHandler, CCID_SUBCLASS};
+    use crate::loopback::Loopback;
+    use crate::util::tests::*;
+
+    /// Answers every APDU with its instruction, then 90 00
+    struct EchoCard;
+
+    impl CcidBackend for EchoCard {
+        fn power_on(&mut self) -> Result<Vec<u8>> {
+            Ok(vec![0x3B, 0x00])
+        }
+
+        fn power_off(&mut self) -> Result<()> {
+            Ok(())
+        }
+
+        fn transmit(&mut self, apdu: &[u8]) -> Result<Vec<u8>> {
+            Ok(vec![apdu[1], 0x90, 0x00])
+        }
+    }
+
+    fn reader(handler: Box<dyn UsbInterfaceHandler + Send>) -> UsbDevice {
+        UsbDevice::new(0).with_interface(
+            ClassCode::SmartCard as u8,
+            CCID_SUBCLASS,
+            0x00,
+            "Test CCID",
+            UsbCcidHandler::<EchoCard>::endpoints(),
+            Arc::new(Mutex::new(handler)),
+        )
+    }
+
+    const VERIFY: &[u8] = &[
+        0x00, 0x20, 0x00, 0x80, 0x08, 0x31, 0x32, 0x33, 0x34, 0x35, 0x36, 0xFF, 0xFF,
+    ];
+
+    async fn exchange(device: UsbDevice) -> Vec<Vec<u8>> {
+        let loopback = Loopback::new(vec![device]);
+        let mut reader = RemoteReader::open(loopback.import("0-0-0").await.unwrap())
+            .await
+            .unwrap();
+        let mut replies = vec![reader.power_on().await.unwrap()];
+        replies.push(reader.transmit(VERIFY, || ()).await.unwrap());
+        replies.push(
+            reader
+                .transmit(&[0x00, 0xCB, 0x3F, 0xFF], || ())
+                .await
+                .unwrap(),
+        );
+        replies
+    }
+
+    #[tokio::test]
+    async fn record_and_replay() {
+        setup_test_logger();
+        let out = SharedBuffer::default();
+        let trace = Arc::new(Trace::new(out.clone(), true).unwrap());
+        let device = reader(Box::new(UsbCcidHandler::new(EchoCard)));
+        record(&device, &trace);
+        let replies = exchange(device).await;
+        assert_eq!(replies[1], [0x20, 0x90, 0x00]);
+
+        let recorded = String::from_utf8(out.0.lock().unwrap().clone()).unwrap();
+        assert!(recorded.starts_with(HEADER));
+        // The PIN is redacted, the rest of the APDU is not
+        assert!(recorded.contains("0020008008xxxxxxxxxxxxxxxx "));
+        assert!(!recorded.contains("313233343536"));
+        assert!(recorded.contains("00cb3fff"));
+
+        // The same exchange against the trace, with another PIN
+        let device = reader(Box::new(Detached));
+        replay(&device, recorded.as_bytes()).unwrap();
+        let loopback = Loopback::new(vec![device]);
+        let mut reader = RemoteReader::open(loopback.import("0-0-0").await.unwrap())
+            .await
+            .unwrap();
+        assert_eq!(reader.power_on().await.unwrap(), replies[0]);
+        let mut other_pin = VERIFY.to_vec();
+        other_pin[5] = 0x39;
+        assert_eq!(
+            reader.transmit(&other_pin, || ()).await.unwrap(),
+            replies[1]
+        );
+        assert_eq!(
+            reader
+                .transmit(&[0x00, 0xCB, 0x3F, 0xFF], || ())
+                .await
+                .unwrap(),
+            replies[2]
+        );
+        // Past the end of the trace
+        assert!(reader
+            .transmit(&[0x00, 0xCB, 0x3F, 0xFF], || ())
+            .await
+            .is_err());
+    }
+
+    #[test]
+    fn traces() {
+        let device = UsbDevice::new(0);
+        assert!(replay(&device, &b"0.1 0-0-0 0 81\n"[..]).is_err());
+        let trace = format!("{}\n0.1 0-0-0 0 81 0000000000000000 8 - 0 zz\n", HEADER);
+        assert!(replay(&device, trace.as_bytes()).is_err());
+        // Other devices' URBs are skipped
+        let trace = format!("{}\n0.1 1-2 0 81 0000000000000000 8 - 0 -\n", HEADER);
+        assert!(replay(&device, trace.as_bytes()).is_ok());
+
+        assert_eq!(hex([Some(1), None, Some(0xAB)].into_iter()), "01xxab");
+        assert_eq!(unhex("01xxab"), Some(vec![Some(1), None, Some(0xAB)]));
+        assert_eq!(hex([].into_iter()), "-");
+        assert_eq!(unhex("-"), Some(vec![]));
+        assert_eq!(unhex("0"), None);
+    }
+}
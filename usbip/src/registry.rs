@@ -0,0 +1,283 @@
+//! The devices a server exports
+//!
+//! Every device is registered under its bus id, and is either available or
+//! imported by exactly one connection. Each device has its own lock, so that
+//! connections importing or releasing different devices don't wait on each
+//! other: the registry as a whole is only locked exclusively to add or remove
+//! a device.
+//!
+//! An import is a lease on the device, which the server can revoke: the
+//! device stays busy until the connection holding it notices and lets go.
+use crate::UsbDevice;
+use std::collections::BTreeMap;
+use std::io::{Error, ErrorKind, Result};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use tokio::sync::RwLock;
+
+/// Tells the connections of a server apart, so that a device released or
+/// unplugged and then imported again isn't mistaken for the old import
+pub(crate) type ConnectionId = u64;
+
+struct Entry {
+    device: Arc<UsbDevice>,
+    /// The connection that imported the device
+    importer: Option<ConnectionId>,
+    /// Whether the import was revoked, and the importer has yet to let go
+    revoked: bool,
+}
+
+/// The devices of a server, by bus id
+#[derive(Default)]
+pub(crate) struct Registry {
+    devices: RwLock<BTreeMap<String, Mutex<Entry>>>,
+    next_connection: AtomicU64,
+}
+
+impl Registry {
+    pub(crate) fn new(devices: Vec<UsbDevice>) -> Self {
+        Self {
+            devices: RwLock::new(
+                devices
+                    .into_iter()
+                    .map(|device| (device.bus_id.clone(), Entry::new(device)))
+                    .collect(),
+            ),
+            ..Default::default()
+        }
+    }
+
+    /// All devices, before the server runs
+    pub(crate) fn get_mut(&mut self) -> impl Iterator<Item = &UsbDevice> {
+        self.devices
+            .get_mut()
+            .values_mut()
+            .map(|entry| &*entry.get_mut().unwrap().device)
+    }
+
+    /// A new id for a connection
+    pub(crate) fn connection_id(&self) -> ConnectionId {
+        self.next_connection.fetch_add(1, Ordering::Relaxed)
+    }
+
+    /// The devices no connection imported
+    pub(crate) async fn available(&self) -> Vec<UsbDevice> {
+        self.devices
+            .read()
+            .await
+            .values()
+            .filter_map(|entry| {
+                let entry = entry.lock().unwrap();
+                entry.importer.is_none().then(|| (*entry.device).clone())
+            })
+            .collect()
+    }
+
+    /// All devices, imported or not
+    #[cfg_attr(not(feature = "mdns"), allow(dead_code))]
+    pub(crate) async fn all(&self) -> Vec<Arc<UsbDevice>> {
+        self.devices
+            .read()
+            .await
+            .values()
+            .map(|entry| entry.lock().unwrap().device.clone())
+            .collect()
+    }
+
+    /// The bus ids of all devices, imported or not
+    pub(crate) async fn bus_ids(&self) -> Vec<String> {
+        self.devices.read().await.keys().cloned().collect()
+    }
+
+    pub(crate) async fn contains(&self, bus_id: &str) -> bool {
+        self.devices.read().await.contains_key(bus_id)
+    }
+
+    /// The device with `bus_id`, if a connection imported it
+    pub(crate) async fn imported(&self, bus_id: &str) -> Option<Arc<UsbDevice>> {
+        let devices = self.devices.read().await;
+        let entry = devices.get(bus_id)?.lock().unwrap();
+        entry.importer.map(|_| entry.device.clone())
+    }
+
+    /// Whether the device with `bus_id` is imported by `connection`, i.e. it
+    /// was neither released, revoked nor unplugged since
+    pub(crate) async fn is_imported_by(&self, bus_id: &str, connection: ConnectionId) -> bool {
+        let devices = self.devices.read().await;
+        devices.get(bus_id).is_some_and(|entry| {
+            let entry = entry.lock().unwrap();
+            entry.importer == Some(connection) && !entry.revoked
+        })
+    }
+
+    /// Register `device`, replacing an available device with the same bus id
+    ///
+    /// An imported device is kept, and `device` is dropped.
+    pub(crate) async fn add(&self, device: UsbDevice) {
+        let mut devices = self.devices.write().await;
+        if devices.get(&device.bus_id).is_some_and(Entry::in_use) {
+            tracing::warn!("Device {} is in use, not replacing it", device.bus_id);
+        } else {
+            devices.insert(device.bus_id.clone(), Entry::new(device));
+        }
+    }
+
+    /// Remove the device with `bus_id`, unless it is imported
+    pub(crate) async fn remove(&self, bus_id: &str) -> Result<()> {
+        let mut devices = self.devices.write().await;
+        match devices.get(bus_id) {
+            Some(entry) if Entry::in_use(entry) => {
+                Err(Error::other(format!("Device {} is in use", bus_id)))
+            }
+            Some(_) => {
+                devices.remove(bus_id);
+                Ok(())
+            }
+            None => Err(not_found(bus_id)),
+        }
+    }
+
+    /// Remove the device with `bus_id` even if it is imported, returning
+    /// whether it was
+    pub(crate) async fn unplug(&self, bus_id: &str) -> Result<bool> {
+        match self.devices.write().await.remove(bus_id) {
+            Some(entry) => Ok(Entry::in_use(&entry)),
+            None => Err(not_found(bus_id)),
+        }
+    }
+
+    /// Import the device whose bus id is `bus_id` for `connection`
+    ///
+    /// A device that isn't `allowed` is as good as unknown, failing with
+    /// [ErrorKind::NotFound]. One another connection holds fails with
+    /// [ErrorKind::ResourceBusy].
+    pub(crate) async fn import(
+        &self,
+        bus_id: &[u8],
+        connection: ConnectionId,
+        allowed: impl FnOnce(&UsbDevice) -> bool,
+    ) -> Result<Arc<UsbDevice>> {
+        let bus_id = String::from_utf8_lossy(bus_id);
+        let devices = self.devices.read().await;
+        let mut entry = devices
+            .get(bus_id.as_ref())
+            .ok_or_else(|| not_found(&bus_id))?
+            .lock()
+            .unwrap();
+        if !allowed(&entry.device) {
+            return Err(not_found(&bus_id));
+        }
+        if entry.importer.is_some() {
+            return Err(Error::new(
+                ErrorKind::ResourceBusy,
+                format!("Device {} is in use", bus_id),
+            ));
+        }
+        entry.importer = Some(connection);
+        Ok(entry.device.clone())
+    }
+
+    /// Revoke the import of the device with `bus_id`
+    ///
+    /// Fails with [ErrorKind::InvalidInput] if no connection imported it.
+    pub(crate) async fn revoke(&self, bus_id: &str) -> Result<()> {
+        let devices = self.devices.read().await;
+        let mut entry = devices
+            .get(bus_id)
+            .ok_or_else(|| not_found(bus_id))?
+            .lock()
+            .unwrap();
+        if entry.importer.is_none() {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!("Device {} is not imported", bus_id),
+            ));
+        }
+        entry.revoked = true;
+        Ok(())
+    }
+
+    /// Make the device with `bus_id` available again, if `connection` still
+    /// has it imported, returning it
+    pub(crate) async fn release(
+        &self,
+        bus_id: &str,
+        connection: ConnectionId,
+    ) -> Option<Arc<UsbDevice>> {
+        let devices = self.devices.read().await;
+        let mut entry = devices.get(bus_id)?.lock().unwrap();
+        if entry.importer != Some(connection) {
+            return None;
+        }
+        entry.importer = None;
+        entry.revoked = false;
+        Some(entry.device.clone())
+    }
+}
+
+impl Entry {
+    fn new(device: UsbDevice) -> Mutex<Self> {
+        Mutex::new(Self {
+            device: Arc::new(device),
+            importer: None,
+            revoked: false,
+        })
+    }
+
+    fn in_use(entry: &Mutex<Self>) -> bool {
+        entry.lock().unwrap().importer.is_some()
+    }
+}
+
+fn not_found(bus_id: &str) -> Error {
+    Error::new(ErrorKind::NotFound, format!("Device {} not found", bus_id))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn import_and_release() {
+        let registry = Registry::new((0..2).map(UsbDevice::new).collect());
+        let (first, second) = (registry.connection_id(), registry.connection_id());
+
+        // Different devices can be imported at the same time, each only once
+        assert!(registry.import(b"0-0-0", first, |_| true).await.is_ok());
+        let busy = registry.import(b"0-0-0", second, |_| true).await;
+        assert_eq!(
+            busy.err().map(|err| err.kind()),
+            Some(ErrorKind::ResourceBusy)
+        );
+        let denied = registry.import(b"0-0-1", second, |_| false).await;
+        assert_eq!(
+            denied.err().map(|err| err.kind()),
+            Some(ErrorKind::NotFound)
+        );
+        assert!(registry.import(b"0-0-1", second, |_| true).await.is_ok());
+        assert!(registry.available().await.is_empty());
+        assert!(registry.remove("0-0-1").await.is_err());
+
+        // Only the importer releases a device
+        registry.release("0-0-0", second).await;
+        assert!(registry.is_imported_by("0-0-0", first).await);
+        registry.release("0-0-0", first).await;
+        assert_eq!(registry.available().await.len(), 1);
+
+        // A revoked device stays busy until its importer lets go
+        assert!(registry.revoke("0-0-0").await.is_err());
+        assert!(registry.import(b"0-0-0", first, |_| true).await.is_ok());
+        registry.revoke("0-0-0").await.unwrap();
+        assert!(!registry.is_imported_by("0-0-0", first).await);
+        assert!(registry.import(b"0-0-0", second, |_| true).await.is_err());
+        registry.release("0-0-0", first).await;
+        assert!(registry.import(b"0-0-0", second, |_| true).await.is_ok());
+
+        // A device plugged back in isn't imported by the old connection
+        assert!(registry.unplug("0-0-1").await.unwrap());
+        registry.add(UsbDevice::new(1)).await;
+        assert!(!registry.is_imported_by("0-0-1", second).await);
+        assert!(registry.remove("0-0-1").await.is_ok());
+        assert!(registry.remove("0-0-1").await.is_err());
+    }
+}
@@ -0,0 +1,336 @@
+//! Rate limits, and throttling of PIN guesses
+//!
+//! A server given a [RateLimit] with [crate::UsbIpServer::with_rate_limit]
+//! keeps a token bucket for each client, told apart by its address, TLS
+//! certificate, user id and token: every command the client sends takes a
+//! token, and once they are used up its commands wait for the bucket to
+//! refill. A client flooding the server only slows itself down.
+//!
+//! With [crate::UsbIpServer::with_pin_throttle], the server also follows the
+//! replies of smart cards to the APDUs that check a PIN or PUK: VERIFY with a
+//! PIN, CHANGE REFERENCE DATA and RESET RETRY COUNTER. After a failed check,
+//! the next one to the same device waits, twice as long after each further
+//! failure, until one succeeds. YubiKeys block a PIN after a few wrong
+//! guesses, but the number of retries can be set to up to 255, and a client
+//! knowing the PUK can reset them.
+use super::*;
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+use tokio::time::Instant;
+
+/// Buckets of idle clients are forgotten once there are this many
+const MAX_BUCKETS: usize = 1024;
+
+/// The longest a PIN check is held up
+pub const MAX_PIN_DELAY: Duration = Duration::from_secs(3600);
+
+/// How many commands a client may send
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RateLimit {
+    /// Commands per second on average
+    pub rate: f64,
+    /// Commands at once, after the client was idle for a while
+    pub burst: f64,
+}
+
+/// A client, as far as rate limits are concerned
+#[derive(Clone, PartialEq, Eq, Hash)]
+pub(crate) struct Client {
+    addr: Option<IpAddr>,
+    cert_fingerprint: Option<[u8; 32]>,
+    uid: Option<u32>,
+    token: Option<String>,
+}
+
+impl From<&acl::Peer> for Client {
+    fn from(peer: &acl::Peer) -> Self {
+        Self {
+            addr: peer.addr,
+            cert_fingerprint: peer.cert_fingerprint,
+            uid: peer.uid,
+            token: peer.token.clone(),
+        }
+    }
+}
+
+struct Bucket {
+    /// Negative while commands wait for tokens they reserved
+    tokens: f64,
+    updated: Instant,
+}
+
+/// The rate limits and PIN throttling of a server
+#[derive(Default)]
+pub(crate) struct Throttle {
+    pub(crate) rate_limit: Option<RateLimit>,
+    pub(crate) pin_delay: Option<Duration>,
+    buckets: Mutex<HashMap<Client, Bucket>>,
+    /// Failed PIN checks in a row, by the bus id of the device
+    pin_failures: Arc<Mutex<HashMap<String, u32>>>,
+}
+
+impl Throttle {
+    /// Take a token from the bucket of `client`, returning how long it must
+    /// wait for it
+    fn take(&self, client: &Client, now: Instant) -> Option<Duration> {
+        let limit = self.rate_limit?;
+        let mut buckets = self.buckets.lock().unwrap();
+        if buckets.len() >= MAX_BUCKETS {
+            buckets.retain(|_, bucket| {
+                bucket.tokens + (now - bucket.updated).as_secs_f64() * limit.rate < limit.burst
+            });
+        }
+        let bucket = buckets.entry(client.clone()).or_insert(Bucket {
+            tokens: limit.burst,
+            updated: now,
+        });
+        let refilled = (now - bucket.updated).as_secs_f64() * limit.rate;
+        bucket.tokens = (bucket.tokens + refilled).min(limit.burst) - 1.0;
+        bucket.updated = now;
+        (bucket.tokens < 0.0).then(|| Duration::from_secs_f64(-bucket.tokens / limit.rate))
+    }
+
+    /// Wait until `client` may send another command
+    pub(crate) async fn admit(&self, client: &Client) {
+        if let Some(wait) = self.take(client, Instant::now()) {
+            trace!("Rate limiting a client for {:?}", wait);
+            tokio::time::sleep(wait).await;
+        }
+    }
+
+    /// Follows the PIN checks of one connection, if they are throttled
+    pub(crate) fn pin_watcher(&self) -> Option<PinWatcher> {
+        Some(PinWatcher {
+            delay: self.pin_delay?,
+            failures: self.pin_failures.clone(),
+            checking: Default::default(),
+        })
+    }
+}
+
+/// Whether `apdu` checks a PIN or PUK, rather than e.g. asking for the
+/// retries left with an empty VERIFY
+pub(crate) fn checks_pin(apdu: &[u8]) -> bool {
+    matches!(apdu, [_, 0x20 | 0x24 | 0x2C, _, _, lc, ..] if *lc != 0 || apdu.len() > 7)
+}
+
+/// Follows the PIN checks to the devices one connection imports, holding
+/// them up after failed ones
+#[derive(Clone)]
+pub(crate) struct PinWatcher {
+    delay: Duration,
+    failures: Arc<Mutex<HashMap<String, u32>>>,
+    /// Whether the reply the card sends next answers a PIN check
+    checking: Arc<AtomicBool>,
+}
+
+impl PinWatcher {
+    /// How long the URB with `header` and `data` to `device` must wait, if
+    /// it carries a PIN check after failed ones
+    pub(crate) fn before(
+        &self,
+        device: &UsbDevice,
+        header: &UsbIpHeaderBasic,
+        data: &[u8],
+    ) -> Option<Duration> {
+        audit::apdu_instruction(device, header, data)?;
+        // Past the header of the PC_to_RDR_XfrBlock
+        if !checks_pin(&data[10..]) {
+            return None;
+        }
+        self.checking.store(true, Ordering::SeqCst);
+        let failures = *self.failures.lock().unwrap().get(&device.bus_id)?;
+        Some(
+            self.delay
+                .saturating_mul(1 << (failures - 1).min(31))
+                .min(MAX_PIN_DELAY),
+        )
+    }
+
+    /// Follow the reply `res` to the URB with `header` to `device`, returning
+    /// the failed PIN checks in a row if it tells of another one
+    pub(crate) fn after(
+        &self,
+        device: &UsbDevice,
+        header: &UsbIpHeaderBasic,
+        res: &UsbIpResponse,
+    ) -> Option<u32> {
+        if events::ccid_waiting(device, header, res) != Some(false)
+            || !self.checking.swap(false, Ordering::SeqCst)
+        {
+            return None;
+        }
+        let UsbIpResponse::UsbIpRetSubmit {
+            transfer_buffer, ..
+        } = res
+        else {
+            return None;
+        };
+        let mut failures = self.failures.lock().unwrap();
+        match transfer_buffer[..] {
+            [.., 0x90, 0x00] => {
+                failures.remove(&device.bus_id);
+                None
+            }
+            // Wrong, with the retries left in the low nibble, or blocked
+            [.., 0x63, _] | [.., 0x69, 0x83] => {
+                let failures = failures.entry(device.bus_id.clone()).or_default();
+                *failures += 1;
+                Some(*failures)
+            }
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::tests::*;
+
+    const PIN: &[u8] = b"123456\xFF\xFF";
+
+    /// Checks VERIFYs of the PIV PIN against [PIN], and echoes other APDUs
+    struct PinCard;
+
+    impl ccid::CcidBackend for PinCard {
+        fn power_on(&mut self) -> Result<Vec<u8>> {
+            Ok(vec![0x3B, 0x00])
+        }
+
+        fn power_off(&mut self) -> Result<()> {
+            Ok(())
+        }
+
+        fn transmit(&mut self, apdu: &[u8]) -> Result<Vec<u8>> {
+            Ok(match apdu {
+                [0x00, 0x20, 0x00, 0x80] => vec![0x63, 0xC3],
+                [0x00, 0x20, 0x00, 0x80, 0x08, pin @ ..] if pin == PIN => vec![0x90, 0x00],
+                [0x00, 0x20, 0x00, 0x80, ..] => vec![0x63, 0xC2],
+                apdu => [apdu, &[0x90, 0x00]].concat(),
+            })
+        }
+
+        fn present(&mut self) -> bool {
+            true
+        }
+    }
+
+    fn verify(pin: &[u8]) -> Vec<u8> {
+        [&[0x00, 0x20, 0x00, 0x80, pin.len() as u8], pin].concat()
+    }
+
+    /// Send a VERIFY of `pin`, returning the reply and how long it took
+    async fn check(reader: &mut ccid::RemoteReader, pin: &[u8]) -> (Vec<u8>, Duration) {
+        let start = std::time::Instant::now();
+        let reply = reader.transmit(&verify(pin), || ()).await.unwrap();
+        (reply, start.elapsed())
+    }
+
+    #[test]
+    fn token_bucket() {
+        let throttle = Throttle {
+            rate_limit: Some(RateLimit {
+                rate: 10.0,
+                burst: 2.0,
+            }),
+            ..Default::default()
+        };
+        let client = Client::from(&acl::Peer::default());
+        let other = Client::from(&acl::Peer {
+            uid: Some(1000),
+            ..Default::default()
+        });
+        let now = Instant::now();
+        assert_eq!(throttle.take(&client, now), None);
+        assert_eq!(throttle.take(&client, now), None);
+        assert_eq!(
+            throttle.take(&client, now),
+            Some(Duration::from_millis(100))
+        );
+        assert_eq!(
+            throttle.take(&client, now),
+            Some(Duration::from_millis(200))
+        );
+        // Other clients have buckets of their own
+        assert_eq!(throttle.take(&other, now), None);
+        // The bucket refills, but only up to the burst
+        let later = now + Duration::from_secs(10);
+        assert_eq!(throttle.take(&client, later), None);
+        assert_eq!(throttle.take(&client, later), None);
+        assert!(throttle.take(&client, later).is_some());
+
+        assert_eq!(Throttle::default().take(&client, now), None);
+    }
+
+    #[test]
+    fn pin_checks() {
+        assert!(checks_pin(&verify(PIN)));
+        assert!(checks_pin(&[0x00, 0x2C, 0x00, 0x80, 0x10]));
+        // Asking for the retries left isn't a guess
+        assert!(!checks_pin(&[0x00, 0x20, 0x00, 0x80]));
+        assert!(!checks_pin(&[0x00, 0x20, 0x00, 0x80, 0x00]));
+        assert!(!checks_pin(&[0x00, 0x87, 0x11, 0x9A, 0x10]));
+    }
+
+    #[tokio::test]
+    async fn failed_pins_are_throttled() {
+        setup_test_logger();
+        let device = UsbDevice::new(0).with_interface(
+            ClassCode::SmartCard as u8,
+            ccid::CCID_SUBCLASS,
+            0x00,
+            "Test CCID",
+            ccid::UsbCcidHandler::<PinCard>::endpoints(),
+            Arc::new(Mutex::new(
+                Box::new(ccid::UsbCcidHandler::new(PinCard)) as Box<dyn UsbInterfaceHandler + Send>
+            )),
+        );
+        let bus_id = device.bus_id.clone();
+        let (records, mut audit) = mpsc::unbounded_channel();
+        let server = UsbIpServer::new_simulated(vec![device])
+            .with_pin_throttle(Duration::from_millis(200))
+            .with_audit(records);
+        let addr = get_free_address().await;
+        tokio::spawn(crate::server(addr, Arc::new(server)));
+
+        let mut socket = poll_connect(addr).await;
+        let device = client::import(&mut socket, &bus_id).await.unwrap();
+        let device = client::ImportedDevice::new(socket, device);
+        let mut reader = ccid::RemoteReader::open(device).await.unwrap();
+        reader.power_on().await.unwrap();
+
+        let (reply, elapsed) = check(&mut reader, b"000000\xFF\xFF").await;
+        assert_eq!(reply, [0x63, 0xC2]);
+        assert!(elapsed < Duration::from_millis(200));
+        // The next guesses wait, twice as long after each
+        let (_, elapsed) = check(&mut reader, b"111111\xFF\xFF").await;
+        assert!(elapsed >= Duration::from_millis(200));
+        let (reply, elapsed) = check(&mut reader, PIN).await;
+        assert_eq!(reply, [0x90, 0x00]);
+        assert!(elapsed >= Duration::from_millis(400));
+        // Until one succeeds
+        let (_, elapsed) = check(&mut reader, b"000000\xFF\xFF").await;
+        assert!(elapsed < Duration::from_millis(200));
+        // Other APDUs never wait
+        let start = std::time::Instant::now();
+        reader
+            .transmit(&[0x00, 0x20, 0x00, 0x80], || ())
+            .await
+            .unwrap();
+        reader
+            .transmit(&[0x00, 0xCB, 0x3F, 0xFF], || ())
+            .await
+            .unwrap();
+        assert!(start.elapsed() < Duration::from_millis(200));
+
+        let mut failures = vec![];
+        while let Ok(record) = audit.try_recv() {
+            if let audit::Record::PinFailed { failures: n, .. } = record {
+                failures.push(n);
+            }
+        }
+        assert_eq!(failures, [1, 2, 1]);
+    }
+}
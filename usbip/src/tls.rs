@@ -0,0 +1,286 @@
+//! TLS transport
+//!
+//! USB/IP is plaintext, so anyone on the path can read and inject URBs,
+//! including the PINs sent to a smart card. With the `tls` feature the
+//! connection can be wrapped in TLS instead: the server presents a
+//! certificate, and can require one from its clients as well. Both ends must
+//! agree; the Linux `usbip` tools and vhci-hcd only speak plain TCP.
+//!
+//! ```no_run
+//! # async fn run(server: std::sync::Arc<usbip::UsbIpServer>) -> std::io::Result<()> {
+//! use usbip::tls;
+//!
+//! let acceptor = tls::acceptor(
+//!     tls::load_certs("server.pem")?,
+//!     tls::load_private_key("server.key")?,
+//!     Some(tls::load_certs("clients-ca.pem")?),
+//! )?;
+//! tls::server("0.0.0.0:3240".parse().unwrap(), server, acceptor).await;
+//! # Ok(())
+//! # }
+//! ```
+use crate::acl::Peer;
+use crate::{Transport, UsbIpServer};
+use rustls_pki_types::pem::PemObject;
+use rustls_pki_types::{CertificateDer, PrivateKeyDer, ServerName};
+use sha2::{Digest, Sha256};
+use std::io::{Error, ErrorKind, Result};
+use std::net::SocketAddr;
+use std::path::Path;
+use std::sync::Arc;
+use tokio::net::{TcpListener, TcpStream, ToSocketAddrs};
+use tokio_rustls::rustls::crypto::{ring, CryptoProvider};
+use tokio_rustls::rustls::server::WebPkiClientVerifier;
+use tokio_rustls::rustls::{ClientConfig, RootCertStore, ServerConfig};
+
+pub use tokio_rustls::client::TlsStream;
+pub use tokio_rustls::{rustls, TlsAcceptor, TlsConnector};
+
+fn invalid(err: impl std::fmt::Display) -> Error {
+    Error::new(ErrorKind::InvalidInput, err.to_string())
+}
+
+fn provider() -> Arc<CryptoProvider> {
+    Arc::new(ring::default_provider())
+}
+
+fn root_store(roots: Vec<CertificateDer<'static>>) -> Result<Arc<RootCertStore>> {
+    let mut store = RootCertStore::empty();
+    for cert in roots {
+        store.add(cert).map_err(invalid)?;
+    }
+    Ok(Arc::new(store))
+}
+
+/// Read the PEM-encoded certificates in `path`
+pub fn load_certs(path: impl AsRef<Path>) -> Result<Vec<CertificateDer<'static>>> {
+    CertificateDer::pem_file_iter(path)
+        .and_then(|certs| certs.collect())
+        .map_err(invalid)
+}
+
+/// Read the first PEM-encoded private key in `path`
+pub fn load_private_key(path: impl AsRef<Path>) -> Result<PrivateKeyDer<'static>> {
+    PrivateKeyDer::from_pem_file(path).map_err(invalid)
+}
+
+/// Server side of the handshake, presenting `certs` signed by `key`
+///
+/// With `client_roots`, clients must present a certificate issued by one of
+/// them, otherwise any client may connect.
+pub fn acceptor(
+    certs: Vec<CertificateDer<'static>>,
+    key: PrivateKeyDer<'static>,
+    client_roots: Option<Vec<CertificateDer<'static>>>,
+) -> Result<TlsAcceptor> {
+    Ok(TlsAcceptor::from(Arc::new(server_config(
+        certs,
+        key,
+        client_roots,
+    )?)))
+}
+
+/// The configuration behind [acceptor], shared with [crate::quic]
+pub(crate) fn server_config(
+    certs: Vec<CertificateDer<'static>>,
+    key: PrivateKeyDer<'static>,
+    client_roots: Option<Vec<CertificateDer<'static>>>,
+) -> Result<ServerConfig> {
+    let builder = ServerConfig::builder_with_provider(provider())
+        .with_safe_default_protocol_versions()
+        .map_err(invalid)?;
+    let builder = match client_roots {
+        Some(roots) => builder.with_client_cert_verifier(
+            WebPkiClientVerifier::builder_with_provider(root_store(roots)?, provider())
+                .build()
+                .map_err(invalid)?,
+        ),
+        None => builder.with_no_client_auth(),
+    };
+    builder.with_single_cert(certs, key).map_err(invalid)
+}
+
+/// Client side of the handshake, trusting servers issued by `roots`
+///
+/// `identity` is the certificate chain and key to present to servers that
+/// ask for one.
+pub fn connector(
+    roots: Vec<CertificateDer<'static>>,
+    identity: Option<(Vec<CertificateDer<'static>>, PrivateKeyDer<'static>)>,
+) -> Result<TlsConnector> {
+    Ok(TlsConnector::from(Arc::new(client_config(
+        roots, identity,
+    )?)))
+}
+
+/// The configuration behind [connector], shared with [crate::quic]
+pub(crate) fn client_config(
+    roots: Vec<CertificateDer<'static>>,
+    identity: Option<(Vec<CertificateDer<'static>>, PrivateKeyDer<'static>)>,
+) -> Result<ClientConfig> {
+    let builder = ClientConfig::builder_with_provider(provider())
+        .with_safe_default_protocol_versions()
+        .map_err(invalid)?
+        .with_root_certificates(root_store(roots)?);
+    match identity {
+        Some((certs, key)) => builder.with_client_auth_cert(certs, key).map_err(invalid),
+        None => Ok(builder.with_no_client_auth()),
+    }
+}
+
+/// The SHA-256 fingerprint of the first of `certs`, the client's own
+pub(crate) fn fingerprint(certs: Option<&[CertificateDer<'_>]>) -> Option<[u8; 32]> {
+    certs
+        .and_then(|certs| certs.first())
+        .map(|cert| Sha256::digest(cert).into())
+}
+
+/// Connect to the server at `addr`, whose certificate must be valid for
+/// `server_name`
+///
+/// The stream can be used with [crate::client::list_devices],
+/// [crate::client::import] and [crate::client::ImportedDevice].
+pub async fn connect(
+    addr: impl ToSocketAddrs,
+    server_name: &str,
+    connector: &TlsConnector,
+) -> Result<TlsStream<TcpStream>> {
+    let server_name = ServerName::try_from(server_name.to_string()).map_err(invalid)?;
+    let socket = TcpStream::connect(addr).await?;
+    socket.set_nodelay(true)?;
+    connector.connect(server_name, socket).await
+}
+
+/// Spawn a USB/IP server at `addr` that only speaks TLS
+pub async fn server(addr: SocketAddr, server: Arc<UsbIpServer>, acceptor: TlsAcceptor) {
+    server_with_shutdown(addr, server, acceptor, std::future::pending()).await
+}
+
+/// Like [server], until `shutdown` completes, see [crate::server_with_shutdown]
+pub async fn server_with_shutdown(
+    addr: SocketAddr,
+    server: Arc<UsbIpServer>,
+    acceptor: TlsAcceptor,
+    shutdown: impl std::future::Future<Output = ()>,
+) {
+    let listener = TcpListener::bind(addr).await.expect("bind to addr");
+    listener_server_with_shutdown(listener, server, acceptor, shutdown).await
+}
+
+/// Like [server_with_shutdown], on a `listener` bound elsewhere, see
+/// [crate::listener_server_with_shutdown]
+pub async fn listener_server_with_shutdown(
+    listener: TcpListener,
+    server: Arc<UsbIpServer>,
+    acceptor: TlsAcceptor,
+    shutdown: impl std::future::Future<Output = ()>,
+) {
+    crate::serve(listener, server, shutdown, |socket| acceptor.accept(socket)).await
+}
+
+impl<T: Transport> Transport for tokio_rustls::server::TlsStream<T> {
+    fn peer(&self) -> Peer {
+        let (socket, connection) = self.get_ref();
+        Peer {
+            cert_fingerprint: fingerprint(connection.peer_certificates()),
+            ..socket.peer()
+        }
+    }
+}
+
+impl<T: Transport> Transport for TlsStream<T> {
+    fn peer(&self) -> Peer {
+        self.get_ref().0.peer()
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::acl::Policy;
+    use crate::util::tests::*;
+    use crate::{cdc, client, ClassCode, UsbDevice, UsbInterfaceHandler};
+    use rcgen::{BasicConstraints, CertificateParams, IsCa, KeyPair};
+    use std::sync::Mutex;
+
+    pub(crate) fn new_server() -> UsbIpServer {
+        UsbIpServer::new_simulated(vec![UsbDevice::new(0).with_interface(
+            ClassCode::CDC as u8,
+            cdc::CDC_ACM_SUBCLASS,
+            0x00,
+            "Test CDC ACM",
+            cdc::UsbCdcAcmHandler::endpoints(),
+            Arc::new(Mutex::new(
+                Box::new(cdc::UsbCdcAcmHandler::new()) as Box<dyn UsbInterfaceHandler + Send>
+            )),
+        )])
+    }
+
+    pub(crate) type Identity = (Vec<CertificateDer<'static>>, PrivateKeyDer<'static>);
+
+    /// A CA, and a certificate for `localhost` issued by it
+    pub(crate) fn issue(ca_name: &str) -> (CertificateDer<'static>, Identity) {
+        let ca_key = KeyPair::generate().unwrap();
+        let mut params = CertificateParams::new(vec![ca_name.to_string()]).unwrap();
+        params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        let ca = params.self_signed(&ca_key).unwrap();
+
+        let key = KeyPair::generate().unwrap();
+        let cert = CertificateParams::new(vec!["localhost".to_string()])
+            .unwrap()
+            .signed_by(&key, &ca, &ca_key)
+            .unwrap();
+        let key = PrivateKeyDer::from_pem_slice(key.serialize_pem().as_bytes()).unwrap();
+        (ca.der().clone(), (vec![cert.der().clone()], key))
+    }
+
+    #[tokio::test]
+    async fn mutual_authentication() {
+        setup_test_logger();
+        let (server_ca, (server_certs, server_key)) = issue("server ca");
+        let (client_ca, client_identity) = issue("client ca");
+        let (_, stranger_identity) = issue("other ca");
+
+        // Only this client may import devices, see crate::acl
+        let fingerprint: String = Sha256::digest(&client_identity.0[0])
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect();
+        let policy = Policy::parse(&format!("allow cert:{} any", fingerprint)).unwrap();
+
+        let addr = get_free_address().await;
+        let acceptor = acceptor(server_certs, server_key, Some(vec![client_ca])).unwrap();
+        tokio::spawn(server(
+            addr,
+            Arc::new(new_server().with_policy(policy)),
+            acceptor,
+        ));
+        poll_connect(addr).await;
+
+        let trusted = connector(vec![server_ca.clone()], Some(client_identity)).unwrap();
+        let mut socket = connect(addr, "localhost", &trusted).await.unwrap();
+        let devices = client::list_devices(&mut socket).await.unwrap();
+        assert_eq!(devices.len(), 1);
+
+        let mut socket = connect(addr, "localhost", &trusted).await.unwrap();
+        let device = client::import(&mut socket, &devices[0].bus_id)
+            .await
+            .unwrap();
+        let device = client::ImportedDevice::new(socket, device);
+        let setup = [0x80, 0x06, 0x00, 0x01, 0x00, 0x00, 0x12, 0x00];
+        assert_eq!(device.control_in(setup).await.unwrap().len(), 0x12);
+
+        // The server rejects clients without a certificate from its CA
+        for identity in [None, Some(stranger_identity)] {
+            let untrusted = connector(vec![server_ca.clone()], identity).unwrap();
+            let res = match connect(addr, "localhost", &untrusted).await {
+                Ok(mut socket) => client::list_devices(&mut socket).await,
+                Err(err) => Err(err),
+            };
+            assert!(res.is_err());
+        }
+
+        // and the client rejects servers with a certificate for another name
+        assert!(connect(addr, "example.com", &trusted).await.is_err());
+    }
+}
@@ -0,0 +1,166 @@
+//! Transports
+//!
+//! USB/IP runs over any byte stream. A [Transport] is one that can also tell
+//! what it knows about the other end, as the [Peer] an [crate::acl::Policy]
+//! matches: TCP the address of the client, a Unix domain socket its user, TLS
+//! the fingerprint of its certificate. Servers get the peers of their
+//! connections from their transports, and [crate::client::ImportedDevice]
+//! takes any transport.
+//!
+//! [crate::serve_connection] serves a single connection, which can be one end
+//! of an in-memory [tokio::io::duplex] pipe, for tests that need no sockets.
+//! [WithPeer] makes the client on the other end anyone a test needs:
+//!
+//! ```
+//! # #[tokio::main(flavor = "current_thread")]
+//! # async fn main() -> std::io::Result<()> {
+//! use std::sync::Arc;
+//! use usbip::{acl, client, transport::WithPeer, UsbDevice, UsbIpServer};
+//!
+//! let server = Arc::new(UsbIpServer::new_simulated(vec![UsbDevice::new(0)]));
+//! let (mut socket, server_end) = tokio::io::duplex(64 * 1024);
+//! let peer = acl::Peer {
+//!     uid: Some(1000),
+//!     ..Default::default()
+//! };
+//! tokio::spawn(usbip::serve_connection(WithPeer::new(server_end, peer), server));
+//!
+//! let devices = client::list_devices(&mut socket).await?;
+//! assert_eq!(devices.len(), 1);
+//! # Ok(())
+//! # }
+//! ```
+use crate::acl::Peer;
+use std::io::Result;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use tokio::io::{AsyncRead, AsyncWrite, DuplexStream, ReadBuf};
+use tokio::net::TcpStream;
+
+/// A connection USB/IP runs over, which knows something about the other end
+pub trait Transport: AsyncRead + AsyncWrite + Send + Unpin {
+    /// What the connection tells about the other end, e.g. its address;
+    /// nothing by default
+    fn peer(&self) -> Peer {
+        Peer::default()
+    }
+}
+
+impl Transport for TcpStream {
+    fn peer(&self) -> Peer {
+        Peer {
+            addr: self.peer_addr().ok().map(|addr| addr.ip()),
+            ..Default::default()
+        }
+    }
+}
+
+impl Transport for DuplexStream {}
+
+impl<T: Transport + ?Sized> Transport for Box<T> {
+    fn peer(&self) -> Peer {
+        (**self).peer()
+    }
+}
+
+impl<T: Transport> Transport for tokio::io::BufReader<T> {
+    fn peer(&self) -> Peer {
+        self.get_ref().peer()
+    }
+}
+
+impl<T: Transport> Transport for tokio::io::BufStream<T> {
+    fn peer(&self) -> Peer {
+        self.get_ref().peer()
+    }
+}
+
+/// A stream that doesn't know the other end itself, with what is known about
+/// it from elsewhere, e.g. the handshake of a protocol it runs over
+pub struct WithPeer<T> {
+    stream: T,
+    peer: Peer,
+}
+
+impl<T> WithPeer<T> {
+    pub fn new(stream: T, peer: Peer) -> Self {
+        Self { stream, peer }
+    }
+}
+
+impl<T: AsyncRead + AsyncWrite + Send + Unpin> Transport for WithPeer<T> {
+    fn peer(&self) -> Peer {
+        self.peer.clone()
+    }
+}
+
+impl<T: AsyncRead + Unpin> AsyncRead for WithPeer<T> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<Result<()>> {
+        Pin::new(&mut self.stream).poll_read(cx, buf)
+    }
+}
+
+impl<T: AsyncWrite + Unpin> AsyncWrite for WithPeer<T> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<Result<usize>> {
+        Pin::new(&mut self.stream).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        Pin::new(&mut self.stream).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        Pin::new(&mut self.stream).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::tests::*;
+    use crate::{acl, client, UsbDevice, UsbIpServer};
+    use std::sync::Arc;
+
+    #[tokio::test]
+    async fn tcp_peer() {
+        setup_test_logger();
+        let addr = get_free_address().await;
+        let listener = tokio::net::TcpListener::bind(addr).await.unwrap();
+        let client = TcpStream::connect(addr).await.unwrap();
+        let (server, _) = listener.accept().await.unwrap();
+        assert_eq!(server.peer().addr, Some(client.local_addr().unwrap().ip()));
+        let boxed: Box<dyn Transport> = Box::new(server);
+        assert!(boxed.peer().addr.is_some());
+    }
+
+    #[tokio::test]
+    async fn policy_over_duplex() {
+        setup_test_logger();
+        let device = UsbDevice::new(0);
+        let bus_id = device.bus_id.clone();
+        let policy = acl::Policy::parse("allow uid:1000 any").unwrap();
+        let server = Arc::new(UsbIpServer::new_simulated(vec![device]).with_policy(policy));
+
+        for (uid, allowed) in [(1000, true), (1001, false)] {
+            let (mut socket, server_end) = tokio::io::duplex(4096);
+            let peer = Peer {
+                uid: Some(uid),
+                ..Default::default()
+            };
+            tokio::spawn(crate::serve_connection(
+                WithPeer::new(server_end, peer),
+                server.clone(),
+            ));
+            let imported = client::import(&mut socket, &bus_id).await;
+            assert_eq!(imported.is_ok(), allowed);
+        }
+    }
+}
@@ -0,0 +1,144 @@
+//! Results of URBs
+use super::*;
+
+// Negated errnos of the status of a USBIP_RET_SUBMIT, as Linux reports them
+const EPIPE: i32 = 32;
+const ENODEV: i32 = 19;
+const EOVERFLOW: i32 = 75;
+const EPROTO: i32 = 71;
+const ETIMEDOUT: i32 = 110;
+
+/// Why a URB failed, sent to the client as the status of its
+/// USBIP_RET_SUBMIT
+#[derive(Debug)]
+pub enum UrbError {
+    /// The endpoint stalled (-EPIPE), e.g. on a request the device doesn't
+    /// support. Transfers to an endpoint other than ep0 keep failing until
+    /// the client clears the halt with CLEAR_FEATURE(ENDPOINT_HALT).
+    Stall,
+    /// The device didn't complete the transfer in time (-ETIMEDOUT)
+    Timeout,
+    /// The device is gone (-ENODEV)
+    NoDevice,
+    /// The device sent more data than the transfer buffer holds (-EOVERFLOW)
+    Overflow,
+    /// Any other failure (-EPROTO)
+    Other(std::io::Error),
+}
+
+/// The result of a URB: the data of IN transfers, or why it failed
+pub type UrbResult<T = Vec<u8>> = std::result::Result<T, UrbError>;
+
+impl UrbError {
+    /// The status of a USBIP_RET_SUBMIT for this error
+    pub fn status(&self) -> i32 {
+        -match self {
+            UrbError::Stall => EPIPE,
+            UrbError::Timeout => ETIMEDOUT,
+            UrbError::NoDevice => ENODEV,
+            UrbError::Overflow => EOVERFLOW,
+            UrbError::Other(_) => EPROTO,
+        }
+    }
+
+    /// The error a USBIP_RET_SUBMIT with `status` reports
+    pub fn from_status(status: i32) -> Self {
+        match -status {
+            EPIPE => UrbError::Stall,
+            ETIMEDOUT => UrbError::Timeout,
+            ENODEV => UrbError::NoDevice,
+            EOVERFLOW => UrbError::Overflow,
+            _ => UrbError::Other(std::io::Error::other(format!(
+                "URB failed with status {}",
+                status
+            ))),
+        }
+    }
+}
+
+impl std::fmt::Display for UrbError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            UrbError::Stall => write!(f, "Endpoint stalled"),
+            UrbError::Timeout => write!(f, "Transfer timed out"),
+            UrbError::NoDevice => write!(f, "No such device"),
+            UrbError::Overflow => write!(f, "Transfer overflowed"),
+            UrbError::Other(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+impl std::error::Error for UrbError {}
+
+impl From<rusb::Error> for UrbError {
+    fn from(err: rusb::Error) -> Self {
+        match err {
+            rusb::Error::Pipe => UrbError::Stall,
+            rusb::Error::Timeout => UrbError::Timeout,
+            rusb::Error::NoDevice => UrbError::NoDevice,
+            rusb::Error::Overflow => UrbError::Overflow,
+            err => UrbError::Other(std::io::Error::other(err)),
+        }
+    }
+}
+
+impl From<std::io::Error> for UrbError {
+    fn from(err: std::io::Error) -> Self {
+        if err.get_ref().is_some_and(|inner| inner.is::<UrbError>()) {
+            return *err.into_inner().unwrap().downcast::<UrbError>().unwrap();
+        }
+        // Errors of the libusb backend keep the rusb error
+        if let Some(err) = err
+            .get_ref()
+            .and_then(|inner| inner.downcast_ref::<rusb::Error>())
+        {
+            return (*err).into();
+        }
+        match err.kind() {
+            ErrorKind::BrokenPipe => UrbError::Stall,
+            ErrorKind::TimedOut => UrbError::Timeout,
+            ErrorKind::NotFound | ErrorKind::NotConnected => UrbError::NoDevice,
+            _ => UrbError::Other(err),
+        }
+    }
+}
+
+impl From<UrbError> for std::io::Error {
+    fn from(err: UrbError) -> Self {
+        let kind = match err {
+            UrbError::Stall => ErrorKind::BrokenPipe,
+            UrbError::Timeout => ErrorKind::TimedOut,
+            UrbError::NoDevice => ErrorKind::NotFound,
+            UrbError::Overflow => ErrorKind::InvalidData,
+            UrbError::Other(err) => return err,
+        };
+        std::io::Error::new(kind, err)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn statuses() {
+        for err in [
+            UrbError::Stall,
+            UrbError::Timeout,
+            UrbError::NoDevice,
+            UrbError::Overflow,
+        ] {
+            let status = err.status();
+            assert!(status < 0);
+            assert_eq!(UrbError::from_status(status).status(), status);
+            // and back through std::io::Error
+            assert_eq!(UrbError::from(std::io::Error::from(err)).status(), status);
+        }
+        assert_eq!(UrbError::from_status(-1).status(), -EPROTO);
+
+        let libusb = std::io::Error::other(rusb::Error::Pipe);
+        assert!(matches!(UrbError::from(libusb), UrbError::Stall));
+        let other = std::io::Error::new(ErrorKind::InvalidInput, "bad request");
+        assert_eq!(UrbError::from(other).status(), -EPROTO);
+    }
+}
@@ -6,90 +6,28 @@
 //!
 //! They are based on the [Linux kernel documentation](https://docs.kernel.org/usb/usbip_protocol.html).
 
-use log::trace;
+use bytes::BufMut;
 use std::io::Result;
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tracing::trace;
 
 #[cfg(feature = "serde")]
-use serde::{Deserialize, Serialize};
+use serde::Serialize;
 
 use crate::UsbDevice;
 
-/// USB/IP protocol version
-///
-/// This is currently the only supported version of USB/IP
-/// for this library.
-pub const USBIP_VERSION: u16 = 0x0111;
-
-/// Command code: Retrieve the list of exported USB devices
-pub const OP_REQ_DEVLIST: u16 = 0x8005;
-/// Command code: import a remote USB device
-pub const OP_REQ_IMPORT: u16 = 0x8003;
-/// Reply code: The list of exported USB devices
-pub const OP_REP_DEVLIST: u16 = 0x0005;
-/// Reply code: Reply to import
-pub const OP_REP_IMPORT: u16 = 0x0003;
-
-/// Command code: Submit an URB
-pub const USBIP_CMD_SUBMIT: u16 = 0x0001;
-/// Command code: Unlink an URB
-pub const USBIP_CMD_UNLINK: u16 = 0x0002;
-/// Reply code: Reply for submitting an URB
-pub const USBIP_RET_SUBMIT: u16 = 0x0003;
-/// Reply code: Reply for URB unlink
-pub const USBIP_RET_UNLINK: u16 = 0x0004;
-
-/// USB/IP direction
-///
-/// NOTE: Must not be confused with rusb::Direction,
-/// which has the opposite enum values. This is only for
-/// internal use in the USB/IP protocol.
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
-enum Direction {
-    Out = 0,
-    In = 1,
-}
+use crate::wire::Direction;
+pub use crate::wire::{
+    ExportedDevice, ExportedInterface, IsoPacketDescriptor, UsbIpCommand, UsbIpHeaderBasic,
+    UsbIpReply, OP_REP_DEVLIST, OP_REP_IMPORT, OP_REP_IMPORT_BUSY, OP_REQ_DEVLIST, OP_REQ_IMPORT,
+    USBIP_CMD_SUBMIT, USBIP_CMD_UNLINK, USBIP_RET_SUBMIT, USBIP_RET_UNLINK, USBIP_VERSION,
+};
 
-/// Common header for all context sensitive packets
-///
-/// All commands/responses which rely on a device being attached
-/// to a client use this header.
-#[derive(Debug, Clone, PartialEq, Eq)]
-#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
-pub struct UsbIpHeaderBasic {
-    pub command: u32,
-    pub seqnum: u32,
-    pub devid: u32,
-    pub direction: u32,
-    pub ep: u32,
-}
+/// errno of the status of a USBIP_RET_UNLINK for a URB that was cancelled,
+/// negated as Linux sends it
+pub const ECONNRESET: i32 = 104;
 
 impl UsbIpHeaderBasic {
-    /// Converts a byte array into a [UsbIpHeaderBasic].
-    pub fn from_bytes(bytes: &[u8; 20]) -> Self {
-        let result = UsbIpHeaderBasic {
-            command: u32::from_be_bytes(bytes[0..4].try_into().unwrap()),
-            seqnum: u32::from_be_bytes(bytes[4..8].try_into().unwrap()),
-            devid: u32::from_be_bytes(bytes[8..12].try_into().unwrap()),
-            direction: u32::from_be_bytes(bytes[12..16].try_into().unwrap()),
-            ep: u32::from_be_bytes(bytes[16..20].try_into().unwrap()),
-        };
-        // The direction should be 0 or 1
-        debug_assert!(result.direction & 1 == result.direction);
-        result
-    }
-
-    /// Converts the [UsbIpHeaderBasic] into a byte array.
-    pub fn to_bytes(&self) -> [u8; 20] {
-        let mut result = [0u8; 20];
-        result[0..4].copy_from_slice(&self.command.to_be_bytes());
-        result[4..8].copy_from_slice(&self.seqnum.to_be_bytes());
-        result[8..12].copy_from_slice(&self.devid.to_be_bytes());
-        result[12..16].copy_from_slice(&self.direction.to_be_bytes());
-        result[16..20].copy_from_slice(&self.ep.to_be_bytes());
-        result
-    }
-
     pub(crate) async fn read_from_socket_with_command<T: AsyncReadExt + Unpin>(
         socket: &mut T,
         command: u16,
@@ -111,34 +49,6 @@ impl UsbIpHeaderBasic {
     }
 }
 
-/// Client side commands from the Virtual Host Controller
-#[derive(Debug, Clone, PartialEq, Eq)]
-#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
-pub enum UsbIpCommand {
-    OpReqDevlist {
-        status: u32,
-    },
-    OpReqImport {
-        status: u32,
-        busid: [u8; 32],
-    },
-    UsbIpCmdSubmit {
-        header: UsbIpHeaderBasic,
-        transfer_flags: u32,
-        transfer_buffer_length: u32,
-        start_frame: u32,
-        number_of_packets: u32,
-        interval: u32,
-        setup: [u8; 8],
-        data: Vec<u8>,
-        iso_packet_descriptor: Vec<u8>,
-    },
-    UsbIpCmdUnlink {
-        header: UsbIpHeaderBasic,
-        unlink_seqnum: u32,
-    },
-}
-
 impl UsbIpCommand {
     /// Constructs a [UsbIpCommand] from a socket
     ///
@@ -148,10 +58,10 @@ impl UsbIpCommand {
         let version: u16 = socket.read_u16().await?;
 
         if version != 0 && version != USBIP_VERSION {
-            return Err(std::io::Error::new(
-                std::io::ErrorKind::Other,
-                format!("Unknown version: {:#04X}", version),
-            ));
+            return Err(std::io::Error::other(format!(
+                "Unknown version: {:#04X}",
+                version
+            )));
         }
 
         let command: u16 = socket.read_u16().await?;
@@ -242,69 +152,10 @@ impl UsbIpCommand {
                     unlink_seqnum,
                 })
             }
-            _ => Err(std::io::Error::new(
-                std::io::ErrorKind::Other,
-                format!("Unknown command: {:#04X}", command),
-            )),
-        }
-    }
-
-    /// Converts the [UsbIpCommand] into a byte vector
-    pub fn to_bytes(&self) -> Vec<u8> {
-        match *self {
-            UsbIpCommand::OpReqDevlist { status } => {
-                let mut result = Vec::with_capacity(8);
-                result.extend_from_slice(&USBIP_VERSION.to_be_bytes());
-                result.extend_from_slice(&OP_REQ_DEVLIST.to_be_bytes());
-                result.extend_from_slice(&status.to_be_bytes());
-                result
-            }
-            UsbIpCommand::OpReqImport { status, busid } => {
-                let mut result = Vec::with_capacity(40);
-                result.extend_from_slice(&USBIP_VERSION.to_be_bytes());
-                result.extend_from_slice(&OP_REQ_IMPORT.to_be_bytes());
-                result.extend_from_slice(&status.to_be_bytes());
-                result.extend_from_slice(&busid);
-                result
-            }
-            UsbIpCommand::UsbIpCmdSubmit {
-                ref header,
-                transfer_flags,
-                transfer_buffer_length,
-                start_frame,
-                number_of_packets,
-                interval,
-                setup,
-                ref data,
-                ref iso_packet_descriptor,
-            } => {
-                debug_assert!(
-                    header.direction != Direction::Out as u32
-                        || transfer_buffer_length == data.len() as u32
-                );
-
-                let mut result = Vec::with_capacity(48 + data.len() + iso_packet_descriptor.len());
-                result.extend_from_slice(&header.to_bytes());
-                result.extend_from_slice(&transfer_flags.to_be_bytes());
-                result.extend_from_slice(&transfer_buffer_length.to_be_bytes());
-                result.extend_from_slice(&start_frame.to_be_bytes());
-                result.extend_from_slice(&number_of_packets.to_be_bytes());
-                result.extend_from_slice(&interval.to_be_bytes());
-                result.extend_from_slice(&setup);
-                result.extend_from_slice(data);
-                result.extend_from_slice(iso_packet_descriptor);
-                result
-            }
-            UsbIpCommand::UsbIpCmdUnlink {
-                ref header,
-                unlink_seqnum,
-            } => {
-                let mut result = Vec::with_capacity(48);
-                result.extend_from_slice(&header.to_bytes());
-                result.extend_from_slice(&unlink_seqnum.to_be_bytes());
-                result.extend_from_slice(&[0; 24]);
-                result
-            }
+            _ => Err(std::io::Error::other(format!(
+                "Unknown command: {:#04X}",
+                command
+            ))),
         }
     }
 }
@@ -339,39 +190,57 @@ pub enum UsbIpResponse {
 }
 
 impl UsbIpResponse {
+    /// Length of the encoded response, in bytes
+    pub fn encoded_len(&self) -> usize {
+        match self {
+            Self::OpRepDevlist { devices, .. } => {
+                12 + devices.len() * 312
+                    + devices
+                        .iter()
+                        .map(|d| d.interfaces.len() * 4)
+                        .sum::<usize>()
+            }
+            Self::OpRepImport { device, .. } => 8 + device.as_ref().map_or(0, |_| 312),
+            Self::UsbIpRetSubmit {
+                transfer_buffer,
+                iso_packet_descriptor,
+                ..
+            } => 48 + transfer_buffer.len() + iso_packet_descriptor.len(),
+            Self::UsbIpRetUnlink { .. } => 48,
+        }
+    }
+
     /// Converts the [UsbIpResponse] into a byte vector
     pub fn to_bytes(&self) -> Vec<u8> {
+        let mut result = Vec::with_capacity(self.encoded_len());
+        self.encode(&mut result);
+        result
+    }
+
+    /// Appends the [UsbIpResponse] to `buf`, without allocating if it has
+    /// room for it
+    pub fn encode<B: BufMut>(&self, buf: &mut B) {
         match *self {
             Self::OpRepDevlist {
                 status,
                 device_count,
                 ref devices,
             } => {
-                let mut result = Vec::with_capacity(
-                    12 + devices.len() * 312
-                        + devices
-                            .iter()
-                            .map(|d| d.interfaces.len() * 4)
-                            .sum::<usize>(),
-                );
-                result.extend_from_slice(&USBIP_VERSION.to_be_bytes());
-                result.extend_from_slice(&OP_REP_DEVLIST.to_be_bytes());
-                result.extend_from_slice(&status.to_be_bytes());
-                result.extend_from_slice(&device_count.to_be_bytes());
+                buf.put_u16(USBIP_VERSION);
+                buf.put_u16(OP_REP_DEVLIST);
+                buf.put_u32(status);
+                buf.put_u32(device_count);
                 for dev in devices {
-                    result.extend_from_slice(&dev.to_bytes_with_interfaces());
+                    buf.put_slice(&dev.to_bytes_with_interfaces());
                 }
-                result
             }
             Self::OpRepImport { status, ref device } => {
-                let mut result = Vec::with_capacity(320);
-                result.extend_from_slice(&USBIP_VERSION.to_be_bytes());
-                result.extend_from_slice(&OP_REP_IMPORT.to_be_bytes());
-                result.extend_from_slice(&status.to_be_bytes());
+                buf.put_u16(USBIP_VERSION);
+                buf.put_u16(OP_REP_IMPORT);
+                buf.put_u32(status);
                 if let Some(device) = device {
-                    result.extend_from_slice(&device.to_bytes());
+                    buf.put_slice(&device.to_bytes());
                 }
-                result
             }
             Self::UsbIpRetSubmit {
                 ref header,
@@ -383,36 +252,30 @@ impl UsbIpResponse {
                 ref transfer_buffer,
                 ref iso_packet_descriptor,
             } => {
-                let mut result =
-                    Vec::with_capacity(48 + transfer_buffer.len() + iso_packet_descriptor.len());
-
-                debug_assert!(header.command == USBIP_RET_SUBMIT.into());
+                debug_assert!(header.command == u32::from(USBIP_RET_SUBMIT));
                 debug_assert!(if header.direction == Direction::In as u32 {
                     actual_length == transfer_buffer.len() as u32
                 } else {
                     actual_length == 0
                 });
 
-                result.extend_from_slice(&header.to_bytes());
-                result.extend_from_slice(&status.to_be_bytes());
-                result.extend_from_slice(&actual_length.to_be_bytes());
-                result.extend_from_slice(&start_frame.to_be_bytes());
-                result.extend_from_slice(&number_of_packets.to_be_bytes());
-                result.extend_from_slice(&error_count.to_be_bytes());
-                result.extend_from_slice(&[0; 8]);
-                result.extend_from_slice(transfer_buffer);
-                result.extend_from_slice(iso_packet_descriptor);
-                result
+                let mut head = [0; 48];
+                head[..20].copy_from_slice(&header.to_bytes());
+                head[20..24].copy_from_slice(&status.to_be_bytes());
+                head[24..28].copy_from_slice(&actual_length.to_be_bytes());
+                head[28..32].copy_from_slice(&start_frame.to_be_bytes());
+                head[32..36].copy_from_slice(&number_of_packets.to_be_bytes());
+                head[36..40].copy_from_slice(&error_count.to_be_bytes());
+                buf.put_slice(&head);
+                buf.put_slice(transfer_buffer);
+                buf.put_slice(iso_packet_descriptor);
             }
             Self::UsbIpRetUnlink { ref header, status } => {
-                let mut result = Vec::with_capacity(48);
-
-                debug_assert!(header.command == USBIP_RET_UNLINK.into());
+                debug_assert!(header.command == u32::from(USBIP_RET_UNLINK));
 
-                result.extend_from_slice(&header.to_bytes());
-                result.extend_from_slice(&status.to_be_bytes());
-                result.extend_from_slice(&[0; 24]);
-                result
+                buf.put_slice(&header.to_bytes());
+                buf.put_u32(status);
+                buf.put_bytes(0, 24);
             }
         }
     }
@@ -446,6 +309,15 @@ impl UsbIpResponse {
         }
     }
 
+    /// Constructs an OP_REP_IMPORT response for a device another client
+    /// imported, ST_DEV_BUSY
+    pub fn op_rep_import_busy() -> Self {
+        Self::OpRepImport {
+            status: OP_REP_IMPORT_BUSY,
+            device: None,
+        }
+    }
+
     /// Constructs a successful OP_REP_IMPORT response
     pub fn usbip_ret_submit_success(
         header: &UsbIpHeaderBasic,
@@ -466,6 +338,43 @@ impl UsbIpResponse {
         }
     }
 
+    /// Constructs a USBIP_RET_SUBMIT response for an isochronous URB
+    ///
+    /// For IN transfers `transfer_buffer` holds the received data of each
+    /// packet back to back, without the gaps between packets.
+    pub fn usbip_ret_submit_iso(
+        header: &UsbIpHeaderBasic,
+        start_frame: u32,
+        transfer_buffer: Vec<u8>,
+        packets: &[IsoPacketDescriptor],
+    ) -> Self {
+        Self::UsbIpRetSubmit {
+            header: header.clone(),
+            status: 0,
+            actual_length: transfer_buffer.len() as u32,
+            start_frame,
+            number_of_packets: packets.len() as u32,
+            error_count: packets.iter().filter(|p| p.status != 0).count() as u32,
+            transfer_buffer,
+            iso_packet_descriptor: IsoPacketDescriptor::encode_all(packets),
+        }
+    }
+
+    /// Constructs a USBIP_RET_SUBMIT response for a URB that failed with
+    /// `status`, a negated errno, see [crate::UrbError::status]
+    pub fn usbip_ret_submit_error(header: &UsbIpHeaderBasic, status: i32) -> Self {
+        Self::UsbIpRetSubmit {
+            header: header.clone(),
+            status: status as u32,
+            actual_length: 0,
+            start_frame: 0,
+            number_of_packets: 0,
+            error_count: 0,
+            transfer_buffer: vec![],
+            iso_packet_descriptor: vec![],
+        }
+    }
+
     /// Constructs a failed OP_REP_IMPORT response
     pub fn usbip_ret_submit_fail(header: &UsbIpHeaderBasic) -> Self {
         Self::UsbIpRetSubmit {
@@ -488,6 +397,15 @@ impl UsbIpResponse {
         }
     }
 
+    /// Constructs a USBIP_RET_UNLINK response for a URB that was cancelled
+    /// before it completed, so it gets no USBIP_RET_SUBMIT
+    pub fn usbip_ret_unlink_cancelled(header: &UsbIpHeaderBasic) -> Self {
+        Self::UsbIpRetUnlink {
+            header: header.clone(),
+            status: (-ECONNRESET) as u32,
+        }
+    }
+
     /// Constructs a failed OP_REP_IMPORT response.
     pub fn usbip_ret_unlink_fail(header: &UsbIpHeaderBasic) -> Self {
         Self::UsbIpRetUnlink {
@@ -500,6 +418,7 @@ impl UsbIpResponse {
 #[cfg(test)]
 mod tests {
     use crate::util::tests::*;
+    use crate::wire::WireError;
 
     use super::*;
 
@@ -623,10 +542,10 @@ mod tests {
     fn byte_serialize_op_rep_devlist() {
         setup_test_logger();
         let device = example_device();
-        let res = UsbIpResponse::op_rep_devlist(&[device.clone()]);
+        let res = UsbIpResponse::op_rep_devlist(std::slice::from_ref(&device));
         assert_eq!(
             res.to_bytes(),
-            vec![
+            [
                 vec![0x01, 0x11],             // version
                 vec![0x00, 0x05],             // command
                 vec![0x00, 0x00, 0x00, 0x00], // status
@@ -645,7 +564,7 @@ mod tests {
         let res = UsbIpResponse::op_rep_import_success(&device);
         assert_eq!(
             res.to_bytes(),
-            vec![
+            [
                 vec![0x01, 0x11],             // version
                 vec![0x00, 0x03],             // command
                 vec![0x00, 0x00, 0x00, 0x00], // status
@@ -773,6 +692,60 @@ mod tests {
         assert_eq!(res.to_bytes(), expected_result,);
     }
 
+    #[test]
+    fn decode_replies() {
+        setup_test_logger();
+        let device = example_device();
+        let bytes = UsbIpResponse::op_rep_devlist(std::slice::from_ref(&device)).to_bytes();
+        let (reply, len) = UsbIpReply::decode(&bytes, |_| false).unwrap();
+        assert_eq!(len, bytes.len());
+        let UsbIpReply::OpRepDevlist { status: 0, devices } = reply else {
+            panic!("unexpected reply {:?}", reply);
+        };
+        assert_eq!(devices.len(), 1);
+        assert_eq!(devices[0].bus_id, device.bus_id);
+        assert_eq!(devices[0].path, device.path);
+        assert_eq!(devices[0].vendor_id, device.vendor_id);
+        assert_eq!(devices[0].interfaces.len(), device.interfaces.len());
+
+        let bytes = UsbIpResponse::op_rep_import_fail().to_bytes();
+        assert_eq!(
+            UsbIpReply::decode(&bytes, |_| false),
+            Ok((
+                UsbIpReply::OpRepImport {
+                    status: 1,
+                    device: None
+                },
+                bytes.len()
+            ))
+        );
+
+        let header = UsbIpHeaderBasic {
+            command: USBIP_RET_SUBMIT.into(),
+            seqnum: 2,
+            devid: 3,
+            direction: Direction::In as u32,
+            ep: 4,
+        };
+        let bytes = UsbIpResponse::usbip_ret_submit_success(&header, 0, 0, vec![1, 2, 3], vec![])
+            .to_bytes();
+        let (reply, _) = UsbIpReply::decode(&bytes, |seqnum| seqnum == 2).unwrap();
+        let UsbIpReply::UsbIpRetSubmit {
+            status: 0,
+            actual_length: 3,
+            transfer_buffer,
+            ..
+        } = reply
+        else {
+            panic!("unexpected reply {:?}", reply);
+        };
+        assert_eq!(transfer_buffer, [1, 2, 3]);
+        assert_eq!(
+            UsbIpReply::decode(&bytes[..50], |_| true),
+            Err(WireError::Truncated { needed: 1 })
+        );
+    }
+
     #[tokio::test]
     async fn read_op_req_devlist_from_socket() -> Result<()> {
         setup_test_logger();
@@ -65,6 +65,8 @@ pub(crate) mod tests {
         }
     }
 
+    impl crate::Transport for MockSocket {}
+
     pub(crate) async fn get_free_address() -> SocketAddr {
         let stream = TcpListener::bind("127.0.0.1:0").await.unwrap();
         stream.local_addr().unwrap()
@@ -78,6 +80,20 @@ pub(crate) mod tests {
         }
     }
 
+    /// Writes to a buffer the test reads afterwards
+    #[derive(Clone, Default)]
+    pub(crate) struct SharedBuffer(pub std::sync::Arc<std::sync::Mutex<Vec<u8>>>);
+
+    impl Write for SharedBuffer {
+        fn write(&mut self, buf: &[u8]) -> Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> Result<()> {
+            Ok(())
+        }
+    }
+
     pub(crate) fn setup_test_logger() {
         let _ = env_logger::builder().is_test(true).try_init();
     }
@@ -0,0 +1,612 @@
+//! WebUSB style client for devices imported over USB/IP
+//!
+//! [WebUsbBridge] implements the client side of USB/IP without doing any IO:
+//! requests return the bytes to send, and [WebUsbBridge::receive] consumes
+//! whatever bytes arrived. The same code can therefore drive a TCP socket
+//! natively, or a WebSocket-to-TCP relay when compiled to wasm32 for a browser.
+//! Transfers follow the shape of the WebUSB API (`controlTransferIn`,
+//! `transferOut`, `"ok"`/`"stall"`/`"babble"`), so a web console written
+//! against `navigator.usb` can talk to the CCID interface of a remote YubiKey.
+//!
+//! Like [super::wire], this module only depends on `core` and `alloc`.
+
+use alloc::collections::BTreeMap;
+use alloc::vec::Vec;
+use core::fmt;
+
+use super::wire::{
+    ConfigurationDescriptor, EndpointDescriptor, ExportedDevice, InterfaceDescriptor, SetupPacket,
+    UsbIpCommand, UsbIpHeaderBasic, UsbIpReply, WireError, USBIP_CMD_SUBMIT, USBIP_CMD_UNLINK,
+};
+
+/// `-EPIPE`, reported by the host when an endpoint stalls
+const EPIPE: i32 = 32;
+/// `-EOVERFLOW`, reported by the host when a device sends more than requested
+const EOVERFLOW: i32 = 75;
+
+/// `bmRequestType` type bits, mirrors `USBRequestType`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RequestType {
+    Standard = 0,
+    Class = 1,
+    Vendor = 2,
+}
+
+/// `bmRequestType` recipient bits, mirrors `USBRecipient`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Recipient {
+    Device = 0,
+    Interface = 1,
+    Endpoint = 2,
+    Other = 3,
+}
+
+/// Mirrors `USBControlTransferParameters`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ControlTransferParameters {
+    pub request_type: RequestType,
+    pub recipient: Recipient,
+    pub request: u8,
+    pub value: u16,
+    pub index: u16,
+}
+
+impl ControlTransferParameters {
+    fn setup(&self, device_to_host: bool, length: u16) -> SetupPacket {
+        SetupPacket {
+            request_type: (device_to_host as u8) << 7
+                | (self.request_type as u8) << 5
+                | self.recipient as u8,
+            request: self.request,
+            value: self.value,
+            index: self.index,
+            length,
+        }
+    }
+}
+
+/// Mirrors `USBTransferStatus`, plus errors WebUSB would raise as exceptions
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TransferStatus {
+    Ok,
+    Stall,
+    Babble,
+    /// Any other negated errno reported by the server
+    Error(i32),
+}
+
+impl TransferStatus {
+    fn from_status(status: i32) -> Self {
+        // Servers send negative errnos; `i32::MIN` has no positive counterpart.
+        match status.checked_neg() {
+            Some(0) => TransferStatus::Ok,
+            Some(EPIPE) => TransferStatus::Stall,
+            Some(EOVERFLOW) => TransferStatus::Babble,
+            _ => TransferStatus::Error(status),
+        }
+    }
+}
+
+/// Completion of an operation started on a [WebUsbBridge]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BridgeEvent {
+    /// Reply to [WebUsbBridge::request_devices]
+    Devices(Vec<ExportedDevice>),
+    /// Reply to [WebUsbBridge::open], transfers may be started from now on
+    Opened(ExportedDevice),
+    /// The server refused to export the device
+    OpenFailed { status: u32 },
+    /// Mirrors `USBInTransferResult`
+    TransferIn {
+        id: u32,
+        status: TransferStatus,
+        data: Vec<u8>,
+    },
+    /// Mirrors `USBOutTransferResult`
+    TransferOut {
+        id: u32,
+        status: TransferStatus,
+        bytes_written: u32,
+    },
+    /// Reply to [WebUsbBridge::cancel], `unlinked` is false if the transfer
+    /// had already completed
+    Cancelled { id: u32, unlinked: bool },
+}
+
+/// Errors of a [WebUsbBridge]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BridgeError {
+    /// The server sent bytes that are not valid USB/IP
+    Wire(WireError),
+    /// A transfer was started before the device was opened
+    NotOpened,
+    /// The server replied to something that was never requested
+    Unexpected,
+}
+
+impl fmt::Display for BridgeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BridgeError::Wire(err) => write!(f, "{}", err),
+            BridgeError::NotOpened => write!(f, "Device is not opened"),
+            BridgeError::Unexpected => write!(f, "Unexpected reply"),
+        }
+    }
+}
+
+impl From<WireError> for BridgeError {
+    fn from(err: WireError) -> Self {
+        BridgeError::Wire(err)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Pending {
+    Devices,
+    Open,
+    In,
+    Out,
+    Unlink(u32),
+}
+
+/// Sans-IO USB/IP client exposing one imported device through WebUSB style calls
+///
+/// Every transfer method returns an id identifying the matching
+/// [BridgeEvent], along with the bytes to send to the server.
+#[derive(Debug, Default)]
+pub struct WebUsbBridge {
+    devid: Option<u32>,
+    next_seqnum: u32,
+    pending: BTreeMap<u32, Pending>,
+    op: Option<Pending>,
+    input: Vec<u8>,
+}
+
+impl WebUsbBridge {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Whether [BridgeEvent::Opened] has been received
+    pub fn is_opened(&self) -> bool {
+        self.devid.is_some()
+    }
+
+    /// Lists the devices exported by the server
+    ///
+    /// Servers usually close the connection after the reply, so the device
+    /// should be opened on a new connection with a fresh bridge.
+    pub fn request_devices(&mut self) -> Vec<u8> {
+        self.op = Some(Pending::Devices);
+        UsbIpCommand::OpReqDevlist { status: 0 }.to_bytes()
+    }
+
+    /// Imports the device with the given bus id
+    pub fn open(&mut self, bus_id: &str) -> Vec<u8> {
+        let mut busid = [0; 32];
+        let len = bus_id.len().min(busid.len() - 1);
+        busid[..len].copy_from_slice(&bus_id.as_bytes()[..len]);
+        self.op = Some(Pending::Open);
+        UsbIpCommand::OpReqImport { status: 0, busid }.to_bytes()
+    }
+
+    /// Mirrors `controlTransferIn`
+    pub fn control_transfer_in(
+        &mut self,
+        setup: ControlTransferParameters,
+        length: u16,
+    ) -> Result<(u32, Vec<u8>), BridgeError> {
+        let setup = setup.setup(true, length);
+        self.submit(0, Pending::In, setup.to_bytes(), Vec::new(), length.into())
+    }
+
+    /// Mirrors `controlTransferOut`
+    pub fn control_transfer_out(
+        &mut self,
+        setup: ControlTransferParameters,
+        data: &[u8],
+    ) -> Result<(u32, Vec<u8>), BridgeError> {
+        let setup = setup.setup(false, data.len() as u16);
+        self.submit(
+            0,
+            Pending::Out,
+            setup.to_bytes(),
+            data.to_vec(),
+            data.len() as u32,
+        )
+    }
+
+    /// Mirrors `transferIn`, `endpoint_number` excludes the direction bit
+    pub fn transfer_in(
+        &mut self,
+        endpoint_number: u8,
+        length: u32,
+    ) -> Result<(u32, Vec<u8>), BridgeError> {
+        self.submit(endpoint_number, Pending::In, [0; 8], Vec::new(), length)
+    }
+
+    /// Mirrors `transferOut`, `endpoint_number` excludes the direction bit
+    pub fn transfer_out(
+        &mut self,
+        endpoint_number: u8,
+        data: &[u8],
+    ) -> Result<(u32, Vec<u8>), BridgeError> {
+        self.submit(
+            endpoint_number,
+            Pending::Out,
+            [0; 8],
+            data.to_vec(),
+            data.len() as u32,
+        )
+    }
+
+    /// Cancels a pending transfer, e.g. an interrupt IN waiting for a touch
+    pub fn cancel(&mut self, id: u32) -> Result<Vec<u8>, BridgeError> {
+        let devid = self.devid.ok_or(BridgeError::NotOpened)?;
+        let seqnum = self.next_seqnum();
+        self.pending.insert(seqnum, Pending::Unlink(id));
+        Ok(UsbIpCommand::UsbIpCmdUnlink {
+            header: UsbIpHeaderBasic {
+                command: USBIP_CMD_UNLINK.into(),
+                seqnum,
+                devid,
+                direction: 0,
+                ep: 0,
+            },
+            unlink_seqnum: id,
+        }
+        .to_bytes())
+    }
+
+    /// Feeds bytes received from the server
+    ///
+    /// Partial replies are buffered until the rest arrives.
+    pub fn receive(&mut self, bytes: &[u8]) -> Result<Vec<BridgeEvent>, BridgeError> {
+        self.input.extend_from_slice(bytes);
+        let mut events = Vec::new();
+        let mut consumed = 0;
+        loop {
+            let pending = &self.pending;
+            let reply = UsbIpReply::decode(&self.input[consumed..], |seqnum| {
+                pending.get(&seqnum) == Some(&Pending::In)
+            });
+            match reply {
+                Ok((reply, len)) => {
+                    consumed += len;
+                    events.push(self.complete(reply)?);
+                }
+                Err(WireError::Truncated { .. }) => break,
+                Err(err) => return Err(err.into()),
+            }
+        }
+        self.input.drain(..consumed);
+        Ok(events)
+    }
+
+    fn next_seqnum(&mut self) -> u32 {
+        self.next_seqnum = self.next_seqnum.wrapping_add(1);
+        self.next_seqnum
+    }
+
+    fn submit(
+        &mut self,
+        ep: u8,
+        kind: Pending,
+        setup: [u8; 8],
+        data: Vec<u8>,
+        length: u32,
+    ) -> Result<(u32, Vec<u8>), BridgeError> {
+        let devid = self.devid.ok_or(BridgeError::NotOpened)?;
+        let seqnum = self.next_seqnum();
+        self.pending.insert(seqnum, kind);
+        let cmd = UsbIpCommand::UsbIpCmdSubmit {
+            header: UsbIpHeaderBasic {
+                command: USBIP_CMD_SUBMIT.into(),
+                seqnum,
+                devid,
+                direction: (kind == Pending::In) as u32,
+                ep: ep.into(),
+            },
+            transfer_flags: 0,
+            transfer_buffer_length: length,
+            start_frame: 0,
+            number_of_packets: 0,
+            interval: 0,
+            setup,
+            data,
+            iso_packet_descriptor: Vec::new(),
+        };
+        Ok((seqnum, cmd.to_bytes()))
+    }
+
+    fn complete(&mut self, reply: UsbIpReply) -> Result<BridgeEvent, BridgeError> {
+        Ok(match reply {
+            UsbIpReply::OpRepDevlist { devices, .. } => {
+                if self.op.take() != Some(Pending::Devices) {
+                    return Err(BridgeError::Unexpected);
+                }
+                BridgeEvent::Devices(devices)
+            }
+            UsbIpReply::OpRepImport { status, device } => {
+                if self.op.take() != Some(Pending::Open) {
+                    return Err(BridgeError::Unexpected);
+                }
+                match device {
+                    Some(device) => {
+                        self.devid = Some(device.bus_num << 16 | device.dev_num);
+                        BridgeEvent::Opened(device)
+                    }
+                    None => BridgeEvent::OpenFailed { status },
+                }
+            }
+            UsbIpReply::UsbIpRetSubmit {
+                header,
+                status,
+                actual_length,
+                transfer_buffer,
+                ..
+            } => {
+                let id = header.seqnum;
+                let status = TransferStatus::from_status(status);
+                match self.pending.remove(&id) {
+                    Some(Pending::In) => BridgeEvent::TransferIn {
+                        id,
+                        status,
+                        data: transfer_buffer,
+                    },
+                    Some(Pending::Out) => BridgeEvent::TransferOut {
+                        id,
+                        status,
+                        bytes_written: actual_length,
+                    },
+                    _ => return Err(BridgeError::Unexpected),
+                }
+            }
+            UsbIpReply::UsbIpRetUnlink { header, status } => {
+                let Some(Pending::Unlink(id)) = self.pending.remove(&header.seqnum) else {
+                    return Err(BridgeError::Unexpected);
+                };
+                // The unlinked URB never gets a RET_SUBMIT
+                let unlinked = status != 0;
+                if unlinked {
+                    self.pending.remove(&id);
+                }
+                BridgeEvent::Cancelled { id, unlinked }
+            }
+        })
+    }
+}
+
+/// bInterfaceClass of smart card (CCID) interfaces
+pub const CCID_CLASS: u8 = 0x0B;
+
+/// Endpoint numbers of a CCID interface, for use with `claimInterface` and
+/// the transfer methods of [WebUsbBridge]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CcidEndpoints {
+    pub interface_number: u8,
+    pub bulk_out: u8,
+    pub bulk_in: u8,
+    pub interrupt_in: Option<u8>,
+}
+
+impl CcidEndpoints {
+    /// Finds the CCID interface in a full configuration descriptor, as returned
+    /// by `GET_DESCRIPTOR(CONFIGURATION)` with wLength set to wTotalLength
+    pub fn find(configuration: &[u8]) -> Option<Self> {
+        let header = ConfigurationDescriptor::from_bytes(configuration).ok()?;
+        let total = configuration.len().min(header.total_length.into());
+        let mut offset = ConfigurationDescriptor::LENGTH;
+        let mut res: Option<CcidEndpoints> = None;
+        let mut in_ccid = false;
+        while offset + 1 < total {
+            let desc = &configuration[offset..total];
+            let len = desc[0] as usize;
+            if len < 2 || len > desc.len() {
+                return None;
+            }
+            if let Ok(intf) = InterfaceDescriptor::from_bytes(&desc[..len]) {
+                if res.is_some() {
+                    break;
+                }
+                in_ccid = intf.interface_class == CCID_CLASS;
+                if in_ccid {
+                    res = Some(CcidEndpoints {
+                        interface_number: intf.interface_number,
+                        bulk_out: 0,
+                        bulk_in: 0,
+                        interrupt_in: None,
+                    });
+                }
+            } else if let (true, Some(ccid), Ok(ep)) = (
+                in_ccid,
+                res.as_mut(),
+                EndpointDescriptor::from_bytes(&desc[..len]),
+            ) {
+                let number = ep.address & 0x0F;
+                match (ep.address & 0x80 != 0, ep.attributes & 0x03) {
+                    (false, 0x02) => ccid.bulk_out = number,
+                    (true, 0x02) => ccid.bulk_in = number,
+                    (true, 0x03) => ccid.interrupt_in = Some(number),
+                    _ => {}
+                }
+            }
+            offset += len;
+        }
+        res.filter(|ccid| ccid.bulk_in != 0 && ccid.bulk_out != 0)
+    }
+}
+
+/// Builds a `PC_to_RDR_XfrBlock` message carrying `apdu`
+pub fn ccid_xfr_block(slot: u8, seq: u8, apdu: &[u8]) -> Vec<u8> {
+    let mut res = Vec::with_capacity(10 + apdu.len());
+    res.push(0x6F);
+    res.extend_from_slice(&(apdu.len() as u32).to_le_bytes());
+    res.extend_from_slice(&[slot, seq, 0, 0, 0]);
+    res.extend_from_slice(apdu);
+    res
+}
+
+/// Extracts the response APDU from a `RDR_to_PC_DataBlock` message
+///
+/// Returns `None` if the message is not a complete data block for `seq`, or
+/// if the reader asks for more time (bStatus time extension); in that case the
+/// caller should issue another bulk IN transfer.
+pub fn ccid_data_block(seq: u8, message: &[u8]) -> Option<&[u8]> {
+    if message.len() < 10 || message[0] != 0x80 || message[6] != seq {
+        return None;
+    }
+    // bmCommandStatus: 0 processed, 1 failed, 2 time extension
+    if message[7] >> 6 != 0 {
+        return None;
+    }
+    let len = u32::from_le_bytes(message[1..5].try_into().unwrap()) as usize;
+    message.get(10..10 + len)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::wire::{UsbIpCommand, OP_REP_IMPORT, USBIP_RET_SUBMIT, USBIP_VERSION};
+    use alloc::vec;
+
+    fn opened() -> WebUsbBridge {
+        let mut bridge = WebUsbBridge::new();
+        bridge.open("1-1");
+        let mut reply = vec![];
+        reply.extend_from_slice(&USBIP_VERSION.to_be_bytes());
+        reply.extend_from_slice(&OP_REP_IMPORT.to_be_bytes());
+        reply.extend_from_slice(&[0; 4]);
+        let mut device = vec![0; 0x138];
+        device[256..259].copy_from_slice(b"1-1");
+        device[291] = 1; // bus_num
+        device[295] = 2; // dev_num
+        reply.extend_from_slice(&device);
+
+        // Split the reply to exercise buffering
+        assert_eq!(bridge.receive(&reply[..100]), Ok(vec![]));
+        let events = bridge.receive(&reply[100..]).unwrap();
+        assert!(matches!(&events[..], [BridgeEvent::Opened(d)] if d.bus_id == "1-1"));
+        bridge
+    }
+
+    fn ret_submit(seqnum: u32, status: i32, actual_length: u32, data: &[u8]) -> Vec<u8> {
+        let mut res = vec![];
+        res.extend_from_slice(&u32::from(USBIP_RET_SUBMIT).to_be_bytes());
+        res.extend_from_slice(&seqnum.to_be_bytes());
+        res.extend_from_slice(&[0; 12]);
+        res.extend_from_slice(&status.to_be_bytes());
+        res.extend_from_slice(&actual_length.to_be_bytes());
+        res.extend_from_slice(&[0; 20]);
+        res.extend_from_slice(data);
+        res
+    }
+
+    #[test]
+    fn transfers() {
+        let mut bridge = WebUsbBridge::new();
+        assert_eq!(bridge.transfer_in(1, 64), Err(BridgeError::NotOpened));
+
+        let mut bridge = opened();
+        let (id, bytes) = bridge
+            .control_transfer_in(
+                ControlTransferParameters {
+                    request_type: RequestType::Standard,
+                    recipient: Recipient::Device,
+                    request: 0x06,
+                    value: 0x0100,
+                    index: 0,
+                },
+                18,
+            )
+            .unwrap();
+        let Ok((UsbIpCommand::UsbIpCmdSubmit { header, setup, .. }, _)) =
+            UsbIpCommand::decode(&bytes)
+        else {
+            panic!("not a submit");
+        };
+        assert_eq!(header.devid, 1 << 16 | 2);
+        assert_eq!(header.direction, 1);
+        assert_eq!(setup, [0x80, 0x06, 0x00, 0x01, 0x00, 0x00, 0x12, 0x00]);
+
+        let (out_id, _) = bridge.transfer_out(2, &[1, 2, 3]).unwrap();
+        let events = bridge
+            .receive(
+                &[
+                    ret_submit(out_id, -EPIPE, 0, &[]),
+                    ret_submit(id, 0, 2, &[0x12, 0x01]),
+                ]
+                .concat(),
+            )
+            .unwrap();
+        assert_eq!(
+            events,
+            [
+                BridgeEvent::TransferOut {
+                    id: out_id,
+                    status: TransferStatus::Stall,
+                    bytes_written: 0
+                },
+                BridgeEvent::TransferIn {
+                    id,
+                    status: TransferStatus::Ok,
+                    data: vec![0x12, 0x01]
+                }
+            ]
+        );
+        assert_eq!(
+            bridge.receive(&ret_submit(id, 0, 0, &[])),
+            Err(BridgeError::Unexpected)
+        );
+
+        // A status with no positive errno is just an error
+        let mut bridge = opened();
+        let (out_id, _) = bridge.transfer_out(2, &[1]).unwrap();
+        assert_eq!(
+            bridge.receive(&ret_submit(out_id, i32::MIN, 0, &[])),
+            Ok(vec![BridgeEvent::TransferOut {
+                id: out_id,
+                status: TransferStatus::Error(i32::MIN),
+                bytes_written: 0
+            }])
+        );
+    }
+
+    #[test]
+    fn find_ccid() {
+        let config = [
+            vec![0x09, 0x02, 48, 0x00, 0x02, 0x01, 0x00, 0x80, 0x32],
+            // HID interface with an interrupt endpoint
+            vec![0x09, 0x04, 0x00, 0x00, 0x01, 0x03, 0x00, 0x00, 0x00],
+            vec![0x07, 0x05, 0x83, 0x03, 0x40, 0x00, 0x0A],
+            // CCID interface with bulk endpoints
+            vec![0x09, 0x04, 0x01, 0x00, 0x02, 0x0B, 0x00, 0x00, 0x00],
+            vec![0x07, 0x05, 0x02, 0x02, 0x40, 0x00, 0x00],
+            vec![0x07, 0x05, 0x82, 0x02, 0x40, 0x00, 0x00],
+        ]
+        .concat();
+        assert_eq!(
+            CcidEndpoints::find(&config),
+            Some(CcidEndpoints {
+                interface_number: 1,
+                bulk_out: 2,
+                bulk_in: 2,
+                interrupt_in: None,
+            })
+        );
+        assert_eq!(CcidEndpoints::find(&config[..30]), None);
+    }
+
+    #[test]
+    fn ccid_framing() {
+        let msg = ccid_xfr_block(0, 7, &[0x00, 0xA4]);
+        assert_eq!(msg, [0x6F, 2, 0, 0, 0, 0, 7, 0, 0, 0, 0x00, 0xA4]);
+
+        let reply = [0x80, 2, 0, 0, 0, 0, 7, 0, 0, 0, 0x90, 0x00];
+        assert_eq!(ccid_data_block(7, &reply), Some(&[0x90, 0x00][..]));
+        assert_eq!(ccid_data_block(8, &reply), None);
+        let mut waiting = reply;
+        waiting[7] = 0x80;
+        assert_eq!(ccid_data_block(7, &waiting), None);
+    }
+}
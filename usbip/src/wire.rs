@@ -0,0 +1,1004 @@
+//! Wire format of USB/IP and USB descriptors
+//!
+//! Everything in this module only depends on `core` and `alloc`, so it can be
+//! reused on targets without `std` (e.g. an embedded USB/IP gateway) by copying
+//! or path-including this file. Encoding and decoding work on byte slices and
+//! do not depend on serde or any async runtime.
+
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::fmt;
+
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+
+/// Errors produced while decoding wire-format data
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WireError {
+    /// More bytes are needed to decode the item
+    Truncated { needed: usize },
+    /// The USB/IP version is not supported
+    UnknownVersion(u16),
+    /// The USB/IP command code is not known
+    UnknownCommand(u16),
+    /// A descriptor has an unexpected length or type
+    InvalidDescriptor,
+    /// A length field describes more bytes than this platform can address
+    TooLong,
+}
+
+impl fmt::Display for WireError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            WireError::Truncated { needed } => write!(f, "Truncated: {} more bytes needed", needed),
+            WireError::UnknownVersion(version) => write!(f, "Unknown version: {:#04X}", version),
+            WireError::UnknownCommand(command) => write!(f, "Unknown command: {:#04X}", command),
+            WireError::InvalidDescriptor => write!(f, "Invalid descriptor"),
+            WireError::TooLong => write!(f, "Length out of range"),
+        }
+    }
+}
+
+/// A cursor over a byte slice reading big endian integers
+struct Reader<'a> {
+    bytes: &'a [u8],
+    offset: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn new(bytes: &'a [u8]) -> Self {
+        Self { bytes, offset: 0 }
+    }
+
+    fn take(&mut self, len: usize) -> Result<&'a [u8], WireError> {
+        let end = self.offset.checked_add(len).ok_or(WireError::TooLong)?;
+        if end > self.bytes.len() {
+            return Err(WireError::Truncated {
+                needed: end - self.bytes.len(),
+            });
+        }
+        let res = &self.bytes[self.offset..end];
+        self.offset = end;
+        Ok(res)
+    }
+
+    fn u16(&mut self) -> Result<u16, WireError> {
+        Ok(u16::from_be_bytes(self.take(2)?.try_into().unwrap()))
+    }
+
+    fn u32(&mut self) -> Result<u32, WireError> {
+        Ok(u32::from_be_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    fn array<const N: usize>(&mut self) -> Result<[u8; N], WireError> {
+        Ok(self.take(N)?.try_into().unwrap())
+    }
+}
+
+/// The length of `count` items of `size` bytes, which overflows `usize` on 16 and
+/// 32-bit targets such as wasm32 for untrusted counts
+fn byte_length(count: u32, size: usize) -> Result<usize, WireError> {
+    usize::try_from(count)
+        .ok()
+        .and_then(|count| count.checked_mul(size))
+        .ok_or(WireError::TooLong)
+}
+
+/// USB/IP protocol version
+///
+/// This is currently the only supported version of USB/IP
+/// for this library.
+pub const USBIP_VERSION: u16 = 0x0111;
+
+/// Command code: Retrieve the list of exported USB devices
+pub const OP_REQ_DEVLIST: u16 = 0x8005;
+/// Command code: import a remote USB device
+pub const OP_REQ_IMPORT: u16 = 0x8003;
+/// Reply code: The list of exported USB devices
+pub const OP_REP_DEVLIST: u16 = 0x0005;
+/// Reply code: Reply to import
+pub const OP_REP_IMPORT: u16 = 0x0003;
+/// Status of a reply to import: another client imported the device
+/// (ST_DEV_BUSY)
+pub const OP_REP_IMPORT_BUSY: u32 = 2;
+
+/// Command code: Submit an URB
+pub const USBIP_CMD_SUBMIT: u16 = 0x0001;
+/// Command code: Unlink an URB
+pub const USBIP_CMD_UNLINK: u16 = 0x0002;
+/// Reply code: Reply for submitting an URB
+pub const USBIP_RET_SUBMIT: u16 = 0x0003;
+/// Reply code: Reply for URB unlink
+pub const USBIP_RET_UNLINK: u16 = 0x0004;
+
+/// USB/IP direction
+///
+/// NOTE: Must not be confused with rusb::Direction,
+/// which has the opposite enum values. This is only for
+/// internal use in the USB/IP protocol.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Direction {
+    Out = 0,
+    In = 1,
+}
+
+/// Common header for all context sensitive packets
+///
+/// All commands/responses which rely on a device being attached
+/// to a client use this header.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct UsbIpHeaderBasic {
+    pub command: u32,
+    pub seqnum: u32,
+    pub devid: u32,
+    pub direction: u32,
+    pub ep: u32,
+}
+
+impl UsbIpHeaderBasic {
+    /// Converts a byte array into a [UsbIpHeaderBasic].
+    pub fn from_bytes(bytes: &[u8; 20]) -> Self {
+        let result = UsbIpHeaderBasic {
+            command: u32::from_be_bytes(bytes[0..4].try_into().unwrap()),
+            seqnum: u32::from_be_bytes(bytes[4..8].try_into().unwrap()),
+            devid: u32::from_be_bytes(bytes[8..12].try_into().unwrap()),
+            direction: u32::from_be_bytes(bytes[12..16].try_into().unwrap()),
+            ep: u32::from_be_bytes(bytes[16..20].try_into().unwrap()),
+        };
+        // The direction should be 0 or 1
+        debug_assert!(result.direction & 1 == result.direction);
+        result
+    }
+
+    /// Converts the [UsbIpHeaderBasic] into a byte array.
+    pub fn to_bytes(&self) -> [u8; 20] {
+        let mut result = [0u8; 20];
+        result[0..4].copy_from_slice(&self.command.to_be_bytes());
+        result[4..8].copy_from_slice(&self.seqnum.to_be_bytes());
+        result[8..12].copy_from_slice(&self.devid.to_be_bytes());
+        result[12..16].copy_from_slice(&self.direction.to_be_bytes());
+        result[16..20].copy_from_slice(&self.ep.to_be_bytes());
+        result
+    }
+}
+
+/// Client side commands from the Virtual Host Controller
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum UsbIpCommand {
+    OpReqDevlist {
+        status: u32,
+    },
+    OpReqImport {
+        status: u32,
+        busid: [u8; 32],
+    },
+    UsbIpCmdSubmit {
+        header: UsbIpHeaderBasic,
+        transfer_flags: u32,
+        transfer_buffer_length: u32,
+        start_frame: u32,
+        number_of_packets: u32,
+        interval: u32,
+        setup: [u8; 8],
+        data: Vec<u8>,
+        iso_packet_descriptor: Vec<u8>,
+    },
+    UsbIpCmdUnlink {
+        header: UsbIpHeaderBasic,
+        unlink_seqnum: u32,
+    },
+}
+
+impl UsbIpCommand {
+    /// Converts the [UsbIpCommand] into a byte vector
+    pub fn to_bytes(&self) -> Vec<u8> {
+        match *self {
+            UsbIpCommand::OpReqDevlist { status } => {
+                let mut result = Vec::with_capacity(8);
+                result.extend_from_slice(&USBIP_VERSION.to_be_bytes());
+                result.extend_from_slice(&OP_REQ_DEVLIST.to_be_bytes());
+                result.extend_from_slice(&status.to_be_bytes());
+                result
+            }
+            UsbIpCommand::OpReqImport { status, busid } => {
+                let mut result = Vec::with_capacity(40);
+                result.extend_from_slice(&USBIP_VERSION.to_be_bytes());
+                result.extend_from_slice(&OP_REQ_IMPORT.to_be_bytes());
+                result.extend_from_slice(&status.to_be_bytes());
+                result.extend_from_slice(&busid);
+                result
+            }
+            UsbIpCommand::UsbIpCmdSubmit {
+                ref header,
+                transfer_flags,
+                transfer_buffer_length,
+                start_frame,
+                number_of_packets,
+                interval,
+                setup,
+                ref data,
+                ref iso_packet_descriptor,
+            } => {
+                debug_assert!(
+                    header.direction != Direction::Out as u32
+                        || transfer_buffer_length == data.len() as u32
+                );
+
+                let mut result = Vec::with_capacity(48 + data.len() + iso_packet_descriptor.len());
+                result.extend_from_slice(&header.to_bytes());
+                result.extend_from_slice(&transfer_flags.to_be_bytes());
+                result.extend_from_slice(&transfer_buffer_length.to_be_bytes());
+                result.extend_from_slice(&start_frame.to_be_bytes());
+                result.extend_from_slice(&number_of_packets.to_be_bytes());
+                result.extend_from_slice(&interval.to_be_bytes());
+                result.extend_from_slice(&setup);
+                result.extend_from_slice(data);
+                result.extend_from_slice(iso_packet_descriptor);
+                result
+            }
+            UsbIpCommand::UsbIpCmdUnlink {
+                ref header,
+                unlink_seqnum,
+            } => {
+                let mut result = Vec::with_capacity(48);
+                result.extend_from_slice(&header.to_bytes());
+                result.extend_from_slice(&unlink_seqnum.to_be_bytes());
+                result.extend_from_slice(&[0; 24]);
+                result
+            }
+        }
+    }
+}
+
+impl UsbIpCommand {
+    /// Decodes a [UsbIpCommand] from the start of `bytes`
+    ///
+    /// Returns the command along with the number of bytes it occupied. If `bytes`
+    /// does not yet hold a complete command, [WireError::Truncated] tells how many
+    /// more bytes are required at least.
+    pub fn decode(bytes: &[u8]) -> Result<(UsbIpCommand, usize), WireError> {
+        let mut r = Reader::new(bytes);
+        let version = r.u16()?;
+        let command = r.u16()?;
+
+        let res = match (version, command) {
+            (USBIP_VERSION, OP_REQ_DEVLIST) => UsbIpCommand::OpReqDevlist { status: r.u32()? },
+            (USBIP_VERSION, OP_REQ_IMPORT) => UsbIpCommand::OpReqImport {
+                status: r.u32()?,
+                busid: r.array()?,
+            },
+            (USBIP_VERSION, _) => return Err(WireError::UnknownCommand(command)),
+            // USBIP_CMD_* start with a 32 bit command, so the "version" is zero.
+            (0, USBIP_CMD_SUBMIT) => {
+                let header = UsbIpHeaderBasic::decode_with_command(&mut r, command)?;
+                let transfer_flags = r.u32()?;
+                let transfer_buffer_length = r.u32()?;
+                let start_frame = r.u32()?;
+                let number_of_packets = r.u32()?;
+                let interval = r.u32()?;
+                let setup = r.array()?;
+                let data = if header.direction == Direction::In as u32 {
+                    Vec::new()
+                } else {
+                    r.take(byte_length(transfer_buffer_length, 1)?)?.to_vec()
+                };
+                let iso_packet_descriptor =
+                    if number_of_packets != 0 && number_of_packets != 0xFFFFFFFF {
+                        r.take(byte_length(number_of_packets, IsoPacketDescriptor::LENGTH)?)?
+                            .to_vec()
+                    } else {
+                        Vec::new()
+                    };
+                UsbIpCommand::UsbIpCmdSubmit {
+                    header,
+                    transfer_flags,
+                    transfer_buffer_length,
+                    start_frame,
+                    number_of_packets,
+                    interval,
+                    setup,
+                    data,
+                    iso_packet_descriptor,
+                }
+            }
+            (0, USBIP_CMD_UNLINK) => {
+                let header = UsbIpHeaderBasic::decode_with_command(&mut r, command)?;
+                let unlink_seqnum = r.u32()?;
+                r.take(24)?;
+                UsbIpCommand::UsbIpCmdUnlink {
+                    header,
+                    unlink_seqnum,
+                }
+            }
+            (0, _) => return Err(WireError::UnknownCommand(command)),
+            _ => return Err(WireError::UnknownVersion(version)),
+        };
+        Ok((res, r.offset))
+    }
+}
+
+impl UsbIpHeaderBasic {
+    fn decode_with_command(r: &mut Reader, command: u16) -> Result<Self, WireError> {
+        Ok(UsbIpHeaderBasic {
+            command: command.into(),
+            seqnum: r.u32()?,
+            devid: r.u32()?,
+            direction: r.u32()?,
+            ep: r.u32()?,
+        })
+    }
+}
+
+/// Device record of `OP_REP_DEVLIST` and `OP_REP_IMPORT` as seen by a client
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct ExportedDevice {
+    pub path: String,
+    pub bus_id: String,
+    pub bus_num: u32,
+    pub dev_num: u32,
+    pub speed: u32,
+    pub vendor_id: u16,
+    pub product_id: u16,
+    pub device_bcd: u16,
+    pub device_class: u8,
+    pub device_subclass: u8,
+    pub device_protocol: u8,
+    pub configuration_value: u8,
+    pub num_configurations: u8,
+    pub num_interfaces: u8,
+    /// Only present in `OP_REP_DEVLIST`
+    pub interfaces: Vec<ExportedInterface>,
+}
+
+/// Interface record following an [ExportedDevice] in `OP_REP_DEVLIST`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct ExportedInterface {
+    pub interface_class: u8,
+    pub interface_subclass: u8,
+    pub interface_protocol: u8,
+}
+
+impl ExportedDevice {
+    fn decode(r: &mut Reader, with_interfaces: bool) -> Result<Self, WireError> {
+        fn c_string(bytes: &[u8]) -> String {
+            let len = bytes.iter().position(|b| *b == 0).unwrap_or(bytes.len());
+            String::from_utf8_lossy(&bytes[..len]).into()
+        }
+
+        let mut res = ExportedDevice {
+            path: c_string(r.take(256)?),
+            bus_id: c_string(r.take(32)?),
+            bus_num: r.u32()?,
+            dev_num: r.u32()?,
+            speed: r.u32()?,
+            vendor_id: r.u16()?,
+            product_id: r.u16()?,
+            device_bcd: r.u16()?,
+            ..Default::default()
+        };
+        let [class, subclass, protocol, configuration_value, num_configurations, num_interfaces] =
+            r.array()?;
+        res.device_class = class;
+        res.device_subclass = subclass;
+        res.device_protocol = protocol;
+        res.configuration_value = configuration_value;
+        res.num_configurations = num_configurations;
+        res.num_interfaces = num_interfaces;
+        if with_interfaces {
+            for _ in 0..num_interfaces {
+                let [interface_class, interface_subclass, interface_protocol, _padding] =
+                    r.array()?;
+                res.interfaces.push(ExportedInterface {
+                    interface_class,
+                    interface_subclass,
+                    interface_protocol,
+                });
+            }
+        }
+        Ok(res)
+    }
+}
+
+/// Server side replies, decoded by a client
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum UsbIpReply {
+    OpRepDevlist {
+        status: u32,
+        devices: Vec<ExportedDevice>,
+    },
+    OpRepImport {
+        status: u32,
+        /// `None` if the import failed
+        device: Option<ExportedDevice>,
+    },
+    UsbIpRetSubmit {
+        header: UsbIpHeaderBasic,
+        /// Zero on success, a negated errno otherwise
+        status: i32,
+        actual_length: u32,
+        start_frame: u32,
+        number_of_packets: u32,
+        error_count: u32,
+        transfer_buffer: Vec<u8>,
+        iso_packet_descriptor: Vec<u8>,
+    },
+    UsbIpRetUnlink {
+        header: UsbIpHeaderBasic,
+        status: i32,
+    },
+}
+
+impl UsbIpReply {
+    /// Decodes a [UsbIpReply] from the start of `bytes`
+    ///
+    /// `USBIP_RET_SUBMIT` only carries a transfer buffer for IN transfers, and
+    /// servers are not required to echo the direction, so `is_in` is asked for
+    /// the direction of the URB with the given seqnum.
+    pub fn decode(
+        bytes: &[u8],
+        is_in: impl Fn(u32) -> bool,
+    ) -> Result<(UsbIpReply, usize), WireError> {
+        let mut r = Reader::new(bytes);
+        let version = r.u16()?;
+        let command = r.u16()?;
+
+        let res = match (version, command) {
+            (USBIP_VERSION, OP_REP_DEVLIST) => {
+                let status = r.u32()?;
+                let count = r.u32()?;
+                let mut devices = Vec::new();
+                for _ in 0..count {
+                    devices.push(ExportedDevice::decode(&mut r, true)?);
+                }
+                UsbIpReply::OpRepDevlist { status, devices }
+            }
+            (USBIP_VERSION, OP_REP_IMPORT) => {
+                let status = r.u32()?;
+                let device = if status == 0 {
+                    Some(ExportedDevice::decode(&mut r, false)?)
+                } else {
+                    None
+                };
+                UsbIpReply::OpRepImport { status, device }
+            }
+            (USBIP_VERSION, _) => return Err(WireError::UnknownCommand(command)),
+            (0, USBIP_RET_SUBMIT) => {
+                let header = UsbIpHeaderBasic::decode_with_command(&mut r, command)?;
+                let status = r.u32()? as i32;
+                let actual_length = r.u32()?;
+                let start_frame = r.u32()?;
+                let number_of_packets = r.u32()?;
+                let error_count = r.u32()?;
+                r.take(8)?;
+                let transfer_buffer = if is_in(header.seqnum) {
+                    r.take(byte_length(actual_length, 1)?)?.to_vec()
+                } else {
+                    Vec::new()
+                };
+                let iso_packet_descriptor =
+                    if number_of_packets != 0 && number_of_packets != 0xFFFFFFFF {
+                        r.take(byte_length(number_of_packets, IsoPacketDescriptor::LENGTH)?)?
+                            .to_vec()
+                    } else {
+                        Vec::new()
+                    };
+                UsbIpReply::UsbIpRetSubmit {
+                    header,
+                    status,
+                    actual_length,
+                    start_frame,
+                    number_of_packets,
+                    error_count,
+                    transfer_buffer,
+                    iso_packet_descriptor,
+                }
+            }
+            (0, USBIP_RET_UNLINK) => {
+                let header = UsbIpHeaderBasic::decode_with_command(&mut r, command)?;
+                let status = r.u32()? as i32;
+                r.take(24)?;
+                UsbIpReply::UsbIpRetUnlink { header, status }
+            }
+            (0, _) => return Err(WireError::UnknownCommand(command)),
+            _ => return Err(WireError::UnknownVersion(version)),
+        };
+        Ok((res, r.offset))
+    }
+}
+
+/// One packet of an isochronous URB, `struct usbip_iso_packet_descriptor`
+///
+/// `USBIP_CMD_SUBMIT` and `USBIP_RET_SUBMIT` of isochronous transfers are
+/// followed by `number_of_packets` of these. `offset` and `length` locate the
+/// packet in the transfer buffer; the reply fills in `actual_length` and
+/// `status` (zero, or a negated errno).
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct IsoPacketDescriptor {
+    pub offset: u32,
+    pub length: u32,
+    pub actual_length: u32,
+    pub status: i32,
+}
+
+impl IsoPacketDescriptor {
+    pub const LENGTH: usize = 16;
+
+    pub fn to_bytes(&self) -> [u8; Self::LENGTH] {
+        let mut result = [0u8; Self::LENGTH];
+        result[0..4].copy_from_slice(&self.offset.to_be_bytes());
+        result[4..8].copy_from_slice(&self.length.to_be_bytes());
+        result[8..12].copy_from_slice(&self.actual_length.to_be_bytes());
+        result[12..16].copy_from_slice(&self.status.to_be_bytes());
+        result
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, WireError> {
+        let mut r = Reader::new(bytes);
+        Ok(Self {
+            offset: r.u32()?,
+            length: r.u32()?,
+            actual_length: r.u32()?,
+            status: r.u32()? as i32,
+        })
+    }
+
+    /// Decodes the `iso_packet_descriptor` field of a submit command or reply
+    pub fn decode_all(bytes: &[u8]) -> Result<Vec<Self>, WireError> {
+        let partial = bytes.len() % Self::LENGTH;
+        if partial != 0 {
+            return Err(WireError::Truncated {
+                needed: Self::LENGTH - partial,
+            });
+        }
+        bytes.chunks(Self::LENGTH).map(Self::from_bytes).collect()
+    }
+
+    /// Encodes `packets` as the `iso_packet_descriptor` field of a submit command or reply
+    pub fn encode_all(packets: &[Self]) -> Vec<u8> {
+        packets.iter().flat_map(|p| p.to_bytes()).collect()
+    }
+}
+
+/// Parse the SETUP packet of control transfers
+#[derive(Clone, Copy, Debug, Default)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct SetupPacket {
+    /// bmRequestType
+    pub request_type: u8,
+    /// bRequest
+    pub request: u8,
+    /// wValue
+    pub value: u16,
+    /// wIndex
+    pub index: u16,
+    /// wLength
+    pub length: u16,
+}
+
+impl SetupPacket {
+    /// Parse a [SetupPacket] from raw setup packet
+    pub fn parse(setup: &[u8; 8]) -> SetupPacket {
+        SetupPacket {
+            request_type: setup[0],
+            request: setup[1],
+            value: (setup[3] as u16) << 8 | (setup[2] as u16),
+            index: (setup[5] as u16) << 8 | (setup[4] as u16),
+            length: (setup[7] as u16) << 8 | (setup[6] as u16),
+        }
+    }
+
+    /// Converts the [SetupPacket] into its 8 byte encoding
+    pub fn to_bytes(&self) -> [u8; 8] {
+        let value = self.value.to_le_bytes();
+        let index = self.index.to_le_bytes();
+        let length = self.length.to_le_bytes();
+        [
+            self.request_type,
+            self.request,
+            value[0],
+            value[1],
+            index[0],
+            index[1],
+            length[0],
+            length[1],
+        ]
+    }
+}
+
+/// bDescriptorType of a device descriptor
+const DEVICE_DESCRIPTOR: u8 = 0x01;
+/// bDescriptorType of a configuration descriptor
+const CONFIGURATION_DESCRIPTOR: u8 = 0x02;
+/// bDescriptorType of an interface descriptor
+const INTERFACE_DESCRIPTOR: u8 = 0x04;
+/// bDescriptorType of an endpoint descriptor
+const ENDPOINT_DESCRIPTOR: u8 = 0x05;
+
+fn check_descriptor<const N: usize>(bytes: &[u8], kind: u8) -> Result<[u8; N], WireError> {
+    if bytes.len() < N {
+        return Err(WireError::Truncated {
+            needed: N - bytes.len(),
+        });
+    }
+    if bytes[0] as usize != N || bytes[1] != kind {
+        return Err(WireError::InvalidDescriptor);
+    }
+    Ok(bytes[..N].try_into().unwrap())
+}
+
+/// Standard device descriptor, USB 2.0 Table 9-8
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct DeviceDescriptor {
+    pub usb_version: u16,
+    pub device_class: u8,
+    pub device_subclass: u8,
+    pub device_protocol: u8,
+    pub max_packet_size0: u8,
+    pub vendor_id: u16,
+    pub product_id: u16,
+    pub device_version: u16,
+    pub manufacturer_string: u8,
+    pub product_string: u8,
+    pub serial_string: u8,
+    pub num_configurations: u8,
+}
+
+impl DeviceDescriptor {
+    pub const LENGTH: usize = 0x12;
+
+    pub fn to_bytes(&self) -> [u8; Self::LENGTH] {
+        let usb = self.usb_version.to_le_bytes();
+        let vid = self.vendor_id.to_le_bytes();
+        let pid = self.product_id.to_le_bytes();
+        let bcd = self.device_version.to_le_bytes();
+        [
+            Self::LENGTH as u8,
+            DEVICE_DESCRIPTOR,
+            usb[0],
+            usb[1],
+            self.device_class,
+            self.device_subclass,
+            self.device_protocol,
+            self.max_packet_size0,
+            vid[0],
+            vid[1],
+            pid[0],
+            pid[1],
+            bcd[0],
+            bcd[1],
+            self.manufacturer_string,
+            self.product_string,
+            self.serial_string,
+            self.num_configurations,
+        ]
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, WireError> {
+        let b = check_descriptor::<{ Self::LENGTH }>(bytes, DEVICE_DESCRIPTOR)?;
+        Ok(Self {
+            usb_version: u16::from_le_bytes([b[2], b[3]]),
+            device_class: b[4],
+            device_subclass: b[5],
+            device_protocol: b[6],
+            max_packet_size0: b[7],
+            vendor_id: u16::from_le_bytes([b[8], b[9]]),
+            product_id: u16::from_le_bytes([b[10], b[11]]),
+            device_version: u16::from_le_bytes([b[12], b[13]]),
+            manufacturer_string: b[14],
+            product_string: b[15],
+            serial_string: b[16],
+            num_configurations: b[17],
+        })
+    }
+}
+
+/// Standard configuration descriptor header, USB 2.0 Table 9-10
+///
+/// `total_length` covers the interface, endpoint and class specific
+/// descriptors that follow it.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct ConfigurationDescriptor {
+    pub total_length: u16,
+    pub num_interfaces: u8,
+    pub configuration_value: u8,
+    pub configuration_string: u8,
+    pub attributes: u8,
+    pub max_power: u8,
+}
+
+impl ConfigurationDescriptor {
+    pub const LENGTH: usize = 0x09;
+
+    pub fn to_bytes(&self) -> [u8; Self::LENGTH] {
+        let total = self.total_length.to_le_bytes();
+        [
+            Self::LENGTH as u8,
+            CONFIGURATION_DESCRIPTOR,
+            total[0],
+            total[1],
+            self.num_interfaces,
+            self.configuration_value,
+            self.configuration_string,
+            self.attributes,
+            self.max_power,
+        ]
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, WireError> {
+        let b = check_descriptor::<{ Self::LENGTH }>(bytes, CONFIGURATION_DESCRIPTOR)?;
+        Ok(Self {
+            total_length: u16::from_le_bytes([b[2], b[3]]),
+            num_interfaces: b[4],
+            configuration_value: b[5],
+            configuration_string: b[6],
+            attributes: b[7],
+            max_power: b[8],
+        })
+    }
+}
+
+/// Standard interface descriptor, USB 2.0 Table 9-12
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct InterfaceDescriptor {
+    pub interface_number: u8,
+    pub alternate_setting: u8,
+    pub num_endpoints: u8,
+    pub interface_class: u8,
+    pub interface_subclass: u8,
+    pub interface_protocol: u8,
+    pub interface_string: u8,
+}
+
+impl InterfaceDescriptor {
+    pub const LENGTH: usize = 0x09;
+
+    pub fn to_bytes(&self) -> [u8; Self::LENGTH] {
+        [
+            Self::LENGTH as u8,
+            INTERFACE_DESCRIPTOR,
+            self.interface_number,
+            self.alternate_setting,
+            self.num_endpoints,
+            self.interface_class,
+            self.interface_subclass,
+            self.interface_protocol,
+            self.interface_string,
+        ]
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, WireError> {
+        let b = check_descriptor::<{ Self::LENGTH }>(bytes, INTERFACE_DESCRIPTOR)?;
+        Ok(Self {
+            interface_number: b[2],
+            alternate_setting: b[3],
+            num_endpoints: b[4],
+            interface_class: b[5],
+            interface_subclass: b[6],
+            interface_protocol: b[7],
+            interface_string: b[8],
+        })
+    }
+}
+
+/// Standard endpoint descriptor, USB 2.0 Table 9-13
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct EndpointDescriptor {
+    pub address: u8,
+    pub attributes: u8,
+    pub max_packet_size: u16,
+    pub interval: u8,
+}
+
+impl EndpointDescriptor {
+    pub const LENGTH: usize = 0x07;
+
+    pub fn to_bytes(&self) -> [u8; Self::LENGTH] {
+        let mps = self.max_packet_size.to_le_bytes();
+        [
+            Self::LENGTH as u8,
+            ENDPOINT_DESCRIPTOR,
+            self.address,
+            self.attributes,
+            mps[0],
+            mps[1],
+            self.interval,
+        ]
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, WireError> {
+        let b = check_descriptor::<{ Self::LENGTH }>(bytes, ENDPOINT_DESCRIPTOR)?;
+        Ok(Self {
+            address: b[2],
+            attributes: b[3],
+            max_packet_size: u16::from_le_bytes([b[4], b[5]]),
+            interval: b[6],
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec;
+
+    #[test]
+    fn decode_round_trip() {
+        let cmds = [
+            UsbIpCommand::OpReqDevlist { status: 0 },
+            UsbIpCommand::OpReqImport {
+                status: 0,
+                busid: [b'1'; 32],
+            },
+            UsbIpCommand::UsbIpCmdSubmit {
+                header: UsbIpHeaderBasic {
+                    command: USBIP_CMD_SUBMIT.into(),
+                    seqnum: 1,
+                    devid: 2,
+                    direction: Direction::Out as u32,
+                    ep: 3,
+                },
+                transfer_flags: 0,
+                transfer_buffer_length: 4,
+                start_frame: 0,
+                number_of_packets: 1,
+                interval: 0,
+                setup: [0; 8],
+                data: vec![1, 2, 3, 4],
+                iso_packet_descriptor: vec![0xFF; 16],
+            },
+            UsbIpCommand::UsbIpCmdUnlink {
+                header: UsbIpHeaderBasic {
+                    command: USBIP_CMD_UNLINK.into(),
+                    seqnum: 2,
+                    devid: 2,
+                    direction: 0,
+                    ep: 0,
+                },
+                unlink_seqnum: 1,
+            },
+        ];
+        for cmd in cmds {
+            let bytes = cmd.to_bytes();
+            assert_eq!(UsbIpCommand::decode(&bytes), Ok((cmd, bytes.len())));
+        }
+    }
+
+    #[test]
+    fn decode_truncated() {
+        let bytes = UsbIpCommand::OpReqImport {
+            status: 0,
+            busid: [0; 32],
+        }
+        .to_bytes();
+        assert_eq!(
+            UsbIpCommand::decode(&bytes[..30]),
+            Err(WireError::Truncated { needed: 10 })
+        );
+    }
+
+    #[test]
+    fn decode_unknown() {
+        assert_eq!(
+            UsbIpCommand::decode(&[0x01, 0x10, 0x80, 0x05, 0, 0, 0, 0]),
+            Err(WireError::UnknownVersion(0x0110))
+        );
+        assert_eq!(
+            UsbIpCommand::decode(&[0x01, 0x11, 0x10, 0x05, 0, 0, 0, 0]),
+            Err(WireError::UnknownCommand(0x1005))
+        );
+    }
+
+    #[test]
+    fn decode_huge_lengths() {
+        let mut bytes = UsbIpCommand::UsbIpCmdSubmit {
+            header: UsbIpHeaderBasic {
+                command: USBIP_CMD_SUBMIT.into(),
+                seqnum: 1,
+                devid: 2,
+                direction: Direction::Out as u32,
+                ep: 1,
+            },
+            transfer_flags: 0,
+            transfer_buffer_length: 0,
+            start_frame: 0,
+            number_of_packets: 0,
+            interval: 0,
+            setup: [0; 8],
+            data: vec![],
+            iso_packet_descriptor: vec![],
+        }
+        .to_bytes();
+        // number_of_packets = u32::MAX means "not isochronous"
+        bytes[32..36].copy_from_slice(&u32::MAX.to_be_bytes());
+        assert!(UsbIpCommand::decode(&bytes).is_ok());
+        // Any other huge count must fail cleanly, overflowing or not
+        bytes[32..36].copy_from_slice(&(u32::MAX - 1).to_be_bytes());
+        assert!(matches!(
+            UsbIpCommand::decode(&bytes),
+            Err(WireError::Truncated { .. } | WireError::TooLong)
+        ));
+        bytes[24..28].copy_from_slice(&u32::MAX.to_be_bytes());
+        assert!(UsbIpCommand::decode(&bytes).is_err());
+
+        assert_eq!(byte_length(u32::MAX, usize::MAX), Err(WireError::TooLong));
+        let mut r = Reader::new(&[0; 4]);
+        r.take(1).unwrap();
+        assert_eq!(r.take(usize::MAX), Err(WireError::TooLong));
+    }
+
+    #[test]
+    fn iso_packet_descriptor_round_trip() {
+        let packets = [
+            IsoPacketDescriptor {
+                offset: 0,
+                length: 192,
+                actual_length: 192,
+                status: 0,
+            },
+            IsoPacketDescriptor {
+                offset: 192,
+                length: 192,
+                actual_length: 0,
+                status: -18,
+            },
+        ];
+        let bytes = IsoPacketDescriptor::encode_all(&packets);
+        assert_eq!(bytes.len(), 32);
+        assert_eq!(bytes[28..32], [0xFF, 0xFF, 0xFF, 0xEE]);
+        assert_eq!(
+            IsoPacketDescriptor::decode_all(&bytes),
+            Ok(packets.to_vec())
+        );
+        assert_eq!(
+            IsoPacketDescriptor::decode_all(&bytes[..20]),
+            Err(WireError::Truncated { needed: 12 })
+        );
+    }
+
+    #[test]
+    fn setup_packet_round_trip() {
+        let bytes = [0x80, 0x06, 0x00, 0x01, 0x00, 0x00, 0x40, 0x00];
+        assert_eq!(SetupPacket::parse(&bytes).to_bytes(), bytes);
+    }
+
+    #[test]
+    fn descriptor_round_trip() {
+        let device = DeviceDescriptor {
+            usb_version: 0x0200,
+            vendor_id: 0x1050,
+            product_id: 0x0407,
+            num_configurations: 1,
+            ..Default::default()
+        };
+        assert_eq!(DeviceDescriptor::from_bytes(&device.to_bytes()), Ok(device));
+
+        let endpoint = EndpointDescriptor {
+            address: 0x81,
+            attributes: 0x03,
+            max_packet_size: 64,
+            interval: 10,
+        };
+        assert_eq!(
+            EndpointDescriptor::from_bytes(&endpoint.to_bytes()),
+            Ok(endpoint)
+        );
+        assert_eq!(
+            InterfaceDescriptor::from_bytes(&endpoint.to_bytes()),
+            Err(WireError::Truncated { needed: 2 })
+        );
+        assert_eq!(
+            ConfigurationDescriptor::from_bytes(&InterfaceDescriptor::default().to_bytes()),
+            Err(WireError::InvalidDescriptor)
+        );
+    }
+}
@@ -0,0 +1,20 @@
+//! `no_std` build of the USB/IP client protocol layer
+//!
+//! This crate compiles [wire] and [webusb] from the `usbip` crate without
+//! tokio or rusb, so they can be built for `wasm32-unknown-unknown`:
+//!
+//! ```sh
+//! cargo build --target wasm32-unknown-unknown --release
+//! ```
+//!
+//! Bindings to JavaScript (e.g. with `wasm-bindgen`) are left to the
+//! application, which forwards bytes between [webusb::WebUsbBridge] and a
+//! WebSocket relayed to the USB/IP server.
+#![cfg_attr(not(test), no_std)]
+
+extern crate alloc;
+
+#[path = "../../src/webusb.rs"]
+pub mod webusb;
+#[path = "../../src/wire.rs"]
+pub mod wire;
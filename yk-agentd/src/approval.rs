@@ -0,0 +1,72 @@
+//! Desktop prompts for the approval of key use
+//!
+//! With `--approval SECS`, key use waits for approval on the host, see
+//! `usbip::approval`. With `--approve-command CMD`, each request runs CMD in
+//! a shell, with `YK_APPROVAL_ID`, `YK_BUS_ID` and `YK_SERIAL` set, e.g. a
+//! `zenity --question` prompt: the request is approved if CMD exits with
+//! status 0, and denied otherwise. Requests can also be answered with
+//! `--approve ID` and `--deny ID`.
+use std::sync::Arc;
+use tokio::sync::broadcast::error::RecvError;
+use tracing::*;
+use usbip::events::Event;
+use usbip::UsbIpServer;
+
+/// Run `command` for each approval `server` asks for, and answer it
+pub(crate) async fn prompt(server: Arc<UsbIpServer>, command: String) {
+    let mut events = server.subscribe();
+    loop {
+        let (id, bus_id, serial) = match events.recv().await {
+            Ok(Event::ApprovalRequired { id, bus_id, serial }) => (id, bus_id, serial),
+            Ok(_) => continue,
+            Err(RecvError::Lagged(missed)) => {
+                warn!(
+                    "Missed {} events, approvals among them go unanswered",
+                    missed
+                );
+                continue;
+            }
+            Err(RecvError::Closed) => return,
+        };
+        let server = server.clone();
+        let mut command = crate::hooks::shell(&command);
+        command
+            .env("YK_APPROVAL_ID", id.to_string())
+            .env("YK_BUS_ID", &bus_id)
+            .env("YK_SERIAL", serial.as_deref().unwrap_or(""))
+            .kill_on_drop(true);
+        tokio::spawn(async move {
+            let approved = match command.status().await {
+                Ok(status) => status.success(),
+                Err(err) => {
+                    warn!("Running the approval command failed: {}", err);
+                    false
+                }
+            };
+            // Unless it timed out or was answered otherwise meanwhile
+            if let Err(err) = server.answer_approval(id, approved) {
+                debug!("Answering approval {} failed: {}", id, err);
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn prompts() {
+        let server = Arc::new(UsbIpServer::new_simulated(vec![]));
+        let command = if cfg!(windows) {
+            "exit 0"
+        } else {
+            "test \"$YK_APPROVAL_ID\" = 7"
+        };
+        let mut command = crate::hooks::shell(command);
+        command.env("YK_APPROVAL_ID", "7");
+        assert!(command.status().await.unwrap().success());
+        // Nothing to answer on a server that doesn't ask
+        assert!(server.answer_approval(7, true).is_err());
+    }
+}
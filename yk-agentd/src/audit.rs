@@ -0,0 +1,222 @@
+//! The audit log
+//!
+//! Every `usbip::audit::Record` is written as one JSON object per line, with
+//! the time and the kind of record as `event`:
+//!
+//! ```text
+//! {"bus_id":"1-2","connection":3,"event":"imported","serial":"12345678","time":"2026-01-02T03:04:05Z"}
+//! ```
+//!
+//! Tokens are never written, only whether the client sent one. Once the file
+//! would grow past its maximum size it is renamed to `FILE.1`, the previous
+//! `FILE.1` to `FILE.2` and so on, keeping a given number of old files.
+use serde_json::{json, Value};
+use std::fs::{File, OpenOptions};
+use std::io::{Result, Write};
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+use tokio::sync::mpsc;
+use tracing::*;
+use usbip::{acl::Peer, audit::Record};
+
+pub(crate) struct AuditLog {
+    path: PathBuf,
+    max_size: u64,
+    keep: usize,
+    file: File,
+    size: u64,
+}
+
+impl AuditLog {
+    /// Append to the log at `path`, rotating it past `max_size` bytes and
+    /// keeping `keep` old files
+    pub(crate) fn open(path: &Path, max_size: u64, keep: usize) -> Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        let size = file.metadata()?.len();
+        Ok(Self {
+            path: path.to_path_buf(),
+            max_size,
+            keep,
+            file,
+            size,
+        })
+    }
+
+    pub(crate) fn write(&mut self, record: &Record, time: SystemTime) -> Result<()> {
+        let mut line = to_json(record, time).to_string();
+        line.push('\n');
+        if self.size > 0 && self.size + line.len() as u64 > self.max_size {
+            self.rotate()?;
+        }
+        self.file.write_all(line.as_bytes())?;
+        self.size += line.len() as u64;
+        Ok(())
+    }
+
+    fn rotated(&self, n: usize) -> PathBuf {
+        let mut path = self.path.clone().into_os_string();
+        path.push(format!(".{}", n));
+        path.into()
+    }
+
+    fn rotate(&mut self) -> Result<()> {
+        if self.keep == 0 {
+            std::fs::remove_file(&self.path)?;
+        } else {
+            for n in (1..self.keep).rev() {
+                match std::fs::rename(self.rotated(n), self.rotated(n + 1)) {
+                    Err(err) if err.kind() != std::io::ErrorKind::NotFound => return Err(err),
+                    _ => (),
+                }
+            }
+            std::fs::rename(&self.path, self.rotated(1))?;
+        }
+        *self = Self::open(&self.path, self.max_size, self.keep)?;
+        Ok(())
+    }
+
+    /// Write the records sent to the returned sender on a thread of its own,
+    /// until every sender is dropped
+    pub(crate) fn spawn(mut self) -> mpsc::UnboundedSender<Record> {
+        let (records, mut rx) = mpsc::unbounded_channel();
+        std::thread::spawn(move || {
+            while let Some(record) = rx.blocking_recv() {
+                if let Err(err) = self.write(&record, SystemTime::now()) {
+                    error!("Writing the audit log failed: {}", err);
+                }
+            }
+        });
+        records
+    }
+}
+
+fn peer(peer: &Peer) -> Value {
+    json!({
+        "addr": peer.addr.map(|addr| addr.to_string()),
+        "uid": peer.uid,
+        "cert": peer.cert_fingerprint.map(|fingerprint| {
+            fingerprint.iter().map(|b| format!("{:02x}", b)).collect::<String>()
+        }),
+        "token": peer.token.is_some(),
+    })
+}
+
+fn to_json(record: &Record, time: SystemTime) -> Value {
+    let mut value = match record {
+        Record::Connected {
+            connection,
+            peer: p,
+        } => json!({"event": "connected", "connection": connection, "peer": peer(p)}),
+        Record::Imported {
+            connection,
+            bus_id,
+            serial,
+        } => json!({
+            "event": "imported",
+            "connection": connection,
+            "bus_id": bus_id,
+            "serial": serial,
+        }),
+        Record::ImportFailed {
+            connection,
+            bus_id,
+            busy,
+        } => json!({
+            "event": "import_failed",
+            "connection": connection,
+            "bus_id": bus_id,
+            "busy": busy,
+        }),
+        Record::TouchRequired { connection, bus_id } => {
+            json!({"event": "touch_required", "connection": connection, "bus_id": bus_id})
+        }
+        Record::PinFailed {
+            connection,
+            bus_id,
+            failures,
+        } => json!({
+            "event": "pin_failed",
+            "connection": connection,
+            "bus_id": bus_id,
+            "failures": failures,
+        }),
+        Record::Evicted {
+            connection,
+            bus_id,
+            reason,
+        } => json!({
+            "event": "evicted",
+            "connection": connection,
+            "bus_id": bus_id,
+            "reason": reason,
+        }),
+        Record::Released {
+            connection,
+            bus_id,
+            usage,
+        } => json!({
+            "event": "released",
+            "connection": connection,
+            "bus_id": bus_id,
+            "urbs": usage.urbs,
+            "failed_urbs": usage.failed_urbs,
+            "apdus": usage.apdus,
+        }),
+        Record::Disconnected { connection, error } => {
+            json!({"event": "disconnected", "connection": connection, "error": error})
+        }
+        Record::Admin {
+            peer: p,
+            request,
+            error,
+        } => json!({
+            "event": "admin",
+            "peer": peer(p),
+            "request": request,
+            "error": error,
+        }),
+    };
+    value["time"] = humantime::format_rfc3339_seconds(time).to_string().into();
+    value
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn write_and_rotate() {
+        let dir = std::env::temp_dir().join(format!("yk-agentd-audit-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("audit.jsonl");
+        let time = SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+
+        let connected = Record::Connected {
+            connection: 1,
+            peer: Peer {
+                addr: Some("192.0.2.1".parse().unwrap()),
+                token: Some("s3cret".to_string()),
+                ..Default::default()
+            },
+        };
+        let line = to_json(&connected, time).to_string();
+        assert_eq!(
+            line,
+            r#"{"connection":1,"event":"connected","peer":{"addr":"192.0.2.1","cert":null,"token":true,"uid":null},"time":"2023-11-14T22:13:20Z"}"#
+        );
+
+        // Two records fit in a file, and one old file is kept
+        let max_size = 2 * line.len() as u64 + 2;
+        let mut log = AuditLog::open(&path, max_size, 1).unwrap();
+        for _ in 0..5 {
+            log.write(&connected, time).unwrap();
+        }
+        let lines = |path: &Path| std::fs::read_to_string(path).unwrap().lines().count();
+        assert_eq!(lines(&path), 1);
+        assert_eq!(lines(&log.rotated(1)), 2);
+        assert!(!log.rotated(2).exists());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
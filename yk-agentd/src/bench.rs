@@ -0,0 +1,508 @@
+//! The `--bench BUSID` mode
+//!
+//! Imports a device from the daemon at `--listen` and reports attach time,
+//! control transfer round trips, CCID APDU round trips and PIV ECDH
+//! throughput. If a device with the same VID/PID is plugged in locally, the
+//! same transfers are also timed against it directly through libusb (with
+//! opening it standing in for attaching), so the two can be compared before
+//! deciding where to plug the YubiKeys in.
+use rusb::{Context, DeviceHandle, UsbContext};
+use std::io::{Error, ErrorKind, Result};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tracing::*;
+use usbip::local::{self, Address};
+use usbip::usbip_protocol::{UsbIpCommand, UsbIpHeaderBasic, USBIP_CMD_SUBMIT};
+use usbip::Transport;
+
+const CCID_CLASS: u8 = 0x0B;
+const CCID_HEADER_LEN: usize = 10;
+const PC_TO_RDR_ICC_POWER_ON: u8 = 0x62;
+const PC_TO_RDR_XFR_BLOCK: u8 = 0x6F;
+const RDR_TO_PC_DATA_BLOCK: u8 = 0x80;
+const TRANSFER_TIMEOUT: Duration = Duration::from_secs(1);
+/// How long we wait for a CCID response before giving up (covers touch waits).
+const CCID_DEADLINE: Duration = Duration::from_secs(30);
+
+const SELECT_PIV: [u8; 14] = [
+    0x00, 0xA4, 0x04, 0x00, 0x09, 0xA0, 0x00, 0x00, 0x03, 0x08, 0x00, 0x00, 0x10, 0x00,
+];
+
+/// The uncompressed SEC-1 encoding of the P-256 generator, used as ECDH peer point.
+const P256_GENERATOR: [u8; 65] = [
+    0x04, 0x6B, 0x17, 0xD1, 0xF2, 0xE1, 0x2C, 0x42, 0x47, 0xF8, 0xBC, 0xE6, 0xE5, 0x63, 0xA4, 0x40,
+    0xF2, 0x77, 0x03, 0x7D, 0x81, 0x2D, 0xEB, 0x33, 0xA0, 0xF4, 0xA1, 0x39, 0x45, 0xD8, 0x98, 0xC2,
+    0x96, 0x4F, 0xE3, 0x42, 0xE2, 0xFE, 0x1A, 0x7F, 0x9B, 0x8E, 0xE7, 0xEB, 0x4A, 0x7C, 0x0F, 0x9E,
+    0x16, 0x2B, 0xCE, 0x33, 0x57, 0x6B, 0x31, 0x5E, 0xCE, 0xCB, 0xB6, 0x40, 0x68, 0x37, 0xBF, 0x51,
+    0xF5,
+];
+
+/// What to measure
+#[derive(Debug)]
+pub(crate) struct Options {
+    /// Rounds of each measurement
+    pub iterations: usize,
+    /// PIV slot to perform ECDH with, if any
+    pub ecdh_slot: Option<u8>,
+    /// Time a single ECDH that waits for a touch instead
+    pub touch: bool,
+}
+
+/// An imported device on a remote USB/IP server
+struct RemoteDevice {
+    socket: Box<dyn Transport>,
+    seqnum: u32,
+    devid: u32,
+    vendor_id: u16,
+    product_id: u16,
+}
+
+impl RemoteDevice {
+    async fn attach(addr: &Address, busid: &str) -> Result<Self> {
+        // The bus id travels NUL-terminated in 32 bytes
+        let mut id = [0; 32];
+        if busid.len() >= id.len() {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!("bus id {} is longer than {} bytes", busid, id.len() - 1),
+            ));
+        }
+        id[..busid.len()].copy_from_slice(busid.as_bytes());
+        let mut socket = local::connect(addr).await?;
+        let req = UsbIpCommand::OpReqImport {
+            status: 0,
+            busid: id,
+        };
+        socket.write_all(&req.to_bytes()).await?;
+
+        // OP_REP_IMPORT: version, command, status, then the device
+        socket.read_u32().await?;
+        if socket.read_u32().await? != 0 {
+            return Err(Error::new(
+                ErrorKind::NotFound,
+                format!("remote refused to export {}", busid),
+            ));
+        }
+        let mut device = [0; 0x138];
+        socket.read_exact(&mut device).await?;
+        let bus_num = u32::from_be_bytes(device[288..292].try_into().unwrap());
+        let dev_num = u32::from_be_bytes(device[292..296].try_into().unwrap());
+
+        Ok(Self {
+            socket,
+            seqnum: 0,
+            devid: (bus_num << 16) | dev_num,
+            vendor_id: u16::from_be_bytes(device[300..302].try_into().unwrap()),
+            product_id: u16::from_be_bytes(device[302..304].try_into().unwrap()),
+        })
+    }
+
+    /// Submit a URB and wait for its USBIP_RET_SUBMIT
+    async fn submit(&mut self, ep: u8, setup: [u8; 8], data: Vec<u8>, len: u32) -> Result<Vec<u8>> {
+        self.seqnum += 1;
+        let direction = (ep >> 7) as u32;
+        let cmd = UsbIpCommand::UsbIpCmdSubmit {
+            header: UsbIpHeaderBasic {
+                command: USBIP_CMD_SUBMIT.into(),
+                seqnum: self.seqnum,
+                devid: self.devid,
+                direction,
+                ep: (ep & 0x7F) as u32,
+            },
+            transfer_flags: 0,
+            transfer_buffer_length: if direction == 0 {
+                data.len() as u32
+            } else {
+                len
+            },
+            start_frame: 0,
+            number_of_packets: 0,
+            interval: 0,
+            setup,
+            data,
+            iso_packet_descriptor: vec![],
+        };
+        self.socket.write_all(&cmd.to_bytes()).await?;
+
+        let mut header = [0; 48];
+        self.socket.read_exact(&mut header).await?;
+        let status = u32::from_be_bytes(header[20..24].try_into().unwrap());
+        let actual_length = u32::from_be_bytes(header[24..28].try_into().unwrap());
+        let mut buffer = vec![0; if direction == 1 { actual_length } else { 0 } as usize];
+        self.socket.read_exact(&mut buffer).await?;
+        if status != 0 {
+            return Err(Error::other(format!("URB failed with status {}", status)));
+        }
+        Ok(buffer)
+    }
+}
+
+/// Somewhere to send USB transfers: either over USB/IP, or straight to libusb
+enum Link {
+    Remote(RemoteDevice),
+    Local(DeviceHandle<Context>),
+}
+
+impl Link {
+    async fn control_in(&mut self, setup: [u8; 8]) -> Result<Vec<u8>> {
+        let length = u16::from_le_bytes([setup[6], setup[7]]);
+        match self {
+            Link::Remote(dev) => dev.submit(0x80, setup, vec![], length as u32).await,
+            Link::Local(handle) => {
+                let mut buffer = vec![0; length as usize];
+                let len = handle
+                    .read_control(
+                        setup[0],
+                        setup[1],
+                        u16::from_le_bytes([setup[2], setup[3]]),
+                        u16::from_le_bytes([setup[4], setup[5]]),
+                        &mut buffer,
+                        TRANSFER_TIMEOUT,
+                    )
+                    .map_err(Error::other)?;
+                buffer.truncate(len);
+                Ok(buffer)
+            }
+        }
+    }
+
+    async fn bulk_out(&mut self, ep: u8, data: Vec<u8>) -> Result<()> {
+        match self {
+            Link::Remote(dev) => dev.submit(ep, [0; 8], data, 0).await.map(|_| ()),
+            Link::Local(handle) => handle
+                .write_bulk(ep, &data, TRANSFER_TIMEOUT)
+                .map(|_| ())
+                .map_err(Error::other),
+        }
+    }
+
+    async fn bulk_in(&mut self, ep: u8, len: u32) -> Result<Vec<u8>> {
+        match self {
+            Link::Remote(dev) => dev.submit(ep, [0; 8], vec![], len).await,
+            Link::Local(handle) => {
+                let mut buffer = vec![0; len as usize];
+                match handle.read_bulk(ep, &mut buffer, TRANSFER_TIMEOUT) {
+                    Ok(len) => {
+                        buffer.truncate(len);
+                        Ok(buffer)
+                    }
+                    // Match the USB/IP host handler, which reports timeouts as empty reads.
+                    Err(rusb::Error::Timeout) => Ok(vec![]),
+                    Err(err) => Err(Error::other(err)),
+                }
+            }
+        }
+    }
+
+    fn get_descriptor(kind: u8, len: u16) -> [u8; 8] {
+        let len = len.to_le_bytes();
+        [0x80, 0x06, 0x00, kind, 0x00, 0x00, len[0], len[1]]
+    }
+
+    /// Find the bulk endpoints of the CCID interface, if the device has one
+    async fn find_ccid(&mut self) -> Result<Option<(u8, u8)>> {
+        let header = self.control_in(Self::get_descriptor(0x02, 9)).await?;
+        if header.len() < 4 {
+            return Ok(None);
+        }
+        let total = u16::from_le_bytes([header[2], header[3]]);
+        let desc = self.control_in(Self::get_descriptor(0x02, total)).await?;
+
+        let (mut number, mut class) = (0, 0);
+        let (mut ccid, mut bulk_in, mut bulk_out) = (None, None, None);
+        let mut offset = 0;
+        while offset + 1 < desc.len() && desc[offset] != 0 {
+            let d = &desc[offset..(offset + desc[offset] as usize).min(desc.len())];
+            match d[1] {
+                // interface
+                0x04 if d.len() >= 6 => {
+                    number = d[2];
+                    class = d[5];
+                }
+                // bulk endpoint
+                0x05 if d.len() >= 4 && class == CCID_CLASS && d[3] & 0x03 == 0x02 => {
+                    ccid = Some(number);
+                    if d[2] & 0x80 != 0 {
+                        bulk_in = Some(d[2]);
+                    } else {
+                        bulk_out = Some(d[2]);
+                    }
+                }
+                _ => (),
+            }
+            offset += d[0] as usize;
+        }
+
+        if let (Link::Local(handle), Some(number)) = (self, ccid) {
+            // The remote host claims interfaces itself, but locally we have to.
+            handle.set_auto_detach_kernel_driver(true).ok();
+            handle.claim_interface(number).map_err(Error::other)?;
+        }
+        Ok(bulk_in.zip(bulk_out))
+    }
+}
+
+/// A minimal CCID reader driver, enough to exchange APDUs with slot 0
+struct Ccid<'a> {
+    link: &'a mut Link,
+    bulk_in: u8,
+    bulk_out: u8,
+    seq: u8,
+}
+
+impl<'a> Ccid<'a> {
+    async fn command(&mut self, message_type: u8, data: &[u8]) -> Result<Vec<u8>> {
+        let mut msg = vec![message_type];
+        msg.extend_from_slice(&(data.len() as u32).to_le_bytes());
+        msg.extend_from_slice(&[0x00, self.seq, 0x00, 0x00, 0x00]);
+        msg.extend_from_slice(data);
+        self.seq = self.seq.wrapping_add(1);
+        self.link.bulk_out(self.bulk_out, msg).await?;
+
+        let deadline = Instant::now() + CCID_DEADLINE;
+        loop {
+            let resp = self.link.bulk_in(self.bulk_in, 65536).await?;
+            if resp.len() < CCID_HEADER_LEN {
+                // Timed out on the host side, keep polling.
+            } else if resp[7] & 0xC0 == 0x80 {
+                // Time extension, e.g. while waiting for a touch.
+            } else if resp[0] != RDR_TO_PC_DATA_BLOCK || resp[7] & 0x40 != 0 {
+                return Err(Error::other(format!(
+                    "CCID error: status {:#04x}, error {:#04x}",
+                    resp[7], resp[8]
+                )));
+            } else {
+                return Ok(resp[CCID_HEADER_LEN..].to_vec());
+            }
+            if Instant::now() >= deadline {
+                return Err(Error::new(ErrorKind::TimedOut, "CCID response timed out"));
+            }
+        }
+    }
+
+    async fn transmit(&mut self, apdu: &[u8]) -> Result<(Vec<u8>, u16)> {
+        let mut resp = self.command(PC_TO_RDR_XFR_BLOCK, apdu).await?;
+        if resp.len() < 2 {
+            return Err(Error::other("APDU response is missing its status word"));
+        }
+        let sw = u16::from_be_bytes([resp[resp.len() - 2], resp[resp.len() - 1]]);
+        resp.truncate(resp.len() - 2);
+        Ok((resp, sw))
+    }
+}
+
+/// A PIV GENERAL AUTHENTICATE performing ECDH with the given slot
+fn ecdh_apdu(slot: u8) -> Vec<u8> {
+    let mut apdu = vec![
+        0x00, 0x87, 0x11, slot, 0x47, 0x7C, 0x45, 0x82, 0x00, 0x85, 0x41,
+    ];
+    apdu.extend_from_slice(&P256_GENERATOR);
+    apdu
+}
+
+#[derive(Default)]
+struct Report {
+    attach: Option<Duration>,
+    control: Vec<Duration>,
+    apdu: Vec<Duration>,
+    ecdh: Vec<Duration>,
+    touch: Option<Duration>,
+}
+
+impl Report {
+    fn summary(samples: &[Duration]) -> String {
+        if samples.is_empty() {
+            return "n/a".into();
+        }
+        let mut sorted = samples.to_vec();
+        sorted.sort();
+        let percentile = |p: usize| sorted[(sorted.len() - 1) * p / 100];
+        format!(
+            "p50 {:>9.3?}  p95 {:>9.3?}  max {:>9.3?}",
+            percentile(50),
+            percentile(95),
+            sorted[sorted.len() - 1],
+        )
+    }
+
+    fn throughput(samples: &[Duration]) -> String {
+        let total: Duration = samples.iter().sum();
+        if samples.is_empty() || total.is_zero() {
+            return "n/a".into();
+        }
+        format!("{:.1} ops/s", samples.len() as f64 / total.as_secs_f64())
+    }
+
+    fn single(sample: Option<Duration>) -> String {
+        sample
+            .map(|d| format!("{:.3?}", d))
+            .unwrap_or_else(|| "n/a".into())
+    }
+}
+
+async fn run(link: &mut Link, options: &Options, report: &mut Report) -> Result<()> {
+    let device = Link::get_descriptor(0x01, 18);
+    for _ in 0..options.iterations {
+        let start = Instant::now();
+        link.control_in(device).await?;
+        report.control.push(start.elapsed());
+    }
+
+    let (bulk_in, bulk_out) = match link.find_ccid().await? {
+        Some(eps) => eps,
+        None => {
+            warn!("Device has no CCID interface, skipping APDU measurements");
+            return Ok(());
+        }
+    };
+    let mut ccid = Ccid {
+        link,
+        bulk_in,
+        bulk_out,
+        seq: 0,
+    };
+    ccid.command(PC_TO_RDR_ICC_POWER_ON, &[]).await?;
+
+    for _ in 0..options.iterations {
+        let start = Instant::now();
+        let (_, sw) = ccid.transmit(&SELECT_PIV).await?;
+        report.apdu.push(start.elapsed());
+        if sw != 0x9000 {
+            warn!(
+                "SELECT PIV failed with {:04x}, skipping PIV measurements",
+                sw
+            );
+            return Ok(());
+        }
+    }
+
+    if let Some(slot) = options.ecdh_slot {
+        let apdu = ecdh_apdu(slot);
+        let rounds = if options.touch { 1 } else { options.iterations };
+        for _ in 0..rounds {
+            let start = Instant::now();
+            let (_, sw) = ccid.transmit(&apdu).await?;
+            let elapsed = start.elapsed();
+            match sw {
+                0x9000 if options.touch => report.touch = Some(elapsed),
+                0x9000 => report.ecdh.push(elapsed),
+                0x6982 => {
+                    warn!(
+                        "Slot {:02x} requires a PIN, skipping ECDH measurements",
+                        slot
+                    );
+                    break;
+                }
+                sw => {
+                    warn!("ECDH with slot {:02x} failed with {:04x}", slot, sw);
+                    break;
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Benchmark the device `busid` of the daemon at `listen`, and the same
+/// device plugged in locally if there is one
+pub(crate) async fn bench(listen: &Address, busid: &str, options: &Options) -> Result<()> {
+    if options.touch {
+        info!("Touch the YubiKey when it blinks to measure the touch path");
+    }
+
+    let mut remote = Report::default();
+    let start = Instant::now();
+    let device = RemoteDevice::attach(listen, busid).await?;
+    remote.attach = Some(start.elapsed());
+    let (vid, pid) = (device.vendor_id, device.product_id);
+    run(&mut Link::Remote(device), options, &mut remote).await?;
+
+    // Opening the device through libusb is the local counterpart of attaching.
+    let mut local = Report::default();
+    let start = Instant::now();
+    match Context::new()
+        .ok()
+        .and_then(|ctx| ctx.open_device_with_vid_pid(vid, pid))
+    {
+        Some(handle) => {
+            local.attach = Some(start.elapsed());
+            let mut link = Link::Local(handle);
+            if let Err(err) = run(&mut link, options, &mut local).await {
+                // Usually pcscd holding the CCID interface.
+                warn!("Local baseline incomplete: {}", err);
+            }
+        }
+        None => warn!("No local {:04x}:{:04x} device, skipping baseline", vid, pid),
+    }
+
+    println!("{:<20} {:<48} local", "", "remote");
+    println!(
+        "{:<20} {:<48} {}",
+        "attach",
+        Report::single(remote.attach),
+        Report::single(local.attach)
+    );
+    for (name, r, l) in [
+        ("control round trip", &remote.control, &local.control),
+        ("APDU round trip", &remote.apdu, &local.apdu),
+        ("ECDH latency", &remote.ecdh, &local.ecdh),
+    ] {
+        println!(
+            "{:<20} {:<48} {}",
+            name,
+            Report::summary(r),
+            Report::summary(l)
+        );
+    }
+    println!(
+        "{:<20} {:<48} {}",
+        "ECDH throughput",
+        Report::throughput(&remote.ecdh),
+        Report::throughput(&local.ecdh)
+    );
+    println!(
+        "{:<20} {:<48} {}",
+        "touch path",
+        Report::single(remote.touch),
+        Report::single(local.touch)
+    );
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn long_bus_id() {
+        // Refused before connecting anywhere
+        let addr = Address::Tcp("127.0.0.1:1".into());
+        let err = RemoteDevice::attach(&addr, &"1".repeat(32))
+            .await
+            .err()
+            .unwrap();
+        assert_eq!(err.kind(), ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn summary() {
+        assert_eq!(Report::summary(&[]), "n/a");
+        assert_eq!(Report::throughput(&[]), "n/a");
+        let samples: Vec<_> = (1..=100).map(Duration::from_millis).collect();
+        assert_eq!(
+            Report::summary(&samples),
+            format!(
+                "p50 {:>9.3?}  p95 {:>9.3?}  max {:>9.3?}",
+                Duration::from_millis(50),
+                Duration::from_millis(95),
+                Duration::from_millis(100)
+            )
+        );
+        assert_eq!(
+            Report::throughput(&[Duration::from_millis(500); 4]),
+            "2.0 ops/s"
+        );
+    }
+}
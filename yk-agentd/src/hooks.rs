@@ -0,0 +1,124 @@
+//! Shell hooks run on the events of the server
+//!
+//! Headless hosts have no desktop to notify (see [crate::notify]), but can
+//! alert whoever uses the keys by other means: `--on-touch-required CMD`
+//! runs CMD in a shell whenever a YubiKey waits for a touch, and
+//! `--on-session-start CMD` whenever a client starts using one. CMD sees
+//! `YK_EVENT` (`touch` or `import`), `YK_BUS_ID`, `YK_SERIAL` and
+//! `YK_MESSAGE`, what happened in words, e.g. to post to ntfy:
+//!
+//! ```text
+//! --on-touch-required 'curl -s -d "$YK_MESSAGE" https://ntfy.sh/my-yubikeys'
+//! ```
+//!
+//! Hooks run in the background, and their failures are only logged.
+use std::sync::Arc;
+use tokio::process::Command;
+use tokio::sync::broadcast::error::RecvError;
+use tracing::*;
+use usbip::events::Event;
+use usbip::UsbIpServer;
+
+/// The commands to run on events
+#[derive(Clone, Debug, Default)]
+pub(crate) struct Hooks {
+    pub(crate) on_touch_required: Option<String>,
+    pub(crate) on_session_start: Option<String>,
+}
+
+impl Hooks {
+    pub(crate) fn is_empty(&self) -> bool {
+        self.on_touch_required.is_none() && self.on_session_start.is_none()
+    }
+
+    /// The hook to run on `event`, if any, ready to spawn
+    fn command(&self, event: &Event) -> Option<Command> {
+        let (hook, name, bus_id, serial) = match event {
+            Event::TouchRequired { bus_id, serial } => {
+                (&self.on_touch_required, "touch", bus_id, serial)
+            }
+            Event::Imported { bus_id, serial } => {
+                (&self.on_session_start, "import", bus_id, serial)
+            }
+            Event::ApprovalRequired { .. } => return None,
+        };
+        let mut command = shell(hook.as_deref()?);
+        command
+            .env("YK_EVENT", name)
+            .env("YK_BUS_ID", bus_id)
+            .env("YK_SERIAL", serial.as_deref().unwrap_or(""))
+            .env("YK_MESSAGE", format!("{}.", event))
+            .kill_on_drop(true);
+        Some(command)
+    }
+}
+
+/// Run `hooks` on the events of `server`, until aborted
+pub(crate) async fn run(server: Arc<UsbIpServer>, hooks: Hooks) {
+    let mut events = server.subscribe();
+    loop {
+        let event = match events.recv().await {
+            Ok(event) => event,
+            Err(RecvError::Lagged(missed)) => {
+                warn!("Missed {} events to run hooks on", missed);
+                continue;
+            }
+            Err(RecvError::Closed) => return,
+        };
+        let Some(mut command) = hooks.command(&event) else {
+            continue;
+        };
+        tokio::spawn(async move {
+            match command.status().await {
+                Ok(status) if status.success() => {}
+                Ok(status) => warn!("The hook for \"{}\" failed: {}", event, status),
+                Err(err) => warn!("Running the hook for \"{}\" failed: {}", event, err),
+            }
+        });
+    }
+}
+
+/// `command`, to be run by the shell
+pub(crate) fn shell(command: &str) -> Command {
+    if cfg!(windows) {
+        let mut shell = Command::new("cmd");
+        shell.args(["/C", command]);
+        shell
+    } else {
+        let mut shell = Command::new("sh");
+        shell.args(["-c", command]);
+        shell
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn hook_commands() {
+        let hooks = Hooks {
+            on_touch_required: Some(if cfg!(windows) {
+                "exit 0".to_string()
+            } else {
+                "test \"$YK_EVENT $YK_SERIAL\" = \"touch 12345678\"".to_string()
+            }),
+            on_session_start: None,
+        };
+        let touch = Event::TouchRequired {
+            bus_id: "1-2".to_string(),
+            serial: Some("12345678".to_string()),
+        };
+        let mut command = hooks.command(&touch).unwrap();
+        assert!(command.status().await.unwrap().success());
+
+        // No hook for it
+        let import = Event::Imported {
+            bus_id: "1-2".to_string(),
+            serial: None,
+        };
+        assert!(hooks.command(&import).is_none());
+        assert!(!hooks.is_empty());
+        assert!(Hooks::default().is_empty());
+    }
+}
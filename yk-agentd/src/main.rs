@@ -0,0 +1,1237 @@
+//! yk-agentd: share the YubiKeys of this machine over USB/IP
+//!
+//! Exports the YubiKeys plugged into the host (or other devices, see
+//! `--devices`), follows them as they are plugged in and removed, and serves
+//! them to USB/IP clients, over TLS with `--tls-cert` and `--tls-key` (and
+//! QUIC with `--quic`), or to clients on the same machine over a Unix domain socket or named pipe, see
+//! `usbip::local`. Who may import what is set by a `--policy` file, see
+//! `usbip::acl`.
+mod approval;
+mod audit;
+mod bench;
+mod hooks;
+mod metrics;
+mod notify;
+mod service;
+mod systemd;
+
+use gumdrop::Options;
+use std::io::{Error, ErrorKind, Result};
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::*;
+use tracing_subscriber::fmt::format::FmtSpan;
+use tracing_subscriber::fmt::MakeWriter;
+use tracing_subscriber::EnvFilter;
+use usbip::{local, local::Address, quic, throttle, tls, DeviceFilter, UsbIpServer};
+
+#[derive(Debug, Options)]
+struct AgentOptions {
+    #[options(help = "Print this help message and exit.")]
+    help: bool,
+
+    #[options(help = "Print version info and exit.", short = "V")]
+    version: bool,
+
+    #[options(
+        help = "What to log: a level such as debug, or directives such as usbip=trace (default RUST_LOG, or info).",
+        no_short,
+        meta = "LEVEL"
+    )]
+    log_level: Option<String>,
+
+    #[options(
+        help = "Log as text or json, one object per line.",
+        no_short,
+        meta = "FORMAT",
+        default = "text"
+    )]
+    log_format: String,
+
+    #[options(
+        help = "Address to listen on: HOST:PORT, unix:PATH or pipe:NAME.",
+        meta = "ADDR",
+        default = "0.0.0.0:3240"
+    )]
+    listen: Address,
+
+    #[options(
+        help = "Permissions of the unix: socket, in octal.",
+        no_short,
+        meta = "MODE",
+        default = "660"
+    )]
+    socket_mode: String,
+
+    #[options(
+        help = "Devices to export, as VID[:PID] in hex separated by commas, or 'any' (default YubiKeys).",
+        no_short,
+        meta = "IDS"
+    )]
+    devices: Option<String>,
+
+    #[options(
+        help = "Classes every interface of an exported device must have, in hex separated by commas, or 'any'.",
+        no_short,
+        meta = "CLASSES"
+    )]
+    interface_classes: Option<String>,
+
+    #[options(
+        help = "Access control file saying which clients may import which devices.",
+        no_short,
+        meta = "FILE"
+    )]
+    policy: Option<PathBuf>,
+
+    #[options(
+        help = "PEM server certificate chain, to only speak TLS.",
+        no_short,
+        meta = "FILE"
+    )]
+    tls_cert: Option<PathBuf>,
+
+    #[options(
+        help = "PEM private key of the server certificate.",
+        no_short,
+        meta = "FILE"
+    )]
+    tls_key: Option<PathBuf>,
+
+    #[options(
+        help = "PEM CA certificates; clients must present a certificate issued by one.",
+        no_short,
+        meta = "FILE"
+    )]
+    client_ca: Option<PathBuf>,
+
+    #[options(
+        help = "Also serve QUIC on this UDP address, with the certificate of --tls-cert.",
+        no_short,
+        meta = "ADDR"
+    )]
+    quic: Option<SocketAddr>,
+
+    #[options(help = "Don't follow hotplug events, only rescan.", no_short)]
+    no_hotplug: bool,
+
+    #[options(
+        help = "Seconds between rescans of the host's devices, 0 to never rescan.",
+        no_short,
+        meta = "SECS",
+        default = "10"
+    )]
+    rescan_interval: u64,
+
+    #[options(
+        help = "Share the card in this PC/SC reader instead of claiming USB devices, so the host keeps using it. Can be repeated.",
+        no_short,
+        meta = "READER"
+    )]
+    pcsc_reader: Vec<String>,
+
+    #[options(
+        help = "With --pcsc-reader, type the OTPs of the host's YubiKeys on the clients, through simulated keyboards.",
+        no_short
+    )]
+    otp_keyboard: bool,
+
+    #[options(
+        help = "Seconds a client may keep a device it imported, 0 for no limit.",
+        no_short,
+        meta = "SECS",
+        default = "0"
+    )]
+    max_lease: u64,
+
+    #[options(
+        help = "Seconds of silence after which a client is taken to be gone and its devices freed, 0 to wait for TCP.",
+        no_short,
+        meta = "SECS",
+        default = "0"
+    )]
+    dead_peer_timeout: u64,
+
+    #[options(
+        help = "Commands per second a client may send, in bursts of as many, 0 for no limit.",
+        no_short,
+        meta = "N",
+        default = "0"
+    )]
+    rate_limit: u32,
+
+    #[options(
+        help = "Seconds to hold up a PIN check after a failed one, doubling with each further failure, 0 not to.",
+        no_short,
+        meta = "SECS",
+        default = "1"
+    )]
+    pin_delay: u64,
+
+    #[options(
+        help = "Refuse the APDUs that could change the YubiKeys, e.g. generating keys or changing PINs, and writes to their FIDO and OTP interfaces.",
+        no_short
+    )]
+    read_only: bool,
+
+    #[options(
+        help = "Let the APDU sessions with a YubiKey share it, taking turns, instead of importing it exclusively.",
+        no_short
+    )]
+    share_cards: bool,
+
+    #[options(
+        help = "Hold each use of a key of the YubiKeys until it is approved on this host, for up to this many seconds, 0 not to.",
+        no_short,
+        meta = "SECS",
+        default = "0"
+    )]
+    approval: u64,
+
+    #[options(
+        help = "Run this shell command to ask for each approval, e.g. a desktop prompt; exit status 0 approves.",
+        no_short,
+        meta = "CMD"
+    )]
+    approve_command: Option<String>,
+
+    #[options(
+        help = "Pop up a desktop notification on this host when a YubiKey is used, waits for a touch or for approval.",
+        no_short
+    )]
+    notify: bool,
+
+    #[options(
+        help = "Run this shell command whenever a YubiKey waits for a touch.",
+        no_short,
+        meta = "CMD"
+    )]
+    on_touch_required: Option<String>,
+
+    #[options(
+        help = "Run this shell command whenever a client starts using a YubiKey.",
+        no_short,
+        meta = "CMD"
+    )]
+    on_session_start: Option<String>,
+
+    #[options(
+        help = "Approve this request for key use, on the daemon at --listen, and exit.",
+        no_short,
+        meta = "ID"
+    )]
+    approve: Option<u64>,
+
+    #[options(
+        help = "Deny this request for key use, on the daemon at --listen, and exit.",
+        no_short,
+        meta = "ID"
+    )]
+    deny: Option<u64>,
+
+    #[options(
+        help = "Record the URBs to the devices to this file, with PINs redacted, for bug reports.",
+        no_short,
+        meta = "FILE"
+    )]
+    record: Option<PathBuf>,
+
+    #[options(
+        help = "Capture the URBs clients submit to this pcapng file, for Wireshark. PINs are not redacted.",
+        no_short,
+        meta = "FILE"
+    )]
+    pcap: Option<PathBuf>,
+
+    #[options(
+        help = "Take this device away from the client using it, on the daemon at --listen, and exit.",
+        no_short,
+        meta = "BUSID"
+    )]
+    revoke: Option<String>,
+
+    #[options(
+        help = "Print the URB latencies of each device on the daemon at --listen, and exit.",
+        no_short
+    )]
+    stats: bool,
+
+    #[options(
+        help = "Import this device from the daemon at --listen, time transfers to it against the same device plugged in here, and exit.",
+        no_short,
+        meta = "BUSID"
+    )]
+    bench: Option<String>,
+
+    #[options(
+        help = "Rounds of each --bench measurement.",
+        no_short,
+        meta = "N",
+        default = "100"
+    )]
+    bench_iterations: usize,
+
+    #[options(
+        help = "Also time PIV ECDH with the key in this slot, in hex (e.g. 9d), in --bench.",
+        no_short,
+        meta = "SLOT"
+    )]
+    bench_slot: Option<String>,
+
+    #[options(
+        help = "Time a single --bench-slot ECDH that waits for a touch instead.",
+        no_short
+    )]
+    bench_touch: bool,
+
+    #[options(
+        help = "Install the Windows service running yk-agentd with the other options, and exit.",
+        no_short
+    )]
+    install_service: bool,
+
+    #[options(
+        help = "Run as the Windows service, which --install-service sets up.",
+        no_short
+    )]
+    run_as_service: bool,
+
+    #[options(
+        help = "Warn about URBs that take longer than this many milliseconds, 0 not to.",
+        no_short,
+        meta = "MS",
+        default = "0"
+    )]
+    slow_urb: u64,
+
+    #[options(
+        help = "Record connections, imports and their use to this file, as JSON lines.",
+        no_short,
+        meta = "FILE"
+    )]
+    audit_log: Option<PathBuf>,
+
+    #[options(
+        help = "Validate the bearer tokens of clients against this OpenID Connect issuer, so the policy can match their claims.",
+        no_short,
+        meta = "URL"
+    )]
+    oidc_issuer: Option<String>,
+
+    #[options(
+        help = "Audience the tokens of --oidc-issuer must be issued for.",
+        no_short,
+        meta = "AUD"
+    )]
+    oidc_audience: Option<String>,
+
+    #[options(
+        help = "Size in MiB past which the audit log is rotated.",
+        no_short,
+        meta = "MIB",
+        default = "10"
+    )]
+    audit_max_size: u64,
+
+    #[options(
+        help = "Number of rotated audit logs to keep.",
+        no_short,
+        meta = "N",
+        default = "5"
+    )]
+    audit_keep: usize,
+
+    #[options(
+        help = "Serve Prometheus metrics at http://ADDR/metrics.",
+        no_short,
+        meta = "ADDR"
+    )]
+    metrics: Option<SocketAddr>,
+
+    #[options(
+        help = "Advertise the daemon on the local network as NAME, over mDNS.",
+        no_short,
+        meta = "NAME"
+    )]
+    mdns: Option<String>,
+}
+
+impl AgentOptions {
+    /// Which host devices to export
+    fn filter(&self) -> Result<DeviceFilter> {
+        let mut filter = DeviceFilter::yubikeys();
+        if let Some(ids) = &self.devices {
+            filter.ids = DeviceFilter::parse_ids(ids)?;
+        }
+        if let Some(classes) = &self.interface_classes {
+            filter.interface_classes = DeviceFilter::parse_classes(classes)?;
+        }
+        Ok(filter)
+    }
+
+    /// The TCP address to listen on, if not a local transport
+    fn tcp_addr(&self) -> Result<Option<SocketAddr>> {
+        match &self.listen {
+            Address::Tcp(addr) => addr.parse().map(Some).map_err(|_| {
+                Error::new(ErrorKind::InvalidInput, format!("Invalid address {}", addr))
+            }),
+            _ => Ok(None),
+        }
+    }
+
+    /// The permissions of the socket file of a `unix:` address
+    fn socket_mode(&self) -> Result<u32> {
+        u32::from_str_radix(&self.socket_mode, 8)
+            .ok()
+            .filter(|mode| *mode <= 0o777)
+            .ok_or_else(|| {
+                Error::new(
+                    ErrorKind::InvalidInput,
+                    "--socket-mode takes octal permissions",
+                )
+            })
+    }
+
+    /// What `--bench` measures
+    fn bench_options(&self) -> Result<bench::Options> {
+        let ecdh_slot = match &self.bench_slot {
+            Some(slot) => Some(u8::from_str_radix(slot, 16).map_err(|_| {
+                Error::new(
+                    ErrorKind::InvalidInput,
+                    "--bench-slot takes a slot in hex, e.g. 9d",
+                )
+            })?),
+            None if self.bench_touch => {
+                return Err(Error::new(
+                    ErrorKind::InvalidInput,
+                    "--bench-touch needs --bench-slot",
+                ))
+            }
+            None => None,
+        };
+        Ok(bench::Options {
+            iterations: self.bench_iterations,
+            ecdh_slot,
+            touch: self.bench_touch,
+        })
+    }
+
+    /// What to log, from --log-level or else RUST_LOG
+    fn log_filter(&self) -> Result<EnvFilter> {
+        match &self.log_level {
+            Some(level) => EnvFilter::try_new(level).map_err(|err| {
+                Error::new(
+                    ErrorKind::InvalidInput,
+                    format!("Invalid --log-level: {}", err),
+                )
+            }),
+            None => {
+                Ok(EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info")))
+            }
+        }
+    }
+
+    /// Whether to log JSON objects rather than text
+    fn json_logs(&self) -> Result<bool> {
+        match self.log_format.as_str() {
+            "text" => Ok(false),
+            "json" => Ok(true),
+            _ => Err(Error::new(
+                ErrorKind::InvalidInput,
+                "--log-format is text or json",
+            )),
+        }
+    }
+
+    /// Log to stderr as the options say, including how long the spans of
+    /// URBs and APDUs took when they close
+    fn init_logging(&self) -> Result<()> {
+        self.init_logging_to(std::io::stderr, true)
+    }
+
+    /// Like [Self::init_logging], to `writer`, colored if `ansi`
+    fn init_logging_to<W>(&self, writer: W, ansi: bool) -> Result<()>
+    where
+        W: for<'a> MakeWriter<'a> + Send + Sync + 'static,
+    {
+        let logs = tracing_subscriber::fmt()
+            .with_env_filter(self.log_filter()?)
+            .with_span_events(FmtSpan::CLOSE)
+            .with_ansi(ansi)
+            .with_writer(writer);
+        if self.json_logs()? {
+            logs.json().init();
+        } else {
+            logs.init();
+        }
+        Ok(())
+    }
+
+    /// The issuer and audience of the bearer tokens to validate, if any
+    fn oidc(&self) -> Result<Option<(&str, &str)>> {
+        match (&self.oidc_issuer, &self.oidc_audience) {
+            (Some(issuer), Some(audience)) => Ok(Some((issuer, audience))),
+            (None, None) => Ok(None),
+            _ => Err(Error::new(
+                ErrorKind::InvalidInput,
+                "--oidc-issuer and --oidc-audience go together",
+            )),
+        }
+    }
+
+    /// The address and configuration of the QUIC server, if any
+    fn quic(&self) -> Result<Option<(SocketAddr, quic::quinn::ServerConfig)>> {
+        let (Some(addr), Some(cert), Some(key)) = (self.quic, &self.tls_cert, &self.tls_key) else {
+            return match self.quic {
+                Some(_) => Err(Error::new(
+                    ErrorKind::InvalidInput,
+                    "--quic needs --tls-cert and --tls-key",
+                )),
+                None => Ok(None),
+            };
+        };
+        let client_roots = match &self.client_ca {
+            Some(path) => Some(tls::load_certs(path)?),
+            None => None,
+        };
+        let config = quic::server_config(
+            tls::load_certs(cert)?,
+            tls::load_private_key(key)?,
+            client_roots,
+        )?;
+        Ok(Some((addr, config)))
+    }
+
+    /// The TLS acceptor, if the server speaks TLS
+    fn acceptor(&self) -> Result<Option<tls::TlsAcceptor>> {
+        match (&self.tls_cert, &self.tls_key) {
+            (Some(cert), Some(key)) if !self.listen.is_local() => {
+                let client_roots = match &self.client_ca {
+                    Some(path) => Some(tls::load_certs(path)?),
+                    None => None,
+                };
+                tls::acceptor(
+                    tls::load_certs(cert)?,
+                    tls::load_private_key(key)?,
+                    client_roots,
+                )
+                .map(Some)
+            }
+            (Some(_), Some(_)) => Err(Error::new(
+                ErrorKind::InvalidInput,
+                "TLS is only spoken over TCP, not local transports",
+            )),
+            (None, None) if self.client_ca.is_none() => Ok(None),
+            _ => Err(Error::new(
+                ErrorKind::InvalidInput,
+                "--tls-cert and --tls-key go together, and --client-ca needs them",
+            )),
+        }
+    }
+}
+
+#[cfg(feature = "pcsc")]
+fn pcsc_devices(readers: &[String]) -> Result<Vec<usbip::UsbDevice>> {
+    readers
+        .iter()
+        .enumerate()
+        .map(|(index, reader)| {
+            info!("Sharing PC/SC reader {}", reader);
+            usbip::pcsc::reader_device(index as u32, reader)
+        })
+        .collect()
+}
+
+#[cfg(not(feature = "pcsc"))]
+fn pcsc_devices(_: &[String]) -> Result<Vec<usbip::UsbDevice>> {
+    Err(Error::new(
+        ErrorKind::Unsupported,
+        "--pcsc-reader needs yk-agentd built with the pcsc feature",
+    ))
+}
+
+/// Completes on Ctrl-C, or when the service manager stops us
+async fn shutdown_signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+        match signal(SignalKind::terminate()) {
+            Ok(mut terminate) => {
+                tokio::select! {
+                    _ = tokio::signal::ctrl_c() => {}
+                    _ = terminate.recv() => {}
+                }
+            }
+            Err(_) => {
+                tokio::signal::ctrl_c().await.ok();
+            }
+        }
+    }
+    #[cfg(windows)]
+    tokio::select! {
+        _ = tokio::signal::ctrl_c() => {}
+        _ = service::stopped() => {}
+    }
+    #[cfg(not(any(unix, windows)))]
+    tokio::signal::ctrl_c().await.ok();
+    info!("Shutting down");
+    systemd::notify("STOPPING=1");
+}
+
+/// Keep the exported devices in line with the host: follow hotplug events
+/// where libusb supports them, and rescan every `interval` to catch what
+/// they miss (devices that were busy when plugged in, or no hotplug at all)
+///
+/// Runs until dropped.
+async fn supervise(
+    server: Arc<UsbIpServer>,
+    filter: DeviceFilter,
+    hotplug: bool,
+    interval: Duration,
+) {
+    let _watcher = if hotplug {
+        let filter = filter.clone();
+        match server.watch_host(move |dev| filter.matches(dev)) {
+            Ok(watcher) => Some(watcher),
+            Err(err) => {
+                warn!("Not following hotplug events: {}", err);
+                None
+            }
+        }
+    } else {
+        None
+    };
+    if interval.is_zero() {
+        return std::future::pending().await;
+    }
+
+    let mut ticks = tokio::time::interval(interval);
+    ticks.tick().await;
+    loop {
+        ticks.tick().await;
+        if let Err(err) = server.rescan_host(|dev| filter.matches(dev)).await {
+            warn!("Rescanning host devices failed: {}", err);
+        }
+    }
+}
+
+fn main() -> Result<()> {
+    let opts = AgentOptions::parse_args_default_or_exit();
+    if opts.version {
+        println!("yk-agentd {}", env!("CARGO_PKG_VERSION"));
+        return Ok(());
+    }
+    if opts.run_as_service {
+        // Logs to the event log
+        return service::run(opts);
+    }
+    opts.init_logging()?;
+    if opts.install_service {
+        return service::install(&opts);
+    }
+    tokio::runtime::Runtime::new()?.block_on(run(opts))
+}
+
+/// Run the daemon, or the admin request, of `opts`
+async fn run(opts: AgentOptions) -> Result<()> {
+    if let Some(bus_id) = &opts.revoke {
+        return revoke(&opts.listen, bus_id).await;
+    }
+    if opts.stats {
+        return stats(&opts.listen).await;
+    }
+    if let Some(bus_id) = &opts.bench {
+        return bench::bench(&opts.listen, bus_id, &opts.bench_options()?).await;
+    }
+    if let Some(id) = opts.approve.or(opts.deny) {
+        return answer_approval(&opts.listen, id, opts.approve.is_some()).await;
+    }
+
+    let filter = opts.filter()?;
+    let acceptor = opts.acceptor()?;
+    let quic = opts.quic()?;
+    let tcp_addr = opts.tcp_addr()?;
+    let socket_mode = opts.socket_mode()?;
+    let oidc = opts.oidc()?;
+    let pcsc = !opts.pcsc_reader.is_empty();
+    if opts.otp_keyboard && !pcsc {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            "--otp-keyboard needs --pcsc-reader, exported YubiKeys bring their own keyboard",
+        ));
+    }
+    let mut otp_forwarders = vec![];
+    let mut server = if pcsc {
+        let mut devices = pcsc_devices(&opts.pcsc_reader)?;
+        if opts.otp_keyboard {
+            for (device, forwarder) in usbip::otp::forward_host(devices.len() as u32)? {
+                devices.push(device);
+                otp_forwarders.push(forwarder);
+            }
+        }
+        UsbIpServer::new_simulated(devices)
+    } else {
+        UsbIpServer::new_from_host_with_filter(|dev| filter.matches(dev))
+    };
+    if let Some(path) = &opts.policy {
+        server = server.with_policy(usbip::acl::Policy::load(path)?);
+        info!("Loaded access control policy {}", path.display());
+    } else if acceptor.is_none() && tcp_addr.is_some() {
+        warn!(
+            "No --policy nor TLS: anyone who can reach {} may use the devices",
+            opts.listen
+        );
+    }
+    if let Some(path) = &opts.audit_log {
+        let log = audit::AuditLog::open(path, opts.audit_max_size << 20, opts.audit_keep)?;
+        server = server.with_audit(log.spawn());
+        info!("Writing the audit log to {}", path.display());
+    }
+    if let Some((issuer, audience)) = oidc {
+        server = server.with_oidc(usbip::oidc::Validator::discover(issuer, audience).await?);
+        if opts.policy.is_none() {
+            warn!("--oidc-issuer without a --policy lets clients in whatever their token");
+        }
+    }
+    if opts.max_lease > 0 {
+        server = server.with_max_lease(Duration::from_secs(opts.max_lease));
+    }
+    if opts.dead_peer_timeout > 0 {
+        server = server.with_dead_peer_timeout(Duration::from_secs(opts.dead_peer_timeout));
+    }
+    if opts.rate_limit > 0 {
+        server = server.with_rate_limit(throttle::RateLimit {
+            rate: opts.rate_limit.into(),
+            burst: opts.rate_limit.into(),
+        });
+    }
+    if opts.pin_delay > 0 {
+        server = server.with_pin_throttle(Duration::from_secs(opts.pin_delay));
+    }
+    if opts.slow_urb > 0 {
+        server = server.with_latency_budget(usbip::metrics::LatencyBudget::uniform(
+            Duration::from_millis(opts.slow_urb),
+        ));
+    }
+    if opts.read_only {
+        server = server.with_read_only(true);
+        info!("Refusing changes to the devices' smart cards");
+    }
+    if opts.share_cards {
+        server = server.with_card_sharing(true);
+        info!("Sharing the devices' smart cards between APDU sessions");
+    }
+    if opts.approval > 0 {
+        server = server.with_approval(Duration::from_secs(opts.approval));
+        info!("Holding key use until it is approved on this host");
+    } else if opts.approve_command.is_some() {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            "--approve-command needs --approval",
+        ));
+    }
+    if let Some(path) = &opts.record {
+        server = server.with_trace(usbip::record::Trace::create(path, true)?);
+        warn!(
+            "Recording the URBs to the devices to {}, which holds what they sent",
+            path.display()
+        );
+    }
+    if let Some(path) = &opts.pcap {
+        server = server.with_capture(usbip::pcap::Capture::create(path)?);
+        warn!(
+            "Capturing the URBs to {}, which holds the PINs clients send",
+            path.display()
+        );
+    }
+    let server = Arc::new(server);
+
+    // PC/SC readers stay as they are, the host's stack follows the cards
+    let supervisor = (!pcsc).then(|| {
+        tokio::spawn(supervise(
+            server.clone(),
+            filter,
+            !opts.no_hotplug,
+            Duration::from_secs(opts.rescan_interval),
+        ))
+    });
+
+    let notifications = if opts.notify {
+        Some(notify::spawn(server.clone())?)
+    } else {
+        None
+    };
+    let hooks = hooks::Hooks {
+        on_touch_required: opts.on_touch_required.clone(),
+        on_session_start: opts.on_session_start.clone(),
+    };
+    let hooks = (!hooks.is_empty()).then(|| tokio::spawn(hooks::run(server.clone(), hooks)));
+    let prompts = opts
+        .approve_command
+        .clone()
+        .map(|command| tokio::spawn(approval::prompt(server.clone(), command)));
+
+    let metrics = opts.metrics.map(|addr| {
+        let server = server.clone();
+        tokio::spawn(async move {
+            if let Err(err) = metrics::serve(addr, server).await {
+                error!("Serving metrics on {} failed: {}", addr, err);
+            }
+        })
+    });
+
+    let _advertisement = match (&opts.mdns, tcp_addr) {
+        (Some(name), Some(addr)) => Some(
+            usbip::mdns::advertise(server.clone(), name, addr.port(), acceptor.is_some()).await?,
+        ),
+        (Some(_), None) => {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "--mdns only advertises TCP addresses",
+            ))
+        }
+        (None, _) => None,
+    };
+
+    // Alongside the main listener, shutting down with it
+    let quic = quic.map(|(addr, config)| {
+        info!("Accepting QUIC connections on {}", addr);
+        tokio::spawn(quic::server_with_shutdown(
+            addr,
+            server.clone(),
+            config,
+            shutdown_signal(),
+        ))
+    });
+
+    let watchdog = tokio::spawn(systemd::watchdog());
+
+    info!("Listening on {}", opts.listen);
+    let res = serve(&opts.listen, tcp_addr, socket_mode, server, acceptor).await;
+    watchdog.abort();
+    if let Some(quic) = quic {
+        quic.await.ok();
+    }
+    if let Some(prompts) = prompts {
+        prompts.abort();
+    }
+    if let Some(notifications) = notifications {
+        notifications.abort();
+    }
+    if let Some(hooks) = hooks {
+        hooks.abort();
+    }
+    if let Some(metrics) = metrics {
+        metrics.abort();
+    }
+    // Release the devices
+    if let Some(supervisor) = supervisor {
+        supervisor.abort();
+        supervisor.await.ok();
+    }
+    drop(otp_forwarders);
+    res
+}
+
+/// Ask the daemon listening on `listen` to revoke the import of `bus_id`
+///
+/// TLS is not spoken: the daemon must be reachable over a local transport or
+/// plain TCP, and let us in with an `admin` rule of its policy.
+async fn revoke(listen: &Address, bus_id: &str) -> Result<()> {
+    let socket = local::connect(listen).await?;
+    usbip::admin::revoke(&mut tokio::io::BufStream::new(socket), bus_id).await?;
+    info!("Revoked the import of {}", bus_id);
+    Ok(())
+}
+
+/// Ask the daemon listening on `listen` to approve or deny the request for
+/// key use `id`
+async fn answer_approval(listen: &Address, id: u64, approved: bool) -> Result<()> {
+    let socket = local::connect(listen).await?;
+    usbip::admin::approve(&mut tokio::io::BufStream::new(socket), id, approved).await?;
+    info!(
+        "{} request {}",
+        if approved { "Approved" } else { "Denied" },
+        id
+    );
+    Ok(())
+}
+
+/// Print the latencies of the recent URBs to each device of the daemon
+/// listening on `listen`
+async fn stats(listen: &Address) -> Result<()> {
+    let socket = local::connect(listen).await?;
+    let stats = usbip::admin::stats(&mut tokio::io::BufStream::new(socket)).await?;
+    println!(
+        "{:<10} {:<10} {:>8} {:>10} {:>10} {:>10}",
+        "BUS ID", "SERIAL", "URBS", "P50", "P95", "P99"
+    );
+    for device in stats {
+        println!(
+            "{:<10} {:<10} {:>8} {:>10} {:>10} {:>10}",
+            device.bus_id,
+            device.serial,
+            device.urbs,
+            format!("{:.1?}", device.p50),
+            format!("{:.1?}", device.p95),
+            format!("{:.1?}", device.p99),
+        );
+    }
+    Ok(())
+}
+
+/// Serve `server` on `listen`, or the socket systemd passed us, until we are
+/// asked to shut down
+async fn serve(
+    listen: &Address,
+    tcp_addr: Option<SocketAddr>,
+    socket_mode: u32,
+    server: Arc<UsbIpServer>,
+    acceptor: Option<tls::TlsAcceptor>,
+) -> Result<()> {
+    match (listen, tcp_addr, acceptor) {
+        (_, Some(addr), acceptor) => {
+            let listener = match systemd::tcp_listener()? {
+                Some(listener) => {
+                    info!("Serving the socket passed by systemd");
+                    listener
+                }
+                None => tokio::net::TcpListener::bind(addr).await?,
+            };
+            systemd::notify("READY=1");
+            match acceptor {
+                Some(acceptor) => {
+                    info!("Only accepting TLS connections");
+                    tls::listener_server_with_shutdown(
+                        listener,
+                        server,
+                        acceptor,
+                        shutdown_signal(),
+                    )
+                    .await
+                }
+                None => {
+                    usbip::listener_server_with_shutdown(listener, server, shutdown_signal()).await
+                }
+            }
+            Ok(())
+        }
+        #[cfg(unix)]
+        (Address::Unix(path), None, _) => {
+            use std::os::unix::fs::PermissionsExt;
+
+            if let Some(listener) = systemd::unix_listener()? {
+                // The socket unit sets its permissions, and keeps it
+                info!("Serving the socket passed by systemd");
+                systemd::notify("READY=1");
+                local::inherited_unix_server_with_shutdown(listener, server, shutdown_signal())
+                    .await;
+                return Ok(());
+            }
+            let listener = local::bind_unix(path)?;
+            std::fs::set_permissions(path, std::fs::Permissions::from_mode(socket_mode))?;
+            systemd::notify("READY=1");
+            local::unix_server_with_shutdown(listener, server, shutdown_signal()).await;
+            Ok(())
+        }
+        #[cfg(windows)]
+        (Address::Pipe(name), None, _) => {
+            let _ = socket_mode;
+            local::pipe_server_with_shutdown(name, server, shutdown_signal()).await
+        }
+        (Address::Ssh(..), None, _) => Err(Error::new(
+            ErrorKind::InvalidInput,
+            "ssh: addresses are for clients, listen on a unix: socket instead",
+        )),
+        _ => Err(Error::new(
+            ErrorKind::Unsupported,
+            format!("{} is not supported on this platform", listen),
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn device_filter() {
+        let opts = AgentOptions::parse_args_default::<&str>(&[]).unwrap();
+        assert_eq!(
+            opts.tcp_addr().unwrap(),
+            Some("0.0.0.0:3240".parse().unwrap())
+        );
+        assert_eq!(opts.filter().unwrap(), DeviceFilter::yubikeys());
+
+        let opts = AgentOptions::parse_args_default(&[
+            "--devices",
+            "1050,20a0:4108",
+            "--interface-classes",
+            "any",
+        ])
+        .unwrap();
+        let filter = opts.filter().unwrap();
+        assert_eq!(filter.ids, vec![(0x1050, None), (0x20a0, Some(0x4108))]);
+        assert!(filter.interface_classes.is_empty());
+    }
+
+    #[test]
+    fn tls_options() {
+        let opts = AgentOptions::parse_args_default::<&str>(&[]).unwrap();
+        assert!(opts.acceptor().unwrap().is_none());
+
+        for args in [
+            &["--tls-cert", "server.pem"][..],
+            &["--client-ca", "ca.pem"][..],
+            &[
+                "--listen",
+                "unix:/run/yk-agentd.sock",
+                "--tls-cert",
+                "server.pem",
+                "--tls-key",
+                "server.key",
+            ][..],
+        ] {
+            let opts = AgentOptions::parse_args_default(args).unwrap();
+            assert_eq!(
+                opts.acceptor().err().map(|err| err.kind()),
+                Some(ErrorKind::InvalidInput)
+            );
+        }
+
+        assert!(opts.quic().unwrap().is_none());
+        let opts = AgentOptions::parse_args_default(&["--quic", "0.0.0.0:3240"]).unwrap();
+        assert_eq!(
+            opts.quic().err().map(|err| err.kind()),
+            Some(ErrorKind::InvalidInput)
+        );
+    }
+
+    #[test]
+    fn local_transports() {
+        let opts =
+            AgentOptions::parse_args_default(&["--listen", "unix:/run/yk-agentd.sock"]).unwrap();
+        assert_eq!(opts.listen, Address::Unix("/run/yk-agentd.sock".into()));
+        assert_eq!(opts.tcp_addr().unwrap(), None);
+        assert_eq!(opts.socket_mode().unwrap(), 0o660);
+
+        let opts = AgentOptions::parse_args_default(&["--listen", "pipe:yk-agentd"]).unwrap();
+        assert_eq!(opts.listen, Address::Pipe("yk-agentd".into()));
+
+        for args in [
+            &["--listen", "localhost"][..],
+            &["--socket-mode", "999"][..],
+        ] {
+            let opts = AgentOptions::parse_args_default(args).unwrap();
+            assert!(opts.tcp_addr().and(opts.socket_mode()).is_err());
+        }
+    }
+
+    #[test]
+    fn leases() {
+        let opts = AgentOptions::parse_args_default::<&str>(&[]).unwrap();
+        assert_eq!(opts.max_lease, 0);
+        assert_eq!(opts.revoke, None);
+
+        let opts =
+            AgentOptions::parse_args_default(&["--max-lease", "3600", "--revoke", "1-2"]).unwrap();
+        assert_eq!(opts.max_lease, 3600);
+        assert_eq!(opts.revoke.as_deref(), Some("1-2"));
+    }
+
+    #[test]
+    fn throttling() {
+        let opts = AgentOptions::parse_args_default::<&str>(&[]).unwrap();
+        assert_eq!(opts.rate_limit, 0);
+        assert_eq!(opts.pin_delay, 1);
+
+        let opts =
+            AgentOptions::parse_args_default(&["--rate-limit", "500", "--pin-delay", "0"]).unwrap();
+        assert_eq!(opts.rate_limit, 500);
+        assert_eq!(opts.pin_delay, 0);
+    }
+
+    #[test]
+    fn bench() {
+        let opts = AgentOptions::parse_args_default::<&str>(&[]).unwrap();
+        assert_eq!(opts.bench, None);
+        let options = opts.bench_options().unwrap();
+        assert_eq!(options.iterations, 100);
+        assert_eq!(options.ecdh_slot, None);
+
+        let opts = AgentOptions::parse_args_default(&[
+            "--bench",
+            "1-2",
+            "--bench-iterations",
+            "10",
+            "--bench-slot",
+            "9d",
+            "--bench-touch",
+        ])
+        .unwrap();
+        assert_eq!(opts.bench.as_deref(), Some("1-2"));
+        let options = opts.bench_options().unwrap();
+        assert_eq!(options.iterations, 10);
+        assert_eq!(options.ecdh_slot, Some(0x9d));
+        assert!(options.touch);
+
+        for args in [&["--bench-slot", "9z"][..], &["--bench-touch"][..]] {
+            let opts = AgentOptions::parse_args_default(args).unwrap();
+            assert!(opts.bench_options().is_err());
+        }
+    }
+
+    #[test]
+    fn latencies() {
+        let opts = AgentOptions::parse_args_default::<&str>(&[]).unwrap();
+        assert!(!opts.stats);
+        assert_eq!(opts.slow_urb, 0);
+
+        let opts = AgentOptions::parse_args_default(&["--stats", "--slow-urb", "2000"]).unwrap();
+        assert!(opts.stats);
+        assert_eq!(opts.slow_urb, 2000);
+    }
+
+    #[test]
+    fn card_sharing() {
+        let opts = AgentOptions::parse_args_default::<&str>(&[]).unwrap();
+        assert!(!opts.read_only);
+        assert!(!opts.share_cards);
+
+        let opts = AgentOptions::parse_args_default(&["--read-only", "--share-cards"]).unwrap();
+        assert!(opts.read_only);
+        assert!(opts.share_cards);
+    }
+
+    #[test]
+    fn approvals() {
+        let opts = AgentOptions::parse_args_default::<&str>(&[]).unwrap();
+        assert_eq!(opts.approval, 0);
+        assert_eq!(opts.approve_command, None);
+        assert_eq!((opts.approve, opts.deny), (None, None));
+        assert!(!opts.notify);
+
+        let opts = AgentOptions::parse_args_default(&[
+            "--approval",
+            "30",
+            "--approve-command",
+            "zenity --question",
+        ])
+        .unwrap();
+        assert_eq!(opts.approval, 30);
+        assert_eq!(opts.approve_command.as_deref(), Some("zenity --question"));
+
+        let opts = AgentOptions::parse_args_default(&["--deny", "7", "--notify"]).unwrap();
+        assert_eq!((opts.approve, opts.deny), (None, Some(7)));
+        assert!(opts.notify);
+    }
+
+    #[test]
+    fn hooks() {
+        let opts = AgentOptions::parse_args_default::<&str>(&[]).unwrap();
+        assert_eq!(opts.on_touch_required, None);
+        assert_eq!(opts.on_session_start, None);
+
+        let opts = AgentOptions::parse_args_default(&[
+            "--on-touch-required",
+            "curl -d \"$YK_MESSAGE\" https://ntfy.sh/keys",
+            "--on-session-start",
+            "logger \"$YK_MESSAGE\"",
+        ])
+        .unwrap();
+        assert_eq!(
+            opts.on_touch_required.as_deref(),
+            Some("curl -d \"$YK_MESSAGE\" https://ntfy.sh/keys")
+        );
+        assert_eq!(
+            opts.on_session_start.as_deref(),
+            Some("logger \"$YK_MESSAGE\"")
+        );
+    }
+
+    #[test]
+    fn windows_service() {
+        let opts = AgentOptions::parse_args_default::<&str>(&[]).unwrap();
+        assert!(!opts.install_service && !opts.run_as_service);
+
+        let opts = AgentOptions::parse_args_default(&["--install-service"]).unwrap();
+        assert!(opts.install_service);
+        #[cfg(not(windows))]
+        assert_eq!(
+            service::install(&opts).unwrap_err().kind(),
+            ErrorKind::Unsupported
+        );
+    }
+
+    #[test]
+    fn dead_peer_timeout() {
+        let opts = AgentOptions::parse_args_default::<&str>(&[]).unwrap();
+        assert_eq!(opts.dead_peer_timeout, 0);
+
+        let opts = AgentOptions::parse_args_default(&["--dead-peer-timeout", "60"]).unwrap();
+        assert_eq!(opts.dead_peer_timeout, 60);
+    }
+
+    #[test]
+    fn logging() {
+        let opts = AgentOptions::parse_args_default::<&str>(&[]).unwrap();
+        assert!(!opts.json_logs().unwrap());
+
+        let opts = AgentOptions::parse_args_default(&[
+            "--log-level",
+            "info,usbip=trace",
+            "--log-format",
+            "json",
+        ])
+        .unwrap();
+        assert_eq!(opts.log_filter().unwrap().to_string(), "usbip=trace,info");
+        assert!(opts.json_logs().unwrap());
+
+        for args in [
+            &["--log-level", "usbip=loud"][..],
+            &["--log-format", "xml"][..],
+        ] {
+            let opts = AgentOptions::parse_args_default(args).unwrap();
+            assert!(opts.log_filter().and(opts.json_logs()).is_err());
+        }
+    }
+
+    #[test]
+    fn record() {
+        let opts = AgentOptions::parse_args_default::<&str>(&[]).unwrap();
+        assert_eq!(opts.record, None);
+
+        assert_eq!(opts.pcap, None);
+
+        let opts =
+            AgentOptions::parse_args_default(&["--record", "trace.txt", "--pcap", "usbip.pcapng"])
+                .unwrap();
+        assert_eq!(opts.record, Some(PathBuf::from("trace.txt")));
+        assert_eq!(opts.pcap, Some(PathBuf::from("usbip.pcapng")));
+    }
+
+    #[test]
+    fn oidc() {
+        let opts = AgentOptions::parse_args_default::<&str>(&[]).unwrap();
+        assert_eq!(opts.oidc().unwrap(), None);
+
+        let opts = AgentOptions::parse_args_default(&[
+            "--oidc-issuer",
+            "https://login.example.com",
+            "--oidc-audience",
+            "yk-agentd",
+        ])
+        .unwrap();
+        assert_eq!(
+            opts.oidc().unwrap(),
+            Some(("https://login.example.com", "yk-agentd"))
+        );
+
+        let opts =
+            AgentOptions::parse_args_default(&["--oidc-issuer", "https://login.example.com"])
+                .unwrap();
+        assert!(opts.oidc().is_err());
+    }
+}
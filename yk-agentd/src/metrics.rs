@@ -0,0 +1,100 @@
+//! The Prometheus metrics endpoint
+//!
+//! With `--metrics ADDR`, `GET /metrics` on ADDR answers with
+//! `usbip::metrics::Metrics::render`. This is a bare HTTP/1 server, enough
+//! for a scraper: one request per connection, and any other path is a 404.
+use std::io::Result;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader};
+use tokio::net::TcpListener;
+use tracing::*;
+use usbip::UsbIpServer;
+
+/// Longest request head accepted, in bytes
+const MAX_REQUEST_LENGTH: u64 = 8192;
+
+/// How long a scraper may take to send its request
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Serve the metrics of `server` on `addr`, until dropped
+pub(crate) async fn serve(addr: SocketAddr, server: Arc<UsbIpServer>) -> Result<()> {
+    let listener = TcpListener::bind(addr).await?;
+    info!("Serving metrics on http://{}/metrics", addr);
+    loop {
+        let (socket, peer) = listener.accept().await?;
+        let server = server.clone();
+        tokio::spawn(async move {
+            match tokio::time::timeout(REQUEST_TIMEOUT, handle(socket, &server)).await {
+                Ok(Ok(())) => {}
+                Ok(Err(err)) => debug!("Metrics request from {} failed: {}", peer, err),
+                Err(_) => debug!("Metrics request from {} timed out", peer),
+            }
+        });
+    }
+}
+
+/// Answer the request on `socket`
+async fn handle<T: AsyncRead + AsyncWrite + Unpin>(
+    mut socket: T,
+    server: &UsbIpServer,
+) -> Result<()> {
+    let mut head = BufReader::new(tokio::io::AsyncReadExt::take(
+        &mut socket,
+        MAX_REQUEST_LENGTH,
+    ));
+    let mut request_line = String::new();
+    head.read_line(&mut request_line).await?;
+    // Skip the headers, up to the blank line ending them
+    let mut line = String::new();
+    while head.read_line(&mut line).await? > 0 && !line.trim_end().is_empty() {
+        line.clear();
+    }
+
+    let response = match request_line.split_whitespace().collect::<Vec<_>>()[..] {
+        ["GET", "/metrics", _] => {
+            let body = server.metrics().render();
+            format!(
+                "HTTP/1.1 200 OK\r\n\
+                 Content-Type: text/plain; version=0.0.4\r\n\
+                 Content-Length: {}\r\n\
+                 Connection: close\r\n\r\n{}",
+                body.len(),
+                body
+            )
+        }
+        _ => "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n".to_string(),
+    };
+    socket.write_all(response.as_bytes()).await?;
+    socket.shutdown().await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::AsyncReadExt;
+
+    async fn get(server: &UsbIpServer, path: &str) -> String {
+        let (mut client, socket) = tokio::io::duplex(1 << 16);
+        let request = format!("GET {} HTTP/1.1\r\nHost: localhost\r\n\r\n", path);
+        client.write_all(request.as_bytes()).await.unwrap();
+        handle(socket, server).await.unwrap();
+        let mut response = String::new();
+        client.read_to_string(&mut response).await.unwrap();
+        response
+    }
+
+    #[tokio::test]
+    async fn metrics() {
+        let server = UsbIpServer::new_simulated(vec![]);
+        let response = get(&server, "/metrics").await;
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.contains("Content-Type: text/plain; version=0.0.4\r\n"));
+        assert!(response.contains("\r\n\r\n# HELP usbip_sessions_active "));
+        assert!(response.contains("\nusbip_sessions_active 0\n"));
+
+        let response = get(&server, "/").await;
+        assert!(response.starts_with("HTTP/1.1 404 Not Found\r\n"));
+    }
+}
@@ -0,0 +1,96 @@
+//! Desktop notifications on the host
+//!
+//! With `--notify`, the daemon pops up a desktop notification whenever a
+//! client starts using a YubiKey, when one waits for a touch, and when key
+//! use waits for approval (see [crate::approval]), so that whoever owns the
+//! keys sees what remote clients do with them. Needs the `notifications`
+//! feature.
+use std::io::Result;
+use std::sync::Arc;
+use tokio::task::JoinHandle;
+use usbip::events::Event;
+use usbip::UsbIpServer;
+
+/// The summary and body of the notification of `event`
+#[cfg_attr(not(feature = "notifications"), allow(dead_code))]
+fn message(event: &Event) -> (String, String) {
+    let summary = match event {
+        Event::Imported { .. } => "YubiKey in use",
+        Event::TouchRequired { .. } => "YubiKey waiting for a touch",
+        Event::ApprovalRequired { .. } => "YubiKey use waiting for approval",
+    };
+    let body = match event {
+        Event::ApprovalRequired { id, .. } => format!(
+            "{}. Answer with yk-agentd --approve {} or --deny {}.",
+            event, id, id
+        ),
+        event => format!("{}.", event),
+    };
+    (summary.to_string(), body)
+}
+
+/// Notify of the events of `server`, until aborted
+#[cfg(feature = "notifications")]
+pub(crate) fn spawn(server: Arc<UsbIpServer>) -> Result<JoinHandle<()>> {
+    use tokio::sync::broadcast::error::RecvError;
+    use tracing::*;
+
+    let mut events = server.subscribe();
+    Ok(tokio::spawn(async move {
+        loop {
+            let event = match events.recv().await {
+                Ok(event) => event,
+                Err(RecvError::Lagged(missed)) => {
+                    warn!("Missed {} events to notify of", missed);
+                    continue;
+                }
+                Err(RecvError::Closed) => return,
+            };
+            let (summary, body) = message(&event);
+            // Showing it may wait on the notification server
+            tokio::task::spawn_blocking(move || {
+                if let Err(err) = notify_rust::Notification::new()
+                    .appname("yk-agentd")
+                    .summary(&summary)
+                    .body(&body)
+                    .show()
+                {
+                    warn!("Showing a notification failed: {}", err);
+                }
+            });
+        }
+    }))
+}
+
+#[cfg(not(feature = "notifications"))]
+pub(crate) fn spawn(_: Arc<UsbIpServer>) -> Result<JoinHandle<()>> {
+    Err(std::io::Error::new(
+        std::io::ErrorKind::Unsupported,
+        "--notify needs yk-agentd built with the notifications feature",
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn messages() {
+        let (summary, body) = message(&Event::Imported {
+            bus_id: "1-2".to_string(),
+            serial: Some("12345678".to_string()),
+        });
+        assert_eq!(summary, "YubiKey in use");
+        assert_eq!(body, "Device #12345678 (1-2) is in use.");
+
+        let (_, body) = message(&Event::ApprovalRequired {
+            id: 7,
+            bus_id: "1-2".to_string(),
+            serial: None,
+        });
+        assert_eq!(
+            body,
+            "Approval 7 required for key use on 1-2. Answer with yk-agentd --approve 7 or --deny 7."
+        );
+    }
+}
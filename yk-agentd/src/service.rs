@@ -0,0 +1,419 @@
+//! Running as a Windows service
+//!
+//! `--install-service` registers the daemon with the service control manager
+//! as `yk-agentd`, to run with the other options it was given, so that it
+//! shares the host's YubiKeys from boot on without a console window. The
+//! service runs as LocalSystem, which may open the devices and the smart card
+//! service, starts after the latter with `--pcsc-reader`, and is restarted
+//! when it fails. What it logs goes to the Application event log.
+//!
+//! The service control manager starts it with `--run-as-service`, and stops
+//! it as Ctrl-C would.
+use crate::AgentOptions;
+use std::ffi::OsString;
+use std::io::Result;
+
+/// The name of the service, and the source of its events
+#[cfg_attr(not(windows), allow(dead_code))]
+const SERVICE_NAME: &str = "yk-agentd";
+
+/// The arguments the service is started with, from those `args` that
+/// installed it
+#[cfg_attr(not(windows), allow(dead_code))]
+fn launch_arguments(args: impl IntoIterator<Item = OsString>) -> Vec<OsString> {
+    args.into_iter()
+        .filter(|arg| arg != "--install-service")
+        .chain([OsString::from("--run-as-service")])
+        .collect()
+}
+
+/// Install the service, to run with the options of this process
+#[cfg(windows)]
+pub(crate) fn install(opts: &AgentOptions) -> Result<()> {
+    use std::time::Duration;
+    use tracing::*;
+    use windows_service::service::*;
+    use windows_service::service_manager::{ServiceManager, ServiceManagerAccess};
+
+    let manager = ServiceManager::local_computer(
+        None::<&str>,
+        ServiceManagerAccess::CONNECT | ServiceManagerAccess::CREATE_SERVICE,
+    )
+    .map_err(to_io)?;
+    let info = ServiceInfo {
+        name: SERVICE_NAME.into(),
+        display_name: "YubiKey sharing over USB/IP".into(),
+        service_type: ServiceType::OWN_PROCESS,
+        start_type: ServiceStartType::AutoStart,
+        error_control: ServiceErrorControl::Normal,
+        executable_path: std::env::current_exe()?,
+        launch_arguments: launch_arguments(std::env::args_os().skip(1)),
+        dependencies: if opts.pcsc_reader.is_empty() {
+            vec![]
+        } else {
+            vec![ServiceDependency::Service("SCardSvr".into())]
+        },
+        account_name: None,
+        account_password: None,
+    };
+    let service = manager
+        .create_service(&info, ServiceAccess::CHANGE_CONFIG)
+        .map_err(to_io)?;
+    service
+        .set_description("Shares the YubiKeys of this machine over USB/IP")
+        .map_err(to_io)?;
+    service
+        .update_failure_actions(ServiceFailureActions {
+            reset_period: ServiceFailureResetPeriod::After(Duration::from_secs(24 * 60 * 60)),
+            reboot_msg: None,
+            command: None,
+            actions: Some(vec![
+                ServiceAction {
+                    action_type: ServiceActionType::Restart,
+                    delay: Duration::from_secs(5),
+                };
+                3
+            ]),
+        })
+        .map_err(to_io)?;
+    // Also when we stop with an error, not only when we crash
+    service
+        .set_failure_actions_on_non_crash_failures(true)
+        .map_err(to_io)?;
+    event_log::register()?;
+    info!("Installed the {} service", SERVICE_NAME);
+
+    if opts.pcsc_reader.is_empty() {
+        check_devices(opts)?;
+    }
+    Ok(())
+}
+
+#[cfg(not(windows))]
+pub(crate) fn install(_: &AgentOptions) -> Result<()> {
+    Err(std::io::Error::new(
+        std::io::ErrorKind::Unsupported,
+        "--install-service is only supported on Windows",
+    ))
+}
+
+/// Warn about the devices to export that can't be opened, which libusb only
+/// does through the WinUSB driver
+#[cfg(windows)]
+fn check_devices(opts: &AgentOptions) -> Result<()> {
+    use tracing::*;
+
+    let filter = opts.filter()?;
+    let devices = rusb::devices().map_err(std::io::Error::other)?;
+    let mut found = false;
+    for device in devices.iter().filter(|device| filter.matches(device)) {
+        found = true;
+        if let Err(err) = device.open() {
+            warn!(
+                "The service can't open {:?} ({}): give it the WinUSB driver, \
+                 e.g. with Zadig, or share its card with --pcsc-reader",
+                device, err
+            );
+        }
+    }
+    if !found {
+        warn!("No device to export is plugged in");
+    }
+    Ok(())
+}
+
+/// Run as the service, with `opts`, until the service control manager stops
+/// us
+#[cfg(windows)]
+pub(crate) fn run(opts: AgentOptions) -> Result<()> {
+    *OPTIONS.lock().unwrap() = Some(opts);
+    windows_service::service_dispatcher::start(SERVICE_NAME, ffi_service_main).map_err(to_io)
+}
+
+#[cfg(not(windows))]
+pub(crate) fn run(_: AgentOptions) -> Result<()> {
+    Err(std::io::Error::new(
+        std::io::ErrorKind::Unsupported,
+        "--run-as-service is only supported on Windows",
+    ))
+}
+
+/// The options for [service_main], which the dispatcher calls without
+#[cfg(windows)]
+static OPTIONS: std::sync::Mutex<Option<AgentOptions>> = std::sync::Mutex::new(None);
+
+/// Whether the service control manager asked us to stop
+#[cfg(windows)]
+static STOP: std::sync::OnceLock<tokio::sync::watch::Sender<bool>> = std::sync::OnceLock::new();
+
+#[cfg(windows)]
+fn stop_sender() -> &'static tokio::sync::watch::Sender<bool> {
+    STOP.get_or_init(|| tokio::sync::watch::channel(false).0)
+}
+
+/// Completes once the service control manager asks us to stop
+#[cfg(windows)]
+pub(crate) async fn stopped() {
+    stop_sender().subscribe().wait_for(|&stop| stop).await.ok();
+}
+
+#[cfg(windows)]
+windows_service::define_windows_service!(ffi_service_main, service_main);
+
+#[cfg(windows)]
+fn service_main(_: Vec<OsString>) {
+    let Some(opts) = OPTIONS.lock().unwrap().take() else {
+        return;
+    };
+    if let Err(err) = serve(opts) {
+        tracing::error!("{}", err);
+    }
+}
+
+/// Run the daemon, telling the service control manager how it goes
+#[cfg(windows)]
+fn serve(opts: AgentOptions) -> Result<()> {
+    use std::time::Duration;
+    use windows_service::service::*;
+    use windows_service::service_control_handler::{self, ServiceControlHandlerResult};
+
+    opts.init_logging_to(event_log::EventLog::open()?, false)?;
+    let status = service_control_handler::register(SERVICE_NAME, |control| match control {
+        ServiceControl::Stop | ServiceControl::Shutdown => {
+            stop_sender().send_replace(true);
+            ServiceControlHandlerResult::NoError
+        }
+        ServiceControl::Interrogate => ServiceControlHandlerResult::NoError,
+        _ => ServiceControlHandlerResult::NotImplemented,
+    })
+    .map_err(to_io)?;
+    let report = |state, exit_code| {
+        status
+            .set_service_status(ServiceStatus {
+                service_type: ServiceType::OWN_PROCESS,
+                current_state: state,
+                controls_accepted: match state {
+                    ServiceState::Running => {
+                        ServiceControlAccept::STOP | ServiceControlAccept::SHUTDOWN
+                    }
+                    _ => ServiceControlAccept::empty(),
+                },
+                exit_code,
+                checkpoint: 0,
+                wait_hint: Duration::default(),
+                process_id: None,
+            })
+            .map_err(to_io)
+    };
+
+    report(ServiceState::Running, ServiceExitCode::NO_ERROR)?;
+    let res = tokio::runtime::Runtime::new().and_then(|runtime| runtime.block_on(crate::run(opts)));
+    // A failure makes the service control manager restart us
+    let exit_code = match &res {
+        Ok(()) => ServiceExitCode::NO_ERROR,
+        Err(_) => ServiceExitCode::ServiceSpecific(1),
+    };
+    report(ServiceState::Stopped, exit_code)?;
+    res
+}
+
+#[cfg(windows)]
+fn to_io(err: windows_service::Error) -> std::io::Error {
+    match err {
+        windows_service::Error::Winapi(err) => err,
+        err => std::io::Error::other(err.to_string()),
+    }
+}
+
+/// Logging to the Application event log
+#[cfg(windows)]
+mod event_log {
+    use super::SERVICE_NAME;
+    use std::io::{Result, Write};
+    use tracing::{Level, Metadata};
+    use tracing_subscriber::fmt::MakeWriter;
+    use windows_sys::Win32::System::EventLog::*;
+    use windows_sys::Win32::System::Registry::*;
+
+    /// The message file of .NET's event sources, whose messages are the
+    /// string reported, which spares us one of our own
+    const MESSAGE_FILE: &str =
+        r"%SystemRoot%\Microsoft.NET\Framework64\v4.0.30319\EventLogMessages.dll";
+
+    fn wide(s: &str) -> Vec<u16> {
+        s.encode_utf16().chain([0]).collect()
+    }
+
+    /// Register our event source, so that the Event Viewer shows our
+    /// messages as they are
+    pub(super) fn register() -> Result<()> {
+        let key = wide(&format!(
+            r"SYSTEM\CurrentControlSet\Services\EventLog\Application\{}",
+            SERVICE_NAME
+        ));
+        let mut handle = std::ptr::null_mut();
+        // Safety: the strings are NUL terminated, and the key closed below
+        let err = unsafe {
+            RegCreateKeyExW(
+                HKEY_LOCAL_MACHINE,
+                key.as_ptr(),
+                0,
+                std::ptr::null(),
+                REG_OPTION_NON_VOLATILE,
+                KEY_SET_VALUE,
+                std::ptr::null(),
+                &mut handle,
+                std::ptr::null_mut(),
+            )
+        };
+        if err != 0 {
+            return Err(std::io::Error::from_raw_os_error(err as i32));
+        }
+        let message_file: Vec<u8> = wide(MESSAGE_FILE)
+            .iter()
+            .flat_map(|unit| unit.to_le_bytes())
+            .collect();
+        let types_supported =
+            (EVENTLOG_ERROR_TYPE | EVENTLOG_WARNING_TYPE | EVENTLOG_INFORMATION_TYPE) as u32;
+        // Safety: the data is as long as told, and of the type told
+        let err = unsafe {
+            let err = RegSetValueExW(
+                handle,
+                wide("EventMessageFile").as_ptr(),
+                0,
+                REG_EXPAND_SZ,
+                message_file.as_ptr(),
+                message_file.len() as u32,
+            );
+            let err = match err {
+                0 => RegSetValueExW(
+                    handle,
+                    wide("TypesSupported").as_ptr(),
+                    0,
+                    REG_DWORD,
+                    types_supported.to_le_bytes().as_ptr(),
+                    4,
+                ),
+                err => err,
+            };
+            RegCloseKey(handle);
+            err
+        };
+        match err {
+            0 => Ok(()),
+            err => Err(std::io::Error::from_raw_os_error(err as i32)),
+        }
+    }
+
+    /// Where the logs go, one event per line
+    pub(crate) struct EventLog(usize);
+
+    impl EventLog {
+        pub(crate) fn open() -> Result<Self> {
+            let name = wide(SERVICE_NAME);
+            // Safety: the name is NUL terminated
+            let handle = unsafe { RegisterEventSourceW(std::ptr::null(), name.as_ptr()) };
+            if handle.is_null() {
+                return Err(std::io::Error::last_os_error());
+            }
+            Ok(Self(handle as usize))
+        }
+    }
+
+    impl Drop for EventLog {
+        fn drop(&mut self) {
+            // Safety: the handle is ours, and no writer outlives us
+            unsafe { DeregisterEventSource(self.0 as _) };
+        }
+    }
+
+    impl<'a> MakeWriter<'a> for EventLog {
+        type Writer = Event;
+
+        fn make_writer(&'a self) -> Event {
+            Event {
+                source: self.0,
+                kind: EVENTLOG_INFORMATION_TYPE,
+                message: vec![],
+            }
+        }
+
+        fn make_writer_for(&'a self, meta: &Metadata<'_>) -> Event {
+            let kind = match *meta.level() {
+                Level::ERROR => EVENTLOG_ERROR_TYPE,
+                Level::WARN => EVENTLOG_WARNING_TYPE,
+                _ => EVENTLOG_INFORMATION_TYPE,
+            };
+            Event {
+                source: self.0,
+                kind,
+                message: vec![],
+            }
+        }
+    }
+
+    /// An event being written, reported when dropped
+    pub(crate) struct Event {
+        source: usize,
+        kind: REPORT_EVENT_TYPE,
+        message: Vec<u8>,
+    }
+
+    impl Write for Event {
+        fn write(&mut self, buf: &[u8]) -> Result<usize> {
+            self.message.extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Drop for Event {
+        fn drop(&mut self) {
+            let message = String::from_utf8_lossy(&self.message);
+            let message = wide(message.trim_end());
+            let strings = [message.as_ptr()];
+            // Safety: the string is NUL terminated, and the source open
+            unsafe {
+                ReportEventW(
+                    self.source as _,
+                    self.kind,
+                    0,
+                    0,
+                    std::ptr::null_mut(),
+                    1,
+                    0,
+                    strings.as_ptr(),
+                    std::ptr::null(),
+                )
+            };
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn launch_args() {
+        let args = [
+            "--listen",
+            r"pipe:yk-agentd",
+            "--install-service",
+            "--read-only",
+        ];
+        assert_eq!(
+            launch_arguments(args.map(OsString::from)),
+            [
+                "--listen",
+                "pipe:yk-agentd",
+                "--read-only",
+                "--run-as-service"
+            ]
+            .map(OsString::from)
+        );
+    }
+}
@@ -0,0 +1,175 @@
+//! Running under systemd
+//!
+//! When systemd starts the daemon, with `Type=notify`, it is told over
+//! `$NOTIFY_SOCKET` once the daemon listens (`READY=1`) and when it shuts
+//! down (`STOPPING=1`). With `WatchdogSec=`, the daemon pings it twice per
+//! period (`WATCHDOG=1`), so that a hung daemon is restarted. With a socket
+//! unit, the daemon serves the socket systemd passed it (`LISTEN_FDS`)
+//! instead of binding `--listen`, so it only starts when a client connects.
+//!
+//! This speaks the protocols of `sd_notify(3)` and `sd_listen_fds(3)`
+//! itself, without libsystemd; elsewhere, none of the variables are set and
+//! it does nothing.
+use std::io::Result;
+use std::time::Duration;
+
+/// The first file descriptor systemd passes, after stdin, stdout and stderr
+#[cfg(unix)]
+const LISTEN_FDS_START: i32 = 3;
+
+/// Whether variables set for the process with id `pid`, as `pid_var`, are
+/// meant for us, rather than for a parent that left them behind
+fn for_us(pid_var: Option<&str>, pid: u32) -> bool {
+    // Unset for the watchdog, which is then meant for the main process
+    pid_var.is_none_or(|var| var.parse() == Ok(pid))
+}
+
+/// The number of sockets passed to the process with id `pid`, from
+/// `LISTEN_PID` and `LISTEN_FDS`
+#[cfg_attr(not(unix), allow(dead_code))]
+fn listen_fds_count(listen_pid: Option<&str>, listen_fds: Option<&str>, pid: u32) -> usize {
+    match (listen_pid, listen_fds) {
+        (Some(_), Some(fds)) if for_us(listen_pid, pid) => fds.parse().unwrap_or(0),
+        _ => 0,
+    }
+}
+
+/// How often the process with id `pid` must ping the watchdog, from
+/// `WATCHDOG_USEC` and `WATCHDOG_PID`
+fn watchdog_interval(usec: Option<&str>, watchdog_pid: Option<&str>, pid: u32) -> Option<Duration> {
+    let usec: u64 = usec?.parse().ok().filter(|&usec| usec > 0)?;
+    for_us(watchdog_pid, pid).then(|| Duration::from_micros(usec / 2))
+}
+
+fn var(name: &str) -> Option<String> {
+    std::env::var(name).ok()
+}
+
+/// Tell systemd about our `state`, such as `READY=1`, if it is listening
+#[cfg(unix)]
+pub(crate) fn notify(state: &str) {
+    use std::os::unix::net::UnixDatagram;
+    use tracing::*;
+
+    let Some(path) = var("NOTIFY_SOCKET") else {
+        return;
+    };
+    let res = UnixDatagram::unbound().and_then(|socket| match path.strip_prefix('@') {
+        #[cfg(target_os = "linux")]
+        Some(name) => {
+            use std::os::linux::net::SocketAddrExt;
+            let addr = std::os::unix::net::SocketAddr::from_abstract_name(name)?;
+            socket.send_to_addr(state.as_bytes(), &addr)
+        }
+        _ => socket.send_to(state.as_bytes(), &path),
+    });
+    if let Err(err) = res {
+        warn!("Notifying systemd at {} failed: {}", path, err);
+    }
+}
+
+#[cfg(not(unix))]
+pub(crate) fn notify(_: &str) {}
+
+/// Ping the watchdog, if systemd wants us to, until dropped
+pub(crate) async fn watchdog() {
+    let pid = std::process::id();
+    let Some(interval) = watchdog_interval(
+        var("WATCHDOG_USEC").as_deref(),
+        var("WATCHDOG_PID").as_deref(),
+        pid,
+    ) else {
+        return;
+    };
+    tracing::info!("Pinging the systemd watchdog every {:?}", interval);
+    let mut ticks = tokio::time::interval(interval);
+    loop {
+        ticks.tick().await;
+        notify("WATCHDOG=1");
+    }
+}
+
+/// The socket systemd passed us, if any: the first of them
+///
+/// The variables are unset, so that the commands we run don't take them for
+/// theirs.
+#[cfg(unix)]
+fn listen_fd() -> Option<std::os::fd::OwnedFd> {
+    use std::os::fd::FromRawFd;
+
+    let count = listen_fds_count(
+        var("LISTEN_PID").as_deref(),
+        var("LISTEN_FDS").as_deref(),
+        std::process::id(),
+    );
+    for name in ["LISTEN_PID", "LISTEN_FDS", "LISTEN_FDNAMES"] {
+        std::env::remove_var(name);
+    }
+    if count > 1 {
+        tracing::warn!(
+            "Only serving the first of the {} sockets systemd passed",
+            count
+        );
+    }
+    // Safety: systemd passes the sockets from LISTEN_FDS_START on, and
+    // nothing else took them, since the variables are only read once
+    (count > 0).then(|| unsafe { std::os::fd::OwnedFd::from_raw_fd(LISTEN_FDS_START) })
+}
+
+/// The TCP socket systemd passed us, if any
+#[cfg(unix)]
+pub(crate) fn tcp_listener() -> Result<Option<tokio::net::TcpListener>> {
+    let Some(fd) = listen_fd() else {
+        return Ok(None);
+    };
+    let listener = std::net::TcpListener::from(fd);
+    listener.set_nonblocking(true)?;
+    tokio::net::TcpListener::from_std(listener).map(Some)
+}
+
+/// The Unix domain socket systemd passed us, if any
+#[cfg(unix)]
+pub(crate) fn unix_listener() -> Result<Option<tokio::net::UnixListener>> {
+    let Some(fd) = listen_fd() else {
+        return Ok(None);
+    };
+    let listener = std::os::unix::net::UnixListener::from(fd);
+    listener.set_nonblocking(true)?;
+    tokio::net::UnixListener::from_std(listener).map(Some)
+}
+
+#[cfg(not(unix))]
+pub(crate) fn tcp_listener() -> Result<Option<tokio::net::TcpListener>> {
+    Ok(None)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn listen_fds() {
+        assert_eq!(listen_fds_count(Some("42"), Some("1"), 42), 1);
+        assert_eq!(listen_fds_count(Some("42"), Some("2"), 42), 2);
+        // Meant for another process
+        assert_eq!(listen_fds_count(Some("41"), Some("1"), 42), 0);
+        assert_eq!(listen_fds_count(None, Some("1"), 42), 0);
+        assert_eq!(listen_fds_count(Some("42"), Some("x"), 42), 0);
+        assert_eq!(listen_fds_count(None, None, 42), 0);
+    }
+
+    #[test]
+    fn watchdog_intervals() {
+        assert_eq!(
+            watchdog_interval(Some("30000000"), None, 42),
+            Some(Duration::from_secs(15))
+        );
+        assert_eq!(
+            watchdog_interval(Some("30000000"), Some("42"), 42),
+            Some(Duration::from_secs(15))
+        );
+        assert_eq!(watchdog_interval(Some("30000000"), Some("41"), 42), None);
+        assert_eq!(watchdog_interval(Some("0"), None, 42), None);
+        assert_eq!(watchdog_interval(None, None, 42), None);
+    }
+}